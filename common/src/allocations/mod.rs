@@ -6,6 +6,7 @@ use serde::{Deserialize, Deserializer};
 use thegraph_core::{Address, DeploymentId};
 
 pub mod monitor;
+pub mod watcher;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Allocation {
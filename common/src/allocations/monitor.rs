@@ -8,7 +8,7 @@ use std::{
 };
 
 use super::Allocation;
-use crate::prelude::SubgraphClient;
+use crate::{heartbeat::Heartbeat, prelude::SubgraphClient, subgraph_client::CacheValidators};
 use alloy::primitives::{TxHash, B256, U256};
 use eventuals::{timer, Eventual, EventualExt};
 use graphql_client::GraphQLQuery;
@@ -56,22 +56,33 @@ impl TryFrom<allocations_query::AllocationFragment> for Allocation {
 }
 
 /// An always up-to-date list of an indexer's active and recently closed allocations.
+///
+/// The `heartbeat`, if any, beats on every successful poll, whether the allocations changed or not.
 pub fn indexer_allocations(
     network_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     recently_closed_allocation_buffer: Duration,
+    heartbeat: Option<Heartbeat>,
 ) -> Eventual<HashMap<Address, Allocation>> {
     // Refresh indexer allocations every now and then
     timer(interval).map_with_retry(
-        move |_| async move {
-            get_allocations(
-                network_subgraph,
-                indexer_address,
-                recently_closed_allocation_buffer,
-            )
-            .await
-            .map_err(|e| e.to_string())
+        move |_| {
+            let heartbeat = heartbeat.clone();
+            async move {
+                let allocations = get_allocations(
+                    network_subgraph,
+                    indexer_address,
+                    recently_closed_allocation_buffer,
+                    false,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+                if let Some(heartbeat) = heartbeat {
+                    heartbeat.beat();
+                }
+                Ok(allocations)
+            }
         },
         // Need to use string errors here because eventuals `map_with_retry` retries
         // errors that can be cloned
@@ -87,38 +98,53 @@ pub fn indexer_allocations(
     )
 }
 
+/// With `uncached`, the fresh responses cached by the subgraph client are ignored, see
+/// [`SubgraphClient::query_conditional_uncached`].
 pub async fn get_allocations(
     network_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     recently_closed_allocation_buffer: Duration,
+    uncached: bool,
 ) -> Result<HashMap<Address, Allocation>, anyhow::Error> {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    let closed_at_threshold = since_the_epoch - recently_closed_allocation_buffer;
+    let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    // A buffer longer than the epoch keeps all the closed allocations
+    let closed_at_threshold = since_the_epoch
+        .checked_sub(recently_closed_allocation_buffer)
+        .unwrap_or_default();
 
     let mut hash: Option<TxHash> = None;
     let mut last: Option<String> = None;
     let mut responses = vec![];
     let page_size = 200;
     loop {
-        let result = network_subgraph
-            .query::<AllocationsQuery, _>(allocations_query::Variables {
-                indexer: indexer_address.to_string().to_ascii_lowercase(),
-                closed_at_threshold: closed_at_threshold.as_secs() as i64,
-                first: page_size,
-                last: last.unwrap_or_default(),
-                block: hash.map(|hash| allocations_query::Block_height {
-                    hash: Some(hash),
-                    number: None,
-                    number_gte: None,
-                }),
-            })
-            .await
-            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
-
-        let mut data = result?;
+        let variables = allocations_query::Variables {
+            indexer: indexer_address.to_string().to_ascii_lowercase(),
+            closed_at_threshold: closed_at_threshold.as_secs() as i64,
+            first: page_size,
+            last: last.unwrap_or_default(),
+            block: hash.map(|hash| allocations_query::Block_height {
+                hash: Some(hash),
+                number: None,
+                number_gte: None,
+            }),
+        };
+        let mut validators = CacheValidators::default();
+        let result = if uncached {
+            network_subgraph
+                .query_conditional_uncached::<AllocationsQuery, _>(variables, &mut validators)
+                .await
+        } else {
+            network_subgraph
+                .query_conditional::<AllocationsQuery, _>(variables, &mut validators)
+                .await
+        }
+        .map_err(|e| anyhow::anyhow!(e.to_string()))?
+        .ok_or_else(|| {
+            anyhow::anyhow!("Unexpected `304 Not Modified` to an unconditional query")
+        })?;
+
+        // The last known good response isn't a successful poll, the last allocations are kept
+        let mut data = result.fresh()??;
         let page_len = data.allocations.len();
 
         hash = data.meta.and_then(|meta| meta.block.hash);
@@ -165,6 +191,7 @@ mod test {
             network_subgraph_client(),
             Address::from_str("0x326c584e0f0eab1f1f83c93cc6ae1acc0feba0bc").unwrap(),
             Duration::from_secs(1712448507),
+            false,
         )
         .await;
         assert!(result.unwrap().len() > 2000)
@@ -177,6 +204,7 @@ mod test {
             network_subgraph_client(),
             Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap(),
             Duration::from_secs(1712448507),
+            false,
         )
         .await
         .unwrap();
@@ -0,0 +1,228 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The indexer's active and recently closed allocations, on a [`watch`] channel.
+//!
+//! Polled from the network subgraph every syncing interval, and as soon as the active allocations
+//! change if the network subgraph has a subscription endpoint, skipping the cache of the subgraph
+//! client then. A failed poll is logged and the allocations of the last successful one are kept,
+//! the watcher never panics. The channel is only updated when the allocations changed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use eventuals::Eventual;
+use serde_json::json;
+use thegraph_core::{Address, DeploymentId};
+use tokio::{
+    sync::watch,
+    time::{self, sleep, MissedTickBehavior},
+};
+use tracing::error;
+
+use super::{monitor::get_allocations, Allocation};
+use crate::{heartbeat::Heartbeat, prelude::SubgraphClient};
+
+/// Changes when an allocation of the indexer is opened, which counts in `totalAllocationCount`,
+/// or closed, which doesn't in `allocationCount` anymore, however many allocations it has. The
+/// closed ones leaving the retention window are caught by the polls.
+const ACTIVE_ALLOCATIONS_SUBSCRIPTION: &str = "subscription ActiveAllocations($indexer: ID!) { \
+    indexer(id: $indexer) { allocationCount totalAllocationCount } }";
+
+/// Watches the active allocations of `indexer_address`, and the ones closed less than
+/// `recently_closed_retention` ago. If `deployments` is set, only the allocations of these
+/// deployments.
+///
+/// Waits for the first successful poll. The `heartbeat`, if any, beats on every successful poll,
+/// whether the allocations changed or not, and the watcher stops once it's retired.
+pub async fn indexer_allocations_watcher(
+    network_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    interval: Duration,
+    recently_closed_retention: Duration,
+    deployments: Option<HashSet<DeploymentId>>,
+    heartbeat: Option<Heartbeat>,
+) -> watch::Receiver<HashMap<Address, Allocation>> {
+    let poll = move |uncached: bool| {
+        let deployments = deployments.clone();
+        async move {
+            get_allocations(
+                network_subgraph,
+                indexer_address,
+                recently_closed_retention,
+                uncached,
+            )
+            .await
+            .map(|allocations| filter_deployments(allocations, deployments.as_ref()))
+        }
+    };
+
+    let allocations = loop {
+        match poll(false).await {
+            Ok(allocations) => break allocations,
+            Err(err) => error!(
+                "Failed to fetch active or recently closed allocations for indexer {:?}: {}",
+                indexer_address, err
+            ),
+        }
+        sleep(interval.div_f32(2.0)).await;
+    };
+    if let Some(ref heartbeat) = heartbeat {
+        heartbeat.beat();
+    }
+
+    let (sender, receiver) = watch::channel(allocations);
+    tokio::spawn(async move {
+        let mut subscription = network_subgraph.subscribe(
+            ACTIVE_ALLOCATIONS_SUBSCRIPTION,
+            json!({ "indexer": indexer_address.to_string().to_ascii_lowercase() }),
+        );
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, and the allocations were just fetched
+        ticker.tick().await;
+
+        while !sender.is_closed() && !heartbeat.as_ref().is_some_and(Heartbeat::is_retired) {
+            let pushed = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = subscription.changed() => {
+                    ticker.reset();
+                    true
+                }
+            };
+
+            let allocations = match poll(pushed).await {
+                Ok(allocations) => allocations,
+                Err(err) => {
+                    error!(
+                        "Failed to fetch active or recently closed allocations for indexer {:?}: {}",
+                        indexer_address, err
+                    );
+                    continue;
+                }
+            };
+            sender.send_if_modified(|current| {
+                let modified = *current != allocations;
+                *current = allocations;
+                modified
+            });
+            if let Some(ref heartbeat) = heartbeat {
+                heartbeat.beat();
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Feeds the updates of [`indexer_allocations_watcher`] to an [`Eventual`], for the consumers
+/// that still expect one.
+pub fn allocations_eventual(
+    mut receiver: watch::Receiver<HashMap<Address, Allocation>>,
+) -> Eventual<HashMap<Address, Allocation>> {
+    let (mut writer, eventual) = Eventual::new();
+    tokio::spawn(async move {
+        loop {
+            let allocations = receiver.borrow_and_update().clone();
+            writer.write(allocations);
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    eventual
+}
+
+fn filter_deployments(
+    allocations: HashMap<Address, Allocation>,
+    deployments: Option<&HashSet<DeploymentId>>,
+) -> HashMap<Address, Allocation> {
+    match deployments {
+        Some(deployments) => allocations
+            .into_iter()
+            .filter(|(_, allocation)| deployments.contains(&allocation.subgraph_deployment.id))
+            .collect(),
+        None => allocations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, str::FromStr, time::Duration};
+
+    use serde_json::json;
+    use thegraph_core::{Address, DeploymentId};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::indexer_allocations_watcher;
+    use crate::{prelude::SubgraphClient, subgraph_client::DeploymentDetails};
+
+    const DEPLOYMENT_0: &str = "QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP";
+    const DEPLOYMENT_1: &str = "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz";
+
+    fn allocation(id: &str, deployment: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "indexer": { "id": "0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c" },
+            "allocatedTokens": "1000",
+            "createdAtBlockHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "createdAtEpoch": 1,
+            "closedAtEpoch": null,
+            "subgraphDeployment": {
+                "id": deployment,
+                "deniedAt": 0
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_filter_deployments() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/allocations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "meta": { "block": { "number": 1, "hash": null, "timestamp": 1 } },
+                    "allocations": [
+                        allocation("0xfa44c72b753a66591f241c7dc04e8178c30e13af", DEPLOYMENT_0),
+                        allocation("0xdd975e30aafebb143e54d215db8a3e8fd916a701", DEPLOYMENT_1),
+                    ]
+                }
+            })))
+            .mount(&mock_server)
+            .await;
+        let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&format!("{}/allocations", mock_server.uri()))
+                .unwrap(),
+        )));
+
+        let allocations = indexer_allocations_watcher(
+            network_subgraph,
+            Address::from_str("0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c").unwrap(),
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            Some(HashSet::from([
+                DeploymentId::from_str(DEPLOYMENT_0).unwrap()
+            ])),
+            None,
+        )
+        .await;
+
+        let allocations = allocations.borrow();
+        assert_eq!(allocations.len(), 1);
+        let allocation = allocations
+            .get(&Address::from_str("0xfa44c72b753a66591f241c7dc04e8178c30e13af").unwrap())
+            .unwrap();
+        assert_eq!(
+            allocation.subgraph_deployment.id,
+            DeploymentId::from_str(DEPLOYMENT_0).unwrap()
+        );
+    }
+}
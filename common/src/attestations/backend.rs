@@ -0,0 +1,189 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the attestations are signed.
+//!
+//! By default, the key of each allocation is derived from the operator mnemonic and kept in the
+//! process, see [`LocalSigner`]. An [`AttestationSignerBackend`] can delegate the signing instead
+//! to a remote signer holding the allocation keys, so that they never enter the process:
+//! - [`Web3Signer`], a web3signer or any signer with its Ethereum JSON-RPC API, set with
+//!   `service.attestation_signer.web3signer_url`.
+//! - any other backend, such as a KMS, passed to
+//!   [`crate::indexer_service::http::IndexerServiceOptions`].
+//!
+//! A remote backend only signs for the allocations it lists in
+//! [`AttestationSignerBackend::addresses`], the allocation keys must be imported in it as they
+//! are created.
+
+use std::{collections::HashSet, fmt};
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    primitives::{Address, Bytes, Signature},
+    signers::{local::PrivateKeySigner, SignerSync},
+    sol,
+    sol_types::SolStruct,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+sol! {
+    /// The EIP-712 message signed by an attestation.
+    #[derive(Debug)]
+    struct Receipt {
+        bytes32 requestCID;
+        bytes32 responseCID;
+        bytes32 subgraphDeploymentID;
+    }
+}
+
+/// Signs the attestations with the keys of the allocations.
+#[async_trait]
+pub trait AttestationSignerBackend: Send + Sync + fmt::Debug {
+    /// The addresses of the keys the backend signs with, the allocation ids it can sign for.
+    async fn addresses(&self) -> Result<HashSet<Address>>;
+
+    /// Signs `receipt` in `domain` with the key of `address`.
+    async fn sign(
+        &self,
+        address: Address,
+        domain: &Eip712Domain,
+        receipt: &Receipt,
+    ) -> Result<Signature>;
+}
+
+/// The key of an allocation, in the process.
+#[derive(Debug)]
+pub struct LocalSigner(pub PrivateKeySigner);
+
+#[async_trait]
+impl AttestationSignerBackend for LocalSigner {
+    async fn addresses(&self) -> Result<HashSet<Address>> {
+        Ok(HashSet::from([self.0.address()]))
+    }
+
+    async fn sign(
+        &self,
+        address: Address,
+        domain: &Eip712Domain,
+        receipt: &Receipt,
+    ) -> Result<Signature> {
+        if address != self.0.address() {
+            return Err(anyhow!("No key for {}", address));
+        }
+        Ok(self
+            .0
+            .sign_hash_sync(&receipt.eip712_signing_hash(domain))?)
+    }
+}
+
+/// A signer with the Ethereum JSON-RPC API of web3signer: `eth_accounts` and
+/// `eth_signTypedData`.
+#[derive(Debug)]
+pub struct Web3Signer {
+    http_client: reqwest::Client,
+    url: Url,
+}
+
+impl Web3Signer {
+    pub fn new(http_client: reqwest::Client, url: Url) -> Self {
+        Self { http_client, url }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        #[derive(Deserialize)]
+        struct Response<T> {
+            result: Option<T>,
+            error: Option<Value>,
+        }
+
+        let response: Response<T> = self
+            .http_client
+            .post(self.url.clone())
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(anyhow!("`{}` failed: {}", method, error)),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(anyhow!("`{}` returned no result", method)),
+        }
+    }
+}
+
+#[async_trait]
+impl AttestationSignerBackend for Web3Signer {
+    async fn addresses(&self) -> Result<HashSet<Address>> {
+        self.call("eth_accounts", json!([])).await
+    }
+
+    async fn sign(
+        &self,
+        address: Address,
+        domain: &Eip712Domain,
+        receipt: &Receipt,
+    ) -> Result<Signature> {
+        let signature: Bytes = self
+            .call(
+                "eth_signTypedData",
+                json!([address, typed_data(domain, receipt)]),
+            )
+            .await?;
+        Ok(Signature::try_from(signature.as_ref())?)
+    }
+}
+
+/// The EIP-712 typed data of `receipt` in `domain`, as `eth_signTypedData` expects it.
+fn typed_data(domain: &Eip712Domain, receipt: &Receipt) -> Value {
+    let mut domain_types = Vec::new();
+    let mut domain_values = serde_json::Map::new();
+    if let Some(name) = &domain.name {
+        domain_types.push(json!({ "name": "name", "type": "string" }));
+        domain_values.insert("name".into(), json!(name));
+    }
+    if let Some(version) = &domain.version {
+        domain_types.push(json!({ "name": "version", "type": "string" }));
+        domain_values.insert("version".into(), json!(version));
+    }
+    if let Some(chain_id) = &domain.chain_id {
+        domain_types.push(json!({ "name": "chainId", "type": "uint256" }));
+        domain_values.insert("chainId".into(), json!(chain_id.to_string()));
+    }
+    if let Some(verifying_contract) = &domain.verifying_contract {
+        domain_types.push(json!({ "name": "verifyingContract", "type": "address" }));
+        domain_values.insert("verifyingContract".into(), json!(verifying_contract));
+    }
+    if let Some(salt) = &domain.salt {
+        domain_types.push(json!({ "name": "salt", "type": "bytes32" }));
+        domain_values.insert("salt".into(), json!(salt));
+    }
+
+    json!({
+        "types": {
+            "EIP712Domain": domain_types,
+            "Receipt": [
+                { "name": "requestCID", "type": "bytes32" },
+                { "name": "responseCID", "type": "bytes32" },
+                { "name": "subgraphDeploymentID", "type": "bytes32" },
+            ],
+        },
+        "primaryType": "Receipt",
+        "domain": domain_values,
+        "message": {
+            "requestCID": receipt.requestCID,
+            "responseCID": receipt.responseCID,
+            "subgraphDeploymentID": receipt.subgraphDeploymentID,
+        },
+    })
+}
@@ -5,11 +5,24 @@ use crate::subgraph_client::SubgraphClient;
 use alloy::primitives::Address;
 use anyhow::Error;
 use graphql_client::GraphQLQuery;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
 use std::time::Duration;
 use tokio::sync::watch::{self, Receiver};
-use tokio::time::{self, sleep};
+use tokio::time::sleep;
 use tracing::warn;
 
+lazy_static! {
+    static ref QUERY_FAILURES: IntCounter = register_int_counter!(
+        "indexer_dispute_manager_query_failures_total",
+        "Failed queries of the dispute manager to the network subgraph"
+    )
+    .unwrap();
+}
+
+/// First retry after a failed query, doubled on each failure up to the interval.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 type Bytes = Address;
 
 #[derive(GraphQLQuery)]
@@ -21,17 +34,17 @@ type Bytes = Address;
 )]
 struct DisputeManager;
 
+/// The dispute manager of the network, queried every `interval`. `None` until the first query
+/// succeeded, the failed ones are retried with an exponential backoff. Only notified when it
+/// changes.
 pub fn dispute_manager(
     network_subgraph: &'static SubgraphClient,
     interval: Duration,
 ) -> Receiver<Option<Address>> {
     let (tx, rx) = watch::channel(None);
     tokio::spawn(async move {
-        let mut time_interval = time::interval(interval);
-        time_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        let mut retry_interval = MIN_RETRY_INTERVAL;
         loop {
-            time_interval.tick().await;
-
             let result = async {
                 let response = network_subgraph
                     .query::<DisputeManager, _>(dispute_manager::Variables {})
@@ -44,13 +57,21 @@ pub fn dispute_manager(
             .await;
 
             match result {
-                Ok(address) => tx
-                    .send(Some(address))
-                    .expect("Failed to update dispute_manager channel"),
+                Ok(address) => {
+                    tx.send_if_modified(|dispute_manager| {
+                        dispute_manager.replace(address) != Some(address)
+                    });
+                    retry_interval = MIN_RETRY_INTERVAL;
+                    sleep(interval).await;
+                }
                 Err(err) => {
-                    warn!("Failed to query dispute manager for network: {}", err);
-                    // Sleep for a bit before we retry
-                    sleep(interval.div_f32(2.0)).await;
+                    QUERY_FAILURES.inc();
+                    warn!(
+                        retry_in_secs = retry_interval.as_secs(),
+                        "Failed to query dispute manager for network: {}", err
+                    );
+                    sleep(retry_interval).await;
+                    retry_interval = (retry_interval * 2).min(interval);
                 }
             }
         }
@@ -114,4 +135,37 @@ mod test {
         let result = *dispute_manager.borrow();
         assert_eq!(result.unwrap(), *DISPUTE_MANAGER_ADDRESS);
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_retries_failed_queries() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .respond_with(ResponseTemplate::new(500))
+                    .up_to_n_times(1)
+                    .with_priority(1),
+            )
+            .await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    json!({ "data": { "graphNetwork": { "disputeManager": *DISPUTE_MANAGER_ADDRESS }}}),
+                ),
+            ))
+            .await;
+        let network_subgraph = Box::leak(Box::new(SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )));
+
+        // Way before the interval
+        let mut dispute_manager = dispute_manager(network_subgraph, Duration::from_secs(3600));
+        tokio::time::timeout(Duration::from_secs(5), dispute_manager.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*dispute_manager.borrow(), Some(*DISPUTE_MANAGER_ADDRESS));
+    }
 }
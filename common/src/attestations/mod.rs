@@ -1,6 +1,8 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod backend;
 pub mod dispute_manager;
 pub mod signer;
 pub mod signers;
+pub mod verifier;
@@ -1,15 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{fmt, sync::Arc};
+
 use alloy::{
     dyn_abi::Eip712Domain,
-    signers::{
-        k256,
-        local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
-    },
+    primitives::{keccak256, Signature, B256},
+    signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+    sol_types::SolStruct,
 };
 use thegraph_core::{attestation, Address, Attestation, ChainId, DeploymentId};
 
+use super::backend::{AttestationSignerBackend, LocalSigner, Receipt};
 use crate::prelude::Allocation;
 
 pub fn derive_key_pair(
@@ -37,14 +39,37 @@ pub fn derive_key_pair(
         .build()?)
 }
 
-/// An attestation signer tied to a specific allocation via its signer key
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// An attestation signer tied to a specific allocation via its signer key, signing with the
+/// [`AttestationSignerBackend`] holding the key
+#[derive(Clone)]
 pub struct AttestationSigner {
     deployment: DeploymentId,
     domain: Eip712Domain,
-    signer: k256::ecdsa::SigningKey,
+    address: Address,
+    backend: Arc<dyn AttestationSignerBackend>,
+}
+
+impl fmt::Debug for AttestationSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttestationSigner")
+            .field("deployment", &self.deployment)
+            .field("domain", &self.domain)
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Two signers are the same if they sign for the same allocation, whatever their backend
+impl PartialEq for AttestationSigner {
+    fn eq(&self, other: &Self) -> bool {
+        self.deployment == other.deployment
+            && self.domain == other.domain
+            && self.address == other.address
+    }
 }
 
+impl Eq for AttestationSigner {}
+
 impl AttestationSigner {
     pub fn new(
         indexer_mnemonic: &str,
@@ -55,16 +80,81 @@ impl AttestationSigner {
         // Recreate a wallet that has the same address as the allocation
         let wallet = wallet_for_allocation(indexer_mnemonic, allocation)?;
 
-        Ok(Self {
+        Ok(Self::from_wallet(
+            wallet,
+            allocation,
+            chain_id,
+            dispute_manager,
+        ))
+    }
+
+    /// A signer for the allocation with its key, already derived.
+    pub fn from_wallet(
+        wallet: PrivateKeySigner,
+        allocation: &Allocation,
+        chain_id: ChainId,
+        dispute_manager: Address,
+    ) -> Self {
+        Self {
             deployment: allocation.subgraph_deployment.id,
             domain: attestation::eip712_domain(chain_id, dispute_manager),
-            signer: wallet.into_credential(),
-        })
+            address: wallet.address(),
+            backend: Arc::new(LocalSigner(wallet)),
+        }
+    }
+
+    /// A signer for the allocation whose key is held by `backend`, see
+    /// [`super::backend`]. Nothing is derived from the operator mnemonic.
+    pub fn with_backend(
+        backend: Arc<dyn AttestationSignerBackend>,
+        allocation: &Allocation,
+        chain_id: ChainId,
+        dispute_manager: Address,
+    ) -> Self {
+        Self {
+            deployment: allocation.subgraph_deployment.id,
+            domain: attestation::eip712_domain(chain_id, dispute_manager),
+            address: allocation.id,
+            backend,
+        }
+    }
+
+    /// The same signer, in the domain of another dispute manager.
+    pub fn with_dispute_manager(&self, chain_id: ChainId, dispute_manager: Address) -> Self {
+        Self {
+            domain: attestation::eip712_domain(chain_id, dispute_manager),
+            ..self.clone()
+        }
     }
 
-    pub fn create_attestation(&self, request: &str, response: &str) -> Attestation {
-        let wallet = PrivateKeySigner::from_signing_key(self.signer.clone());
-        attestation::create(&self.domain, &wallet, &self.deployment, request, response)
+    /// The address of the key signing the attestations, the allocation id.
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub async fn create_attestation(
+        &self,
+        request: &str,
+        response: &str,
+    ) -> Result<Attestation, anyhow::Error> {
+        let receipt = Receipt {
+            requestCID: keccak256(request),
+            responseCID: keccak256(response),
+            subgraphDeploymentID: B256::from(self.deployment),
+        };
+        let signature = self
+            .backend
+            .sign(self.address, &self.domain, &receipt)
+            .await?;
+
+        Ok(Attestation {
+            request_cid: receipt.requestCID,
+            response_cid: receipt.responseCID,
+            deployment: receipt.subgraphDeploymentID,
+            r: signature.r().into(),
+            s: signature.s().into(),
+            v: 27 + signature.v().y_parity_byte(),
+        })
     }
 
     pub fn verify(
@@ -82,9 +172,32 @@ impl AttestationSigner {
             response,
         )
     }
+
+    /// Whether `attestation` was signed by this signer, for its deployment, whatever the request
+    /// and the response. Binds the receipts relayed by a peer to a query it served, see
+    /// [`crate::indexer_service::http::peer_relay`].
+    pub fn attests(&self, attestation: &Attestation) -> bool {
+        if attestation.deployment != B256::from(self.deployment) {
+            return false;
+        }
+        let receipt = Receipt {
+            requestCID: attestation.request_cid,
+            responseCID: attestation.response_cid,
+            subgraphDeploymentID: attestation.deployment,
+        };
+        let mut signature = [0u8; 65];
+        signature[..32].copy_from_slice(attestation.r.as_slice());
+        signature[32..64].copy_from_slice(attestation.s.as_slice());
+        signature[64] = attestation.v;
+        Signature::try_from(&signature[..])
+            .and_then(|signature| {
+                signature.recover_address_from_prehash(&receipt.eip712_signing_hash(&self.domain))
+            })
+            .is_ok_and(|signer| signer == self.address)
+    }
 }
 
-fn wallet_for_allocation(
+pub(super) fn wallet_for_allocation(
     indexer_mnemonic: &str,
     allocation: &Allocation,
 ) -> Result<PrivateKeySigner, anyhow::Error> {
@@ -189,16 +302,14 @@ mod tests {
             query_fees_collected: None,
         };
         assert_eq!(
-            PrivateKeySigner::from_signing_key(
-                AttestationSigner::new(
-                    INDEXER_OPERATOR_MNEMONIC,
-                    &allocation,
-                    1,
-                    *DISPUTE_MANAGER_ADDRESS
-                )
-                .unwrap()
-                .signer
-            ),
+            AttestationSigner::new(
+                INDEXER_OPERATOR_MNEMONIC,
+                &allocation,
+                1,
+                *DISPUTE_MANAGER_ADDRESS
+            )
+            .unwrap()
+            .address(),
             derive_key_pair(
                 INDEXER_OPERATOR_MNEMONIC,
                 940,
@@ -206,6 +317,71 @@ mod tests {
                 2
             )
             .unwrap()
+            .address()
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_create_attestation() {
+        let allocation = Allocation {
+            id: Address::from_str("0xa171cd12c3dde7eb8fe7717a0bcd06f3ffa65658").unwrap(),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::ZERO,
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            closed_at_epoch: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        // Signed through the backend, the same as signed by `attestation::create`
+        let attestation = signer
+            .create_attestation("request", "response")
+            .await
+            .unwrap();
+        signer
+            .verify(&attestation, "request", "response", &allocation.id)
+            .unwrap();
+        assert!(signer.attests(&attestation));
+        let tampered = Attestation {
+            response_cid: keccak256("other"),
+            ..attestation.clone()
+        };
+        assert!(!signer.attests(&tampered));
+        let wallet = derive_key_pair(
+            INDEXER_OPERATOR_MNEMONIC,
+            940,
+            &allocation.subgraph_deployment.id,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            attestation,
+            attestation::create(
+                &signer.domain,
+                &wallet,
+                &allocation.subgraph_deployment.id,
+                "request",
+                "response"
+            )
         );
     }
 
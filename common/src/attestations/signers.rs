@@ -1,30 +1,201 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use alloy::{
+    primitives::{keccak256, B256},
+    signers::local::PrivateKeySigner,
+};
 use eventuals::{Eventual, EventualExt};
-use std::collections::HashMap;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter, register_int_gauge_vec, IntCounter, IntGaugeVec};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::Duration;
 use thegraph_core::{Address, ChainId};
 use tokio::{
     select,
     sync::{
-        watch::{self, Receiver},
-        Mutex,
+        watch::{self, error::RecvError, Receiver},
+        Mutex, Semaphore,
     },
+    task::JoinSet,
+    time::{sleep_until, Instant},
 };
-use tracing::warn;
+use tracing::{info, warn};
 
+use super::{backend::AttestationSignerBackend, signer::wallet_for_allocation};
 use crate::prelude::{Allocation, AttestationSigner};
 
-/// An always up-to-date list of attestation signers, one for each of the indexer's allocations.
+/// Allocation keys derived at the same time, on the blocking threads.
+const MAX_PARALLEL_DERIVATIONS: usize = 8;
+
+/// Allocation keys cached per network, by the hash of their mnemonic and their allocation id.
+const DERIVED_WALLETS_CACHE_SIZE: usize = 4096;
+
+type DerivedWallets = LruCache<(B256, Address), Option<PrivateKeySigner>>;
+
+/// Delays of the retries while the backend fails or misses allocation keys, doubled after each
+/// attempt.
+const MIN_BACKEND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BACKEND_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    static ref SIGNERS_CREATED: IntCounter = register_int_counter!(
+        "indexer_attestation_signers_created_total",
+        "Attestation signers created for the allocations"
+    )
+    .unwrap();
+    static ref SIGNERS_FAILED: IntCounter = register_int_counter!(
+        "indexer_attestation_signers_failed_total",
+        "Attestation signers that could not be created for an allocation, on each update"
+    )
+    .unwrap();
+    static ref SIGNERS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_attestation_signers",
+        "Attestation signers of the active and recently closed allocations of the network",
+        &["chain_id"]
+    )
+    .unwrap();
+    static ref DEGRADED: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_attestation_signers_degraded",
+        "Whether the dispute manager of the network is unknown, so that the signers of its new \
+        allocations can't be created",
+        &["chain_id"]
+    )
+    .unwrap();
+}
+
+/// The allocations of the indexer on a protocol network, and the dispute manager of the network.
+/// Both go in the EIP-712 domain of their attestations.
+pub struct ProtocolNetwork {
+    pub allocations: Eventual<HashMap<Address, Allocation>>,
+    pub dispute_manager: Receiver<Option<Address>>,
+}
+
+/// Where the keys of the allocations come from.
+#[derive(Clone)]
+pub enum AllocationKeys {
+    /// Derived from the operator mnemonic, rotated through the receiver, see [`crate::secrets`].
+    Mnemonic(Receiver<String>),
+    /// Held by a signer backend, nothing is derived in the process, see [`super::backend`].
+    Backend(Arc<dyn AttestationSignerBackend>),
+}
+
+impl AllocationKeys {
+    /// Resolves when the mnemonic is rotated, never with a backend.
+    async fn rotated(&mut self) -> Result<(), RecvError> {
+        match self {
+            AllocationKeys::Mnemonic(mnemonic) => mnemonic.changed().await,
+            AllocationKeys::Backend(_) => std::future::pending().await,
+        }
+    }
+}
+
+/// The attestation signers of the allocations on all the protocol networks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignersState {
+    Ready(HashMap<Address, AttestationSigner>),
+    /// The dispute manager of the `networks` is unknown, it couldn't be fetched from the network
+    /// subgraph yet. The signers of their new allocations can't be created, these are listed in
+    /// the allocations missing a signer.
+    Degraded {
+        signers: HashMap<Address, AttestationSigner>,
+        networks: BTreeSet<ChainId>,
+    },
+}
+
+impl Default for SignersState {
+    fn default() -> Self {
+        SignersState::Ready(HashMap::new())
+    }
+}
+
+impl SignersState {
+    pub fn signers(&self) -> &HashMap<Address, AttestationSigner> {
+        match self {
+            SignersState::Ready(signers) | SignersState::Degraded { signers, .. } => signers,
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        matches!(self, SignersState::Degraded { .. })
+    }
+
+    fn update(
+        &mut self,
+        f: impl FnOnce(&mut HashMap<Address, AttestationSigner>, &mut BTreeSet<ChainId>),
+    ) {
+        let (mut signers, mut networks) = match std::mem::take(self) {
+            SignersState::Ready(signers) => (signers, BTreeSet::new()),
+            SignersState::Degraded { signers, networks } => (signers, networks),
+        };
+        f(&mut signers, &mut networks);
+        *self = if networks.is_empty() {
+            SignersState::Ready(signers)
+        } else {
+            SignersState::Degraded { signers, networks }
+        };
+    }
+}
+
+/// An always up-to-date list of attestation signers, one for each of the indexer's allocations
+/// on all the `networks`, by chain id.
+///
+/// The signers of a network are rebuilt when its dispute manager changes. While it's unknown,
+/// the state is [`SignersState::Degraded`].
+///
+/// When the mnemonic of `keys` is rotated, the signers of the new allocations are created with
+/// the new mnemonic, see [`crate::secrets`]. The allocations created with the previous one keep
+/// their signers until they're closed, their keys can't be derived from the new one. With a
+/// backend, the signers of the allocations it
+/// holds the key of sign with it instead. While it fails, or misses the key of an allocation, it's
+/// asked again with an exponential backoff, up to [`MAX_BACKEND_RETRY_INTERVAL`].
+///
+/// Also returns the allocations missing a signer, whose key couldn't be found, so that their
+/// queries can be refused rather than answered without an attestation.
 pub async fn attestation_signers(
-    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
-    indexer_mnemonic: String,
+    networks: HashMap<ChainId, ProtocolNetwork>,
+    keys: AllocationKeys,
+) -> (Receiver<SignersState>, Receiver<HashSet<Address>>) {
+    let (signers_tx, mut signers_rx) = watch::channel(SignersState::default());
+    let (missing_tx, mut missing_rx) = watch::channel(HashSet::new());
+    let signers_tx = Arc::new(signers_tx);
+    let missing_tx = Arc::new(missing_tx);
+    for (chain_id, network) in networks {
+        watch_network(
+            chain_id,
+            network,
+            keys.clone(),
+            signers_tx.clone(),
+            missing_tx.clone(),
+        )
+        .await;
+    }
+    // The signers of all the networks are there
+    signers_rx.borrow_and_update();
+    missing_rx.borrow_and_update();
+
+    (signers_rx, missing_rx)
+}
+
+/// Creates the signers of the allocations of a network, then keeps them up to date.
+async fn watch_network(
     chain_id: ChainId,
-    mut dispute_manager_rx: Receiver<Option<Address>>,
-) -> Receiver<HashMap<Address, AttestationSigner>> {
+    ProtocolNetwork {
+        allocations: indexer_allocations,
+        dispute_manager: mut dispute_manager_rx,
+    }: ProtocolNetwork,
+    mut keys: AllocationKeys,
+    signers_tx: Arc<watch::Sender<SignersState>>,
+    missing_tx: Arc<watch::Sender<HashSet<Address>>>,
+) {
     let attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>> =
         Box::leak(Box::new(Mutex::new(HashMap::new())));
+    let derived_wallets = std::sync::Mutex::new(LruCache::new(
+        NonZeroUsize::new(DERIVED_WALLETS_CACHE_SIZE).unwrap(),
+    ));
 
     // Actively listening to indexer_allocations to update allocations channel
     // Temporary fix until the indexer_allocations is migrated to tokio watch
@@ -38,35 +209,102 @@ pub async fn attestation_signers(
         })
         .forever();
 
-    let starter_signers_map = modify_sigers(
-        Arc::new(indexer_mnemonic.clone()),
+    // The dispute manager of the signers in the map
+    let mut signers_dispute_manager = *dispute_manager_rx.borrow_and_update();
+    if let AllocationKeys::Mnemonic(mnemonic) = &mut keys {
+        mnemonic.borrow_and_update();
+    }
+    let (starter_signers_map, mut backend_incomplete) = modify_sigers(
+        &keys,
         chain_id,
         attestation_signers_map,
+        &derived_wallets,
         allocations_rx.clone(),
         dispute_manager_rx.clone(),
     )
     .await;
+    let mut backend_retry_interval = MIN_BACKEND_RETRY_INTERVAL;
+    // Allocations of the network, in the channels
+    let mut allocation_ids = HashSet::new();
+    let mut degraded = signers_dispute_manager.is_none();
+    DEGRADED
+        .with_label_values(&[&chain_id.to_string()])
+        .set(degraded as i64);
+    publish(
+        chain_id,
+        &signers_tx,
+        &missing_tx,
+        &mut allocation_ids,
+        &allocations_rx.borrow(),
+        starter_signers_map,
+        degraded,
+    );
 
     // Whenever the indexer's active or recently closed allocations change, make sure
     // we have attestation signers for all of them.
-    let (signers_tx, signers_rx) = watch::channel(starter_signers_map);
     tokio::spawn(async move {
         loop {
-            let updated_signers = select! {
+            let retry_at = Instant::now() + backend_retry_interval;
+            let (updated_signers, incomplete) = select! {
                 Ok(())= allocations_rx.changed() =>{
                     modify_sigers(
-                        Arc::new(indexer_mnemonic.clone()),
+                        &keys,
                         chain_id,
                         attestation_signers_map,
+                        &derived_wallets,
                         allocations_rx.clone(),
                         dispute_manager_rx.clone(),
                     ).await
                 },
                 Ok(())= dispute_manager_rx.changed() =>{
+                    let dispute_manager = *dispute_manager_rx.borrow_and_update();
+                    if let Some(new_dispute_manager) =
+                        dispute_manager.filter(|_| dispute_manager != signers_dispute_manager)
+                    {
+                        // The domain of the signers of the previous one is outdated, their keys
+                        // are kept, they may come from a previous mnemonic
+                        info!(
+                            chain_id,
+                            ?dispute_manager,
+                            "Dispute manager changed, rebuilding the attestation signers"
+                        );
+                        for signer in attestation_signers_map.lock().await.values_mut() {
+                            *signer = signer.with_dispute_manager(chain_id, new_dispute_manager);
+                        }
+                        signers_dispute_manager = dispute_manager;
+                    }
+                    modify_sigers(
+                        &keys,
+                        chain_id,
+                        attestation_signers_map,
+                        &derived_wallets,
+                        allocations_rx.clone(),
+                        dispute_manager_rx.clone()
+                    ).await
+                },
+                Ok(())= keys.rotated() =>{
+                    // The signers of the previous mnemonic are kept, and the allocations missing
+                    // one are derived again
+                    info!(
+                        chain_id,
+                        "Operator mnemonic rotated, creating the attestation signers of the new \
+                        allocations with it"
+                    );
+                    modify_sigers(
+                        &keys,
+                        chain_id,
+                        attestation_signers_map,
+                        &derived_wallets,
+                        allocations_rx.clone(),
+                        dispute_manager_rx.clone()
+                    ).await
+                },
+                () = sleep_until(retry_at), if backend_incomplete =>{
                     modify_sigers(
-                        Arc::new(indexer_mnemonic.clone()),
+                        &keys,
                         chain_id,
                         attestation_signers_map,
+                        &derived_wallets,
                         allocations_rx.clone(),
                         dispute_manager_rx.clone()
                     ).await
@@ -76,47 +314,276 @@ pub async fn attestation_signers(
                     panic!("dispute_manager_rx or allocations_rx was dropped");
                 }
             };
-            signers_tx
-                .send(updated_signers)
-                .expect("Failed to update signers channel");
+            backend_retry_interval = match (incomplete, backend_incomplete) {
+                (false, _) => MIN_BACKEND_RETRY_INTERVAL,
+                (true, false) => backend_retry_interval,
+                (true, true) => (backend_retry_interval * 2).min(MAX_BACKEND_RETRY_INTERVAL),
+            };
+            backend_incomplete = incomplete;
+            let was_degraded =
+                std::mem::replace(&mut degraded, dispute_manager_rx.borrow().is_none());
+            report_degraded(chain_id, degraded, was_degraded);
+            publish(
+                chain_id,
+                &signers_tx,
+                &missing_tx,
+                &mut allocation_ids,
+                &allocations_rx.borrow(),
+                updated_signers,
+                degraded,
+            );
         }
     });
+}
 
-    signers_rx
+/// Warns on every update while the dispute manager is unknown, the allocations without a
+/// signer then have their queries refused.
+fn report_degraded(chain_id: ChainId, degraded: bool, was_degraded: bool) {
+    DEGRADED
+        .with_label_values(&[&chain_id.to_string()])
+        .set(degraded as i64);
+    if degraded {
+        warn!(
+            chain_id,
+            "The dispute manager of the network is still unknown, the attestation signers of its \
+            new allocations can't be created"
+        );
+    } else if was_degraded {
+        info!(
+            chain_id,
+            "Dispute manager of the network known, attestation signers created"
+        );
+    }
 }
+
+/// Replaces the signers of the allocations of a network, `allocation_ids` until now, in the ones
+/// of all the networks.
+fn publish(
+    chain_id: ChainId,
+    signers_tx: &watch::Sender<SignersState>,
+    missing_tx: &watch::Sender<HashSet<Address>>,
+    allocation_ids: &mut HashSet<Address>,
+    allocations: &HashMap<Address, Allocation>,
+    signers: HashMap<Address, AttestationSigner>,
+    degraded: bool,
+) {
+    let missing: HashSet<Address> = allocations
+        .keys()
+        .filter(|id| !signers.contains_key(id))
+        .copied()
+        .collect();
+    missing_tx.send_if_modified(|all_missing| {
+        let previous = all_missing.clone();
+        all_missing.retain(|id| !allocation_ids.contains(id));
+        all_missing.extend(missing);
+        *all_missing != previous
+    });
+    let previous_ids = std::mem::replace(
+        allocation_ids,
+        allocations.keys().chain(signers.keys()).copied().collect(),
+    );
+    signers_tx.send_modify(|state| {
+        state.update(|all_signers, degraded_networks| {
+            all_signers.retain(|id, _| !previous_ids.contains(id));
+            all_signers.extend(signers);
+            if degraded {
+                degraded_networks.insert(chain_id);
+            } else {
+                degraded_networks.remove(&chain_id);
+            }
+        })
+    });
+}
+
+/// Also returns whether the backend failed or missed the key of an allocation, to be asked again.
 async fn modify_sigers(
-    indexer_mnemonic: Arc<String>,
+    keys: &AllocationKeys,
     chain_id: ChainId,
     attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>>,
+    derived_wallets: &std::sync::Mutex<DerivedWallets>,
     allocations_rx: Receiver<HashMap<Address, Allocation>>,
     dispute_manager_rx: Receiver<Option<Address>>,
-) -> HashMap<thegraph_core::Address, AttestationSigner> {
+) -> (HashMap<thegraph_core::Address, AttestationSigner>, bool) {
     let mut signers = attestation_signers_map.lock().await;
     let allocations = allocations_rx.borrow().clone();
     let Some(dispute_manager) = *dispute_manager_rx.borrow() else {
-        return signers.clone();
+        return (signers.clone(), false);
+    };
+    // Remove signers for allocations that are no longer active or recently closed, and their
+    // keys
+    let mut closed = HashSet::new();
+    signers.retain(|id, _| {
+        let active = allocations.contains_key(id);
+        if !active {
+            closed.insert(*id);
+        }
+        active
+    });
+    forget_wallets(derived_wallets, |(_, id)| closed.contains(id));
+    SIGNERS
+        .with_label_values(&[&chain_id.to_string()])
+        .set(signers.len() as i64);
+
+    let indexer_mnemonic = match keys {
+        AllocationKeys::Mnemonic(mnemonic) => Arc::new(mnemonic.borrow().clone()),
+        AllocationKeys::Backend(backend) => {
+            let new_allocations: Vec<_> = allocations
+                .values()
+                .filter(|allocation| !signers.contains_key(&allocation.id))
+                .collect();
+            if new_allocations.is_empty() {
+                return (signers.clone(), false);
+            }
+            let addresses = match backend.addresses().await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    warn!(
+                        "Failed to list the keys of the attestation signer backend: {}",
+                        e
+                    );
+                    SIGNERS_FAILED.inc_by(new_allocations.len() as u64);
+                    return (signers.clone(), true);
+                }
+            };
+            let mut incomplete = false;
+            for allocation in new_allocations {
+                if addresses.contains(&allocation.id) {
+                    signers.insert(
+                        allocation.id,
+                        AttestationSigner::with_backend(
+                            backend.clone(),
+                            allocation,
+                            chain_id,
+                            dispute_manager,
+                        ),
+                    );
+                    SIGNERS_CREATED.inc();
+                } else {
+                    warn!(
+                        "The attestation signer backend has no key for allocation {}, \
+                        deployment {}",
+                        allocation.id, allocation.subgraph_deployment.id
+                    );
+                    SIGNERS_FAILED.inc();
+                    incomplete = true;
+                }
+            }
+            SIGNERS
+                .with_label_values(&[&chain_id.to_string()])
+                .set(signers.len() as i64);
+            return (signers.clone(), incomplete);
+        }
     };
-    // Remove signers for allocations that are no longer active or recently closed
-    signers.retain(|id, _| allocations.contains_key(id));
-
-    // Create signers for new allocations
-    for (id, allocation) in allocations.iter() {
-        if !signers.contains_key(id) {
-            let signer =
-                AttestationSigner::new(&indexer_mnemonic, allocation, chain_id, dispute_manager);
-            if let Err(e) = signer {
+
+    // Create signers for new allocations, their keys derived in parallel
+    let new_allocations = allocations
+        .values()
+        .filter(|allocation| !signers.contains_key(&allocation.id))
+        .cloned()
+        .collect();
+    let wallets = derive_wallets(derived_wallets, indexer_mnemonic, new_allocations).await;
+    for (id, wallet) in wallets {
+        let Some(allocation) = allocations.get(&id) else {
+            continue;
+        };
+        match wallet {
+            Some(wallet) => {
+                signers.insert(
+                    id,
+                    AttestationSigner::from_wallet(wallet, allocation, chain_id, dispute_manager),
+                );
+                SIGNERS_CREATED.inc();
+            }
+            None => SIGNERS_FAILED.inc(),
+        }
+    }
+    SIGNERS
+        .with_label_values(&[&chain_id.to_string()])
+        .set(signers.len() as i64);
+
+    (signers.clone(), false)
+}
+
+/// The keys of `allocations`, derived from `indexer_mnemonic` at most
+/// [`MAX_PARALLEL_DERIVATIONS`] at a time, `None` for the allocations that aren't one of its.
+///
+/// Finding the key of an allocation takes up to 200 derivations, so they're cached in
+/// `derived_wallets`, even when no key matched: an allocation is only derived again after a
+/// rotation of the mnemonic or once evicted. The keys of a previous mnemonic are dropped from it.
+async fn derive_wallets(
+    derived_wallets: &std::sync::Mutex<DerivedWallets>,
+    indexer_mnemonic: Arc<String>,
+    allocations: Vec<Allocation>,
+) -> HashMap<Address, Option<PrivateKeySigner>> {
+    let mnemonic_hash = keccak256(indexer_mnemonic.as_bytes());
+    forget_wallets(derived_wallets, |(hash, _)| *hash != mnemonic_hash);
+    let mut wallets = HashMap::new();
+    let mut to_derive = Vec::new();
+    {
+        let mut cache = derived_wallets.lock().unwrap();
+        for allocation in allocations {
+            match cache.get(&(mnemonic_hash, allocation.id)) {
+                Some(wallet) => {
+                    wallets.insert(allocation.id, wallet.clone());
+                }
+                None => to_derive.push(allocation),
+            }
+        }
+    }
+
+    let permits = Arc::new(Semaphore::new(MAX_PARALLEL_DERIVATIONS));
+    let mut derivations = JoinSet::new();
+    for allocation in to_derive {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore isn't closed");
+        let indexer_mnemonic = indexer_mnemonic.clone();
+        derivations.spawn_blocking(move || {
+            let _permit = permit;
+            let wallet = wallet_for_allocation(&indexer_mnemonic, &allocation);
+            (allocation, wallet)
+        });
+    }
+    while let Some(derivation) = derivations.join_next().await {
+        let Ok((allocation, wallet)) = derivation else {
+            continue;
+        };
+        let wallet = match wallet {
+            Ok(wallet) => Some(wallet),
+            Err(e) => {
                 warn!(
                     "Failed to establish signer for allocation {}, deployment {}, createdAtEpoch {}: {}",
                     allocation.id, allocation.subgraph_deployment.id,
                     allocation.created_at_epoch, e
                 );
-            } else {
-                signers.insert(*id, signer.unwrap());
+                None
             }
-        }
+        };
+        derived_wallets
+            .lock()
+            .unwrap()
+            .put((mnemonic_hash, allocation.id), wallet.clone());
+        wallets.insert(allocation.id, wallet);
     }
+    wallets
+}
 
-    signers.clone()
+/// Drops the cached keys matching `forget`.
+fn forget_wallets(
+    derived_wallets: &std::sync::Mutex<DerivedWallets>,
+    forget: impl Fn(&(B256, Address)) -> bool,
+) {
+    let mut cache = derived_wallets.lock().unwrap();
+    let forgotten: Vec<_> = cache
+        .iter()
+        .map(|(key, _)| *key)
+        .filter(|key| forget(key))
+        .collect();
+    for key in forgotten {
+        cache.pop(&key);
+    }
 }
 
 #[cfg(test)]
@@ -125,6 +592,10 @@ mod tests {
         DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_OPERATOR_MNEMONIC,
     };
 
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
     use super::*;
 
     #[tokio::test]
@@ -134,24 +605,27 @@ mod tests {
         dispute_manager_tx
             .send(Some(*DISPUTE_MANAGER_ADDRESS))
             .unwrap();
-        let mut signers = attestation_signers(
-            allocations,
-            (*INDEXER_OPERATOR_MNEMONIC).to_string(),
+        let (_, mnemonic) = watch::channel((*INDEXER_OPERATOR_MNEMONIC).to_string());
+        let networks = HashMap::from([(
             1,
-            dispute_manager_rx,
-        )
-        .await;
+            ProtocolNetwork {
+                allocations,
+                dispute_manager: dispute_manager_rx,
+            },
+        )]);
+        let (mut signers, _) =
+            attestation_signers(networks, AllocationKeys::Mnemonic(mnemonic)).await;
 
         // Test that an empty set of allocations leads to an empty set of signers
         allocations_writer.write(HashMap::new());
         signers.changed().await.unwrap();
-        let latest_signers = signers.borrow().clone();
+        let latest_signers = signers.borrow().signers().clone();
         assert_eq!(latest_signers, HashMap::new());
 
         // Test that writing our set of test allocations results in corresponding signers for all of them
         allocations_writer.write((*INDEXER_ALLOCATIONS).clone());
         signers.changed().await.unwrap();
-        let latest_signers = signers.borrow().clone();
+        let latest_signers = signers.borrow().signers().clone();
         assert_eq!(latest_signers.len(), INDEXER_ALLOCATIONS.len());
 
         for signer_allocation_id in latest_signers.keys() {
@@ -160,4 +634,265 @@ mod tests {
                 .any(|allocation_id| signer_allocation_id == allocation_id));
         }
     }
+
+    #[tokio::test]
+    async fn test_attestation_signers_of_each_network() {
+        // Every other allocation on each network
+        let (allocations_1, allocations_2): (Vec<_>, Vec<_>) = INDEXER_ALLOCATIONS
+            .values()
+            .cloned()
+            .enumerate()
+            .partition(|(i, _)| i % 2 == 0);
+        let dispute_manager_2 = Address::repeat_byte(0x22);
+        let network = |allocations: Vec<(usize, Allocation)>, dispute_manager| ProtocolNetwork {
+            allocations: Eventual::from_value(
+                allocations
+                    .into_iter()
+                    .map(|(_, allocation)| (allocation.id, allocation))
+                    .collect(),
+            ),
+            dispute_manager: watch::channel(Some(dispute_manager)).1,
+        };
+        let networks = HashMap::from([
+            (1, network(allocations_1, *DISPUTE_MANAGER_ADDRESS)),
+            (42161, network(allocations_2, dispute_manager_2)),
+        ]);
+        let (_, mnemonic) = watch::channel((*INDEXER_OPERATOR_MNEMONIC).to_string());
+        let (signers, missing) =
+            attestation_signers(networks, AllocationKeys::Mnemonic(mnemonic)).await;
+
+        let signers = signers.borrow().signers().clone();
+        assert_eq!(signers.len(), INDEXER_ALLOCATIONS.len());
+        assert!(missing.borrow().is_empty());
+        for (i, (id, allocation)) in INDEXER_ALLOCATIONS.iter().enumerate() {
+            let (chain_id, dispute_manager) = if i % 2 == 0 {
+                (1, *DISPUTE_MANAGER_ADDRESS)
+            } else {
+                (42161, dispute_manager_2)
+            };
+            let expected = AttestationSigner::new(
+                &INDEXER_OPERATOR_MNEMONIC,
+                allocation,
+                chain_id,
+                dispute_manager,
+            )
+            .unwrap();
+            assert_eq!(signers[id], expected);
+        }
+        assert_eq!(
+            SIGNERS.with_label_values(&["42161"]).get() as usize,
+            INDEXER_ALLOCATIONS.len() / 2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attestation_signers_degraded_without_dispute_manager() {
+        let (dispute_manager_tx, dispute_manager_rx) = watch::channel(None);
+        let (_, mnemonic) = watch::channel((*INDEXER_OPERATOR_MNEMONIC).to_string());
+        let networks = HashMap::from([(
+            1,
+            ProtocolNetwork {
+                allocations: Eventual::from_value((*INDEXER_ALLOCATIONS).clone()),
+                dispute_manager: dispute_manager_rx,
+            },
+        )]);
+        let (mut signers, missing) =
+            attestation_signers(networks, AllocationKeys::Mnemonic(mnemonic)).await;
+        assert_eq!(
+            *signers.borrow(),
+            SignersState::Degraded {
+                signers: HashMap::new(),
+                networks: BTreeSet::from([1]),
+            }
+        );
+        assert_eq!(
+            *missing.borrow(),
+            INDEXER_ALLOCATIONS.keys().copied().collect::<HashSet<_>>()
+        );
+
+        dispute_manager_tx
+            .send(Some(*DISPUTE_MANAGER_ADDRESS))
+            .unwrap();
+        timeout(
+            Duration::from_secs(5),
+            signers.wait_for(|signers| !signers.is_degraded()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(signers.borrow().signers().len(), INDEXER_ALLOCATIONS.len());
+        assert!(missing.borrow().is_empty());
+
+        // Rebuilt with the domain of the new dispute manager
+        let dispute_manager = Address::repeat_byte(0x22);
+        dispute_manager_tx.send(Some(dispute_manager)).unwrap();
+        let (id, allocation) = INDEXER_ALLOCATIONS.iter().next().unwrap();
+        let expected =
+            AttestationSigner::new(&INDEXER_OPERATOR_MNEMONIC, allocation, 1, dispute_manager)
+                .unwrap();
+        timeout(
+            Duration::from_secs(5),
+            signers.wait_for(|signers| signers.signers().get(id) == Some(&expected)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    /// Fails to list its keys the first time.
+    #[derive(Debug)]
+    struct FlakyBackend {
+        calls: std::sync::atomic::AtomicUsize,
+        wallet: PrivateKeySigner,
+    }
+
+    #[async_trait::async_trait]
+    impl AttestationSignerBackend for FlakyBackend {
+        async fn addresses(&self) -> anyhow::Result<HashSet<Address>> {
+            if self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                anyhow::bail!("Unavailable");
+            }
+            Ok(HashSet::from([self.wallet.address()]))
+        }
+
+        async fn sign(
+            &self,
+            address: Address,
+            domain: &alloy::dyn_abi::Eip712Domain,
+            receipt: &crate::attestations::backend::Receipt,
+        ) -> anyhow::Result<alloy::primitives::Signature> {
+            crate::attestations::backend::LocalSigner(self.wallet.clone())
+                .sign(address, domain, receipt)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attestation_signers_backend_retried() {
+        let (id, allocation) = INDEXER_ALLOCATIONS.iter().next().unwrap();
+        let backend = Arc::new(FlakyBackend {
+            calls: Default::default(),
+            wallet: wallet_for_allocation(&INDEXER_OPERATOR_MNEMONIC, allocation).unwrap(),
+        });
+        let networks = HashMap::from([(
+            1,
+            ProtocolNetwork {
+                allocations: Eventual::from_value(HashMap::from([(*id, allocation.clone())])),
+                dispute_manager: watch::channel(Some(*DISPUTE_MANAGER_ADDRESS)).1,
+            },
+        )]);
+        let (mut signers, missing) =
+            attestation_signers(networks, AllocationKeys::Backend(backend)).await;
+        assert!(signers.borrow().signers().is_empty());
+        assert!(missing.borrow().contains(id));
+
+        timeout(
+            Duration::from_secs(5),
+            signers.wait_for(|signers| signers.signers().contains_key(id)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_derive_wallets_cached() {
+        let derived_wallets = std::sync::Mutex::new(LruCache::new(
+            NonZeroUsize::new(DERIVED_WALLETS_CACHE_SIZE).unwrap(),
+        ));
+        let mnemonic = Arc::new((*INDEXER_OPERATOR_MNEMONIC).to_string());
+        let wallets = derive_wallets(
+            &derived_wallets,
+            mnemonic.clone(),
+            INDEXER_ALLOCATIONS.values().cloned().collect(),
+        )
+        .await;
+        assert_eq!(wallets.len(), INDEXER_ALLOCATIONS.len());
+        for (id, wallet) in &wallets {
+            assert_eq!(wallet.as_ref().unwrap().address(), *id);
+        }
+
+        let mnemonic_hash = keccak256(mnemonic.as_bytes());
+        assert!(INDEXER_ALLOCATIONS.keys().all(|id| derived_wallets
+            .lock()
+            .unwrap()
+            .contains(&(mnemonic_hash, *id))));
+
+        // The keys of the previous mnemonic are dropped once rotated
+        let other_mnemonic = Arc::new(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+            abandon about"
+                .to_string(),
+        );
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let wallets = derive_wallets(&derived_wallets, other_mnemonic, vec![allocation]).await;
+        assert!(wallets.values().all(Option::is_none));
+        assert!(derived_wallets
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|((hash, _), _)| *hash != mnemonic_hash));
+    }
+
+    #[tokio::test]
+    async fn test_attestation_signers_kept_on_mnemonic_rotation() {
+        let other_mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon \
+            abandon abandon abandon about";
+        let (_, dispute_manager_rx) = watch::channel(Some(*DISPUTE_MANAGER_ADDRESS));
+        let (mut allocations_writer, allocations) = Eventual::<HashMap<Address, Allocation>>::new();
+        allocations_writer.write((*INDEXER_ALLOCATIONS).clone());
+        let (mnemonic_tx, mnemonic_rx) = watch::channel(other_mnemonic.to_string());
+        let networks = HashMap::from([(
+            1,
+            ProtocolNetwork {
+                allocations,
+                dispute_manager: dispute_manager_rx,
+            },
+        )]);
+        let (mut signers, missing) =
+            attestation_signers(networks, AllocationKeys::Mnemonic(mnemonic_rx)).await;
+        // The allocations aren't the ones of this mnemonic, no signer can be derived for them
+        assert!(signers.borrow().signers().is_empty());
+        assert_eq!(
+            *missing.borrow(),
+            INDEXER_ALLOCATIONS.keys().copied().collect::<HashSet<_>>()
+        );
+
+        // Derived again with the rotated mnemonic
+        mnemonic_tx
+            .send((*INDEXER_OPERATOR_MNEMONIC).to_string())
+            .unwrap();
+        timeout(
+            Duration::from_secs(5),
+            signers.wait_for(|signers| signers.signers().len() == INDEXER_ALLOCATIONS.len()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(missing.borrow().is_empty());
+        let initial_signers = signers.borrow_and_update().clone();
+
+        // Kept after another rotation, until their allocations close
+        mnemonic_tx.send(other_mnemonic.to_string()).unwrap();
+        timeout(Duration::from_secs(5), signers.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*signers.borrow_and_update(), initial_signers);
+        let mut remaining = (*INDEXER_ALLOCATIONS).clone();
+        let closed = *remaining.keys().next().unwrap();
+        remaining.remove(&closed);
+        allocations_writer.write(remaining);
+        timeout(
+            Duration::from_secs(5),
+            signers.wait_for(|signers| !signers.signers().contains_key(&closed)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            signers.borrow().signers().len(),
+            INDEXER_ALLOCATIONS.len() - 1
+        );
+    }
 }
@@ -0,0 +1,137 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the attestations of the queries, as a gateway would do, for the integration
+//! tests and the tools checking the responses of an indexer.
+
+use alloy::{dyn_abi::Eip712Domain, primitives::B256};
+use thegraph_core::{attestation, Address, Attestation, ChainId, DeploymentId};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AttestationVerificationError {
+    #[error("Attestation is for deployment {found}, expected {expected}")]
+    DeploymentMismatch { expected: B256, found: B256 },
+    #[error("Invalid attestation: {0}")]
+    Invalid(#[from] attestation::VerificationError),
+}
+
+/// Verifies the attestations of a protocol network, in the EIP-712 domain of its chain id and
+/// dispute manager.
+#[derive(Debug, Clone)]
+pub struct AttestationVerifier {
+    domain: Eip712Domain,
+}
+
+impl AttestationVerifier {
+    pub fn new(chain_id: ChainId, dispute_manager: Address) -> Self {
+        Self {
+            domain: attestation::eip712_domain(chain_id, dispute_manager),
+        }
+    }
+
+    /// Checks that `attestation` attests `response` to `request` for `deployment`, and that it's
+    /// signed by the key of `allocation_id`.
+    pub fn verify(
+        &self,
+        attestation: &Attestation,
+        request: &str,
+        response: &str,
+        allocation_id: &Address,
+        deployment: &DeploymentId,
+    ) -> Result<(), AttestationVerificationError> {
+        let expected = B256::from(*deployment);
+        if attestation.deployment != expected {
+            return Err(AttestationVerificationError::DeploymentMismatch {
+                expected,
+                found: attestation.deployment,
+            });
+        }
+        attestation::verify(&self.domain, attestation, allocation_id, request, response)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use thegraph_core::{Address, DeploymentId};
+
+    use super::{AttestationVerificationError, AttestationVerifier};
+    use crate::{
+        prelude::AttestationSigner,
+        test_vectors::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_OPERATOR_MNEMONIC},
+    };
+
+    #[tokio::test]
+    async fn test_verify_attestation() {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap();
+        let deployment = allocation.subgraph_deployment.id;
+        let signer = AttestationSigner::new(
+            &INDEXER_OPERATOR_MNEMONIC,
+            allocation,
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+        let attestation = signer
+            .create_attestation("request", "response")
+            .await
+            .unwrap();
+
+        let verifier = AttestationVerifier::new(1, *DISPUTE_MANAGER_ADDRESS);
+        verifier
+            .verify(
+                &attestation,
+                "request",
+                "response",
+                &allocation.id,
+                &deployment,
+            )
+            .unwrap();
+
+        // Another response, signer, deployment or domain
+        assert!(matches!(
+            verifier.verify(
+                &attestation,
+                "request",
+                "other",
+                &allocation.id,
+                &deployment
+            ),
+            Err(AttestationVerificationError::Invalid(_))
+        ));
+        assert!(matches!(
+            verifier.verify(
+                &attestation,
+                "request",
+                "response",
+                &Address::repeat_byte(0x11),
+                &deployment
+            ),
+            Err(AttestationVerificationError::Invalid(_))
+        ));
+        let other_deployment =
+            DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap();
+        assert!(matches!(
+            verifier.verify(
+                &attestation,
+                "request",
+                "response",
+                &allocation.id,
+                &other_deployment
+            ),
+            Err(AttestationVerificationError::DeploymentMismatch { .. })
+        ));
+        assert!(AttestationVerifier::new(42161, *DISPUTE_MANAGER_ADDRESS)
+            .verify(
+                &attestation,
+                "request",
+                "response",
+                &allocation.id,
+                &deployment
+            )
+            .is_err());
+    }
+}
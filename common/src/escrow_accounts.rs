@@ -2,20 +2,45 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     str::FromStr,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use alloy::primitives::{Address, U256};
+use alloy::{
+    primitives::{Address, U256},
+    providers::ProviderBuilder,
+    sol,
+};
 use anyhow::{anyhow, Result};
-use eventuals::{timer, Eventual, EventualExt};
-use graphql_client::GraphQLQuery;
+use eventuals::Eventual;
+use futures_util::future::select_all;
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use reqwest::Url;
 use thiserror::Error;
-use tokio::time::sleep;
-use tracing::{error, warn};
+use tokio::{
+    sync::watch,
+    task::JoinSet,
+    time::{self, sleep, MissedTickBehavior},
+};
+use tracing::{debug, error, warn};
 
-use crate::prelude::SubgraphClient;
+use crate::{
+    heartbeat::Heartbeat,
+    prelude::SubgraphClient,
+    subgraph_client::{CacheValidators, Subscription},
+};
+
+pub use schema::EscrowSchema;
+use schema::{EscrowAccount, EscrowAccountQuery, PaymentsEscrowAccount, Variables, MAX_PAGE_SIZE};
+pub use unknown_signers::{
+    log_unknown_signers, record_unknown_signer, resolve_unknown_signers, subscribe_unknown_signers,
+    unknown_signers, UnknownSigner,
+};
+
+mod schema;
+mod unknown_signers;
 
 #[derive(Error, Debug)]
 pub enum EscrowAccountsError {
@@ -25,13 +50,69 @@ pub enum EscrowAccountsError {
     NoBalanceFound { sender: Address },
     #[error("No sender found for signer {signer}")]
     NoSenderFound { signer: Address },
+    #[error("No Horizon balance found for payer {payer} and collector {collector}")]
+    NoHorizonBalanceFound { payer: Address, collector: Address },
+    #[error("The Horizon escrow balances are unknown")]
+    HorizonBalancesUnknown,
+}
+
+/// Changes of the balance of a sender kept, see [`EscrowAccounts::balance_trend`].
+const BALANCE_HISTORY_LEN: usize = 8;
+/// Thaws in a row from which a balance is steadily decreasing.
+const STEADY_DECREASES: usize = 2;
+
+/// Balance of a sender, legacy and Horizon, and the amount it's thawing, as of `at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub at: SystemTime,
+    /// Minus the amount thawing.
+    pub balance: U256,
+    pub thawing: U256,
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// How the balance of a sender changed lately.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Trend {
+    /// Unchanged since it's watched.
+    #[default]
+    Stable,
+    Increasing,
+    /// Decreased by its last change, for example by the redemption of a RAV, or by a single thaw.
+    Dip,
+    /// The sender thawed more of its escrow in its last changes, at least [`STEADY_DECREASES`]
+    /// of them. By `last_decrease` the last time. The redemptions of RAVs don't count, they don't
+    /// change the amount thawing.
+    Decreasing {
+        last_decrease: U256,
+    },
+}
+
+#[derive(Clone, Debug, Default, Eq)]
 pub struct EscrowAccounts {
     senders_balances: HashMap<Address, U256>,
     signers_to_senders: HashMap<Address, Address>,
     senders_to_signers: HashMap<Address, Vec<Address>>,
+    /// Horizon escrow balances, keyed by (payer, collector). The payer is the sender, and the
+    /// receiver is always the indexer. `None` until they're known, while the escrow subgraph
+    /// doesn't have them.
+    horizon_balances: Option<HashMap<(Address, Address), U256>>,
+    /// Amount each sender is thawing, `totalAmountThawing` of its legacy account plus
+    /// `tokensThawing` of its Horizon ones.
+    senders_thawing: HashMap<Address, U256>,
+    /// Latest changes of the balance of each sender, oldest first, see
+    /// [`EscrowAccounts::with_history_of`].
+    balance_history: HashMap<Address, VecDeque<BalanceChange>>,
+}
+
+/// The balance history isn't compared, it only changes with the balances.
+impl PartialEq for EscrowAccounts {
+    fn eq(&self, other: &Self) -> bool {
+        self.senders_balances == other.senders_balances
+            && self.signers_to_senders == other.signers_to_senders
+            && self.senders_to_signers == other.senders_to_signers
+            && self.horizon_balances == other.horizon_balances
+            && self.senders_thawing == other.senders_thawing
+    }
 }
 
 impl EscrowAccounts {
@@ -48,9 +129,28 @@ impl EscrowAccounts {
             senders_balances,
             signers_to_senders,
             senders_to_signers,
+            horizon_balances: None,
+            senders_thawing: HashMap::new(),
+            balance_history: HashMap::new(),
         }
     }
 
+    /// Adds the Horizon escrow balances, keyed by (payer, collector).
+    pub fn with_horizon_balances(
+        mut self,
+        horizon_balances: HashMap<(Address, Address), U256>,
+    ) -> Self {
+        self.horizon_balances = Some(horizon_balances);
+        self
+    }
+
+    /// Adds the amount each sender is thawing, legacy and Horizon, see
+    /// [`EscrowAccounts::balance_trend`].
+    pub fn with_senders_thawing(mut self, senders_thawing: HashMap<Address, U256>) -> Self {
+        self.senders_thawing = senders_thawing;
+        self
+    }
+
     pub fn get_signers_for_sender(&self, sender: &Address) -> Vec<Address> {
         self.senders_to_signers
             .get(sender)
@@ -83,97 +183,651 @@ impl EscrowAccounts {
             .and_then(|sender| self.get_balance_for_sender(&sender))
     }
 
+    /// Fails with [`EscrowAccountsError::HorizonBalancesUnknown`] rather than
+    /// [`EscrowAccountsError::NoHorizonBalanceFound`] while the Horizon balances are unknown.
+    pub fn get_balance_for_payer_and_collector(
+        &self,
+        payer: &Address,
+        collector: &Address,
+    ) -> Result<U256, EscrowAccountsError> {
+        self.horizon_balances
+            .as_ref()
+            .ok_or(EscrowAccountsError::HorizonBalancesUnknown)?
+            .get(&(*payer, *collector))
+            .ok_or(EscrowAccountsError::NoHorizonBalanceFound {
+                payer: payer.to_owned(),
+                collector: collector.to_owned(),
+            })
+            .copied()
+    }
+
+    /// Carries over the balance history of `previous`, the accounts these ones replace, with the
+    /// balances that changed since, as of `now`. The senders without an account anymore are
+    /// forgotten.
+    pub fn with_history_of(mut self, previous: &EscrowAccounts, now: SystemTime) -> Self {
+        for sender in self.get_senders() {
+            let balance = self.total_balance(&sender);
+            let thawing = self
+                .senders_thawing
+                .get(&sender)
+                .copied()
+                .unwrap_or_default();
+            let mut history = previous
+                .balance_history
+                .get(&sender)
+                .cloned()
+                .unwrap_or_default();
+            if history.back().map_or(true, |last| {
+                last.balance != balance || last.thawing != thawing
+            }) {
+                if history.len() == BALANCE_HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back(BalanceChange {
+                    at: now,
+                    balance,
+                    thawing,
+                });
+            }
+            self.balance_history.insert(sender, history);
+        }
+        self
+    }
+
+    /// Legacy balance of the sender plus the ones of all its Horizon accounts.
+    fn total_balance(&self, sender: &Address) -> U256 {
+        let legacy = self
+            .senders_balances
+            .get(sender)
+            .copied()
+            .unwrap_or_default();
+        self.horizon_balances
+            .iter()
+            .flatten()
+            .filter(|((payer, _), _)| payer == sender)
+            .fold(legacy, |total, (_, balance)| total.saturating_add(*balance))
+    }
+
+    /// Latest changes of the balance of the sender, oldest first, the first one being its
+    /// balance when it started to be watched.
+    pub fn balance_history(&self, sender: &Address) -> Vec<BalanceChange> {
+        self.balance_history
+            .get(sender)
+            .map(|history| history.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the balance of the sender, legacy and Horizon, is steadily decreasing, or only
+    /// dipped, from its history. It's steadily decreasing while the sender keeps thawing more of
+    /// its escrow, whatever the balance, as the redemptions of RAVs also decrease it.
+    pub fn balance_trend(&self, sender: &Address) -> Trend {
+        let Some(history) = self.balance_history.get(sender) else {
+            return Trend::Stable;
+        };
+        let mut changes = history.iter().rev().zip(history.iter().rev().skip(1));
+        let thaws = changes
+            .clone()
+            .take_while(|(change, before)| change.thawing > before.thawing)
+            .count();
+        match changes.next() {
+            None => Trend::Stable,
+            Some((change, before)) if thaws >= STEADY_DECREASES => Trend::Decreasing {
+                last_decrease: change.thawing - before.thawing,
+            },
+            Some((change, before)) if change.thawing > before.thawing => Trend::Dip,
+            Some((change, before)) if change.balance > before.balance => Trend::Increasing,
+            Some((change, before)) if change.balance < before.balance => Trend::Dip,
+            Some(_) => Trend::Stable,
+        }
+    }
+
+    /// Senders with a legacy or a Horizon escrow account.
     pub fn get_senders(&self) -> HashSet<Address> {
-        self.senders_balances.keys().copied().collect()
+        self.senders_balances
+            .keys()
+            .chain(
+                self.horizon_balances
+                    .iter()
+                    .flat_map(|balances| balances.keys().map(|(payer, _)| payer)),
+            )
+            .copied()
+            .collect()
+    }
+}
+
+/// Number of escrow balances read concurrently from the Escrow contract.
+const RPC_BATCH_SIZE: usize = 32;
+
+lazy_static! {
+    static ref ESCROW_BALANCE_MISMATCH: CounterVec = register_counter_vec!(
+        "indexer_escrow_balance_mismatch_total",
+        "Escrow balances of the subgraph that differ from the ones of the Escrow contract",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+/// Reads the escrow balances from the Escrow contract when the escrow subgraph lags behind.
+#[derive(Debug, Clone)]
+pub struct EscrowRpcFallback {
+    pub rpc_url: Url,
+    pub escrow_address: Address,
+    /// Age of the last block indexed by the subgraph from which it's considered lagging
+    pub max_subgraph_lag: Duration,
+    /// Also read the balances from the contract when the subgraph is up to date, and report
+    /// the ones that differ
+    pub cross_check: bool,
+}
+
+sol! {
+    #[sol(rpc)]
+    contract Escrow {
+        /// Balance of the escrow account minus the amount thawing
+        function getEscrowAmount(address sender, address receiver)
+            external
+            view
+            returns (uint256);
+    }
+}
+
+impl EscrowRpcFallback {
+    /// Reads the balances of the (legacy) senders of `accounts` from the Escrow contract. The
+    /// signers can't be listed from the contract, so they're kept as they are.
+    async fn refresh_balances(
+        &self,
+        accounts: &EscrowAccounts,
+        indexer_address: Address,
+    ) -> Result<EscrowAccounts> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+        let escrow = Escrow::new(self.escrow_address, provider);
+
+        // The balances are read concurrently, a batch at a time, to keep the refresh fast with
+        // many senders without flooding the RPC node
+        let senders = accounts
+            .senders_balances
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let mut senders_balances = HashMap::new();
+        for batch in senders.chunks(RPC_BATCH_SIZE) {
+            let mut calls = JoinSet::new();
+            for sender in batch.iter().copied() {
+                let escrow = escrow.clone();
+                calls.spawn(async move {
+                    let amount = escrow.getEscrowAmount(sender, indexer_address).call().await;
+                    (sender, amount)
+                });
+            }
+            while let Some(call) = calls.join_next().await {
+                let (sender, amount) = call?;
+                senders_balances.insert(sender, amount?._0);
+            }
+        }
+
+        Ok(EscrowAccounts {
+            horizon_balances: accounts.horizon_balances.clone(),
+            senders_thawing: accounts.senders_thawing.clone(),
+            ..EscrowAccounts::new(senders_balances, accounts.senders_to_signers.clone())
+        })
     }
 }
 
-type BigInt = String;
+/// Reports the senders whose balance in `subgraph` differs from the one in `contract`.
+fn cross_check(subgraph: &EscrowAccounts, contract: &EscrowAccounts) {
+    for (sender, balance) in &subgraph.senders_balances {
+        let Some(contract_balance) = contract.senders_balances.get(sender) else {
+            continue;
+        };
+        if balance != contract_balance {
+            warn!(
+                %sender,
+                subgraph_balance = %balance,
+                contract_balance = %contract_balance,
+                "The escrow subgraph and the Escrow contract disagree on the balance of the \
+                sender, using the one of the contract."
+            );
+            ESCROW_BALANCE_MISMATCH
+                .with_label_values(&[&sender.to_string()])
+                .inc();
+        }
+    }
+}
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "../graphql/tap.schema.graphql",
-    query_path = "../graphql/escrow_account.query.graphql",
-    response_derives = "Debug",
-    variables_derives = "Clone"
-)]
-pub struct EscrowAccountQuery;
+/// Escrow accounts as of the last block indexed by the escrow subgraph.
+struct SubgraphSnapshot {
+    accounts: EscrowAccounts,
+    block_timestamp: Option<u64>,
+}
 
-pub fn escrow_accounts(
+impl SubgraphSnapshot {
+    fn is_lagging(&self, now: SystemTime, max_lag: Duration) -> bool {
+        let Some(block_timestamp) = self.block_timestamp else {
+            return false;
+        };
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        now.saturating_sub(block_timestamp) > max_lag.as_secs()
+    }
+}
+
+/// Watches the escrow accounts of the indexer, querying the escrow subgraph every `interval`.
+///
+/// Returns once the subgraph has been queried successfully. The subgraph is then queried with the
+/// validators of its last response, so that an unchanged response isn't sent again. If the query
+/// fails, or the last block indexed by the subgraph is older than `max_subgraph_lag`, the balances
+/// are read from the Escrow contract instead, when an `rpc_fallback` is given. With `cross_check`,
+/// they're always read from the contract, and compared to the ones of the subgraph. The balances
+/// of the subgraph are kept when the contract can't be read, unless the subgraph failed too.
+///
+/// If the escrow subgraph has a subscription endpoint, it's also polled as soon as the accounts
+/// change, with a subscription per page of the last poll, see
+/// [`crate::subgraph_client::Subscription`]. These polls skip the cache of the subgraph client.
+///
+/// Without the fields to filter the signers on, see [`EscrowSchema::check_signer_filters`], it
+/// doesn't return, and if the schema loses them later on, the accounts are emptied until it has
+/// them again.
+///
+/// The `heartbeat`, if any, beats on every successful poll, of the subgraph or of the Escrow
+/// contract, whether the accounts changed or not, and the watcher stops once it's retired.
+pub async fn escrow_accounts_watcher(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     reject_thawing_signers: bool,
-) -> Eventual<EscrowAccounts> {
-    timer(interval).map_with_retry(
-        move |_| async move {
-            get_escrow_accounts(escrow_subgraph, indexer_address, reject_thawing_signers)
-                .await
-                .map_err(|e| e.to_string())
-        },
-        move |err: String| {
-            error!(
-                "Failed to fetch escrow accounts for indexer {:?}: {}",
-                indexer_address, err
-            );
+    rpc_fallback: Option<EscrowRpcFallback>,
+    heartbeat: Option<Heartbeat>,
+) -> watch::Receiver<EscrowAccounts> {
+    let mut pages = EscrowPages::default();
+    let mut schema = EscrowSchema::detect_or_default(escrow_subgraph).await;
+    schema.report();
+    let mut snapshot = loop {
+        match get_escrow_accounts(
+            escrow_subgraph,
+            schema,
+            indexer_address,
+            reject_thawing_signers,
+            &mut pages,
+            false,
+        )
+        .await
+        {
+            Ok(Some(snapshot)) => break snapshot,
+            Ok(None) => error!("Unexpected `304 Not Modified` to the first escrow accounts query"),
+            Err(err) => {
+                error!(
+                    "Failed to fetch escrow accounts for indexer {:?}: {}",
+                    indexer_address, err
+                );
+                redetect_schema(escrow_subgraph, &mut schema, &mut pages).await;
+            }
+        }
+        sleep(interval.div_f32(2.0)).await;
+    };
+    if let Some(ref heartbeat) = heartbeat {
+        heartbeat.beat();
+    }
+
+    let (sender, receiver) = watch::channel(
+        snapshot
+            .accounts
+            .clone()
+            .with_history_of(&EscrowAccounts::default(), SystemTime::now()),
+    );
+    tokio::spawn(async move {
+        let subscribe = |variables: &[Variables]| -> Vec<Subscription> {
+            variables
+                .iter()
+                .map(|variables| {
+                    escrow_subgraph.subscribe(&variables.schema.subscription(), variables)
+                })
+                .collect()
+        };
+        let mut subscribed = pages.variables();
+        let mut subscriptions = subscribe(&subscribed);
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, and the accounts were just fetched
+        ticker.tick().await;
+
+        while !sender.is_closed() && !heartbeat.as_ref().is_some_and(Heartbeat::is_retired) {
+            let pushed = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = any_changed(&mut subscriptions) => {
+                    ticker.reset();
+                    true
+                }
+            };
+
+            let subgraph_ok = match get_escrow_accounts(
+                escrow_subgraph,
+                schema,
+                indexer_address,
+                reject_thawing_signers,
+                &mut pages,
+                pushed,
+            )
+            .await
+            {
+                Ok(Some(new_snapshot)) => {
+                    snapshot = new_snapshot;
+                    true
+                }
+                Ok(None) => true,
+                Err(err) => {
+                    error!(
+                        "Failed to fetch escrow accounts for indexer {:?}: {}",
+                        indexer_address, err
+                    );
+                    redetect_schema(escrow_subgraph, &mut schema, &mut pages).await;
+                    if schema.check_signer_filters(reject_thawing_signers).is_err() {
+                        snapshot.accounts = EscrowAccounts::default();
+                    }
+                    false
+                }
+            };
+            if pages.variables() != subscribed {
+                subscribed = pages.variables();
+                subscriptions = subscribe(&subscribed);
+            }
+
+            let mut accounts = snapshot.accounts.clone();
+            let mut polled = subgraph_ok;
+            if let Some(ref rpc_fallback) = rpc_fallback {
+                let lagging = !subgraph_ok
+                    || snapshot.is_lagging(SystemTime::now(), rpc_fallback.max_subgraph_lag);
+                if lagging || rpc_fallback.cross_check {
+                    match rpc_fallback
+                        .refresh_balances(&accounts, indexer_address)
+                        .await
+                    {
+                        Ok(refreshed) => {
+                            if !lagging {
+                                cross_check(&accounts, &refreshed);
+                            }
+                            accounts = refreshed;
+                            polled = true;
+                        }
+                        Err(err) => {
+                            error!(
+                                "Failed to read the escrow balances from the Escrow contract {}: {}",
+                                rpc_fallback.escrow_address, err
+                            );
+                            // Nothing new to publish
+                            if !subgraph_ok {
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+
+            sender.send_if_modified(|current| {
+                let accounts = accounts.with_history_of(current, SystemTime::now());
+                let modified = *current != accounts;
+                *current = accounts;
+                modified
+            });
+            if let Some(heartbeat) = heartbeat.as_ref().filter(|_| polled) {
+                heartbeat.beat();
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Detects the schema of the escrow subgraph again after a failed query, in case it was upgraded.
+/// Keeps the current one if it can't.
+async fn redetect_schema(
+    escrow_subgraph: &SubgraphClient,
+    schema: &mut EscrowSchema,
+    pages: &mut EscrowPages,
+) {
+    if let Ok(detected) = EscrowSchema::detect(escrow_subgraph).await {
+        if detected != *schema {
+            detected.report();
+            *schema = detected;
+            *pages = EscrowPages::default();
+        }
+    }
+}
 
-            sleep(interval.div_f32(2.0))
-        },
+/// Waits for the next change of any of `subscriptions`.
+async fn any_changed(subscriptions: &mut [Subscription]) {
+    if subscriptions.is_empty() {
+        return std::future::pending().await;
+    }
+    select_all(
+        subscriptions
+            .iter_mut()
+            .map(|subscription| Box::pin(subscription.changed())),
     )
+    .await;
 }
 
-async fn get_escrow_accounts(
-    escrow_subgraph: &'static SubgraphClient,
+/// Feeds the updates of [`escrow_accounts_watcher`] to an [`Eventual`], for the consumers that
+/// still expect one.
+pub fn escrow_accounts_eventual(
+    mut receiver: watch::Receiver<EscrowAccounts>,
+) -> Eventual<EscrowAccounts> {
+    let (mut writer, eventual) = Eventual::new();
+    tokio::spawn(async move {
+        loop {
+            let accounts = receiver.borrow_and_update().clone();
+            writer.write(accounts);
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    eventual
+}
+
+/// Balance of an escrow account that isn't thawing.
+fn available_balance(sender: &Address, balance: U256, total_amount_thawing: U256) -> U256 {
+    U256::checked_sub(balance, total_amount_thawing).unwrap_or_else(|| {
+        warn!(
+            "Balance minus total amount thawing underflowed for account {}. \
+            Setting balance to 0, no queries will be served for this sender.",
+            sender
+        );
+        U256::from(0)
+    })
+}
+
+fn query_variables(
+    schema: EscrowSchema,
     indexer_address: Address,
     reject_thawing_signers: bool,
-) -> Result<EscrowAccounts> {
+) -> Variables {
     // thawEndTimestamp == 0 means that the signer is not thawing. This also means
     // that we don't wait for the thawing period to end before stopping serving
     // queries for this signer.
     // isAuthorized == true means that the signer is still authorized to sign
     // payments in the name of the sender.
-    let response = escrow_subgraph
-        .query::<EscrowAccountQuery, _>(escrow_account_query::Variables {
-            indexer: format!("{:x?}", indexer_address),
-            thaw_end_timestamp: if reject_thawing_signers {
+    Variables {
+        indexer: format!("{:x?}", indexer_address),
+        first: MAX_PAGE_SIZE,
+        last_id: String::new(),
+        thaw_end_timestamp: schema.signer_thaw_end_timestamp.then(|| {
+            if reject_thawing_signers {
                 U256::ZERO.to_string()
             } else {
                 U256::MAX.to_string()
-            },
-        })
-        .await?;
+            }
+        }),
+        first_horizon: schema.horizon.then_some(MAX_PAGE_SIZE),
+        last_horizon_id: schema.horizon.then(String::new),
+        block: None,
+        schema,
+    }
+}
+
+/// The pages of escrow accounts of the last poll, to query them conditionally on the next one.
+#[derive(Default)]
+struct EscrowPages(Vec<EscrowPage>);
+
+struct EscrowPage {
+    /// Without the block the page was pinned to.
+    variables: Variables,
+    validators: CacheValidators,
+    escrow_accounts: Vec<EscrowAccount>,
+    horizon_accounts: Vec<PaymentsEscrowAccount>,
+}
+
+impl EscrowPages {
+    /// The variables of each page, to subscribe to them all.
+    fn variables(&self) -> Vec<Variables> {
+        self.0.iter().map(|page| page.variables.clone()).collect()
+    }
+}
+
+/// Fetches all the escrow accounts of the indexer, a page of [`MAX_PAGE_SIZE`] at a time. The
+/// Horizon ones are paged along, until both run out. The pages after the first one are pinned
+/// to its block, when the schema has `_meta`, so that they're all from the same block.
+///
+/// Each page is queried with the validators of the same page in `pages`, and taken from there
+/// if it didn't change. As the first page has the block, nothing changed if it didn't and `None`
+/// is returned. With `uncached`, the fresh responses cached by the subgraph client are ignored,
+/// see [`SubgraphClient::query_conditional_uncached`].
+async fn get_escrow_accounts(
+    escrow_subgraph: &'static SubgraphClient,
+    schema: EscrowSchema,
+    indexer_address: Address,
+    reject_thawing_signers: bool,
+    pages: &mut EscrowPages,
+    uncached: bool,
+) -> Result<Option<SubgraphSnapshot>> {
+    schema.check_signer_filters(reject_thawing_signers)?;
+    let mut variables = query_variables(schema, indexer_address, reject_thawing_signers);
+    let mut fetched: Vec<EscrowPage> = Vec::new();
+    let mut block = None;
+    let mut block_timestamp = None;
+    loop {
+        let previous = pages
+            .0
+            .get(fetched.len())
+            .filter(|page| page.variables == variables);
+        let mut validators = previous
+            .map(|page| page.validators.clone())
+            .unwrap_or_default();
+        let query = Variables {
+            block,
+            ..variables.clone()
+        };
+        let response = if uncached {
+            escrow_subgraph
+                .query_conditional_uncached::<EscrowAccountQuery, _>(query, &mut validators)
+                .await?
+        } else {
+            escrow_subgraph
+                .query_conditional::<EscrowAccountQuery, _>(query, &mut validators)
+                .await?
+        };
+        let (escrow_accounts, horizon_accounts) = match (response, previous) {
+            (Some(response), _) => {
+                // The last known good response isn't a successful poll, the last snapshot is kept
+                let response = response.fresh()??;
+                if fetched.is_empty() {
+                    let meta_block = response.meta.map(|meta| meta.block);
+                    block = meta_block.as_ref().and_then(|block| block.number);
+                    block_timestamp = meta_block
+                        .and_then(|block| block.timestamp)
+                        .and_then(|timestamp| timestamp.to_u256().ok())
+                        .and_then(|timestamp| u64::try_from(timestamp).ok());
+                }
+                (response.escrow_accounts, response.payments_escrow_accounts)
+            }
+            (None, _) if fetched.is_empty() && schema.meta => return Ok(None),
+            (None, Some(previous)) => (
+                previous.escrow_accounts.clone(),
+                previous.horizon_accounts.clone(),
+            ),
+            (None, None) => {
+                return Err(anyhow!(
+                    "Unexpected `304 Not Modified` to an unconditional escrow accounts query"
+                ))
+            }
+        };
 
-    let response = response?;
+        let page_variables = variables.clone();
+        // A list that ran out is queried with `first: 0` on the next pages
+        if variables.first > 0 && escrow_accounts.len() >= variables.first {
+            variables.last_id = escrow_accounts
+                .last()
+                .and_then(|account| account.id.clone())
+                .ok_or_else(|| {
+                    anyhow!("The escrow accounts have no `id` to query the next page with")
+                })?;
+        } else {
+            variables.first = 0;
+        }
+        if let Some(first_horizon) = variables.first_horizon.filter(|first| *first > 0) {
+            if horizon_accounts.len() >= first_horizon {
+                variables.last_horizon_id = Some(
+                    horizon_accounts
+                        .last()
+                        .and_then(|account| account.id.clone())
+                        .ok_or_else(|| {
+                            anyhow!("The Horizon escrow accounts have no `id` to query the next page with")
+                        })?,
+                );
+            } else {
+                variables.first_horizon = Some(0);
+            }
+        }
+        fetched.push(EscrowPage {
+            variables: page_variables,
+            validators,
+            escrow_accounts,
+            horizon_accounts,
+        });
+        if variables.first == 0 && variables.first_horizon.unwrap_or_default() == 0 {
+            break;
+        }
+    }
+    let escrow_accounts: Vec<&EscrowAccount> = fetched
+        .iter()
+        .flat_map(|page| &page.escrow_accounts)
+        .collect();
+    let horizon_accounts: Vec<&PaymentsEscrowAccount> = fetched
+        .iter()
+        .flat_map(|page| &page.horizon_accounts)
+        .collect();
+    if fetched.len() > 1 {
+        debug!(
+            pages = fetched.len(),
+            block,
+            accounts = escrow_accounts.len(),
+            horizon_accounts = horizon_accounts.len(),
+            "Escrow accounts fetched in pages."
+        );
+    }
 
-    let senders_balances: HashMap<Address, U256> = response
-        .escrow_accounts
+    let mut senders_thawing: HashMap<Address, U256> = HashMap::new();
+    let senders_balances: HashMap<Address, U256> = escrow_accounts
         .iter()
         .map(|account| {
-            let balance = U256::checked_sub(
-                U256::from_str(&account.balance)?,
-                U256::from_str(&account.total_amount_thawing)?,
-            )
-            .unwrap_or_else(|| {
-                warn!(
-                    "Balance minus total amount thawing underflowed for account {}. \
-                                 Setting balance to 0, no queries will be served for this sender.",
-                    account.sender.id
-                );
-                U256::from(0)
-            });
+            let sender = Address::from_str(&account.sender.id)?;
+            let total_amount_thawing = match &account.total_amount_thawing {
+                Some(total_amount_thawing) => total_amount_thawing.to_u256()?,
+                None => U256::ZERO,
+            };
+            let balance =
+                available_balance(&sender, account.balance.to_u256()?, total_amount_thawing);
+            senders_thawing.insert(sender, total_amount_thawing);
 
-            Ok((Address::from_str(&account.sender.id)?, balance))
+            Ok((sender, balance))
         })
         .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
 
-    let senders_to_signers = response
-        .escrow_accounts
-        .into_iter()
+    let senders_to_signers = escrow_accounts
+        .iter()
         .map(|account| {
             let sender = Address::from_str(&account.sender.id)?;
             let signers = account
                 .sender
                 .signers
+                .as_ref()
                 .ok_or(anyhow!("Could not find any signers for sender {sender}"))?
                 .iter()
                 .map(|signer| Address::from_str(&signer.id))
@@ -182,14 +836,41 @@ async fn get_escrow_accounts(
         })
         .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
 
-    Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
+    let mut accounts = EscrowAccounts::new(senders_balances, senders_to_signers);
+    if schema.horizon {
+        let horizon_balances = horizon_accounts
+            .iter()
+            .map(|account| {
+                let payer = Address::from_str(&account.payer.id)?;
+                let collector = Address::from_str(&account.collector.id)?;
+                let tokens_thawing = match &account.tokens_thawing {
+                    Some(tokens_thawing) => tokens_thawing.to_u256()?,
+                    None => U256::ZERO,
+                };
+                let balance = available_balance(&payer, account.balance.to_u256()?, tokens_thawing);
+                let thawing = senders_thawing.entry(payer).or_default();
+                *thawing = thawing.saturating_add(tokens_thawing);
+                Ok(((payer, collector), balance))
+            })
+            .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
+        accounts = accounts.with_horizon_balances(horizon_balances);
+    }
+    accounts = accounts.with_senders_thawing(senders_thawing);
+
+    *pages = EscrowPages(fetched);
+    Ok(Some(SubgraphSnapshot {
+        accounts,
+        block_timestamp,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use test_log::test;
-    use wiremock::matchers::{method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
 
     use crate::prelude::DeploymentDetails;
     use crate::test_vectors;
@@ -209,11 +890,37 @@ mod tests {
         )
     }
 
-    #[test(tokio::test)]
-    async fn test_current_accounts() {
-        // Set up a mock escrow subgraph
-        let mock_server = MockServer::start().await;
-        let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
+    #[test]
+    fn test_horizon_balances() {
+        let payer = Address::from([1u8; 20]);
+        let collector = Address::from([2u8; 20]);
+        let escrow_accounts = EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        )
+        .with_horizon_balances(HashMap::from([((payer, collector), U256::from(42))]));
+
+        assert!(matches!(
+            EscrowAccounts::default().get_balance_for_payer_and_collector(&payer, &collector),
+            Err(EscrowAccountsError::HorizonBalancesUnknown)
+        ));
+        assert_eq!(
+            escrow_accounts
+                .get_balance_for_payer_and_collector(&payer, &collector)
+                .unwrap(),
+            U256::from(42)
+        );
+        assert!(matches!(
+            escrow_accounts.get_balance_for_payer_and_collector(&collector, &payer),
+            Err(EscrowAccountsError::NoHorizonBalanceFound { .. })
+        ));
+        // Payers without a legacy escrow account are senders as well
+        assert!(escrow_accounts.get_senders().contains(&payer));
+        assert!(escrow_accounts.get_balance_for_sender(&payer).is_err());
+    }
+
+    fn escrow_subgraph(mock_server: &MockServer) -> &'static SubgraphClient {
+        Box::leak(Box::new(SubgraphClient::new(
             reqwest::Client::new(),
             None,
             DeploymentDetails::for_query_url(&format!(
@@ -222,7 +929,14 @@ mod tests {
                 *test_vectors::ESCROW_SUBGRAPH_DEPLOYMENT
             ))
             .unwrap(),
-        )));
+        )))
+    }
+
+    #[test(tokio::test)]
+    async fn test_current_accounts() {
+        // Set up a mock escrow subgraph
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = escrow_subgraph(&mock_server);
 
         let mock = Mock::given(method("POST"))
             .and(path(format!(
@@ -235,19 +949,434 @@ mod tests {
             );
         mock_server.register(mock).await;
 
-        let accounts = escrow_accounts(
+        let accounts = escrow_accounts_watcher(
             escrow_subgraph,
             *test_vectors::INDEXER_ADDRESS,
             Duration::from_secs(60),
             true,
-        );
+            None,
+            None,
+        )
+        .await;
 
         assert_eq!(
-            accounts.value().await.unwrap(),
+            *accounts.borrow(),
             EscrowAccounts::new(
                 test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
                 test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
             )
         );
+
+        let eventual = escrow_accounts_eventual(accounts.clone());
+        assert_eq!(eventual.value().await.unwrap(), *accounts.borrow());
+    }
+
+    #[test(tokio::test)]
+    async fn test_not_modified() {
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = escrow_subgraph(&mock_server);
+
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(header("If-None-Match", "\"1\""))
+                    .respond_with(ResponseTemplate::new(304))
+                    .with_priority(1),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("ETag", "\"1\"")
+                        .set_body_raw(test_vectors::ESCROW_QUERY_RESPONSE, "application/json"),
+                ),
+            )
+            .await;
+
+        let mut pages = EscrowPages::default();
+        let snapshot = get_escrow_accounts(
+            escrow_subgraph,
+            EscrowSchema::default(),
+            *test_vectors::INDEXER_ADDRESS,
+            true,
+            &mut pages,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(snapshot.is_some());
+
+        let snapshot = get_escrow_accounts(
+            escrow_subgraph,
+            EscrowSchema::default(),
+            *test_vectors::INDEXER_ADDRESS,
+            true,
+            &mut pages,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(snapshot.is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn test_paginated_accounts() {
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = escrow_subgraph(&mock_server);
+
+        let account = |i: usize| {
+            serde_json::json!({
+                "id": format!("{i:04}"),
+                "balance": "100",
+                "totalAmountThawing": "0",
+                "sender": {
+                    "id": format!("0x{:040x}", i + 1),
+                    "signers": [{ "id": format!("0x{:040x}", i + 100_000) }]
+                }
+            })
+        };
+        // The second page didn't change
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(header("If-None-Match", "\"2\""))
+                    .respond_with(ResponseTemplate::new(304))
+                    .with_priority(1),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(move |request: &Request| {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    let variables = &request["variables"];
+                    match variables["lastId"].as_str().unwrap() {
+                        "" => {
+                            assert!(variables.get("block").is_none());
+                            ResponseTemplate::new(200)
+                                .insert_header("ETag", "\"1\"")
+                                .set_body_json(serde_json::json!({
+                                    "data": {
+                                        "meta": { "block": { "number": 7, "timestamp": "1" } },
+                                        "escrowAccounts": (0..MAX_PAGE_SIZE)
+                                            .map(account)
+                                            .collect::<Vec<_>>()
+                                    }
+                                }))
+                        }
+                        "0999" => {
+                            // Pinned to the block of the first page
+                            assert_eq!(variables["block"], 7);
+                            assert!(request["query"]
+                                .as_str()
+                                .unwrap()
+                                .contains("block: { number: $block }"));
+                            ResponseTemplate::new(200)
+                                .insert_header("ETag", "\"2\"")
+                                .set_body_json(serde_json::json!({
+                                    "data": { "escrowAccounts": [account(MAX_PAGE_SIZE)] }
+                                }))
+                        }
+                        last_id => panic!("Unexpected page after {last_id}"),
+                    }
+                }),
+            )
+            .await;
+
+        let mut pages = EscrowPages::default();
+        for _ in 0..2 {
+            let snapshot = get_escrow_accounts(
+                escrow_subgraph,
+                EscrowSchema::default(),
+                *test_vectors::INDEXER_ADDRESS,
+                true,
+                &mut pages,
+                false,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let senders = snapshot.accounts.get_senders();
+            assert_eq!(senders.len(), MAX_PAGE_SIZE + 1);
+            let last = Address::from_str(&format!("0x{:040x}", MAX_PAGE_SIZE + 1)).unwrap();
+            assert_eq!(
+                snapshot.accounts.get_balance_for_sender(&last).unwrap(),
+                U256::from(100)
+            );
+            // Both pages are watched, and queried conditionally next time
+            assert_eq!(pages.variables().len(), 2);
+            assert!(pages
+                .0
+                .iter()
+                .all(|page| !page.validators.is_unconditional()));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_horizon_accounts() {
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = escrow_subgraph(&mock_server);
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "data": {
+                        "escrowAccounts": [],
+                        "paymentsEscrowAccounts": [{
+                            "id": "1",
+                            "balance": "100",
+                            "tokensThawing": "30",
+                            "payer": { "id": "0x0101010101010101010101010101010101010101" },
+                            "collector": { "id": "0x0202020202020202020202020202020202020202" }
+                        }]
+                    }
+                })),
+            ))
+            .await;
+        let payer = Address::from([1u8; 20]);
+        let collector = Address::from([2u8; 20]);
+
+        let snapshot = get_escrow_accounts(
+            escrow_subgraph,
+            EscrowSchema {
+                horizon: true,
+                ..Default::default()
+            },
+            *test_vectors::INDEXER_ADDRESS,
+            true,
+            &mut EscrowPages::default(),
+            false,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            snapshot
+                .accounts
+                .get_balance_for_payer_and_collector(&payer, &collector)
+                .unwrap(),
+            U256::from(70)
+        );
+
+        // Unknown without the Horizon escrow accounts in the schema
+        let snapshot = get_escrow_accounts(
+            escrow_subgraph,
+            EscrowSchema::default(),
+            *test_vectors::INDEXER_ADDRESS,
+            true,
+            &mut EscrowPages::default(),
+            false,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(matches!(
+            snapshot
+                .accounts
+                .get_balance_for_payer_and_collector(&payer, &collector),
+            Err(EscrowAccountsError::HorizonBalancesUnknown)
+        ));
+    }
+
+    #[test]
+    fn test_balance_trend() {
+        let sender = Address::from([1u8; 20]);
+        let collector = Address::from([2u8; 20]);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let accounts = |balance: u64, thawing: u64| {
+            EscrowAccounts::new(
+                HashMap::from([(sender, U256::from(balance))]),
+                HashMap::new(),
+            )
+            .with_senders_thawing(HashMap::from([(sender, U256::from(thawing))]))
+        };
+        let mut history = accounts(100, 0).with_history_of(&EscrowAccounts::default(), start);
+        let mut next = |accounts: EscrowAccounts, secs: u64| {
+            history = accounts.with_history_of(&history, start + Duration::from_secs(secs));
+            history.balance_trend(&sender)
+        };
+
+        assert_eq!(next(accounts(100, 0), 10), Trend::Stable);
+        assert_eq!(next(accounts(120, 0), 20), Trend::Increasing);
+        // The redemptions of RAVs only dip the balance, even in a row
+        assert_eq!(next(accounts(90, 0), 30), Trend::Dip);
+        assert_eq!(next(accounts(60, 0), 40), Trend::Dip);
+        assert_eq!(next(accounts(50, 10), 50), Trend::Dip);
+        assert_eq!(
+            next(accounts(30, 30), 60),
+            Trend::Decreasing {
+                last_decrease: U256::from(20)
+            }
+        );
+        assert_eq!(next(accounts(70, 30), 70), Trend::Increasing);
+        // The Horizon accounts count too
+        assert_eq!(
+            next(
+                accounts(70, 30)
+                    .with_horizon_balances(HashMap::from([((sender, collector), U256::from(40))]))
+                    .with_senders_thawing(HashMap::from([(sender, U256::from(40))])),
+                80
+            ),
+            Trend::Dip
+        );
+        assert_eq!(
+            next(
+                accounts(70, 30)
+                    .with_horizon_balances(HashMap::from([((sender, collector), U256::from(20))]))
+                    .with_senders_thawing(HashMap::from([(sender, U256::from(60))])),
+                90
+            ),
+            Trend::Decreasing {
+                last_decrease: U256::from(20)
+            }
+        );
+        assert_eq!(
+            history.balance_history(&sender).last().unwrap().balance,
+            U256::from(90)
+        );
+
+        // Only the changes are kept, up to the length of the history
+        for secs in 0..20 {
+            next(accounts(secs, 0), 100 + secs);
+        }
+        let changes = history.balance_history(&sender);
+        assert_eq!(changes.len(), BALANCE_HISTORY_LEN);
+        assert_eq!(changes.last().unwrap().at, start + Duration::from_secs(119));
+        assert!(EscrowAccounts::default()
+            .balance_history(&sender)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_is_lagging() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let max_lag = Duration::from_secs(300);
+        let snapshot = |block_timestamp| SubgraphSnapshot {
+            accounts: EscrowAccounts::default(),
+            block_timestamp,
+        };
+
+        assert!(!snapshot(None).is_lagging(now, max_lag));
+        assert!(!snapshot(Some(700)).is_lagging(now, max_lag));
+        assert!(snapshot(Some(699)).is_lagging(now, max_lag));
+        // Clock skew
+        assert!(!snapshot(Some(1_010)).is_lagging(now, max_lag));
+    }
+
+    #[test(tokio::test)]
+    async fn test_rpc_fallback() {
+        // Set up a mock JSON-RPC endpoint answering every `eth_call` with an escrow amount of 90
+        let mock_server = MockServer::start().await;
+        let result = format!(
+            "0x{}",
+            alloy::hex::encode(U256::from(90).to_be_bytes::<32>())
+        );
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(move |request: &Request| {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": result,
+                    }))
+                }),
+            )
+            .await;
+
+        let rpc_fallback = EscrowRpcFallback {
+            rpc_url: Url::parse(&mock_server.uri()).unwrap(),
+            escrow_address: Address::from([4u8; 20]),
+            max_subgraph_lag: Duration::from_secs(300),
+            cross_check: false,
+        };
+        let accounts = EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+
+        let refreshed = rpc_fallback
+            .refresh_balances(&accounts, *test_vectors::INDEXER_ADDRESS)
+            .await
+            .unwrap();
+
+        for sender in test_vectors::ESCROW_ACCOUNTS_BALANCES.keys() {
+            assert_eq!(
+                refreshed.get_balance_for_sender(sender).unwrap(),
+                U256::from(90)
+            );
+            assert_eq!(
+                refreshed.get_signers_for_sender(sender),
+                accounts.get_signers_for_sender(sender)
+            );
+        }
+
+        // None of the balances of the subgraph is 90
+        let mismatching = Address::from_str("0x22d491bde2303f2f43325b2108d26f1eaba1e32b").unwrap();
+        let mismatches = || {
+            ESCROW_BALANCE_MISMATCH
+                .with_label_values(&[&mismatching.to_string()])
+                .get()
+        };
+        let before = mismatches();
+        cross_check(&accounts, &refreshed);
+        assert_eq!(mismatches(), before + 1.0);
+    }
+
+    #[test(tokio::test)]
+    async fn test_rpc_fallback_failed() {
+        // The escrow subgraph has no accounts at first
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = escrow_subgraph(&mock_server);
+        let queries = AtomicUsize::new(0);
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(move |request: &Request| {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    let introspection = request["query"].as_str().unwrap().contains("__type");
+                    if !introspection && queries.fetch_add(1, Ordering::SeqCst) == 0 {
+                        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                            "data": { "escrowAccounts": [] }
+                        }))
+                    } else {
+                        ResponseTemplate::new(200)
+                            .set_body_raw(test_vectors::ESCROW_QUERY_RESPONSE, "application/json")
+                    }
+                }),
+            )
+            .await;
+        // And the Escrow contract can't be read
+        let rpc_server = MockServer::start().await;
+        rpc_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)))
+            .await;
+
+        let mut accounts = escrow_accounts_watcher(
+            escrow_subgraph,
+            *test_vectors::INDEXER_ADDRESS,
+            Duration::from_millis(50),
+            true,
+            Some(EscrowRpcFallback {
+                rpc_url: Url::parse(&rpc_server.uri()).unwrap(),
+                escrow_address: Address::from([4u8; 20]),
+                max_subgraph_lag: Duration::from_secs(300),
+                cross_check: true,
+            }),
+            None,
+        )
+        .await;
+        assert!(accounts.borrow().get_senders().is_empty());
+
+        // The accounts of the subgraph are published anyway
+        tokio::time::timeout(Duration::from_secs(5), accounts.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            accounts.borrow().get_senders(),
+            test_vectors::ESCROW_ACCOUNTS_BALANCES
+                .keys()
+                .copied()
+                .collect::<HashSet<_>>()
+        );
     }
 }
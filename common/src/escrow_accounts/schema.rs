@@ -0,0 +1,468 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The escrow accounts query, tolerant to the evolution of the escrow subgraph schema.
+//!
+//! The escrow subgraph is upgraded on its own schedule, for example with the Horizon additions.
+//! Instead of a query generated from a copy of its schema, which breaks as soon as a field it
+//! selects is renamed or removed:
+//! - the fields of the schema are detected once, through introspection, and reported in the
+//!   `indexer_escrow_subgraph_capability` metric. They're detected again after a failed query.
+//! - the query only selects the optional fields the schema has. Without `totalAmountThawing`,
+//!   nothing is considered thawing. The signers must be filtered though: without `isAuthorized`,
+//!   or without `thawEndTimestamp` when the thawing signers are rejected, the escrow accounts
+//!   aren't fetched at all, see [`EscrowSchema::check_signer_filters`].
+//! - the response accepts unknown fields, missing optional fields, and `BigInt`s as strings or
+//!   numbers.
+//! - the escrow accounts are queried in pages of [`MAX_PAGE_SIZE`] ordered by `id`, rather than
+//!   only the first ones. Up to as many signers are selected per sender, rather than 100. The
+//!   pages after the first one are pinned to its block, with `_meta`.
+//! - with `paymentsEscrowAccounts`, the Horizon escrow accounts of the indexer are queried in the
+//!   same pages, for their balance per payer and collector.
+//!
+//! If introspection fails, the schema of `graphql/tap.schema.graphql` is assumed.
+
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use alloy::primitives::U256;
+use anyhow::{anyhow, Result};
+use graphql_client::{GraphQLQuery, QueryBody};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::prelude::SubgraphClient;
+
+lazy_static! {
+    static ref CAPABILITIES: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_escrow_subgraph_capability",
+        "Optional fields of the escrow subgraph schema, 1 if present",
+        &["capability"]
+    )
+    .unwrap();
+    /// The queries built for each schema, there's at most two per combination of capabilities,
+    /// pinned to a block or not.
+    static ref QUERIES: Mutex<HashMap<(EscrowSchema, bool), &'static str>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Most entities the subgraph returns for a field at once, the escrow accounts are queried in
+/// pages of this many.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+const INTROSPECTION_QUERY: &str = r#"{
+    query: __type(name: "Query") { fields { name } }
+    escrowAccount: __type(name: "EscrowAccount") { fields { name } }
+    signer: __type(name: "Signer") { fields { name } }
+}"#;
+
+/// Optional fields of the escrow subgraph schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct EscrowSchema {
+    /// `_meta`, the last block indexed.
+    pub meta: bool,
+    /// `EscrowAccount.totalAmountThawing`.
+    pub total_amount_thawing: bool,
+    /// `Signer.thawEndTimestamp`.
+    pub signer_thaw_end_timestamp: bool,
+    /// `Signer.isAuthorized`.
+    pub signer_is_authorized: bool,
+    /// `paymentsEscrowAccounts`, the Horizon escrow accounts.
+    pub horizon: bool,
+}
+
+impl Default for EscrowSchema {
+    /// The schema of `graphql/tap.schema.graphql`.
+    fn default() -> Self {
+        Self {
+            meta: true,
+            total_amount_thawing: true,
+            signer_thaw_end_timestamp: true,
+            signer_is_authorized: true,
+            horizon: false,
+        }
+    }
+}
+
+impl EscrowSchema {
+    pub async fn detect(escrow_subgraph: &SubgraphClient) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Type {
+            fields: Vec<Field>,
+        }
+        #[derive(Deserialize)]
+        struct Field {
+            name: String,
+        }
+        #[derive(Deserialize)]
+        struct Types {
+            query: Option<Type>,
+            #[serde(rename = "escrowAccount")]
+            escrow_account: Option<Type>,
+            signer: Option<Type>,
+        }
+
+        let body = serde_json::to_vec(&serde_json::json!({ "query": INTROSPECTION_QUERY }))?;
+        let response: graphql_client::Response<Types> = escrow_subgraph
+            .query_raw(body.into())
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let types = match (response.data, response.errors) {
+            (Some(types), None) => types,
+            (_, Some(errors)) => return Err(anyhow!("{errors:?}")),
+            (None, None) => return Err(anyhow!("Empty introspection response")),
+        };
+        if types.escrow_account.is_none() {
+            return Err(anyhow!("The schema has no `EscrowAccount` type"));
+        }
+        let has = |ty: &Option<Type>, name: &str| {
+            ty.as_ref()
+                .is_some_and(|ty| ty.fields.iter().any(|field| field.name == name))
+        };
+        Ok(Self {
+            meta: has(&types.query, "_meta"),
+            total_amount_thawing: has(&types.escrow_account, "totalAmountThawing"),
+            signer_thaw_end_timestamp: has(&types.signer, "thawEndTimestamp"),
+            signer_is_authorized: has(&types.signer, "isAuthorized"),
+            horizon: has(&types.query, "paymentsEscrowAccounts"),
+        })
+    }
+
+    /// Detects the schema, or assumes the default one if it can't.
+    pub async fn detect_or_default(escrow_subgraph: &SubgraphClient) -> Self {
+        Self::detect(escrow_subgraph).await.unwrap_or_else(|e| {
+            warn!(
+                "Failed to detect the escrow subgraph schema, assuming the default one: {}",
+                e
+            );
+            Self::default()
+        })
+    }
+
+    pub fn report(&self) {
+        let capabilities = [
+            ("meta", self.meta),
+            ("total_amount_thawing", self.total_amount_thawing),
+            ("signer_thaw_end_timestamp", self.signer_thaw_end_timestamp),
+            ("signer_is_authorized", self.signer_is_authorized),
+            ("horizon", self.horizon),
+        ];
+        for (capability, present) in capabilities {
+            CAPABILITIES
+                .with_label_values(&[capability])
+                .set(present as i64);
+        }
+        info!(schema = ?self, "Escrow subgraph schema detected");
+        if !self.signer_thaw_end_timestamp || !self.signer_is_authorized {
+            error!(
+                "The escrow subgraph can't filter the signers on their thawing or authorization, \
+                no signer is accepted until it can."
+            );
+        }
+    }
+
+    /// Fails if the signers of the senders can't be filtered on their authorization, or on their
+    /// thawing when `reject_thawing_signers`. Their receipts would be accepted otherwise.
+    pub fn check_signer_filters(&self, reject_thawing_signers: bool) -> Result<()> {
+        if !self.signer_is_authorized {
+            return Err(anyhow!(
+                "The escrow subgraph has no `Signer.isAuthorized` to filter the signers on"
+            ));
+        }
+        if reject_thawing_signers && !self.signer_thaw_end_timestamp {
+            return Err(anyhow!(
+                "The escrow subgraph has no `Signer.thawEndTimestamp` to reject the thawing \
+                signers on"
+            ));
+        }
+        Ok(())
+    }
+
+    fn query(&self, pinned: bool) -> &'static str {
+        QUERIES
+            .lock()
+            .unwrap()
+            .entry((*self, pinned))
+            .or_insert_with(|| Box::leak(self.build_query(pinned).into_boxed_str()))
+    }
+
+    /// The query as a subscription, without `_meta`, which would change on every block.
+    pub fn subscription(&self) -> String {
+        Self {
+            meta: false,
+            ..*self
+        }
+        .build_query(false)
+        .replacen("query ", "subscription ", 1)
+    }
+
+    /// `pinned` to the block of the `$block` variable, without `_meta` then.
+    fn build_query(&self, pinned: bool) -> String {
+        let mut filters = Vec::new();
+        if self.signer_thaw_end_timestamp {
+            filters.push("thawEndTimestamp_lte: $thawEndTimestamp");
+        }
+        if self.signer_is_authorized {
+            filters.push("isAuthorized: true");
+        }
+        let signers = if filters.is_empty() {
+            format!("signers(first: {MAX_PAGE_SIZE})")
+        } else {
+            format!(
+                "signers(first: {MAX_PAGE_SIZE}, where: {{ {} }})",
+                filters.join(", ")
+            )
+        };
+        let block = if pinned {
+            "block: { number: $block }, "
+        } else {
+            ""
+        };
+        format!(
+            "query EscrowAccountQuery($indexer: ID!, $first: Int!, $lastId: ID!{}{}{}) {{ {} \
+            escrowAccounts({block}first: $first, orderBy: id, orderDirection: asc, where: \
+            {{ receiver_: {{ id: $indexer }}, id_gt: $lastId }}) {{ id balance {} sender \
+            {{ id {} {{ id }} }} }} {} }}",
+            if self.signer_thaw_end_timestamp {
+                ", $thawEndTimestamp: BigInt!"
+            } else {
+                ""
+            },
+            if self.horizon {
+                ", $firstHorizon: Int!, $lastHorizonId: ID!"
+            } else {
+                ""
+            },
+            if pinned { ", $block: Int!" } else { "" },
+            if self.meta && !pinned {
+                "meta: _meta { block { number timestamp } }"
+            } else {
+                ""
+            },
+            if self.total_amount_thawing {
+                "totalAmountThawing"
+            } else {
+                ""
+            },
+            signers,
+            if self.horizon {
+                format!(
+                    "paymentsEscrowAccounts({block}first: $firstHorizon, orderBy: id, \
+                    orderDirection: asc, where: {{ receiver_: {{ id: $indexer }}, \
+                    id_gt: $lastHorizonId }}) {{ id balance tokensThawing payer {{ id }} \
+                    collector {{ id }} }}"
+                )
+            } else {
+                String::new()
+            },
+        )
+    }
+}
+
+/// Escrow accounts of the indexer, for an [`EscrowSchema`].
+pub struct EscrowAccountQuery;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Variables {
+    pub indexer: String,
+    pub first: usize,
+    /// Of the last escrow account of the previous page, empty for the first one.
+    #[serde(rename = "lastId")]
+    pub last_id: String,
+    #[serde(rename = "thawEndTimestamp", skip_serializing_if = "Option::is_none")]
+    pub thaw_end_timestamp: Option<String>,
+    /// Same as `first` and `last_id` for the Horizon escrow accounts, if the schema has them.
+    #[serde(rename = "firstHorizon", skip_serializing_if = "Option::is_none")]
+    pub first_horizon: Option<usize>,
+    #[serde(rename = "lastHorizonId", skip_serializing_if = "Option::is_none")]
+    pub last_horizon_id: Option<String>,
+    /// Number of the block to pin the query to, the one of the first page for the next ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block: Option<u64>,
+    #[serde(skip)]
+    pub schema: EscrowSchema,
+}
+
+impl GraphQLQuery for EscrowAccountQuery {
+    type Variables = Variables;
+    type ResponseData = ResponseData;
+
+    fn build_query(variables: Self::Variables) -> QueryBody<Self::Variables> {
+        QueryBody {
+            query: variables.schema.query(variables.block.is_some()),
+            variables,
+            operation_name: "EscrowAccountQuery",
+        }
+    }
+}
+
+/// A `BigInt`, a string in the current schema, also accepted as a number.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BigInt {
+    String(String),
+    Number(serde_json::Number),
+}
+
+impl BigInt {
+    pub fn to_u256(&self) -> Result<U256> {
+        Ok(match self {
+            BigInt::String(value) => U256::from_str(value)?,
+            BigInt::Number(value) => U256::from_str(&value.to_string())?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseData {
+    #[serde(default)]
+    pub meta: Option<Meta>,
+    pub escrow_accounts: Vec<EscrowAccount>,
+    #[serde(default)]
+    pub payments_escrow_accounts: Vec<PaymentsEscrowAccount>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Meta {
+    pub block: Block,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Block {
+    #[serde(default)]
+    pub number: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<BigInt>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EscrowAccount {
+    /// Only needed to query the next page.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub balance: BigInt,
+    #[serde(default)]
+    pub total_amount_thawing: Option<BigInt>,
+    pub sender: Sender,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sender {
+    pub id: String,
+    #[serde(default)]
+    pub signers: Option<Vec<Signer>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Signer {
+    pub id: String,
+}
+
+/// A Horizon escrow account of the indexer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentsEscrowAccount {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub balance: BigInt,
+    #[serde(default)]
+    pub tokens_thawing: Option<BigInt>,
+    pub payer: Entity,
+    pub collector: Entity,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entity {
+    pub id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::U256;
+
+    use super::{EscrowSchema, ResponseData};
+
+    #[test]
+    fn test_query_follows_schema() {
+        let query = EscrowSchema::default().query(false);
+        assert!(query.contains("totalAmountThawing"));
+        assert!(query.contains("$thawEndTimestamp: BigInt!"));
+        assert!(query.contains("isAuthorized: true"));
+
+        let query = EscrowSchema {
+            meta: false,
+            total_amount_thawing: false,
+            signer_thaw_end_timestamp: false,
+            signer_is_authorized: false,
+            horizon: true,
+        }
+        .query(false);
+        assert!(!query.contains("_meta"));
+        assert!(!query.contains("totalAmountThawing"));
+        assert!(!query.contains("thawEndTimestamp"));
+        assert!(query.contains("signers(first: 1000) { id }"));
+        assert!(query.contains("id_gt: $lastId"));
+        assert!(query.contains("paymentsEscrowAccounts(first: $firstHorizon"));
+        assert!(!EscrowSchema::default()
+            .query(false)
+            .contains("paymentsEscrowAccounts"));
+
+        let pinned = EscrowSchema {
+            horizon: true,
+            ..Default::default()
+        }
+        .query(true);
+        assert!(pinned.contains("$block: Int!"));
+        assert!(pinned.contains("escrowAccounts(block: { number: $block }, first: $first"));
+        assert!(pinned.contains("paymentsEscrowAccounts(block: { number: $block }, first"));
+        assert!(!pinned.contains("_meta"));
+
+        let subscription = EscrowSchema::default().subscription();
+        assert!(subscription.starts_with("subscription EscrowAccountQuery("));
+        assert!(!subscription.contains("_meta"));
+        assert!(subscription.contains("totalAmountThawing"));
+    }
+
+    #[test]
+    fn test_check_signer_filters() {
+        let schema = EscrowSchema::default();
+        assert!(schema.check_signer_filters(true).is_ok());
+
+        let schema = EscrowSchema {
+            signer_thaw_end_timestamp: false,
+            ..Default::default()
+        };
+        assert!(schema.check_signer_filters(true).is_err());
+        assert!(schema.check_signer_filters(false).is_ok());
+
+        let schema = EscrowSchema {
+            signer_is_authorized: false,
+            ..Default::default()
+        };
+        assert!(schema.check_signer_filters(false).is_err());
+    }
+
+    #[test]
+    fn test_tolerant_response() {
+        let response: ResponseData = serde_json::from_str(
+            r#"{
+                "escrowAccounts": [{
+                    "balance": 34,
+                    "collector": { "id": "0x0000000000000000000000000000000000000001" },
+                    "sender": { "id": "0x9858effd232b4033e47d90003d41ec34ecaeda94" }
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert!(response.meta.is_none());
+        let account = &response.escrow_accounts[0];
+        assert_eq!(account.balance.to_u256().unwrap(), U256::from(34));
+        assert!(account.total_amount_thawing.is_none());
+        assert!(account.sender.signers.is_none());
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signers of receipts authorized by no sender of the escrow accounts.
+//!
+//! Such a receipt is invalid. It usually comes from a misconfigured gateway, signing with a key
+//! it hasn't authorized yet, or whose authorization isn't indexed by the escrow subgraph yet.
+//! Each one is recorded with [`record_unknown_signer`]:
+//! - the latest [`MAX_UNKNOWN_SIGNERS`] signers are counted, and listed by [`unknown_signers`].
+//! - each receipt is broadcast as an [`UnknownSigner`] event to the receivers of
+//!   [`subscribe_unknown_signers`], such as [`log_unknown_signers`] which warns about them.
+//!
+//! Once the escrow accounts authorize a signer, [`resolve_unknown_signers`] returns it with its
+//! sender, and forgets it, so that its receipts can be validated again.
+
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::primitives::Address;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter, IntCounter};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use super::EscrowAccounts;
+
+pub const MAX_UNKNOWN_SIGNERS: usize = 1000;
+/// Events kept for the receivers lagging behind.
+const EVENTS_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref UNKNOWN_SIGNER_RECEIPTS: IntCounter = register_int_counter!(
+        "indexer_unknown_signer_receipts_total",
+        "Receipts signed by a signer authorized by no sender"
+    )
+    .unwrap();
+    static ref UNKNOWN_SIGNERS: Mutex<LruCache<Address, UnknownSigner>> = Mutex::new(
+        LruCache::new(NonZeroUsize::new(MAX_UNKNOWN_SIGNERS).unwrap())
+    );
+    static ref EVENTS: broadcast::Sender<UnknownSigner> = broadcast::channel(EVENTS_CAPACITY).0;
+}
+
+/// A signer authorized by no sender, with its receipts so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UnknownSigner {
+    pub signer: Address,
+    pub receipts: u64,
+    /// Milliseconds since the UNIX epoch.
+    pub first_seen_ms: u64,
+    pub last_seen_ms: u64,
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Records a receipt of `signer`, authorized by no sender.
+pub fn record_unknown_signer(signer: Address) -> UnknownSigner {
+    let now = unix_ms();
+    let event = {
+        let mut signers = UNKNOWN_SIGNERS.lock().unwrap();
+        let unknown = signers.get_or_insert_mut(signer, || UnknownSigner {
+            signer,
+            receipts: 0,
+            first_seen_ms: now,
+            last_seen_ms: now,
+        });
+        unknown.receipts += 1;
+        unknown.last_seen_ms = now;
+        *unknown
+    };
+    UNKNOWN_SIGNER_RECEIPTS.inc();
+    // Without receivers, the event is dropped
+    let _ = EVENTS.send(event);
+    event
+}
+
+/// An event for each receipt recorded from now on. The receivers lagging behind miss the oldest
+/// ones.
+pub fn subscribe_unknown_signers() -> broadcast::Receiver<UnknownSigner> {
+    EVENTS.subscribe()
+}
+
+/// Warns about the receipts of the unknown signers, on the 1st, 10th, 100th... receipt of each
+/// signer, so that a gateway that stays misconfigured keeps being reported without flooding the
+/// logs. Runs until the events stop.
+pub async fn log_unknown_signers() {
+    let mut events = subscribe_unknown_signers();
+    loop {
+        match events.recv().await {
+            Ok(unknown) if is_power_of_ten(unknown.receipts) => warn!(
+                signer = %unknown.signer,
+                receipts = unknown.receipts,
+                first_seen_ms = unknown.first_seen_ms,
+                "Receipts signed by a signer authorized by no sender, its gateway may be \
+                misconfigured"
+            ),
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    missed,
+                    "Missed unknown signer events, the receipts come too fast"
+                )
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+fn is_power_of_ten(mut n: u64) -> bool {
+    while n >= 10 && n % 10 == 0 {
+        n /= 10;
+    }
+    n == 1
+}
+
+/// The signers recorded and not resolved yet, the most recently seen first.
+pub fn unknown_signers() -> Vec<UnknownSigner> {
+    UNKNOWN_SIGNERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, unknown)| *unknown)
+        .collect()
+}
+
+/// The signers recorded that `accounts` authorize now, with their sender. They're forgotten.
+pub fn resolve_unknown_signers(accounts: &EscrowAccounts) -> Vec<(UnknownSigner, Address)> {
+    let mut signers = UNKNOWN_SIGNERS.lock().unwrap();
+    let resolved: Vec<_> = signers
+        .iter()
+        .filter_map(|(signer, unknown)| {
+            accounts
+                .get_sender_for_signer(signer)
+                .ok()
+                .map(|sender| (*unknown, sender))
+        })
+        .collect();
+    for (unknown, _) in &resolved {
+        signers.pop(&unknown.signer);
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::primitives::Address;
+
+    use super::{
+        is_power_of_ten, record_unknown_signer, resolve_unknown_signers, subscribe_unknown_signers,
+        unknown_signers,
+    };
+    use crate::escrow_accounts::EscrowAccounts;
+
+    #[tokio::test]
+    async fn test_unknown_signers() {
+        let signer = Address::repeat_byte(0x51);
+        let sender = Address::repeat_byte(0x52);
+        let mut events = subscribe_unknown_signers();
+
+        record_unknown_signer(signer);
+        let unknown = record_unknown_signer(signer);
+        assert_eq!(unknown.receipts, 2);
+        assert!(unknown_signers().contains(&unknown));
+        // Other tests may record their signers meanwhile
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let event = events.recv().await.unwrap();
+            if event.signer == signer {
+                seen.push(event.receipts);
+            }
+        }
+        assert_eq!(seen, vec![1, 2]);
+
+        assert!(resolve_unknown_signers(&EscrowAccounts::default())
+            .iter()
+            .all(|(unknown, _)| unknown.signer != signer));
+        let accounts = EscrowAccounts::new(HashMap::new(), HashMap::from([(sender, vec![signer])]));
+        assert_eq!(resolve_unknown_signers(&accounts), vec![(unknown, sender)]);
+        assert!(unknown_signers()
+            .iter()
+            .all(|unknown| unknown.signer != signer));
+    }
+
+    #[test]
+    fn test_is_power_of_ten() {
+        let logged: Vec<u64> = (0..=1000).filter(|n| is_power_of_ten(*n)).collect();
+        assert_eq!(logged, vec![1, 10, 100, 1000]);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Amounts of query fees, with the token they're denominated in.
+//!
+//! All the fees are in GRT today, and most of the code still handles them as `u128` values in
+//! wei. The token is written next to the values in the `fee_token` column of the receipts and
+//! RAVs tables, so that the rows of another token, after a migration to it, can't be mistaken for
+//! GRT. Every write of a receipt or RAV sets it, and the moves between the tables (quarantine,
+//! archive) carry it over. A [`FeeAmount`] carries the token along with the value, and refuses to
+//! mix tokens.
+
+use std::{fmt, str::FromStr};
+
+use bigdecimal::{
+    num_bigint::{BigInt, ToBigInt},
+    BigDecimal, ToPrimitive,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FeeToken {
+    #[default]
+    #[serde(rename = "GRT")]
+    Grt,
+}
+
+impl FeeToken {
+    /// As in the `fee_token` columns.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            FeeToken::Grt => "GRT",
+        }
+    }
+
+    /// The values are in units of 10^-decimals token.
+    pub fn decimals(&self) -> u32 {
+        match self {
+            FeeToken::Grt => 18,
+        }
+    }
+}
+
+impl fmt::Display for FeeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+impl FromStr for FeeToken {
+    type Err = FeeAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GRT" => Ok(FeeToken::Grt),
+            _ => Err(FeeAmountError::UnknownToken(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FeeAmountError {
+    #[error("Unknown fee token {0}")]
+    UnknownToken(String),
+    #[error("Fees in {0} and {1} can't be combined")]
+    MixedTokens(FeeToken, FeeToken),
+    #[error("Fee value {0} out of range")]
+    OutOfRange(String),
+}
+
+/// A fee value, in the smallest unit of its token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FeeAmount {
+    pub value: u128,
+    pub token: FeeToken,
+}
+
+impl FeeAmount {
+    pub fn new(value: u128, token: FeeToken) -> Self {
+        Self { value, token }
+    }
+
+    /// `value` GRT wei.
+    pub fn grt(value: u128) -> Self {
+        Self::new(value, FeeToken::Grt)
+    }
+
+    /// A value read from the database, with the `fee_token` of its row.
+    pub fn from_decimal(value: &BigDecimal, token: &str) -> Result<Self, FeeAmountError> {
+        Ok(Self::new(
+            // BigDecimal::to_u128() goes through to_u64()
+            value
+                .to_bigint()
+                .filter(|_| value.is_integer())
+                .and_then(|value| value.to_u128())
+                .ok_or_else(|| FeeAmountError::OutOfRange(value.to_string()))?,
+            token.parse()?,
+        ))
+    }
+
+    pub fn to_decimal(&self) -> BigDecimal {
+        BigDecimal::from(BigInt::from(self.value))
+    }
+
+    pub fn checked_add(self, other: FeeAmount) -> Result<Self, FeeAmountError> {
+        self.same_token(&other)?;
+        let value = self
+            .value
+            .checked_add(other.value)
+            .ok_or_else(|| FeeAmountError::OutOfRange(format!("{} + {}", self, other)))?;
+        Ok(Self::new(value, self.token))
+    }
+
+    pub fn saturating_sub(self, other: FeeAmount) -> Result<Self, FeeAmountError> {
+        self.same_token(&other)?;
+        Ok(Self::new(
+            self.value.saturating_sub(other.value),
+            self.token,
+        ))
+    }
+
+    /// The value in whole tokens, approximated, for metrics and logs.
+    pub fn to_tokens(&self) -> f64 {
+        self.value as f64 / 10f64.powi(self.token.decimals() as i32)
+    }
+
+    /// The value in whole tokens, exactly, without the symbol, such as `1.5`.
+    pub fn to_tokens_string(&self) -> String {
+        let unit = 10u128.pow(self.token.decimals());
+        let (whole, fraction) = (self.value / unit, self.value % unit);
+        if fraction == 0 {
+            return whole.to_string();
+        }
+        let fraction = format!(
+            "{:0width$}",
+            fraction,
+            width = self.token.decimals() as usize
+        );
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+
+    fn same_token(&self, other: &FeeAmount) -> Result<(), FeeAmountError> {
+        if self.token != other.token {
+            return Err(FeeAmountError::MixedTokens(self.token, other.token));
+        }
+        Ok(())
+    }
+}
+
+/// In whole tokens, exactly, such as `1.5 GRT`.
+impl fmt::Display for FeeAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.to_tokens_string(), self.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigdecimal::BigDecimal;
+
+    use super::{FeeAmount, FeeAmountError, FeeToken};
+
+    #[test]
+    fn test_fee_amount() {
+        let amount = FeeAmount::grt(1_500_000_000_000_000_000);
+        assert_eq!(amount.to_string(), "1.5 GRT");
+        assert_eq!(FeeAmount::grt(2 * 10u128.pow(18)).to_string(), "2 GRT");
+        assert_eq!(FeeAmount::grt(1).to_string(), "0.000000000000000001 GRT");
+        assert_eq!(amount.to_tokens(), 1.5);
+        assert_eq!(amount.to_tokens_string(), "1.5");
+        assert_eq!(FeeAmount::grt(0).to_tokens_string(), "0");
+
+        assert_eq!(
+            amount.checked_add(FeeAmount::grt(1)).unwrap().value,
+            1_500_000_000_000_000_001
+        );
+        assert!(matches!(
+            FeeAmount::grt(u128::MAX).checked_add(FeeAmount::grt(1)),
+            Err(FeeAmountError::OutOfRange(_))
+        ));
+        assert_eq!(
+            FeeAmount::grt(1).saturating_sub(amount).unwrap(),
+            FeeAmount::grt(0)
+        );
+    }
+
+    #[test]
+    fn test_fee_amount_from_database() {
+        let amount = FeeAmount::from_decimal(&BigDecimal::from(100), "GRT").unwrap();
+        assert_eq!(amount, FeeAmount::new(100, FeeToken::Grt));
+        assert_eq!(amount.to_decimal(), BigDecimal::from(100));
+        assert_eq!(
+            FeeAmount::from_decimal(&BigDecimal::from(100), "USDC"),
+            Err(FeeAmountError::UnknownToken("USDC".to_string()))
+        );
+        assert!(FeeAmount::from_decimal(&BigDecimal::from(-1), "GRT").is_err());
+        let large = BigDecimal::from_str("100000000000000000000000").unwrap();
+        assert_eq!(
+            FeeAmount::from_decimal(&large, "GRT").unwrap().value,
+            100_000 * 10u128.pow(18)
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Time of the last successful poll of a watcher.
+///
+/// The watchers only publish the values that changed, so a silent watcher can't be told apart
+/// from a wedged one by its updates alone. They beat on every successful poll instead.
+///
+/// Each poller has its own heartbeat. Once it's replaced, its heartbeat is retired, and it stops
+/// at its next poll, if it ever gets to it.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    last_beat: Arc<Mutex<Instant>>,
+    retired: Arc<AtomicBool>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last_beat: Arc::new(Mutex::new(Instant::now())),
+            retired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn beat(&self) {
+        *self.last_beat.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since the last beat, or since the creation if there wasn't any.
+    pub fn elapsed(&self) -> Duration {
+        self.last_beat.lock().unwrap().elapsed()
+    }
+
+    /// Tells the poller to stop, it was replaced.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+}
@@ -1,11 +1,20 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap, net::SocketAddr, num::NonZeroUsize, path::PathBuf, time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use thegraph_core::{Address, DeploymentId};
 
+use super::receipt_rate_limit::{ReceiptRateLimit, ReceiptRateLimitConfig};
+use crate::{
+    listener::Listener,
+    subgraph_client::{LocalFailoverConfig, QueryCacheConfig, QueryPolicy, SubscriptionConfig},
+    tap::CustomCheckConfig,
+};
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DatabaseConfig {
     pub postgres_url: String,
@@ -22,12 +31,20 @@ pub struct SubgraphConfig {
     pub query_auth_token: Option<String>,
     pub syncing_interval: u64,
     pub recently_closed_allocation_buffer_seconds: u64,
+    #[serde(default)]
+    pub query_policy: QueryPolicy,
+    #[serde(default)]
+    pub cache: Option<QueryCacheConfig>,
+    #[serde(default)]
+    pub local_failover: Option<LocalFailoverConfig>,
+    #[serde(default)]
+    pub subscription: Option<SubscriptionConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
-    pub host_and_port: SocketAddr,
-    pub metrics_host_and_port: SocketAddr,
+    pub listener: Listener,
+    pub metrics_listener: Listener,
     pub url_prefix: String,
     pub free_query_auth_token: Option<String>,
 }
@@ -42,6 +59,18 @@ pub struct IndexerServiceConfig {
     pub escrow_subgraph: SubgraphConfig,
     pub graph_network: GraphNetworkConfig,
     pub tap: TapConfig,
+    /// See [`super::peer_relay`].
+    #[serde(default)]
+    pub peer_relay: Option<PeerRelayConfig>,
+    /// See [`super::response_cache`].
+    #[serde(default)]
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// See [`super::receipt_rate_limit`].
+    #[serde(default)]
+    pub receipt_rate_limit: Option<ReceiptRateLimitConfig>,
+    /// See [`super::dips`].
+    #[serde(default)]
+    pub dips: Option<DipsConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -58,13 +87,65 @@ pub struct GraphNetworkConfig {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IndexerConfig {
     pub indexer_address: Address,
-    pub operator_mnemonic: String,
+    /// Only unset with `web3signer_url`, or an attestation signer backend.
+    #[serde(default)]
+    pub operator_mnemonic: Option<String>,
+    /// Signs the attestations instead of the operator mnemonic, see
+    /// [`crate::attestations::backend::Web3Signer`].
+    #[serde(default)]
+    pub web3signer_url: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TapConfig {
     pub chain_id: u64,
     pub receipts_verifier_address: Address,
-    pub timestamp_error_tolerance: u64,
+    /// How far ahead of the clock of the indexer the timestamp of a receipt can be.
+    pub max_future_skew: Duration,
+    /// How far behind the clock of the indexer the timestamp of a receipt can be.
+    pub max_receipt_age: Duration,
     pub receipt_max_value: u128,
+    /// Names of the checks run in shadow mode, see [`crate::tap::IndexerTapContext::get_checks`].
+    #[serde(default)]
+    pub shadow_checks: Vec<String>,
+    /// Names of the checks not run at all.
+    #[serde(default)]
+    pub disabled_checks: Vec<String>,
+    /// Run after the built-in checks.
+    #[serde(default)]
+    pub custom_checks: Vec<CustomCheckConfig>,
+    /// Fraction of the receipts profiled, see [`crate::receipt_profiler`].
+    #[serde(default)]
+    pub receipt_profiling_ratio: f64,
+    /// How often tap-agent updates the rate limits of the senders, applied by the
+    /// `sender_rate_limit` check.
+    pub rate_limits_interval: Duration,
+    /// Number of replicas of indexer-service sharing the rate limits of the senders.
+    pub rate_limits_replicas: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PeerRelayConfig {
+    pub listener: SocketAddr,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Issuer of the client certificates of the peers.
+    pub client_ca_cert_path: PathBuf,
+    /// SHA-256 fingerprints of the client certificates of the peers, by peer name.
+    pub peers: HashMap<String, String>,
+    pub max_batch_size: usize,
+    /// Of the receipts of each peer.
+    pub rate_limit: ReceiptRateLimit,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DipsConfig {
+    /// The RecurringCollector contract, verifier of the indexing fee vouchers.
+    pub verifier_address: Address,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ResponseCacheConfig {
+    pub max_entries: NonZeroUsize,
+    pub ttl: Duration,
 }
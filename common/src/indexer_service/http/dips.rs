@@ -0,0 +1,194 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Intake of the indexing fee vouchers (DIPS), see [`crate::tap::dips`].
+//!
+//! The payers send their vouchers with `POST /dips/vouchers`, one at a time, as they're few. A
+//! voucher is only stored if it's for the indexer and signed by one of the signers of its payer,
+//! in `dips_fee_vouchers` where tap-agent aggregates them. The agreement of the voucher isn't
+//! checked here: indexer-agent may not have recorded it yet, tap-agent checks it once it has.
+
+use std::sync::Arc;
+
+use alloy::{dyn_abi::Eip712Domain, hex::ToHexExt};
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use bigdecimal::num_bigint::BigInt;
+use eventuals::Eventual;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use sqlx::{types::BigDecimal, PgPool};
+use thegraph_core::Address;
+use tracing::error;
+
+use crate::{escrow_accounts::EscrowAccounts, tap::dips::SignedVoucher};
+
+lazy_static! {
+    static ref VOUCHERS: IntCounterVec = register_int_counter_vec!(
+        "indexer_dips_vouchers_total",
+        "Indexing fee vouchers sent by the payers, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+pub struct DipsState {
+    pub pgpool: PgPool,
+    pub escrow_accounts: Eventual<EscrowAccounts>,
+    /// Of the RecurringCollector contract.
+    pub domain_separator: Eip712Domain,
+    pub indexer: Address,
+}
+
+pub fn router(state: Arc<DipsState>) -> Router {
+    Router::new()
+        .route("/dips/vouchers", post(store_voucher))
+        .with_state(state)
+}
+
+async fn store_voucher(
+    State(state): State<Arc<DipsState>>,
+    Json(voucher): Json<SignedVoucher>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = check_and_store(&state, &voucher).await;
+    VOUCHERS
+        .with_label_values(&[match &result {
+            Ok(()) => "accepted",
+            Err((StatusCode::INTERNAL_SERVER_ERROR, _)) => "error",
+            Err(_) => "rejected",
+        }])
+        .inc();
+    result.map(|()| StatusCode::OK)
+}
+
+async fn check_and_store(
+    state: &DipsState,
+    voucher: &SignedVoucher,
+) -> Result<(), (StatusCode, String)> {
+    let message = &voucher.message;
+    if message.serviceProvider != state.indexer {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Voucher service provider {} is not the indexer {}",
+                message.serviceProvider, state.indexer
+            ),
+        ));
+    }
+    let signer = voucher
+        .recover_signer(&state.domain_separator)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid signature: {e}")))?;
+    let escrow_accounts = state.escrow_accounts.value_immediate().unwrap_or_default();
+    if escrow_accounts.get_sender_for_signer(&signer).ok() != Some(message.payer) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!(
+                "Voucher signer {} is not a signer of the payer {}",
+                signer, message.payer
+            ),
+        ));
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO dips_fee_vouchers (
+                signer_address, signature, agreement_id, payer, service_provider,
+                timestamp_ns, nonce, value
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        signer.encode_hex(),
+        voucher.signature.as_bytes().to_vec(),
+        message.agreementId.encode_hex(),
+        message.payer.encode_hex(),
+        message.serviceProvider.encode_hex(),
+        BigDecimal::from(message.timestampNs),
+        BigDecimal::from(message.nonce),
+        BigDecimal::from(BigInt::from(message.value)),
+    )
+    .execute(&state.pgpool)
+    .await
+    .map_err(|e| {
+        error!("Failed to store an indexing fee voucher: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to store the voucher".to_string(),
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use alloy::primitives::{Address, FixedBytes, U256};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use eventuals::Eventual;
+    use sqlx::PgPool;
+    use tap_core::signed_message::EIP712SignedMessage;
+    use tower::ServiceExt;
+
+    use super::{router, DipsState};
+    use crate::{
+        escrow_accounts::EscrowAccounts,
+        tap::dips::{self, IndexingFeeVoucher, SignedVoucher},
+        test_vectors::{INDEXER_ADDRESS, TAP_SENDER, TAP_SIGNER},
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_store_voucher(pgpool: PgPool) {
+        let domain_separator = dips::eip712_domain(1, Address::from([0x44u8; 20]));
+        let app = router(Arc::new(DipsState {
+            pgpool: pgpool.clone(),
+            escrow_accounts: Eventual::from_value(EscrowAccounts::new(
+                HashMap::from([(TAP_SENDER.1, U256::from(1000))]),
+                HashMap::from([(TAP_SENDER.1, vec![TAP_SIGNER.1])]),
+            )),
+            domain_separator: domain_separator.clone(),
+            indexer: *INDEXER_ADDRESS,
+        }));
+        let voucher = |payer: Address, service_provider: Address| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                IndexingFeeVoucher {
+                    agreementId: FixedBytes([0x42u8; 16]),
+                    payer,
+                    serviceProvider: service_provider,
+                    timestampNs: 1,
+                    nonce: 1,
+                    value: 100,
+                },
+                &TAP_SIGNER.0,
+            )
+            .unwrap()
+        };
+        let send = |voucher: SignedVoucher| {
+            app.clone().oneshot(
+                Request::post("/dips/vouchers")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&voucher).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        let response = send(voucher(TAP_SENDER.1, *INDEXER_ADDRESS)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // For another indexer
+        let response = send(voucher(TAP_SENDER.1, Address::ZERO)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Not signed by a signer of the payer
+        let response = send(voucher(Address::ZERO, *INDEXER_ADDRESS))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let count = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM dips_fee_vouchers"#)
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -4,31 +4,32 @@
 use alloy::dyn_abi::Eip712Domain;
 use anyhow;
 use axum::extract::MatchedPath;
-use axum::extract::Request as ExtractRequest;
-use axum::http::{Method, Request};
+use axum::http::{header, Method, Request};
 use axum::{
     async_trait,
     response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Json, Router,
 };
-use axum::{serve, ServiceExt};
 use build_info::BuildInfo;
 use eventuals::Eventual;
 use prometheus::TextEncoder;
-use reqwest::StatusCode;
+use reqwest::{StatusCode, Url};
 use serde::{de::DeserializeOwned, Serialize};
 use sqlx::postgres::PgPoolOptions;
 use std::{
-    collections::HashMap, error::Error, fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Debug,
+    path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 use tap_core::{manager::Manager, receipt::checks::CheckList, tap_eip712_domain};
 use thegraph_core::{Address, Attestation, DeploymentId};
 use thiserror::Error;
-use tokio::net::TcpListener;
 use tokio::signal;
-use tokio::sync::watch::Receiver;
+use tokio::sync::watch::{self, Receiver};
 use tower_governor::{governor::GovernorConfigBuilder, GovernorLayer};
 use tower_http::{cors, cors::CorsLayer, normalize_path::NormalizePath, trace::TraceLayer};
 use tracing::error;
@@ -36,17 +37,32 @@ use tracing::{info, info_span};
 
 use crate::escrow_accounts::EscrowAccounts;
 use crate::escrow_accounts::EscrowAccountsError;
+use crate::escrow_accounts::{log_unknown_signers, unknown_signers};
 use crate::{
     address::public_key,
+    attestations::{
+        backend::{AttestationSignerBackend, Web3Signer},
+        signers::AllocationKeys,
+    },
     indexer_service::http::static_subgraph::static_subgraph_request_handler,
+    listener::{self, Listener},
     prelude::{
-        attestation_signers, dispute_manager, escrow_accounts, indexer_allocations,
-        AttestationSigner, DeploymentDetails, SubgraphClient,
+        attestation_signers, dispute_manager, escrow_accounts_eventual, escrow_accounts_watcher,
+        indexer_allocations, DeploymentDetails, ProtocolNetwork, SignersState, SubgraphClient,
     },
-    tap::IndexerTapContext,
+    receipt_profiler::Sampler,
+    secrets,
+    tap::{rejection::RejectionCode, IndexerTapContext},
 };
 
-use super::{request_handler::request_handler, IndexerServiceConfig};
+use super::{
+    dips::{self, DipsState},
+    peer_relay::{self, PeerRelayState},
+    receipt_rate_limit::ReceiptRateLimiter,
+    request_handler::request_handler,
+    response_cache::ResponseCache,
+    IndexerServiceConfig,
+};
 
 pub trait IndexerServiceResponse {
     type Data: IntoResponse;
@@ -66,7 +82,8 @@ pub enum AttestationOutput {
 pub trait IndexerServiceImpl {
     type Error: std::error::Error;
     type Request: DeserializeOwned + Send + Debug + Serialize;
-    type Response: IndexerServiceResponse + Sized;
+    /// Cloned into the [`ResponseCache`] when it's enabled.
+    type Response: IndexerServiceResponse + Clone + Sized;
     type State: Send + Sync;
 
     async fn process_request(
@@ -74,6 +91,12 @@ pub trait IndexerServiceImpl {
         manifest_id: DeploymentId,
         request: Self::Request,
     ) -> Result<(Self::Request, Self::Response), Self::Error>;
+
+    /// Whether the response to `request` can't change, such as a query pinned to a block by its
+    /// hash. Only those are cached in the [`ResponseCache`].
+    fn is_deterministic(&self, _request: &Self::Request) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Error)]
@@ -105,6 +128,28 @@ where
 
     #[error("There was an error while accessing escrow account: {0}")]
     EscrowAccount(EscrowAccountsError),
+
+    #[error("Sender `{sender}` is over its receipt rate limit, retry in {:.1}s", retry_after.as_secs_f64())]
+    RateLimited {
+        sender: Address,
+        retry_after: Duration,
+    },
+}
+
+impl<E> IndexerServiceError<E>
+where
+    E: std::error::Error,
+{
+    /// Set if the receipt was rejected, for the gateway to know why.
+    pub fn rejection_code(&self) -> Option<RejectionCode> {
+        match self {
+            IndexerServiceError::ReceiptError(e) => Some(RejectionCode::classify(&e.to_string())),
+            IndexerServiceError::CouldNotDecodeSigner(_) => Some(RejectionCode::BadSignature),
+            IndexerServiceError::EscrowAccount(_) => Some(RejectionCode::UnknownSigner),
+            IndexerServiceError::RateLimited { .. } => Some(RejectionCode::RateLimited),
+            _ => None,
+        }
+    }
 }
 
 impl<E> IntoResponse for IndexerServiceError<E>
@@ -117,11 +162,22 @@ where
         #[derive(Serialize)]
         struct ErrorResponse {
             message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            code: Option<RejectionCode>,
         }
 
+        // Whole seconds, rounded up
+        let retry_after = match &self {
+            RateLimited { retry_after, .. } => {
+                Some(retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64)
+            }
+            _ => None,
+        };
         let status = match self {
             ServiceNotReady => StatusCode::SERVICE_UNAVAILABLE,
 
+            RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+
             Unauthorized => StatusCode::UNAUTHORIZED,
 
             NoSignerForAllocation(_) | FailedToSignAttestation => StatusCode::INTERNAL_SERVER_ERROR,
@@ -135,14 +191,25 @@ where
 
             FailedToQueryStaticSubgraph(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        tracing::error!(%self, "An IndexerServiceError occoured.");
-        (
+        // Counted by `indexer_receipts_rate_limited_total` instead, a flood of receipts would
+        // flood the logs
+        if retry_after.is_none() {
+            tracing::error!(%self, "An IndexerServiceError occoured.");
+        }
+        let mut response = (
             status,
             Json(ErrorResponse {
                 message: self.to_string(),
+                code: self.rejection_code(),
             }),
         )
-            .into_response()
+            .into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+        response
     }
 }
 
@@ -176,6 +243,14 @@ where
     pub release: IndexerServiceRelease,
     pub url_namespace: &'static str,
     pub extra_routes: Router<Arc<IndexerServiceState<I>>>,
+    /// Rotations of the Postgres URL, see [`crate::secrets`].
+    pub postgres_urls: Option<Receiver<String>>,
+    /// Rotations of the operator mnemonic, see [`crate::secrets`].
+    pub operator_mnemonics: Option<Receiver<String>>,
+    /// Signs the attestations instead of the operator mnemonic, see
+    /// [`crate::attestations::backend`]. A [`Web3Signer`] if unset and
+    /// `service.attestation_signer.web3signer_url` is.
+    pub attestation_signer_backend: Option<Arc<dyn AttestationSignerBackend>>,
 }
 
 pub struct IndexerServiceState<I>
@@ -183,13 +258,21 @@ where
     I: IndexerServiceImpl + Sync + Send + 'static,
 {
     pub config: IndexerServiceConfig,
-    pub attestation_signers: Receiver<HashMap<Address, AttestationSigner>>,
+    pub attestation_signers: Receiver<SignersState>,
+    /// The allocations whose signer couldn't be created, their queries are refused.
+    pub allocations_missing_signers: Receiver<HashSet<Address>>,
     pub tap_manager: Manager<IndexerTapContext>,
     pub service_impl: Arc<I>,
 
     // tap
     pub escrow_accounts: Eventual<EscrowAccounts>,
     pub domain_separator: Eip712Domain,
+    /// See [`crate::receipt_profiler`].
+    pub receipt_sampler: Sampler,
+    /// See [`super::response_cache`], disabled if unset.
+    pub response_cache: Option<ResponseCache<I::Response>>,
+    /// See [`super::receipt_rate_limit`], disabled if unset.
+    pub receipt_rate_limiter: Option<ReceiptRateLimiter>,
 }
 
 pub struct IndexerService {}
@@ -205,26 +288,35 @@ impl IndexerService {
             .build()
             .expect("Failed to init HTTP client");
 
-        let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
-            http_client.clone(),
-            options
-                .config
-                .graph_node
-                .as_ref()
-                .zip(options.config.network_subgraph.deployment)
-                .map(|(graph_node, deployment)| {
-                    DeploymentDetails::for_graph_node(
-                        &graph_node.status_url,
-                        &graph_node.query_base_url,
-                        deployment,
-                    )
-                })
-                .transpose()?,
-            DeploymentDetails::for_query_url_with_token(
-                &options.config.network_subgraph.query_url,
-                options.config.network_subgraph.query_auth_token.clone(),
-            )?,
-        )));
+        let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(
+            SubgraphClient::new(
+                http_client.clone(),
+                options
+                    .config
+                    .graph_node
+                    .as_ref()
+                    .zip(options.config.network_subgraph.deployment)
+                    .map(|(graph_node, deployment)| {
+                        DeploymentDetails::for_graph_node(
+                            &graph_node.status_url,
+                            &graph_node.query_base_url,
+                            deployment,
+                        )
+                    })
+                    .transpose()?,
+                DeploymentDetails::for_query_url_with_token(
+                    &options.config.network_subgraph.query_url,
+                    options.config.network_subgraph.query_auth_token.clone(),
+                )?,
+            )
+            .with_policy(
+                "network",
+                options.config.network_subgraph.query_policy.clone(),
+            )
+            .with_cache(options.config.network_subgraph.cache.clone())
+            .with_local_failover(options.config.network_subgraph.local_failover.clone())
+            .with_subscription(options.config.network_subgraph.subscription.clone()),
+        ));
 
         // Identify the dispute manager for the configured network
         let dispute_manager = dispute_manager(network_subgraph, Duration::from_secs(3600));
@@ -240,44 +332,88 @@ impl IndexerService {
                     .network_subgraph
                     .recently_closed_allocation_buffer_seconds,
             ),
+            None,
         );
 
+        let operator_mnemonics = options.operator_mnemonics.or_else(|| {
+            options
+                .config
+                .indexer
+                .operator_mnemonic
+                .clone()
+                .map(|mnemonic| watch::channel(mnemonic).1)
+        });
+
+        let allocation_keys = match (
+            options.attestation_signer_backend,
+            &options.config.indexer.web3signer_url,
+            &operator_mnemonics,
+        ) {
+            (Some(backend), _, _) => AllocationKeys::Backend(backend),
+            (None, Some(url), _) => AllocationKeys::Backend(Arc::new(Web3Signer::new(
+                http_client.clone(),
+                Url::parse(url)?,
+            ))),
+            (None, None, Some(operator_mnemonics)) => {
+                AllocationKeys::Mnemonic(operator_mnemonics.clone())
+            }
+            (None, None, None) => anyhow::bail!(
+                "`indexer.operator_mnemonic` must be set without an attestation signer backend"
+            ),
+        };
+
         // Maintain an up-to-date set of attestation signers, one for each
         // allocation
-        let attestation_signers = attestation_signers(
-            allocations.clone(),
-            options.config.indexer.operator_mnemonic.clone(),
+        let networks = HashMap::from([(
             options.config.graph_network.chain_id,
-            dispute_manager,
-        )
-        .await;
-
-        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
-            http_client,
-            options
-                .config
-                .graph_node
-                .as_ref()
-                .zip(options.config.escrow_subgraph.deployment)
-                .map(|(graph_node, deployment)| {
-                    DeploymentDetails::for_graph_node(
-                        &graph_node.status_url,
-                        &graph_node.query_base_url,
-                        deployment,
-                    )
-                })
-                .transpose()?,
-            DeploymentDetails::for_query_url_with_token(
-                &options.config.escrow_subgraph.query_url,
-                options.config.escrow_subgraph.query_auth_token.clone(),
-            )?,
-        )));
-
-        let escrow_accounts = escrow_accounts(
-            escrow_subgraph,
-            options.config.indexer.indexer_address,
-            Duration::from_secs(options.config.escrow_subgraph.syncing_interval),
-            true, // Reject thawing signers eagerly
+            ProtocolNetwork {
+                allocations: allocations.clone(),
+                dispute_manager,
+            },
+        )]);
+        let (attestation_signers, allocations_missing_signers) =
+            attestation_signers(networks, allocation_keys).await;
+
+        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(
+            SubgraphClient::new(
+                http_client,
+                options
+                    .config
+                    .graph_node
+                    .as_ref()
+                    .zip(options.config.escrow_subgraph.deployment)
+                    .map(|(graph_node, deployment)| {
+                        DeploymentDetails::for_graph_node(
+                            &graph_node.status_url,
+                            &graph_node.query_base_url,
+                            deployment,
+                        )
+                    })
+                    .transpose()?,
+                DeploymentDetails::for_query_url_with_token(
+                    &options.config.escrow_subgraph.query_url,
+                    options.config.escrow_subgraph.query_auth_token.clone(),
+                )?,
+            )
+            .with_policy(
+                "escrow",
+                options.config.escrow_subgraph.query_policy.clone(),
+            )
+            .with_cache(options.config.escrow_subgraph.cache.clone())
+            .with_local_failover(options.config.escrow_subgraph.local_failover.clone())
+            .with_subscription(options.config.escrow_subgraph.subscription.clone()),
+        ));
+
+        let escrow_accounts = escrow_accounts_eventual(
+            escrow_accounts_watcher(
+                escrow_subgraph,
+                options.config.indexer.indexer_address,
+                Duration::from_secs(options.config.escrow_subgraph.syncing_interval),
+                true, // Reject thawing signers eagerly
+                None,
+                None,
+            )
+            .await,
         );
 
         // Establish Database connection necessary for serving indexer management
@@ -292,6 +428,9 @@ impl IndexerService {
             .acquire_timeout(Duration::from_secs(30))
             .connect(&options.config.database.postgres_url)
             .await?;
+        if let Some(urls) = options.postgres_urls {
+            secrets::rotate_postgres_url(vec![database.clone()], urls);
+        }
 
         let domain_separator = tap_eip712_domain(
             options.config.tap.chain_id,
@@ -299,20 +438,58 @@ impl IndexerService {
         );
         let indexer_context =
             IndexerTapContext::new(database.clone(), domain_separator.clone()).await;
-        let timestamp_error_tolerance =
-            Duration::from_secs(options.config.tap.timestamp_error_tolerance);
+        let relay = if let Some(peer_relay) = &options.config.peer_relay {
+            // Every check is enforced for the relayed receipts, the shadow ones included
+            let relay_checks = IndexerTapContext::get_checks(
+                database.clone(),
+                allocations.clone(),
+                escrow_accounts.clone(),
+                domain_separator.clone(),
+                &options.config.tap,
+                &[],
+            )
+            .await?;
+            let relay_state = Arc::new(PeerRelayState::new(
+                Manager::new(
+                    domain_separator.clone(),
+                    IndexerTapContext::new(database.clone(), domain_separator.clone()).await,
+                    CheckList::new(relay_checks),
+                ),
+                allocations.clone(),
+                attestation_signers.clone(),
+                peer_relay.max_batch_size,
+                peer_relay.rate_limit,
+            ));
+            Some(peer_relay::serve(peer_relay, relay_state).await?)
+        } else {
+            None
+        };
 
-        let receipt_max_value = options.config.tap.receipt_max_value;
+        let dips_routes = match &options.config.dips {
+            Some(dips) => {
+                info!("Taking the indexing fee vouchers at /dips/vouchers");
+                dips::router(Arc::new(DipsState {
+                    pgpool: database.clone(),
+                    escrow_accounts: escrow_accounts.clone(),
+                    domain_separator: crate::tap::dips::eip712_domain(
+                        options.config.tap.chain_id,
+                        dips.verifier_address,
+                    ),
+                    indexer: options.config.indexer.indexer_address,
+                }))
+            }
+            None => Router::new(),
+        };
 
         let checks = IndexerTapContext::get_checks(
             database,
             allocations,
             escrow_accounts.clone(),
             domain_separator.clone(),
-            timestamp_error_tolerance,
-            receipt_max_value,
+            &options.config.tap,
+            &options.config.tap.shadow_checks,
         )
-        .await;
+        .await?;
 
         let tap_manager = Manager::new(
             domain_separator.clone(),
@@ -323,10 +500,22 @@ impl IndexerService {
         let state = Arc::new(IndexerServiceState {
             config: options.config.clone(),
             attestation_signers,
+            allocations_missing_signers,
             tap_manager,
             service_impl: Arc::new(options.service_impl),
             escrow_accounts,
             domain_separator,
+            receipt_sampler: Sampler::new(options.config.tap.receipt_profiling_ratio),
+            response_cache: options
+                .config
+                .response_cache
+                .as_ref()
+                .map(|cache| ResponseCache::new(cache.max_entries, cache.ttl)),
+            receipt_rate_limiter: options
+                .config
+                .receipt_rate_limit
+                .clone()
+                .map(ReceiptRateLimiter::new),
         });
 
         // Rate limits by allowing bursts of 10 requests and requiring 100ms of
@@ -342,15 +531,24 @@ impl IndexerService {
             )),
         };
 
-        let operator_address = Json(
-            serde_json::json!({ "publicKey": public_key(&options.config.indexer.operator_mnemonic)?}),
-        );
-
         let mut misc_routes = Router::new()
             .route("/", get("Service is up and running"))
-            .route("/version", get(Json(options.release)))
-            .route("/info", get(operator_address))
-            .layer(misc_rate_limiter);
+            .route("/version", get(Json(options.release)));
+        // The operator key is only known from the mnemonic
+        if let Some(operator_mnemonics) = operator_mnemonics {
+            // Fails on startup rather than on the first request
+            public_key(&operator_mnemonics.borrow())?;
+            let operator_address = move || {
+                let public_key = public_key(&operator_mnemonics.borrow());
+                async move {
+                    public_key
+                        .map(|public_key| Json(serde_json::json!({ "publicKey": public_key })))
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            };
+            misc_routes = misc_routes.route("/info", get(operator_address));
+        }
+        let mut misc_routes = misc_routes.layer(misc_rate_limiter);
 
         // Rate limits by allowing bursts of 50 requests and requiring 20ms of
         // time between consecutive requests after that, effectively rate
@@ -406,6 +604,7 @@ impl IndexerService {
         let router = NormalizePath::trim_trailing_slash(
             misc_routes
                 .merge(data_routes)
+                .merge(dips_routes)
                 .merge(options.extra_routes)
                 .layer(
                     CorsLayer::new()
@@ -440,55 +639,56 @@ impl IndexerService {
                 .with_state(state),
         );
 
-        Self::serve_metrics(options.config.server.metrics_host_and_port);
+        tokio::spawn(log_unknown_signers());
+        Self::serve_metrics(options.config.server.metrics_listener);
 
         info!(
-            address = %options.config.server.host_and_port,
+            address = %options.config.server.listener.address,
             "Serving requests",
         );
-        let listener = TcpListener::bind(&options.config.server.host_and_port)
-            .await
-            .expect("Failed to bind to indexer-service port");
-
-        Ok(serve(
-            listener,
-            ServiceExt::<ExtractRequest>::into_make_service_with_connect_info::<SocketAddr>(router),
-        )
-        .with_graceful_shutdown(shutdown_signal())
-        .await?)
+        let serving = listener::serve(&options.config.server.listener, router, shutdown_signal());
+        match relay {
+            // The relay only stops if it panicked
+            Some(relay) => tokio::select! {
+                result = serving => Ok(result?),
+                result = relay => Err(anyhow::anyhow!("The peer relay stopped: {:?}", result)),
+            },
+            None => Ok(serving.await?),
+        }
     }
 
-    fn serve_metrics(host_and_port: SocketAddr) {
-        info!(address = %host_and_port, "Serving prometheus metrics");
+    fn serve_metrics(metrics_listener: Listener) {
+        info!(address = %metrics_listener.address, "Serving prometheus metrics");
 
         tokio::spawn(async move {
-            let router = Router::new().route(
-                "/metrics",
-                get(|| async {
-                    let metric_families = prometheus::gather();
-                    let encoder = TextEncoder::new();
-
-                    match encoder.encode_to_string(&metric_families) {
-                        Ok(s) => (StatusCode::OK, s),
-                        Err(e) => {
-                            error!("Error encoding metrics: {}", e);
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("Error encoding metrics: {}", e),
-                            )
+            // Next to the metrics, as they're for the operator and not for the gateways
+            let router = Router::new()
+                .route(
+                    "/unknown-signers",
+                    get(|| async { Json(unknown_signers()) }),
+                )
+                .route(
+                    "/metrics",
+                    get(|| async {
+                        let metric_families = prometheus::gather();
+                        let encoder = TextEncoder::new();
+
+                        match encoder.encode_to_string(&metric_families) {
+                            Ok(s) => (StatusCode::OK, s),
+                            Err(e) => {
+                                error!("Error encoding metrics: {}", e);
+                                (
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    format!("Error encoding metrics: {}", e),
+                                )
+                            }
                         }
-                    }
-                }),
-            );
+                    }),
+                );
 
-            serve(
-                TcpListener::bind(host_and_port)
-                    .await
-                    .expect("Failed to bind to metrics port"),
-                router.into_make_service(),
-            )
-            .await
-            .expect("Failed to serve metrics")
+            listener::serve(&metrics_listener, router, std::future::pending())
+                .await
+                .expect("Failed to serve metrics")
         });
     }
 }
@@ -2,14 +2,19 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod config;
+pub mod dips;
 mod indexer_service;
+pub mod peer_relay;
+pub mod receipt_rate_limit;
 mod request_handler;
+pub mod response_cache;
 mod static_subgraph;
 mod tap_receipt_header;
 
 pub use config::{
-    DatabaseConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig, IndexerServiceConfig,
-    ServerConfig, SubgraphConfig, TapConfig,
+    DatabaseConfig, DipsConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig,
+    IndexerServiceConfig, PeerRelayConfig, ResponseCacheConfig, ServerConfig, SubgraphConfig,
+    TapConfig,
 };
 pub use indexer_service::{
     AttestationOutput, IndexerService, IndexerServiceImpl, IndexerServiceOptions,
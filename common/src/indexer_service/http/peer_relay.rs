@@ -0,0 +1,608 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Intake of the receipts relayed by trusted peers.
+//!
+//! When the queries are served on other hosts than the one doing the TAP accounting, such as a
+//! fronting proxy or an indexer-service in another region, the receipts they collected are
+//! relayed here in batches, with `POST /receipts`. The relay listener only accepts connections
+//! with a client certificate issued by `client_ca_cert_path` and whose SHA-256 fingerprint is in
+//! the allowlist. The name the peer is listed under is stored with its receipts, in the
+//! `relayed_by` column.
+//!
+//! The queries of the relayed receipts weren't served here, so they're validated more strictly:
+//! - each receipt comes with the attestation of the response it paid for, which must be signed
+//!   with the key of the allocation of the receipt, for its deployment. A peer can only relay
+//!   the receipts of the queries served with the keys of the indexer.
+//! - every check is enforced, the ones in shadow mode included.
+//! - each peer has a token bucket of `rate_limit.burst` receipts, refilled at
+//!   `rate_limit.receipts_per_second`.
+//!
+//! Each receipt of a batch is accepted or rejected on its own, the peer retries the rejected
+//! ones if it makes sense.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use alloy::hex;
+use anyhow::{anyhow, Context};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    routing::post,
+    Extension, Json, Router,
+};
+use eventuals::Eventual;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, register_int_counter, CounterVec, IntCounter};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tap_core::{manager::Manager, receipt::SignedReceipt};
+use thegraph_core::{Address, Attestation, DeploymentId};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{error, info, warn};
+
+use super::{receipt_rate_limit::ReceiptRateLimit, PeerRelayConfig};
+use crate::{
+    prelude::{Allocation, SignersState},
+    tap::{
+        self,
+        rejection::{self, RejectionCode},
+        IndexerTapContext, TokenBucket,
+    },
+};
+
+lazy_static! {
+    static ref RELAYED_RECEIPTS: CounterVec = register_counter_vec!(
+        "indexer_relayed_receipts_total",
+        "Receipts relayed by the peers, by peer and outcome",
+        &["peer", "outcome"]
+    )
+    .unwrap();
+    static ref REFUSED_CONNECTIONS: IntCounter = register_int_counter!(
+        "indexer_relay_refused_connections_total",
+        "Relay connections refused, for a failed handshake or a certificate not in the allowlist"
+    )
+    .unwrap();
+}
+
+/// Name of the peer of a relay connection, in the extensions of its requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayPeer(pub String);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayRequest {
+    pub receipts: Vec<RelayedReceipt>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayedReceipt {
+    /// Deployment of the query the receipt paid for.
+    pub deployment: DeploymentId,
+    /// Of the response to the query, signed with the key of the allocation of the receipt.
+    pub attestation: Attestation,
+    pub receipt: SignedReceipt,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayResponse {
+    /// In the order of the receipts of the request.
+    pub results: Vec<RelayResult>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RelayResult {
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<RejectionCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl RelayResult {
+    fn accepted() -> Self {
+        Self {
+            accepted: true,
+            code: None,
+            message: None,
+        }
+    }
+
+    fn rejected(code: RejectionCode, message: String) -> Self {
+        Self {
+            accepted: false,
+            code: Some(code),
+            message: Some(message),
+        }
+    }
+}
+
+pub struct PeerRelayState {
+    /// With all the checks enforced.
+    tap_manager: Manager<IndexerTapContext>,
+    allocations: Eventual<HashMap<Address, Allocation>>,
+    attestation_signers: watch::Receiver<SignersState>,
+    max_batch_size: usize,
+    rate_limit: ReceiptRateLimit,
+    /// By peer.
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl PeerRelayState {
+    pub fn new(
+        tap_manager: Manager<IndexerTapContext>,
+        allocations: Eventual<HashMap<Address, Allocation>>,
+        attestation_signers: watch::Receiver<SignersState>,
+        max_batch_size: usize,
+        rate_limit: ReceiptRateLimit,
+    ) -> Self {
+        Self {
+            tap_manager,
+            allocations,
+            attestation_signers,
+            max_batch_size,
+            rate_limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a receipt from the bucket of `peer`.
+    fn take(&self, peer: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(peer.to_string())
+            .or_insert_with(|| {
+                TokenBucket::new(
+                    self.rate_limit.burst as u128,
+                    self.rate_limit.receipts_per_second as u128,
+                    now,
+                )
+            })
+            .take(1, now)
+    }
+
+    /// Whether `attestation` is signed with the key of `allocation_id`, for its deployment.
+    fn attests(&self, allocation_id: &Address, attestation: &Attestation) -> bool {
+        self.attestation_signers
+            .borrow()
+            .signers()
+            .get(allocation_id)
+            .is_some_and(|signer| signer.attests(attestation))
+    }
+}
+
+pub fn router(state: Arc<PeerRelayState>) -> Router {
+    Router::new()
+        .route("/receipts", post(relay_receipts))
+        .with_state(state)
+}
+
+async fn relay_receipts(
+    State(state): State<Arc<PeerRelayState>>,
+    Extension(RelayPeer(peer)): Extension<RelayPeer>,
+    Json(request): Json<RelayRequest>,
+) -> Result<Json<RelayResponse>, (StatusCode, String)> {
+    if request.receipts.len() > state.max_batch_size {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "{} receipts relayed, at most {} are accepted at once",
+                request.receipts.len(),
+                state.max_batch_size
+            ),
+        ));
+    }
+
+    let allocations = state.allocations.value_immediate().unwrap_or_default();
+    let mut results = Vec::with_capacity(request.receipts.len());
+    for relayed in request.receipts {
+        let result = relay_receipt(&state, &allocations, &peer, relayed).await;
+        RELAYED_RECEIPTS
+            .with_label_values(&[
+                &peer,
+                result
+                    .code
+                    .as_ref()
+                    .map_or("accepted", RejectionCode::as_str),
+            ])
+            .inc();
+        results.push(result);
+    }
+    Ok(Json(RelayResponse { results }))
+}
+
+async fn relay_receipt(
+    state: &PeerRelayState,
+    allocations: &HashMap<Address, Allocation>,
+    peer: &str,
+    relayed: RelayedReceipt,
+) -> RelayResult {
+    if !state.take(peer, Instant::now()) {
+        return RelayResult::rejected(
+            RejectionCode::RateLimited,
+            format!("Peer `{peer}` is over its rate limit"),
+        );
+    }
+    let allocation_id = relayed.receipt.message.allocation_id;
+    match allocations.get(&allocation_id) {
+        Some(allocation) if allocation.subgraph_deployment.id == relayed.deployment => {}
+        _ => {
+            return RelayResult::rejected(
+                RejectionCode::AllocationMismatch,
+                format!(
+                    "Receipt for allocation {allocation_id}, which isn't an allocation of \
+                    deployment {}",
+                    relayed.deployment
+                ),
+            )
+        }
+    }
+
+    if !state.attests(&allocation_id, &relayed.attestation) {
+        return RelayResult::rejected(
+            RejectionCode::Unattested,
+            format!(
+                "Attestation not signed with the key of allocation {allocation_id} for \
+                deployment {}",
+                relayed.deployment
+            ),
+        );
+    }
+
+    let (result, code) = rejection::capture(tap::relayed_by(
+        peer.to_string(),
+        state.tap_manager.verify_and_store_receipt(relayed.receipt),
+    ))
+    .await;
+    match result {
+        Ok(()) => RelayResult::accepted(),
+        Err(e) => RelayResult::rejected(code.unwrap_or(RejectionCode::Other), e.to_string()),
+    }
+}
+
+/// Serves the relay endpoint on `config.listener`, until the process exits. Fails if the
+/// certificates or the allowlist are invalid, or the address can't be bound.
+pub async fn serve(
+    config: &PeerRelayConfig,
+    state: Arc<PeerRelayState>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(config)?));
+    let peers = Arc::new(allowlist(&config.peers)?);
+    let listener = TcpListener::bind(config.listener).await?;
+    let router = router(state);
+    info!(address = %config.listener, peers = peers.len(), "Serving the peer relay");
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept a relay connection: {}", e);
+                    continue;
+                }
+            };
+            let (acceptor, peers, router) = (acceptor.clone(), peers.clone(), router.clone());
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        REFUSED_CONNECTIONS.inc();
+                        warn!(%addr, "Failed TLS handshake with a relay peer: {}", e);
+                        return;
+                    }
+                };
+                let Some(peer) = identify(&peers, stream.get_ref().1.peer_certificates()) else {
+                    REFUSED_CONNECTIONS.inc();
+                    warn!(
+                        %addr,
+                        "Refused a relay connection, its certificate isn't in \
+                        `service.peer_relay.peers`"
+                    );
+                    return;
+                };
+
+                let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                    let mut request = request.map(Body::new);
+                    request.extensions_mut().insert(RelayPeer(peer.clone()));
+                    router.clone().oneshot(request)
+                });
+                if let Err(e) = Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    warn!(%addr, "Error while serving a relay connection: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// Requires a client certificate issued by `client_ca_cert_path`.
+fn tls_config(config: &PeerRelayConfig) -> anyhow::Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&config.client_ca_cert_path)? {
+        roots.add(cert).with_context(|| {
+            format!(
+                "Invalid CA certificate in {}",
+                config.client_ca_cert_path.display()
+            )
+        })?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .context("Failed to set up the verification of the peer certificates")?;
+
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(&config.cert_path)?, read_key(&config.key_path)?)
+        .with_context(|| format!("Invalid relay certificate {}", config.cert_path.display()))
+}
+
+/// The names of the peers, by the fingerprint of their certificate.
+fn allowlist(peers: &HashMap<String, String>) -> anyhow::Result<HashMap<[u8; 32], String>> {
+    peers
+        .iter()
+        .map(|(name, fingerprint)| {
+            let fingerprint = hex::decode(fingerprint.replace(':', ""))
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .ok_or_else(|| anyhow!("Invalid SHA-256 fingerprint for peer `{name}`"))?;
+            Ok((fingerprint, name.clone()))
+        })
+        .collect()
+}
+
+/// The peer presenting `certificates`, by the fingerprint of the first one, its own.
+fn identify(
+    peers: &HashMap<[u8; 32], String>,
+    certificates: Option<&[CertificateDer<'_>]>,
+) -> Option<String> {
+    let certificate = certificates?.first()?;
+    let fingerprint: [u8; 32] = Sha256::digest(certificate).as_slice().try_into().ok()?;
+    peers.get(&fingerprint).cloned()
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read the certificates of {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Failed to read the private key of {}", path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+    use alloy::{
+        hex::ToHexExt,
+        primitives::{Address, B256},
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use eventuals::Eventual;
+    use rustls::pki_types::CertificateDer;
+    use sha2::{Digest, Sha256};
+    use sqlx::PgPool;
+    use tap_core::{manager::Manager, receipt::checks::CheckList};
+    use thegraph_core::{Attestation, DeploymentId};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{
+        allowlist, identify, router, PeerRelayState, RelayPeer, RelayRequest, RelayResponse,
+        RelayResult, RelayedReceipt,
+    };
+    use crate::{
+        indexer_service::http::receipt_rate_limit::ReceiptRateLimit,
+        prelude::{AttestationSigner, SignersState},
+        tap::{rejection::RejectionCode, IndexerTapContext},
+        test_vectors::{
+            create_signed_receipt, DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS,
+            INDEXER_OPERATOR_MNEMONIC, TAP_EIP712_DOMAIN,
+        },
+    };
+
+    #[test]
+    fn test_identify_peer() {
+        let certificate = CertificateDer::from(vec![1, 2, 3]);
+        let fingerprint = Sha256::digest(&certificate)
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        let peers = allowlist(&HashMap::from([("proxy-eu".to_string(), fingerprint)])).unwrap();
+
+        assert_eq!(
+            identify(&peers, Some(&[certificate][..])),
+            Some("proxy-eu".to_string())
+        );
+        assert_eq!(
+            identify(&peers, Some(&[CertificateDer::from(vec![4, 5, 6])][..])),
+            None
+        );
+        assert_eq!(identify(&peers, None), None);
+        assert!(allowlist(&HashMap::from([("proxy".to_string(), "ab:cd".to_string())])).is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_relay_receipts(pgpool: PgPool) {
+        let (allocation_id, allocation) = INDEXER_ALLOCATIONS.iter().next().unwrap();
+        let signer = AttestationSigner::new(
+            &INDEXER_OPERATOR_MNEMONIC,
+            allocation,
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+        let attestation = signer
+            .create_attestation("request", "response")
+            .await
+            .unwrap();
+        let state = Arc::new(PeerRelayState::new(
+            Manager::new(
+                TAP_EIP712_DOMAIN.clone(),
+                IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await,
+                CheckList::new(vec![]),
+            ),
+            Eventual::from_value(INDEXER_ALLOCATIONS.clone()),
+            watch::channel(SignersState::Ready(HashMap::from([(
+                *allocation_id,
+                signer,
+            )])))
+            .1,
+            2,
+            // Three receipts, never refilled
+            ReceiptRateLimit {
+                receipts_per_second: 0,
+                burst: 3,
+            },
+        ));
+        let relay = |receipts: Vec<RelayedReceipt>| {
+            let mut request = Request::post("/receipts")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&RelayRequest { receipts }).unwrap(),
+                ))
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(RelayPeer("proxy-eu".to_string()));
+            router(state.clone()).oneshot(request)
+        };
+
+        let other_deployment =
+            DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap();
+        let response = relay(vec![
+            RelayedReceipt {
+                deployment: allocation.subgraph_deployment.id,
+                attestation: attestation.clone(),
+                receipt: create_signed_receipt(*allocation_id, 1, 1, 100).await,
+            },
+            RelayedReceipt {
+                deployment: other_deployment,
+                attestation: attestation.clone(),
+                receipt: create_signed_receipt(*allocation_id, 2, 1, 100).await,
+            },
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: RelayResponse =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        assert_eq!(response.results[0], RelayResult::accepted());
+        assert!(!response.results[1].accepted);
+        assert_eq!(
+            response.results[1].code,
+            Some(RejectionCode::AllocationMismatch)
+        );
+
+        // Stored with the name of the peer, once the batch is written
+        let relayed_by = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let rows =
+                    sqlx::query!("SELECT allocation_id, relayed_by FROM scalar_tap_receipts")
+                        .fetch_all(&pgpool)
+                        .await
+                        .unwrap();
+                if !rows.is_empty() {
+                    break rows
+                        .into_iter()
+                        .map(|row| (row.allocation_id, row.relayed_by))
+                        .collect::<Vec<_>>();
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            relayed_by,
+            [(allocation_id.encode_hex(), Some("proxy-eu".to_string()))]
+        );
+
+        // Not bound to a query served with the key of the allocation, then over the rate limit
+        let tampered = Attestation {
+            response_cid: B256::repeat_byte(0x42),
+            ..attestation.clone()
+        };
+        let response = relay(vec![
+            RelayedReceipt {
+                deployment: allocation.subgraph_deployment.id,
+                attestation: tampered,
+                receipt: create_signed_receipt(*allocation_id, 3, 1, 100).await,
+            },
+            RelayedReceipt {
+                deployment: allocation.subgraph_deployment.id,
+                attestation: attestation.clone(),
+                receipt: create_signed_receipt(*allocation_id, 4, 1, 100).await,
+            },
+        ])
+        .await
+        .unwrap();
+        let response: RelayResponse =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        assert_eq!(
+            response
+                .results
+                .iter()
+                .map(|result| result.code)
+                .collect::<Vec<_>>(),
+            [
+                Some(RejectionCode::Unattested),
+                Some(RejectionCode::RateLimited)
+            ]
+        );
+
+        // Too many receipts at once
+        let mut receipts = Vec::new();
+        for nonce in 5..8 {
+            receipts.push(RelayedReceipt {
+                deployment: allocation.subgraph_deployment.id,
+                attestation: attestation.clone(),
+                receipt: create_signed_receipt(Address::ZERO, nonce, 1, 100).await,
+            });
+        }
+        let response = relay(receipts).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
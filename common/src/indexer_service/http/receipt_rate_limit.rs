@@ -0,0 +1,147 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rate limit of the receipts of each sender at intake, before they're checked and stored.
+//!
+//! A misbehaving gateway can flood the indexer with receipts, each one a row of the receipts
+//! table until it's aggregated. Each sender, or each of its signers with `per_signer`, has a
+//! token bucket of `burst` receipts refilled at `receipts_per_second`. The limits of `senders`
+//! override the default one, by sender like the per-sender settings of tap-agent. A receipt over
+//! the limit is refused with `429 Too Many Requests`, and a `Retry-After` header.
+//!
+//! Unlike the `sender_rate_limit` check, sized by tap-agent from the fees of the sender, this
+//! limits the number of receipts whatever their value.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use thegraph_core::Address;
+
+use crate::tap::TokenBucket;
+
+lazy_static! {
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipts_rate_limited_total",
+        "Receipts refused at intake, over the rate limit of their sender",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReceiptRateLimit {
+    pub receipts_per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReceiptRateLimitConfig {
+    pub limit: ReceiptRateLimit,
+    /// A bucket per signer of the sender, rather than one for the sender.
+    pub per_signer: bool,
+    /// Overrides of `limit`, by sender.
+    pub senders: HashMap<Address, ReceiptRateLimit>,
+}
+
+pub struct ReceiptRateLimiter {
+    config: ReceiptRateLimitConfig,
+    /// By sender, and signer with `per_signer`.
+    buckets: Mutex<HashMap<(Address, Option<Address>), TokenBucket>>,
+}
+
+impl ReceiptRateLimiter {
+    pub fn new(config: ReceiptRateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a receipt of `signer`, authorized by `sender`. Over the limit, fails with how long
+    /// to wait before the next one.
+    pub fn take(&self, sender: Address, signer: Address) -> Result<(), Duration> {
+        self.take_at(sender, signer, Instant::now())
+    }
+
+    fn take_at(&self, sender: Address, signer: Address, now: Instant) -> Result<(), Duration> {
+        let limit = self
+            .config
+            .senders
+            .get(&sender)
+            .unwrap_or(&self.config.limit);
+        let key = (sender, self.config.per_signer.then_some(signer));
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert_with(|| {
+            TokenBucket::new(limit.burst as u128, limit.receipts_per_second as u128, now)
+        });
+        if bucket.take(1, now) {
+            return Ok(());
+        }
+        RATE_LIMITED.with_label_values(&[&sender.to_string()]).inc();
+        // The limits are validated to be greater than 0
+        Err(bucket.wait_for(1).unwrap_or(Duration::from_secs(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use thegraph_core::Address;
+
+    use super::{ReceiptRateLimit, ReceiptRateLimitConfig, ReceiptRateLimiter};
+
+    #[test]
+    fn test_receipt_rate_limiter() {
+        let sender = Address::from([0x11u8; 20]);
+        let signers = [Address::from([0x12u8; 20]), Address::from([0x13u8; 20])];
+        let trusted_sender = Address::from([0x21u8; 20]);
+        let limiter = |per_signer| {
+            ReceiptRateLimiter::new(ReceiptRateLimitConfig {
+                limit: ReceiptRateLimit {
+                    receipts_per_second: 10,
+                    burst: 2,
+                },
+                per_signer,
+                senders: HashMap::from([(
+                    trusted_sender,
+                    ReceiptRateLimit {
+                        receipts_per_second: 10,
+                        burst: 100,
+                    },
+                )]),
+            })
+        };
+        let now = Instant::now();
+
+        let per_sender = limiter(false);
+        assert!(per_sender.take_at(sender, signers[0], now).is_ok());
+        assert!(per_sender.take_at(sender, signers[1], now).is_ok());
+        assert_eq!(
+            per_sender.take_at(sender, signers[0], now),
+            Err(Duration::from_millis(100))
+        );
+        assert!(per_sender
+            .take_at(sender, signers[0], now + Duration::from_millis(100))
+            .is_ok());
+        for _ in 0..100 {
+            assert!(per_sender.take_at(trusted_sender, signers[0], now).is_ok());
+        }
+
+        let per_signer = limiter(true);
+        for signer in signers {
+            assert!(per_signer.take_at(sender, signer, now).is_ok());
+            assert!(per_signer.take_at(sender, signer, now).is_ok());
+            assert!(per_signer.take_at(sender, signer, now).is_err());
+        }
+    }
+}
@@ -3,6 +3,7 @@
 
 use std::sync::Arc;
 
+use alloy::primitives::keccak256;
 use axum::{
     body::Bytes,
     extract::{Path, State},
@@ -14,9 +15,14 @@ use lazy_static::lazy_static;
 use prometheus::{register_counter_vec, register_histogram_vec, CounterVec, HistogramVec};
 use reqwest::StatusCode;
 use thegraph_core::DeploymentId;
-use tracing::trace;
+use tracing::{trace, warn};
 
-use crate::indexer_service::http::IndexerServiceResponse;
+use crate::{
+    escrow_accounts::record_unknown_signer,
+    indexer_service::http::IndexerServiceResponse,
+    receipt_profiler::{self, stage, RECEIPT_INTAKE_STAGES},
+    tap,
+};
 
 use super::{
     indexer_service::{AttestationOutput, IndexerServiceError, IndexerServiceState},
@@ -44,6 +50,12 @@ lazy_static! {
         &["deployment", "allocation", "sender"]
     ).unwrap();
 
+    pub static ref REJECTED_RECEIPT: CounterVec = register_counter_vec!(
+        "indexer_receipt_rejected_total",
+        "Rejected receipts, by rejection code",
+        &["deployment", "code"]
+    ).unwrap();
+
 }
 
 pub async fn request_handler<I>(
@@ -58,10 +70,15 @@ where
 {
     _request_handler(manifest_id, typed_header, state, headers, body)
         .await
-        .inspect_err(|_| {
+        .inspect_err(|e| {
             HANDLER_FAILURE
                 .with_label_values(&[&manifest_id.to_string()])
-                .inc()
+                .inc();
+            if let Some(code) = e.rejection_code() {
+                REJECTED_RECEIPT
+                    .with_label_values(&[&manifest_id.to_string(), code.as_str()])
+                    .inc();
+            }
         })
 }
 
@@ -109,6 +126,15 @@ where
     };
 
     let allocation_id = receipt.message.allocation_id;
+    // Refused before the receipt is stored, the query couldn't be attested
+    if state
+        .allocations_missing_signers
+        .borrow()
+        .contains(&allocation_id)
+    {
+        return Err(IndexerServiceError::NoSignerForAllocation(allocation_id));
+    }
+    let mut stages = state.receipt_sampler.sample();
 
     // recover the signer address
     // get escrow accounts from eventual
@@ -128,7 +154,21 @@ where
 
     let sender = escrow_accounts
         .get_sender_for_signer(&signer)
+        .inspect_err(|_| {
+            record_unknown_signer(signer);
+        })
         .map_err(IndexerServiceError::EscrowAccount)?;
+    if let Some(limiter) = &state.receipt_rate_limiter {
+        limiter
+            .take(sender, signer)
+            .map_err(|retry_after| IndexerServiceError::RateLimited {
+                sender,
+                retry_after,
+            })?;
+    }
+    if let Some(stages) = &mut stages {
+        stages.observe(&RECEIPT_INTAKE_STAGES, stage::SIGNER_RECOVERY);
+    }
 
     let _metric = HANDLER_HISTOGRAM
         .with_label_values(&[
@@ -138,30 +178,50 @@ where
         ])
         .start_timer();
 
-    // Verify the receipt and store it in the database
-    state
-        .tap_manager
-        .verify_and_store_receipt(receipt)
-        .await
-        .inspect_err(|_| {
-            FAILED_RECEIPT
-                .with_label_values(&[
-                    &manifest_id.to_string(),
-                    &allocation_id.to_string(),
-                    &sender.to_string(),
-                ])
-                .inc()
-        })
-        .map_err(IndexerServiceError::ReceiptError)?;
+    // Verify the receipt and store it in the database. The stages of a sampled receipt are
+    // recorded by the store, once the checks passed.
+    receipt_profiler::scope(
+        stages,
+        tap::sent_by(sender, state.tap_manager.verify_and_store_receipt(receipt)),
+    )
+    .await
+    .inspect_err(|_| {
+        FAILED_RECEIPT
+            .with_label_values(&[
+                &manifest_id.to_string(),
+                &allocation_id.to_string(),
+                &sender.to_string(),
+            ])
+            .inc()
+    })
+    .map_err(IndexerServiceError::ReceiptError)?;
 
     // Check if we have an attestation signer for the allocation the receipt was created for
     let signer = state
         .attestation_signers
         .borrow()
+        .signers()
         .get(&allocation_id)
         .cloned()
         .ok_or_else(|| (IndexerServiceError::NoSignerForAllocation(allocation_id)))?;
 
+    // The attestation covers the serialized request, the cached responses are reused for the
+    // requests serialized the same
+    let request_hash = match &state.response_cache {
+        Some(cache) if state.service_impl.is_deterministic(&request) => {
+            let request_hash = keccak256(
+                serde_json::to_string(&request)
+                    .map_err(|_| IndexerServiceError::FailedToSignAttestation)?,
+            );
+            if let Some((response, attestation)) = cache.get(allocation_id, request_hash) {
+                let response = response.finalize(AttestationOutput::Attestation(Some(attestation)));
+                return Ok((StatusCode::OK, response));
+            }
+            Some(request_hash)
+        }
+        _ => None,
+    };
+
     let (request, response) = state
         .service_impl
         .process_request(manifest_id, request)
@@ -175,11 +235,25 @@ where
         .as_str()
         .map_err(|_| IndexerServiceError::FailedToSignAttestation)?;
 
-    let attestation = AttestationOutput::Attestation(
-        response
-            .is_attestable()
-            .then(|| signer.create_attestation(&req, res)),
-    );
+    let attestation = if response.is_attestable() {
+        Some(signer.create_attestation(&req, res).await.map_err(|e| {
+            warn!(%allocation_id, "Failed to sign the attestation: {}", e);
+            IndexerServiceError::FailedToSignAttestation
+        })?)
+    } else {
+        None
+    };
+    if let (Some(cache), Some(request_hash), Some(attestation)) =
+        (&state.response_cache, request_hash, &attestation)
+    {
+        cache.insert(
+            allocation_id,
+            request_hash,
+            response.clone(),
+            attestation.clone(),
+        );
+    }
+    let attestation = AttestationOutput::Attestation(attestation);
 
     let response = response.finalize(attestation);
 
@@ -0,0 +1,180 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of the attested responses, reused for the identical queries to the same deployment.
+//!
+//! Hot queries are often sent again and again by the gateways, each one a query to graph-node
+//! and an attestation to sign. The responses are cached with their attestation, by allocation
+//! and hash of the request, which is what the attestation covers. A cached response is only
+//! reused for a receipt of the allocation it was attested for, the attestation is signed by the
+//! key of the allocation, and the allocations of a deployment don't evict each other's entry.
+//!
+//! Only the attestable responses to the requests whose response can't change are cached, such
+//! as the queries pinned to a block by its hash, see
+//! [`super::IndexerServiceImpl::is_deterministic`]. The TTL bounds how long an entry is kept.
+
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::B256;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use thegraph_core::{Address, Attestation};
+
+lazy_static! {
+    static ref LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "indexer_response_cache_lookups_total",
+        "Lookups of the attested responses cache, by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+struct CachedResponse<R> {
+    response: R,
+    attestation: Attestation,
+    cached_at: Instant,
+}
+
+pub struct ResponseCache<R> {
+    entries: Mutex<LruCache<(Address, B256), CachedResponse<R>>>,
+    ttl: Duration,
+}
+
+impl<R: Clone> ResponseCache<R> {
+    pub fn new(max_entries: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(max_entries)),
+            ttl,
+        }
+    }
+
+    /// The response to the request hashed to `request_hash`, if it was attested for
+    /// `allocation_id` less than the TTL ago.
+    pub fn get(&self, allocation_id: Address, request_hash: B256) -> Option<(R, Attestation)> {
+        self.get_at(allocation_id, request_hash, Instant::now())
+    }
+
+    fn get_at(
+        &self,
+        allocation_id: Address,
+        request_hash: B256,
+        now: Instant,
+    ) -> Option<(R, Attestation)> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (allocation_id, request_hash);
+        let expired = entries
+            .peek(&key)
+            .is_some_and(|entry| now.saturating_duration_since(entry.cached_at) >= self.ttl);
+        if expired {
+            entries.pop(&key);
+        }
+        let result = entries
+            .get(&key)
+            .map(|entry| (entry.response.clone(), entry.attestation.clone()));
+        LOOKUPS
+            .with_label_values(&[if result.is_some() { "hit" } else { "miss" }])
+            .inc();
+        result
+    }
+
+    pub fn insert(
+        &self,
+        allocation_id: Address,
+        request_hash: B256,
+        response: R,
+        attestation: Attestation,
+    ) {
+        self.insert_at(
+            allocation_id,
+            request_hash,
+            response,
+            attestation,
+            Instant::now(),
+        )
+    }
+
+    fn insert_at(
+        &self,
+        allocation_id: Address,
+        request_hash: B256,
+        response: R,
+        attestation: Attestation,
+        now: Instant,
+    ) {
+        self.entries.lock().unwrap().put(
+            (allocation_id, request_hash),
+            CachedResponse {
+                response,
+                attestation,
+                cached_at: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        time::{Duration, Instant},
+    };
+
+    use alloy::primitives::{keccak256, Address};
+
+    use super::ResponseCache;
+    use crate::{
+        prelude::AttestationSigner,
+        test_vectors::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_OPERATOR_MNEMONIC},
+    };
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        let signer = AttestationSigner::new(
+            &INDEXER_OPERATOR_MNEMONIC,
+            INDEXER_ALLOCATIONS.values().next().unwrap(),
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+        let attested = signer
+            .create_attestation("query", "response")
+            .await
+            .unwrap();
+        let attestation = || attested.clone();
+
+        let cache = ResponseCache::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(2));
+        let allocation = Address::repeat_byte(0x11);
+        let other_allocation = Address::repeat_byte(0x22);
+        let (query, other_query) = (keccak256("query"), keccak256("other"));
+        let start = Instant::now();
+
+        cache.insert_at(allocation, query, "response", attestation(), start);
+        let (response, cached) = cache.get_at(allocation, query, start).unwrap();
+        assert_eq!(response, "response");
+        assert_eq!(cached.request_cid, attested.request_cid);
+        // Another query, or the same one for another allocation
+        assert!(cache.get_at(allocation, other_query, start).is_none());
+        assert!(cache.get_at(other_allocation, query, start).is_none());
+        // Expired
+        assert!(cache
+            .get_at(allocation, query, start + Duration::from_secs(2))
+            .is_none());
+
+        // The allocations of the same deployment don't evict each other
+        cache.insert_at(allocation, query, "1", attestation(), start);
+        cache.insert_at(other_allocation, query, "2", attestation(), start);
+        assert_eq!(cache.get_at(allocation, query, start).unwrap().0, "1");
+        assert_eq!(cache.get_at(other_allocation, query, start).unwrap().0, "2");
+
+        // The least recently used query is evicted
+        cache.get_at(allocation, query, start);
+        cache.insert_at(allocation, keccak256("third"), "3", attestation(), start);
+        assert!(cache.get_at(allocation, query, start).is_some());
+        assert!(cache.get_at(other_allocation, query, start).is_none());
+    }
+}
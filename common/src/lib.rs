@@ -5,8 +5,13 @@ pub mod address;
 pub mod allocations;
 pub mod attestations;
 pub mod escrow_accounts;
+pub mod fees;
 pub mod graphql;
+pub mod heartbeat;
 pub mod indexer_service;
+pub mod listener;
+pub mod receipt_profiler;
+pub mod secrets;
 pub mod subgraph_client;
 pub mod tap;
 
@@ -15,12 +20,24 @@ mod test_vectors;
 
 pub mod prelude {
     pub use super::allocations::{
-        monitor::indexer_allocations, Allocation, AllocationStatus, SubgraphDeployment,
+        monitor::indexer_allocations,
+        watcher::{allocations_eventual, indexer_allocations_watcher},
+        Allocation, AllocationStatus, SubgraphDeployment,
     };
     pub use super::attestations::{
-        dispute_manager::dispute_manager, signer::AttestationSigner, signers::attestation_signers,
+        backend::AttestationSignerBackend,
+        dispute_manager::dispute_manager,
+        signer::AttestationSigner,
+        signers::{attestation_signers, ProtocolNetwork, SignersState},
+        verifier::{AttestationVerificationError, AttestationVerifier},
+    };
+    pub use super::escrow_accounts::{
+        escrow_accounts_eventual, escrow_accounts_watcher, EscrowRpcFallback,
+    };
+    pub use super::heartbeat::Heartbeat;
+    pub use super::subgraph_client::{
+        DeploymentDetails, LocalFailoverConfig, Query, QueryCacheConfig, QueryPolicy,
+        QueryVariables, SubgraphClient, Subscription, SubscriptionConfig,
     };
-    pub use super::escrow_accounts::escrow_accounts;
-    pub use super::subgraph_client::{DeploymentDetails, Query, QueryVariables, SubgraphClient};
     pub use super::tap::IndexerTapContext;
 }
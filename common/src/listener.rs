@@ -0,0 +1,193 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binding of the HTTP servers, to a TCP address, IPv4 or IPv6, or to a unix domain socket.
+//!
+//! Unix domain sockets suit the deployments running in a network namespace, where the admin
+//! endpoints shouldn't be reachable over the network at all. Their access is controlled by the
+//! permissions of the socket file instead. With `socket_mode`, the socket is bound in a directory
+//! only the indexer can access, and moved to its path once it has its mode, so it's never
+//! reachable with the default permissions. An existing file at the path is only replaced if it's
+//! a socket nothing listens on anymore, left over by a previous run.
+
+use std::{
+    convert::Infallible,
+    fs::{self, DirBuilder, Permissions},
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::Path,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    response::Response,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::net::{TcpListener, UnixListener};
+use tower::{Service, ServiceExt};
+use tracing::{error, warn};
+
+// Parsed from the configuration
+pub use indexer_config::{ListenAddress, ListenerConfig as Listener};
+
+/// Peer address of the requests received on a unix domain socket, which have none. The rate
+/// limits keyed by peer address apply to all of them together.
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Serves `service` on `listener` until `shutdown` completes. The requests carry the
+/// [`ConnectInfo`] of their peer, [`UNIX_PEER`] on unix sockets.
+pub async fn serve<S>(
+    listener: &Listener,
+    service: S,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    match &listener.address {
+        ListenAddress::Tcp(addr) => {
+            let tcp = TcpListener::bind(addr).await?;
+            axum::serve(
+                tcp,
+                axum::ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(
+                    service,
+                ),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await
+        }
+        ListenAddress::Unix(path) => {
+            let unix = bind_unix(path, listener.socket_mode)?;
+
+            tokio::pin!(shutdown);
+            loop {
+                let stream = tokio::select! {
+                    _ = &mut shutdown => break,
+                    accepted = unix.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            error!(path = %path.display(), "Failed to accept a connection: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                let service = service.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                        let mut request = request.map(Body::new);
+                        request.extensions_mut().insert(ConnectInfo(UNIX_PEER));
+                        service.clone().oneshot(request)
+                    });
+                    if let Err(e) = Builder::new(TokioExecutor::new())
+                        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                        .await
+                    {
+                        warn!("Error while serving a unix socket connection: {}", e);
+                    }
+                });
+            }
+            let _ = tokio::fs::remove_file(path).await;
+            Ok(())
+        }
+    }
+}
+
+/// Binds a unix socket at `path`, with `mode` if any, see the module docs.
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{}` has no file name", path.display()),
+        )
+    })?;
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join(file_name);
+    let bound = UnixListener::bind(&private_path).and_then(|unix| {
+        fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+        fs::rename(&private_path, path)?;
+        Ok(unix)
+    });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    bound
+}
+
+/// Removes the socket at `path` if nothing listens on it anymore. Fails if `path` isn't a socket,
+/// or if it's in use.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("`{}` exists and isn't a unix socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("The unix socket `{}` is in use", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!(path = %path.display(), "Replacing a stale unix socket");
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::bind_unix;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = std::env::temp_dir().join(format!("indexer-listener-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+
+        let unix = bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // In use
+        assert!(bind_unix(&path, None).is_err());
+        drop(unix);
+        // Stale, left over once closed
+        bind_unix(&path, None).unwrap();
+
+        // Not a socket
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        assert!(bind_unix(&file, None).is_err());
+        assert!(fs::metadata(&file).unwrap().is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sampled timing of the stages of the receipt intake.
+//!
+//! A fraction of the receipts, `metrics.receipt_profiling_ratio` of the configuration, get the
+//! time spent in each stage of their processing recorded in a histogram labeled by stage, so
+//! performance work can target the stage actually bounding the receipt throughput. Timing every
+//! receipt would add its own cost to the hot path, a sample is enough for the distributions.
+//!
+//! Each process records its own stages: indexer-service the validation and storage of the
+//! receipts in [`RECEIPT_INTAKE_STAGES`], tap-agent the handling of their notifications by its
+//! actors in `tap_receipt_intake_stage_seconds`.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+use prometheus::{exponential_buckets, register_histogram_vec, HistogramVec};
+
+lazy_static! {
+    pub static ref RECEIPT_INTAKE_STAGES: HistogramVec = register_histogram_vec!(
+        "indexer_receipt_intake_stage_seconds",
+        "Time spent by the sampled receipts in each stage of their intake",
+        &["stage"],
+        exponential_buckets(0.00001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+}
+
+/// Values of the `stage` label of [`RECEIPT_INTAKE_STAGES`].
+pub mod stage {
+    /// Recovery of the signer and lookup of its sender.
+    pub const SIGNER_RECOVERY: &str = "signer_recovery";
+    /// Checks of the receipt, until it's queued for storage.
+    pub const VALIDATION: &str = "validation";
+    /// From the queue to the database, including the wait for the rest of its batch.
+    pub const DB_WRITE: &str = "db_write";
+}
+
+tokio::task_local! {
+    /// Stages of the receipt verified by the current task, which `tap_core` doesn't pass along
+    /// from the checks to the store.
+    static CURRENT: Option<Stages>;
+}
+
+/// Picks the receipts to profile.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    /// Sample one receipt every `every`, none if 0.
+    every: u64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    /// Samples `ratio` of the receipts. They're sampled at a regular interval rather than at
+    /// random, so that bursts are sampled as much as the rest.
+    pub fn new(ratio: f64) -> Self {
+        let every = if ratio > 0.0 {
+            (1.0 / ratio.min(1.0)).round() as u64
+        } else {
+            0
+        };
+        Self {
+            every,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// The stages to record if the next receipt is sampled, starting now.
+    pub fn sample(&self) -> Option<Stages> {
+        if self.every == 0 {
+            return None;
+        }
+        (self.count.fetch_add(1, Ordering::Relaxed) % self.every == 0).then(Stages::start)
+    }
+}
+
+/// Stages of a sampled receipt, recorded one after the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stages {
+    /// End of the previous stage.
+    since: Instant,
+}
+
+impl Stages {
+    pub fn start() -> Self {
+        Self {
+            since: Instant::now(),
+        }
+    }
+
+    /// Records the time since the end of the previous stage as the time spent in `stage`.
+    pub fn observe(&mut self, histogram: &HistogramVec, stage: &str) {
+        let now = Instant::now();
+        histogram
+            .with_label_values(&[stage])
+            .observe(now.duration_since(self.since).as_secs_f64());
+        self.since = now;
+    }
+}
+
+/// Runs `f` with `stages` as the [`current`] ones.
+pub async fn scope<F: Future>(stages: Option<Stages>, f: F) -> F::Output {
+    CURRENT.scope(stages, f).await
+}
+
+/// The stages set by the enclosing [`scope`], if the receipt is sampled.
+pub fn current() -> Option<Stages> {
+    CURRENT.try_with(|stages| *stages).ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, scope, Sampler, Stages};
+
+    #[test]
+    fn test_sampler() {
+        let sampled = |sampler: Sampler| (0..100).filter(|_| sampler.sample().is_some()).count();
+        assert_eq!(sampled(Sampler::default()), 0);
+        assert_eq!(sampled(Sampler::new(0.0)), 0);
+        assert_eq!(sampled(Sampler::new(0.1)), 10);
+        assert_eq!(sampled(Sampler::new(1.0)), 100);
+        assert_eq!(sampled(Sampler::new(2.0)), 100);
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert_eq!(current(), None);
+        let stages = Stages::start();
+        assert_eq!(scope(Some(stages), async { current() }).await, Some(stages));
+        assert_eq!(scope(None, async { current() }).await, None);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rotation of the secrets referenced by the configuration, see `indexer_config::secrets`.
+//!
+//! The secrets are fetched while parsing the configuration. To pick up a rotated secret, the
+//! configuration is parsed again every `secrets.refresh_interval_secs`, and the consumers are
+//! told when the secret they use changed through a [`watch::Receiver`].
+//!
+//! The Postgres pools follow the rotation, see [`rotate_postgres_url`]. They keep their open
+//! connections, only the new ones use the new URL, so the previous credentials must stay valid
+//! for a while after the rotation. In indexer-service, the attestation signers of the new
+//! allocations are created with the rotated operator mnemonic, the ones of the previous
+//! mnemonic are kept until their allocations close, see [`crate::attestations::signers`]. The
+//! other secrets are read on startup.
+
+use std::{str::FromStr, time::Duration};
+
+use sqlx::{postgres::PgConnectOptions, PgPool};
+use tokio::{
+    sync::watch,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// Loads the secret with `load` every `interval`, parsing the configuration again, and sends it
+/// when it changed. `load` may block on the secret providers, it runs on the blocking threads.
+pub fn watch<T, F>(initial: T, interval: Duration, load: F) -> watch::Receiver<T>
+where
+    T: PartialEq + Send + Sync + 'static,
+    F: Fn() -> Result<T, String> + Clone + Send + 'static,
+{
+    let (sender, receiver) = watch::channel(initial);
+    tokio::spawn(async move {
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, and the secret was just loaded
+        ticker.tick().await;
+
+        while !sender.is_closed() {
+            ticker.tick().await;
+
+            let loaded = tokio::task::spawn_blocking(load.clone())
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|secret| secret);
+            let secret = match loaded {
+                Ok(secret) => secret,
+                Err(error) => {
+                    warn!(%error, "Failed to reload the secrets, keeping the current ones.");
+                    continue;
+                }
+            };
+            sender.send_if_modified(|current| {
+                let modified = *current != secret;
+                *current = secret;
+                modified
+            });
+        }
+    });
+    receiver
+}
+
+/// The part of the secrets picked by `f`, sent when it changed.
+pub fn map<T, U, F>(mut secrets: watch::Receiver<T>, f: F) -> watch::Receiver<U>
+where
+    T: Send + Sync + 'static,
+    U: PartialEq + Send + Sync + 'static,
+    F: Fn(&T) -> U + Send + 'static,
+{
+    let (sender, receiver) = watch::channel(f(&secrets.borrow_and_update()));
+    tokio::spawn(async move {
+        while !sender.is_closed() && secrets.changed().await.is_ok() {
+            let secret = f(&secrets.borrow_and_update());
+            sender.send_if_modified(|current| {
+                let modified = *current != secret;
+                *current = secret;
+                modified
+            });
+        }
+    });
+    receiver
+}
+
+/// Connects the new connections of `pools` with the Postgres URL sent by `urls`, until it's
+/// closed.
+pub fn rotate_postgres_url(pools: Vec<PgPool>, mut urls: watch::Receiver<String>) {
+    tokio::spawn(async move {
+        while urls.changed().await.is_ok() {
+            let url = urls.borrow_and_update().clone();
+            match PgConnectOptions::from_str(&url) {
+                Ok(options) => {
+                    for pool in &pools {
+                        pool.set_connect_options(options.clone());
+                    }
+                    info!("Postgres URL rotated, the new connections use it.");
+                }
+                Err(error) => {
+                    warn!(%error, "Invalid rotated Postgres URL, keeping the current one.");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::watch;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let loads = Arc::new(AtomicU32::new(0));
+        let counter = loads.clone();
+        // Rotated on the second reload, failing on the third
+        let mut secret = watch(
+            "initial".to_string(),
+            Duration::from_millis(10),
+            move || match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok("initial".to_string()),
+                1 => Ok("rotated".to_string()),
+                _ => Err("unreachable provider".to_string()),
+            },
+        );
+
+        tokio::time::timeout(Duration::from_secs(1), secret.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*secret.borrow_and_update(), "rotated");
+        assert!(loads.load(Ordering::SeqCst) >= 2);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!secret.has_changed().unwrap());
+    }
+}
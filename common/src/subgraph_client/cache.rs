@@ -0,0 +1,252 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of the responses of a [`super::SubgraphClient`].
+//!
+//! The escrow accounts and allocations are queried again and again with the same variables. The
+//! responses are cached by hash of the query and its variables, and by the block it's pinned to.
+//! A query pinned to a block hash always gets the same response, it's cached until evicted. The
+//! others are cached for the TTL.
+//!
+//! The last response to a query is also the last known good one: it's returned when the subgraph
+//! can't be queried, unless it's older than `max_stale`. It's returned with its age, for the
+//! pollers to tell it from a response of the subgraph.
+
+use std::{
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::{keccak256, B256};
+use axum::body::Bytes;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+lazy_static! {
+    static ref LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_cache_lookups_total",
+        "Lookups of the cache of the subgraph responses, by result",
+        &["subgraph", "result"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryCacheConfig {
+    pub max_entries: NonZeroUsize,
+    /// Of the responses to the queries not pinned to a block hash.
+    pub ttl: Duration,
+    /// Past which the last known good response is no longer returned.
+    pub max_stale: Duration,
+}
+
+/// The `block` variable of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum BlockConstraint {
+    Latest,
+    Hash(B256),
+    Number(u64),
+    NumberGte(u64),
+}
+
+impl BlockConstraint {
+    fn of(body: &Value) -> Self {
+        let Some(block) = body
+            .get("variables")
+            .and_then(|variables| variables.get("block"))
+        else {
+            return Self::Latest;
+        };
+        if let Some(hash) = block
+            .get("hash")
+            .and_then(Value::as_str)
+            .and_then(|hash| B256::from_str(hash).ok())
+        {
+            Self::Hash(hash)
+        } else if let Some(number) = block.get("number").and_then(Value::as_u64) {
+            Self::Number(number)
+        } else if let Some(number) = block.get("number_gte").and_then(Value::as_u64) {
+            Self::NumberGte(number)
+        } else {
+            Self::Latest
+        }
+    }
+
+    /// A block number may still be reorged.
+    fn is_pinned(&self) -> bool {
+        matches!(self, Self::Hash(_))
+    }
+}
+
+pub(super) type CacheKey = (B256, BlockConstraint);
+
+/// The key of the query `body`, as sent to the subgraph.
+pub(super) fn key<B: Serialize>(body: &B) -> anyhow::Result<CacheKey> {
+    let body = serde_json::to_value(body)?;
+    Ok((
+        keccak256(serde_json::to_vec(&body)?),
+        BlockConstraint::of(&body),
+    ))
+}
+
+struct Entry {
+    body: Bytes,
+    cached_at: Instant,
+}
+
+pub(super) struct QueryCache {
+    entries: Mutex<LruCache<CacheKey, Entry>>,
+    ttl: Duration,
+    max_stale: Duration,
+}
+
+impl QueryCache {
+    pub fn new(config: QueryCacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(config.max_entries)),
+            ttl: config.ttl,
+            max_stale: config.max_stale,
+        }
+    }
+
+    /// The response to the query, if pinned or cached less than the TTL ago.
+    pub fn fresh(&self, subgraph: &str, key: &CacheKey) -> Option<Bytes> {
+        self.fresh_at(subgraph, key, Instant::now())
+    }
+
+    fn fresh_at(&self, subgraph: &str, key: &CacheKey, now: Instant) -> Option<Bytes> {
+        let body = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|entry| {
+                key.1.is_pinned() || now.saturating_duration_since(entry.cached_at) < self.ttl
+            })
+            .map(|entry| entry.body.clone());
+        LOOKUPS
+            .with_label_values(&[subgraph, if body.is_some() { "hit" } else { "miss" }])
+            .inc();
+        body
+    }
+
+    /// The last response to the query and its age, if cached less than `max_stale` ago.
+    pub fn last_known_good(&self, subgraph: &str, key: &CacheKey) -> Option<(Bytes, Duration)> {
+        self.last_known_good_at(subgraph, key, Instant::now())
+    }
+
+    fn last_known_good_at(
+        &self,
+        subgraph: &str,
+        key: &CacheKey,
+        now: Instant,
+    ) -> Option<(Bytes, Duration)> {
+        let response = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|entry| {
+                (
+                    entry.body.clone(),
+                    now.saturating_duration_since(entry.cached_at),
+                )
+            })
+            .filter(|(_, age)| *age < self.max_stale);
+        if response.is_some() {
+            LOOKUPS.with_label_values(&[subgraph, "stale"]).inc();
+        }
+        response
+    }
+
+    pub fn insert(&self, key: CacheKey, body: Bytes) {
+        self.insert_at(key, body, Instant::now())
+    }
+
+    fn insert_at(&self, key: CacheKey, body: Bytes, now: Instant) {
+        self.entries.lock().unwrap().put(
+            key,
+            Entry {
+                body,
+                cached_at: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        time::{Duration, Instant},
+    };
+
+    use alloy::primitives::B256;
+    use axum::body::Bytes;
+    use serde_json::json;
+
+    use super::{key, BlockConstraint, QueryCache, QueryCacheConfig};
+
+    #[test]
+    fn test_block_constraint() {
+        let hash = B256::repeat_byte(0x11);
+        let query =
+            |block| json!({ "query": "{ _meta { block { number } } }", "variables": block });
+        assert_eq!(key(&query(json!({}))).unwrap().1, BlockConstraint::Latest);
+        assert_eq!(
+            key(&query(json!({ "block": { "hash": hash } }))).unwrap().1,
+            BlockConstraint::Hash(hash)
+        );
+        assert_eq!(
+            key(&query(json!({ "block": { "number": 42 } }))).unwrap().1,
+            BlockConstraint::Number(42)
+        );
+        assert_ne!(
+            key(&query(json!({ "block": { "number": 42 } }))).unwrap().0,
+            key(&query(json!({ "block": { "number": 43 } }))).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let cache = QueryCache::new(QueryCacheConfig {
+            max_entries: NonZeroUsize::new(10).unwrap(),
+            ttl: Duration::from_secs(10),
+            max_stale: Duration::from_secs(60),
+        });
+        let latest = key(&json!({ "query": "a", "variables": {} })).unwrap();
+        let pinned = key(&json!({
+            "query": "a",
+            "variables": { "block": { "hash": B256::repeat_byte(0x11) } }
+        }))
+        .unwrap();
+        let start = Instant::now();
+        cache.insert_at(latest, Bytes::from("latest"), start);
+        cache.insert_at(pinned, Bytes::from("pinned"), start);
+
+        assert_eq!(
+            cache.fresh_at("test", &latest, start).unwrap(),
+            Bytes::from("latest")
+        );
+        let later = start + Duration::from_secs(10);
+        assert!(cache.fresh_at("test", &latest, later).is_none());
+        assert_eq!(
+            cache.fresh_at("test", &pinned, later).unwrap(),
+            Bytes::from("pinned")
+        );
+        // Still the last known good response, until `max_stale`
+        assert_eq!(
+            cache.last_known_good_at("test", &latest, later).unwrap(),
+            (Bytes::from("latest"), Duration::from_secs(10))
+        );
+        let much_later = start + Duration::from_secs(60);
+        assert!(cache
+            .last_known_good_at("test", &latest, much_later)
+            .is_none());
+    }
+}
@@ -1,13 +1,31 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::monitor::{monitor_deployment_status, DeploymentStatus};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use super::{
+    cache::{self, QueryCache, QueryCacheConfig},
+    failover::{self, LocalFailoverConfig},
+    monitor::{monitor_deployment_status, DeploymentStatus},
+    policy::{PolicyState, QueryPolicy},
+    subscription::{Subscription, SubscriptionConfig},
+};
 use anyhow::anyhow;
 use axum::body::Bytes;
 use eventuals::Eventual;
 use graphql_client::GraphQLQuery;
-use reqwest::{header, Url};
-use serde_json::{Map, Value};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    StatusCode, Url,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
 use thegraph_core::DeploymentId;
 use thegraph_graphql_http::{
     graphql::{Document, IntoDocument},
@@ -23,6 +41,44 @@ pub struct Query {
 
 pub type ResponseResult<T> = Result<T, anyhow::Error>;
 
+/// A response, with its body as received.
+type RawResponse<T> = (Bytes, ResponseResult<T>);
+
+/// A response to [`SubgraphClient::query_conditional`].
+pub struct QueryResponse<T> {
+    pub result: ResponseResult<T>,
+    /// Age of the last known good response, returned from the cache as the subgraph couldn't be
+    /// queried. `None` for a response of the subgraph.
+    pub stale: Option<Duration>,
+}
+
+impl<T> QueryResponse<T> {
+    /// The result, or an error if it's the last known good one, for the pollers that keep their
+    /// last result anyway and mustn't take the fallback for a successful poll.
+    pub fn fresh(self) -> Result<ResponseResult<T>, anyhow::Error> {
+        match self.stale {
+            Some(age) => Err(anyhow!(
+                "The subgraph can't be queried, only the last known good response is, {}s old",
+                age.as_secs()
+            )),
+            None => Ok(self.result),
+        }
+    }
+}
+
+fn parse_response<T: GraphQLQuery>(
+    body: &[u8],
+) -> Result<ResponseResult<T::ResponseData>, anyhow::Error> {
+    let response: graphql_client::Response<T::ResponseData> = serde_json::from_slice(body)?;
+
+    // TODO handle partial responses
+    Ok(match (response.data, response.errors) {
+        (Some(data), None) => Ok(data),
+        (_, Some(errors)) => Err(anyhow!("{errors:?}")),
+        (_, _) => Err(anyhow!("Invalid error")),
+    })
+}
+
 impl Query {
     pub fn new(query: &str) -> Self {
         Self {
@@ -120,7 +176,27 @@ impl DeploymentDetails {
     }
 }
 
-struct DeploymentClient {
+/// Validators of the last response to a query, sent back as `If-None-Match` and
+/// `If-Modified-Since` so that an unchanged response isn't sent again.
+#[derive(Debug, Default, Clone)]
+pub struct CacheValidators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl CacheValidators {
+    pub(crate) fn is_unconditional(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn update(&mut self, headers: &HeaderMap) {
+        self.etag = headers.get(header::ETAG).cloned();
+        self.last_modified = headers.get(header::LAST_MODIFIED).cloned();
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct DeploymentClient {
     pub http_client: reqwest::Client,
     pub status: Option<Eventual<DeploymentStatus>>,
     pub query_url: Url,
@@ -138,10 +214,11 @@ impl DeploymentClient {
         }
     }
 
-    pub async fn query<T: GraphQLQuery>(
+    pub async fn query_conditional<T: GraphQLQuery>(
         &self,
         variables: T::Variables,
-    ) -> Result<ResponseResult<T::ResponseData>, anyhow::Error> {
+        validators: &mut CacheValidators,
+    ) -> Result<Option<RawResponse<T::ResponseData>>, anyhow::Error> {
         if let Some(ref status) = self.status {
             let deployment_status = status.value().await.expect("reading deployment status");
 
@@ -154,21 +231,51 @@ impl DeploymentClient {
         }
 
         let body = T::build_query(variables);
-        let reqwest_response = self
+        let mut request = self
+            .http_client
+            .post(self.query_url.as_ref())
+            .header(header::USER_AGENT, "indexer-common")
+            .json(&body);
+        if let Some(ref etag) = validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let reqwest_response = request.send().await?;
+        let status = reqwest_response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(anyhow!(
+                "Deployment `{}` responded with {status}",
+                self.query_url
+            ));
+        }
+        validators.update(reqwest_response.headers());
+        let body = reqwest_response.bytes().await?;
+        let response = parse_response::<T>(&body)?;
+        Ok(Some((body, response)))
+    }
+
+    /// Number of the latest block indexed, from `_meta`.
+    pub async fn latest_block(&self) -> Result<u64, anyhow::Error> {
+        let response: graphql_client::Response<Value> = self
             .http_client
             .post(self.query_url.as_ref())
             .header(header::USER_AGENT, "indexer-common")
-            .json(&body)
+            .json(&json!({ "query": "{ _meta { block { number } } }" }))
             .send()
+            .await?
+            .error_for_status()?
+            .json()
             .await?;
-        let response: graphql_client::Response<T::ResponseData> = reqwest_response.json().await?;
-
-        // TODO handle partial responses
-        Ok(match (response.data, response.errors) {
-            (Some(data), None) => Ok(data),
-            (_, Some(errors)) => Err(anyhow!("{errors:?}")),
-            (_, _) => Err(anyhow!("Invalid error")),
-        })
+        response
+            .data
+            .as_ref()
+            .and_then(|data| data["_meta"]["block"]["number"].as_u64())
+            .ok_or_else(|| anyhow!("Deployment `{}` has no latest block", self.query_url))
     }
 
     pub async fn query_raw(&self, body: Bytes) -> Result<reqwest::Response, anyhow::Error> {
@@ -195,9 +302,18 @@ impl DeploymentClient {
 }
 
 /// Client for a subgraph that can fall back from a local deployment to a remote query URL
+///
+/// The queries are retried and timed out following a [`QueryPolicy`], the default one unless set
+/// with [`SubgraphClient::with_policy`]. Their responses are cached if set with
+/// [`SubgraphClient::with_cache`].
 pub struct SubgraphClient {
     local_client: Option<DeploymentClient>,
     remote_client: DeploymentClient,
+    policy: PolicyState,
+    cache: Option<QueryCache>,
+    /// See [`SubgraphClient::with_local_failover`].
+    local_lagging: Arc<AtomicBool>,
+    subscription: Option<SubscriptionConfig>,
 }
 
 impl SubgraphClient {
@@ -206,24 +322,227 @@ impl SubgraphClient {
         local_deployment: Option<DeploymentDetails>,
         remote_deployment: DeploymentDetails,
     ) -> Self {
+        // Not the whole query URL, which may contain an API key
+        let subgraph = remote_deployment
+            .query_url
+            .host_str()
+            .unwrap_or("unknown")
+            .to_string();
         Self {
             local_client: local_deployment.map(|d| DeploymentClient::new(http_client.clone(), d)),
             remote_client: DeploymentClient::new(http_client, remote_deployment),
+            policy: PolicyState::new(subgraph, QueryPolicy::default()),
+            cache: None,
+            local_lagging: Arc::new(AtomicBool::new(false)),
+            subscription: None,
+        }
+    }
+
+    /// Sets the `policy` of the queries, and the name of the subgraph in the logs and metrics.
+    pub fn with_policy(mut self, subgraph: impl Into<String>, policy: QueryPolicy) -> Self {
+        self.policy = PolicyState::new(subgraph.into(), policy);
+        self
+    }
+
+    /// If `config` is set, caches the responses for `config.ttl`, and returns the last one to a
+    /// query when the subgraph can't be queried, unless it's older than `config.max_stale`.
+    pub fn with_cache(mut self, config: Option<QueryCacheConfig>) -> Self {
+        self.cache = config.map(QueryCache::new);
+        self
+    }
+
+    /// If `config` is set, queries the remote query URL rather than the local deployment while
+    /// the local one is more than `config.max_lag_blocks` behind it. To be set after the policy,
+    /// whose subgraph name is used by the lag metric.
+    pub fn with_local_failover(self, config: Option<LocalFailoverConfig>) -> Self {
+        if let Some((config, local_client)) = config.zip(self.local_client.clone()) {
+            tokio::spawn(failover::monitor_lag(
+                self.policy.subgraph().to_string(),
+                local_client,
+                self.remote_client.clone(),
+                config,
+                Arc::downgrade(&self.local_lagging),
+            ));
+        }
+        self
+    }
+
+    /// Sets the WebSocket endpoint of [`SubgraphClient::subscribe`].
+    pub fn with_subscription(mut self, config: Option<SubscriptionConfig>) -> Self {
+        self.subscription = config;
+        self
+    }
+
+    /// Subscribes to `query`, a GraphQL subscription, to be notified when its result changes.
+    /// The subscription is never notified without a subscription endpoint.
+    pub fn subscribe(&self, query: &str, variables: impl Serialize) -> Subscription {
+        match &self.subscription {
+            Some(config) => Subscription::start(
+                self.policy.subgraph().to_string(),
+                config.clone(),
+                json!({ "query": query, "variables": variables }),
+            ),
+            None => Subscription::inactive(),
         }
     }
 
+    /// The local deployment, unless it's lagging behind.
+    fn local_client(&self) -> Option<&DeploymentClient> {
+        self.local_client
+            .as_ref()
+            .filter(|_| !self.local_lagging.load(Ordering::Relaxed))
+    }
+
+    /// The response may be the last known good one, see [`SubgraphClient::with_cache`]. Use
+    /// [`SubgraphClient::query_conditional`] to tell it apart.
     pub async fn query<Q, V>(
         &self,
         variables: Q::Variables,
     ) -> Result<ResponseResult<Q::ResponseData>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        self.query_conditional::<Q, V>(variables, &mut CacheValidators::default())
+            .await?
+            .map(|response| response.result)
+            .ok_or_else(|| anyhow!("Unexpected `304 Not Modified` to an unconditional query"))
+    }
+
+    /// Like [`SubgraphClient::query`], but returns `None` if the response didn't change since the
+    /// one `validators` were last updated from, and flags the last known good response.
+    ///
+    /// A conditional query always reaches the subgraph, the cached response is only returned if
+    /// it can't be queried.
+    pub async fn query_conditional<Q, V>(
+        &self,
+        variables: Q::Variables,
+        validators: &mut CacheValidators,
+    ) -> Result<Option<QueryResponse<Q::ResponseData>>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        self.query_conditional_cached::<Q, V>(variables, validators, true)
+            .await
+    }
+
+    /// Like [`SubgraphClient::query_conditional`], but never answered with a fresh cached
+    /// response, for when the subgraph is known to have changed, such as on a push of a
+    /// [`Subscription`]. The last known good response is still returned if it can't be queried.
+    pub async fn query_conditional_uncached<Q, V>(
+        &self,
+        variables: Q::Variables,
+        validators: &mut CacheValidators,
+    ) -> Result<Option<QueryResponse<Q::ResponseData>>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        self.query_conditional_cached::<Q, V>(variables, validators, false)
+            .await
+    }
+
+    async fn query_conditional_cached<Q, V>(
+        &self,
+        variables: Q::Variables,
+        validators: &mut CacheValidators,
+        use_fresh: bool,
+    ) -> Result<Option<QueryResponse<Q::ResponseData>>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        let fresh = |result| QueryResponse {
+            result,
+            stale: None,
+        };
+        let Some(cache) = &self.cache else {
+            return Ok(self
+                .fetch_conditional::<Q, V>(variables, validators)
+                .await?
+                .map(|(_, response)| fresh(response)));
+        };
+        let key = cache::key(&Q::build_query(variables.clone()))?;
+        let subgraph = self.policy.subgraph();
+        if use_fresh && validators.is_unconditional() {
+            if let Some(body) = cache.fresh(subgraph, &key) {
+                return parse_response::<Q>(&body).map(|response| Some(fresh(response)));
+            }
+        }
+        match self.fetch_conditional::<Q, V>(variables, validators).await {
+            Ok(Some((body, response))) => {
+                if response.is_ok() {
+                    cache.insert(key, body);
+                }
+                Ok(Some(fresh(response)))
+            }
+            Ok(None) => Ok(None),
+            Err(err) => match cache.last_known_good(subgraph, &key) {
+                Some((body, age)) => {
+                    warn!(
+                        subgraph,
+                        age_secs = age.as_secs(),
+                        "Failed to query the subgraph, using the last known good response: {:#}",
+                        err
+                    );
+                    parse_response::<Q>(&body).map(|result| {
+                        Some(QueryResponse {
+                            result,
+                            stale: Some(age),
+                        })
+                    })
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Queries the subgraph following the policy.
+    async fn fetch_conditional<Q, V>(
+        &self,
+        variables: Q::Variables,
+        validators: &mut CacheValidators,
+    ) -> Result<Option<RawResponse<Q::ResponseData>>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        let start = self.policy.admit()?;
+        let mut attempt = 0;
+        let result = loop {
+            match self
+                .policy
+                .attempt(self.query_conditional_once::<Q, V>(variables.clone(), validators))
+                .await
+            {
+                Err(err) if attempt < self.policy.max_retries() => {
+                    self.policy.backoff(attempt, &err).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        self.policy.finish(start, &result);
+        result
+    }
+
+    async fn query_conditional_once<Q, V>(
+        &self,
+        variables: Q::Variables,
+        validators: &mut CacheValidators,
+    ) -> Result<Option<RawResponse<Q::ResponseData>>, anyhow::Error>
     where
         Q: GraphQLQuery<Variables = V>,
         V: Clone,
     {
         // Try the local client first; if that fails, log the error and move on
         // to the remote client
-        if let Some(ref local_client) = self.local_client {
-            match local_client.query::<Q>(variables.clone()).await {
+        if let Some(local_client) = self.local_client() {
+            match local_client
+                .query_conditional::<Q>(variables.clone(), validators)
+                .await
+            {
                 Ok(response) => return Ok(response),
                 Err(err) => warn!(
                     "Failed to query local subgraph deployment `{}`, trying remote deployment next: {}",
@@ -234,7 +553,7 @@ impl SubgraphClient {
 
         // Try the remote client
         self.remote_client
-            .query::<Q>(variables)
+            .query_conditional::<Q>(variables, validators)
             .await
             .map_err(|err| {
                 warn!(
@@ -246,10 +565,32 @@ impl SubgraphClient {
             })
     }
 
+    /// Sends `query` as is, for the subgraphs served to the gateways. Only the queries that
+    /// didn't get a response are retried, the response is passed on whatever its status.
     pub async fn query_raw(&self, query: Bytes) -> Result<reqwest::Response, anyhow::Error> {
+        let start = self.policy.admit()?;
+        let mut attempt = 0;
+        let result = loop {
+            match self
+                .policy
+                .attempt(self.query_raw_once(query.clone()))
+                .await
+            {
+                Err(err) if attempt < self.policy.max_retries() => {
+                    self.policy.backoff(attempt, &err).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        self.policy.finish(start, &result);
+        result
+    }
+
+    async fn query_raw_once(&self, query: Bytes) -> Result<reqwest::Response, anyhow::Error> {
         // Try the local client first; if that fails, log the error and move on
         // to the remote client
-        if let Some(ref local_client) = self.local_client {
+        if let Some(local_client) = self.local_client() {
             match local_client.query_raw(query.clone()).await {
                 Ok(response) => return Ok(response),
                 Err(err) => warn!(
@@ -273,7 +614,7 @@ impl SubgraphClient {
 
 #[cfg(test)]
 mod test {
-    use std::str::FromStr;
+    use std::{num::NonZeroUsize, str::FromStr, time::Duration};
 
     use serde_json::json;
     use wiremock::matchers::{method, path};
@@ -589,4 +930,186 @@ mod test {
 
         assert_eq!(data.user.name, "remote".to_string());
     }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .respond_with(ResponseTemplate::new(502))
+                    .up_to_n_times(1)
+                    .with_priority(1),
+            )
+            .await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": {
+                        "user": {
+                            "name": "remote"
+                        }
+                    }
+                })),
+            ))
+            .await;
+        let policy = QueryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(10),
+            circuit_breaker_failures: 1,
+            ..Default::default()
+        };
+        let client = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .with_policy("test", policy.clone());
+
+        let data = client
+            .query::<UserQuery, _>(user_query::Variables {})
+            .await
+            .expect("Query should succeed after a retry")
+            .expect("Query result should have a value");
+        assert_eq!(data.user.name, "remote".to_string());
+
+        // Out of retries, the circuit opens
+        mock_server.reset().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(502)))
+            .await;
+        assert!(client
+            .query::<UserQuery, _>(user_query::Variables {})
+            .await
+            .is_err());
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+        assert!(client
+            .query::<UserQuery, _>(user_query::Variables {})
+            .await
+            .is_err());
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_last_known_good_response() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": {
+                        "user": {
+                            "name": "remote"
+                        }
+                    }
+                })),
+            ))
+            .await;
+        let client = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .with_policy(
+            "test",
+            QueryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            },
+        )
+        .with_cache(Some(QueryCacheConfig {
+            max_entries: NonZeroUsize::new(10).unwrap(),
+            ttl: Duration::ZERO,
+            max_stale: Duration::from_secs(60),
+        }));
+        let response = client
+            .query_conditional::<UserQuery, _>(
+                user_query::Variables {},
+                &mut CacheValidators::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.stale.is_none());
+        response.fresh().unwrap().unwrap();
+
+        mock_server.reset().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(502)))
+            .await;
+        let data = client
+            .query::<UserQuery, _>(user_query::Variables {})
+            .await
+            .expect("Query should fall back to the cached response")
+            .unwrap();
+        assert_eq!(data.user.name, "remote".to_string());
+
+        // Flagged, and not taken for a successful poll
+        let response = client
+            .query_conditional::<UserQuery, _>(
+                user_query::Variables {},
+                &mut CacheValidators::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.stale.is_some());
+        assert!(response.fresh().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_conditional_uncached() {
+        let mock_server = MockServer::start().await;
+        let user = |name: &str| {
+            Mock::given(method("POST")).respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({
+                    "data": {
+                        "user": {
+                            "name": name
+                        }
+                    }
+                }),
+            ))
+        };
+        mock_server.register(user("before")).await;
+        let client = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .with_cache(Some(QueryCacheConfig {
+            max_entries: NonZeroUsize::new(10).unwrap(),
+            ttl: Duration::from_secs(60),
+            max_stale: Duration::from_secs(60),
+        }));
+        let name = |response: Option<QueryResponse<user_query::ResponseData>>| {
+            response.unwrap().fresh().unwrap().unwrap().user.name
+        };
+        let response = client
+            .query_conditional::<UserQuery, _>(
+                user_query::Variables {},
+                &mut CacheValidators::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(name(response), "before");
+
+        mock_server.reset().await;
+        mock_server.register(user("after")).await;
+        let response = client
+            .query_conditional::<UserQuery, _>(
+                user_query::Variables {},
+                &mut CacheValidators::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(name(response), "before");
+        let response = client
+            .query_conditional_uncached::<UserQuery, _>(
+                user_query::Variables {},
+                &mut CacheValidators::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(name(response), "after");
+    }
 }
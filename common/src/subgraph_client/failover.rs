@@ -0,0 +1,160 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Failover of a [`super::SubgraphClient`] from the local deployment to the remote query URL while
+//! the local one lags behind.
+//!
+//! A local deployment that is synced and healthy may still fall behind the chain head for a
+//! while, after a restart of graph-node or under load, and answer with outdated data. The latest
+//! block of both, from `_meta.block`, is compared every `check_interval`: the queries go to the
+//! remote query URL while the local deployment is more than `max_lag_blocks` behind.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Weak,
+    },
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::client::DeploymentClient;
+
+lazy_static! {
+    static ref LOCAL_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_local_lag_blocks",
+        "Blocks the local deployment of the subgraph is behind its remote query URL",
+        &["subgraph"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalFailoverConfig {
+    pub max_lag_blocks: u64,
+    pub check_interval: Duration,
+}
+
+/// Sets `lagging` while the local deployment is more than `config.max_lag_blocks` behind the
+/// remote one, until it's dropped.
+pub(super) async fn monitor_lag(
+    subgraph: String,
+    local: DeploymentClient,
+    remote: DeploymentClient,
+    config: LocalFailoverConfig,
+    lagging: Weak<AtomicBool>,
+) {
+    let mut interval = tokio::time::interval(config.check_interval);
+    loop {
+        interval.tick().await;
+        let Some(lagging) = lagging.upgrade() else {
+            break;
+        };
+        let remote_block = match remote.latest_block().await {
+            Ok(block) => block,
+            // Nothing to compare to, keep the local deployment as it was
+            Err(e) => {
+                warn!(%subgraph, "Failed to get the latest block of the remote subgraph: {:#}", e);
+                continue;
+            }
+        };
+        let lag = match local.latest_block().await {
+            Ok(local_block) => remote_block.saturating_sub(local_block),
+            Err(e) => {
+                warn!(%subgraph, "Failed to get the latest block of the local subgraph: {:#}", e);
+                u64::MAX
+            }
+        };
+        LOCAL_LAG
+            .with_label_values(&[&subgraph])
+            .set(lag.min(i64::MAX as u64) as i64);
+
+        let is_lagging = lag > config.max_lag_blocks;
+        if lagging.swap(is_lagging, Ordering::Relaxed) != is_lagging {
+            if is_lagging {
+                warn!(
+                    %subgraph,
+                    lag,
+                    "The local subgraph is lagging behind, querying the remote one"
+                );
+            } else {
+                info!(%subgraph, "The local subgraph caught up, querying it again");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use serde_json::json;
+    use thegraph_core::DeploymentId;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use super::{monitor_lag, LocalFailoverConfig};
+    use crate::subgraph_client::{client::DeploymentClient, DeploymentDetails};
+
+    async fn subgraph_at(block: u64) -> MockServer {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": { "_meta": { "block": { "number": block } } }
+                })),
+            ))
+            .await;
+        mock_server
+    }
+
+    #[tokio::test]
+    async fn test_monitor_lag() {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let local_server = subgraph_at(100).await;
+        let remote_server = subgraph_at(111).await;
+        let client = |url: &str| {
+            DeploymentClient::new(
+                reqwest::Client::new(),
+                DeploymentDetails::for_query_url(&format!("{url}/subgraphs/id/{deployment}"))
+                    .unwrap(),
+            )
+        };
+        let lagging = Arc::new(AtomicBool::new(false));
+        tokio::spawn(monitor_lag(
+            "test".to_string(),
+            client(&local_server.uri()),
+            client(&remote_server.uri()),
+            LocalFailoverConfig {
+                max_lag_blocks: 10,
+                check_interval: Duration::from_millis(20),
+            },
+            Arc::downgrade(&lagging),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(lagging.load(Ordering::Relaxed));
+
+        // Caught up
+        local_server.reset().await;
+        local_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": { "_meta": { "block": { "number": 110 } } }
+                })),
+            ))
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!lagging.load(Ordering::Relaxed));
+    }
+}
@@ -1,7 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod cache;
 mod client;
+mod failover;
 mod monitor;
+mod policy;
+mod subscription;
 
-pub use client::{DeploymentDetails, Query, QueryVariables, SubgraphClient};
+pub use cache::QueryCacheConfig;
+pub use client::{
+    CacheValidators, DeploymentDetails, Query, QueryResponse, QueryVariables, SubgraphClient,
+};
+pub use failover::LocalFailoverConfig;
+pub use policy::QueryPolicy;
+pub use subscription::{Subscription, SubscriptionConfig};
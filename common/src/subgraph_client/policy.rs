@@ -0,0 +1,268 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Retries, timeout and circuit breaker of the queries of a [`super::SubgraphClient`].
+//!
+//! A query failing on its way to the subgraph, by a transport error, a timeout or a `5xx` or
+//! `429` response, is retried after a jittered exponential backoff. The errors in the GraphQL
+//! response are not retried, the subgraph answered.
+//!
+//! After `circuit_breaker_failures` queries in a row failed all their attempts, the circuit
+//! opens: the queries fail right away, without reaching the subgraph, for
+//! `circuit_breaker_cooldown`. The queries are let through again afterwards, and the first one
+//! failing opens the circuit again.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+lazy_static! {
+    static ref QUERIES: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_queries_total",
+        "Queries to the subgraphs, by outcome",
+        &["subgraph", "outcome"]
+    )
+    .unwrap();
+    static ref QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "indexer_subgraph_query_duration_seconds",
+        "Duration of the queries to the subgraphs, retries included",
+        &["subgraph"]
+    )
+    .unwrap();
+    static ref RETRIES: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_query_retries_total",
+        "Attempts of the queries to the subgraphs after the first one",
+        &["subgraph"]
+    )
+    .unwrap();
+    static ref CIRCUIT_OPEN: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_circuit_open",
+        "Whether the queries to the subgraph fail right away after too many failures",
+        &["subgraph"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryPolicy {
+    /// Attempts after the first one.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for each of the next ones.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Timeout of each attempt.
+    pub timeout: Duration,
+    /// Queries failing in a row before the circuit opens.
+    pub circuit_breaker_failures: u32,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+impl Default for QueryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            circuit_breaker_failures: 5,
+            circuit_breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl QueryPolicy {
+    /// Backoff before the retry following `attempt`, between half and all of the exponential
+    /// backoff.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        backoff.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// The [`QueryPolicy`] of a subgraph, with the state of its circuit.
+pub(super) struct PolicyState {
+    subgraph: String,
+    policy: QueryPolicy,
+    circuit: Mutex<Circuit>,
+}
+
+impl PolicyState {
+    pub fn new(subgraph: String, policy: QueryPolicy) -> Self {
+        Self {
+            subgraph,
+            policy,
+            circuit: Mutex::new(Circuit::default()),
+        }
+    }
+
+    pub fn subgraph(&self) -> &str {
+        &self.subgraph
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.policy.max_retries
+    }
+
+    /// Fails if the circuit is open, otherwise returns the start of the query.
+    pub fn admit(&self) -> anyhow::Result<Instant> {
+        let now = Instant::now();
+        let circuit = self.circuit.lock().unwrap();
+        if let Some(open_until) = circuit.open_until.filter(|open_until| now < *open_until) {
+            QUERIES
+                .with_label_values(&[&self.subgraph, "circuit_open"])
+                .inc();
+            return Err(anyhow!(
+                "Queries to the {} subgraph are suspended for {:.1}s after {} failures",
+                self.subgraph,
+                (open_until - now).as_secs_f64(),
+                circuit.failures
+            ));
+        }
+        Ok(now)
+    }
+
+    /// Runs an attempt of a query, with the timeout.
+    pub async fn attempt<T>(
+        &self,
+        f: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        tokio::time::timeout(self.policy.timeout, f)
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow!(
+                    "Query to the {} subgraph timed out after {}s",
+                    self.subgraph,
+                    self.policy.timeout.as_secs_f64()
+                ))
+            })
+    }
+
+    /// Waits before the retry following `attempt`, which failed with `error`.
+    pub async fn backoff(&self, attempt: u32, error: &anyhow::Error) {
+        let backoff = self.policy.backoff(attempt);
+        warn!(
+            subgraph = %self.subgraph,
+            attempt = attempt + 1,
+            "Query to the subgraph failed, retrying in {:.1}s: {:#}",
+            backoff.as_secs_f64(),
+            error
+        );
+        RETRIES.with_label_values(&[&self.subgraph]).inc();
+        tokio::time::sleep(backoff).await;
+    }
+
+    /// Records the outcome of the query started at `start`, once it's done retrying.
+    pub fn finish<T>(&self, start: Instant, result: &anyhow::Result<T>) {
+        QUERY_DURATION
+            .with_label_values(&[&self.subgraph])
+            .observe(start.elapsed().as_secs_f64());
+        let mut circuit = self.circuit.lock().unwrap();
+        match result {
+            Ok(_) => {
+                QUERIES
+                    .with_label_values(&[&self.subgraph, "success"])
+                    .inc();
+                circuit.failures = 0;
+                circuit.open_until = None;
+            }
+            Err(_) => {
+                QUERIES.with_label_values(&[&self.subgraph, "error"]).inc();
+                circuit.failures += 1;
+                if circuit.failures >= self.policy.circuit_breaker_failures {
+                    warn!(
+                        subgraph = %self.subgraph,
+                        "Suspending the queries to the subgraph for {}s after {} failures",
+                        self.policy.circuit_breaker_cooldown.as_secs_f64(),
+                        circuit.failures
+                    );
+                    circuit.open_until =
+                        Some(Instant::now() + self.policy.circuit_breaker_cooldown);
+                }
+            }
+        }
+        CIRCUIT_OPEN
+            .with_label_values(&[&self.subgraph])
+            .set(circuit.open_until.is_some() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::{PolicyState, QueryPolicy};
+
+    #[test]
+    fn test_backoff() {
+        let policy = QueryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..Default::default()
+        };
+        for (attempt, max) in [(0, 1), (1, 2), (2, 4), (3, 5), (30, 5)] {
+            let backoff = policy.backoff(attempt);
+            let max = Duration::from_secs(max);
+            assert!(
+                backoff >= max / 2 && backoff <= max,
+                "{attempt}: {backoff:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let state = PolicyState::new(
+            "test".to_string(),
+            QueryPolicy {
+                circuit_breaker_failures: 2,
+                circuit_breaker_cooldown: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+        let failure: anyhow::Result<()> = Err(anyhow!("502 Bad Gateway"));
+
+        let start = state.admit().unwrap();
+        state.finish(start, &failure);
+        let start = state.admit().unwrap();
+        state.finish(start, &failure);
+        assert!(state.admit().is_err());
+
+        // Let through after the cooldown, and open again by the next failure
+        std::thread::sleep(Duration::from_millis(60));
+        let start = state.admit().unwrap();
+        state.finish(start, &failure);
+        assert!(state.admit().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let start = state.admit().unwrap();
+        state.finish(start, &Ok(()));
+        let start = state.admit().unwrap();
+        state.finish(start, &failure);
+        assert!(state.admit().is_ok());
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Subscriptions to a subgraph over WebSocket, to poll it as soon as the data changes.
+//!
+//! With a subscription URL, the watchers of the escrow accounts and of the allocations also
+//! subscribe to a query selecting what they watch, with the `graphql-ws` protocol of graph-node,
+//! which pushes its result again when it changes. The watcher then polls right away, bypassing the
+//! cache of the subgraph client, instead of waiting for its syncing interval.
+//!
+//! The pushed data itself isn't used, the polls stay the source of truth. A dropped connection is
+//! opened again after an exponential backoff, and followed by a poll to catch up on the changes
+//! missed meanwhile.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use futures_util::{SinkExt, Stream, StreamExt};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{sync::watch, time::sleep};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        http::{header, HeaderValue},
+        Message,
+    },
+};
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_subscription_connected",
+        "Whether the subscription to the subgraph is connected",
+        &["subgraph"]
+    )
+    .unwrap();
+    static ref PUSHES: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_subscription_pushes_total",
+        "Changes pushed by the subscriptions to the subgraph",
+        &["subgraph"]
+    )
+    .unwrap();
+}
+
+const PROTOCOL: &str = "graphql-ws";
+const SUBSCRIPTION_ID: &str = "1";
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionConfig {
+    /// WebSocket URL of the subgraph, `ws://graph-node:8001/subgraphs/id/Qm...` for graph-node.
+    pub url: String,
+    pub max_reconnect_backoff: Duration,
+}
+
+/// The changes pushed by a subscription, see [`super::SubgraphClient::subscribe`]. Unsubscribes
+/// once dropped.
+pub struct Subscription {
+    receiver: Option<watch::Receiver<()>>,
+}
+
+impl Subscription {
+    /// Never notified, for a subgraph without subscription URL.
+    pub(super) fn inactive() -> Self {
+        Self { receiver: None }
+    }
+
+    pub(super) fn start(subgraph: String, config: SubscriptionConfig, payload: Value) -> Self {
+        let (sender, receiver) = watch::channel(());
+        tokio::spawn(run(subgraph, config, payload, sender));
+        Self {
+            receiver: Some(receiver),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    /// Waits for the next change, or connection, of the subscription.
+    pub async fn changed(&mut self) {
+        if let Some(receiver) = &mut self.receiver {
+            if receiver.changed().await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// Keeps the subscription connected until the [`Subscription`] is dropped.
+async fn run(
+    subgraph: String,
+    config: SubscriptionConfig,
+    payload: Value,
+    sender: watch::Sender<()>,
+) {
+    let initial_backoff = INITIAL_RECONNECT_BACKOFF.min(config.max_reconnect_backoff);
+    let mut backoff = initial_backoff;
+    loop {
+        let connected = tokio::select! {
+            result = stream(&subgraph, &config.url, &payload, &sender) => result,
+            _ = sender.closed() => break,
+        };
+        CONNECTED.with_label_values(&[&subgraph]).set(0);
+        match connected {
+            // It was up, back to the initial backoff
+            Ok(true) => backoff = initial_backoff,
+            Ok(false) => {}
+            Err(e) => warn!(
+                %subgraph,
+                "The subscription to the subgraph failed, reconnecting in {:.1}s: {:#}",
+                backoff.as_secs_f64(),
+                e
+            ),
+        }
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = sender.closed() => break,
+        }
+        backoff = backoff.saturating_mul(2).min(config.max_reconnect_backoff);
+    }
+    CONNECTED.with_label_values(&[&subgraph]).set(0);
+}
+
+/// Connects and notifies `sender` of the changes, until the connection is closed. Returns whether
+/// the subscription was acknowledged.
+async fn stream(
+    subgraph: &str,
+    url: &str,
+    payload: &Value,
+    sender: &watch::Sender<()>,
+) -> anyhow::Result<bool> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(PROTOCOL),
+    );
+    let (mut socket, _) = connect_async(request).await?;
+    socket
+        .send(Message::Text(
+            json!({ "type": "connection_init" }).to_string(),
+        ))
+        .await?;
+
+    let mut acked = false;
+    loop {
+        let message = if acked {
+            receive(&mut socket).await?
+        } else {
+            tokio::time::timeout(ACK_TIMEOUT, receive(&mut socket))
+                .await
+                .map_err(|_| {
+                    anyhow!("No `connection_ack` after {}s", ACK_TIMEOUT.as_secs_f64())
+                })??
+        };
+        let Some(message) = message else {
+            if acked {
+                info!(%subgraph, "The subscription to the subgraph was closed, reconnecting");
+                return Ok(true);
+            }
+            bail!("Closed before `connection_ack`");
+        };
+        match message["type"].as_str() {
+            Some("connection_ack") if !acked => {
+                socket
+                    .send(Message::Text(
+                        json!({ "id": SUBSCRIPTION_ID, "type": "start", "payload": payload })
+                            .to_string(),
+                    ))
+                    .await?;
+                acked = true;
+                CONNECTED.with_label_values(&[subgraph]).set(1);
+                // Catch up on the changes missed while disconnected
+                sender.send_replace(());
+            }
+            Some("data") => {
+                PUSHES.with_label_values(&[subgraph]).inc();
+                sender.send_replace(());
+            }
+            Some("ka") => {}
+            Some("complete") => return Ok(acked),
+            _ => bail!("Unexpected message from the subgraph: {message}"),
+        }
+    }
+}
+
+/// The next JSON message, or `None` once the connection is closed.
+async fn receive(
+    socket: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> anyhow::Result<Option<Value>> {
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Text(text) => return Ok(Some(serde_json::from_str(&text)?)),
+            Message::Close(_) => return Ok(None),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, sync::oneshot};
+    use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+    use super::{Subscription, SubscriptionConfig, PUSHES};
+
+    async fn receive<S>(socket: &mut WebSocketStream<S>) -> Value
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let message = socket.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    /// Pushes a change on the first connection and closes it, then signals the second one.
+    async fn subgraph() -> (String, oneshot::Receiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (reconnected, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            for connection in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut socket = accept_async(stream).await.unwrap();
+                assert_eq!(receive(&mut socket).await["type"], "connection_init");
+                socket
+                    .send(Message::Text(
+                        json!({ "type": "connection_ack" }).to_string(),
+                    ))
+                    .await
+                    .unwrap();
+                let start = receive(&mut socket).await;
+                assert_eq!(start["type"], "start");
+                assert_eq!(start["payload"]["variables"]["indexer"], "0x11");
+                if connection == 0 {
+                    socket
+                        .send(Message::Text(
+                            json!({ "id": "1", "type": "data", "payload": { "data": {} } })
+                                .to_string(),
+                        ))
+                        .await
+                        .unwrap();
+                    socket.close(None).await.unwrap();
+                } else {
+                    reconnected.send(()).unwrap();
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let (url, reconnected) = subgraph().await;
+        let mut subscription = Subscription::start(
+            "test".to_string(),
+            SubscriptionConfig {
+                url,
+                max_reconnect_backoff: Duration::from_millis(10),
+            },
+            json!({ "query": "subscription { a }", "variables": { "indexer": "0x11" } }),
+        );
+        tokio::time::timeout(Duration::from_secs(5), subscription.changed())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), reconnected)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(PUSHES.with_label_values(&["test"]).get(), 1);
+        // The second connection is followed by a poll
+        tokio::time::timeout(Duration::from_secs(5), subscription.changed())
+            .await
+            .unwrap();
+
+        let mut inactive = Subscription::inactive();
+        assert!(!inactive.is_active());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), inactive.changed())
+                .await
+                .is_err()
+        );
+    }
+}
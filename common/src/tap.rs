@@ -1,32 +1,66 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::indexer_service::http::TapConfig;
 use crate::tap::checks::allocation_eligible::AllocationEligible;
+use crate::tap::checks::custom::CustomCheck;
 use crate::tap::checks::deny_list_check::DenyListCheck;
+use crate::tap::checks::metered::MeteredCheck;
+use crate::tap::checks::paused_sender_check::PausedSenderCheck;
 use crate::tap::checks::receipt_max_val_check::ReceiptMaxValueCheck;
 use crate::tap::checks::sender_balance_check::SenderBalanceCheck;
+use crate::tap::checks::sender_rate_limit_check::SenderRateLimitCheck;
+use crate::tap::checks::shadow::ShadowCheck;
 use crate::tap::checks::timestamp_check::TimestampCheck;
 use crate::{escrow_accounts::EscrowAccounts, prelude::Allocation};
 use alloy::dyn_abi::Eip712Domain;
 use alloy::primitives::Address;
 use eventuals::Eventual;
-use receipt_store::{DatabaseReceipt, InnerContext};
+use receipt_store::{DatabaseReceipt, InnerContext, RecentReceipts};
 use sqlx::PgPool;
 use std::fmt::Debug;
-use std::time::Duration;
+use std::future::Future;
 use std::{collections::HashMap, sync::Arc};
 use tap_core::receipt::checks::ReceiptCheck;
 use tokio::sync::mpsc::{self, Sender};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{error, warn};
 
 mod checks;
+pub mod dips;
 mod receipt_store;
 
+pub use checks::custom::{CustomCheckConfig, CustomRule};
+pub(crate) use checks::sender_rate_limit_check::TokenBucket;
+pub mod rejection;
+
+tokio::task_local! {
+    /// Peer that relayed the receipt verified by the current task, stored with it.
+    static RELAYED_BY: String;
+}
+
+/// Runs `f`, verifying and storing a receipt relayed by `peer`, see
+/// [`crate::indexer_service::http::peer_relay`].
+pub async fn relayed_by<F: Future>(peer: String, f: F) -> F::Output {
+    RELAYED_BY.scope(peer, f).await
+}
+
+tokio::task_local! {
+    /// Sender of the receipt verified by the current task, already resolved from its signer.
+    static RECEIPT_SENDER: Address;
+}
+
+/// Runs `f`, verifying and storing a receipt of `sender`, for the checks to reuse it instead of
+/// recovering the signer of the receipt again.
+pub async fn sent_by<F: Future>(sender: Address, f: F) -> F::Output {
+    RECEIPT_SENDER.scope(sender, f).await
+}
+
 pub struct IndexerTapContext {
     domain_separator: Arc<Eip712Domain>,
     receipt_producer: Sender<DatabaseReceipt>,
     cancelation_token: CancellationToken,
+    recent_receipts: RecentReceipts,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -36,24 +70,126 @@ pub enum AdapterError {
 }
 
 impl IndexerTapContext {
+    /// Checks of the receipts at intake, the built-in ones then the custom ones of
+    /// `config.custom_checks`, but the ones named in `config.disabled_checks`. The ones named in
+    /// `shadow_checks` run in shadow mode, see [`ShadowCheck`]. The outcome of each one is
+    /// recorded, see [`MeteredCheck`]. Fails if a custom check is named like another one, as
+    /// the settings naming it would apply to both, or if the settings name an unknown check, so
+    /// that a typo doesn't leave a check enforced or running.
     pub async fn get_checks(
         pgpool: PgPool,
         indexer_allocations: Eventual<HashMap<Address, Allocation>>,
         escrow_accounts: Eventual<EscrowAccounts>,
         domain_separator: Eip712Domain,
-        timestamp_error_tolerance: Duration,
-        receipt_max_value: u128,
-    ) -> Vec<ReceiptCheck> {
-        vec![
-            Arc::new(AllocationEligible::new(indexer_allocations)),
-            Arc::new(SenderBalanceCheck::new(
-                escrow_accounts.clone(),
-                domain_separator.clone(),
-            )),
-            Arc::new(TimestampCheck::new(timestamp_error_tolerance)),
-            Arc::new(DenyListCheck::new(pgpool, escrow_accounts, domain_separator).await),
-            Arc::new(ReceiptMaxValueCheck::new(receipt_max_value)),
+        config: &TapConfig,
+        shadow_checks: &[String],
+    ) -> anyhow::Result<Vec<ReceiptCheck>> {
+        let mut checks: Vec<(String, ReceiptCheck)> = vec![
+            (
+                "allocation_eligible",
+                Arc::new(AllocationEligible::new(indexer_allocations)),
+            ),
+            (
+                "sender_balance",
+                Arc::new(SenderBalanceCheck::new(
+                    escrow_accounts.clone(),
+                    domain_separator.clone(),
+                )),
+            ),
+            (
+                "timestamp",
+                Arc::new(TimestampCheck::new(
+                    config.max_future_skew,
+                    config.max_receipt_age,
+                )),
+            ),
+            (
+                "deny_list",
+                Arc::new(
+                    DenyListCheck::new(
+                        pgpool.clone(),
+                        escrow_accounts.clone(),
+                        domain_separator.clone(),
+                    )
+                    .await?,
+                ),
+            ),
+            (
+                "paused_sender",
+                Arc::new(
+                    PausedSenderCheck::new(
+                        pgpool.clone(),
+                        escrow_accounts.clone(),
+                        domain_separator.clone(),
+                    )
+                    .await?,
+                ),
+            ),
+            (
+                "sender_rate_limit",
+                Arc::new(
+                    SenderRateLimitCheck::new(
+                        pgpool,
+                        escrow_accounts.clone(),
+                        domain_separator.clone(),
+                        config.rate_limits_interval,
+                        config.rate_limits_replicas,
+                    )
+                    .await,
+                ),
+            ),
+            (
+                "receipt_max_value",
+                Arc::new(ReceiptMaxValueCheck::new(config.receipt_max_value)),
+            ),
         ]
+        .into_iter()
+        .map(|(name, check)| (name.to_string(), check))
+        .collect();
+        for custom in &config.custom_checks {
+            if checks.iter().any(|(name, _)| name == &custom.name) {
+                anyhow::bail!(
+                    "The custom receipt check `{}` is named like another check",
+                    custom.name
+                );
+            }
+            checks.push((
+                custom.name.clone(),
+                Arc::new(CustomCheck::new(
+                    custom.clone(),
+                    escrow_accounts.clone(),
+                    domain_separator.clone(),
+                )) as ReceiptCheck,
+            ));
+        }
+        for (names, setting) in [
+            (shadow_checks, "shadow_checks"),
+            (&config.disabled_checks[..], "disabled_checks"),
+        ] {
+            for name in names {
+                if !checks.iter().any(|(check, _)| check == name) {
+                    anyhow::bail!("Unknown receipt check `{name}` in `service.tap.{setting}`");
+                }
+            }
+        }
+        Ok(checks
+            .into_iter()
+            .filter(|(name, _)| {
+                let disabled = config.disabled_checks.contains(name);
+                if disabled {
+                    warn!(check = %name, "Receipt check disabled, the receipts aren't checked by it");
+                }
+                !disabled
+            })
+            .map(|(name, check)| {
+                let check = Arc::new(MeteredCheck::new(name.clone(), check)) as ReceiptCheck;
+                if shadow_checks.contains(&name) {
+                    Arc::new(ShadowCheck::new(name, check)) as ReceiptCheck
+                } else {
+                    check
+                }
+            })
+            .collect())
     }
 
     pub async fn new(pgpool: PgPool, domain_separator: Eip712Domain) -> Self {
@@ -67,6 +203,7 @@ impl IndexerTapContext {
             cancelation_token,
             receipt_producer: tx,
             domain_separator: Arc::new(domain_separator),
+            recent_receipts: RecentReceipts::default(),
         }
     }
 }
@@ -2,7 +2,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod allocation_eligible;
+pub mod custom;
 pub mod deny_list_check;
+pub mod metered;
+pub mod paused_sender_check;
 pub mod receipt_max_val_check;
 pub mod sender_balance_check;
+pub mod sender_rate_limit_check;
+pub mod sender_set_check;
+pub mod shadow;
 pub mod timestamp_check;
@@ -4,7 +4,6 @@
 use std::collections::HashMap;
 
 use alloy::primitives::Address;
-use anyhow::anyhow;
 use eventuals::Eventual;
 
 use tap_core::receipt::{
@@ -14,6 +13,8 @@ use tap_core::receipt::{
 };
 
 use crate::prelude::Allocation;
+use crate::tap::rejection::RejectionCode;
+
 pub struct AllocationEligible {
     indexer_allocations: Eventual<HashMap<Address, Allocation>>,
 }
@@ -36,10 +37,12 @@ impl Check for AllocationEligible {
             .map(|allocations| allocations.contains_key(&allocation_id))
             .unwrap_or(false)
         {
-            return Err(CheckError::Failed(anyhow!(
-                "Receipt allocation ID `{}` is not eligible for this indexer",
-                allocation_id
-            )));
+            return Err(CheckError::Failed(
+                RejectionCode::AllocationMismatch.reject(format!(
+                    "Receipt allocation ID `{}` is not eligible for this indexer",
+                    allocation_id
+                )),
+            ));
         }
         Ok(())
     }
@@ -0,0 +1,170 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks of the receipts defined by the operator in the configuration, on top of the built-in
+//! ones of [`crate::tap::IndexerTapContext::get_checks`].
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address};
+use eventuals::Eventual;
+use serde::{Deserialize, Serialize};
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+
+use crate::{escrow_accounts::EscrowAccounts, tap::rejection::RejectionCode};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomCheckConfig {
+    /// Of the check in the metrics, and in the shadow and disabled checks.
+    pub name: String,
+    /// Only the receipts of these senders are checked, all of them if empty.
+    pub senders: Vec<Address>,
+    pub rule: CustomRule,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CustomRule {
+    /// The value of the receipts must be lower, in GRT wei.
+    MaxValue(u128),
+    /// The value of the receipts must be at least this, in GRT wei.
+    MinValue(u128),
+}
+
+pub struct CustomCheck {
+    config: CustomCheckConfig,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+}
+
+impl CustomCheck {
+    pub fn new(
+        config: CustomCheckConfig,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+    ) -> Self {
+        Self {
+            config,
+            escrow_accounts,
+            domain_separator,
+        }
+    }
+
+    /// Whether the sender of `receipt` is one of the senders checked. The receipts of an unknown
+    /// signer are left to the other checks.
+    fn applies_to(&self, receipt: &ReceiptWithState<Checking>) -> bool {
+        if self.config.senders.is_empty() {
+            return true;
+        }
+        let Ok(signer) = receipt
+            .signed_receipt()
+            .recover_signer(&self.domain_separator)
+        else {
+            return false;
+        };
+        self.escrow_accounts
+            .value_immediate()
+            .and_then(|escrow_accounts| escrow_accounts.get_sender_for_signer(&signer).ok())
+            .map_or(false, |sender| self.config.senders.contains(&sender))
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for CustomCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        if !self.applies_to(receipt) {
+            return Ok(());
+        }
+        let value = receipt.signed_receipt().message.value;
+        match self.config.rule {
+            CustomRule::MaxValue(max_value) if value >= max_value => {
+                Err(CheckError::Failed(RejectionCode::ValueCap.reject(format!(
+                    "Receipt value `{value}` is higher than the limit of the check `{}`",
+                    self.config.name
+                ))))
+            }
+            CustomRule::MinValue(min_value) if value < min_value => Err(CheckError::Failed(
+                RejectionCode::ValueFloor.reject(format!(
+                    "Receipt value `{value}` is lower than the minimum of the check `{}`",
+                    self.config.name
+                )),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::SystemTime};
+
+    use alloy::{
+        primitives::{Address, U256},
+        signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+    };
+    use eventuals::Eventual;
+    use tap_core::{
+        receipt::{checks::Check, state::Checking, Receipt, ReceiptWithState},
+        signed_message::EIP712SignedMessage,
+        tap_eip712_domain,
+    };
+
+    use super::{CustomCheck, CustomCheckConfig, CustomRule};
+    use crate::escrow_accounts::EscrowAccounts;
+
+    #[tokio::test]
+    async fn test_custom_check() {
+        let wallet: PrivateKeySigner = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let sender = Address::from([0x33u8; 20]);
+        let domain = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(sender, U256::from(1000))]),
+            HashMap::from([(sender, vec![wallet.address()])]),
+        ));
+        let timestamp_ns = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let receipt = |value| {
+            ReceiptWithState::<Checking>::new(
+                EIP712SignedMessage::new(
+                    &domain,
+                    Receipt {
+                        allocation_id: Address::from([0x22u8; 20]),
+                        nonce: 1,
+                        timestamp_ns,
+                        value,
+                    },
+                    &wallet,
+                )
+                .unwrap(),
+            )
+        };
+        let check = |senders, rule| {
+            CustomCheck::new(
+                CustomCheckConfig {
+                    name: "test".to_string(),
+                    senders,
+                    rule,
+                },
+                escrow_accounts.clone(),
+                domain.clone(),
+            )
+        };
+
+        let max_value = check(vec![sender], CustomRule::MaxValue(100));
+        assert!(max_value.check(&receipt(99)).await.is_ok());
+        assert!(max_value.check(&receipt(100)).await.is_err());
+        // Another sender
+        let other_sender = check(vec![Address::from([0x44u8; 20])], CustomRule::MaxValue(100));
+        assert!(other_sender.check(&receipt(100)).await.is_ok());
+
+        let min_value = check(vec![], CustomRule::MinValue(10));
+        assert!(min_value.check(&receipt(10)).await.is_ok());
+        assert!(min_value.check(&receipt(9)).await.is_err());
+    }
+}
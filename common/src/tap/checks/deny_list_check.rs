@@ -1,202 +1,47 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::escrow_accounts::EscrowAccounts;
-use alloy::dyn_abi::Eip712Domain;
+use crate::tap::checks::sender_set_check::{SenderSet, SenderSetCheck};
+use crate::tap::rejection::RejectionCode;
 use alloy::primitives::Address;
-use eventuals::Eventual;
-use sqlx::postgres::PgListener;
 use sqlx::PgPool;
 use std::collections::HashSet;
-use std::sync::RwLock;
-use std::{str::FromStr, sync::Arc};
-use tap_core::receipt::checks::CheckError;
-use tap_core::receipt::{
-    checks::{Check, CheckResult},
-    state::Checking,
-    ReceiptWithState,
-};
-use tracing::error;
+use std::str::FromStr;
 
-pub struct DenyListCheck {
-    escrow_accounts: Eventual<EscrowAccounts>,
-    domain_separator: Eip712Domain,
-    sender_denylist: Arc<RwLock<HashSet<Address>>>,
-    _sender_denylist_watcher_handle: Arc<tokio::task::JoinHandle<()>>,
-    sender_denylist_watcher_cancel_token: tokio_util::sync::CancellationToken,
-}
-
-impl DenyListCheck {
-    pub async fn new(
-        pgpool: PgPool,
-        escrow_accounts: Eventual<EscrowAccounts>,
-        domain_separator: Eip712Domain,
-    ) -> Self {
-        // Listen to pg_notify events. We start it before updating the sender_denylist so that we
-        // don't miss any updates. PG will buffer the notifications until we start consuming them.
-        let mut pglistener = PgListener::connect_with(&pgpool.clone()).await.unwrap();
-        pglistener
-            .listen("scalar_tap_deny_notification")
-            .await
-            .expect(
-                "should be able to subscribe to Postgres Notify events on the channel \
-                'scalar_tap_deny_notification'",
-            );
-
-        // Fetch the denylist from the DB
-        let sender_denylist = Arc::new(RwLock::new(HashSet::new()));
-        Self::sender_denylist_reload(pgpool.clone(), sender_denylist.clone())
-            .await
-            .expect("should be able to fetch the sender_denylist from the DB on startup");
+/// The senders in `scalar_tap_denylist`, written by tap-agent.
+pub struct DenyList;
 
-        let sender_denylist_watcher_cancel_token = tokio_util::sync::CancellationToken::new();
-        let sender_denylist_watcher_handle = Arc::new(tokio::spawn(Self::sender_denylist_watcher(
-            pgpool.clone(),
-            pglistener,
-            sender_denylist.clone(),
-            sender_denylist_watcher_cancel_token.clone(),
-        )));
-        Self {
-            domain_separator,
-            escrow_accounts,
-            sender_denylist,
-            _sender_denylist_watcher_handle: sender_denylist_watcher_handle,
-            sender_denylist_watcher_cancel_token,
-        }
-    }
+#[async_trait::async_trait]
+impl SenderSet for DenyList {
+    const CHANNEL: &'static str = "scalar_tap_deny_notification";
+    const REJECTION: RejectionCode = RejectionCode::SenderDenied;
+    const DESCRIPTION: &'static str = "denylisted";
 
-    async fn sender_denylist_reload(
-        pgpool: PgPool,
-        denylist_rwlock: Arc<RwLock<HashSet<Address>>>,
-    ) -> anyhow::Result<()> {
-        // Fetch the denylist from the DB
-        let sender_denylist = sqlx::query!(
+    async fn load(pgpool: &PgPool) -> anyhow::Result<HashSet<Address>> {
+        Ok(sqlx::query!(
             r#"
                 SELECT sender_address FROM scalar_tap_denylist
             "#
         )
-        .fetch_all(&pgpool)
+        .fetch_all(pgpool)
         .await?
         .iter()
         .map(|row| Address::from_str(&row.sender_address))
-        .collect::<Result<HashSet<_>, _>>()?;
-
-        *(denylist_rwlock.write().unwrap()) = sender_denylist;
-
-        Ok(())
-    }
-
-    async fn sender_denylist_watcher(
-        pgpool: PgPool,
-        mut pglistener: PgListener,
-        denylist: Arc<RwLock<HashSet<Address>>>,
-        cancel_token: tokio_util::sync::CancellationToken,
-    ) {
-        #[derive(serde::Deserialize)]
-        struct DenylistNotification {
-            tg_op: String,
-            sender_address: Address,
-        }
-
-        loop {
-            tokio::select! {
-                _ = cancel_token.cancelled() => {
-                    break;
-                }
-
-                pg_notification = pglistener.recv() => {
-                    let pg_notification = pg_notification.expect(
-                    "should be able to receive Postgres Notify events on the channel \
-                    'scalar_tap_deny_notification'",
-                    );
-
-                    let denylist_notification: DenylistNotification =
-                        serde_json::from_str(pg_notification.payload()).expect(
-                            "should be able to deserialize the Postgres Notify event payload as a \
-                            DenylistNotification",
-                        );
-
-                    match denylist_notification.tg_op.as_str() {
-                        "INSERT" => {
-                            denylist
-                                .write()
-                                .unwrap()
-                                .insert(denylist_notification.sender_address);
-                        }
-                        "DELETE" => {
-                            denylist
-                                .write()
-                                .unwrap()
-                                .remove(&denylist_notification.sender_address);
-                        }
-                        // UPDATE and TRUNCATE are not expected to happen. Reload the entire denylist.
-                        _ => {
-                            error!(
-                                "Received an unexpected denylist table notification: {}. Reloading entire \
-                                denylist.",
-                                denylist_notification.tg_op
-                            );
-
-                            Self::sender_denylist_reload(pgpool.clone(), denylist.clone())
-                                .await
-                                .expect("should be able to reload the sender denylist")
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
-#[async_trait::async_trait]
-impl Check for DenyListCheck {
-    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
-        let receipt_signer = receipt
-            .signed_receipt()
-            .recover_signer(&self.domain_separator)
-            .map_err(|e| {
-                error!("Failed to recover receipt signer: {}", e);
-                anyhow::anyhow!(e)
-            })
-            .map_err(CheckError::Failed)?;
-        let escrow_accounts_snapshot = self.escrow_accounts.value_immediate().unwrap_or_default();
-
-        let receipt_sender = escrow_accounts_snapshot
-            .get_sender_for_signer(&receipt_signer)
-            .map_err(|e| CheckError::Failed(e.into()))?;
-
-        // Check that the sender is not denylisted
-        if self
-            .sender_denylist
-            .read()
-            .unwrap()
-            .contains(&receipt_sender)
-        {
-            return Err(CheckError::Failed(anyhow::anyhow!(
-                "Received a receipt from a denylisted sender: {}",
-                receipt_sender
-            )));
-        }
-
-        Ok(())
+        .collect::<Result<HashSet<_>, _>>()?)
     }
 }
 
-impl Drop for DenyListCheck {
-    fn drop(&mut self) {
-        // Clean shutdown for the sender_denylist_watcher
-        // Though since it's not a critical task, we don't wait for it to finish (join).
-        self.sender_denylist_watcher_cancel_token.cancel();
-    }
-}
+pub type DenyListCheck = SenderSetCheck<DenyList>;
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
     use alloy::hex::ToHexExt;
-    use tap_core::receipt::ReceiptWithState;
+    use eventuals::Eventual;
+    use tap_core::receipt::{checks::Check, ReceiptWithState};
 
+    use crate::escrow_accounts::EscrowAccounts;
     use crate::test_vectors::{self, create_signed_receipt, TAP_SENDER};
 
     use super::*;
@@ -216,6 +61,7 @@ mod tests {
             test_vectors::TAP_EIP712_DOMAIN.to_owned(),
         )
         .await
+        .unwrap()
     }
 
     #[sqlx::test(migrations = "../migrations")]
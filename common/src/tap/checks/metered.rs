@@ -0,0 +1,124 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use tap_core::receipt::{
+    checks::{CheckError, CheckResult, ReceiptCheck},
+    state::Checking,
+    ReceiptWithState,
+};
+
+use crate::tap::rejection::RejectionCode;
+
+lazy_static! {
+    static ref CHECKS: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_checks_total",
+        "Receipts checked, by check and outcome, `passed` or the rejection code",
+        &["check", "outcome"]
+    )
+    .unwrap();
+    static ref CHECK_DURATION: HistogramVec = register_histogram_vec!(
+        "indexer_receipt_check_duration_seconds",
+        "Duration of the checks of the receipts",
+        &["check"]
+    )
+    .unwrap();
+}
+
+/// Records the outcome and duration of each run of a check.
+pub struct MeteredCheck {
+    name: String,
+    check: ReceiptCheck,
+}
+
+impl MeteredCheck {
+    pub fn new(name: impl Into<String>, check: ReceiptCheck) -> Self {
+        Self {
+            name: name.into(),
+            check,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl tap_core::receipt::checks::Check for MeteredCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let start = Instant::now();
+        let result = self.check.check(receipt).await;
+        CHECK_DURATION
+            .with_label_values(&[&self.name])
+            .observe(start.elapsed().as_secs_f64());
+        let outcome = match &result {
+            Ok(()) => "passed",
+            Err(CheckError::Failed(error) | CheckError::Retryable(error)) => {
+                RejectionCode::of(error).as_str()
+            }
+        };
+        CHECKS.with_label_values(&[&self.name, outcome]).inc();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use alloy::{
+        primitives::Address,
+        signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+    };
+    use tap_core::{
+        receipt::{checks::Check, state::Checking, Receipt, ReceiptWithState},
+        signed_message::EIP712SignedMessage,
+        tap_eip712_domain,
+    };
+
+    use super::{MeteredCheck, CHECKS};
+    use crate::tap::checks::receipt_max_val_check::ReceiptMaxValueCheck;
+
+    #[tokio::test]
+    async fn test_metered_check() {
+        let wallet: PrivateKeySigner = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let domain = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+        let timestamp_ns = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let receipt = |value| {
+            ReceiptWithState::<Checking>::new(
+                EIP712SignedMessage::new(
+                    &domain,
+                    Receipt {
+                        allocation_id: Address::from([0x22u8; 20]),
+                        nonce: 1,
+                        timestamp_ns,
+                        value,
+                    },
+                    &wallet,
+                )
+                .unwrap(),
+            )
+        };
+
+        let check = MeteredCheck::new(
+            "test_metered_value_cap",
+            Arc::new(ReceiptMaxValueCheck::new(10)),
+        );
+        assert!(check.check(&receipt(5)).await.is_ok());
+        assert!(check.check(&receipt(1000)).await.is_err());
+        assert!(check.check(&receipt(1000)).await.is_err());
+        let count = |outcome| {
+            CHECKS
+                .with_label_values(&["test_metered_value_cap", outcome])
+                .get()
+        };
+        assert_eq!(count("passed"), 1);
+        assert_eq!(count("value_cap"), 2);
+    }
+}
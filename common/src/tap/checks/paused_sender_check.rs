@@ -0,0 +1,93 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rejects the receipts of the senders paused by the operator in `tap_paused_senders`, see the
+//! `pause` admin endpoint of tap-agent. Kept apart from the denylist, which tap-agent writes on
+//! its own.
+
+use crate::tap::checks::sender_set_check::{SenderSet, SenderSetCheck};
+use crate::tap::rejection::RejectionCode;
+use alloy::primitives::Address;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// The senders in `tap_paused_senders`.
+pub struct PausedSenders;
+
+#[async_trait::async_trait]
+impl SenderSet for PausedSenders {
+    const CHANNEL: &'static str = "tap_pause_notification";
+    const REJECTION: RejectionCode = RejectionCode::SenderPaused;
+    const DESCRIPTION: &'static str = "paused";
+
+    async fn load(pgpool: &PgPool) -> anyhow::Result<HashSet<Address>> {
+        Ok(
+            sqlx::query_scalar!("SELECT sender_address FROM tap_paused_senders")
+                .fetch_all(pgpool)
+                .await?
+                .iter()
+                .map(|sender| Address::from_str(sender))
+                .collect::<Result<HashSet<_>, _>>()?,
+        )
+    }
+}
+
+pub type PausedSenderCheck = SenderSetCheck<PausedSenders>;
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use alloy::{hex::ToHexExt, primitives::Address};
+    use eventuals::Eventual;
+    use sqlx::PgPool;
+    use tap_core::receipt::{checks::Check, ReceiptWithState};
+
+    use super::PausedSenderCheck;
+    use crate::{
+        escrow_accounts::EscrowAccounts,
+        test_vectors::{self, create_signed_receipt, TAP_SENDER},
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_paused_sender_updates(pgpool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        ));
+        let check = PausedSenderCheck::new(
+            pgpool.clone(),
+            escrow_accounts,
+            test_vectors::TAP_EIP712_DOMAIN.to_owned(),
+        )
+        .await
+        .unwrap();
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(allocation_id, u64::MAX, u64::MAX, u128::MAX).await,
+        );
+        check.check(&receipt).await.unwrap();
+
+        sqlx::query!(
+            "INSERT INTO tap_paused_senders (sender_address) VALUES ($1)",
+            TAP_SENDER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(check.check(&receipt).await.is_err());
+
+        sqlx::query!(
+            "DELETE FROM tap_paused_senders WHERE sender_address = $1",
+            TAP_SENDER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        check.check(&receipt).await.unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
-use anyhow::anyhow;
+
+use crate::tap::rejection::RejectionCode;
 
 pub struct ReceiptMaxValueCheck {
     receipt_max_value: u128,
@@ -26,10 +27,10 @@ impl Check for ReceiptMaxValueCheck {
         if receipt_value < self.receipt_max_value {
             Ok(())
         } else {
-            Err(CheckError::Failed(anyhow!(
+            Err(CheckError::Failed(RejectionCode::ValueCap.reject(format!(
                 "Receipt value `{}` is higher than the limit set by the user",
                 receipt_value
-            )))
+            ))))
         }
     }
 }
@@ -2,9 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::escrow_accounts::EscrowAccounts;
+use crate::tap::rejection::RejectionCode;
 use alloy::dyn_abi::Eip712Domain;
 use alloy::primitives::U256;
-use anyhow::anyhow;
 use eventuals::Eventual;
 use tap_core::receipt::{
     checks::{Check, CheckError, CheckResult},
@@ -39,13 +39,13 @@ impl Check for SenderBalanceCheck {
             .inspect_err(|e| {
                 error!("Failed to recover receipt signer: {}", e);
             })
-            .map_err(|e| CheckError::Failed(e.into()))?;
+            .map_err(|e| CheckError::Failed(RejectionCode::BadSignature.reject(e)))?;
 
         // We bail if the receipt signer does not have a corresponding sender in the escrow
         // accounts.
         let receipt_sender = escrow_accounts_snapshot
             .get_sender_for_signer(&receipt_signer)
-            .map_err(|e| CheckError::Failed(e.into()))?;
+            .map_err(|e| CheckError::Failed(RejectionCode::UnknownSigner.reject(e)))?;
 
         // Check that the sender has a non-zero balance -- more advanced accounting is done in
         // `tap-agent`.
@@ -53,10 +53,12 @@ impl Check for SenderBalanceCheck {
             .get_balance_for_sender(&receipt_sender)
             .map_or(false, |balance| balance > U256::ZERO)
         {
-            return Err(CheckError::Failed(anyhow!(
-                "Receipt sender `{}` does not have a sufficient balance",
-                receipt_signer,
-            )));
+            return Err(CheckError::Failed(
+                RejectionCode::InsufficientBalance.reject(format!(
+                    "Receipt sender `{}` does not have a sufficient balance",
+                    receipt_signer,
+                )),
+            ));
         }
         Ok(())
     }
@@ -0,0 +1,369 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rejects the receipts of the senders over their rate limit in `tap_sender_rate_limits`.
+//!
+//! tap-agent sizes the token bucket of each sender from its escrow headroom and fee velocity,
+//! see its `rate_limits` module. The buckets are loaded every [`RELOAD_INTERVAL`] and kept in
+//! memory: each receipt takes its value from the bucket of its sender, refilled continuously.
+//! The senders without a row aren't limited, nor the ones whose row wasn't updated for
+//! [`STALE_INTERVALS`] of the updates of tap-agent, it may not be running anymore.
+//!
+//! Each replica of indexer-service only takes in a share of the receipts, so it applies its share
+//! of the buckets, the limits divided by the number of replicas.
+
+use crate::escrow_accounts::EscrowAccounts;
+use crate::tap::rejection::RejectionCode;
+use alloy::dyn_abi::Eip712Domain;
+use alloy::primitives::Address;
+use bigdecimal::ToPrimitive;
+use eventuals::Eventual;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{str::FromStr, sync::Arc};
+use tap_core::receipt::checks::CheckError;
+use tap_core::receipt::{
+    checks::{Check, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+/// How often the buckets are reloaded, tap-agent doesn't update them more often.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// Updates of tap-agent missed from which the row of a sender is ignored.
+const STALE_INTERVALS: u32 = 3;
+
+/// How the rows of `tap_sender_rate_limits` are read.
+#[derive(Debug, Clone, Copy)]
+struct RowsReading {
+    /// Rows not updated for longer are ignored.
+    max_age: Duration,
+    /// Number of replicas of indexer-service the limits are divided by.
+    replicas: u128,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TokenBucket {
+    capacity: u128,
+    refill_per_second: u128,
+    tokens: u128,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u128, refill_per_second: u128, now: Instant) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            refilled_at: now,
+        }
+    }
+
+    /// Sets the limits written by tap-agent. The tokens left are kept, up to the new capacity.
+    fn update(&mut self, capacity: u128, refill_per_second: u128, now: Instant) {
+        self.refill(now);
+        self.capacity = capacity;
+        self.refill_per_second = refill_per_second;
+        self.tokens = self.tokens.min(capacity);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let refilled = self.refill_per_second.saturating_mul(elapsed.as_nanos()) / 1_000_000_000;
+        self.tokens = self.tokens.saturating_add(refilled).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Takes `value` from the bucket, if there's enough.
+    pub(crate) fn take(&mut self, value: u128, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < value {
+            return false;
+        }
+        self.tokens -= value;
+        true
+    }
+
+    /// How long until `value` can be taken, as of the last refill. `None` if never.
+    pub(crate) fn wait_for(&self, value: u128) -> Option<Duration> {
+        if value > self.capacity || (self.refill_per_second == 0 && self.tokens < value) {
+            return None;
+        }
+        let missing = value.saturating_sub(self.tokens);
+        let nanos = missing
+            .saturating_mul(1_000_000_000)
+            .div_ceil(self.refill_per_second.max(1));
+        Some(Duration::from_nanos(nanos.min(u64::MAX as u128) as u64))
+    }
+}
+
+pub struct SenderRateLimitCheck {
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    buckets: Arc<Mutex<HashMap<Address, TokenBucket>>>,
+    reloader_cancel_token: CancellationToken,
+}
+
+impl SenderRateLimitCheck {
+    /// `update_interval` is how often tap-agent updates the rows, `replicas` the number of
+    /// replicas of indexer-service.
+    pub async fn new(
+        pgpool: PgPool,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+        update_interval: Duration,
+        replicas: u32,
+    ) -> Self {
+        let reading = RowsReading {
+            max_age: update_interval * STALE_INTERVALS,
+            replicas: u128::from(replicas.max(1)),
+        };
+        let buckets = Arc::new(Mutex::new(HashMap::new()));
+        Self::reload(&pgpool, &buckets, reading)
+            .await
+            .expect("should be able to fetch the sender rate limits from the DB on startup");
+
+        let reloader_cancel_token = CancellationToken::new();
+        tokio::spawn(Self::reloader(
+            pgpool,
+            buckets.clone(),
+            reading,
+            reloader_cancel_token.clone(),
+        ));
+        Self {
+            escrow_accounts,
+            domain_separator,
+            buckets,
+            reloader_cancel_token,
+        }
+    }
+
+    async fn reload(
+        pgpool: &PgPool,
+        buckets: &Mutex<HashMap<Address, TokenBucket>>,
+        reading: RowsReading,
+    ) -> anyhow::Result<()> {
+        let rows = sqlx::query!(
+            r#"
+                SELECT sender_address, capacity, refill_per_second
+                FROM tap_sender_rate_limits
+                WHERE updated_at > NOW() - make_interval(secs => $1)
+            "#,
+            reading.max_age.as_secs_f64()
+        )
+        .fetch_all(pgpool)
+        .await?;
+        let limits = rows
+            .into_iter()
+            .map(|row| {
+                Ok((
+                    Address::from_str(&row.sender_address)?,
+                    row.capacity.to_u128().unwrap_or(u128::MAX) / reading.replicas,
+                    row.refill_per_second.to_u128().unwrap_or(u128::MAX) / reading.replicas,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let now = Instant::now();
+        let mut buckets = buckets.lock().unwrap();
+        let mut updated = HashMap::with_capacity(limits.len());
+        for (sender, capacity, refill_per_second) in limits {
+            let bucket = match buckets.remove(&sender) {
+                Some(mut bucket) => {
+                    bucket.update(capacity, refill_per_second, now);
+                    bucket
+                }
+                None => TokenBucket::new(capacity, refill_per_second, now),
+            };
+            updated.insert(sender, bucket);
+        }
+        *buckets = updated;
+        Ok(())
+    }
+
+    async fn reloader(
+        pgpool: PgPool,
+        buckets: Arc<Mutex<HashMap<Address, TokenBucket>>>,
+        reading: RowsReading,
+        cancel_token: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        // The first tick completes immediately, and the buckets were just loaded
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            if let Err(e) = Self::reload(&pgpool, &buckets, reading).await {
+                error!("Failed to reload the sender rate limits: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for SenderRateLimitCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let receipt_signer = receipt
+            .signed_receipt()
+            .recover_signer(&self.domain_separator)
+            .map_err(|e| CheckError::Failed(RejectionCode::BadSignature.reject(e)))?;
+        let receipt_sender = self
+            .escrow_accounts
+            .value_immediate()
+            .unwrap_or_default()
+            .get_sender_for_signer(&receipt_signer)
+            .map_err(|e| CheckError::Failed(RejectionCode::UnknownSigner.reject(e)))?;
+
+        let value = receipt.signed_receipt().message.value;
+        let mut buckets = self.buckets.lock().unwrap();
+        let Some(bucket) = buckets.get_mut(&receipt_sender) else {
+            return Ok(());
+        };
+        if !bucket.take(value, Instant::now()) {
+            return Err(CheckError::Failed(
+                RejectionCode::RateLimited
+                    .reject(format!("Sender {receipt_sender} is over its rate limit")),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SenderRateLimitCheck {
+    fn drop(&mut self) {
+        self.reloader_cancel_token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use alloy::{hex::ToHexExt, primitives::Address};
+    use eventuals::Eventual;
+    use sqlx::{types::BigDecimal, PgPool};
+    use tap_core::receipt::{checks::Check, ReceiptWithState};
+
+    use super::{RowsReading, SenderRateLimitCheck, TokenBucket};
+    use crate::{
+        escrow_accounts::EscrowAccounts,
+        test_vectors::{self, create_signed_receipt, TAP_SENDER},
+    };
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 10, start);
+        assert!(bucket.take(60, start));
+        assert!(!bucket.take(60, start));
+
+        // Refilled by 10 per second, up to the capacity
+        assert!(bucket.take(60, start + Duration::from_secs(2)));
+        assert!(!bucket.take(1, start + Duration::from_secs(2)));
+        assert!(bucket.take(100, start + Duration::from_secs(60)));
+        assert_eq!(bucket.wait_for(5), Some(Duration::from_millis(500)));
+        assert_eq!(bucket.wait_for(101), None);
+
+        // The tokens left are kept, up to the new capacity
+        let now = start + Duration::from_secs(65);
+        bucket.update(20, 0, now);
+        assert_eq!(bucket.tokens, 20);
+        assert!(!bucket.take(21, now + Duration::from_secs(60)));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_sender_rate_limit(pgpool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        ));
+        let check = SenderRateLimitCheck::new(
+            pgpool.clone(),
+            escrow_accounts,
+            test_vectors::TAP_EIP712_DOMAIN.to_owned(),
+            Duration::from_secs(10),
+            1,
+        )
+        .await;
+        let reading = RowsReading {
+            max_age: Duration::from_secs(30),
+            replicas: 1,
+        };
+        let allocation_id =
+            Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let receipt =
+            |nonce| async move { create_signed_receipt(allocation_id, nonce, 1, 100).await };
+
+        // Not limited without a row
+        for nonce in 0..3 {
+            check
+                .check(&ReceiptWithState::new(receipt(nonce).await))
+                .await
+                .unwrap();
+        }
+
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_sender_rate_limits (sender_address, capacity, refill_per_second)
+                VALUES ($1, $2, $3)
+            "#,
+            TAP_SENDER.1.encode_hex(),
+            BigDecimal::from(150),
+            BigDecimal::from(0)
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        SenderRateLimitCheck::reload(&pgpool, &check.buckets, reading)
+            .await
+            .unwrap();
+        check
+            .check(&ReceiptWithState::new(receipt(3).await))
+            .await
+            .unwrap();
+        assert!(check
+            .check(&ReceiptWithState::new(receipt(4).await))
+            .await
+            .is_err());
+
+        // Each replica applies its share of the limits
+        check.buckets.lock().unwrap().clear();
+        SenderRateLimitCheck::reload(
+            &pgpool,
+            &check.buckets,
+            RowsReading {
+                replicas: 2,
+                ..reading
+            },
+        )
+        .await
+        .unwrap();
+        assert!(check
+            .check(&ReceiptWithState::new(receipt(5).await))
+            .await
+            .is_err());
+
+        // A row tap-agent stopped updating is ignored
+        sqlx::query!("UPDATE tap_sender_rate_limits SET updated_at = NOW() - INTERVAL '1 hour'")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        SenderRateLimitCheck::reload(&pgpool, &check.buckets, reading)
+            .await
+            .unwrap();
+        check
+            .check(&ReceiptWithState::new(receipt(6).await))
+            .await
+            .unwrap();
+    }
+}
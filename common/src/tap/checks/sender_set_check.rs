@@ -0,0 +1,226 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rejects the receipts of a set of senders kept in a table, such as the denylist or the paused
+//! senders. The set is loaded on startup, then kept up to date by the `pg_notify` events of the
+//! triggers of the table, `{"tg_op": ..., "sender_address": ...}`. Any other event reloads it,
+//! as does a failure to receive the events, after a jittered exponential backoff.
+
+use crate::escrow_accounts::EscrowAccounts;
+use crate::tap::rejection::RejectionCode;
+use alloy::dyn_abi::Eip712Domain;
+use alloy::primitives::Address;
+use anyhow::Context;
+use eventuals::Eventual;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tap_core::receipt::checks::CheckError;
+use tap_core::receipt::{
+    checks::{Check, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+const RECV_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const RECV_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A set of senders whose receipts are rejected.
+#[async_trait::async_trait]
+pub trait SenderSet: Send + Sync + 'static {
+    /// Channel of the notifications of the triggers of the table.
+    const CHANNEL: &'static str;
+    const REJECTION: RejectionCode;
+    /// How the senders of the set are called in the rejections, e.g. "denylisted".
+    const DESCRIPTION: &'static str;
+
+    async fn load(pgpool: &PgPool) -> anyhow::Result<HashSet<Address>>;
+}
+
+pub struct SenderSetCheck<S: SenderSet> {
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    senders: Arc<RwLock<HashSet<Address>>>,
+    watcher_cancel_token: CancellationToken,
+    _set: PhantomData<fn() -> S>,
+}
+
+impl<S: SenderSet> SenderSetCheck<S> {
+    pub async fn new(
+        pgpool: PgPool,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+    ) -> anyhow::Result<Self> {
+        // Listening before the first load, so that no update is missed in between. PG buffers
+        // the notifications until they're consumed.
+        let mut pglistener = PgListener::connect_with(&pgpool).await?;
+        pglistener.listen(S::CHANNEL).await.with_context(|| {
+            format!(
+                "Failed to subscribe to the Postgres notifications of the channel '{}'",
+                S::CHANNEL
+            )
+        })?;
+
+        let senders = S::load(&pgpool)
+            .await
+            .with_context(|| format!("Failed to load the {} senders", S::DESCRIPTION))?;
+        let senders = Arc::new(RwLock::new(senders));
+
+        let watcher_cancel_token = CancellationToken::new();
+        tokio::spawn(Self::watcher(
+            pgpool,
+            pglistener,
+            senders.clone(),
+            watcher_cancel_token.clone(),
+        ));
+        Ok(Self {
+            escrow_accounts,
+            domain_separator,
+            senders,
+            watcher_cancel_token,
+            _set: PhantomData,
+        })
+    }
+
+    async fn watcher(
+        pgpool: PgPool,
+        mut pglistener: PgListener,
+        senders: Arc<RwLock<HashSet<Address>>>,
+        cancel_token: CancellationToken,
+    ) {
+        #[derive(serde::Deserialize)]
+        struct SenderSetNotification {
+            tg_op: String,
+            sender_address: Address,
+        }
+
+        let mut failures = 0;
+        loop {
+            let pg_notification = tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                pg_notification = pglistener.recv() => pg_notification,
+            };
+            let pg_notification = match pg_notification {
+                Ok(pg_notification) => {
+                    failures = 0;
+                    pg_notification
+                }
+                // The listener reconnects on the next call, the notifications sent meanwhile are
+                // lost.
+                Err(e) => {
+                    let backoff = recv_backoff(failures);
+                    failures += 1;
+                    error!(
+                        channel = S::CHANNEL,
+                        "Failed to receive the notifications of the {} senders, retrying in \
+                        {backoff:?}: {e}",
+                        S::DESCRIPTION
+                    );
+                    tokio::select! {
+                        _ = cancel_token.cancelled() => break,
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    Self::reload(&pgpool, &senders).await;
+                    continue;
+                }
+            };
+            match serde_json::from_str::<SenderSetNotification>(pg_notification.payload()) {
+                Ok(notification) if notification.tg_op == "INSERT" => {
+                    senders.write().unwrap().insert(notification.sender_address);
+                }
+                Ok(notification) if notification.tg_op == "DELETE" => {
+                    senders
+                        .write()
+                        .unwrap()
+                        .remove(&notification.sender_address);
+                }
+                // UPDATE and TRUNCATE are not expected to happen
+                _ => {
+                    error!(
+                        channel = S::CHANNEL,
+                        "Unexpected notification of the {} senders, reloading them.",
+                        S::DESCRIPTION
+                    );
+                    Self::reload(&pgpool, &senders).await;
+                }
+            }
+        }
+    }
+
+    async fn reload(pgpool: &PgPool, senders: &RwLock<HashSet<Address>>) {
+        match S::load(pgpool).await {
+            Ok(reloaded) => *senders.write().unwrap() = reloaded,
+            Err(e) => error!("Failed to reload the {} senders: {}", S::DESCRIPTION, e),
+        }
+    }
+}
+
+/// Backoff after `failures` consecutive failures to receive the notifications, between half and
+/// all of the exponential backoff.
+fn recv_backoff(failures: u32) -> Duration {
+    let backoff = RECV_INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(RECV_MAX_BACKOFF);
+    let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    backoff.mul_f64(0.5 + jitter / 2.0)
+}
+
+#[async_trait::async_trait]
+impl<S: SenderSet> Check for SenderSetCheck<S> {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let receipt_signer = receipt
+            .signed_receipt()
+            .recover_signer(&self.domain_separator)
+            .map_err(|e| {
+                error!("Failed to recover receipt signer: {}", e);
+                CheckError::Failed(RejectionCode::BadSignature.reject(e))
+            })?;
+        let receipt_sender = self
+            .escrow_accounts
+            .value_immediate()
+            .unwrap_or_default()
+            .get_sender_for_signer(&receipt_signer)
+            .map_err(|e| CheckError::Failed(RejectionCode::UnknownSigner.reject(e)))?;
+
+        if self.senders.read().unwrap().contains(&receipt_sender) {
+            return Err(CheckError::Failed(S::REJECTION.reject(format!(
+                "Received a receipt from a {} sender: {receipt_sender}",
+                S::DESCRIPTION
+            ))));
+        }
+        Ok(())
+    }
+}
+
+impl<S: SenderSet> Drop for SenderSetCheck<S> {
+    fn drop(&mut self) {
+        // Not a critical task, it isn't waited for
+        self.watcher_cancel_token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::recv_backoff;
+
+    #[test]
+    fn test_recv_backoff() {
+        for (failures, max) in [(0, 500), (1, 1_000), (2, 2_000), (6, 30_000), (40, 30_000)] {
+            let backoff = recv_backoff(failures);
+            let max = Duration::from_millis(max);
+            assert!(
+                backoff >= max / 2 && backoff <= max,
+                "{failures}: {backoff:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use tap_core::receipt::{
+    checks::{CheckError, CheckResult, ReceiptCheck},
+    state::Checking,
+    ReceiptWithState,
+};
+use tracing::warn;
+
+use crate::tap::rejection::RejectionCode;
+
+/// One in this many receipts rejected by a shadow check is logged.
+const LOG_SAMPLE_RATE: u64 = 100;
+
+lazy_static! {
+    static ref SHADOW_REJECTED: CounterVec = register_counter_vec!(
+        "indexer_receipt_shadow_rejected_total",
+        "Receipts that a check in shadow mode would have rejected, by check and rejection code",
+        &["check", "code"]
+    )
+    .unwrap();
+}
+
+/// Runs a check in shadow mode, to roll it out safely: the receipts it would reject are counted
+/// and a sample of them is logged, but they're accepted.
+pub struct ShadowCheck {
+    name: String,
+    check: ReceiptCheck,
+    rejected: AtomicU64,
+}
+
+impl ShadowCheck {
+    pub fn new(name: impl Into<String>, check: ReceiptCheck) -> Self {
+        Self {
+            name: name.into(),
+            check,
+            rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl tap_core::receipt::checks::Check for ShadowCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        if let Err(CheckError::Failed(error) | CheckError::Retryable(error)) =
+            self.check.check(receipt).await
+        {
+            let code = RejectionCode::of(&error);
+            SHADOW_REJECTED
+                .with_label_values(&[&self.name, code.as_str()])
+                .inc();
+            if self.rejected.fetch_add(1, Ordering::Relaxed) % LOG_SAMPLE_RATE == 0 {
+                warn!(
+                    check = %self.name,
+                    %code,
+                    %error,
+                    "Receipt accepted, but rejected by a check in shadow mode"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use alloy::{
+        primitives::Address,
+        signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+    };
+    use tap_core::{
+        receipt::{checks::Check, state::Checking, Receipt, ReceiptWithState},
+        signed_message::EIP712SignedMessage,
+        tap_eip712_domain,
+    };
+
+    use super::{ShadowCheck, SHADOW_REJECTED};
+    use crate::tap::checks::receipt_max_val_check::ReceiptMaxValueCheck;
+
+    #[tokio::test]
+    async fn test_shadow_check_accepts() {
+        let wallet: PrivateKeySigner = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let domain = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+        let timestamp_ns = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let receipt = ReceiptWithState::<Checking>::new(
+            EIP712SignedMessage::new(
+                &domain,
+                Receipt {
+                    allocation_id: Address::from([0x22u8; 20]),
+                    nonce: 1,
+                    timestamp_ns,
+                    value: 1000,
+                },
+                &wallet,
+            )
+            .unwrap(),
+        );
+
+        let check = ShadowCheck::new("test_value_cap", Arc::new(ReceiptMaxValueCheck::new(10)));
+        assert!(check.check(&receipt).await.is_ok());
+        assert!(check.check(&receipt).await.is_ok());
+        assert_eq!(
+            SHADOW_REJECTED
+                .with_label_values(&["test_value_cap", "value_cap"])
+                .get(),
+            2.0
+        );
+
+        let passing = ShadowCheck::new("test_passing", Arc::new(ReceiptMaxValueCheck::new(10_000)));
+        assert!(passing.check(&receipt).await.is_ok());
+        assert_eq!(
+            SHADOW_REJECTED
+                .with_label_values(&["test_passing", "value_cap"])
+                .get(),
+            0.0
+        );
+    }
+}
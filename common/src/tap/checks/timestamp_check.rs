@@ -1,22 +1,57 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
-use anyhow::anyhow;
-use std::time::{Duration, SystemTime};
 
-pub struct TimestampCheck {
-    timestamp_error_tolerance: Duration,
-}
+//! Window of the timestamps of the receipts accepted at intake.
+//!
+//! A receipt is rejected if its timestamp is more than `service.tap.max_future_skew_secs` ahead
+//! of the clock of the indexer, as when the clock of the gateway is ahead, or more than
+//! `service.tap.max_receipt_age_secs` behind it. To only flag them, the `timestamp` check can be
+//! run in shadow mode. The skew of the receipts of each sender is recorded either way, with the
+//! sender resolved by the request handler, see [`crate::tap::sent_by`]. The receipts relayed by
+//! the peers, whose sender isn't resolved before the checks, aren't recorded.
+
+use std::time::{Duration, SystemTime};
 
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, HistogramVec};
 use tap_core::receipt::{
     checks::{Check, CheckError, CheckResult},
     state::Checking,
     ReceiptWithState,
 };
 
+use crate::tap::{rejection::RejectionCode, RECEIPT_SENDER};
+
+lazy_static! {
+    static ref TIMESTAMP_SKEW: HistogramVec = register_histogram_vec!(
+        "indexer_receipt_timestamp_skew_seconds",
+        "Timestamp of the receipts minus the time they're received at, positive for the \
+        receipts ahead of the clock of the indexer",
+        &["sender"],
+        vec![-300.0, -60.0, -30.0, -10.0, -5.0, -1.0, 0.0, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0]
+    )
+    .unwrap();
+}
+
+pub struct TimestampCheck {
+    max_future_skew: Duration,
+    max_receipt_age: Duration,
+}
+
 impl TimestampCheck {
-    pub fn new(timestamp_error_tolerance: Duration) -> Self {
+    pub fn new(max_future_skew: Duration, max_receipt_age: Duration) -> Self {
         Self {
-            timestamp_error_tolerance,
+            max_future_skew,
+            max_receipt_age,
+        }
+    }
+
+    /// Records the skew of the receipt being verified, for its sender, if it's known.
+    fn record_skew(&self, skew_secs: f64) {
+        if let Ok(sender) = RECEIPT_SENDER.try_with(|sender| *sender) {
+            TIMESTAMP_SKEW
+                .with_label_values(&[&sender.to_string()])
+                .observe(skew_secs);
         }
     }
 }
@@ -27,19 +62,36 @@ impl Check for TimestampCheck {
         let timestamp_now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .map_err(|e| CheckError::Failed(e.into()))?;
-        let min_timestamp = timestamp_now - self.timestamp_error_tolerance;
-        let max_timestamp = timestamp_now + self.timestamp_error_tolerance;
-
         let receipt_timestamp = Duration::from_nanos(receipt.signed_receipt().message.timestamp_ns);
 
-        if receipt_timestamp < max_timestamp && receipt_timestamp > min_timestamp {
-            Ok(())
+        if receipt_timestamp >= timestamp_now {
+            let skew = receipt_timestamp - timestamp_now;
+            self.record_skew(skew.as_secs_f64());
+            if skew >= self.max_future_skew {
+                return Err(CheckError::Failed(
+                    RejectionCode::TimestampOutOfWindow.reject(format!(
+                        "Receipt timestamp `{}` is {}s ahead of the current system time, more \
+                        than max_future_skew_secs",
+                        receipt_timestamp.as_secs(),
+                        skew.as_secs()
+                    )),
+                ));
+            }
         } else {
-            Err(CheckError::Failed(anyhow!(
-                "Receipt timestamp `{}` is outside of current system time +/- timestamp_error_tolerance",
-                receipt_timestamp.as_secs()
-            )))
+            let age = timestamp_now - receipt_timestamp;
+            self.record_skew(-age.as_secs_f64());
+            if age >= self.max_receipt_age {
+                return Err(CheckError::Failed(
+                    RejectionCode::TimestampOutOfWindow.reject(format!(
+                        "Receipt timestamp `{}` is {}s behind the current system time, more \
+                        than max_receipt_age_secs",
+                        receipt_timestamp.as_secs(),
+                        age.as_secs()
+                    )),
+                ));
+            }
         }
+        Ok(())
     }
 }
 #[cfg(test)]
@@ -88,6 +140,13 @@ mod tests {
         ReceiptWithState::<Checking>::new(receipt)
     }
 
+    fn timestamp_check(max_future_skew_secs: u64, max_receipt_age_secs: u64) -> TimestampCheck {
+        TimestampCheck::new(
+            Duration::from_secs(max_future_skew_secs),
+            Duration::from_secs(max_receipt_age_secs),
+        )
+    }
+
     #[tokio::test]
     async fn test_timestamp_inside_tolerance() {
         let timestamp = SystemTime::now()
@@ -97,7 +156,7 @@ mod tests {
             + Duration::from_secs(15).as_nanos();
         let timestamp_ns = timestamp as u64;
         let signed_receipt = create_signed_receipt_with_custom_timestamp(timestamp_ns);
-        let timestamp_check = TimestampCheck::new(Duration::from_secs(30));
+        let timestamp_check = timestamp_check(30, 30);
         assert!(timestamp_check.check(&signed_receipt).await.is_ok());
     }
 
@@ -110,7 +169,7 @@ mod tests {
             + Duration::from_secs(33).as_nanos();
         let timestamp_ns = timestamp as u64;
         let signed_receipt = create_signed_receipt_with_custom_timestamp(timestamp_ns);
-        let timestamp_check = TimestampCheck::new(Duration::from_secs(30));
+        let timestamp_check = timestamp_check(30, 30);
         assert!(timestamp_check.check(&signed_receipt).await.is_err());
     }
 
@@ -123,7 +182,46 @@ mod tests {
             - Duration::from_secs(33).as_nanos();
         let timestamp_ns = timestamp as u64;
         let signed_receipt = create_signed_receipt_with_custom_timestamp(timestamp_ns);
-        let timestamp_check = TimestampCheck::new(Duration::from_secs(30));
+        let timestamp_check = timestamp_check(30, 30);
         assert!(timestamp_check.check(&signed_receipt).await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_asymmetric_window() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards");
+        let timestamp_check = timestamp_check(10, 60);
+
+        let ahead = create_signed_receipt_with_custom_timestamp(
+            (now + Duration::from_secs(20)).as_nanos() as u64,
+        );
+        assert!(timestamp_check.check(&ahead).await.is_err());
+        let behind = create_signed_receipt_with_custom_timestamp(
+            (now - Duration::from_secs(20)).as_nanos() as u64,
+        );
+        assert!(timestamp_check.check(&behind).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_skew_recorded_for_sender() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards");
+        let receipt = create_signed_receipt_with_custom_timestamp(
+            (now - Duration::from_secs(5)).as_nanos() as u64,
+        );
+        let timestamp_check = timestamp_check(30, 30);
+        let sender = Address::from([0x42u8; 20]);
+        let histogram = TIMESTAMP_SKEW.with_label_values(&[&sender.to_string()]);
+
+        // Without a resolved sender, nothing is recorded
+        timestamp_check.check(&receipt).await.unwrap();
+        assert_eq!(histogram.get_sample_count(), 0);
+        crate::tap::sent_by(sender, timestamp_check.check(&receipt))
+            .await
+            .unwrap();
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!(histogram.get_sample_sum() < 0.0);
+    }
 }
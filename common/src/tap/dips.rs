@@ -0,0 +1,49 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Indexing fee vouchers (DIPS) and their RAVs.
+//!
+//! Under an indexing agreement, the payer pays the indexer for indexing a deployment with signed
+//! vouchers, instead of receipts for the queries it serves. A voucher carries the id of the
+//! agreement, and is signed for the RecurringCollector contract. The fields of the voucher and of
+//! its RAV are named like in the contract, in camel case.
+//!
+//! The vouchers are sent to indexer-service, see [`crate::indexer_service::http::dips`], and
+//! aggregated into a RAV per agreement by tap-agent, like the receipts.
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address, sol, sol_types::eip712_domain};
+use serde::{Deserialize, Serialize};
+use tap_core::signed_message::EIP712SignedMessage;
+
+sol! {
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct IndexingFeeVoucher {
+        bytes16 agreementId;
+        address payer;
+        address serviceProvider;
+        uint64 timestampNs;
+        uint64 nonce;
+        uint128 value;
+    }
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct IndexingFeeAggregateVoucher {
+        bytes16 agreementId;
+        address payer;
+        address serviceProvider;
+        uint64 timestampNs;
+        uint128 valueAggregate;
+    }
+}
+
+pub type SignedVoucher = EIP712SignedMessage<IndexingFeeVoucher>;
+pub type SignedRav = EIP712SignedMessage<IndexingFeeAggregateVoucher>;
+
+pub fn eip712_domain(chain_id: u64, verifying_contract: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "RecurringCollector",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: verifying_contract,
+    }
+}
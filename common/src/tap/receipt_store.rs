@@ -1,9 +1,25 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use alloy::{dyn_abi::Eip712Domain, hex::ToHexExt};
+//! Storage of the receipts accepted at intake, in batches.
+//!
+//! A receipt delivered again, such as by a gateway retrying a query, isn't stored twice: the
+//! signatures of the recent receipts are kept in memory, keyed by their hash, and the receipts of
+//! an allocation are unique by signer and nonce in the database, for the duplicates older than
+//! that or with a re-encoded signature. Both are counted by `tap_duplicate_receipts_total`.
+
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    hex::ToHexExt,
+    primitives::{keccak256, B256},
+};
 use anyhow::anyhow;
 use bigdecimal::num_bigint::BigInt;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
 use sqlx::{types::BigDecimal, PgPool};
 use tap_core::{
     manager::adapters::ReceiptStore,
@@ -11,9 +27,51 @@ use tap_core::{
 };
 use tokio::{select, sync::mpsc::Receiver, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{debug, error};
+
+use super::{AdapterError, IndexerTapContext, RELAYED_BY};
+use crate::{
+    fees::FeeToken,
+    receipt_profiler::{self, stage, Stages, RECEIPT_INTAKE_STAGES},
+};
+
+/// Signatures remembered, a few minutes of receipts for a busy indexer.
+const RECENT_RECEIPTS: usize = 100_000;
 
-use super::{AdapterError, IndexerTapContext};
+lazy_static! {
+    static ref DUPLICATE_RECEIPTS: IntCounterVec = register_int_counter_vec!(
+        "tap_duplicate_receipts_total",
+        "Receipts delivered again and not stored, by where they were detected (memory or database)",
+        &["detected_by"]
+    )
+    .unwrap();
+}
+
+/// Hashes of the signatures of the receipts queued for storage recently.
+pub struct RecentReceipts(Mutex<LruCache<B256, ()>>);
+
+impl Default for RecentReceipts {
+    fn default() -> Self {
+        Self(Mutex::new(LruCache::new(
+            NonZeroUsize::new(RECENT_RECEIPTS).unwrap(),
+        )))
+    }
+}
+
+impl RecentReceipts {
+    /// Whether the receipt of `signature` wasn't seen recently, remembering it.
+    fn insert(&self, signature: &[u8]) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .put(keccak256(signature), ())
+            .is_none()
+    }
+
+    fn remove(&self, signature: &[u8]) {
+        self.0.lock().unwrap().pop(&keccak256(signature));
+    }
+}
 
 #[derive(Clone)]
 pub struct InnerContext {
@@ -29,37 +87,47 @@ impl InnerContext {
         let mut timestamps = Vec::with_capacity(receipts_len);
         let mut nonces = Vec::with_capacity(receipts_len);
         let mut values = Vec::with_capacity(receipts_len);
+        let mut relayed_by = Vec::with_capacity(receipts_len);
+        let mut sampled = Vec::new();
 
         for receipt in receipts {
+            sampled.extend(receipt.stages);
             signers.push(receipt.signer_address);
             signatures.push(receipt.signature);
             allocation_ids.push(receipt.allocation_id);
             timestamps.push(receipt.timestamp_ns);
             nonces.push(receipt.nonce);
             values.push(receipt.value);
+            relayed_by.push(receipt.relayed_by);
         }
-        sqlx::query!(
+        let result = sqlx::query!(
             r#"INSERT INTO scalar_tap_receipts (
                 signer_address,
                 signature,
                 allocation_id,
                 timestamp_ns,
                 nonce,
-                value
-            ) SELECT * FROM UNNEST(
+                value,
+                relayed_by,
+                fee_token
+            ) SELECT *, $8 FROM UNNEST(
                 $1::CHAR(40)[],
                 $2::BYTEA[],
                 $3::CHAR(40)[],
                 $4::NUMERIC(20)[],
                 $5::NUMERIC(20)[],
-                $6::NUMERIC(40)[]
-            )"#,
+                $6::NUMERIC(40)[],
+                $7::VARCHAR(255)[]
+            )
+            ON CONFLICT DO NOTHING"#,
             &signers,
             &signatures,
             &allocation_ids,
             &timestamps,
             &nonces,
             &values,
+            &relayed_by as &[Option<String>],
+            FeeToken::Grt.symbol(),
         )
         .execute(&self.pgpool)
         .await
@@ -67,7 +135,17 @@ impl InnerContext {
             error!("Failed to store receipt: {}", e);
             anyhow!(e)
         })?;
+        let duplicates = (receipts_len as u64).saturating_sub(result.rows_affected());
+        if duplicates > 0 {
+            debug!(duplicates, "Duplicate receipts already stored.");
+            DUPLICATE_RECEIPTS
+                .with_label_values(&["database"])
+                .inc_by(duplicates);
+        }
 
+        for mut stages in sampled {
+            stages.observe(&RECEIPT_INTAKE_STAGES, stage::DB_WRITE);
+        }
         Ok(())
     }
 }
@@ -104,11 +182,30 @@ impl ReceiptStore for IndexerTapContext {
         &self,
         receipt: ReceiptWithState<Checking>,
     ) -> Result<u64, Self::AdapterError> {
-        let db_receipt = DatabaseReceipt::from_receipt(receipt, &self.domain_separator)?;
-        self.receipt_producer.send(db_receipt).await.map_err(|e| {
+        let mut db_receipt = DatabaseReceipt::from_receipt(receipt, &self.domain_separator)?;
+        if !self.recent_receipts.insert(&db_receipt.signature) {
+            debug!(
+                signer = %db_receipt.signer_address,
+                allocation_id = %db_receipt.allocation_id,
+                nonce = %db_receipt.nonce,
+                "Duplicate receipt, not stored again."
+            );
+            DUPLICATE_RECEIPTS.with_label_values(&["memory"]).inc();
+            // Accepted, the receipt already pays for a query
+            return Ok(0);
+        }
+        // The checks passed, see `request_handler`
+        db_receipt.stages = receipt_profiler::current().map(|mut stages| {
+            stages.observe(&RECEIPT_INTAKE_STAGES, stage::VALIDATION);
+            stages
+        });
+        db_receipt.relayed_by = RELAYED_BY.try_with(Clone::clone).ok();
+        if let Err(e) = self.receipt_producer.send(db_receipt).await {
+            // Not stored, so a retry of the receipt mustn't be taken for a duplicate
+            self.recent_receipts.remove(&e.0.signature);
             error!("Failed to queue receipt for storage: {}", e);
-            anyhow!(e)
-        })?;
+            return Err(anyhow!(e).into());
+        }
 
         // We don't need receipt_ids
         Ok(0)
@@ -122,6 +219,10 @@ pub struct DatabaseReceipt {
     timestamp_ns: BigDecimal,
     nonce: BigDecimal,
     value: BigDecimal,
+    /// Set if the receipt is profiled, see [`receipt_profiler`].
+    stages: Option<Stages>,
+    /// Set if the receipt was relayed by a peer, see [`super::relayed_by`].
+    relayed_by: Option<String>,
 }
 
 impl DatabaseReceipt {
@@ -151,6 +252,99 @@ impl DatabaseReceipt {
             signer_address,
             timestamp_ns,
             value,
+            stages: None,
+            relayed_by: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::mpsc;
+
+    use crate::test_vectors::{create_signed_receipt, TAP_EIP712_DOMAIN};
+
+    use super::*;
+
+    const ALLOCATION_ID: &str = "0xdeadbeefcafebabedeadbeefcafebabedeadbeef";
+
+    async fn stored_nonces(pgpool: &PgPool, count: usize) -> Vec<BigDecimal> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let nonces =
+                    sqlx::query_scalar!("SELECT nonce FROM scalar_tap_receipts ORDER BY nonce")
+                        .fetch_all(pgpool)
+                        .await
+                        .unwrap();
+                if nonces.len() >= count {
+                    break nonces;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_duplicate_receipts(pgpool: PgPool) {
+        let allocation_id = ALLOCATION_ID.parse().unwrap();
+        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        let receipt = create_signed_receipt(allocation_id, 1, 1, 100).await;
+        for _ in 0..2 {
+            context
+                .store_receipt(ReceiptWithState::new(receipt.clone()))
+                .await
+                .unwrap();
+        }
+        context
+            .store_receipt(ReceiptWithState::new(
+                create_signed_receipt(allocation_id, 2, 1, 100).await,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            stored_nonces(&pgpool, 2).await,
+            [BigDecimal::from(1), BigDecimal::from(2)]
+        );
+
+        // Missed by the filter of another instance, such as after a restart
+        let inner = InnerContext {
+            pgpool: pgpool.clone(),
+        };
+        let duplicate = || {
+            DatabaseReceipt::from_receipt(
+                ReceiptWithState::new(receipt.clone()),
+                &TAP_EIP712_DOMAIN,
+            )
+            .unwrap()
+        };
+        inner
+            .store_receipts(vec![duplicate(), duplicate()])
+            .await
+            .unwrap();
+        assert_eq!(stored_nonces(&pgpool, 2).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_receipt_not_queued_is_not_remembered() {
+        let (receipt_producer, receipt_consumer) = mpsc::channel(1);
+        drop(receipt_consumer);
+        let context = IndexerTapContext {
+            cancelation_token: CancellationToken::new(),
+            receipt_producer,
+            domain_separator: Arc::new(TAP_EIP712_DOMAIN.clone()),
+            recent_receipts: RecentReceipts::default(),
+        };
+        let receipt = create_signed_receipt(ALLOCATION_ID.parse().unwrap(), 1, 1, 100).await;
+        // Both fail to be queued, rather than the second one being accepted as a duplicate
+        for _ in 0..2 {
+            assert!(context
+                .store_receipt(ReceiptWithState::new(receipt.clone()))
+                .await
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stable codes for the reasons a receipt is rejected, shared by indexer-service and tap-agent.
+//!
+//! The codes are returned to the gateways, stored with the invalid receipts and used as metric
+//! labels, so they must not be renamed.
+//!
+//! The checks reject the receipts with a [`Rejection`] built by [`RejectionCode::reject`], read
+//! back with [`RejectionCode::of`]. Past `tap_core`, which only keeps the message of a failed
+//! check, the code is recovered from the tagged message with [`RejectionCode::classify`], or
+//! with [`capture`] where the verification runs in a task of its own.
+
+use std::{cell::Cell, error::Error, fmt, future::Future};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionCode {
+    /// The signer of the receipt couldn't be recovered, or isn't the expected one.
+    BadSignature,
+    /// The signer of the receipt isn't authorized by any sender.
+    UnknownSigner,
+    /// The receipt is for an allocation this indexer can't collect.
+    AllocationMismatch,
+    /// The timestamp of the receipt is too far from the current time.
+    TimestampOutOfWindow,
+    /// The value of the receipt is over the configured maximum.
+    ValueCap,
+    /// The value of the receipt is under the minimum of a custom check.
+    ValueFloor,
+    /// The receipt was already received.
+    Duplicate,
+    /// The sender of the receipt is denied.
+    SenderDenied,
+    /// The sender of the receipt is paused by the operator.
+    SenderPaused,
+    /// The sender of the receipt doesn't have enough in escrow.
+    InsufficientBalance,
+    /// The sender of the receipt is over its rate limit, see `tap_sender_rate_limits`.
+    RateLimited,
+    /// The receipt relayed by a peer isn't bound to a query served with the key of its
+    /// allocation.
+    Unattested,
+    /// Any other reason, see the error message.
+    Other,
+}
+
+const TAGGED: [RejectionCode; 12] = [
+    RejectionCode::BadSignature,
+    RejectionCode::UnknownSigner,
+    RejectionCode::AllocationMismatch,
+    RejectionCode::TimestampOutOfWindow,
+    RejectionCode::ValueCap,
+    RejectionCode::ValueFloor,
+    RejectionCode::Duplicate,
+    RejectionCode::SenderDenied,
+    RejectionCode::SenderPaused,
+    RejectionCode::InsufficientBalance,
+    RejectionCode::RateLimited,
+    RejectionCode::Unattested,
+];
+
+/// Error of a check rejecting a receipt, displayed as its message tagged with its code.
+#[derive(Debug)]
+pub struct Rejection {
+    pub code: RejectionCode,
+    message: String,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl Error for Rejection {}
+
+tokio::task_local! {
+    /// Code of the last rejection raised by the current task.
+    static RAISED: Cell<Option<RejectionCode>>;
+}
+
+/// Runs `f`, verifying a receipt, with the code of the last rejection raised meanwhile by
+/// [`RejectionCode::reject`]. The checks in shadow mode don't fail the receipt, so the code is
+/// only meaningful if it's rejected.
+pub async fn capture<F: Future>(f: F) -> (F::Output, Option<RejectionCode>) {
+    RAISED
+        .scope(Cell::new(None), async {
+            let output = f.await;
+            (output, RAISED.with(Cell::get))
+        })
+        .await
+}
+
+impl RejectionCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionCode::BadSignature => "bad_signature",
+            RejectionCode::UnknownSigner => "unknown_signer",
+            RejectionCode::AllocationMismatch => "allocation_mismatch",
+            RejectionCode::TimestampOutOfWindow => "timestamp_out_of_window",
+            RejectionCode::ValueCap => "value_cap",
+            RejectionCode::ValueFloor => "value_floor",
+            RejectionCode::Duplicate => "duplicate",
+            RejectionCode::SenderDenied => "sender_denied",
+            RejectionCode::SenderPaused => "sender_paused",
+            RejectionCode::InsufficientBalance => "insufficient_balance",
+            RejectionCode::RateLimited => "rate_limited",
+            RejectionCode::Unattested => "unattested",
+            RejectionCode::Other => "other",
+        }
+    }
+
+    /// Error tagged with this code, to be returned by a check.
+    pub fn reject(self, message: impl fmt::Display) -> anyhow::Error {
+        // Outside of `capture`, there's nothing to record
+        let _ = RAISED.try_with(|raised| raised.set(Some(self)));
+        anyhow::Error::new(Rejection {
+            code: self,
+            message: message.to_string(),
+        })
+    }
+
+    /// Code of the error of a check, [`RejectionCode::Other`] if it isn't a [`Rejection`].
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<Rejection>()
+            .map_or(RejectionCode::Other, |rejection| rejection.code)
+    }
+
+    /// Code of a rejection from its error message. Messages without a tag are rejections by
+    /// `tap_core` itself, recognized by their wording.
+    pub fn classify(message: &str) -> Self {
+        if let Some(code) = TAGGED
+            .into_iter()
+            .find(|code| message.contains(&format!("[{code}]")))
+        {
+            return code;
+        }
+        let message = message.to_lowercase();
+        if message.contains("unique") || message.contains("duplicate") {
+            RejectionCode::Duplicate
+        } else if message.contains("signature") {
+            RejectionCode::BadSignature
+        } else {
+            RejectionCode::Other
+        }
+    }
+}
+
+impl fmt::Display for RejectionCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capture, RejectionCode};
+
+    #[test]
+    fn test_classify() {
+        for code in super::TAGGED {
+            let error = code.reject("Something went wrong");
+            // tap_core wraps the message of the failed checks
+            let message = format!("Receipt check failed: {error}");
+            assert_eq!(RejectionCode::classify(&message), code);
+        }
+        assert_eq!(
+            RejectionCode::classify("Receipt is not unique"),
+            RejectionCode::Duplicate
+        );
+        assert_eq!(
+            RejectionCode::classify("Something unexpected"),
+            RejectionCode::Other
+        );
+    }
+
+    #[test]
+    fn test_of() {
+        let error = RejectionCode::ValueCap.reject("Too much");
+        assert_eq!(RejectionCode::of(&error), RejectionCode::ValueCap);
+        assert_eq!(error.to_string(), "[value_cap] Too much");
+        assert_eq!(
+            RejectionCode::of(&error.context("While checking")),
+            RejectionCode::ValueCap
+        );
+        // Only tagged in the message
+        assert_eq!(
+            RejectionCode::of(&anyhow::anyhow!("[value_cap] Too much")),
+            RejectionCode::Other
+        );
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let (_, raised) = capture(async {
+            RejectionCode::ValueCap.reject("shadow");
+            tokio::task::yield_now().await;
+            RejectionCode::SenderDenied.reject("enforced")
+        })
+        .await;
+        assert_eq!(raised, Some(RejectionCode::SenderDenied));
+        assert_eq!(capture(async {}).await.1, None);
+    }
+}
@@ -3,6 +3,7 @@
 
 mod config;
 mod grt;
+pub mod secrets;
 
 pub use config::*;
 pub use grt::*;
@@ -0,0 +1,221 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Secrets referenced from the configuration file as `${provider:reference}`, next to the
+//! environment variables referenced as `${NAME}`.
+//!
+//! The references are substituted in the string values of the file once it's parsed, so any of
+//! them can be a secret, and a secret can't change the structure of the file whatever its
+//! content: the Postgres URL or password, the operator mnemonic, the auth tokens. The providers
+//! are:
+//! - `env:NAME`, the environment variable `NAME`, same as `${NAME}`.
+//! - `file:/path`, the content of the file, without its trailing newline. Suits the secrets
+//!   mounted by Kubernetes or Docker.
+//! - `vault:path#key`, the key of a HashiCorp Vault secret, KV version 1 or 2, with `path` as in
+//!   the HTTP API such as `secret/data/indexer`. Reads `VAULT_ADDR` and `VAULT_TOKEN`. Requires
+//!   the `vault` feature.
+//! - `aws-sm:id` or `aws-sm:id#key`, an AWS Secrets Manager secret, or a key of it if it's a JSON
+//!   object. The credentials and region are the ones of the AWS SDK defaults. Requires the
+//!   `aws-secrets-manager` feature.
+//!
+//! A rotated secret is picked up by parsing the configuration again, with
+//! `secrets.refresh_interval_secs` the consumers supporting it are told, see
+//! `indexer_common::secrets`.
+
+use std::{env, fmt, fs};
+
+/// Fetches secrets by reference. Called while parsing the configuration, so it blocks.
+pub trait SecretProvider: Send + Sync {
+    fn fetch(&self, reference: &str) -> Result<String, SecretError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretError {
+    pub provider: String,
+    pub reference: String,
+    pub message: String,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to fetch secret `{}:{}`: {}",
+            self.provider, self.reference, self.message
+        )
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+fn error(provider: &str, reference: &str, message: impl fmt::Display) -> SecretError {
+    SecretError {
+        provider: provider.to_string(),
+        reference: reference.to_string(),
+        message: message.to_string(),
+    }
+}
+
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn fetch(&self, reference: &str) -> Result<String, SecretError> {
+        env::var(reference).map_err(|e| error("env", reference, e))
+    }
+}
+
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn fetch(&self, reference: &str) -> Result<String, SecretError> {
+        let content = fs::read_to_string(reference).map_err(|e| error("file", reference, e))?;
+        Ok(content.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Splits `path#key`.
+#[cfg(any(feature = "vault", feature = "aws-secrets-manager"))]
+fn split_key(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('#') {
+        Some((path, key)) => (path, Some(key)),
+        None => (reference, None),
+    }
+}
+
+#[cfg(feature = "vault")]
+pub struct VaultProvider;
+
+#[cfg(feature = "vault")]
+impl SecretProvider for VaultProvider {
+    fn fetch(&self, reference: &str) -> Result<String, SecretError> {
+        let (path, Some(key)) = split_key(reference) else {
+            return Err(error("vault", reference, "expected `path#key`"));
+        };
+        let address = env::var("VAULT_ADDR").map_err(|e| error("vault", reference, e))?;
+        let token = env::var("VAULT_TOKEN").map_err(|e| error("vault", reference, e))?;
+        let url = format!("{}/v1/{}", address.trim_end_matches('/'), path);
+        // On its own thread, the blocking client panics within an async runtime
+        let response: serde_json::Value = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    reqwest::blocking::Client::new()
+                        .get(&url)
+                        .header("X-Vault-Token", &token)
+                        .send()
+                        .and_then(|response| response.error_for_status())
+                        .and_then(|response| response.json())
+                })
+                .join()
+                .expect("the Vault request shouldn't panic")
+        })
+        .map_err(|e| error("vault", reference, e))?;
+        // KV version 2 nests the secret in `data.data`, version 1 in `data`
+        let data = &response["data"];
+        let data = data.get("data").filter(|d| d.is_object()).unwrap_or(data);
+        data.get(key)
+            .and_then(|value| value.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                error(
+                    "vault",
+                    reference,
+                    format!("no string `{key}` in the secret"),
+                )
+            })
+    }
+}
+
+#[cfg(feature = "aws-secrets-manager")]
+pub struct AwsSecretsManagerProvider;
+
+#[cfg(feature = "aws-secrets-manager")]
+impl SecretProvider for AwsSecretsManagerProvider {
+    fn fetch(&self, reference: &str) -> Result<String, SecretError> {
+        let (id, key) = split_key(reference);
+        // The SDK is async only, it gets a runtime of its own on a thread of its own
+        let secret = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .map_err(|e| e.to_string())?;
+                    runtime.block_on(async {
+                        let config = aws_config::load_from_env().await;
+                        aws_sdk_secretsmanager::Client::new(&config)
+                            .get_secret_value()
+                            .secret_id(id)
+                            .send()
+                            .await
+                            .map_err(|e| e.to_string())
+                    })
+                })
+                .join()
+                .expect("the AWS Secrets Manager request shouldn't panic")
+        })
+        .map_err(|e| error("aws-sm", reference, e))?
+        .secret_string
+        .ok_or_else(|| error("aws-sm", reference, "the secret isn't a string"))?;
+        let Some(key) = key else {
+            return Ok(secret);
+        };
+        let secret: serde_json::Value =
+            serde_json::from_str(&secret).map_err(|e| error("aws-sm", reference, e))?;
+        secret
+            .get(key)
+            .and_then(|value| value.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                error(
+                    "aws-sm",
+                    reference,
+                    format!("no string `{key}` in the secret"),
+                )
+            })
+    }
+}
+
+/// The provider of a `${provider:reference}`.
+pub fn provider(name: &str) -> Result<Box<dyn SecretProvider>, String> {
+    match name {
+        "env" => Ok(Box::new(EnvProvider)),
+        "file" => Ok(Box::new(FileProvider)),
+        #[cfg(feature = "vault")]
+        "vault" => Ok(Box::new(VaultProvider)),
+        #[cfg(not(feature = "vault"))]
+        "vault" => Err("the `vault` secret provider requires the `vault` feature".to_string()),
+        #[cfg(feature = "aws-secrets-manager")]
+        "aws-sm" => Ok(Box::new(AwsSecretsManagerProvider)),
+        #[cfg(not(feature = "aws-secrets-manager"))]
+        "aws-sm" => Err(
+            "the `aws-sm` secret provider requires the `aws-secrets-manager` feature".to_string(),
+        ),
+        _ => Err(format!(
+            "Unknown secret provider `{name}`, expected one of: env, file, vault, aws-sm"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::provider;
+
+    #[test]
+    fn test_file_provider() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "abandon abandon about").unwrap();
+        let secret = provider("file")
+            .unwrap()
+            .fetch(file.path().to_str().unwrap())
+            .unwrap();
+        assert_eq!(secret, "abandon abandon about");
+
+        assert!(provider("file")
+            .unwrap()
+            .fetch("/nonexistent/secret")
+            .is_err());
+        assert!(provider("keychain").is_err());
+    }
+}
@@ -0,0 +1,8 @@
+DROP TRIGGER IF EXISTS receipt_update ON tap_horizon_receipts CASCADE;
+
+DROP FUNCTION IF EXISTS tap_horizon_receipt_notify() CASCADE;
+
+DROP TABLE IF EXISTS tap_horizon_receipts CASCADE;
+DROP TABLE IF EXISTS tap_horizon_receipts_invalid CASCADE;
+DROP TABLE IF EXISTS tap_horizon_ravs CASCADE;
+DROP TABLE IF EXISTS tap_horizon_rav_requests_failed CASCADE;
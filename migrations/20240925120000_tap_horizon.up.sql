@@ -0,0 +1,81 @@
+-- Horizon (TAP v2) receipts and RAVs. Kept apart from the `scalar_tap_*` tables, as both
+-- formats are collected during the transition to Horizon.
+CREATE TABLE IF NOT EXISTS tap_horizon_receipts (
+    id BIGSERIAL PRIMARY KEY, -- id being SERIAL is important for the function of tap-agent
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 receipt
+    signature BYTEA NOT NULL,
+    collection_id CHAR(64) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    data_service CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL
+);
+
+CREATE FUNCTION tap_horizon_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('tap_horizon_receipt_notification', format('{"id": %s, "collection_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s}', NEW.id, NEW.collection_id, NEW.signer_address, NEW.timestamp_ns, NEW.value));
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER receipt_update AFTER INSERT OR UPDATE
+    ON tap_horizon_receipts
+    FOR EACH ROW EXECUTE PROCEDURE tap_horizon_receipt_notify();
+
+CREATE INDEX IF NOT EXISTS tap_horizon_receipts_collection_id_idx ON tap_horizon_receipts (collection_id);
+CREATE INDEX IF NOT EXISTS tap_horizon_receipts_timestamp_ns_idx ON tap_horizon_receipts (timestamp_ns);
+
+-- This table is used to store invalid receipts (receipts that fail at least one of the checks in the tap-agent).
+-- Used for logging and debugging purposes.
+CREATE TABLE IF NOT EXISTS tap_horizon_receipts_invalid (
+    id BIGSERIAL PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 receipt
+    signature BYTEA NOT NULL,
+    collection_id CHAR(64) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    data_service CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    error_log TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS tap_horizon_ravs (
+    -- Values below are the individual fields of the EIP-712 RAV
+    signature BYTEA NOT NULL,
+    collection_id CHAR(64) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    data_service CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+    metadata BYTEA NOT NULL,
+
+    last BOOLEAN DEFAULT FALSE NOT NULL,
+    final BOOLEAN DEFAULT FALSE NOT NULL,
+    PRIMARY KEY (collection_id, payer, service_provider, data_service),
+
+    -- To make indexer-agent's sequelize happy
+    created_at TIMESTAMP WITH TIME ZONE,
+    updated_at TIMESTAMP WITH TIME ZONE
+);
+
+-- This table is used to store failed RAV requests.
+-- Used for logging and debugging purposes.
+CREATE TABLE IF NOT EXISTS tap_horizon_rav_requests_failed (
+    id BIGSERIAL PRIMARY KEY,
+    collection_id CHAR(64) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    expected_rav JSON NOT NULL,
+    rav_response JSON NOT NULL,
+    reason TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS tap_sender_leases CASCADE;
+DROP TABLE IF EXISTS tap_agent_instances CASCADE;
@@ -0,0 +1,11 @@
+-- Coordination of the tap-agents sharing the senders, see `tap.sharding`.
+CREATE TABLE IF NOT EXISTS tap_agent_instances (
+    instance_id VARCHAR(255) PRIMARY KEY,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- The leases of an instance are released along with it.
+CREATE TABLE IF NOT EXISTS tap_sender_leases (
+    sender_address CHAR(40) PRIMARY KEY,
+    instance_id VARCHAR(255) NOT NULL REFERENCES tap_agent_instances (instance_id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE scalar_tap_receipts_invalid DROP COLUMN IF EXISTS error_code;
+ALTER TABLE tap_horizon_receipts_invalid DROP COLUMN IF EXISTS error_code;
@@ -0,0 +1,3 @@
+-- Stable rejection code of the invalid receipts, next to the free-form `error_log`.
+ALTER TABLE scalar_tap_receipts_invalid ADD COLUMN IF NOT EXISTS error_code VARCHAR(32);
+ALTER TABLE tap_horizon_receipts_invalid ADD COLUMN IF NOT EXISTS error_code VARCHAR(32);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS scalar_tap_receipts_quarantined;
+DROP TABLE IF EXISTS tap_horizon_receipts_quarantined;
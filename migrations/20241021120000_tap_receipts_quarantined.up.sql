@@ -0,0 +1,31 @@
+-- Receipts received from denied senders, set aside by tap-agent when
+-- `tap.denied_sender_receipts` is "quarantine". They are kept for the operator, and never
+-- aggregated. `id` is the id the receipt had in its receipts table.
+CREATE TABLE IF NOT EXISTS scalar_tap_receipts_quarantined (
+    id BIGINT PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 receipt
+    signature BYTEA NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    quarantined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS tap_horizon_receipts_quarantined (
+    id BIGINT PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 receipt
+    signature BYTEA NOT NULL,
+    collection_id CHAR(64) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    data_service CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    quarantined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,17 @@
+-- Receipts covered by a final RAV, set aside by tap-agent when `tap.receipt_compaction.mode`
+-- is "archive". Each row holds a batch of receipts of an allocation and sender as a JSON array,
+-- which Postgres stores compressed.
+CREATE TABLE IF NOT EXISTS scalar_tap_receipts_archive (
+    id BIGSERIAL PRIMARY KEY,
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    -- Timestamp of the final RAV covering the receipts
+    rav_timestamp_ns NUMERIC(20) NOT NULL,
+    receipt_count BIGINT NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    receipts JSONB NOT NULL,
+    archived_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_receipts_archive_allocation_id_idx
+    ON scalar_tap_receipts_archive (allocation_id, sender_address);
@@ -0,0 +1,2 @@
+ALTER TABLE scalar_tap_denylist DROP COLUMN IF EXISTS context;
+ALTER TABLE scalar_tap_denylist DROP COLUMN IF EXISTS reason;
@@ -0,0 +1,4 @@
+-- Why tap-agent denied the sender, see `DenyReason`, and the values that led to it. NULL for the
+-- senders denied before, or by hand.
+ALTER TABLE scalar_tap_denylist ADD COLUMN IF NOT EXISTS reason VARCHAR(32);
+ALTER TABLE scalar_tap_denylist ADD COLUMN IF NOT EXISTS context JSONB;
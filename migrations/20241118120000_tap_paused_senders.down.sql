@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS pause_update ON tap_paused_senders CASCADE;
+
+DROP FUNCTION IF EXISTS tap_pause_notify() CASCADE;
+
+DROP TABLE IF EXISTS tap_paused_senders CASCADE;
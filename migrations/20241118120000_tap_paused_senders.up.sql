@@ -0,0 +1,28 @@
+-- Senders paused by the operator: indexer-service rejects their receipts, and tap-agent doesn't
+-- request their RAVs, until they're resumed. Unlike the denylist, it's never written
+-- automatically.
+CREATE TABLE IF NOT EXISTS tap_paused_senders (
+    sender_address CHAR(40) PRIMARY KEY,
+    reason TEXT,
+    paused_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE FUNCTION tap_pause_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('tap_pause_notification', format('{"tg_op": "DELETE", "sender_address": "%s"}', OLD.sender_address));
+        RETURN OLD;
+    ELSIF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('tap_pause_notification', format('{"tg_op": "INSERT", "sender_address": "%s"}', NEW.sender_address));
+        RETURN NEW;
+    ELSE -- UPDATE, the set of paused senders doesn't change
+        RETURN NEW;
+    END IF;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER pause_update AFTER INSERT OR UPDATE OR DELETE
+    ON tap_paused_senders
+    FOR EACH ROW EXECUTE PROCEDURE tap_pause_notify();
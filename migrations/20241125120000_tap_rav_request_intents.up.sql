@@ -0,0 +1,17 @@
+-- RAV requests sent to the sender's TAP aggregator, written by tap-agent before sending them and
+-- deleted once the response is handled. The rows left after a crash are reconciled against
+-- `scalar_tap_ravs` when the allocation is picked up again.
+CREATE TABLE IF NOT EXISTS scalar_tap_rav_request_intents (
+    id BIGSERIAL PRIMARY KEY,
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    -- Range of the timestamps of the receipts in the request
+    min_timestamp_ns NUMERIC(20) NOT NULL,
+    max_timestamp_ns NUMERIC(20) NOT NULL,
+    receipt_count BIGINT NOT NULL,
+    expected_value_aggregate NUMERIC(39) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_rav_request_intents_allocation_id_idx
+    ON scalar_tap_rav_request_intents (allocation_id, sender_address);
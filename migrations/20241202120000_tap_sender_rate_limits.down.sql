@@ -0,0 +1 @@
+DROP TABLE IF EXISTS tap_sender_rate_limits CASCADE;
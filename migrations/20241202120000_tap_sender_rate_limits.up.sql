@@ -0,0 +1,11 @@
+-- Token bucket of the receipt fees of each sender, written by tap-agent from the sender's escrow
+-- headroom and fee velocity, and applied by indexer-service to the receipts it takes in. A
+-- sender without a row isn't limited.
+CREATE TABLE IF NOT EXISTS tap_sender_rate_limits (
+    sender_address CHAR(40) PRIMARY KEY,
+    -- Largest burst of fees, in GRT wei
+    capacity NUMERIC(39) NOT NULL,
+    -- Fees allowed per second once the burst is spent, in GRT wei
+    refill_per_second NUMERIC(39) NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
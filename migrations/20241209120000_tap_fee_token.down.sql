@@ -0,0 +1,9 @@
+ALTER TABLE scalar_tap_receipts DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE scalar_tap_receipts_invalid DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE scalar_tap_receipts_quarantined DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE scalar_tap_receipts_archive DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE scalar_tap_ravs DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE tap_horizon_receipts DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE tap_horizon_receipts_invalid DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE tap_horizon_receipts_quarantined DROP COLUMN IF EXISTS fee_token;
+ALTER TABLE tap_horizon_ravs DROP COLUMN IF EXISTS fee_token;
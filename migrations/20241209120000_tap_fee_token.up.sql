@@ -0,0 +1,11 @@
+-- Token of the fee values next to them, see `indexer_common::fees`. All the fees are in GRT so
+-- far, the existing rows included.
+ALTER TABLE scalar_tap_receipts ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE scalar_tap_receipts_invalid ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE scalar_tap_receipts_quarantined ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE scalar_tap_receipts_archive ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE scalar_tap_ravs ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE tap_horizon_receipts ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE tap_horizon_receipts_invalid ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE tap_horizon_receipts_quarantined ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
+ALTER TABLE tap_horizon_ravs ADD COLUMN IF NOT EXISTS fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT';
@@ -0,0 +1,2 @@
+ALTER TABLE scalar_tap_receipts DROP COLUMN IF EXISTS relayed_by;
+ALTER TABLE tap_horizon_receipts DROP COLUMN IF EXISTS relayed_by;
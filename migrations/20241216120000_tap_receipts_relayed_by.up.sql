@@ -0,0 +1,4 @@
+-- Name of the peer that relayed the receipt, see `service.peer_relay`. NULL for the receipts of
+-- the queries served by the indexer-service storing them.
+ALTER TABLE scalar_tap_receipts ADD COLUMN IF NOT EXISTS relayed_by VARCHAR(255);
+ALTER TABLE tap_horizon_receipts ADD COLUMN IF NOT EXISTS relayed_by VARCHAR(255);
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS dips_agreements CASCADE;
+DROP TABLE IF EXISTS dips_fee_vouchers CASCADE;
+DROP TABLE IF EXISTS dips_fee_vouchers_invalid CASCADE;
+DROP TABLE IF EXISTS dips_ravs CASCADE;
+DROP TABLE IF EXISTS dips_rav_requests_failed CASCADE;
@@ -0,0 +1,76 @@
+-- Indexing agreements (DIPS) accepted by the indexer, under which its payer pays indexing fees for
+-- indexing a deployment. Written when an agreement is accepted or cancelled.
+CREATE TABLE IF NOT EXISTS dips_agreements (
+    agreement_id CHAR(32) PRIMARY KEY,
+    payer CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    deployment_id VARCHAR(64) NOT NULL,
+    accepted_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- The vouchers for the indexing done until then are still collected
+    cancelled_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Indexing fee vouchers, aggregated by tap-agent into a RAV per agreement. Kept apart from the
+-- query fee receipts, as they're aggregated with a different trigger policy.
+CREATE TABLE IF NOT EXISTS dips_fee_vouchers (
+    id BIGSERIAL PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 voucher
+    signature BYTEA NOT NULL,
+    agreement_id CHAR(32) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS dips_fee_vouchers_agreement_id_idx ON dips_fee_vouchers (agreement_id);
+
+-- This table is used to store invalid vouchers (vouchers that fail at least one of the checks in the tap-agent).
+-- Used for logging and debugging purposes.
+CREATE TABLE IF NOT EXISTS dips_fee_vouchers_invalid (
+    id BIGSERIAL PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 voucher
+    signature BYTEA NOT NULL,
+    agreement_id CHAR(32) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    error_log TEXT NOT NULL DEFAULT '',
+    error_code VARCHAR(32)
+);
+
+CREATE TABLE IF NOT EXISTS dips_ravs (
+    -- Values below are the individual fields of the EIP-712 RAV
+    signature BYTEA NOT NULL,
+    agreement_id CHAR(32) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    service_provider CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+
+    last BOOLEAN DEFAULT FALSE NOT NULL,
+    final BOOLEAN DEFAULT FALSE NOT NULL,
+    PRIMARY KEY (agreement_id, payer, service_provider),
+
+    -- To make indexer-agent's sequelize happy
+    created_at TIMESTAMP WITH TIME ZONE,
+    updated_at TIMESTAMP WITH TIME ZONE
+);
+
+-- This table is used to store failed RAV requests.
+-- Used for logging and debugging purposes.
+CREATE TABLE IF NOT EXISTS dips_rav_requests_failed (
+    id BIGSERIAL PRIMARY KEY,
+    agreement_id CHAR(32) NOT NULL,
+    payer CHAR(40) NOT NULL,
+    expected_rav JSON NOT NULL,
+    rav_response JSON NOT NULL,
+    reason TEXT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- no-transaction
+-- Covering indexes of the unaggregated fees of an allocation, summed up by tap-agent when it
+-- starts and after each RAV: the receipts of the allocation after the timestamp of its last RAV.
+-- The fees are summed from the index alone, rather than from the rows of the receipts, which
+-- matters once the receipts tables hold millions of rows.
+--
+-- Built concurrently, so that the receipts keep being written meanwhile, which can only be done
+-- outside of a transaction, one index per migration. A build that fails leaves an invalid index
+-- behind, to be dropped before running the migration again.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS scalar_tap_receipts_fees_idx
+    ON scalar_tap_receipts (allocation_id, timestamp_ns)
+    INCLUDE (id, signer_address, value);
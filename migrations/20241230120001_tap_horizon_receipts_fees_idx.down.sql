@@ -0,0 +1 @@
+DROP INDEX IF EXISTS tap_horizon_receipts_fees_idx;
@@ -0,0 +1,5 @@
+-- no-transaction
+-- See `20241230120000_tap_receipts_fees_idx`.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS tap_horizon_receipts_fees_idx
+    ON tap_horizon_receipts (collection_id, timestamp_ns)
+    INCLUDE (id, signer_address, value);
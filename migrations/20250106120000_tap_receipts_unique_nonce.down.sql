@@ -0,0 +1 @@
+-- The duplicates moved to the invalid receipts tables are left there.
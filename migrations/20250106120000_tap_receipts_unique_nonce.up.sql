@@ -0,0 +1,78 @@
+-- A receipt delivered twice, such as by a gateway retrying a query, is only stored once: the
+-- receipts of an allocation are unique by signer and nonce. indexer-service skips the ones
+-- already stored with `ON CONFLICT DO NOTHING`, rather than failing the whole batch.
+--
+-- The duplicates stored before are moved to the invalid receipts tables, keeping the first one
+-- stored, so that their fees can still be accounted for. The unique indexes are then built
+-- concurrently by the next migrations, without locking the receipts tables against writes. A
+-- duplicate stored in between fails the build and leaves the index invalid: drop it, run this
+-- migration's statements again and build it again.
+WITH duplicates AS (
+    DELETE FROM scalar_tap_receipts duplicate
+        USING scalar_tap_receipts original
+        WHERE duplicate.allocation_id = original.allocation_id
+            AND duplicate.signer_address = original.signer_address
+            AND duplicate.nonce = original.nonce
+            AND duplicate.id > original.id
+        RETURNING duplicate.*
+)
+INSERT INTO scalar_tap_receipts_invalid (
+    signer_address,
+    signature,
+    allocation_id,
+    timestamp_ns,
+    nonce,
+    value,
+    fee_token,
+    error_log,
+    error_code
+)
+SELECT
+    signer_address,
+    signature,
+    allocation_id,
+    timestamp_ns,
+    nonce,
+    value,
+    fee_token,
+    'Duplicate of a receipt with the same signer and nonce',
+    'duplicate'
+FROM duplicates;
+
+WITH duplicates AS (
+    DELETE FROM tap_horizon_receipts duplicate
+        USING tap_horizon_receipts original
+        WHERE duplicate.collection_id = original.collection_id
+            AND duplicate.signer_address = original.signer_address
+            AND duplicate.nonce = original.nonce
+            AND duplicate.id > original.id
+        RETURNING duplicate.*
+)
+INSERT INTO tap_horizon_receipts_invalid (
+    signer_address,
+    signature,
+    collection_id,
+    payer,
+    data_service,
+    service_provider,
+    timestamp_ns,
+    nonce,
+    value,
+    fee_token,
+    error_log,
+    error_code
+)
+SELECT
+    signer_address,
+    signature,
+    collection_id,
+    payer,
+    data_service,
+    service_provider,
+    timestamp_ns,
+    nonce,
+    value,
+    fee_token,
+    'Duplicate of a receipt with the same signer and nonce',
+    'duplicate'
+FROM duplicates;
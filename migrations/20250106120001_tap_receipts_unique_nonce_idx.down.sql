@@ -0,0 +1 @@
+DROP INDEX IF EXISTS scalar_tap_receipts_nonce_idx;
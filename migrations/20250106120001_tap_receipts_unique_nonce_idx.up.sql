@@ -0,0 +1,5 @@
+-- no-transaction
+-- See `20250106120000_tap_receipts_unique_nonce`. Alone in its migration, as an index can only
+-- be built concurrently outside of a transaction, by a single statement.
+CREATE UNIQUE INDEX CONCURRENTLY IF NOT EXISTS scalar_tap_receipts_nonce_idx
+    ON scalar_tap_receipts (allocation_id, signer_address, nonce);
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS tap_horizon_receipts_nonce_idx;
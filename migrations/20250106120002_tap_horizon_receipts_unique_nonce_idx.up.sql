@@ -0,0 +1,4 @@
+-- no-transaction
+-- See `20250106120000_tap_receipts_unique_nonce`.
+CREATE UNIQUE INDEX CONCURRENTLY IF NOT EXISTS tap_horizon_receipts_nonce_idx
+    ON tap_horizon_receipts (collection_id, signer_address, nonce);
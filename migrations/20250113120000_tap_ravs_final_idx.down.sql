@@ -0,0 +1 @@
+DROP INDEX IF EXISTS scalar_tap_ravs_final_idx;
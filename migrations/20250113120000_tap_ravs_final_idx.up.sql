@@ -0,0 +1,8 @@
+-- no-transaction
+-- Partial index of the final RAVs, the only ones whose receipts tap-agent compacts, so that it
+-- finds them without reading the RAVs of the allocations still open.
+--
+-- Built concurrently, see `20241230120000_tap_receipts_fees_idx`.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS scalar_tap_ravs_final_idx
+    ON scalar_tap_ravs (allocation_id, sender_address)
+    WHERE final;
@@ -4,6 +4,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
+use indexer_config::Profile;
 
 #[derive(Parser)]
 pub struct Cli {
@@ -11,4 +12,9 @@ pub struct Cli {
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/service for examples.
     #[arg(long, value_name = "FILE", verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+    /// Network profile, providing the chain id, TAP verifier and gateway aggregators.
+    /// One of `mainnet`, `testnet` or `local-dev`.
+    /// The configuration file can override its values, except for the chain id.
+    #[arg(long, value_name = "PROFILE", verbatim_doc_comment)]
+    pub profile: Option<Profile>,
 }
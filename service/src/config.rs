@@ -1,13 +1,25 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroUsize,
+};
 
-use indexer_common::indexer_service::http::{
-    DatabaseConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig, IndexerServiceConfig,
-    ServerConfig, SubgraphConfig, TapConfig,
+use indexer_common::{
+    indexer_service::http::{
+        receipt_rate_limit::{ReceiptRateLimit, ReceiptRateLimitConfig},
+        DatabaseConfig, DipsConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig,
+        IndexerServiceConfig, PeerRelayConfig, ResponseCacheConfig, ServerConfig, SubgraphConfig,
+        TapConfig,
+    },
+    prelude::{LocalFailoverConfig, QueryCacheConfig, QueryPolicy, SubscriptionConfig},
+    tap::{CustomCheckConfig, CustomRule},
+};
+use indexer_config::{
+    Config as MainConfig, CustomReceiptCheckConfig, CustomReceiptRule, SubgraphCacheConfig,
+    SubgraphLocalFailoverConfig, SubgraphQueryPolicyConfig, SubgraphSubscriptionConfig,
 };
-use indexer_config::Config as MainConfig;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -18,14 +30,27 @@ impl From<MainConfig> for Config {
         Self(IndexerServiceConfig {
             indexer: IndexerConfig {
                 indexer_address: value.indexer.indexer_address,
-                operator_mnemonic: value.indexer.operator_mnemonic.to_string(),
+                operator_mnemonic: value
+                    .indexer
+                    .operator_mnemonic
+                    .map(|mnemonic| mnemonic.to_string()),
+                web3signer_url: value
+                    .service
+                    .attestation_signer
+                    .map(|signer| signer.web3signer_url.into()),
             },
             server: ServerConfig {
-                host_and_port: value.service.host_and_port,
-                metrics_host_and_port: SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::new(0, 0, 0, 0),
-                    value.metrics.port,
-                )),
+                listener: value
+                    .service
+                    .listener
+                    .unwrap_or_else(|| value.service.host_and_port.into()),
+                metrics_listener: value.metrics.listener.unwrap_or_else(|| {
+                    SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::new(0, 0, 0, 0),
+                        value.metrics.port,
+                    ))
+                    .into()
+                }),
                 url_prefix: value.service.url_prefix,
                 free_query_auth_token: value.service.free_query_auth_token,
             },
@@ -53,6 +78,10 @@ impl From<MainConfig> for Config {
                     .network
                     .recently_closed_allocation_buffer_secs
                     .as_secs(),
+                query_policy: query_policy(value.subgraphs.network.config.query_policy),
+                cache: query_cache(value.subgraphs.network.config.cache),
+                local_failover: local_failover(value.subgraphs.network.config.local_failover),
+                subscription: subscription(value.subgraphs.network.config.subscription),
             },
             escrow_subgraph: SubgraphConfig {
                 serve_subgraph: value.service.serve_escrow_subgraph,
@@ -67,6 +96,10 @@ impl From<MainConfig> for Config {
                     .syncing_interval_secs
                     .as_secs(),
                 recently_closed_allocation_buffer_seconds: 0,
+                query_policy: query_policy(value.subgraphs.escrow.config.query_policy),
+                cache: query_cache(value.subgraphs.escrow.config.cache),
+                local_failover: local_failover(value.subgraphs.escrow.config.local_failover),
+                subscription: subscription(value.subgraphs.escrow.config.subscription),
             },
             graph_network: GraphNetworkConfig {
                 chain_id: value.blockchain.chain_id.clone() as u64,
@@ -74,9 +107,121 @@ impl From<MainConfig> for Config {
             tap: TapConfig {
                 chain_id: value.blockchain.chain_id as u64,
                 receipts_verifier_address: value.blockchain.receipts_verifier_address,
-                timestamp_error_tolerance: value.tap.rav_request.timestamp_buffer_secs.as_secs(),
+                max_future_skew: value
+                    .service
+                    .tap
+                    .max_future_skew_secs
+                    .unwrap_or(value.tap.rav_request.timestamp_buffer_secs),
+                max_receipt_age: value
+                    .service
+                    .tap
+                    .max_receipt_age_secs
+                    .unwrap_or(value.tap.rav_request.timestamp_buffer_secs),
                 receipt_max_value: value.service.tap.max_receipt_value_grt.get_value(),
+                shadow_checks: value.service.tap.shadow_checks,
+                disabled_checks: value.service.tap.disabled_checks,
+                custom_checks: value
+                    .service
+                    .tap
+                    .custom_checks
+                    .into_iter()
+                    .map(custom_check)
+                    .collect(),
+                receipt_profiling_ratio: value.metrics.receipt_profiling_ratio,
+                rate_limits_interval: value.tap.rate_limits.interval_secs,
+                rate_limits_replicas: value.tap.rate_limits.service_replicas,
             },
+            peer_relay: value.service.peer_relay.map(|relay| PeerRelayConfig {
+                listener: relay.host_and_port,
+                cert_path: relay.cert_path,
+                key_path: relay.key_path,
+                client_ca_cert_path: relay.client_ca_cert_path,
+                peers: relay.peers,
+                max_batch_size: relay.max_batch_size,
+                rate_limit: receipt_rate_limit(relay.rate_limit),
+            }),
+            response_cache: value
+                .service
+                .response_cache
+                .map(|cache| ResponseCacheConfig {
+                    max_entries: NonZeroUsize::new(cache.max_entries)
+                        .expect("`service.response_cache.max_entries` is validated to be non-zero"),
+                    ttl: cache.ttl_secs,
+                }),
+            dips: value.dips.enabled.then(|| DipsConfig {
+                verifier_address: value
+                    .blockchain
+                    .dips_verifier_address
+                    .expect("`blockchain.dips_verifier_address` is validated to be set"),
+            }),
+            receipt_rate_limit: value.service.receipt_rate_limit.map(|rate_limit| {
+                ReceiptRateLimitConfig {
+                    limit: receipt_rate_limit(rate_limit.limit),
+                    per_signer: rate_limit.per_signer,
+                    senders: rate_limit
+                        .senders
+                        .into_iter()
+                        .map(|(sender, limit)| (sender, receipt_rate_limit(limit)))
+                        .collect(),
+                }
+            }),
         })
     }
 }
+
+/// The retries, timeout and circuit breaker of the queries to a subgraph.
+fn query_policy(config: SubgraphQueryPolicyConfig) -> QueryPolicy {
+    QueryPolicy {
+        max_retries: config.max_retries,
+        initial_backoff: config.initial_backoff_secs,
+        max_backoff: config.max_backoff_secs,
+        timeout: config.timeout_secs,
+        circuit_breaker_failures: config.circuit_breaker_failures,
+        circuit_breaker_cooldown: config.circuit_breaker_cooldown_secs,
+    }
+}
+
+fn query_cache(config: Option<SubgraphCacheConfig>) -> Option<QueryCacheConfig> {
+    config.map(|cache| QueryCacheConfig {
+        max_entries: NonZeroUsize::new(cache.max_entries)
+            .expect("`cache.max_entries` is validated to be non-zero"),
+        ttl: cache.ttl_secs,
+        max_stale: cache.max_stale_secs,
+    })
+}
+
+fn local_failover(config: Option<SubgraphLocalFailoverConfig>) -> Option<LocalFailoverConfig> {
+    config.map(|failover| LocalFailoverConfig {
+        max_lag_blocks: failover.max_lag_blocks,
+        check_interval: failover.check_interval_secs,
+    })
+}
+
+fn subscription(config: Option<SubgraphSubscriptionConfig>) -> Option<SubscriptionConfig> {
+    config.map(|subscription| SubscriptionConfig {
+        url: subscription.url.to_string(),
+        max_reconnect_backoff: subscription.max_reconnect_backoff_secs,
+    })
+}
+
+fn receipt_rate_limit(config: indexer_config::ReceiptRateLimit) -> ReceiptRateLimit {
+    ReceiptRateLimit {
+        receipts_per_second: config.receipts_per_second,
+        burst: config.burst,
+    }
+}
+
+fn custom_check(config: CustomReceiptCheckConfig) -> CustomCheckConfig {
+    CustomCheckConfig {
+        name: config.name,
+        senders: config.senders,
+        rule: match config.rule {
+            CustomReceiptRule::MaxValue { max_value_grt } => {
+                CustomRule::MaxValue(max_value_grt.get_value())
+            }
+            CustomReceiptRule::MinValue { min_value_grt } => {
+                CustomRule::MinValue(min_value_grt.get_value())
+            }
+        },
+    }
+}
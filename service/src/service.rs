@@ -7,8 +7,10 @@ use std::time::Duration;
 use super::{config::Config, error::SubgraphServiceError, routes};
 use anyhow::anyhow;
 use axum::{async_trait, routing::post, Json, Router};
-use indexer_common::indexer_service::http::{
-    AttestationOutput, IndexerServiceImpl, IndexerServiceResponse,
+use graphql::graphql_parser::query as q;
+use indexer_common::{
+    indexer_service::http::{AttestationOutput, IndexerServiceImpl, IndexerServiceResponse},
+    secrets,
 };
 use indexer_config::Config as MainConfig;
 use reqwest::Url;
@@ -24,7 +26,7 @@ use indexer_common::indexer_service::http::{
 };
 use tracing::error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct SubgraphServiceResponse {
     inner: String,
     attestable: bool,
@@ -119,27 +121,136 @@ impl IndexerServiceImpl for SubgraphService {
 
         Ok((request, SubgraphServiceResponse::new(body, attestable)))
     }
+
+    fn is_deterministic(&self, request: &Self::Request) -> bool {
+        pinned_to_block_hash(request)
+    }
+}
+
+/// Whether every root field of the query of `request` is pinned to a block by its hash, with a
+/// `block: { hash: ... }` argument, such that its response can't change. A block number may be
+/// reorged, and the latest block moves.
+fn pinned_to_block_hash(request: &Value) -> bool {
+    let Some(query) = request.get("query").and_then(Value::as_str) else {
+        return false;
+    };
+    let Ok(document) = q::parse_query::<String>(query) else {
+        return false;
+    };
+    let variables = request.get("variables");
+    let mut root_fields = 0;
+    for definition in &document.definitions {
+        let selection_set = match definition {
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => &query.selection_set,
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            // Only used through the spreads, which aren't cached
+            q::Definition::Fragment(_) => continue,
+            q::Definition::Operation(_) => return false,
+        };
+        for selection in &selection_set.items {
+            let q::Selection::Field(field) = selection else {
+                return false;
+            };
+            let pinned = field
+                .arguments
+                .iter()
+                .any(|(name, value)| *name == "block" && block_hash(value, variables).is_some());
+            if !pinned {
+                return false;
+            }
+            root_fields += 1;
+        }
+    }
+    root_fields > 0
+}
+
+/// The hash of a `block` argument, given inline or as a variable.
+fn block_hash<'a>(
+    value: &'a q::Value<'_, String>,
+    variables: Option<&'a Value>,
+) -> Option<&'a str> {
+    let variable = |name: &str| variables?.get(name);
+    match value {
+        q::Value::Object(block) => match block.get("hash")? {
+            q::Value::String(hash) => Some(hash.as_str()),
+            q::Value::Variable(name) => variable(name.as_str())?.as_str(),
+            _ => None,
+        },
+        q::Value::Variable(name) => variable(name.as_str())?.get("hash")?.as_str(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::pinned_to_block_hash;
+
+    #[test]
+    fn test_pinned_to_block_hash() {
+        let pinned = |query: &str, variables| {
+            pinned_to_block_hash(&json!({ "query": query, "variables": variables }))
+        };
+        let hash = "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3";
+
+        assert!(pinned(
+            &format!(r#"{{ tokens(block: {{ hash: "{hash}" }}) {{ id }} }}"#),
+            json!(null)
+        ));
+        assert!(pinned(
+            "query($h: String) { a: tokens(block: { hash: $h }) { id } b: pairs(block: { hash: $h }) { id } }",
+            json!({ "h": hash })
+        ));
+        assert!(pinned(
+            "query($block: Block_height) { tokens(block: $block) { id } }",
+            json!({ "block": { "hash": hash } })
+        ));
+        // Not pinned, pinned to a number, or with a root field not pinned
+        assert!(!pinned("{ tokens { id } }", json!(null)));
+        assert!(!pinned(
+            "{ tokens(block: { number: 100 }) { id } }",
+            json!(null)
+        ));
+        assert!(!pinned(
+            &format!(r#"{{ tokens(block: {{ hash: "{hash}" }}) {{ id }} pairs {{ id }} }}"#),
+            json!(null)
+        ));
+        assert!(!pinned(
+            "query($block: Block_height) { tokens(block: $block) { id } }",
+            json!({ "block": { "number": 100 } })
+        ));
+        assert!(!pinned("not a query", json!(null)));
+    }
 }
 
 /// Run the subgraph indexer service
 pub async fn run() -> anyhow::Result<()> {
     // Parse command line and environment arguments
     let cli = Cli::parse();
+    let config_path = cli.config.clone();
 
     // Load the json-rpc service configuration, which is a combination of the
     // general configuration options for any indexer service and specific
     // options added for JSON-RPC
-    let config = MainConfig::parse(indexer_config::ConfigPrefix::Service, cli.config.as_ref())
-        .map_err(|e| {
-            error!(
-                "Invalid configuration file `{}`: {}, if a value is missing you can also use \
+    let config = MainConfig::parse(
+        indexer_config::ConfigPrefix::Service,
+        cli.config.as_ref(),
+        cli.profile,
+    )
+    .map_err(|e| {
+        error!(
+            "Invalid configuration file `{}`: {}, if a value is missing you can also use \
                 --config to fill the rest of the values",
-                cli.config.unwrap_or_default().display(),
-                e
-            );
-            anyhow!(e)
-        })?;
+            cli.config.unwrap_or_default().display(),
+            e
+        );
+        anyhow!(e)
+    })?;
 
+    let secrets_refresh_interval = config.secrets.refresh_interval_secs;
     let config: Config = config.into();
 
     // Parse basic configurations
@@ -174,6 +285,49 @@ pub async fn run() -> anyhow::Result<()> {
             .clone(),
     });
 
+    // The Postgres URL and the operator mnemonic, as referenced by the configuration. The
+    // mnemonic is empty without one, only the attestation signer backend signs then.
+    let rotated_secrets = secrets_refresh_interval.map(|interval| {
+        let profile = cli.profile;
+        secrets::watch(
+            (
+                config.0.database.postgres_url.clone(),
+                config
+                    .0
+                    .indexer
+                    .operator_mnemonic
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            interval,
+            move || {
+                MainConfig::parse(
+                    indexer_config::ConfigPrefix::Service,
+                    config_path.as_ref(),
+                    profile,
+                )
+                .map(|config| {
+                    (
+                        config.database.get_formated_postgres_url().to_string(),
+                        config
+                            .indexer
+                            .operator_mnemonic
+                            .map(|mnemonic| mnemonic.to_string())
+                            .unwrap_or_default(),
+                    )
+                })
+            },
+        )
+    });
+    let postgres_urls = rotated_secrets.clone().map(|secrets| {
+        let urls = secrets::map(secrets, |(url, _)| url.clone());
+        secrets::rotate_postgres_url(vec![state.database.clone()], urls.clone());
+        urls
+    });
+    let operator_mnemonics = rotated_secrets
+        .filter(|_| config.0.indexer.operator_mnemonic.is_some())
+        .map(|secrets| secrets::map(secrets, |(_, mnemonic)| mnemonic.clone()));
+
     IndexerService::run(IndexerServiceOptions {
         release,
         config: config.0.clone(),
@@ -183,6 +337,9 @@ pub async fn run() -> anyhow::Result<()> {
             .route("/cost", post(routes::cost::cost))
             .route("/status", post(routes::status))
             .with_state(state),
+        postgres_urls,
+        operator_mnemonics,
+        attestation_signer_backend: None,
     })
     .await
 }
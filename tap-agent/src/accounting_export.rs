@@ -0,0 +1,607 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Export of the RAVs and receipts of a time range, for the `export-accounting` subcommand.
+//!
+//! Back-offices account for the revenue of the indexer from the RAVs, by sender and allocation,
+//! and from the receipts not aggregated yet. Each row is written as soon as it's read, so that
+//! long ranges don't have to fit in memory. The values are given both in wei, and in GRT.
+//!
+//! The RAVs are `pending` until their allocation is closed, then `last`, then `final` once
+//! redeemed. The receipts are summarized per day, signer and allocation, `pending`, `invalid`,
+//! `quarantined` while their sender was denied, or `archived` by the receipt compaction once
+//! covered by a final RAV. Their sender is looked up in the escrow accounts, and left empty if
+//! they can't be fetched.
+
+use std::{io::Write, str::FromStr, time::Duration};
+
+use alloy::primitives::Address;
+use anyhow::Result;
+use bigdecimal::num_bigint::{BigInt, ToBigInt};
+use clap::ValueEnum;
+use futures_util::TryStreamExt;
+use indexer_common::{escrow_accounts::EscrowAccounts, prelude::escrow_accounts_watcher};
+use serde::Serialize;
+use sqlx::{
+    postgres::PgRow,
+    types::{
+        chrono::{DateTime, Utc},
+        BigDecimal,
+    },
+    PgPool, Row,
+};
+use tracing::warn;
+
+use crate::{
+    agent::{escrow_subgraph_client, sender_allocation::horizon::to_u64},
+    CONFIG,
+};
+
+/// Decimals of GRT.
+const GRT_DECIMALS: usize = 18;
+const ESCROW_ACCOUNTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportKind {
+    /// One row per RAV.
+    Ravs,
+    /// One row per day, signer, allocation and status of the receipts.
+    Receipts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub kind: ExportKind,
+    pub format: ExportFormat,
+    /// Start of the range, inclusive.
+    pub from: DateTime<Utc>,
+    /// End of the range, exclusive.
+    pub to: DateTime<Utc>,
+    /// Also export the Horizon (TAP v2) RAVs and receipts.
+    pub horizon: bool,
+}
+
+/// A row of the export, written as a CSV record or a JSON object.
+trait Record: Serialize {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+#[derive(Debug, Serialize)]
+struct RavRecord {
+    tap_version: &'static str,
+    sender: Address,
+    allocation_id: Address,
+    timestamp_ns: u64,
+    value_aggregate_wei: String,
+    value_aggregate_grt: String,
+    fee_token: String,
+    status: &'static str,
+}
+
+impl Record for RavRecord {
+    const HEADER: &'static [&'static str] = &[
+        "tap_version",
+        "sender",
+        "allocation_id",
+        "timestamp_ns",
+        "value_aggregate_wei",
+        "value_aggregate_grt",
+        "fee_token",
+        "status",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.tap_version.to_string(),
+            self.sender.to_string(),
+            self.allocation_id.to_string(),
+            self.timestamp_ns.to_string(),
+            self.value_aggregate_wei.clone(),
+            self.value_aggregate_grt.clone(),
+            self.fee_token.clone(),
+            self.status.to_string(),
+        ]
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReceiptsRecord {
+    tap_version: &'static str,
+    /// `YYYY-MM-DD`, in UTC.
+    day: String,
+    sender: Option<Address>,
+    signer: Address,
+    allocation_id: Address,
+    count: i64,
+    value_wei: String,
+    value_grt: String,
+    fee_token: String,
+    status: String,
+}
+
+impl Record for ReceiptsRecord {
+    const HEADER: &'static [&'static str] = &[
+        "tap_version",
+        "day",
+        "sender",
+        "signer",
+        "allocation_id",
+        "count",
+        "value_wei",
+        "value_grt",
+        "fee_token",
+        "status",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.tap_version.to_string(),
+            self.day.clone(),
+            self.sender
+                .map(|sender| sender.to_string())
+                .unwrap_or_default(),
+            self.signer.to_string(),
+            self.allocation_id.to_string(),
+            self.count.to_string(),
+            self.value_wei.clone(),
+            self.value_grt.clone(),
+            self.fee_token.clone(),
+            self.status.clone(),
+        ]
+    }
+}
+
+/// The tables of a TAP version. The allocation of a Horizon collection is the last 20 bytes of
+/// its id, see [`crate::tap::horizon::allocation_id`]. The export queries are built from them at
+/// runtime, so they aren't checked at compile time.
+struct Tables {
+    tap_version: &'static str,
+    ravs: &'static str,
+    /// The status of the receipts of each table, or subquery.
+    receipts: &'static [(&'static str, &'static str)],
+    sender: &'static str,
+    allocation_id: &'static str,
+}
+
+const LEGACY_TABLES: Tables = Tables {
+    tap_version: "v1",
+    ravs: "scalar_tap_ravs",
+    receipts: &[
+        ("pending", "scalar_tap_receipts"),
+        ("invalid", "scalar_tap_receipts_invalid"),
+        ("quarantined", "scalar_tap_receipts_quarantined"),
+        // The batches hold the archived rows, their token is the one of the batch
+        (
+            "archived",
+            "(
+                SELECT
+                    receipt.signer_address, receipt.allocation_id, receipt.timestamp_ns,
+                    receipt.value, batch.fee_token
+                FROM scalar_tap_receipts_archive batch,
+                    jsonb_populate_recordset(NULL::scalar_tap_receipts, batch.receipts) receipt
+            ) archived",
+        ),
+    ],
+    sender: "sender_address",
+    allocation_id: "allocation_id",
+};
+
+const HORIZON_TABLES: Tables = Tables {
+    tap_version: "v2",
+    ravs: "tap_horizon_ravs",
+    receipts: &[
+        ("pending", "tap_horizon_receipts"),
+        ("invalid", "tap_horizon_receipts_invalid"),
+        ("quarantined", "tap_horizon_receipts_quarantined"),
+    ],
+    sender: "payer",
+    allocation_id: "RIGHT(collection_id, 40)",
+};
+
+/// Writes the rows of `options` to `out`. Returns the number of rows written.
+pub async fn export(
+    pgpool: &PgPool,
+    options: &ExportOptions,
+    escrow_accounts: &EscrowAccounts,
+    out: &mut impl Write,
+) -> Result<u64> {
+    let mut tables = vec![LEGACY_TABLES];
+    if options.horizon {
+        tables.push(HORIZON_TABLES);
+    }
+    let range = [options.from, options.to]
+        .map(|at| BigDecimal::from(at.timestamp_nanos_opt().unwrap_or(i64::MAX).max(0)));
+
+    let mut writer = RecordWriter {
+        format: options.format,
+        out,
+        rows: 0,
+    };
+    match options.kind {
+        ExportKind::Ravs => writer.header::<RavRecord>()?,
+        ExportKind::Receipts => writer.header::<ReceiptsRecord>()?,
+    }
+    for tables in &tables {
+        match options.kind {
+            ExportKind::Ravs => {
+                let query = format!(
+                    r#"
+                SELECT
+                    {sender} AS sender, {allocation_id} AS allocation_id, timestamp_ns,
+                    value_aggregate, fee_token, last, final
+                FROM {ravs}
+                WHERE timestamp_ns >= $1 AND timestamp_ns < $2
+                ORDER BY timestamp_ns
+            "#,
+                    sender = tables.sender,
+                    allocation_id = tables.allocation_id,
+                    ravs = tables.ravs,
+                );
+                let mut rows = sqlx::query(&query)
+                    .bind(&range[0])
+                    .bind(&range[1])
+                    .fetch(pgpool);
+                while let Some(row) = rows.try_next().await? {
+                    writer.write(&rav_record(tables.tap_version, &row)?)?;
+                }
+            }
+            ExportKind::Receipts => {
+                let select = |table: &str, status: &str| {
+                    format!(
+                        r#"
+                SELECT
+                    '{status}' AS status,
+                    TO_CHAR(TO_TIMESTAMP((timestamp_ns / 1000000000)::DOUBLE PRECISION)
+                        AT TIME ZONE 'UTC', 'YYYY-MM-DD') AS day,
+                    signer_address, {allocation_id} AS allocation_id, fee_token,
+                    COUNT(*) AS count, SUM(value) AS value
+                FROM {table}
+                WHERE timestamp_ns >= $1 AND timestamp_ns < $2
+                GROUP BY day, signer_address, {allocation_id}, fee_token
+            "#,
+                        allocation_id = tables.allocation_id,
+                    )
+                };
+                let query = format!(
+                    "{} ORDER BY day, status",
+                    tables
+                        .receipts
+                        .iter()
+                        .map(|(status, table)| select(table, status))
+                        .collect::<Vec<_>>()
+                        .join(" UNION ALL "),
+                );
+                let mut rows = sqlx::query(&query)
+                    .bind(&range[0])
+                    .bind(&range[1])
+                    .fetch(pgpool);
+                while let Some(row) = rows.try_next().await? {
+                    writer.write(&receipts_record(tables.tap_version, &row, escrow_accounts)?)?;
+                }
+            }
+        }
+    }
+    writer.out.flush()?;
+    Ok(writer.rows)
+}
+
+/// The escrow accounts of the indexer, empty if they can't be fetched in time.
+pub async fn escrow_accounts() -> EscrowAccounts {
+    let escrow_subgraph = escrow_subgraph_client(&CONFIG, reqwest::Client::new());
+    let accounts = tokio::time::timeout(
+        ESCROW_ACCOUNTS_TIMEOUT,
+        escrow_accounts_watcher(
+            escrow_subgraph,
+            CONFIG.ethereum.indexer_address,
+            Duration::from_millis(CONFIG.escrow_subgraph.escrow_syncing_interval_ms),
+            false,
+            None,
+            None,
+        ),
+    )
+    .await;
+    match accounts {
+        Ok(accounts) => accounts.borrow().clone(),
+        Err(_) => {
+            warn!(
+                "Couldn't fetch the escrow accounts, the senders of the receipts are left empty."
+            );
+            EscrowAccounts::default()
+        }
+    }
+}
+
+struct RecordWriter<'a, W: Write> {
+    format: ExportFormat,
+    out: &'a mut W,
+    rows: u64,
+}
+
+impl<W: Write> RecordWriter<'_, W> {
+    fn header<R: Record>(&mut self) -> Result<()> {
+        if self.format == ExportFormat::Csv {
+            writeln!(self.out, "{}", R::HEADER.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write<R: Record>(&mut self, record: &R) -> Result<()> {
+        match self.format {
+            ExportFormat::Csv => {
+                let fields: Vec<String> = record.fields().iter().map(|f| csv_field(f)).collect();
+                writeln!(self.out, "{}", fields.join(","))?
+            }
+            ExportFormat::Jsonl => {
+                serde_json::to_writer(&mut *self.out, record)?;
+                writeln!(self.out)?;
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+/// `field` quoted if it holds a comma, a quote or a new line, as in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn rav_record(tap_version: &'static str, row: &PgRow) -> Result<RavRecord> {
+    let sender: String = row.try_get("sender")?;
+    let allocation_id: String = row.try_get("allocation_id")?;
+    let timestamp_ns: BigDecimal = row.try_get("timestamp_ns")?;
+    let value_aggregate: BigDecimal = row.try_get("value_aggregate")?;
+    let last: bool = row.try_get("last")?;
+    let is_final: bool = row.try_get("final")?;
+    Ok(RavRecord {
+        tap_version,
+        sender: Address::from_str(&sender)?,
+        allocation_id: Address::from_str(&allocation_id)?,
+        timestamp_ns: to_u64(&timestamp_ns)?,
+        value_aggregate_wei: wei(&value_aggregate)?.to_string(),
+        value_aggregate_grt: grt(&value_aggregate)?,
+        fee_token: row.try_get("fee_token")?,
+        status: match (last, is_final) {
+            (_, true) => "final",
+            (true, false) => "last",
+            (false, false) => "pending",
+        },
+    })
+}
+
+fn receipts_record(
+    tap_version: &'static str,
+    row: &PgRow,
+    escrow_accounts: &EscrowAccounts,
+) -> Result<ReceiptsRecord> {
+    let signer: String = row.try_get("signer_address")?;
+    let signer = Address::from_str(&signer)?;
+    let allocation_id: String = row.try_get("allocation_id")?;
+    let value: BigDecimal = row.try_get("value")?;
+    Ok(ReceiptsRecord {
+        tap_version,
+        day: row.try_get("day")?,
+        sender: escrow_accounts.get_sender_for_signer(&signer).ok(),
+        signer,
+        allocation_id: Address::from_str(&allocation_id)?,
+        count: row.try_get("count")?,
+        value_wei: wei(&value)?.to_string(),
+        value_grt: grt(&value)?,
+        fee_token: row.try_get("fee_token")?,
+        status: row.try_get("status")?,
+    })
+}
+
+fn wei(value: &BigDecimal) -> Result<BigInt> {
+    value
+        .to_bigint()
+        .ok_or_else(|| anyhow::anyhow!("{value} is not an integer"))
+}
+
+/// `value` in GRT, exactly, without trailing zeros.
+fn grt(value: &BigDecimal) -> Result<String> {
+    let wei = wei(value)?.to_string();
+    let (sign, digits) = match wei.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", wei.as_str()),
+    };
+    let digits = format!("{digits:0>width$}", width = GRT_DECIMALS + 1);
+    let (int, fraction) = digits.split_at(digits.len() - GRT_DECIMALS);
+    let fraction = fraction.trim_end_matches('0');
+    Ok(match fraction {
+        "" => format!("{sign}{int}"),
+        fraction => format!("{sign}{int}.{fraction}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::{hex::ToHexExt, primitives::U256};
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgPool,
+    };
+
+    use super::{csv_field, export, grt, ExportFormat, ExportKind, ExportOptions};
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    async fn export_lines(pgpool: &PgPool, kind: ExportKind, format: ExportFormat) -> Vec<String> {
+        let mut out = Vec::new();
+        let options = ExportOptions {
+            kind,
+            format,
+            from: DateTime::<Utc>::from_timestamp(0, 0).unwrap(),
+            to: DateTime::<Utc>::from_timestamp(86400 * 2, 0).unwrap(),
+            horizon: false,
+        };
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let rows = export(pgpool, &options, &escrow_accounts, &mut out)
+            .await
+            .unwrap();
+        let lines: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            lines.len() as u64,
+            rows + u64::from(format == ExportFormat::Csv)
+        );
+        lines
+    }
+
+    #[test]
+    fn test_grt() {
+        let grt = |wei: &str| grt(&wei.parse().unwrap()).unwrap();
+        assert_eq!(grt("0"), "0");
+        assert_eq!(grt("1"), "0.000000000000000001");
+        assert_eq!(grt("1500000000000000000"), "1.5");
+        assert_eq!(grt("20000000000000000000"), "20");
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_export(pgpool: PgPool) {
+        let day = 86_400_000_000_000;
+        for (nonce, timestamp_ns) in [(1, 10), (2, 20), (3, day + 10)] {
+            let receipt = create_received_receipt(
+                &ALLOCATION_ID_0,
+                &SIGNER.0,
+                nonce,
+                timestamp_ns,
+                500_000_000_000_000_000,
+            );
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Out of the range
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 4, 3 * day, 1);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        store_rav(
+            &pgpool,
+            create_rav(
+                *ALLOCATION_ID_1,
+                SIGNER.0.clone(),
+                4,
+                2_000_000_000_000_000_000,
+            ),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+
+        let ravs = export_lines(&pgpool, ExportKind::Ravs, ExportFormat::Csv).await;
+        assert_eq!(
+            ravs,
+            vec![
+                "tap_version,sender,allocation_id,timestamp_ns,value_aggregate_wei,\
+                value_aggregate_grt,fee_token,status"
+                    .to_string(),
+                format!(
+                    "v1,{},{},4,2000000000000000000,2,GRT,pending",
+                    SENDER.1, *ALLOCATION_ID_1
+                ),
+            ]
+        );
+
+        let receipts = export_lines(&pgpool, ExportKind::Receipts, ExportFormat::Csv).await;
+        assert_eq!(receipts.len(), 3);
+        assert_eq!(
+            receipts[1],
+            format!(
+                "v1,1970-01-01,{},{},{},2,1000000000000000000,1,GRT,pending",
+                SENDER.1, SIGNER.1, *ALLOCATION_ID_0
+            )
+        );
+        assert!(receipts[2].starts_with("v1,1970-01-02,"));
+
+        let receipts = export_lines(&pgpool, ExportKind::Receipts, ExportFormat::Jsonl).await;
+        let first: serde_json::Value = serde_json::from_str(&receipts[0]).unwrap();
+        assert_eq!(first["value_grt"], "1");
+        assert_eq!(first["count"], 2);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_export_set_aside_receipts(pgpool: PgPool) {
+        for nonce in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, nonce, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // As the receipt compaction and the quarantine of the denied senders do
+        sqlx::query!(
+            r#"
+                WITH moved AS (DELETE FROM scalar_tap_receipts WHERE nonce <= 2 RETURNING *)
+                INSERT INTO scalar_tap_receipts_archive (
+                    sender_address, allocation_id, rav_timestamp_ns, receipt_count, value, receipts
+                )
+                SELECT $1, $2, 2, COUNT(*), SUM(value), jsonb_agg(moved) FROM moved
+            "#,
+            SENDER.1.encode_hex(),
+            ALLOCATION_ID_0.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"
+                WITH moved AS (DELETE FROM scalar_tap_receipts RETURNING *)
+                INSERT INTO scalar_tap_receipts_quarantined (
+                    id, signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                )
+                SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                FROM moved
+            "#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let receipts = export_lines(&pgpool, ExportKind::Receipts, ExportFormat::Csv).await;
+        assert_eq!(
+            receipts[1..],
+            [
+                format!(
+                    "v1,1970-01-01,{},{},{},2,20,0.00000000000000002,GRT,archived",
+                    SENDER.1, SIGNER.1, *ALLOCATION_ID_0
+                ),
+                format!(
+                    "v1,1970-01-01,{},{},{},1,10,0.00000000000000001,GRT,quarantined",
+                    SENDER.1, SIGNER.1, *ALLOCATION_ID_0
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("GRT"), "GRT");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\"\n"), "\"say \"\"hi\"\"\n\"");
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Operator endpoints to isolate a single sender without restarting the whole agent.
+//!
+//! - `POST /admin/senders/:sender/stop` denies the sender, then stops its `SenderAccount` and
+//!   `SenderAllocation`s. Its allocations are not marked as last, and its receipts stay in the
+//!   database.
+//! - `POST /admin/senders/:sender/start` starts them again. The pending fees are read back from
+//!   the database, and the sender is allowed again once the deny condition isn't reached.
+//! - `GET /admin/senders/:sender/rav-queue` returns the allocations of the sender a RAV can be
+//!   requested for, the next one first, see [`crate::agent::rav_queue`].
+//! - `POST /admin/senders/:sender/allocations/:allocation/resume` starts again the
+//!   `SenderAllocation` of an allocation quarantined after panicking too often, see
+//!   [`crate::agent::quarantine`].
+//! - `POST /admin/senders/:sender/pause?reason=...` pauses the sender, its receipts are rejected
+//!   and no RAV is requested for it until `POST /admin/senders/:sender/resume`, across restarts,
+//!   see [`crate::agent::sender_pause`].
+//! - `GET /admin/senders/:sender/trust-score` returns the trust score of the sender and its
+//!   components, see [`crate::agent::trust_score`].
+//! - `GET /admin/senders/:sender/escrow` returns the escrow balance of the sender, the part of it
+//!   reserved by the RAV requests, and the fees tracked for the sender, see
+//!   [`crate::tap::escrow_adapter`].
+//! - `GET /admin/receipt-compaction/report` returns the latest receipt compaction dry-run report,
+//!   and `POST /admin/receipt-compaction/report/:id/approve` sets aside the receipts it lists,
+//!   see [`crate::agent::receipt_compaction`].
+//!
+//! Served next to the metrics, which should stay private, or on their own with
+//! `tap.admin_listener`, for example a unix socket only reachable from the host.
+
+use std::time::Duration;
+
+use alloy::primitives::Address;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use ractor::{call_t, ActorRef};
+use serde::{Deserialize, Serialize};
+
+use crate::agent::{
+    quarantine::ResumeError,
+    rav_queue::RavQueueEntry,
+    receipt_compaction::{self, ApproveError, CompactionReport},
+    sender_account::SenderAccountMessage,
+    sender_accounts_manager::{SenderAccountControlError, SenderAccountsManagerMessage},
+    sender_pause::PauseError,
+    trust_score::SenderTrust,
+};
+use crate::money::ApiUnit;
+use crate::tap::escrow_adapter::SenderEscrow;
+
+/// Stopping waits for the sender to be denied, starting for its pending allocations to be read
+/// from the database.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Serialize)]
+struct ControlResponse {
+    sender: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn control(
+    manager: &ActorRef<SenderAccountsManagerMessage>,
+    sender: Address,
+    message: fn(
+        Address,
+        ractor::RpcReplyPort<Result<(), SenderAccountControlError>>,
+    ) -> SenderAccountsManagerMessage,
+) -> impl IntoResponse {
+    let (status, error) =
+        match call_t!(manager, message, CONTROL_TIMEOUT.as_millis() as u64, sender) {
+            Ok(Ok(())) => (StatusCode::OK, None),
+            Ok(Err(e)) => {
+                let status = match e {
+                    SenderAccountControlError::UnknownSender(_) => StatusCode::NOT_FOUND,
+                    SenderAccountControlError::AlreadyStopped(_)
+                    | SenderAccountControlError::NotStopped(_) => StatusCode::CONFLICT,
+                    SenderAccountControlError::StartFailed(..) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, Some(e.to_string()))
+            }
+            Err(e) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some(format!("SenderAccountsManager did not respond: {e}")),
+            ),
+        };
+    (status, Json(ControlResponse { sender, error }))
+}
+
+async fn handler_stop(
+    State(manager): State<ActorRef<SenderAccountsManagerMessage>>,
+    Path(sender): Path<Address>,
+) -> impl IntoResponse {
+    control(
+        &manager,
+        sender,
+        SenderAccountsManagerMessage::StopSenderAccount,
+    )
+    .await
+}
+
+async fn handler_start(
+    State(manager): State<ActorRef<SenderAccountsManagerMessage>>,
+    Path(sender): Path<Address>,
+) -> impl IntoResponse {
+    control(
+        &manager,
+        sender,
+        SenderAccountsManagerMessage::StartSenderAccount,
+    )
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct RavQueueResponse {
+    sender: Address,
+    fee_unit: ApiUnit,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<Vec<RavQueueEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_rav_queue(Path(sender): Path<Address>) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(RavQueueResponse {
+                sender,
+                fee_unit: ApiUnit,
+                queue: None,
+                error: Some("SenderAccount is not running".to_string()),
+            }),
+        );
+    };
+    match call_t!(
+        sender_account,
+        SenderAccountMessage::GetRavQueue,
+        QUERY_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(queue) => (
+            StatusCode::OK,
+            Json(RavQueueResponse {
+                sender,
+                fee_unit: ApiUnit,
+                queue: Some(queue),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(RavQueueResponse {
+                sender,
+                fee_unit: ApiUnit,
+                queue: None,
+                error: Some(format!("SenderAccount did not respond: {e}")),
+            }),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TrustResponse {
+    sender: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trust: Option<SenderTrust>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_trust_score(Path(sender): Path<Address>) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(TrustResponse {
+                sender,
+                trust: None,
+                error: Some("SenderAccount is not running".to_string()),
+            }),
+        );
+    };
+    match call_t!(
+        sender_account,
+        SenderAccountMessage::GetTrust,
+        QUERY_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(trust) => (
+            StatusCode::OK,
+            Json(TrustResponse {
+                sender,
+                trust: Some(trust),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(TrustResponse {
+                sender,
+                trust: None,
+                error: Some(format!("SenderAccount did not respond: {e}")),
+            }),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EscrowResponse {
+    sender: Address,
+    fee_unit: ApiUnit,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow: Option<SenderEscrow>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_escrow(Path(sender): Path<Address>) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(EscrowResponse {
+                sender,
+                fee_unit: ApiUnit,
+                escrow: None,
+                error: Some("SenderAccount is not running".to_string()),
+            }),
+        );
+    };
+    match call_t!(
+        sender_account,
+        SenderAccountMessage::GetEscrow,
+        QUERY_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(escrow) => (
+            StatusCode::OK,
+            Json(EscrowResponse {
+                sender,
+                fee_unit: ApiUnit,
+                escrow: Some(escrow),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(EscrowResponse {
+                sender,
+                fee_unit: ApiUnit,
+                escrow: None,
+                error: Some(format!("SenderAccount did not respond: {e}")),
+            }),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ResumeResponse {
+    sender: Address,
+    allocation_id: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_resume_allocation(
+    Path((sender, allocation_id)): Path<(Address, Address)>,
+) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ResumeResponse {
+                sender,
+                allocation_id,
+                error: Some("SenderAccount is not running".to_string()),
+            }),
+        );
+    };
+    let (status, error) = match call_t!(
+        sender_account,
+        SenderAccountMessage::ResumeAllocation,
+        CONTROL_TIMEOUT.as_millis() as u64,
+        allocation_id
+    ) {
+        Ok(Ok(())) => (StatusCode::OK, None),
+        Ok(Err(e)) => {
+            let status = match e {
+                ResumeError::NotQuarantined(_) => StatusCode::CONFLICT,
+                ResumeError::StartFailed(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Some(e.to_string()))
+        }
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some(format!("SenderAccount did not respond: {e}")),
+        ),
+    };
+    (
+        status,
+        Json(ResumeResponse {
+            sender,
+            allocation_id,
+            error,
+        }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseParams {
+    reason: Option<String>,
+}
+
+fn not_running(sender: Address) -> (StatusCode, Json<ControlResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ControlResponse {
+            sender,
+            error: Some("SenderAccount is not running".to_string()),
+        }),
+    )
+}
+
+fn pause_response<E: std::fmt::Display>(
+    sender: Address,
+    result: Result<Result<(), PauseError>, E>,
+) -> (StatusCode, Json<ControlResponse>) {
+    let (status, error) = match result {
+        Ok(Ok(())) => (StatusCode::OK, None),
+        Ok(Err(e)) => {
+            let status = match e {
+                PauseError::AlreadyPaused(_) | PauseError::NotPaused(_) => StatusCode::CONFLICT,
+                PauseError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Some(e.to_string()))
+        }
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some(format!("SenderAccount did not respond: {e}")),
+        ),
+    };
+    (status, Json(ControlResponse { sender, error }))
+}
+
+async fn handler_pause(
+    Path(sender): Path<Address>,
+    Query(PauseParams { reason }): Query<PauseParams>,
+) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return not_running(sender);
+    };
+    let result = call_t!(
+        sender_account,
+        SenderAccountMessage::PauseSender,
+        CONTROL_TIMEOUT.as_millis() as u64,
+        reason
+    );
+    pause_response(sender, result)
+}
+
+async fn handler_resume(Path(sender): Path<Address>) -> impl IntoResponse {
+    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+    else {
+        return not_running(sender);
+    };
+    let result = call_t!(
+        sender_account,
+        SenderAccountMessage::ResumeSender,
+        CONTROL_TIMEOUT.as_millis() as u64
+    );
+    pause_response(sender, result)
+}
+
+#[derive(Debug, Serialize)]
+struct CompactionReportResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<CompactionReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_compaction_report() -> impl IntoResponse {
+    match receipt_compaction::latest_report() {
+        Some(report) => (
+            StatusCode::OK,
+            Json(CompactionReportResponse {
+                report: Some(report),
+                error: None,
+            }),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(CompactionReportResponse {
+                report: None,
+                error: Some(ApproveError::NoReport.to_string()),
+            }),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ApproveResponse {
+    report: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn handler_approve_compaction(Path(report): Path<u64>) -> impl IntoResponse {
+    let (status, error) = match receipt_compaction::approve(report) {
+        // The receipts are set aside in the background, see the report
+        Ok(()) => (StatusCode::ACCEPTED, None),
+        Err(e) => {
+            let status = match e {
+                ApproveError::NoReport => StatusCode::NOT_FOUND,
+                ApproveError::Replaced(..) | ApproveError::AlreadyApproved(_) => {
+                    StatusCode::CONFLICT
+                }
+            };
+            (status, Some(e.to_string()))
+        }
+    };
+    (status, Json(ApproveResponse { report, error }))
+}
+
+pub fn router(manager: ActorRef<SenderAccountsManagerMessage>) -> Router {
+    Router::new()
+        .route("/admin/senders/:sender/stop", post(handler_stop))
+        .route("/admin/senders/:sender/start", post(handler_start))
+        .route("/admin/senders/:sender/rav-queue", get(handler_rav_queue))
+        .route(
+            "/admin/senders/:sender/trust-score",
+            get(handler_trust_score),
+        )
+        .route("/admin/senders/:sender/pause", post(handler_pause))
+        .route("/admin/senders/:sender/resume", post(handler_resume))
+        .route("/admin/senders/:sender/escrow", get(handler_escrow))
+        .route(
+            "/admin/senders/:sender/allocations/:allocation/resume",
+            post(handler_resume_allocation),
+        )
+        .route(
+            "/admin/receipt-compaction/report",
+            get(handler_compaction_report),
+        )
+        .route(
+            "/admin/receipt-compaction/report/:report/approve",
+            post(handler_approve_compaction),
+        )
+        .with_state(manager)
+}
@@ -3,28 +3,127 @@
 
 use std::time::Duration;
 
-use indexer_common::prelude::{
-    escrow_accounts, indexer_allocations, DeploymentDetails, SubgraphClient,
+use anyhow::{anyhow, Context};
+use indexer_common::{
+    prelude::{
+        allocations_eventual, escrow_accounts_eventual, escrow_accounts_watcher,
+        indexer_allocations_watcher, DeploymentDetails, SubgraphClient,
+    },
+    secrets,
 };
 use ractor::concurrency::JoinHandle;
 use ractor::{Actor, ActorRef};
 
+use crate::agent::allocation_closure::closing_allocations;
+use crate::agent::config_reload::Thresholds;
+use crate::agent::denylist_outbox::DenylistOutbox;
 use crate::agent::sender_accounts_manager::{
     SenderAccountsManagerArgs, SenderAccountsManagerMessage,
 };
 use crate::config::{
     Config, EscrowSubgraph, Ethereum, IndexerInfrastructure, NetworkSubgraph, Tap,
 };
-use crate::{database, CONFIG, EIP_712_DOMAIN};
+use crate::health::HealthState;
+use crate::signed_status::{self, StatusSigner};
+use crate::startup::{self, Phase};
+use crate::status::StatusState;
+use crate::{
+    database::{self, Component},
+    metrics, money, privileges, CONFIG, EIP_712_DOMAIN, EIP_712_DOMAIN_DIPS, EIP_712_DOMAIN_V2,
+};
 use sender_accounts_manager::SenderAccountsManager;
 
+pub mod aggregator_client;
+pub mod allocation_closure;
+pub mod capacity_planning;
+pub mod config_reload;
+#[cfg(feature = "debug-rpc")]
+pub mod debug;
+pub mod deny_condition;
+pub mod denylist_outbox;
+pub mod deployment_fees;
+pub mod dips;
+pub mod escrow_top_up;
+pub mod lifecycle_hooks;
+pub mod quarantine;
+pub mod rate_limits;
+pub mod rav_anomalies;
+pub mod rav_intents;
+pub mod rav_queue;
+pub mod receipt_compaction;
+pub mod receipt_profiler;
+pub mod receipt_sampling;
+pub mod redeemed_ravs;
 pub mod sender_account;
 pub mod sender_accounts_manager;
 pub mod sender_allocation;
 pub mod sender_fee_tracker;
+pub mod sender_leases;
+pub mod sender_pause;
+pub mod startup_scans;
+pub mod trust_score;
 pub mod unaggregated_receipts;
+pub mod watchdog;
+
+/// Creates the escrow subgraph client. It is leaked, as it's used for the whole lifetime of the
+/// agent.
+pub fn escrow_subgraph_client(
+    config: &Config,
+    http_client: reqwest::Client,
+) -> &'static SubgraphClient {
+    let Config {
+        indexer_infrastructure:
+            IndexerInfrastructure {
+                graph_node_query_endpoint,
+                graph_node_status_endpoint,
+                ..
+            },
+        escrow_subgraph:
+            EscrowSubgraph {
+                escrow_subgraph_deployment,
+                escrow_subgraph_endpoint,
+                escrow_subgraph_auth_token,
+                escrow_subgraph_query_policy,
+                escrow_subgraph_cache,
+                escrow_subgraph_local_failover,
+                escrow_subgraph_subscription,
+                ..
+            },
+        ..
+    } = config;
+    Box::leak(Box::new(
+        SubgraphClient::new(
+            http_client,
+            escrow_subgraph_deployment
+                .map(|deployment| {
+                    DeploymentDetails::for_graph_node(
+                        graph_node_status_endpoint,
+                        graph_node_query_endpoint,
+                        deployment,
+                    )
+                })
+                .transpose()
+                .expect("Failed to parse graph node query endpoint and escrow subgraph deployment"),
+            DeploymentDetails::for_query_url_with_token(
+                escrow_subgraph_endpoint,
+                escrow_subgraph_auth_token.clone(),
+            )
+            .expect("Failed to parse escrow subgraph endpoint"),
+        )
+        .with_policy("escrow", escrow_subgraph_query_policy.clone())
+        .with_cache(escrow_subgraph_cache.clone())
+        .with_local_failover(escrow_subgraph_local_failover.clone())
+        .with_subscription(escrow_subgraph_subscription.clone()),
+    ))
+}
 
-pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>) {
+/// Starts the agent, through the phases of [`startup`].
+pub async fn start_agent() -> anyhow::Result<(
+    ActorRef<SenderAccountsManagerMessage>,
+    JoinHandle<()>,
+    HealthState,
+    StatusState,
+)> {
     let Config {
         ethereum: Ethereum { indexer_address },
         indexer_infrastructure:
@@ -39,92 +138,276 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                 network_subgraph_deployment,
                 network_subgraph_endpoint,
                 network_subgraph_auth_token,
+                network_subgraph_query_policy,
+                network_subgraph_cache,
+                network_subgraph_local_failover,
+                network_subgraph_subscription,
                 allocation_syncing_interval_ms,
                 recently_closed_allocation_buffer_seconds,
+                allocation_deployments,
             },
         escrow_subgraph:
             EscrowSubgraph {
-                escrow_subgraph_deployment,
-                escrow_subgraph_endpoint,
-                escrow_subgraph_auth_token,
                 escrow_syncing_interval_ms,
+                escrow_rpc_fallback,
+                ..
             },
         tap:
             Tap {
                 // TODO: replace with a proper implementation once the gateway registry contract is ready
                 sender_aggregator_endpoints,
+                closing_allocation_buffer_epochs,
+                denylist_outbox_path,
+                receipt_sampling,
+                receipt_compaction,
+                capacity_planning,
+                horizon_enabled,
+                watchdog: watchdog_config,
+                startup: startup_config,
                 ..
             },
         ..
     } = &*CONFIG;
-    let pgpool = database::connect(postgres).await;
+    let (pgpool, allocation_pgpool) =
+        startup::run(Phase::Database, startup_config.database_timeout, async {
+            let pgpool = database::connect(postgres, Component::SenderAccount).await?;
+            let allocation_pgpool =
+                database::connect(postgres, Component::SenderAllocation).await?;
+            startup::check_database(&pgpool).await?;
+            database::connect_read_replica(postgres).await?;
+            Ok((pgpool, allocation_pgpool))
+        })
+        .await?;
+    if let Some(interval) = postgres.secrets_refresh_interval {
+        let urls = secrets::watch(postgres.postgres_url.to_string(), interval, || {
+            Config::load()
+                .map(|config| config.postgres.postgres_url.to_string())
+                .map_err(|e| e.to_string())
+        });
+        secrets::rotate_postgres_url(vec![pgpool.clone(), allocation_pgpool.clone()], urls);
+    }
+    startup::run(
+        Phase::Schema,
+        startup_config.schema_timeout,
+        startup::check_schema(&pgpool),
+    )
+    .await?;
+    let database_features = startup::run(
+        Phase::Privileges,
+        startup_config.database_timeout,
+        privileges::check(&pgpool, &CONFIG.tap),
+    )
+    .await?;
+    startup::run(Phase::Domains, startup_config.schema_timeout, async {
+        startup::check_domains(&EIP_712_DOMAIN, EIP_712_DOMAIN_V2.as_ref())
+    })
+    .await?;
+    let denylist = DenylistOutbox::new(pgpool.clone(), denylist_outbox_path.clone())
+        .context("Failed to load the denylist outbox")?;
+    // Writes left pending by a previous run
+    denylist.flush().await;
 
     let http_client = reqwest::Client::new();
 
-    let network_subgraph = Box::leak(Box::new(SubgraphClient::new(
-        http_client.clone(),
-        network_subgraph_deployment
-            .map(|deployment| {
-                DeploymentDetails::for_graph_node(
-                    graph_node_status_endpoint,
-                    graph_node_query_endpoint,
-                    deployment,
-                )
-            })
-            .transpose()
-            .expect("Failed to parse graph node query endpoint and network subgraph deployment"),
-        DeploymentDetails::for_query_url_with_token(
-            network_subgraph_endpoint,
-            network_subgraph_auth_token.clone(),
+    let network_subgraph = Box::leak(Box::new(
+        SubgraphClient::new(
+            http_client.clone(),
+            network_subgraph_deployment
+                .map(|deployment| {
+                    DeploymentDetails::for_graph_node(
+                        graph_node_status_endpoint,
+                        graph_node_query_endpoint,
+                        deployment,
+                    )
+                })
+                .transpose()
+                .expect(
+                    "Failed to parse graph node query endpoint and network subgraph deployment",
+                ),
+            DeploymentDetails::for_query_url_with_token(
+                network_subgraph_endpoint,
+                network_subgraph_auth_token.clone(),
+            )
+            .expect("Failed to parse network subgraph endpoint"),
         )
-        .expect("Failed to parse network subgraph endpoint"),
-    )));
+        .with_policy("network", network_subgraph_query_policy.clone())
+        .with_cache(network_subgraph_cache.clone())
+        .with_local_failover(network_subgraph_local_failover.clone())
+        .with_subscription(network_subgraph_subscription.clone()),
+    ));
+
+    let indexer_address = *indexer_address;
+    let allocation_syncing_interval = Duration::from_millis(*allocation_syncing_interval_ms);
+    let recently_closed_allocation_buffer =
+        Duration::from_secs(*recently_closed_allocation_buffer_seconds);
+    let allocation_deployments = allocation_deployments.clone();
+    let indexer_allocations = watchdog::watch_receiver(
+        "allocations",
+        allocation_syncing_interval,
+        watchdog_config,
+        move |heartbeat| {
+            let allocation_deployments = allocation_deployments.clone();
+            async move {
+                indexer_allocations_watcher(
+                    network_subgraph,
+                    indexer_address,
+                    allocation_syncing_interval,
+                    recently_closed_allocation_buffer,
+                    allocation_deployments,
+                    Some(heartbeat),
+                )
+                .await
+            }
+        },
+    )
+    .await;
 
-    let indexer_allocations = indexer_allocations(
+    let closing_allocations = closing_allocations(
         network_subgraph,
-        *indexer_address,
-        Duration::from_millis(*allocation_syncing_interval_ms),
-        Duration::from_secs(*recently_closed_allocation_buffer_seconds),
+        allocations_eventual(indexer_allocations.clone()),
+        allocation_syncing_interval,
+        *closing_allocation_buffer_epochs,
     );
 
-    let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
-        http_client.clone(),
-        escrow_subgraph_deployment
-            .map(|deployment| {
-                DeploymentDetails::for_graph_node(
-                    graph_node_status_endpoint,
-                    graph_node_query_endpoint,
-                    deployment,
+    let escrow_subgraph = escrow_subgraph_client(&CONFIG, http_client.clone());
+
+    if let Some(receipt_sampling) = receipt_sampling {
+        tokio::spawn(receipt_sampling::run(
+            http_client.clone(),
+            receipt_sampling.clone(),
+        ));
+    }
+    if let Some(lifecycle_hooks) = &CONFIG.tap.lifecycle_hooks {
+        lifecycle_hooks::start(http_client.clone(), lifecycle_hooks.clone());
+    }
+    if let Some(metrics_export) = &CONFIG.indexer_infrastructure.metrics_export {
+        tokio::spawn(metrics::export::run(
+            http_client.clone(),
+            metrics_export.clone(),
+        ));
+    }
+    if let Some(usd_price) = &CONFIG.tap.fee_display.usd_price {
+        tokio::spawn(money::watch_usd_price(
+            http_client.clone(),
+            usd_price.clone(),
+        ));
+    }
+
+    let escrow_syncing_interval = Duration::from_millis(*escrow_syncing_interval_ms);
+    let escrow_accounts = watchdog::watch(
+        "escrow_accounts",
+        escrow_syncing_interval,
+        watchdog_config,
+        move |heartbeat| async move {
+            escrow_accounts_eventual(
+                escrow_accounts_watcher(
+                    escrow_subgraph,
+                    indexer_address,
+                    escrow_syncing_interval,
+                    false,
+                    escrow_rpc_fallback.clone(),
+                    Some(heartbeat),
                 )
-            })
-            .transpose()
-            .expect("Failed to parse graph node query endpoint and escrow subgraph deployment"),
-        DeploymentDetails::for_query_url_with_token(
-            escrow_subgraph_endpoint,
-            escrow_subgraph_auth_token.clone(),
-        )
-        .expect("Failed to parse escrow subgraph endpoint"),
-    )));
+                .await,
+            )
+        },
+    )
+    .await;
 
-    let escrow_accounts = escrow_accounts(
-        escrow_subgraph,
-        *indexer_address,
-        Duration::from_millis(*escrow_syncing_interval_ms),
-        false,
-    );
+    // Also awaited by the `SenderAccountsManager` with sharding, and its watchers
+    startup::run(
+        Phase::EscrowAccounts,
+        startup_config.escrow_accounts_timeout,
+        async {
+            escrow_accounts
+                .value()
+                .await
+                .map_err(|_| anyhow!("The escrow accounts were never available"))
+        },
+    )
+    .await?;
+
+    tokio::spawn(capacity_planning::run(
+        pgpool.clone(),
+        escrow_accounts.clone(),
+        capacity_planning.clone(),
+        *horizon_enabled,
+    ));
+
+    if let Some(receipt_compaction) = receipt_compaction
+        .as_ref()
+        .filter(|_| database_features.receipt_archive)
+    {
+        tokio::spawn(receipt_compaction::run(
+            pgpool.clone(),
+            escrow_accounts.clone(),
+            receipt_compaction.clone(),
+        ));
+    }
+
+    if let Some(domain_separator) = EIP_712_DOMAIN_DIPS.clone() {
+        tokio::spawn(dips::run(
+            pgpool.clone(),
+            escrow_accounts.clone(),
+            domain_separator,
+            &CONFIG,
+        ));
+    }
 
     let args = SenderAccountsManagerArgs {
         config: &CONFIG,
         domain_separator: EIP_712_DOMAIN.clone(),
-        pgpool,
+        horizon_domain_separator: EIP_712_DOMAIN_V2.clone(),
+        pgpool: pgpool.clone(),
+        allocation_pgpool,
+        denylist,
         indexer_allocations,
-        escrow_accounts,
+        closing_allocations,
+        escrow_accounts: escrow_accounts.clone(),
         escrow_subgraph,
         sender_aggregator_endpoints: sender_aggregator_endpoints.clone(),
+        database_features,
         prefix: None,
     };
 
-    SenderAccountsManager::spawn(None, SenderAccountsManager, args)
-        .await
-        .expect("Failed to start sender accounts manager actor.")
+    let (manager, handle) = startup::run(
+        Phase::SenderAccounts,
+        startup_config.sender_accounts_timeout,
+        async {
+            SenderAccountsManager::spawn(None, SenderAccountsManager, args)
+                .await
+                .context("Failed to start the SenderAccountsManager")
+        },
+    )
+    .await?;
+
+    tokio::spawn(config_reload::run(
+        manager.clone(),
+        Thresholds::from(&CONFIG.tap),
+    ));
+
+    let health_state = HealthState::new(manager.clone(), pgpool.clone(), escrow_subgraph);
+    let status_signer = CONFIG
+        .tap
+        .signed_status
+        .as_ref()
+        .map(|signed_status| {
+            let signer = StatusSigner::new(&signed_status.operator_mnemonic, indexer_address)?;
+            tokio::spawn(signed_status::run_heartbeats(
+                signer.clone(),
+                signed_status.heartbeat_interval,
+            ));
+            anyhow::Ok(signer)
+        })
+        .transpose()
+        .context("Invalid operator mnemonic")?;
+    let status_state = StatusState::new(
+        manager.clone(),
+        pgpool,
+        escrow_accounts,
+        database_features,
+        status_signer,
+    );
+
+    Ok((manager, handle, health_state, status_state))
 }
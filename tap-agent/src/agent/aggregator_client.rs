@@ -0,0 +1,228 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON-RPC client of the aggregator of a sender, with the credentials of
+//! `tap.sender_aggregator_auth`, for aggregators behind an authenticated gateway.
+//!
+//! The trace context of the current span is sent along with each request, in the W3C
+//! `traceparent` header, so that the spans of the aggregator join the trace of the RAV request,
+//! see [`crate::telemetry`].
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use jsonrpsee::http_client::{
+    HeaderMap, HeaderValue, HttpBackend, HttpClient, HttpClientBuilder, HttpRequest,
+};
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use reqwest::header::HeaderName;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::AggregatorAuthConfig;
+
+pub type AggregatorClient = HttpClient<TraceContext<HttpBackend>>;
+
+/// Adds the trace context of the current span to the requests of `S`.
+#[derive(Clone, Debug)]
+pub struct TraceContext<S>(S);
+
+impl<S: tower_04::Service<HttpRequest>> tower_04::Service<HttpRequest> for TraceContext<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: HttpRequest) -> Self::Future {
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            inject(
+                propagator,
+                &tracing::Span::current().context(),
+                request.headers_mut(),
+            )
+        });
+        self.0.call(request)
+    }
+}
+
+/// Nothing is injected outside of a trace, or without a propagator set.
+fn inject(
+    propagator: &dyn TextMapPropagator,
+    context: &opentelemetry::Context,
+    headers: &mut HeaderMap,
+) {
+    struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::from_str(key), HeaderValue::from_str(&value))
+            {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    propagator.inject_context(context, &mut HeaderInjector(headers));
+}
+
+pub fn build(
+    endpoint: &str,
+    request_timeout: Duration,
+    auth: Option<&AggregatorAuthConfig>,
+) -> Result<AggregatorClient> {
+    let mut builder = HttpClientBuilder::default()
+        .set_http_middleware(tower_04::ServiceBuilder::new().layer_fn(TraceContext))
+        .request_timeout(request_timeout);
+    if let Some(auth) = auth {
+        builder = builder.set_headers(headers(auth)?);
+        if auth.client_cert_path.is_some() || auth.ca_cert_path.is_some() {
+            builder = builder.with_custom_cert_store(tls_config(auth)?);
+        }
+    }
+    Ok(builder.build(endpoint)?)
+}
+
+fn headers(auth: &AggregatorAuthConfig) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = &auth.bearer_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .context("Invalid aggregator bearer token")?;
+        value.set_sensitive(true);
+        headers.insert("authorization", value);
+    }
+    if let Some(key) = &auth.api_key {
+        let mut value = HeaderValue::from_str(key).context("Invalid aggregator API key")?;
+        value.set_sensitive(true);
+        let name: HeaderName = auth
+            .api_key_header
+            .parse()
+            .with_context(|| format!("Invalid API key header `{}`", auth.api_key_header))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// The public roots plus `ca_cert_path`, and the client certificate if there's one.
+pub fn tls_config(auth: &AggregatorAuthConfig) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = &auth.ca_cert_path {
+        for cert in read_certs(path)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+        }
+    }
+
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+    let config = match (&auth.client_cert_path, &auth.client_key_path) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(read_certs(cert_path)?, read_key(key_path)?)
+            .with_context(|| format!("Invalid client certificate {}", cert_path.display()))?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read the certificates of {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Failed to read the private key of {}", path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use jsonrpsee::http_client::HeaderMap;
+    use opentelemetry::{
+        trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+        Context,
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use crate::config::AggregatorAuthConfig;
+
+    #[test]
+    fn test_inject_trace_context() {
+        let propagator = TraceContextPropagator::new();
+        let mut headers = HeaderMap::new();
+        super::inject(&propagator, &Context::new(), &mut headers);
+        assert!(headers.is_empty(), "no trace to propagate");
+
+        let context = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736),
+            SpanId::from_u64(0x00f067aa0ba902b7),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+        super::inject(&propagator, &context, &mut headers);
+        assert_eq!(
+            headers["traceparent"],
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+
+    #[test]
+    fn test_auth_headers() {
+        let auth = AggregatorAuthConfig {
+            bearer_token: Some("token".to_string()),
+            api_key: Some("key".to_string()),
+            api_key_header: "x-gateway-key".to_string(),
+            ..Default::default()
+        };
+        let headers = super::headers(&auth).unwrap();
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(headers["x-gateway-key"], "key");
+        assert!(headers["authorization"].is_sensitive());
+
+        assert!(super::build("http://localhost:8080", Duration::from_secs(1), Some(&auth)).is_ok());
+
+        let missing_cert = AggregatorAuthConfig {
+            client_cert_path: Some("/nonexistent/client.pem".into()),
+            client_key_path: Some("/nonexistent/client.key".into()),
+            ..Default::default()
+        };
+        assert!(super::build(
+            "https://localhost:8080",
+            Duration::from_secs(1),
+            Some(&missing_cert)
+        )
+        .is_err());
+    }
+}
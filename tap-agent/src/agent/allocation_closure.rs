@@ -0,0 +1,138 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the allocations that are about to be closed.
+//!
+//! An allocation can stay open for at most `maxAllocationEpochs` epochs. Once an allocation is
+//! within `closing_allocation_buffer_epochs` of that limit, it's reported as closing soon, and its
+//! `SenderAccount` requests RAVs for it ahead of closure. This way, most of the fees are already
+//! aggregated when the last RAV is requested.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use alloy::primitives::Address;
+use anyhow::anyhow;
+use eventuals::{join, Eventual, EventualExt};
+use graphql_client::GraphQLQuery;
+use indexer_common::prelude::{Allocation, SubgraphClient};
+use tokio::time::sleep;
+use tracing::warn;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../graphql/network.schema.graphql",
+    query_path = "../graphql/network_epochs.query.graphql",
+    response_derives = "Debug"
+)]
+struct NetworkEpochs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Epochs {
+    current_epoch: u64,
+    max_allocation_epochs: u64,
+}
+
+/// An always up-to-date set of the indexer's allocations that are closing soon.
+///
+/// Always empty if `buffer_epochs` is 0.
+pub fn closing_allocations(
+    network_subgraph: &'static SubgraphClient,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    interval: Duration,
+    buffer_epochs: u64,
+) -> Eventual<HashSet<Address>> {
+    if buffer_epochs == 0 {
+        return Eventual::from_value(HashSet::new());
+    }
+    let epochs = eventuals::timer(interval).map_with_retry(
+        move |_| async move {
+            query_network_epochs(network_subgraph)
+                .await
+                .map_err(|e| e.to_string())
+        },
+        move |error: String| {
+            warn!("Failed to fetch the network epochs: {}", error);
+            sleep(interval.div_f32(2.))
+        },
+    );
+    join((indexer_allocations, epochs)).map(move |(allocations, epochs)| async move {
+        allocations
+            .values()
+            .filter(|allocation| is_closing(allocation, epochs, buffer_epochs))
+            .map(|allocation| allocation.id)
+            .collect()
+    })
+}
+
+async fn query_network_epochs(network_subgraph: &'static SubgraphClient) -> anyhow::Result<Epochs> {
+    let network = network_subgraph
+        .query::<NetworkEpochs, _>(network_epochs::Variables {})
+        .await?
+        .map_err(|e| anyhow!(e))?
+        .graph_network
+        .ok_or_else(|| anyhow!("Graph network not found in the network subgraph"))?;
+    Ok(Epochs {
+        current_epoch: network.current_epoch as u64,
+        max_allocation_epochs: network.max_allocation_epochs as u64,
+    })
+}
+
+fn is_closing(allocation: &Allocation, epochs: Epochs, buffer_epochs: u64) -> bool {
+    // Recently closed allocations are part of the indexer allocations as well.
+    allocation.closed_at_epoch.is_none()
+        && allocation.created_at_epoch + epochs.max_allocation_epochs
+            <= epochs.current_epoch + buffer_epochs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use indexer_common::allocations::{Allocation, AllocationStatus, SubgraphDeployment};
+    use thegraph_core::DeploymentId;
+
+    use super::{is_closing, Epochs};
+    use crate::tap::test_utils::ALLOCATION_ID_0;
+
+    fn allocation(created_at_epoch: u64, closed_at_epoch: Option<u64>) -> Allocation {
+        Allocation {
+            id: ALLOCATION_ID_0,
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+            },
+            indexer: Default::default(),
+            allocated_tokens: Default::default(),
+            created_at_epoch,
+            created_at_block_hash: String::new(),
+            closed_at_epoch,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        }
+    }
+
+    #[test]
+    fn test_is_closing() {
+        let epochs = Epochs {
+            current_epoch: 100,
+            max_allocation_epochs: 28,
+        };
+        // Closes at epoch 101
+        assert!(is_closing(&allocation(73, None), epochs, 1));
+        // Closes at epoch 102
+        assert!(!is_closing(&allocation(74, None), epochs, 1));
+        assert!(is_closing(&allocation(74, None), epochs, 2));
+        // Already closed
+        assert!(!is_closing(&allocation(60, Some(99)), epochs, 1));
+    }
+}
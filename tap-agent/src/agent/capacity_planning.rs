@@ -0,0 +1,450 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Projection of the storage used by the receipts, for the operator to provision Postgres ahead
+//! of growth.
+//!
+//! Every interval, the receipts stored over the last day are counted and measured per sender.
+//! Once `retention_days` of them are kept, a sender is projected to use its receipts per day,
+//! times the retention, times the disk space of a receipt in its table. The disk space of a
+//! receipt is the size of the table with its indexes over its estimated number of rows, or the
+//! size of the receipts of the last day if the table was never analyzed.
+//!
+//! The latest measurement is exported as metrics and in [`crate::status`].
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::primitives::Address;
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use serde::Serialize;
+use sqlx::{types::BigDecimal, PgPool};
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    database::{self, Subsystem},
+};
+
+lazy_static! {
+    static ref RECEIPTS_PER_DAY: GaugeVec = register_gauge_vec!(
+        "tap_receipts_per_day",
+        "Receipts stored over the last day",
+        &["sender"]
+    )
+    .unwrap();
+    static ref RECEIPT_BYTES_PER_DAY: GaugeVec = register_gauge_vec!(
+        "tap_receipt_bytes_per_day",
+        "Size of the receipts stored over the last day, without the indexes",
+        &["sender"]
+    )
+    .unwrap();
+    static ref PROJECTED_BYTES: GaugeVec = register_gauge_vec!(
+        "tap_receipts_projected_bytes",
+        "Disk space the receipts will use once the retention period is reached",
+        &["sender"]
+    )
+    .unwrap();
+    static ref TABLE_BYTES: GaugeVec = register_gauge_vec!(
+        "tap_receipts_table_bytes",
+        "Disk space used by a receipts table with its indexes",
+        &["table"]
+    )
+    .unwrap();
+    static ref LATEST: Mutex<Option<CapacityReport>> = Mutex::new(None);
+}
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ReceiptsTable {
+    Legacy,
+    Horizon,
+}
+
+impl ReceiptsTable {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptsTable::Legacy => "scalar_tap_receipts",
+            ReceiptsTable::Horizon => "tap_horizon_receipts",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapacityReport {
+    pub retention_days: u32,
+    /// Milliseconds since the UNIX epoch.
+    pub measured_at_ms: u64,
+    pub senders: Vec<SenderCapacity>,
+    pub tables: Vec<TableCapacity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SenderCapacity {
+    pub sender: Address,
+    pub receipts_per_day: u64,
+    pub bytes_per_day: u64,
+    pub projected_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableCapacity {
+    pub table: &'static str,
+    pub bytes: u64,
+    pub projected_bytes: u64,
+}
+
+/// Receipts of a sender stored over the last day, in a table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DailyReceipts {
+    sender: Address,
+    table: ReceiptsTable,
+    count: u64,
+    bytes: u64,
+}
+
+/// Receipts of a signer, or of a payer, over the last day, as read from a table.
+struct AddressReceipts {
+    address: String,
+    count: i64,
+    bytes: i64,
+}
+
+/// Size of a table with its indexes, and the estimated number of rows in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TableSize {
+    bytes: u64,
+    rows: f64,
+}
+
+/// Latest measurement, if any was made yet.
+pub fn latest() -> Option<CapacityReport> {
+    LATEST.lock().unwrap().clone()
+}
+
+/// Measures the receipts every `config.interval`, forever.
+pub async fn run(
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    config: config::CapacityPlanning,
+    horizon_enabled: bool,
+) {
+    let mut tables = vec![ReceiptsTable::Legacy];
+    if horizon_enabled {
+        tables.push(ReceiptsTable::Horizon);
+    }
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        let Some(escrow_accounts) = escrow_accounts.value_immediate() else {
+            continue;
+        };
+        match measure(&pgpool, &escrow_accounts, &tables, config.retention_days).await {
+            Ok(report) => {
+                info!(
+                    projected_bytes = report
+                        .tables
+                        .iter()
+                        .map(|table| table.projected_bytes)
+                        .sum::<u64>(),
+                    retention_days = report.retention_days,
+                    "Receipts storage projected."
+                );
+                export(&report);
+                *LATEST.lock().unwrap() = Some(report);
+            }
+            Err(error) => warn!(%error, "Failed to measure the receipts storage."),
+        }
+    }
+}
+
+fn export(report: &CapacityReport) {
+    for sender in &report.senders {
+        let sender_label = sender.sender.to_string();
+        let labels = [sender_label.as_str()];
+        RECEIPTS_PER_DAY
+            .with_label_values(&labels)
+            .set(sender.receipts_per_day as f64);
+        RECEIPT_BYTES_PER_DAY
+            .with_label_values(&labels)
+            .set(sender.bytes_per_day as f64);
+        PROJECTED_BYTES
+            .with_label_values(&labels)
+            .set(sender.projected_bytes as f64);
+    }
+    for table in &report.tables {
+        TABLE_BYTES
+            .with_label_values(&[table.table])
+            .set(table.bytes as f64);
+    }
+}
+
+async fn measure(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    tables: &[ReceiptsTable],
+    retention_days: u32,
+) -> anyhow::Result<CapacityReport> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let since_ns = &BigDecimal::from(now.saturating_sub(DAY).as_nanos() as u64);
+    let mut daily = Vec::new();
+    let mut sizes = HashMap::new();
+    for table in tables {
+        let rows = database::acquire(pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| async move {
+                // Legacy receipts only have their signer, Horizon ones have their sender as payer.
+                match table {
+                    ReceiptsTable::Legacy => {
+                        sqlx::query_as!(
+                            AddressReceipts,
+                            r#"
+                                SELECT
+                                    signer_address AS address,
+                                    COUNT(*) AS "count!",
+                                    COALESCE(SUM(pg_column_size(r.*)), 0)::BIGINT AS "bytes!"
+                                FROM scalar_tap_receipts r
+                                WHERE timestamp_ns >= $1
+                                GROUP BY signer_address
+                            "#,
+                            since_ns
+                        )
+                        .fetch_all(conn)
+                        .await
+                    }
+                    ReceiptsTable::Horizon => {
+                        sqlx::query_as!(
+                            AddressReceipts,
+                            r#"
+                                SELECT
+                                    payer AS address,
+                                    COUNT(*) AS "count!",
+                                    COALESCE(SUM(pg_column_size(r.*)), 0)::BIGINT AS "bytes!"
+                                FROM tap_horizon_receipts r
+                                WHERE timestamp_ns >= $1
+                                GROUP BY payer
+                            "#,
+                            since_ns
+                        )
+                        .fetch_all(conn)
+                        .await
+                    }
+                }
+            })
+            .await?;
+        for row in rows {
+            let address = Address::from_str(&row.address)?;
+            let sender = match table {
+                ReceiptsTable::Legacy => match escrow_accounts.get_sender_for_signer(&address) {
+                    Ok(sender) => sender,
+                    // Not a signer anymore, only part of the current size of the table
+                    Err(_) => continue,
+                },
+                ReceiptsTable::Horizon => address,
+            };
+            daily.push(DailyReceipts {
+                sender,
+                table: *table,
+                count: row.count as u64,
+                bytes: row.bytes as u64,
+            });
+        }
+
+        let size = database::acquire(pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        SELECT pg_total_relation_size(oid) AS "bytes!", reltuples
+                        FROM pg_class
+                        WHERE oid = $1::text::regclass
+                    "#,
+                    table.as_str()
+                )
+                .fetch_one(conn)
+            })
+            .await?;
+        sizes.insert(
+            *table,
+            TableSize {
+                bytes: size.bytes as u64,
+                rows: size.reltuples as f64,
+            },
+        );
+    }
+    Ok(project(
+        &daily,
+        &sizes,
+        retention_days,
+        now.as_millis() as u64,
+    ))
+}
+
+fn project(
+    daily: &[DailyReceipts],
+    sizes: &HashMap<ReceiptsTable, TableSize>,
+    retention_days: u32,
+    measured_at_ms: u64,
+) -> CapacityReport {
+    let receipt_bytes = |receipts: &DailyReceipts| {
+        match sizes.get(&receipts.table) {
+            // reltuples is -1 (or 0) if the table was never analyzed
+            Some(size) if size.rows >= 1.0 => size.bytes as f64 / size.rows,
+            _ => receipts.bytes as f64 / receipts.count.max(1) as f64,
+        }
+    };
+    let projected = |receipts: &DailyReceipts| {
+        receipts.count as f64 * retention_days as f64 * receipt_bytes(receipts)
+    };
+
+    let mut senders: HashMap<Address, SenderCapacity> = HashMap::new();
+    let mut tables: HashMap<ReceiptsTable, u64> = HashMap::new();
+    for receipts in daily {
+        let sender = senders
+            .entry(receipts.sender)
+            .or_insert_with(|| SenderCapacity {
+                sender: receipts.sender,
+                receipts_per_day: 0,
+                bytes_per_day: 0,
+                projected_bytes: 0,
+            });
+        sender.receipts_per_day += receipts.count;
+        sender.bytes_per_day += receipts.bytes;
+        sender.projected_bytes += projected(receipts) as u64;
+        *tables.entry(receipts.table).or_default() += projected(receipts) as u64;
+    }
+
+    let mut senders: Vec<SenderCapacity> = senders.into_values().collect();
+    senders.sort_by_key(|sender| sender.sender);
+    let mut tables: Vec<TableCapacity> = sizes
+        .iter()
+        .map(|(table, size)| TableCapacity {
+            table: table.as_str(),
+            bytes: size.bytes,
+            projected_bytes: tables.get(table).copied().unwrap_or_default(),
+        })
+        .collect();
+    tables.sort_by_key(|table| table.table);
+    CapacityReport {
+        retention_days,
+        measured_at_ms,
+        senders,
+        tables,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::primitives::U256;
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::PgPool;
+
+    use super::{measure, project, DailyReceipts, ReceiptsTable, TableSize};
+    use crate::tap::test_utils::{
+        create_received_receipt, store_receipt, ALLOCATION_ID_0, SENDER, SENDER_2, SIGNER,
+    };
+
+    #[test]
+    fn test_project() {
+        let daily = [
+            DailyReceipts {
+                sender: SENDER.1,
+                table: ReceiptsTable::Legacy,
+                count: 100,
+                bytes: 20_000,
+            },
+            DailyReceipts {
+                sender: SENDER_2.1,
+                table: ReceiptsTable::Legacy,
+                count: 10,
+                bytes: 2_000,
+            },
+            DailyReceipts {
+                sender: SENDER.1,
+                table: ReceiptsTable::Horizon,
+                count: 50,
+                bytes: 15_000,
+            },
+        ];
+        let sizes = HashMap::from([
+            // 500 bytes per receipt on disk
+            (
+                ReceiptsTable::Legacy,
+                TableSize {
+                    bytes: 500_000,
+                    rows: 1_000.0,
+                },
+            ),
+            // Never analyzed, the size of the receipts of the last day is used
+            (
+                ReceiptsTable::Horizon,
+                TableSize {
+                    bytes: 8_192,
+                    rows: -1.0,
+                },
+            ),
+        ]);
+        let report = project(&daily, &sizes, 30, 0);
+
+        assert_eq!(report.senders.len(), 2);
+        let sender = report
+            .senders
+            .iter()
+            .find(|sender| sender.sender == SENDER.1)
+            .unwrap();
+        assert_eq!(sender.receipts_per_day, 150);
+        assert_eq!(sender.bytes_per_day, 35_000);
+        assert_eq!(sender.projected_bytes, 100 * 30 * 500 + 50 * 30 * 300);
+
+        let legacy = report
+            .tables
+            .iter()
+            .find(|table| table.table == "scalar_tap_receipts")
+            .unwrap();
+        assert_eq!(legacy.bytes, 500_000);
+        assert_eq!(legacy.projected_bytes, 110 * 30 * 500);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_measure(pgpool: PgPool) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        // Only the receipts of the last day are counted
+        for (nonce, timestamp_ns) in [now, now - 1, now - 2 * super::DAY.as_nanos() as u64]
+            .into_iter()
+            .enumerate()
+        {
+            let receipt =
+                create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce as u64, timestamp_ns, 1);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+
+        let report = measure(&pgpool, &escrow_accounts, &[ReceiptsTable::Legacy], 30)
+            .await
+            .unwrap();
+        assert_eq!(report.senders.len(), 1);
+        assert_eq!(report.senders[0].sender, SENDER.1);
+        assert_eq!(report.senders[0].receipts_per_day, 2);
+        assert!(report.senders[0].bytes_per_day > 0);
+        assert!(report.senders[0].projected_bytes > 0);
+        assert!(report.tables[0].bytes > 0);
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reloads the thresholds of the configuration file on `SIGHUP`, without restarting tap-agent and
+//! its startup scans.
+//!
+//! Only the values of [`Thresholds`] are reloaded, the other changes of the file are ignored until
+//! the next restart. The new thresholds are sent to the `SenderAccountsManager`, which forwards
+//! them to the running `SenderAccount`s and gives them to the new ones.
+
+use std::time::Duration;
+
+use ractor::ActorRef;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use super::sender_accounts_manager::SenderAccountsManagerMessage;
+use crate::config::{Config, Tap};
+
+/// The values of the configuration that can be changed while tap-agent runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub rav_request_trigger_value: u128,
+    pub max_unnaggregated_fees_per_sender: u128,
+    /// Delay before retrying a RAV request while the sender is denied.
+    pub retry_interval: Duration,
+}
+
+impl From<&Tap> for Thresholds {
+    fn from(tap: &Tap) -> Self {
+        Self {
+            rav_request_trigger_value: tap.rav_request_trigger_value,
+            max_unnaggregated_fees_per_sender: tap.max_unnaggregated_fees_per_sender,
+            retry_interval: tap.rav_request_retry_interval,
+        }
+    }
+}
+
+/// Reloads the configuration file on each `SIGHUP`, and sends its thresholds to `manager` when
+/// they changed. Stops once the manager is stopped.
+pub async fn run(manager: ActorRef<SenderAccountsManagerMessage>, initial: Thresholds) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(error) => {
+            error!(%error, "Failed to listen to SIGHUP, the configuration can't be reloaded.");
+            return;
+        }
+    };
+    let mut current = initial;
+    while sighup.recv().await.is_some() {
+        let thresholds = match Config::load() {
+            Ok(config) => Thresholds::from(&config.tap),
+            Err(error) => {
+                warn!(%error, "Failed to reload the configuration, keeping the current one.");
+                continue;
+            }
+        };
+        if thresholds == current {
+            info!("Configuration reloaded, the thresholds are unchanged.");
+            continue;
+        }
+        info!(
+            ?thresholds,
+            "Configuration reloaded, updating the thresholds."
+        );
+        if manager
+            .cast(SenderAccountsManagerMessage::UpdateConfig(thresholds))
+            .is_err()
+        {
+            return;
+        }
+        current = thresholds;
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only access to the internal state of the actors.
+//!
+//! Meant for black-box integration tests and staging tooling, which can't rely on the crate's own
+//! `#[cfg(test)]` items. Only available with the `debug-rpc` feature. None of these calls modify
+//! the state of the actor they're sent to.
+
+use alloy::primitives::Address;
+use ractor::{call, ActorRef, RactorErr};
+
+use super::{sender_account::SenderAccountMessage, sender_fee_tracker::SenderFeeTracker};
+
+pub type DebugResult<T> = Result<T, RactorErr<SenderAccountMessage>>;
+
+/// Finds the `SenderAccount` of `sender`, using the same naming scheme as the
+/// `SenderAccountsManager`.
+pub fn sender_account(
+    prefix: Option<&str>,
+    sender: &Address,
+) -> Option<ActorRef<SenderAccountMessage>> {
+    let name = match prefix {
+        Some(prefix) => format!("{prefix}:{sender}"),
+        None => sender.to_string(),
+    };
+    ActorRef::where_is(name)
+}
+
+/// Returns a snapshot of the unaggregated fees tracked by the `SenderAccount`.
+pub async fn sender_fee_tracker(
+    sender_account: &ActorRef<SenderAccountMessage>,
+) -> DebugResult<SenderFeeTracker> {
+    call!(sender_account, SenderAccountMessage::GetSenderFeeTracker)
+}
+
+/// Returns whether the `SenderAccount` currently denies its sender.
+pub async fn is_denied(sender_account: &ActorRef<SenderAccountMessage>) -> DebugResult<bool> {
+    call!(sender_account, SenderAccountMessage::GetDeny)
+}
+
+/// Returns whether the `SenderAccount` has a RAV request retry scheduled.
+pub async fn is_scheduler_enabled(
+    sender_account: &ActorRef<SenderAccountMessage>,
+) -> DebugResult<bool> {
+    call!(sender_account, SenderAccountMessage::IsSchedulerEnabled)
+}
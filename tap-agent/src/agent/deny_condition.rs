@@ -0,0 +1,317 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::{Arc, Mutex};
+
+use alloy::primitives::Address;
+use lazy_static::lazy_static;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    GaugeVec, IntGaugeVec, Opts,
+};
+
+use super::denylist_outbox::{Denial, DenyReason};
+
+lazy_static! {
+    pub static ref DENY_CONDITION_INPUTS: DenyConditionCollector = {
+        let collector = DenyConditionCollector::new();
+        prometheus::register(Box::new(collector.clone()))
+            .expect("Failed to register deny condition metrics");
+        collector
+    };
+}
+
+/// Values used to decide whether a sender should be denied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DenyConditionInputs {
+    pub pending_ravs: u128,
+    pub unaggregated_fees: u128,
+    pub invalid_receipt_fees: u128,
+    pub sender_balance: u128,
+    /// The amount the sender last thawed if it keeps thawing its escrow, 0 otherwise, see
+    /// [`indexer_common::escrow_accounts::Trend`].
+    pub expected_balance_decrease: u128,
+    pub max_unaggregated_fees: u128,
+    /// See [`crate::agent::trust_score`].
+    pub trust_score: f64,
+    /// `tap.trust_score.deny_below`, the trust score is ignored if unset.
+    pub min_trust_score: Option<f64>,
+    pub reached: bool,
+}
+
+impl DenyConditionInputs {
+    /// The condition reached with these values, if any. The escrow balance is checked first, then
+    /// the escrow balance after its next expected decrease, then the unaggregated fees alone,
+    /// then with the fees of the invalid receipts, then the trust score.
+    ///
+    /// A one-off dip of the balance isn't expected to go on, a sender is only denied ahead of
+    /// time while it keeps thawing its escrow.
+    pub fn reason(&self) -> Option<DenyReason> {
+        if self.pending_ravs + self.unaggregated_fees >= self.sender_balance {
+            Some(DenyReason::EscrowBalance)
+        } else if self.expected_balance_decrease > 0
+            && self.pending_ravs + self.unaggregated_fees
+                >= self
+                    .sender_balance
+                    .saturating_sub(self.expected_balance_decrease)
+        {
+            Some(DenyReason::EscrowThawing)
+        } else if self.unaggregated_fees >= self.max_unaggregated_fees {
+            Some(DenyReason::MaxUnaggregatedFees)
+        } else if self.unaggregated_fees + self.invalid_receipt_fees >= self.max_unaggregated_fees {
+            Some(DenyReason::InvalidReceipts)
+        } else if self
+            .min_trust_score
+            .is_some_and(|min_trust_score| self.trust_score < min_trust_score)
+        {
+            Some(DenyReason::LowTrustScore)
+        } else {
+            None
+        }
+    }
+
+    /// The [`Denial`] of the sender with these values.
+    pub fn denial(&self) -> Denial {
+        Denial::new(
+            self.reason().unwrap_or(DenyReason::Unknown),
+            serde_json::json!({
+                "pending_ravs": self.pending_ravs.to_string(),
+                "unaggregated_fees": self.unaggregated_fees.to_string(),
+                "invalid_receipt_fees": self.invalid_receipt_fees.to_string(),
+                "sender_balance": self.sender_balance.to_string(),
+                "expected_balance_decrease": self.expected_balance_decrease.to_string(),
+                "max_unaggregated_fees": self.max_unaggregated_fees.to_string(),
+                "trust_score": self.trust_score,
+            }),
+        )
+    }
+}
+
+/// Exports the inputs of the latest deny condition evaluation of each sender.
+///
+/// All the gauges of a sender are written and scraped under the same lock, so a scrape never
+/// mixes values from two different evaluations.
+#[derive(Clone)]
+pub struct DenyConditionCollector {
+    lock: Arc<Mutex<()>>,
+    pending_ravs: GaugeVec,
+    unaggregated_fees: GaugeVec,
+    invalid_receipt_fees: GaugeVec,
+    sender_balance: GaugeVec,
+    max_unaggregated_fees: GaugeVec,
+    reached: IntGaugeVec,
+}
+
+fn gauge_vec(name: &str, help: &str) -> GaugeVec {
+    GaugeVec::new(Opts::new(name, help), &["sender"]).expect("Invalid metric definition")
+}
+
+impl DenyConditionCollector {
+    fn new() -> Self {
+        Self {
+            lock: Arc::new(Mutex::new(())),
+            pending_ravs: gauge_vec(
+                "tap_deny_condition_pending_ravs_grt",
+                "Pending RAVs value used in the last deny condition evaluation",
+            ),
+            unaggregated_fees: gauge_vec(
+                "tap_deny_condition_unaggregated_fees_grt",
+                "Unaggregated fees used in the last deny condition evaluation",
+            ),
+            invalid_receipt_fees: gauge_vec(
+                "tap_deny_condition_invalid_receipt_fees_grt",
+                "Invalid receipt fees used in the last deny condition evaluation",
+            ),
+            sender_balance: gauge_vec(
+                "tap_deny_condition_escrow_balance_grt",
+                "Sender escrow balance used in the last deny condition evaluation",
+            ),
+            max_unaggregated_fees: gauge_vec(
+                "tap_deny_condition_max_unaggregated_fees_grt",
+                "Max unaggregated fees per sender used in the last deny condition evaluation",
+            ),
+            reached: IntGaugeVec::new(
+                Opts::new(
+                    "tap_deny_condition_reached",
+                    "Result of the last deny condition evaluation",
+                ),
+                &["sender"],
+            )
+            .expect("Invalid metric definition"),
+        }
+    }
+
+    pub fn record(&self, sender: &Address, inputs: &DenyConditionInputs) {
+        let sender = sender.to_string();
+        let labels = &[sender.as_str()];
+        let _guard = self.lock.lock().unwrap();
+        self.pending_ravs
+            .with_label_values(labels)
+            .set(inputs.pending_ravs as f64);
+        self.unaggregated_fees
+            .with_label_values(labels)
+            .set(inputs.unaggregated_fees as f64);
+        self.invalid_receipt_fees
+            .with_label_values(labels)
+            .set(inputs.invalid_receipt_fees as f64);
+        self.sender_balance
+            .with_label_values(labels)
+            .set(inputs.sender_balance as f64);
+        self.max_unaggregated_fees
+            .with_label_values(labels)
+            .set(inputs.max_unaggregated_fees as f64);
+        self.reached
+            .with_label_values(labels)
+            .set(inputs.reached as i64);
+    }
+
+    pub fn remove(&self, sender: &Address) {
+        let sender = sender.to_string();
+        let labels = &[sender.as_str()];
+        let _guard = self.lock.lock().unwrap();
+        let _ = self.pending_ravs.remove_label_values(labels);
+        let _ = self.unaggregated_fees.remove_label_values(labels);
+        let _ = self.invalid_receipt_fees.remove_label_values(labels);
+        let _ = self.sender_balance.remove_label_values(labels);
+        let _ = self.max_unaggregated_fees.remove_label_values(labels);
+        let _ = self.reached.remove_label_values(labels);
+    }
+}
+
+impl Collector for DenyConditionCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.pending_ravs.desc(),
+            self.unaggregated_fees.desc(),
+            self.invalid_receipt_fees.desc(),
+            self.sender_balance.desc(),
+            self.max_unaggregated_fees.desc(),
+            self.reached.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _guard = self.lock.lock().unwrap();
+        [
+            self.pending_ravs.collect(),
+            self.unaggregated_fees.collect(),
+            self.invalid_receipt_fees.collect(),
+            self.sender_balance.collect(),
+            self.max_unaggregated_fees.collect(),
+            self.reached.collect(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::address;
+    use prometheus::core::Collector;
+
+    use super::{DenyConditionCollector, DenyConditionInputs};
+    use crate::agent::denylist_outbox::DenyReason;
+
+    #[test]
+    fn test_record_and_remove() {
+        let sender = address!("abababababababababababababababababababab");
+        let collector = DenyConditionCollector::new();
+        collector.record(
+            &sender,
+            &DenyConditionInputs {
+                pending_ravs: 1,
+                unaggregated_fees: 2,
+                invalid_receipt_fees: 3,
+                sender_balance: 4,
+                expected_balance_decrease: 0,
+                max_unaggregated_fees: 5,
+                trust_score: 1.0,
+                min_trust_score: None,
+                reached: true,
+            },
+        );
+
+        let families = collector.collect();
+        assert_eq!(families.len(), 6);
+        let value_of = |name: &str| {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            family.get_metric()[0].get_gauge().get_value()
+        };
+        assert_eq!(value_of("tap_deny_condition_pending_ravs_grt"), 1.0);
+        assert_eq!(value_of("tap_deny_condition_escrow_balance_grt"), 4.0);
+        assert_eq!(value_of("tap_deny_condition_reached"), 1.0);
+
+        collector.remove(&sender);
+        assert!(collector
+            .collect()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[test]
+    fn test_reason() {
+        let inputs = DenyConditionInputs {
+            pending_ravs: 0,
+            unaggregated_fees: 10,
+            invalid_receipt_fees: 0,
+            sender_balance: 100,
+            expected_balance_decrease: 0,
+            max_unaggregated_fees: 20,
+            trust_score: 0.4,
+            min_trust_score: None,
+            reached: false,
+        };
+        assert_eq!(inputs.reason(), None);
+        assert_eq!(
+            DenyConditionInputs {
+                pending_ravs: 90,
+                unaggregated_fees: 20,
+                ..inputs.clone()
+            }
+            .reason(),
+            Some(DenyReason::EscrowBalance)
+        );
+        assert_eq!(
+            DenyConditionInputs {
+                pending_ravs: 50,
+                expected_balance_decrease: 40,
+                ..inputs.clone()
+            }
+            .reason(),
+            Some(DenyReason::EscrowThawing)
+        );
+        assert_eq!(
+            DenyConditionInputs {
+                pending_ravs: 50,
+                expected_balance_decrease: 30,
+                ..inputs.clone()
+            }
+            .reason(),
+            None
+        );
+        assert_eq!(
+            DenyConditionInputs {
+                unaggregated_fees: 20,
+                ..inputs.clone()
+            }
+            .reason(),
+            Some(DenyReason::MaxUnaggregatedFees)
+        );
+        assert_eq!(
+            DenyConditionInputs {
+                min_trust_score: Some(0.5),
+                ..inputs.clone()
+            }
+            .reason(),
+            Some(DenyReason::LowTrustScore)
+        );
+        let inputs = DenyConditionInputs {
+            invalid_receipt_fees: 10,
+            ..inputs
+        };
+        assert_eq!(inputs.reason(), Some(DenyReason::InvalidReceipts));
+        assert_eq!(inputs.denial().context["invalid_receipt_fees"], "10");
+    }
+}
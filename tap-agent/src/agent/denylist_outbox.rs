@@ -0,0 +1,422 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes to the sender denylist that survive database outages.
+//!
+//! Denying or allowing a sender is recorded in the outbox first, keeping only the latest intent
+//! per sender, then written to `scalar_tap_denylist`. If the write fails, the intent stays in the
+//! outbox and is retried every [`RETRY_INTERVAL`] until it succeeds, unless a newer intent for
+//! the same sender replaces it meanwhile. Both writes are idempotent, so applying the latest
+//! intent once the database recovers is enough.
+//!
+//! If `tap.denylist_outbox_path` is set, the pending intents are also kept in that file, so that
+//! they survive a restart of the agent.
+//!
+//! Each deny carries a [`Denial`], stored in the `reason` and `context` columns of the row, and
+//! counted in `tap_sender_denials_total` by reason. The reason of a sender already denied is
+//! replaced by the one of its latest deny, unless the operator denied it: tap-agent never
+//! overrides nor allows a sender the operator denied, see [`DenyReason::is_automatic`].
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use alloy::primitives::Address;
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::storage::PgStorage;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref SENDER_DENIALS: IntCounterVec = register_int_counter_vec!(
+        "tap_sender_denials_total",
+        "Number of times a sender was denied, by reason",
+        &["sender", "reason"]
+    )
+    .unwrap();
+}
+
+/// Why a sender was denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    /// The pending RAVs and unaggregated fees reached the escrow balance of the sender.
+    EscrowBalance,
+    /// The escrow balance of the sender is steadily decreasing, as when it's thawing, and the
+    /// pending RAVs and unaggregated fees reached what it'll be after the next decrease.
+    EscrowThawing,
+    /// The unaggregated fees reached `tap.max_amount_willing_to_lose_grt`.
+    MaxUnaggregatedFees,
+    /// The unaggregated fees reached `tap.max_amount_willing_to_lose_grt` only with the fees of
+    /// the invalid receipts.
+    InvalidReceipts,
+    /// The trust score fell below `tap.trust_score.deny_below`, see
+    /// [`crate::agent::trust_score`].
+    LowTrustScore,
+    /// The `SenderAccount` was stopped through the admin API.
+    AdminStop,
+    /// The `SenderAccount` failed to start.
+    StartFailed,
+    /// Denied by the operator, with `tap-agent db deny`.
+    Operator,
+    /// Denied before the reasons were recorded.
+    Unknown,
+}
+
+impl DenyReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenyReason::EscrowBalance => "escrow_balance",
+            DenyReason::EscrowThawing => "escrow_thawing",
+            DenyReason::MaxUnaggregatedFees => "max_unaggregated_fees",
+            DenyReason::InvalidReceipts => "invalid_receipts",
+            DenyReason::LowTrustScore => "low_trust_score",
+            DenyReason::AdminStop => "admin_stop",
+            DenyReason::StartFailed => "start_failed",
+            DenyReason::Operator => "operator",
+            DenyReason::Unknown => "unknown",
+        }
+    }
+
+    /// Reverse of [`DenyReason::as_str`], [`DenyReason::Unknown`] for the rows without a reason.
+    pub fn parse(reason: Option<&str>) -> Self {
+        match reason {
+            Some("escrow_balance") => DenyReason::EscrowBalance,
+            Some("escrow_thawing") => DenyReason::EscrowThawing,
+            Some("max_unaggregated_fees") => DenyReason::MaxUnaggregatedFees,
+            Some("invalid_receipts") => DenyReason::InvalidReceipts,
+            Some("low_trust_score") => DenyReason::LowTrustScore,
+            Some("admin_stop") => DenyReason::AdminStop,
+            Some("start_failed") => DenyReason::StartFailed,
+            Some("operator") => DenyReason::Operator,
+            _ => DenyReason::Unknown,
+        }
+    }
+
+    /// Whether tap-agent may allow the sender again once the deny condition is gone. Only the
+    /// operator allows a sender it denied, with `tap-agent db allow`.
+    pub fn is_automatic(&self) -> bool {
+        *self != DenyReason::Operator
+    }
+}
+
+/// A deny of a sender, with the values that led to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denial {
+    pub reason: DenyReason,
+    /// Free-form, for the operator. Fee values are strings of wei, they don't fit in JSON numbers.
+    #[serde(default)]
+    pub context: serde_json::Value,
+}
+
+impl Denial {
+    pub fn new(reason: DenyReason, context: serde_json::Value) -> Self {
+        Self { reason, context }
+    }
+}
+
+impl From<DenyReason> for Denial {
+    fn from(reason: DenyReason) -> Self {
+        Self::new(reason, serde_json::Value::Null)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Intent {
+    Deny(Denial),
+    Allow,
+}
+
+/// Intents persisted before the deny reasons, read once after an upgrade.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LegacyIntent {
+    Deny,
+    Allow,
+}
+
+impl From<LegacyIntent> for Intent {
+    fn from(intent: LegacyIntent) -> Self {
+        match intent {
+            LegacyIntent::Deny => Intent::Deny(DenyReason::Unknown.into()),
+            LegacyIntent::Allow => Intent::Allow,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DenylistOutbox {
+    storage: PgStorage,
+    /// Only held to read or update the state, never while writing.
+    inner: Arc<std::sync::Mutex<Inner>>,
+    /// Held while writing the intent of a sender, so that the intents of a sender are written in
+    /// order. The senders are written independently of each other.
+    writers: Arc<std::sync::Mutex<HashMap<Address, Arc<Mutex<()>>>>>,
+}
+
+struct Inner {
+    /// With the sequence number of the intent, to tell whether a newer one was recorded while it
+    /// was written.
+    pending: HashMap<Address, (u64, Intent)>,
+    sequence: u64,
+    path: Option<PathBuf>,
+    retrying: bool,
+}
+
+impl DenylistOutbox {
+    /// Loads the intents left pending by a previous run from `path`, if any. They are written by
+    /// the next [`DenylistOutbox::flush`].
+    pub fn new(pgpool: PgPool, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let pending: HashMap<Address, Intent> = match &path {
+            Some(path) if path.exists() => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&content)
+                    .or_else(|error| {
+                        serde_json::from_str::<HashMap<Address, LegacyIntent>>(&content)
+                            .map(|pending| {
+                                pending
+                                    .into_iter()
+                                    .map(|(sender, intent)| (sender, intent.into()))
+                                    .collect()
+                            })
+                            .map_err(|_| error)
+                    })
+                    .with_context(|| format!("Failed to parse {}", path.display()))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            storage,
+            inner: Arc::new(std::sync::Mutex::new(Inner {
+                pending: pending
+                    .into_iter()
+                    .map(|(sender, intent)| (sender, (0, intent)))
+                    .collect(),
+                sequence: 0,
+                path,
+                retrying: false,
+            })),
+            writers: Default::default(),
+        })
+    }
+
+    pub async fn deny(&self, sender: Address, denial: Denial) {
+        SENDER_DENIALS
+            .with_label_values(&[&sender.to_string(), denial.reason.as_str()])
+            .inc();
+        self.record(sender, Intent::Deny(denial)).await;
+    }
+
+    pub async fn allow(&self, sender: Address) {
+        self.record(sender, Intent::Allow).await;
+    }
+
+    /// Intent not written to the database yet, it's more recent than the database.
+    pub async fn pending_intent(&self, sender: Address) -> Option<Intent> {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .get(&sender)
+            .map(|(_, intent)| intent.clone())
+    }
+
+    /// Writes all the pending intents. Returns whether none is left pending.
+    pub async fn flush(&self) -> bool {
+        self.write_pending().await;
+        self.retry_if_pending();
+        self.inner.lock().unwrap().pending.is_empty()
+    }
+
+    async fn record(&self, sender: Address, intent: Intent) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.sequence += 1;
+            let sequence = inner.sequence;
+            inner.pending.insert(sender, (sequence, intent));
+            inner.persist();
+        }
+        self.write(sender).await;
+        self.retry_if_pending();
+    }
+
+    async fn write_pending(&self) {
+        let senders: Vec<Address> = self.inner.lock().unwrap().pending.keys().copied().collect();
+        for sender in senders {
+            self.write(sender).await;
+        }
+    }
+
+    /// Writes the pending intent of `sender`, if any.
+    async fn write(&self, sender: Address) {
+        let writer = self
+            .writers
+            .lock()
+            .unwrap()
+            .entry(sender)
+            .or_default()
+            .clone();
+        let _writing = writer.lock().await;
+        let Some((sequence, intent)) = self.inner.lock().unwrap().pending.get(&sender).cloned()
+        else {
+            return;
+        };
+        let result = match &intent {
+            Intent::Deny(denial) => self.storage.deny_sender(sender, denial).await,
+            Intent::Allow => self.storage.allow_sender(sender).await,
+        };
+        match result {
+            Ok(()) => {
+                let mut inner = self.inner.lock().unwrap();
+                if inner
+                    .pending
+                    .get(&sender)
+                    .is_some_and(|(pending, _)| *pending == sequence)
+                {
+                    inner.pending.remove(&sender);
+                    inner.persist();
+                }
+            }
+            Err(error) => warn!(
+                %error,
+                %sender,
+                ?intent,
+                "Failed to write to the denylist, retrying in {}s.",
+                RETRY_INTERVAL.as_secs()
+            ),
+        }
+    }
+
+    fn retry_if_pending(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.pending.is_empty() && !inner.retrying {
+            inner.retrying = true;
+            let outbox = self.clone();
+            tokio::spawn(async move { outbox.retry().await });
+        }
+    }
+
+    async fn retry(&self) {
+        loop {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+            self.write_pending().await;
+            let mut inner = self.inner.lock().unwrap();
+            if inner.pending.is_empty() {
+                inner.retrying = false;
+                info!("Pending denylist writes applied.");
+                return;
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let pending: HashMap<&Address, &Intent> = self
+            .pending
+            .iter()
+            .map(|(sender, (_, intent))| (sender, intent))
+            .collect();
+        let result = serde_json::to_string(&pending)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(std::fs::write(path, content)?));
+        if let Err(error) = result {
+            error!(
+                %error,
+                path = %path.display(),
+                "Failed to persist the denylist outbox, pending writes won't survive a restart."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::hex::ToHexExt;
+    use sqlx::PgPool;
+
+    use super::{Denial, DenyReason, DenylistOutbox, Intent};
+    use crate::tap::test_utils::SENDER;
+
+    async fn is_denied(pgpool: &PgPool) -> bool {
+        sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM scalar_tap_denylist WHERE sender_address = $1
+                ) AS "denied!"
+            "#,
+            SENDER.1.encode_hex(),
+        )
+        .fetch_one(pgpool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_write_survives_outage(pgpool: PgPool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("denylist-outbox.json");
+        let outbox = DenylistOutbox::new(pgpool.clone(), Some(path.clone())).unwrap();
+
+        let denial = Denial::new(
+            DenyReason::EscrowBalance,
+            serde_json::json!({ "sender_balance": "10" }),
+        );
+        outbox.deny(SENDER.1, denial.clone()).await;
+        assert!(is_denied(&pgpool).await);
+        assert_eq!(outbox.pending_intent(SENDER.1).await, None);
+        let row = sqlx::query!(
+            "SELECT reason, context FROM scalar_tap_denylist WHERE sender_address = $1",
+            SENDER.1.encode_hex(),
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(row.reason.as_deref(), Some("escrow_balance"));
+        assert_eq!(row.context, Some(denial.context.clone()));
+
+        // An agent whose database is down
+        let down = PgPool::connect_lazy_with((*pgpool.connect_options()).clone());
+        down.close().await;
+        let outbox = DenylistOutbox::new(down, Some(path.clone())).unwrap();
+        outbox.allow(SENDER.1).await;
+        outbox.deny(SENDER.1, denial).await;
+        outbox.allow(SENDER.1).await;
+        assert_eq!(outbox.pending_intent(SENDER.1).await, Some(Intent::Allow));
+
+        // Once restarted, it picks up the pending intent
+        let restarted = DenylistOutbox::new(pgpool.clone(), Some(path.clone())).unwrap();
+        assert_eq!(
+            restarted.pending_intent(SENDER.1).await,
+            Some(Intent::Allow)
+        );
+        assert!(restarted.flush().await);
+        assert!(!is_denied(&pgpool).await);
+        assert_eq!(restarted.pending_intent(SENDER.1).await, None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_legacy_outbox_file(pgpool: PgPool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("denylist-outbox.json");
+        std::fs::write(&path, format!(r#"{{"{}": "deny"}}"#, SENDER.1)).unwrap();
+
+        let outbox = DenylistOutbox::new(pgpool.clone(), Some(path)).unwrap();
+        assert_eq!(
+            outbox.pending_intent(SENDER.1).await,
+            Some(Intent::Deny(DenyReason::Unknown.into()))
+        );
+        assert!(outbox.flush().await);
+        assert!(is_denied(&pgpool).await);
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Query fees of each subgraph deployment, to compare their profitability.
+//!
+//! The receipts don't say which deployment they were served for, but each allocation is for a
+//! single deployment, so the value a new RAV adds over the previous one of its allocation is all
+//! attributed to the deployment of the allocation. The deployments are taken from the indexer
+//! allocations, and are kept after the allocations are closed, for their last RAVs.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use alloy::primitives::Address;
+use eventuals::{Eventual, EventualExt, PipeHandle};
+use indexer_common::prelude::Allocation;
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use thegraph_core::DeploymentId;
+
+lazy_static! {
+    static ref DEPLOYMENT_FEES: CounterVec = register_counter_vec!(
+        "tap_deployment_fees_grt_total",
+        "Value of the RAVs received since the start of the program, per deployment",
+        &["deployment", "sender"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone)]
+pub struct DeploymentFees {
+    deployments: Arc<RwLock<HashMap<Address, DeploymentId>>>,
+    _allocations_pipe: Arc<PipeHandle>,
+}
+
+impl DeploymentFees {
+    pub fn new(indexer_allocations: Eventual<HashMap<Address, Allocation>>) -> Self {
+        let deployments = Arc::new(RwLock::new(HashMap::new()));
+        let _allocations_pipe = Arc::new(indexer_allocations.pipe({
+            let deployments = deployments.clone();
+            move |allocations| {
+                deployments.write().unwrap().extend(
+                    allocations
+                        .iter()
+                        .map(|(id, allocation)| (*id, allocation.subgraph_deployment.id)),
+                );
+            }
+        }));
+        Self {
+            deployments,
+            _allocations_pipe,
+        }
+    }
+
+    /// Attributes the value `rav_value` adds over `previous_rav_value` to the deployment of
+    /// `allocation_id`.
+    pub fn record_rav(
+        &self,
+        sender: Address,
+        allocation_id: Address,
+        previous_rav_value: u128,
+        rav_value: u128,
+    ) {
+        let deployment = self
+            .deployments
+            .read()
+            .unwrap()
+            .get(&allocation_id)
+            .map_or_else(|| "unknown".to_string(), |id| id.to_string());
+        DEPLOYMENT_FEES
+            .with_label_values(&[&deployment, &sender.to_string()])
+            .inc_by(rav_value.saturating_sub(previous_rav_value) as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr};
+
+    use alloy::primitives::Address;
+    use eventuals::Eventual;
+    use indexer_common::prelude::{Allocation, AllocationStatus, SubgraphDeployment};
+    use thegraph_core::DeploymentId;
+
+    use super::{DeploymentFees, DEPLOYMENT_FEES};
+    use crate::tap::test_utils::{ALLOCATION_ID_0, ALLOCATION_ID_1, INDEXER};
+
+    #[tokio::test]
+    async fn test_record_rav() {
+        let deployment =
+            DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap();
+        let allocation = Allocation {
+            id: *ALLOCATION_ID_0,
+            indexer: INDEXER.1,
+            allocated_tokens: Default::default(),
+            created_at_epoch: 1,
+            created_at_block_hash: String::new(),
+            closed_at_epoch: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+            status: AllocationStatus::Active,
+            subgraph_deployment: SubgraphDeployment {
+                id: deployment,
+                denied_at: None,
+            },
+        };
+        let fees = DeploymentFees::new(Eventual::from_value(HashMap::from([(
+            *ALLOCATION_ID_0,
+            allocation,
+        )])));
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        // Not used by the other tests, that record their RAVs too
+        let sender = Address::repeat_byte(0x42);
+        fees.record_rav(sender, *ALLOCATION_ID_0, 0, 100);
+        fees.record_rav(sender, *ALLOCATION_ID_0, 100, 250);
+        fees.record_rav(sender, *ALLOCATION_ID_1, 0, 10);
+        let sender = sender.to_string();
+        assert_eq!(
+            DEPLOYMENT_FEES
+                .with_label_values(&[&deployment.to_string(), &sender])
+                .get(),
+            250.0
+        );
+        assert_eq!(
+            DEPLOYMENT_FEES
+                .with_label_values(&["unknown", &sender])
+                .get(),
+            10.0
+        );
+    }
+}
@@ -0,0 +1,996 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Aggregation of the indexing fee vouchers (DIPS), next to the query fee receipts.
+//!
+//! The vouchers of an agreement are few, and not urgent to aggregate: the payer pays for indexing
+//! in large, periodic vouchers, rather than for each query. So instead of the actors tracking the
+//! receipts, the unaggregated fees of each agreement are measured every `dips.interval` in a
+//! [`DipsFeeTracker`]. An agreement gets a RAV once its fees reach `dips.trigger_value`, or once
+//! its oldest voucher is `dips.max_voucher_age` old, see [`DipsFeeTracker::due`].
+//!
+//! A voucher is valid if it's for an agreement of the indexer, paid by the payer of the agreement,
+//! signed by one of the payer's signers, and not after the cancellation of the agreement. The
+//! invalid ones are moved to `dips_fee_vouchers_invalid`. The vouchers of an agreement not in
+//! `dips_agreements` yet are kept pending, as indexer-agent may not have recorded it yet: they're
+//! neither measured nor checked until it is. The RAV is requested from the payer's
+//! aggregator in `tap.sender_aggregator_endpoints`, checked like the RAVs of the receipts, and
+//! stored in `dips_ravs`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    hex::ToHexExt,
+    primitives::{Address, FixedBytes},
+    signers::Signature,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use bigdecimal::num_bigint::BigInt;
+use eventuals::Eventual;
+use indexer_common::{
+    escrow_accounts::EscrowAccounts,
+    tap::{
+        dips::{IndexingFeeAggregateVoucher, IndexingFeeVoucher, SignedRav, SignedVoucher},
+        rejection::RejectionCode,
+    },
+};
+use jsonrpsee::{core::client::ClientT, rpc_params};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec};
+use sqlx::{
+    types::{chrono, BigDecimal},
+    Connection, PgPool,
+};
+use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use tracing::{debug, info, warn};
+
+use crate::{
+    agent::{
+        aggregator_client::{self, AggregatorClient},
+        sender_allocation::{
+            horizon::{to_u128, to_u64},
+            rav_checks,
+        },
+    },
+    config,
+    database::{self, Subsystem},
+    logging::event,
+    money::Money,
+};
+
+/// JSON-RPC method of the payer's aggregator for the indexing fee vouchers.
+const AGGREGATE_VOUCHERS_METHOD: &str = "aggregate_indexing_fee_vouchers";
+const AGGREGATOR_API_VERSION: &str = "0.0";
+
+lazy_static! {
+    static ref UNAGGREGATED_FEES: GaugeVec = register_gauge_vec!(
+        "tap_dips_unaggregated_fees_grt_total",
+        "Unaggregated indexing fees of an agreement, outside of the timestamp buffer",
+        &["payer", "agreement"]
+    )
+    .unwrap();
+    static ref RAV_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "tap_dips_rav_requests_total",
+        "RAV requests of indexing fee vouchers, by outcome",
+        &["payer", "outcome"]
+    )
+    .unwrap();
+    static ref INVALID_VOUCHERS: IntCounterVec = register_int_counter_vec!(
+        "tap_dips_invalid_vouchers_total",
+        "Indexing fee vouchers failing the checks, by rejection code",
+        &["payer", "code"]
+    )
+    .unwrap();
+}
+
+/// Unaggregated fees of an agreement, outside of the timestamp buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgreementFees {
+    pub agreement_id: FixedBytes<16>,
+    pub payer: Address,
+    pub value: u128,
+    pub count: u64,
+    /// Timestamp of the oldest unaggregated voucher.
+    pub oldest_timestamp_ns: u64,
+}
+
+/// The unaggregated fees of each agreement, as of the latest measurement.
+#[derive(Debug, Default)]
+pub struct DipsFeeTracker {
+    /// By agreement and payer, as the vouchers of another payer are only found invalid once
+    /// aggregated.
+    fees: HashMap<(FixedBytes<16>, Address), AgreementFees>,
+}
+
+impl DipsFeeTracker {
+    /// Replaces the fees with the latest measurement, and exports them.
+    pub fn update(&mut self, fees: Vec<AgreementFees>) {
+        let fees: HashMap<_, _> = fees
+            .into_iter()
+            .map(|fees| ((fees.agreement_id, fees.payer), fees))
+            .collect();
+        for (agreement_id, payer) in self.fees.keys() {
+            if !fees.contains_key(&(*agreement_id, *payer)) {
+                let _ = UNAGGREGATED_FEES
+                    .remove_label_values(&[&payer.to_string(), &agreement_id.to_string()]);
+            }
+        }
+        for fees in fees.values() {
+            UNAGGREGATED_FEES
+                .with_label_values(&[&fees.payer.to_string(), &fees.agreement_id.to_string()])
+                .set(fees.value as f64);
+        }
+        self.fees = fees;
+    }
+
+    /// The agreements whose fees reached the trigger value, or whose oldest voucher reached the
+    /// max age at `now_ns`. The most valuable first.
+    pub fn due(&self, config: &config::Dips, now_ns: u64) -> Vec<AgreementFees> {
+        let max_age_ns = config.max_voucher_age.as_nanos().min(u64::MAX as u128) as u64;
+        let mut due: Vec<_> = self
+            .fees
+            .values()
+            .filter(|fees| {
+                fees.value >= config.trigger_value
+                    || now_ns.saturating_sub(fees.oldest_timestamp_ns) >= max_age_ns
+            })
+            .cloned()
+            .collect();
+        due.sort_by(|a, b| b.value.cmp(&a.value));
+        due
+    }
+}
+
+/// Voucher as stored by indexer-service.
+struct StoredVoucher {
+    id: i64,
+    signer_address: String,
+    voucher: SignedVoucher,
+}
+
+/// An agreement, as accepted by the indexer.
+struct Agreement {
+    payer: Address,
+    service_provider: Address,
+    cancelled_at_ns: Option<u64>,
+}
+
+struct DipsCollector {
+    pgpool: PgPool,
+    config: &'static config::Config,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    /// Aggregator clients, by payer.
+    aggregators: HashMap<Address, AggregatorClient>,
+}
+
+/// Aggregates the vouchers due every `dips.interval`, forever. Returns right away if DIPS isn't
+/// enabled.
+pub async fn run(
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    config: &'static config::Config,
+) {
+    let Some(dips) = &config.tap.dips else {
+        return;
+    };
+    let mut collector = DipsCollector {
+        pgpool,
+        config,
+        escrow_accounts,
+        domain_separator,
+        aggregators: HashMap::new(),
+    };
+    let mut tracker = DipsFeeTracker::default();
+    let mut interval = tokio::time::interval(dips.interval);
+    loop {
+        interval.tick().await;
+        let now_ns = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_nanos() as u64,
+            Err(_) => continue,
+        };
+        match collector.unaggregated_fees(now_ns).await {
+            Ok(fees) => tracker.update(fees),
+            Err(error) => {
+                warn!(%error, "Failed to measure the unaggregated indexing fees.");
+                continue;
+            }
+        }
+        for fees in tracker.due(dips, now_ns) {
+            debug!(
+                event = event::RAV_REQUEST_TRIGGERED,
+                agreement = %fees.agreement_id,
+                payer = %fees.payer,
+                value = %Money(fees.value),
+                "Requesting a RAV of indexing fee vouchers."
+            );
+            let outcome = match collector.request_rav(&fees, now_ns).await {
+                Ok(rav) => {
+                    info!(
+                        agreement = %fees.agreement_id,
+                        payer = %fees.payer,
+                        value_aggregate = %Money(rav.message.valueAggregate),
+                        "Indexing fee vouchers aggregated."
+                    );
+                    "success"
+                }
+                Err(error) => {
+                    warn!(
+                        event = event::RAV_REQUEST_FAILED,
+                        agreement = %fees.agreement_id,
+                        payer = %fees.payer,
+                        %error,
+                        "Failed to aggregate the indexing fee vouchers."
+                    );
+                    "failure"
+                }
+            };
+            RAV_REQUESTS
+                .with_label_values(&[&fees.payer.to_string(), outcome])
+                .inc();
+        }
+    }
+}
+
+impl DipsCollector {
+    fn indexer(&self) -> Address {
+        self.config.ethereum.indexer_address
+    }
+
+    fn max_timestamp_ns(&self, now_ns: u64) -> u64 {
+        now_ns.saturating_sub(
+            Duration::from_millis(self.config.tap.rav_request_timestamp_buffer_ms).as_nanos()
+                as u64,
+        )
+    }
+
+    /// The fees of the vouchers after the latest RAV of each known agreement, and outside of the
+    /// timestamp buffer.
+    async fn unaggregated_fees(&self, now_ns: u64) -> Result<Vec<AgreementFees>> {
+        let rows = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT
+                    v.agreement_id,
+                    v.payer,
+                    SUM(v.value) AS "sum!",
+                    COUNT(*) AS "count!",
+                    MIN(v.timestamp_ns) AS "oldest_timestamp_ns!"
+                FROM dips_fee_vouchers v
+                JOIN dips_agreements a ON a.agreement_id = v.agreement_id
+                LEFT JOIN dips_ravs r
+                    ON r.agreement_id = v.agreement_id
+                    AND r.payer = v.payer
+                    AND r.service_provider = $1
+                WHERE
+                    v.timestamp_ns > COALESCE(r.timestamp_ns, 0)
+                    AND v.timestamp_ns <= $2
+                GROUP BY v.agreement_id, v.payer
+            "#,
+                    self.indexer().encode_hex(),
+                    BigDecimal::from(self.max_timestamp_ns(now_ns)),
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(AgreementFees {
+                    agreement_id: FixedBytes::from_str(&row.agreement_id)?,
+                    payer: Address::from_str(&row.payer)?,
+                    value: to_u128(&row.sum)?,
+                    count: row.count.try_into()?,
+                    oldest_timestamp_ns: to_u64(&row.oldest_timestamp_ns)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Requests a RAV for the vouchers of the agreement outside of the timestamp buffer, and
+    /// stores it.
+    async fn request_rav(&mut self, fees: &AgreementFees, now_ns: u64) -> Result<SignedRav> {
+        // Not invalid, the agreement may be recorded later on
+        let agreement = self.agreement(fees.agreement_id).await?.ok_or_else(|| {
+            anyhow!(
+                "Unknown agreement {}, its vouchers are kept until it's known",
+                fees.agreement_id
+            )
+        })?;
+        let latest_rav = self.last_rav(fees).await?;
+        let vouchers = self.fetch_vouchers(fees, &latest_rav, now_ns).await?;
+        let (valid_vouchers, invalid_vouchers) =
+            self.check_vouchers(fees, &agreement, vouchers).await?;
+        if !invalid_vouchers.is_empty() {
+            warn!(
+                "Found {} invalid indexing fee vouchers for agreement {} and payer {}.",
+                invalid_vouchers.len(),
+                fees.agreement_id,
+                fees.payer
+            );
+            self.store_invalid_vouchers(fees.payer, &invalid_vouchers)
+                .await?;
+        }
+        if valid_vouchers.is_empty() {
+            bail!("No valid indexing fee vouchers to aggregate");
+        }
+
+        let valid_vouchers: Vec<SignedVoucher> = valid_vouchers
+            .into_iter()
+            .map(|stored| stored.voucher)
+            .collect();
+        let expected_rav = expected_rav(fees, self.indexer(), &latest_rav, &valid_vouchers)?;
+        debug!(
+            event = event::RAV_REQUEST_SENT,
+            vouchers = valid_vouchers.len(),
+            "Sending indexing fee RAV request to the payer's aggregator."
+        );
+        let response: JsonRpcResponse<SignedRav> = self
+            .aggregator(fees.payer)?
+            .request(
+                AGGREGATE_VOUCHERS_METHOD,
+                rpc_params!(AGGREGATOR_API_VERSION, &valid_vouchers, &latest_rav),
+            )
+            .await?;
+        if let Some(warnings) = response.warnings {
+            warn!("Warnings from payer's aggregator: {:?}", warnings);
+        }
+
+        let rav = response.data;
+        if let Err(e) = self.verify_rav(&latest_rav, &expected_rav, &rav).await {
+            self.store_failed_rav(&expected_rav, &rav, &e.to_string())
+                .await?;
+            bail!("Invalid indexing fee RAV, payer could be malicious: {e}");
+        }
+        self.store_rav(&rav).await?;
+        Ok(rav)
+    }
+
+    fn aggregator(&mut self, payer: Address) -> Result<&AggregatorClient> {
+        if !self.aggregators.contains_key(&payer) {
+            let endpoint = self
+                .config
+                .tap
+                .sender_aggregator_endpoints
+                .get(&payer)
+                .ok_or_else(|| anyhow!("No aggregator endpoint for payer {payer}"))?;
+            let client = aggregator_client::build(
+                endpoint,
+                Duration::from_secs(self.config.tap.rav_request_timeout_secs),
+                self.config.tap.sender_aggregator_auth.get(&payer),
+            )?;
+            self.aggregators.insert(payer, client);
+        }
+        Ok(&self.aggregators[&payer])
+    }
+
+    async fn last_rav(&self, fees: &AgreementFees) -> Result<Option<SignedRav>> {
+        let row = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT signature, timestamp_ns, value_aggregate
+                FROM dips_ravs
+                WHERE agreement_id = $1 AND payer = $2 AND service_provider = $3
+            "#,
+                    fees.agreement_id.encode_hex(),
+                    fees.payer.encode_hex(),
+                    self.indexer().encode_hex(),
+                )
+                .fetch_optional(conn)
+            })
+            .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(SignedRav {
+            message: IndexingFeeAggregateVoucher {
+                agreementId: fees.agreement_id,
+                payer: fees.payer,
+                serviceProvider: self.indexer(),
+                timestampNs: to_u64(&row.timestamp_ns)?,
+                valueAggregate: to_u128(&row.value_aggregate)?,
+            },
+            signature: Signature::try_from(row.signature.as_slice())?,
+        }))
+    }
+
+    async fn agreement(&self, agreement_id: FixedBytes<16>) -> Result<Option<Agreement>> {
+        let row = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT payer, service_provider, cancelled_at
+                FROM dips_agreements
+                WHERE agreement_id = $1
+            "#,
+                    agreement_id.encode_hex(),
+                )
+                .fetch_optional(conn)
+            })
+            .await?;
+        row.map(|row| -> Result<Agreement> {
+            Ok(Agreement {
+                payer: Address::from_str(&row.payer)?,
+                service_provider: Address::from_str(&row.service_provider)?,
+                cancelled_at_ns: row
+                    .cancelled_at
+                    .and_then(|at| at.timestamp_nanos_opt())
+                    .map(|ns| ns.max(0) as u64),
+            })
+        })
+        .transpose()
+    }
+
+    /// Vouchers after the latest RAV and outside of the timestamp buffer, oldest first.
+    async fn fetch_vouchers(
+        &self,
+        fees: &AgreementFees,
+        latest_rav: &Option<SignedRav>,
+        now_ns: u64,
+    ) -> Result<Vec<StoredVoucher>> {
+        let min_timestamp_ns = latest_rav
+            .as_ref()
+            .map(|rav| rav.message.timestampNs)
+            .unwrap_or_default();
+        let rows = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT
+                    id, signer_address, signature, service_provider, timestamp_ns, nonce, value
+                FROM dips_fee_vouchers
+                WHERE
+                    agreement_id = $1
+                    AND payer = $2
+                    AND timestamp_ns > $3
+                    AND timestamp_ns <= $4
+                ORDER BY timestamp_ns ASC
+                LIMIT $5
+            "#,
+                    fees.agreement_id.encode_hex(),
+                    fees.payer.encode_hex(),
+                    BigDecimal::from(min_timestamp_ns),
+                    BigDecimal::from(self.max_timestamp_ns(now_ns)),
+                    self.config.tap.rav_request_receipt_limit as i64,
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|row| -> Result<StoredVoucher> {
+                Ok(StoredVoucher {
+                    id: row.id,
+                    signer_address: row.signer_address,
+                    voucher: SignedVoucher {
+                        message: IndexingFeeVoucher {
+                            agreementId: fees.agreement_id,
+                            payer: fees.payer,
+                            serviceProvider: Address::from_str(&row.service_provider)?,
+                            timestampNs: to_u64(&row.timestamp_ns)?,
+                            nonce: to_u64(&row.nonce)?,
+                            value: to_u128(&row.value)?,
+                        },
+                        signature: Signature::try_from(row.signature.as_slice())?,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Splits the vouchers between the valid ones, and the invalid ones with the reason.
+    async fn check_vouchers(
+        &self,
+        fees: &AgreementFees,
+        agreement: &Agreement,
+        vouchers: Vec<StoredVoucher>,
+    ) -> Result<(Vec<StoredVoucher>, Vec<(StoredVoucher, String)>)> {
+        let escrow_accounts = self
+            .escrow_accounts
+            .value()
+            .await
+            .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?;
+        let mut signatures = HashSet::new();
+        let mut valid_vouchers = Vec::new();
+        let mut invalid_vouchers = Vec::new();
+        for stored in vouchers {
+            match check_voucher(
+                &self.domain_separator,
+                &escrow_accounts,
+                self.indexer(),
+                fees.agreement_id,
+                agreement,
+                &stored.voucher,
+                &mut signatures,
+            ) {
+                Ok(()) => valid_vouchers.push(stored),
+                Err(e) => invalid_vouchers.push((stored, e.to_string())),
+            }
+        }
+        Ok((valid_vouchers, invalid_vouchers))
+    }
+
+    async fn verify_rav(
+        &self,
+        latest_rav: &Option<SignedRav>,
+        expected_rav: &IndexingFeeAggregateVoucher,
+        rav: &SignedRav,
+    ) -> Result<()> {
+        let signers = self
+            .escrow_accounts
+            .value()
+            .await
+            .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?
+            .get_signers_for_sender(&expected_rav.payer);
+        rav_checks::check_value(
+            latest_rav.as_ref().map(|rav| rav.message.valueAggregate),
+            expected_rav.valueAggregate,
+            rav.message.valueAggregate,
+            self.config.tap.rav_value_tolerance,
+        )
+        .and_then(|()| {
+            rav_checks::check_signer(rav.recover_signer(&self.domain_separator), &signers)
+        })?;
+        // Within the tolerance, the rest of the RAV must still be the expected one.
+        ensure!(
+            rav.message
+                == IndexingFeeAggregateVoucher {
+                    valueAggregate: rav.message.valueAggregate,
+                    ..expected_rav.clone()
+                },
+            "Received RAV {:?} does not match the expected RAV {:?}",
+            rav.message,
+            expected_rav
+        );
+        Ok(())
+    }
+
+    /// Stores the RAV, along with the deletion of the vouchers it covers if
+    /// `delete_receipts_with_rav` is enabled.
+    async fn store_rav(&self, rav: &SignedRav) -> Result<()> {
+        let delete_vouchers = self.config.tap.delete_receipts_with_rav;
+        database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| async move {
+                let mut tx = conn.begin().await?;
+                sqlx::query!(
+                    r#"
+                INSERT INTO dips_ravs (
+                    signature,
+                    agreement_id,
+                    payer,
+                    service_provider,
+                    timestamp_ns,
+                    value_aggregate,
+                    created_at,
+                    updated_at
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+                ON CONFLICT (agreement_id, payer, service_provider)
+                DO UPDATE SET
+                    signature = $1,
+                    timestamp_ns = $5,
+                    value_aggregate = $6,
+                    updated_at = $7
+            "#,
+                    rav.signature.as_bytes().to_vec(),
+                    rav.message.agreementId.encode_hex(),
+                    rav.message.payer.encode_hex(),
+                    rav.message.serviceProvider.encode_hex(),
+                    BigDecimal::from(rav.message.timestampNs),
+                    BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+                    chrono::Utc::now(),
+                )
+                .execute(&mut *tx)
+                .await?;
+                if delete_vouchers {
+                    sqlx::query!(
+                        r#"
+                    DELETE FROM dips_fee_vouchers
+                    WHERE agreement_id = $1
+                    AND payer = $2
+                    AND timestamp_ns <= $3
+                "#,
+                        rav.message.agreementId.encode_hex(),
+                        rav.message.payer.encode_hex(),
+                        BigDecimal::from(rav.message.timestampNs),
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the vouchers to the invalid vouchers table.
+    async fn store_invalid_vouchers(
+        &self,
+        payer: Address,
+        vouchers: &[(StoredVoucher, String)],
+    ) -> Result<()> {
+        let mut ids = Vec::with_capacity(vouchers.len());
+        let mut signers = Vec::with_capacity(vouchers.len());
+        let mut signatures = Vec::with_capacity(vouchers.len());
+        let mut agreement_ids = Vec::with_capacity(vouchers.len());
+        let mut payers = Vec::with_capacity(vouchers.len());
+        let mut service_providers = Vec::with_capacity(vouchers.len());
+        let mut timestamps = Vec::with_capacity(vouchers.len());
+        let mut nonces = Vec::with_capacity(vouchers.len());
+        let mut values = Vec::with_capacity(vouchers.len());
+        let mut error_logs = Vec::with_capacity(vouchers.len());
+        let mut error_codes = Vec::with_capacity(vouchers.len());
+        for (stored, error) in vouchers {
+            let voucher = &stored.voucher.message;
+            debug!(
+                "Indexing fee voucher {} for agreement {} failed reason: {}",
+                stored.id, voucher.agreementId, error
+            );
+            ids.push(stored.id);
+            signers.push(stored.signer_address.clone());
+            signatures.push(stored.voucher.signature.as_bytes().to_vec());
+            agreement_ids.push(voucher.agreementId.encode_hex());
+            payers.push(voucher.payer.encode_hex());
+            service_providers.push(voucher.serviceProvider.encode_hex());
+            timestamps.push(BigDecimal::from(voucher.timestampNs));
+            nonces.push(BigDecimal::from(voucher.nonce));
+            values.push(BigDecimal::from(BigInt::from(voucher.value)));
+            error_logs.push(error.clone());
+            let error_code = RejectionCode::classify(error);
+            INVALID_VOUCHERS
+                .with_label_values(&[&payer.to_string(), error_code.as_str()])
+                .inc();
+            error_codes.push(error_code.as_str().to_string());
+        }
+
+        database::acquire(&self.pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| async move {
+                let mut tx = conn.begin().await?;
+                sqlx::query!(
+                    r#"INSERT INTO dips_fee_vouchers_invalid (
+                signer_address,
+                signature,
+                agreement_id,
+                payer,
+                service_provider,
+                timestamp_ns,
+                nonce,
+                value,
+                error_log,
+                error_code
+            ) SELECT * FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::BYTEA[],
+                $3::CHAR(32)[],
+                $4::CHAR(40)[],
+                $5::CHAR(40)[],
+                $6::NUMERIC(20)[],
+                $7::NUMERIC(20)[],
+                $8::NUMERIC(40)[],
+                $9::TEXT[],
+                $10::VARCHAR(32)[]
+            )"#,
+                    &signers,
+                    &signatures,
+                    &agreement_ids,
+                    &payers,
+                    &service_providers,
+                    &timestamps,
+                    &nonces,
+                    &values,
+                    &error_logs,
+                    &error_codes,
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!("DELETE FROM dips_fee_vouchers WHERE id = ANY($1)", &ids)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to store invalid indexing fee vouchers: {e}"))?;
+        Ok(())
+    }
+
+    async fn store_failed_rav(
+        &self,
+        expected_rav: &IndexingFeeAggregateVoucher,
+        rav: &SignedRav,
+        reason: &str,
+    ) -> Result<()> {
+        let expected_rav_json = serde_json::to_value(expected_rav)?;
+        let rav_json = serde_json::to_value(rav)?;
+        database::acquire(&self.pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                INSERT INTO dips_rav_requests_failed (
+                    agreement_id,
+                    payer,
+                    expected_rav,
+                    rav_response,
+                    reason
+                )
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+                    expected_rav.agreementId.encode_hex(),
+                    expected_rav.payer.encode_hex(),
+                    expected_rav_json,
+                    rav_json,
+                    reason,
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to store failed indexing fee RAV: {:?}", e))?;
+        Ok(())
+    }
+}
+
+fn check_voucher(
+    domain_separator: &Eip712Domain,
+    escrow_accounts: &EscrowAccounts,
+    indexer: Address,
+    agreement_id: FixedBytes<16>,
+    agreement: &Agreement,
+    voucher: &SignedVoucher,
+    signatures: &mut HashSet<[u8; 65]>,
+) -> Result<()> {
+    let message = &voucher.message;
+    ensure!(
+        agreement.service_provider == indexer,
+        "Agreement {} is not with the indexer {}",
+        agreement_id,
+        indexer
+    );
+    ensure!(
+        message.payer == agreement.payer,
+        "Voucher payer {} is not the payer {} of the agreement",
+        message.payer,
+        agreement.payer
+    );
+    ensure!(
+        message.serviceProvider == indexer,
+        RejectionCode::AllocationMismatch.reject(format!(
+            "Voucher service provider {} is not the indexer {}",
+            message.serviceProvider, indexer
+        ))
+    );
+    if let Some(cancelled_at_ns) = agreement.cancelled_at_ns {
+        ensure!(
+            message.timestampNs <= cancelled_at_ns,
+            "Voucher is after the cancellation of the agreement"
+        );
+    }
+    let signer = voucher
+        .recover_signer(domain_separator)
+        .map_err(|e| RejectionCode::BadSignature.reject(e))?;
+    ensure!(
+        escrow_accounts.get_sender_for_signer(&signer).ok() == Some(agreement.payer),
+        RejectionCode::UnknownSigner.reject(format!(
+            "Voucher signer {} is not a signer of the payer {}",
+            signer, agreement.payer
+        ))
+    );
+    ensure!(
+        signatures.insert(voucher.signature.as_bytes()),
+        RejectionCode::Duplicate.reject("Duplicate voucher signature")
+    );
+    Ok(())
+}
+
+fn expected_rav(
+    fees: &AgreementFees,
+    indexer: Address,
+    latest_rav: &Option<SignedRav>,
+    vouchers: &[SignedVoucher],
+) -> Result<IndexingFeeAggregateVoucher> {
+    let value_aggregate = vouchers
+        .iter()
+        .try_fold(
+            latest_rav
+                .as_ref()
+                .map_or(0, |rav| rav.message.valueAggregate),
+            |total: u128, voucher| total.checked_add(voucher.message.value),
+        )
+        .ok_or_else(|| anyhow!("Overflow while aggregating the indexing fee vouchers"))?;
+    Ok(IndexingFeeAggregateVoucher {
+        agreementId: fees.agreement_id,
+        payer: fees.payer,
+        serviceProvider: indexer,
+        timestampNs: vouchers
+            .iter()
+            .map(|voucher| voucher.message.timestampNs)
+            .max()
+            .expect("vouchers should not be empty"),
+        valueAggregate: value_aggregate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use alloy::{
+        hex::ToHexExt,
+        primitives::{Address, FixedBytes, U256},
+    };
+    use bigdecimal::num_bigint::BigInt;
+    use eventuals::Eventual;
+    use indexer_common::{
+        escrow_accounts::EscrowAccounts,
+        tap::dips::{self, IndexingFeeVoucher},
+    };
+    use sqlx::{types::BigDecimal, PgPool};
+    use tap_core::signed_message::EIP712SignedMessage;
+
+    use super::{AgreementFees, DipsCollector, DipsFeeTracker};
+    use crate::{
+        config,
+        tap::test_utils::{INDEXER, SENDER, SENDER_2, SIGNER},
+    };
+
+    const AGREEMENT_ID: FixedBytes<16> = FixedBytes([0x42u8; 16]);
+
+    fn fees(agreement: u8, value: u128, oldest_timestamp_ns: u64) -> AgreementFees {
+        AgreementFees {
+            agreement_id: FixedBytes([agreement; 16]),
+            payer: SENDER.1,
+            value,
+            count: 1,
+            oldest_timestamp_ns,
+        }
+    }
+
+    #[test]
+    fn test_trigger_policy() {
+        let config = config::Dips {
+            verifier_address: Address::ZERO,
+            trigger_value: 100,
+            max_voucher_age: Duration::from_nanos(1000),
+            interval: Duration::from_secs(1),
+        };
+        let mut tracker = DipsFeeTracker::default();
+        tracker.update(vec![fees(1, 50, 500), fees(2, 150, 900), fees(3, 10, 0)]);
+
+        let due = |now_ns| {
+            tracker
+                .due(&config, now_ns)
+                .iter()
+                .map(|fees| fees.agreement_id[0])
+                .collect::<Vec<_>>()
+        };
+        // Over the trigger value
+        assert_eq!(due(900), vec![2]);
+        // Then the oldest vouchers reach the max age
+        assert_eq!(due(1000), vec![2, 3]);
+        assert_eq!(due(1500), vec![2, 1, 3]);
+
+        tracker.update(vec![fees(1, 50, 500)]);
+        assert_eq!(due(1000), Vec::<u8>::new());
+    }
+
+    async fn store_voucher(
+        pgpool: &PgPool,
+        agreement_id: FixedBytes<16>,
+        payer: Address,
+        nonce: u64,
+        value: u128,
+    ) {
+        let voucher = EIP712SignedMessage::new(
+            &dips::eip712_domain(1, Address::from([0x44u8; 20])),
+            IndexingFeeVoucher {
+                agreementId: agreement_id,
+                payer,
+                serviceProvider: INDEXER.1,
+                timestampNs: nonce + 1,
+                nonce,
+                value,
+            },
+            &SIGNER.0,
+        )
+        .unwrap();
+        sqlx::query!(
+            r#"
+                INSERT INTO dips_fee_vouchers (
+                    signer_address, signature, agreement_id, payer, service_provider,
+                    timestamp_ns, nonce, value
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            SIGNER.1.encode_hex(),
+            voucher.signature.as_bytes().to_vec(),
+            voucher.message.agreementId.encode_hex(),
+            voucher.message.payer.encode_hex(),
+            voucher.message.serviceProvider.encode_hex(),
+            BigDecimal::from(voucher.message.timestampNs),
+            BigDecimal::from(voucher.message.nonce),
+            BigDecimal::from(BigInt::from(voucher.message.value)),
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_fees_and_voucher_checks(pgpool: PgPool) {
+        sqlx::query!(
+            r#"
+                INSERT INTO dips_agreements (agreement_id, payer, service_provider, deployment_id)
+                VALUES ($1, $2, $3, 'QmQqLJVgZLcRduoszARzRi12qGheUTWAHFf3ixMeGm2xML')
+            "#,
+            AGREEMENT_ID.encode_hex(),
+            SENDER.1.encode_hex(),
+            INDEXER.1.encode_hex(),
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        store_voucher(&pgpool, AGREEMENT_ID, SENDER.1, 1, 10).await;
+        store_voucher(&pgpool, AGREEMENT_ID, SENDER.1, 2, 20).await;
+        // Signed by a signer of SENDER, but paid by another payer
+        store_voucher(&pgpool, AGREEMENT_ID, SENDER_2.1, 3, 40).await;
+        // Of an agreement not recorded yet, kept pending
+        store_voucher(&pgpool, FixedBytes([0x43u8; 16]), SENDER.1, 4, 80).await;
+
+        let config = Box::leak(Box::new(config::Config {
+            ethereum: config::Ethereum {
+                indexer_address: INDEXER.1,
+            },
+            tap: config::Tap {
+                rav_request_timestamp_buffer_ms: 1,
+                rav_request_receipt_limit: 1000,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let collector = DipsCollector {
+            pgpool,
+            config,
+            escrow_accounts: Eventual::from_value(EscrowAccounts::new(
+                HashMap::from([(SENDER.1, U256::from(1000))]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )),
+            domain_separator: dips::eip712_domain(1, Address::from([0x44u8; 20])),
+            aggregators: HashMap::new(),
+        };
+        let now_ns = 1_000_000_000;
+
+        let mut fees = collector.unaggregated_fees(now_ns).await.unwrap();
+        fees.sort_by_key(|fees| fees.value);
+        assert_eq!(fees.len(), 2);
+        assert_eq!(
+            (fees[0].payer, fees[0].value, fees[0].count),
+            (SENDER.1, 30, 2)
+        );
+        assert_eq!(fees[0].oldest_timestamp_ns, 2);
+        assert_eq!((fees[1].payer, fees[1].value), (SENDER_2.1, 40));
+
+        let agreement = collector.agreement(AGREEMENT_ID).await.unwrap().unwrap();
+        for (fees, valid, invalid) in [(&fees[0], 2, 0), (&fees[1], 0, 1)] {
+            let vouchers = collector.fetch_vouchers(fees, &None, now_ns).await.unwrap();
+            let (valid_vouchers, invalid_vouchers) = collector
+                .check_vouchers(fees, &agreement, vouchers)
+                .await
+                .unwrap();
+            assert_eq!(valid_vouchers.len(), valid);
+            assert_eq!(invalid_vouchers.len(), invalid);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Escrow top-up suggestions, to coordinate the deposits of the senders with their fees.
+//!
+//! A sender is denied once its pending RAVs and unaggregated fees reach its escrow balance. Its
+//! suggested top-up is the smallest deposit keeping it under that threshold for the next
+//! `tap.escrow_top_up.horizon_secs`, if its fees keep arriving as fast as they did over the last
+//! `tap.escrow_top_up.velocity_window_secs`. It's reported by the status API and the sender API,
+//! added to the payloads of the lifecycle hooks, and exported as
+//! `tap_escrow_top_up_suggestion_grt`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Latest suggestion of each running `SenderAccount`, for [`suggestion`].
+    static ref SUGGESTIONS: RwLock<HashMap<Address, u128>> = RwLock::new(HashMap::new());
+}
+
+/// Number of buckets the fees of the window are summed in.
+const BUCKETS: u32 = 60;
+
+/// Fees received over a sliding window.
+pub struct FeeVelocity {
+    window: Duration,
+    bucket: Duration,
+    /// Start and total fees of each bucket, oldest first.
+    buckets: VecDeque<(Instant, u128)>,
+}
+
+impl FeeVelocity {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            bucket: window / BUCKETS,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, now: Instant, value: u128) {
+        match self.buckets.back_mut() {
+            Some((start, total)) if now.duration_since(*start) < self.bucket => {
+                *total += value;
+            }
+            _ => self.buckets.push_back((now, value)),
+        }
+        self.expire(now);
+    }
+
+    /// Average fees per second over the window.
+    pub fn per_second(&self, now: Instant) -> f64 {
+        if self.window.is_zero() {
+            return 0.0;
+        }
+        let total: u128 = self
+            .buckets
+            .iter()
+            .filter(|(start, _)| now.duration_since(*start) < self.window)
+            .map(|(_, total)| total)
+            .sum();
+        total as f64 / self.window.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now.duration_since(*start) < self.window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+/// Records the latest suggestion of the sender, as its deny condition is evaluated.
+pub fn publish(sender: Address, top_up: u128) {
+    SUGGESTIONS.write().unwrap().insert(sender, top_up);
+}
+
+pub fn remove(sender: &Address) {
+    SUGGESTIONS.write().unwrap().remove(sender);
+}
+
+/// Latest suggestion of the sender, unset if its `SenderAccount` isn't running.
+pub fn suggestion(sender: &Address) -> Option<u128> {
+    SUGGESTIONS.read().unwrap().get(sender).copied()
+}
+
+/// Smallest top-up keeping `pending_fees` plus the fees expected over `horizon` under the
+/// escrow balance.
+pub fn top_up(
+    escrow_balance: u128,
+    pending_fees: u128,
+    fees_per_second: f64,
+    horizon: Duration,
+) -> u128 {
+    let expected_fees = (fees_per_second * horizon.as_secs_f64()) as u128;
+    let needed = pending_fees.saturating_add(expected_fees);
+    // The sender is denied once its fees reach its balance
+    if needed >= escrow_balance {
+        (needed - escrow_balance).saturating_add(1)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use alloy::primitives::Address;
+
+    use super::{publish, remove, suggestion, top_up, FeeVelocity};
+
+    #[test]
+    fn test_fee_velocity() {
+        let start = Instant::now();
+        let mut velocity = FeeVelocity::new(Duration::from_secs(60));
+        assert_eq!(velocity.per_second(start), 0.0);
+
+        velocity.add(start, 60);
+        velocity.add(start + Duration::from_millis(500), 60);
+        velocity.add(start + Duration::from_secs(30), 120);
+        assert_eq!(velocity.per_second(start + Duration::from_secs(30)), 4.0);
+
+        // The first two fees left the window
+        assert_eq!(velocity.per_second(start + Duration::from_secs(60)), 2.0);
+        assert_eq!(velocity.per_second(start + Duration::from_secs(90)), 0.0);
+
+        let mut disabled = FeeVelocity::new(Duration::ZERO);
+        disabled.add(start, 100);
+        assert_eq!(disabled.per_second(start), 0.0);
+    }
+
+    #[test]
+    fn test_top_up() {
+        let hour = Duration::from_secs(3600);
+        // Enough for the pending fees and the next hour
+        assert_eq!(top_up(10_000, 1_000, 1.0, hour), 0);
+        // The balance must stay above the fees
+        assert_eq!(top_up(4_600, 1_000, 1.0, hour), 1);
+        assert_eq!(top_up(4_000, 1_000, 1.0, hour), 601);
+        // No new fees, only the pending ones
+        assert_eq!(top_up(500, 1_000, 0.0, hour), 501);
+        assert_eq!(top_up(0, u128::MAX, 1.0, hour), u128::MAX);
+    }
+
+    #[test]
+    fn test_suggestion() {
+        let sender = Address::repeat_byte(0x35);
+        assert_eq!(suggestion(&sender), None);
+        publish(sender, 601);
+        assert_eq!(suggestion(&sender), Some(601));
+        remove(&sender);
+        assert_eq!(suggestion(&sender), None);
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Notification of the lifecycle events of the allocations, see `tap.lifecycle_hooks`.
+//!
+//! The actors only queue the events with [`notify`], a task started by [`start`] notifies them
+//! one at a time, so that a slow hook doesn't hold back the RAV requests. The JSON payload of an
+//! event is passed on the standard input of the command, and POSTed to the URL. A failing hook
+//! is logged and not retried, and the events are dropped while the queue is full.
+//!
+//! The events are notified by the tap-agent observing them, they can be notified again after a
+//! restart, as for the `allocation_closing` of an allocation whose last RAV wasn't obtained yet.
+
+use std::{
+    io::{ErrorKind, Write},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::primitives::Address;
+use anyhow::{anyhow, Context, Result};
+use serde::{Serialize, Serializer};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::escrow_top_up;
+use crate::config::{AllocationEvent, LifecycleHooks};
+
+const QUEUE_SIZE: usize = 1000;
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static QUEUE: OnceLock<mpsc::Sender<Payload>> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize)]
+struct Payload {
+    #[serde(serialize_with = "event_name")]
+    event: AllocationEvent,
+    sender: Address,
+    allocation_id: Address,
+    /// Of the RAV, for `last_rav` and `rav_finalized`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_aggregate: Option<String>,
+    /// Suggested escrow top-up of the sender, see [`crate::agent::escrow_top_up`]. Unset if its
+    /// `SenderAccount` isn't running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow_top_up: Option<String>,
+    /// Milliseconds since the UNIX epoch.
+    at_ms: u64,
+}
+
+fn event_name<S: Serializer>(event: &AllocationEvent, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(match event {
+        AllocationEvent::AllocationCreated => "allocation_created",
+        AllocationEvent::AllocationClosing => "allocation_closing",
+        AllocationEvent::LastRav => "last_rav",
+        AllocationEvent::RavFinalized => "rav_finalized",
+    })
+}
+
+/// Starts notifying the events queued by [`notify`].
+pub fn start(http_client: reqwest::Client, config: LifecycleHooks) {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    if QUEUE.set(sender).is_ok() {
+        tokio::spawn(run(http_client, config, receiver));
+    }
+}
+
+/// Queues an event, a no-op unless `tap.lifecycle_hooks` is set.
+pub fn notify(
+    event: AllocationEvent,
+    sender: Address,
+    allocation_id: Address,
+    value_aggregate: Option<u128>,
+) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    let payload = Payload {
+        event,
+        sender,
+        allocation_id,
+        value_aggregate: value_aggregate.map(|value| value.to_string()),
+        escrow_top_up: escrow_top_up::suggestion(&sender).map(|top_up| top_up.to_string()),
+        at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    };
+    if queue.try_send(payload).is_err() {
+        warn!(
+            ?event,
+            %sender,
+            %allocation_id,
+            "The lifecycle hooks are falling behind, dropping the event."
+        );
+    }
+}
+
+async fn run(
+    http_client: reqwest::Client,
+    config: LifecycleHooks,
+    mut receiver: mpsc::Receiver<Payload>,
+) {
+    while let Some(payload) = receiver.recv().await {
+        if config
+            .events
+            .as_ref()
+            .is_some_and(|events| !events.contains(&payload.event))
+        {
+            continue;
+        }
+        if let Err(error) = notify_hooks(&http_client, &config, &payload).await {
+            warn!(
+                %error,
+                event = ?payload.event,
+                sender = %payload.sender,
+                allocation_id = %payload.allocation_id,
+                "Lifecycle hook failed."
+            );
+        }
+    }
+}
+
+async fn notify_hooks(
+    http_client: &reqwest::Client,
+    config: &LifecycleHooks,
+    payload: &Payload,
+) -> Result<()> {
+    let body = serde_json::to_vec(payload)?;
+    if let Some(command) = &config.command {
+        let command = command.clone();
+        let body = body.clone();
+        let timeout = config.timeout;
+        tokio::task::spawn_blocking(move || run_command(&command, &body, timeout)).await??;
+    }
+    if let Some(url) = &config.url {
+        http_client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(config.timeout)
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to post the event to {url}"))?;
+    }
+    debug!(event = ?payload.event, "Lifecycle hooks notified.");
+    Ok(())
+}
+
+/// Runs `command` with `body` on its standard input, killing it after `timeout`.
+fn run_command(command: &[String], body: &[u8], timeout: Duration) -> Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("The command is empty"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run `{program}`"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command doesn't have to read the payload
+        match stdin.write_all(body) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!("`{program}` exited with {status}"))
+            };
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "`{program}` timed out after {}s",
+                timeout.as_secs_f64()
+            ));
+        }
+        std::thread::sleep(COMMAND_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::tap::test_utils::{ALLOCATION_ID_0, SENDER};
+
+    fn payload() -> Payload {
+        Payload {
+            event: AllocationEvent::LastRav,
+            sender: SENDER.1,
+            allocation_id: *ALLOCATION_ID_0,
+            value_aggregate: Some("1000".to_string()),
+            escrow_top_up: Some("500".to_string()),
+            at_ms: 1,
+        }
+    }
+
+    #[test]
+    fn test_run_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("event.json");
+        let command = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "cat > \"$0\"".to_string(),
+            path.to_string_lossy().to_string(),
+        ];
+        let body = serde_json::to_vec(&payload()).unwrap();
+        run_command(&command, &body, Duration::from_secs(5)).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["event"], "last_rav");
+        assert_eq!(written["value_aggregate"], "1000");
+        assert_eq!(written["escrow_top_up"], "500");
+
+        let failing = vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()];
+        assert!(run_command(&failing, &body, Duration::from_secs(5)).is_err());
+
+        let slow = vec!["sleep".to_string(), "5".to_string()];
+        let start = Instant::now();
+        assert!(run_command(&slow, &body, Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_post_event() {
+        let mock_server = MockServer::start().await;
+        let payload = payload();
+        Mock::given(method("POST"))
+            .and(body_json(&payload))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let config = LifecycleHooks {
+            command: None,
+            url: Some(mock_server.uri().parse().unwrap()),
+            events: None,
+            timeout: Duration::from_secs(5),
+        };
+        notify_hooks(&reqwest::Client::new(), &config, &payload)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Quarantine of the `SenderAllocation`s that keep panicking.
+//!
+//! A `SenderAccount` recreates the `SenderAllocation`s that panicked. When the cause persists,
+//! such as a receipt that can't be decoded, that becomes a crash-restart loop. Once an allocation
+//! panicked more than `tap.supervision.max_allocation_restarts` times within
+//! `tap.supervision.restart_window_secs`, it's quarantined instead. The restarts before are
+//! counted in `tap_sender_allocation_restarts_total`.
+//!
+//! Once quarantined:
+//! - its `SenderAllocation` isn't recreated, and no RAV is requested for it. Its receipts stay in
+//!   the database, and its fees keep counting towards the deny condition of the sender.
+//! - it's reported in the `tap_allocation_quarantined` metric and in [`crate::status`], and
+//!   logged with the `allocation_quarantined` event, for alerting.
+//! - it stays quarantined until resumed through the admin API, see [`crate::admin`], or until its
+//!   `SenderAccount` is restarted.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use thiserror::Error;
+
+use crate::config::Supervision;
+
+#[derive(Debug, Error)]
+pub enum ResumeError {
+    #[error("Allocation {0} is not quarantined")]
+    NotQuarantined(Address),
+    #[error("Failed to start the SenderAllocation of allocation {0}: {1}")]
+    StartFailed(Address, String),
+}
+
+pub struct Quarantine {
+    max_restarts: usize,
+    window: Duration,
+    /// Restarts within the window, oldest first.
+    restarts: HashMap<Address, VecDeque<Instant>>,
+    quarantined: HashSet<Address>,
+}
+
+impl Quarantine {
+    pub fn new(config: &Supervision) -> Self {
+        Self {
+            max_restarts: config.max_allocation_restarts as usize,
+            window: config.restart_window,
+            restarts: HashMap::new(),
+            quarantined: HashSet::new(),
+        }
+    }
+
+    /// Records that the `SenderAllocation` of `allocation_id` panicked at `now`. Returns `true`
+    /// if it's quarantined rather than restarted.
+    pub fn record_panic(&mut self, allocation_id: Address, now: Instant) -> bool {
+        let restarts = self.restarts.entry(allocation_id).or_default();
+        while restarts
+            .front()
+            .is_some_and(|restart| now.saturating_duration_since(*restart) >= self.window)
+        {
+            restarts.pop_front();
+        }
+        if restarts.len() >= self.max_restarts {
+            self.restarts.remove(&allocation_id);
+            self.quarantined.insert(allocation_id);
+            return true;
+        }
+        restarts.push_back(now);
+        false
+    }
+
+    pub fn is_quarantined(&self, allocation_id: &Address) -> bool {
+        self.quarantined.contains(allocation_id)
+    }
+
+    /// Lifts the quarantine of `allocation_id`, with a clean restart history. Returns `false` if
+    /// it wasn't quarantined.
+    pub fn resume(&mut self, allocation_id: &Address) -> bool {
+        self.quarantined.remove(allocation_id)
+    }
+
+    pub fn quarantined(&self) -> impl Iterator<Item = &Address> {
+        self.quarantined.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Quarantine;
+    use crate::{
+        config::Supervision,
+        tap::test_utils::{ALLOCATION_ID_0, ALLOCATION_ID_1},
+    };
+
+    #[test]
+    fn test_record_panic() {
+        let mut quarantine = Quarantine::new(&Supervision {
+            max_allocation_restarts: 2,
+            restart_window: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Restarted twice, the panics are counted per allocation
+        assert!(!quarantine.record_panic(*ALLOCATION_ID_0, at(0)));
+        assert!(!quarantine.record_panic(*ALLOCATION_ID_1, at(1)));
+        assert!(!quarantine.record_panic(*ALLOCATION_ID_0, at(10)));
+
+        // The first restart is out of the window
+        assert!(!quarantine.record_panic(*ALLOCATION_ID_0, at(60)));
+        assert!(!quarantine.is_quarantined(&ALLOCATION_ID_0));
+
+        assert!(quarantine.record_panic(*ALLOCATION_ID_0, at(65)));
+        assert!(quarantine.is_quarantined(&ALLOCATION_ID_0));
+        assert!(!quarantine.is_quarantined(&ALLOCATION_ID_1));
+        assert_eq!(
+            quarantine.quarantined().collect::<Vec<_>>(),
+            vec![&*ALLOCATION_ID_0]
+        );
+
+        // Resumed with a clean history
+        assert!(quarantine.resume(&ALLOCATION_ID_0));
+        assert!(!quarantine.resume(&ALLOCATION_ID_0));
+        assert!(!quarantine.record_panic(*ALLOCATION_ID_0, at(66)));
+        assert!(!quarantine.is_quarantined(&ALLOCATION_ID_0));
+    }
+}
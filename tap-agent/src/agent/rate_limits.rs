@@ -0,0 +1,170 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rate limits of the senders' receipts, applied by indexer-service through its
+//! `sender_rate_limit` check.
+//!
+//! Every `tap.rate_limits.interval_secs`, each `SenderAccount` writes the token bucket of its
+//! sender's fees in `tap_sender_rate_limits`, from its escrow headroom, what's left of its escrow
+//! balance once its pending RAVs and unaggregated fees are paid:
+//! - it refills at `tap.rate_limits.velocity_multiplier` times the fee velocity of the sender,
+//!   see [`crate::agent::escrow_top_up`], or at its headroom spread over
+//!   `tap.rate_limits.horizon_secs` if more, so that a sender without recent fees isn't starved.
+//! - it holds up to `tap.rate_limits.burst_secs` of that rate, never more than the headroom.
+//!
+//! A sender that spent its headroom gets an empty bucket, until a RAV is redeemed or it deposits
+//! again. The service only sees the bucket of the last update, so it may take in up to an
+//! interval of refill more than the headroom: the deny condition remains the hard limit. It
+//! ignores the rows not updated for a few intervals, and divides the buckets between its
+//! `tap.rate_limits.service_replicas` replicas.
+//!
+//! The row of a sender is deleted once its `SenderAccount` stops, or starts with the rate limits
+//! disabled.
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use bigdecimal::num_bigint::BigInt;
+use sqlx::{types::BigDecimal, PgPool};
+
+use crate::{
+    config::RateLimits,
+    database::{self, Subsystem},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub capacity: u128,
+    pub refill_per_second: u128,
+}
+
+/// The bucket of a sender with `headroom` left, receiving `fees_per_second`.
+pub fn rate_limit(config: &RateLimits, headroom: u128, fees_per_second: f64) -> RateLimit {
+    if headroom == 0 {
+        return RateLimit {
+            capacity: 0,
+            refill_per_second: 0,
+        };
+    }
+    let refill_per_second = (fees_per_second * config.velocity_multiplier)
+        .max(headroom as f64 / config.horizon.as_secs_f64());
+    RateLimit {
+        capacity: ((refill_per_second * config.burst.as_secs_f64()) as u128).min(headroom),
+        refill_per_second: refill_per_second as u128,
+    }
+}
+
+pub async fn publish(
+    pgpool: &PgPool,
+    sender: Address,
+    limit: RateLimit,
+) -> Result<(), sqlx::Error> {
+    database::acquire(pgpool, Subsystem::Denylist)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    INSERT INTO tap_sender_rate_limits
+                        (sender_address, capacity, refill_per_second, updated_at)
+                    VALUES ($1, $2, $3, NOW())
+                    ON CONFLICT (sender_address) DO UPDATE SET
+                        capacity = EXCLUDED.capacity,
+                        refill_per_second = EXCLUDED.refill_per_second,
+                        updated_at = EXCLUDED.updated_at
+                "#,
+                sender.encode_hex(),
+                BigDecimal::from(BigInt::from(limit.capacity)),
+                BigDecimal::from(BigInt::from(limit.refill_per_second))
+            )
+            .execute(conn)
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn remove(pgpool: &PgPool, sender: Address) -> Result<(), sqlx::Error> {
+    database::acquire(pgpool, Subsystem::Denylist)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                "DELETE FROM tap_sender_rate_limits WHERE sender_address = $1",
+                sender.encode_hex()
+            )
+            .execute(conn)
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use alloy::hex::ToHexExt;
+    use sqlx::{types::BigDecimal, PgPool};
+
+    use super::{publish, rate_limit, remove, RateLimit};
+    use crate::{config::RateLimits, tap::test_utils::SENDER};
+
+    fn config() -> RateLimits {
+        RateLimits {
+            interval: Duration::from_secs(10),
+            horizon: Duration::from_secs(100),
+            velocity_multiplier: 2.0,
+            burst: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        // Without recent fees, the headroom is spread over the horizon
+        assert_eq!(
+            rate_limit(&config(), 10_000, 0.0),
+            RateLimit {
+                capacity: 1_000,
+                refill_per_second: 100,
+            }
+        );
+        // Twice the fee velocity, when more
+        assert_eq!(
+            rate_limit(&config(), 10_000, 200.0),
+            RateLimit {
+                capacity: 4_000,
+                refill_per_second: 400,
+            }
+        );
+        // The burst never exceeds the headroom
+        assert_eq!(rate_limit(&config(), 1_000, 200.0).capacity, 1_000);
+        assert_eq!(
+            rate_limit(&config(), 0, 200.0),
+            RateLimit {
+                capacity: 0,
+                refill_per_second: 0,
+            }
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_publish_and_remove(pgpool: PgPool) {
+        let sender = SENDER.1;
+        let limit = |capacity| RateLimit {
+            capacity,
+            refill_per_second: 10,
+        };
+        let capacity = || async {
+            sqlx::query_scalar!(
+                "SELECT capacity FROM tap_sender_rate_limits WHERE sender_address = $1",
+                sender.encode_hex()
+            )
+            .fetch_optional(&pgpool)
+            .await
+            .unwrap()
+        };
+
+        publish(&pgpool, sender, limit(100)).await.unwrap();
+        assert_eq!(capacity().await, Some(BigDecimal::from(100)));
+        publish(&pgpool, sender, limit(50)).await.unwrap();
+        assert_eq!(capacity().await, Some(BigDecimal::from(50)));
+
+        remove(&pgpool, sender).await.unwrap();
+        assert_eq!(capacity().await, None);
+    }
+}
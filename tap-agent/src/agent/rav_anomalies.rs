@@ -0,0 +1,315 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Early warning of accounting anomalies, from the trend of each sender's RAVs and receipts.
+//!
+//! Two series are followed per sender:
+//! - the value aggregated by each RAV, the difference between its value and the one of the
+//!   previous RAV of the allocation.
+//! - the receipt rate, the receipts received per [`RATE_INTERVAL`].
+//!
+//! Each new value is compared with the ones of the last `tap.rav_anomalies.window_secs` by its
+//! z-score, its distance to their mean in standard deviations. Beyond
+//! `tap.rav_anomalies.z_score_threshold`, it's logged and counted in
+//! `tap_sender_rav_anomalies_total`: a drop may be receipts getting lost, a spike receipts
+//! duplicated or an attack. Nothing is flagged before [`MIN_SAMPLES`] values were seen.
+//!
+//! The receipt rate is sampled when an interval is over, by the sender account every
+//! [`RATE_INTERVAL`], so a sender that stopped sending receipts altogether is flagged as it
+//! stops. A gap longer than the window is flagged once, as a single drop.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tracing::warn;
+
+use super::trust_score::Samples;
+use crate::{config::RavAnomalies, logging::event};
+
+lazy_static! {
+    static ref RAV_ANOMALIES: IntCounterVec = register_int_counter_vec!(
+        "tap_sender_rav_anomalies_total",
+        "RAV values and receipt rates of the sender far from their recent trend",
+        &["sender", "series", "direction"]
+    )
+    .unwrap();
+}
+
+/// Interval of the receipt rate samples.
+pub const RATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Values needed in the window before any is flagged.
+pub const MIN_SAMPLES: usize = 10;
+
+/// The standard deviation of a constant series is taken as this fraction of its mean, so that
+/// moving away from it is still flagged.
+const MIN_RELATIVE_DEVIATION: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Series {
+    RavValue,
+    ReceiptRate,
+}
+
+impl Series {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Series::RavValue => "rav_value",
+            Series::ReceiptRate => "receipt_rate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Drop,
+    Spike,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Drop => "drop",
+            Direction::Spike => "spike",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anomaly {
+    pub series: Series,
+    pub direction: Direction,
+    pub value: f64,
+    pub z_score: f64,
+}
+
+pub struct RavAnomalyDetector {
+    sender: Address,
+    window: Duration,
+    z_score_threshold: f64,
+    rav_values: Samples<f64>,
+    receipt_rates: Samples<f64>,
+    /// Value of the last RAV of each allocation.
+    last_rav_values: HashMap<Address, u128>,
+    rate_interval_start: Instant,
+    rate_interval_receipts: u64,
+}
+
+impl RavAnomalyDetector {
+    pub fn new(config: &RavAnomalies, sender: Address, now: Instant) -> Self {
+        Self {
+            sender,
+            window: config.window,
+            z_score_threshold: config.z_score_threshold,
+            rav_values: Samples::new(),
+            receipt_rates: Samples::new(),
+            last_rav_values: HashMap::new(),
+            rate_interval_start: now,
+            rate_interval_receipts: 0,
+        }
+    }
+
+    pub fn record_receipts(&mut self, now: Instant, count: u64) -> Vec<Anomaly> {
+        let anomalies = self.close_rate_intervals(now);
+        self.rate_interval_receipts += count;
+        anomalies
+    }
+
+    /// Records a RAV of the allocation. Its first one is only remembered, the value it
+    /// aggregated isn't known.
+    pub fn record_rav(
+        &mut self,
+        now: Instant,
+        allocation_id: Address,
+        value_aggregate: u128,
+    ) -> Vec<Anomaly> {
+        let mut anomalies = self.close_rate_intervals(now);
+        if let Some(previous) = self.last_rav_values.insert(allocation_id, value_aggregate) {
+            let value = value_aggregate.saturating_sub(previous) as f64;
+            anomalies.extend(self.observe(Series::RavValue, now, value));
+        }
+        anomalies
+    }
+
+    /// Samples the receipt rate of the intervals over by `now`, without any new receipt.
+    pub fn tick(&mut self, now: Instant) -> Vec<Anomaly> {
+        self.close_rate_intervals(now)
+    }
+
+    pub fn remove_allocation(&mut self, allocation_id: &Address) {
+        self.last_rav_values.remove(allocation_id);
+    }
+
+    /// Removes the metrics of the sender, when its account stops.
+    pub fn remove_series(&self) {
+        let sender = self.sender.to_string();
+        for series in [Series::RavValue, Series::ReceiptRate] {
+            for direction in [Direction::Drop, Direction::Spike] {
+                let _ = RAV_ANOMALIES.remove_label_values(&[
+                    &sender,
+                    series.as_str(),
+                    direction.as_str(),
+                ]);
+            }
+        }
+    }
+
+    /// Samples the receipt rate of the intervals over by `now`.
+    fn close_rate_intervals(&mut self, now: Instant) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        while now.saturating_duration_since(self.rate_interval_start) >= RATE_INTERVAL {
+            self.rate_interval_start += RATE_INTERVAL;
+            let rate = std::mem::take(&mut self.rate_interval_receipts) as f64;
+            anomalies.extend(self.observe(Series::ReceiptRate, self.rate_interval_start, rate));
+            // The rest of a gap longer than the window is a single drop, not one per interval
+            let idle = now.saturating_duration_since(self.rate_interval_start);
+            if rate == 0.0 && idle > self.window {
+                let skipped = (idle.as_nanos() / RATE_INTERVAL.as_nanos()) as u32;
+                self.rate_interval_start += RATE_INTERVAL * skipped;
+            }
+        }
+        anomalies
+    }
+
+    /// Compares `value` with the previous ones of the series, then adds it to them.
+    fn observe(&mut self, series: Series, now: Instant, value: f64) -> Option<Anomaly> {
+        let samples = match series {
+            Series::RavValue => &mut self.rav_values,
+            Series::ReceiptRate => &mut self.receipt_rates,
+        };
+        let previous: Vec<f64> = samples.within(now, self.window).collect();
+        samples.push(now, value, self.window);
+        if previous.len() < MIN_SAMPLES {
+            return None;
+        }
+
+        let mean = previous.iter().sum::<f64>() / previous.len() as f64;
+        let variance =
+            previous.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / previous.len() as f64;
+        let deviation = variance.sqrt().max(mean.abs() * MIN_RELATIVE_DEVIATION);
+        if deviation == 0.0 {
+            return None;
+        }
+        let z_score = (value - mean) / deviation;
+        if z_score.abs() < self.z_score_threshold {
+            return None;
+        }
+
+        let anomaly = Anomaly {
+            series,
+            direction: if z_score < 0.0 {
+                Direction::Drop
+            } else {
+                Direction::Spike
+            },
+            value,
+            z_score,
+        };
+        warn!(
+            event = event::RAV_ANOMALY,
+            sender = %self.sender,
+            series = series.as_str(),
+            direction = anomaly.direction.as_str(),
+            value,
+            mean,
+            z_score,
+            "{} of the sender far from its recent trend",
+            match series {
+                Series::RavValue => "RAV value",
+                Series::ReceiptRate => "Receipt rate",
+            }
+        );
+        RAV_ANOMALIES
+            .with_label_values(&[
+                &self.sender.to_string(),
+                series.as_str(),
+                anomaly.direction.as_str(),
+            ])
+            .inc();
+        Some(anomaly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use alloy::primitives::Address;
+
+    use super::{Direction, RavAnomalyDetector, Series, MIN_SAMPLES, RATE_INTERVAL};
+    use crate::config::RavAnomalies;
+
+    #[test]
+    fn test_rav_value_anomalies() {
+        let start = Instant::now();
+        let allocation_id = Address::repeat_byte(0x01);
+        let mut detector = RavAnomalyDetector::new(&RavAnomalies::default(), Address::ZERO, start);
+
+        // Steady 100 per RAV, with some noise
+        let mut value = 0;
+        let mut now = start;
+        for i in 0..=MIN_SAMPLES as u128 {
+            value += 100 + i % 3;
+            now += Duration::from_secs(1);
+            assert!(detector.record_rav(now, allocation_id, value).is_empty());
+        }
+
+        value += 10_000;
+        let anomalies = detector.record_rav(now, allocation_id, value);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].series, Series::RavValue);
+        assert_eq!(anomalies[0].direction, Direction::Spike);
+
+        value += 1;
+        let anomalies = detector.record_rav(now, allocation_id, value);
+        assert_eq!(anomalies.len(), 0, "the spike widened the deviation");
+    }
+
+    #[test]
+    fn test_receipt_rate_anomalies() {
+        let start = Instant::now();
+        let mut detector = RavAnomalyDetector::new(&RavAnomalies::default(), Address::ZERO, start);
+
+        // 1000 receipts per interval
+        for i in 0..=MIN_SAMPLES as u32 {
+            assert!(detector
+                .record_receipts(start + RATE_INTERVAL * i, 1000)
+                .is_empty());
+        }
+        // 10 receipts in the next interval
+        let now = start + RATE_INTERVAL * (MIN_SAMPLES as u32 + 1);
+        assert!(detector.record_receipts(now, 10).is_empty());
+        let anomalies = detector.record_receipts(now + RATE_INTERVAL, 1000);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].series, Series::ReceiptRate);
+        assert_eq!(anomalies[0].direction, Direction::Drop);
+        assert_eq!(anomalies[0].value, 10.0);
+    }
+
+    #[test]
+    fn test_long_gap_anomaly() {
+        let start = Instant::now();
+        let mut detector = RavAnomalyDetector::new(&RavAnomalies::default(), Address::ZERO, start);
+
+        // 1000 receipts per interval
+        for i in 0..=MIN_SAMPLES as u32 {
+            assert!(detector
+                .record_receipts(start + RATE_INTERVAL * i, 1000)
+                .is_empty());
+        }
+        // No receipt at all for much longer than the window, only the timer ticks
+        let last = start + RATE_INTERVAL * MIN_SAMPLES as u32;
+        let anomalies = detector.tick(last + RavAnomalies::default().window * 3);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].series, Series::ReceiptRate);
+        assert_eq!(anomalies[0].direction, Direction::Drop);
+        assert_eq!(anomalies[0].value, 0.0);
+
+        detector.remove_series();
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! RAV requests in flight, recorded in `scalar_tap_rav_request_intents` so that a restart doesn't
+//! lose track of them.
+//!
+//! Before sending a RAV request to the sender's TAP aggregator, the `SenderAllocation` records
+//! its intent: the range of timestamps of the receipts in the request and the value it expects.
+//! The intent is deleted once the response is handled, whether the RAV was stored or not.
+//!
+//! An intent left behind means tap-agent stopped with the request in flight. When the
+//! allocation is picked up again, before its unaggregated fees are computed, its intents are
+//! reconciled against its last stored RAV:
+//! - if the RAV covers the receipts of the intent, the response was stored before the restart,
+//!   and the receipts aren't counted again.
+//! - otherwise the response was lost, the receipts are still unaggregated and are requested
+//!   again with the next RAV, from the same previous RAV.
+//!
+//! Either way the outcome is logged and counted in `tap_rav_intents_reconciled_total`. Only the
+//! legacy receipts are tracked, not the Horizon ones.
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use bigdecimal::{
+    num_bigint::{BigInt, ToBigInt},
+    ToPrimitive,
+};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use sqlx::{types::BigDecimal, PgPool};
+use tracing::{info, warn};
+
+use crate::database::{self, Subsystem};
+
+lazy_static! {
+    static ref RAV_INTENTS_RECONCILED: IntCounterVec = register_int_counter_vec!(
+        "tap_rav_intents_reconciled_total",
+        "RAV requests found in flight on startup, by whether their RAV was stored",
+        &["sender", "outcome"]
+    )
+    .unwrap();
+}
+
+/// A RAV request about to be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RavIntent {
+    pub min_timestamp_ns: u64,
+    pub max_timestamp_ns: u64,
+    pub receipt_count: u64,
+    pub expected_value_aggregate: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The RAV was stored before the restart.
+    Stored,
+    /// The response was lost, the receipts are still unaggregated.
+    Lost,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Stored => "stored",
+            Outcome::Lost => "lost",
+        }
+    }
+}
+
+/// Records the intent, returns its id for [`complete`].
+pub async fn record(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    sender: Address,
+    intent: &RavIntent,
+) -> Result<i64, sqlx::Error> {
+    database::acquire(pgpool, Subsystem::RavStore)
+        .await?
+        .run(|conn| {
+            sqlx::query_scalar!(
+                r#"
+                    INSERT INTO scalar_tap_rav_request_intents (
+                        sender_address,
+                        allocation_id,
+                        min_timestamp_ns,
+                        max_timestamp_ns,
+                        receipt_count,
+                        expected_value_aggregate
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    RETURNING id
+                "#,
+                sender.encode_hex(),
+                allocation_id.encode_hex(),
+                BigDecimal::from(intent.min_timestamp_ns),
+                BigDecimal::from(intent.max_timestamp_ns),
+                intent.receipt_count as i64,
+                BigDecimal::from(BigInt::from(intent.expected_value_aggregate))
+            )
+            .fetch_one(conn)
+        })
+        .await
+}
+
+/// Deletes the intent once the response of its request is handled.
+pub async fn complete(pgpool: &PgPool, id: i64) -> Result<(), sqlx::Error> {
+    database::acquire(pgpool, Subsystem::RavStore)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                "DELETE FROM scalar_tap_rav_request_intents WHERE id = $1",
+                id
+            )
+            .execute(conn)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Deletes the intents left for the allocation, with the outcome of each against the timestamp
+/// of its last stored RAV.
+pub async fn reconcile(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    sender: Address,
+    last_rav_timestamp_ns: Option<u64>,
+) -> Result<Vec<(RavIntent, Outcome)>, sqlx::Error> {
+    let rows = database::acquire(pgpool, Subsystem::RavStore)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    DELETE FROM scalar_tap_rav_request_intents
+                    WHERE allocation_id = $1 AND sender_address = $2
+                    RETURNING
+                        min_timestamp_ns,
+                        max_timestamp_ns,
+                        receipt_count,
+                        expected_value_aggregate
+                "#,
+                allocation_id.encode_hex(),
+                sender.encode_hex()
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+
+    let mut reconciled = Vec::with_capacity(rows.len());
+    for row in rows {
+        let intent = RavIntent {
+            min_timestamp_ns: row.min_timestamp_ns.to_u64().unwrap_or_default(),
+            max_timestamp_ns: row.max_timestamp_ns.to_u64().unwrap_or_default(),
+            receipt_count: row.receipt_count as u64,
+            expected_value_aggregate: row
+                .expected_value_aggregate
+                .to_bigint()
+                .and_then(|v| v.to_u128())
+                .unwrap_or_default(),
+        };
+        // The RAV takes the timestamp of the last receipt it covers
+        let outcome = match last_rav_timestamp_ns {
+            Some(timestamp_ns) if timestamp_ns >= intent.max_timestamp_ns => Outcome::Stored,
+            _ => Outcome::Lost,
+        };
+        match outcome {
+            Outcome::Stored => info!(
+                %sender,
+                %allocation_id,
+                ?intent,
+                "RAV request in flight before the restart was stored."
+            ),
+            Outcome::Lost => warn!(
+                %sender,
+                %allocation_id,
+                ?intent,
+                "RAV response lost on restart, its receipts will be requested again."
+            ),
+        }
+        RAV_INTENTS_RECONCILED
+            .with_label_values(&[&sender.to_string(), outcome.as_str()])
+            .inc();
+        reconciled.push((intent, outcome));
+    }
+    Ok(reconciled)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{complete, reconcile, record, Outcome, RavIntent};
+    use crate::tap::test_utils::{ALLOCATION_ID_0, SENDER};
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_reconcile(pgpool: PgPool) {
+        let intent = |min_timestamp_ns, max_timestamp_ns| RavIntent {
+            min_timestamp_ns,
+            max_timestamp_ns,
+            receipt_count: 10,
+            expected_value_aggregate: 100,
+        };
+
+        // Completed intents are gone
+        let id = record(&pgpool, *ALLOCATION_ID_0, SENDER.1, &intent(1, 10))
+            .await
+            .unwrap();
+        complete(&pgpool, id).await.unwrap();
+        assert!(reconcile(&pgpool, *ALLOCATION_ID_0, SENDER.1, None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        record(&pgpool, *ALLOCATION_ID_0, SENDER.1, &intent(1, 10))
+            .await
+            .unwrap();
+        record(&pgpool, *ALLOCATION_ID_0, SENDER.1, &intent(11, 20))
+            .await
+            .unwrap();
+        let mut reconciled = reconcile(&pgpool, *ALLOCATION_ID_0, SENDER.1, Some(10))
+            .await
+            .unwrap();
+        reconciled.sort_by_key(|(intent, _)| intent.min_timestamp_ns);
+        assert_eq!(
+            reconciled,
+            vec![
+                (intent(1, 10), Outcome::Stored),
+                (intent(11, 20), Outcome::Lost)
+            ]
+        );
+
+        // Reconciled once
+        assert!(reconcile(&pgpool, *ALLOCATION_ID_0, SENDER.1, Some(10))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Order in which a `SenderAccount` requests the RAVs of its allocations.
+//!
+//! When the fees of a sender reach the RAV request trigger, the allocation at the top of the queue
+//! gets its RAV requested. Its priority is the value of its fees outside the buffer, weighted by
+//! how much they're at risk of being lost:
+//! - multiplied by `1 + days unaggregated`, fees going without a RAV for long have been exposed
+//!   to the sender going away for that long already.
+//! - multiplied by [`CLOSING_WEIGHT`] if the allocation is closing soon, see
+//!   [`crate::agent::allocation_closure`], as whatever isn't aggregated by then is left to its
+//!   last RAV.
+//! - divided by `1 + failed RAV requests`, since an aggregator failing for an allocation is likely
+//!   to fail again, the other allocations get their RAV first rather than waiting behind it.
+//!
+//! The queue is rebuilt on each request, since the priorities change with time. Its content is
+//! served by the admin API, see [`crate::admin`].
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use serde::Serialize;
+
+use crate::money::wei;
+
+const CLOSING_WEIGHT: f64 = 4.0;
+const AGE_UNIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An allocation a RAV can be requested for, see
+/// [`crate::agent::sender_fee_tracker::SenderFeeTracker::rav_candidates`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RavCandidate {
+    pub allocation_id: Address,
+    pub fee_outside_buffer: u128,
+    /// Since the last successful RAV request.
+    pub failed_rav_requests: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RavQueueEntry {
+    pub allocation_id: Address,
+    #[serde(serialize_with = "wei")]
+    pub fee_outside_buffer: u128,
+    pub unaggregated_secs: u64,
+    pub closing: bool,
+    pub failed_rav_requests: u32,
+    pub priority: f64,
+}
+
+impl RavQueueEntry {
+    fn new(candidate: RavCandidate, unaggregated_for: Duration, closing: bool) -> Self {
+        let mut priority = candidate.fee_outside_buffer as f64
+            * (1.0 + unaggregated_for.as_secs_f64() / AGE_UNIT.as_secs_f64());
+        if closing {
+            priority *= CLOSING_WEIGHT;
+        }
+        priority /= 1.0 + candidate.failed_rav_requests as f64;
+        Self {
+            allocation_id: candidate.allocation_id,
+            fee_outside_buffer: candidate.fee_outside_buffer,
+            unaggregated_secs: unaggregated_for.as_secs(),
+            closing,
+            failed_rav_requests: candidate.failed_rav_requests,
+            priority,
+        }
+    }
+}
+
+/// Ordered by priority, then by fees and allocation id, so that the order is deterministic.
+impl Ord for RavQueueEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .total_cmp(&other.priority)
+            .then(self.fee_outside_buffer.cmp(&other.fee_outside_buffer))
+            .then(self.allocation_id.cmp(&other.allocation_id))
+    }
+}
+
+impl PartialOrd for RavQueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RavQueueEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RavQueueEntry {}
+
+/// The allocations of `candidates` by decreasing priority when popped.
+pub fn build(
+    candidates: Vec<RavCandidate>,
+    unaggregated_since: &HashMap<Address, Instant>,
+    closing_allocation_ids: &HashSet<Address>,
+    now: Instant,
+) -> BinaryHeap<RavQueueEntry> {
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let unaggregated_for = unaggregated_since
+                .get(&candidate.allocation_id)
+                .map(|since| now.saturating_duration_since(*since))
+                .unwrap_or_default();
+            let closing = closing_allocation_ids.contains(&candidate.allocation_id);
+            RavQueueEntry::new(candidate, unaggregated_for, closing)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::{Duration, Instant},
+    };
+
+    use alloy::primitives::{address, Address};
+
+    use super::{build, RavCandidate};
+
+    #[test]
+    fn test_priority() {
+        let allocation_0 = address!("abababababababababababababababababababab");
+        let allocation_1 = address!("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc");
+        let allocation_2 = address!("cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd");
+        let candidate = |allocation_id, fee_outside_buffer, failed_rav_requests| RavCandidate {
+            allocation_id,
+            fee_outside_buffer,
+            failed_rav_requests,
+        };
+        // Not subtracting from `Instant::now()`, which can underflow on a recently booted host
+        let since = Instant::now();
+        let now = since + Duration::from_secs(3 * 24 * 60 * 60);
+        let order = |candidates: Vec<RavCandidate>,
+                     unaggregated_since: HashMap<Address, Instant>,
+                     closing: HashSet<Address>| {
+            build(candidates, &unaggregated_since, &closing, now)
+                .into_sorted_vec()
+                .into_iter()
+                .rev()
+                .map(|entry| entry.allocation_id)
+                .collect::<Vec<_>>()
+        };
+
+        // The heaviest first
+        let candidates = vec![
+            candidate(allocation_0, 100, 0),
+            candidate(allocation_1, 300, 0),
+            candidate(allocation_2, 200, 0),
+        ];
+        assert_eq!(
+            order(candidates.clone(), HashMap::new(), HashSet::new()),
+            vec![allocation_1, allocation_2, allocation_0]
+        );
+
+        // 100 unaggregated for 3 days is worth 400
+        assert_eq!(
+            order(
+                candidates.clone(),
+                HashMap::from([(allocation_0, since)]),
+                HashSet::new()
+            ),
+            vec![allocation_0, allocation_1, allocation_2]
+        );
+
+        // Closing, 200 is worth 800
+        assert_eq!(
+            order(
+                candidates.clone(),
+                HashMap::new(),
+                HashSet::from([allocation_2])
+            ),
+            vec![allocation_2, allocation_1, allocation_0]
+        );
+
+        // Failing twice, 300 is worth 100
+        let candidates = vec![
+            candidate(allocation_0, 150, 0),
+            candidate(allocation_1, 300, 2),
+        ];
+        assert_eq!(
+            order(candidates, HashMap::new(), HashSet::new()),
+            vec![allocation_0, allocation_1]
+        );
+    }
+}
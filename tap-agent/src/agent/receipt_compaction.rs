@@ -0,0 +1,637 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sets aside the receipts already covered by a final RAV, so that `scalar_tap_receipts` only
+//! keeps the ones that may still be aggregated.
+//!
+//! Every interval, for each final RAV, the receipts of its allocation signed by a signer of its
+//! sender, up to the RAV timestamp, are moved to `scalar_tap_receipts_archive` or deleted, as
+//! configured by `tap.receipt_compaction.mode`. A RAV is only final once it was redeemed and the
+//! redemption can't be reverted anymore, so these receipts can't be needed for another RAV. As a
+//! safety check, the receipts of a RAV are left in place if they add up to more than the RAV, which
+//! would mean that they were signed for another sender. Senders without signers in the escrow
+//! accounts are skipped, as are the Horizon receipts.
+//!
+//! With `tap.receipt_compaction.dry_run`, nothing is set aside. Every interval, a
+//! [`CompactionReport`] of what would be is made instead, served by [`crate::admin`]. Once the
+//! operator approves it, the receipts it lists as covered by their RAV are set aside, up to the
+//! RAV timestamps of the report. The entries are verified again first: those whose RAV isn't
+//! final with the same timestamp anymore, or whose receipts now add up to more than it, are
+//! skipped.
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::Result;
+use bigdecimal::BigDecimal;
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use serde::Serialize;
+use sqlx::PgPool;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{
+    config::{self, ReceiptCompactionMode},
+    database::{self, Subsystem},
+};
+
+lazy_static! {
+    static ref RECEIPTS_ARCHIVED: CounterVec = register_counter_vec!(
+        "tap_receipts_archived_total",
+        "Receipts covered by a final RAV that were archived or deleted",
+        &["sender", "mode"]
+    )
+    .unwrap();
+    static ref LATEST_REPORT: Mutex<Option<CompactionReport>> = Mutex::new(None);
+    static ref APPROVAL: Notify = Notify::new();
+}
+
+static NEXT_REPORT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Whether the receipts of a final RAV add up to at most its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Coverage {
+    Covered,
+    /// They're kept, they were probably signed for another sender.
+    ExceedsRav,
+}
+
+/// The receipts of an allocation covered by a final RAV of the sender.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionEntry {
+    pub sender: Address,
+    pub allocation_id: String,
+    pub rav_timestamp_ns: String,
+    pub rav_value: String,
+    pub receipts: u64,
+    /// Size of the receipt rows, without the indexes.
+    pub bytes: u64,
+    pub receipts_value: String,
+    pub coverage: Coverage,
+    #[serde(skip)]
+    signers: Vec<String>,
+    #[serde(skip)]
+    timestamp_ns: BigDecimal,
+}
+
+/// What a compaction would set aside, for the operator to review.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionReport {
+    pub id: u64,
+    /// Seconds since the Unix epoch.
+    pub generated_at: u64,
+    pub mode: &'static str,
+    /// Receipts of the covered entries, the ones set aside once approved.
+    pub receipts: u64,
+    pub bytes: u64,
+    pub entries: Vec<CompactionEntry>,
+    pub approved: bool,
+    /// Receipts set aside after the approval.
+    pub compacted_receipts: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum ApproveError {
+    #[error("No receipt compaction report yet")]
+    NoReport,
+    #[error("Report {0} was replaced by report {1}")]
+    Replaced(u64, u64),
+    #[error("Report {0} was already approved")]
+    AlreadyApproved(u64),
+}
+
+/// The latest dry-run report.
+pub fn latest_report() -> Option<CompactionReport> {
+    LATEST_REPORT.lock().unwrap().clone()
+}
+
+/// Approves the report `id`, its receipts are set aside right away.
+pub fn approve(id: u64) -> Result<(), ApproveError> {
+    let mut latest = LATEST_REPORT.lock().unwrap();
+    let report = latest.as_mut().ok_or(ApproveError::NoReport)?;
+    if report.id != id {
+        return Err(ApproveError::Replaced(id, report.id));
+    }
+    if report.approved {
+        return Err(ApproveError::AlreadyApproved(id));
+    }
+    report.approved = true;
+    APPROVAL.notify_one();
+    Ok(())
+}
+
+pub async fn run(
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    config: config::ReceiptCompaction,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = APPROVAL.notified() => {}
+        }
+
+        let Some(escrow_accounts) = escrow_accounts.value_immediate() else {
+            continue;
+        };
+        if config.dry_run {
+            let approved = LATEST_REPORT
+                .lock()
+                .unwrap()
+                .as_ref()
+                .filter(|report| report.approved && report.compacted_receipts.is_none())
+                .map(|report| (report.id, report.entries.clone()));
+            if let Some((id, entries)) = approved {
+                match apply_approved(&pgpool, &escrow_accounts, &config, &entries).await {
+                    Ok(receipts) => {
+                        info!(report = id, receipts, mode = ?config.mode, "Receipts compacted.");
+                        if let Some(report) = LATEST_REPORT.lock().unwrap().as_mut() {
+                            report.compacted_receipts = Some(receipts);
+                        }
+                    }
+                    Err(error) => warn!(%error, report = id, "Failed to compact the receipts."),
+                }
+                continue;
+            }
+        }
+
+        if config.dry_run {
+            match report(&pgpool, &escrow_accounts, &config).await {
+                Ok(report) => {
+                    info!(
+                        report = report.id,
+                        receipts = report.receipts,
+                        bytes = report.bytes,
+                        "Receipt compaction dry run, approve the report to set the receipts aside."
+                    );
+                    *LATEST_REPORT.lock().unwrap() = Some(report);
+                }
+                Err(error) => warn!(%error, "Failed to report the receipts to compact."),
+            }
+            continue;
+        }
+        match compact(&pgpool, &escrow_accounts, &config).await {
+            Ok(0) => {}
+            Ok(receipts) => info!(receipts, mode = ?config.mode, "Receipts compacted."),
+            Err(error) => warn!(%error, "Failed to compact the receipts."),
+        }
+    }
+}
+
+/// Sets aside the receipts covered by the final RAVs, returns how many.
+pub async fn compact(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    config: &config::ReceiptCompaction,
+) -> Result<u64> {
+    let entries = plan(pgpool, escrow_accounts).await?;
+    for entry in &entries {
+        if entry.coverage == Coverage::ExceedsRav {
+            warn!(
+                sender = %entry.sender,
+                allocation_id = entry.allocation_id,
+                covered_value = entry.receipts_value,
+                value_aggregate = entry.rav_value,
+                "The receipts covered by the final RAV add up to more than the RAV, they are kept."
+            );
+        }
+    }
+    apply(pgpool, config, &entries).await
+}
+
+/// Reports what [`compact`] would set aside, without setting anything aside.
+pub async fn report(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    config: &config::ReceiptCompaction,
+) -> Result<CompactionReport> {
+    let entries = plan(pgpool, escrow_accounts).await?;
+    let covered = entries
+        .iter()
+        .filter(|entry| entry.coverage == Coverage::Covered);
+    Ok(CompactionReport {
+        id: NEXT_REPORT_ID.fetch_add(1, Ordering::Relaxed),
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        mode: mode_label(config.mode),
+        receipts: covered.clone().map(|entry| entry.receipts).sum(),
+        bytes: covered.map(|entry| entry.bytes).sum(),
+        entries,
+        approved: false,
+        compacted_receipts: None,
+    })
+}
+
+/// Sets aside the receipts of the approved `entries` still covered by the same final RAV, as
+/// planned now, returns how many.
+async fn apply_approved(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    config: &config::ReceiptCompaction,
+    entries: &[CompactionEntry],
+) -> Result<u64> {
+    let current = plan(pgpool, escrow_accounts).await?;
+    let verified = entries
+        .iter()
+        .filter(|entry| entry.coverage == Coverage::Covered)
+        .filter_map(|entry| {
+            let verified = current.iter().find(|current| {
+                current.sender == entry.sender
+                    && current.allocation_id == entry.allocation_id
+                    && current.timestamp_ns == entry.timestamp_ns
+                    && current.coverage == Coverage::Covered
+            });
+            if verified.is_none() {
+                warn!(
+                    sender = %entry.sender,
+                    allocation_id = entry.allocation_id,
+                    rav_timestamp_ns = entry.rav_timestamp_ns,
+                    "The receipts of the approved entry aren't covered by the final RAV anymore, \
+                    they are kept."
+                );
+            }
+            verified.cloned()
+        })
+        .collect::<Vec<_>>();
+    apply(pgpool, config, &verified).await
+}
+
+/// The receipts covered by each final RAV. The RAVs whose receipts were all set aside already
+/// are skipped, found from the indexes alone rather than by scanning the receipts of each.
+async fn plan(pgpool: &PgPool, escrow_accounts: &EscrowAccounts) -> Result<Vec<CompactionEntry>> {
+    let final_ravs = database::acquire(pgpool, Subsystem::RavStore)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    SELECT sender_address, allocation_id, timestamp_ns, value_aggregate
+                    FROM scalar_tap_ravs
+                    WHERE final
+                        AND EXISTS (
+                            SELECT 1
+                            FROM scalar_tap_receipts
+                            WHERE scalar_tap_receipts.allocation_id = scalar_tap_ravs.allocation_id
+                                AND scalar_tap_receipts.timestamp_ns <= scalar_tap_ravs.timestamp_ns
+                        )
+                "#,
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+
+    let mut entries = Vec::new();
+    for rav in final_ravs {
+        let Ok(sender_address) = Address::from_str(&rav.sender_address) else {
+            continue;
+        };
+        let signers = escrow_accounts
+            .get_signers_for_sender(&sender_address)
+            .iter()
+            .map(|signer| signer.encode_hex())
+            .collect::<Vec<_>>();
+        if signers.is_empty() {
+            continue;
+        }
+
+        let covered = database::acquire(pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        SELECT
+                            COUNT(*) AS "receipts!",
+                            SUM(pg_column_size(scalar_tap_receipts.*)) AS bytes,
+                            SUM(value) AS value
+                        FROM scalar_tap_receipts
+                        WHERE allocation_id = $1
+                            AND signer_address = ANY($2)
+                            AND timestamp_ns <= $3
+                    "#,
+                    &rav.allocation_id,
+                    &signers,
+                    &rav.timestamp_ns,
+                )
+                .fetch_one(conn)
+            })
+            .await?;
+        let Some(covered_value) = covered.value else {
+            continue;
+        };
+        entries.push(CompactionEntry {
+            sender: sender_address,
+            allocation_id: rav.allocation_id,
+            rav_timestamp_ns: rav.timestamp_ns.to_string(),
+            rav_value: rav.value_aggregate.to_string(),
+            receipts: covered.receipts as u64,
+            bytes: covered.bytes.unwrap_or_default() as u64,
+            receipts_value: covered_value.to_string(),
+            coverage: if covered_value > rav.value_aggregate {
+                Coverage::ExceedsRav
+            } else {
+                Coverage::Covered
+            },
+            signers,
+            timestamp_ns: rav.timestamp_ns,
+        });
+    }
+    Ok(entries)
+}
+
+/// Sets aside the receipts of the covered entries, returns how many.
+async fn apply(
+    pgpool: &PgPool,
+    config: &config::ReceiptCompaction,
+    entries: &[CompactionEntry],
+) -> Result<u64> {
+    let mut total = 0;
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.coverage == Coverage::Covered)
+    {
+        let sender = entry.sender.encode_hex();
+        loop {
+            let receipts = compact_batch(
+                pgpool,
+                config,
+                &sender,
+                &entry.allocation_id,
+                &entry.signers,
+                &entry.timestamp_ns,
+            )
+            .await?;
+            RECEIPTS_ARCHIVED
+                .with_label_values(&[&entry.sender.to_string(), mode_label(config.mode)])
+                .inc_by(receipts as f64);
+            total += receipts;
+            if receipts < config.batch_size {
+                break;
+            }
+        }
+    }
+    Ok(total)
+}
+
+async fn compact_batch(
+    pgpool: &PgPool,
+    config: &config::ReceiptCompaction,
+    sender: &str,
+    allocation_id: &str,
+    signers: &[String],
+    timestamp_ns: &BigDecimal,
+) -> Result<u64> {
+    let batch_size = config.batch_size as i64;
+    let receipts = database::acquire(pgpool, Subsystem::ReceiptScan)
+        .await?
+        .run(|conn| async move {
+            match config.mode {
+                ReceiptCompactionMode::Archive => sqlx::query_scalar!(
+                    r#"
+                        WITH moved AS (
+                            DELETE FROM scalar_tap_receipts
+                            WHERE id IN (
+                                SELECT id
+                                FROM scalar_tap_receipts
+                                WHERE allocation_id = $1
+                                    AND signer_address = ANY($2)
+                                    AND timestamp_ns <= $3
+                                ORDER BY id
+                                LIMIT $4
+                            )
+                            RETURNING *
+                        ),
+                        -- A row per token, their values can't be summed
+                        archived AS (
+                            INSERT INTO scalar_tap_receipts_archive (
+                                sender_address,
+                                allocation_id,
+                                rav_timestamp_ns,
+                                receipt_count,
+                                value,
+                                receipts,
+                                fee_token
+                            )
+                            SELECT $5, $1, $3, COUNT(*), SUM(value), jsonb_agg(moved ORDER BY id),
+                                fee_token
+                            FROM moved
+                            GROUP BY fee_token
+                            RETURNING receipt_count
+                        )
+                        SELECT SUM(receipt_count)::BIGINT AS count FROM archived
+                    "#,
+                    allocation_id,
+                    signers,
+                    timestamp_ns,
+                    batch_size,
+                    sender,
+                )
+                .fetch_one(conn)
+                .await
+                .map(|count| count.unwrap_or_default() as u64),
+                ReceiptCompactionMode::Delete => sqlx::query!(
+                    r#"
+                        DELETE FROM scalar_tap_receipts
+                        WHERE id IN (
+                            SELECT id
+                            FROM scalar_tap_receipts
+                            WHERE allocation_id = $1
+                                AND signer_address = ANY($2)
+                                AND timestamp_ns <= $3
+                            ORDER BY id
+                            LIMIT $4
+                        )
+                    "#,
+                    allocation_id,
+                    signers,
+                    timestamp_ns,
+                    batch_size,
+                )
+                .execute(conn)
+                .await
+                .map(|result| result.rows_affected()),
+            }
+        })
+        .await?;
+    Ok(receipts)
+}
+
+fn mode_label(mode: ReceiptCompactionMode) -> &'static str {
+    match mode {
+        ReceiptCompactionMode::Archive => "archive",
+        ReceiptCompactionMode::Delete => "delete",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use alloy::{hex::ToHexExt, primitives::U256};
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::PgPool;
+
+    use super::{apply_approved, compact, report, Coverage};
+    use crate::{
+        config::{ReceiptCompaction, ReceiptCompactionMode},
+        tap::test_utils::{
+            create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0, SENDER,
+            SIGNER,
+        },
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_archive_receipts_of_final_ravs(pgpool: PgPool) {
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Covers the first 5 receipts
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 5, 15);
+        store_rav(&pgpool, rav, SENDER.1).await.unwrap();
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let config = ReceiptCompaction {
+            mode: ReceiptCompactionMode::Archive,
+            interval: Duration::from_secs(60),
+            batch_size: 2,
+            dry_run: false,
+        };
+
+        // Not final yet
+        assert_eq!(
+            compact(&pgpool, &escrow_accounts, &config).await.unwrap(),
+            0
+        );
+
+        sqlx::query!("UPDATE scalar_tap_ravs SET last = true, final = true")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(
+            compact(&pgpool, &escrow_accounts, &config).await.unwrap(),
+            5
+        );
+        let remaining =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, 5);
+        let archive = sqlx::query!(
+            r#"
+                SELECT COUNT(*) AS "batches!", SUM(receipt_count)::TEXT AS archived
+                FROM scalar_tap_receipts_archive
+                WHERE allocation_id = $1
+            "#,
+            ALLOCATION_ID_0.encode_hex()
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(archive.batches, 3);
+        assert_eq!(archive.archived.as_deref(), Some("5"));
+
+        // Receipts adding up to more than the RAV are kept
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 100 + i, i, 100u128);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            compact(&pgpool, &escrow_accounts, &config).await.unwrap(),
+            0
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_dry_run_report(pgpool: PgPool) {
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Covers the first 5 receipts
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 5, 15);
+        store_rav(&pgpool, rav, SENDER.1).await.unwrap();
+        sqlx::query!("UPDATE scalar_tap_ravs SET last = true, final = true")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let config = ReceiptCompaction {
+            mode: ReceiptCompactionMode::Delete,
+            interval: Duration::from_secs(60),
+            batch_size: 100,
+            dry_run: true,
+        };
+
+        let report = report(&pgpool, &escrow_accounts, &config).await.unwrap();
+        assert_eq!(report.receipts, 5);
+        assert!(report.bytes > 0);
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(report.entries[0].sender, SENDER.1);
+        assert_eq!(report.entries[0].receipts_value, "15");
+        assert_eq!(report.entries[0].coverage, Coverage::Covered);
+        let remaining =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, 10, "nothing is removed by a dry run");
+
+        // Receipts covered by the RAV received since the report, now adding up to more than it
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 100 + i, i, 100u128);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Approved, but verified again first
+        assert_eq!(
+            apply_approved(&pgpool, &escrow_accounts, &config, &report.entries)
+                .await
+                .unwrap(),
+            0
+        );
+        sqlx::query!("DELETE FROM scalar_tap_receipts WHERE value = 100")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            apply_approved(&pgpool, &escrow_accounts, &config, &report.entries)
+                .await
+                .unwrap(),
+            5
+        );
+        let remaining =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, 5);
+    }
+}
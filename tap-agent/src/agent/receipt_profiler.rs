@@ -0,0 +1,47 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stages of the receipt notifications profiled by tap-agent, see
+//! [`indexer_common::receipt_profiler`].
+//!
+//! The notifications are sampled when received, and their [`Stages`] travel with them through the
+//! actor messages. Only the first receipt of a batch of receipt fees reaches the
+//! `SenderAccount`, so the stages after [`stage::FEES_BATCH`] are only recorded for the sampled
+//! receipts starting a batch.
+
+use indexer_common::receipt_profiler::Stages;
+use lazy_static::lazy_static;
+use prometheus::{exponential_buckets, register_histogram_vec, HistogramVec};
+
+lazy_static! {
+    pub static ref RECEIPT_INTAKE_STAGES: HistogramVec = register_histogram_vec!(
+        "tap_receipt_intake_stage_seconds",
+        "Time spent by the sampled receipt notifications in each stage of their handling",
+        &["stage"],
+        exponential_buckets(0.00001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+}
+
+/// Values of the `stage` label of [`RECEIPT_INTAKE_STAGES`].
+pub mod stage {
+    /// Lookup of the sender and of its `SenderAllocation`.
+    pub const NOTIFICATION: &str = "notification";
+    /// In the mailbox of the `SenderAllocation`.
+    pub const ALLOCATION_MAILBOX: &str = "allocation_mailbox";
+    /// Accounting of the receipt by the `SenderAllocation`.
+    pub const ALLOCATION_UPDATE: &str = "allocation_update";
+    /// Until the batch of receipt fees is sent to the `SenderAccount`.
+    pub const FEES_BATCH: &str = "fees_batch";
+    /// In the mailbox of the `SenderAccount`.
+    pub const ACCOUNT_MAILBOX: &str = "account_mailbox";
+    /// Update of the fee tracker of the `SenderAccount`.
+    pub const TRACKER_UPDATE: &str = "tracker_update";
+}
+
+/// Records `stage` if the receipt is sampled.
+pub fn observe(stages: &mut Option<Stages>, stage: &str) {
+    if let Some(stages) = stages {
+        stages.observe(&RECEIPT_INTAKE_STAGES, stage);
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cross-check of the receipts received against the queries served by indexer-service.
+//!
+//! Every interval, the query counters of all the indexer-service instances are scraped from their
+//! metrics endpoints, and compared to the receipts this tap-agent received over the same
+//! interval, per sender and allocation. Each query served should come with exactly one receipt,
+//! so the ratio of the two is exported as `tap_receipt_query_ratio`, and allocations whose
+//! receipts are missing (the sender isn't paying for its queries) or excessive are logged.
+//!
+//! The queries are the successful ones, `indexer_query_handler_seconds_count` minus
+//! `indexer_receipt_failed_total`. When the senders are shared with other tap-agents, only the
+//! ratios of the senders owned by this tap-agent are meaningful.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{core::Collector, register_gauge_vec, GaugeVec};
+use reqwest::Url;
+use tracing::{info, warn};
+
+use super::sender_accounts_manager::RECEIPTS_CREATED;
+use crate::config;
+
+lazy_static! {
+    static ref RECEIPT_QUERY_RATIO: GaugeVec = register_gauge_vec!(
+        "tap_receipt_query_ratio",
+        "Receipts received over queries served during the last sampling interval.",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+}
+
+const QUERIES: &str = "indexer_query_handler_seconds_count";
+const FAILED_RECEIPTS: &str = "indexer_receipt_failed_total";
+
+/// Sender and allocation, formatted like in the metric labels.
+type Key = (String, String);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Counts {
+    receipts: f64,
+    queries: f64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Missing,
+    Excessive,
+}
+
+#[derive(Debug, PartialEq)]
+struct Sample {
+    key: Key,
+    ratio: f64,
+    verdict: Option<Verdict>,
+}
+
+/// Compares the receipts and the queries every `config.interval`, forever.
+pub async fn run(http_client: reqwest::Client, config: config::ReceiptSampling) {
+    info!(
+        urls = config.service_metrics_urls.len(),
+        "Comparing the receipts received to the queries served every {}s.",
+        config.interval.as_secs()
+    );
+    let mut previous = None;
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        let queries = match query_counts(&http_client, &config.service_metrics_urls).await {
+            Ok(queries) => queries,
+            Err(error) => {
+                warn!(%error, "Failed to scrape the indexer-service metrics, skipping sample.");
+                // The receipts of this interval can't be matched with their queries anymore.
+                previous = None;
+                continue;
+            }
+        };
+        let current = merge(receipt_counts(), queries);
+        if let Some(previous) = &previous {
+            for sample in compare(previous, &current, config.tolerance) {
+                report(&sample);
+            }
+        }
+        previous = Some(current);
+    }
+}
+
+fn report(sample: &Sample) {
+    let (sender, allocation) = &sample.key;
+    RECEIPT_QUERY_RATIO
+        .with_label_values(&[sender, allocation])
+        .set(sample.ratio);
+    match sample.verdict {
+        Some(Verdict::Missing) => warn!(
+            %sender,
+            %allocation,
+            ratio = sample.ratio,
+            "Fewer receipts received than queries served, the sender may not be paying for all \
+            its queries."
+        ),
+        Some(Verdict::Excessive) => warn!(
+            %sender,
+            %allocation,
+            ratio = sample.ratio,
+            "More receipts received than queries served."
+        ),
+        None => {}
+    }
+}
+
+/// Successful queries per sender and allocation, summed over all the indexer-service instances.
+async fn query_counts(
+    http_client: &reqwest::Client,
+    urls: &[Url],
+) -> anyhow::Result<HashMap<Key, f64>> {
+    let mut counts = HashMap::new();
+    for url in urls {
+        let text = http_client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to query {url}"))?
+            .text()
+            .await
+            .with_context(|| format!("Failed to read the response of {url}"))?;
+        for (key, value) in parse_query_counts(&text) {
+            *counts.entry(key).or_default() += value;
+        }
+    }
+    Ok(counts)
+}
+
+/// Parses the successful queries from the Prometheus text format, summed over the deployments.
+fn parse_query_counts(text: &str) -> HashMap<Key, f64> {
+    let mut counts = HashMap::new();
+    for line in text.lines() {
+        let Some((name, rest)) = line.split_once('{') else {
+            continue;
+        };
+        let sign = match name {
+            QUERIES => 1.0,
+            FAILED_RECEIPTS => -1.0,
+            _ => continue,
+        };
+        let Some((labels, value)) = rest.split_once('}') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<f64>() else {
+            continue;
+        };
+        let mut sender = None;
+        let mut allocation = None;
+        // The label values are addresses and deployment ids, without commas nor quotes
+        for label in labels.split(',') {
+            match label.split_once('=') {
+                Some(("sender", v)) => sender = Some(v.trim_matches('"').to_string()),
+                Some(("allocation", v)) => allocation = Some(v.trim_matches('"').to_string()),
+                _ => {}
+            }
+        }
+        if let (Some(sender), Some(allocation)) = (sender, allocation) {
+            *counts.entry((sender, allocation)).or_default() += sign * value;
+        }
+    }
+    counts
+}
+
+/// Receipts received by this tap-agent since it started, per sender and allocation.
+fn receipt_counts() -> HashMap<Key, f64> {
+    let mut counts = HashMap::new();
+    for family in RECEIPTS_CREATED.collect() {
+        for metric in family.get_metric() {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_string())
+                    .unwrap_or_default()
+            };
+            counts.insert(
+                (label("sender"), label("allocation")),
+                metric.get_counter().get_value(),
+            );
+        }
+    }
+    counts
+}
+
+fn merge(receipts: HashMap<Key, f64>, queries: HashMap<Key, f64>) -> HashMap<Key, Counts> {
+    let mut counts: HashMap<Key, Counts> = HashMap::new();
+    for (key, value) in receipts {
+        counts.entry(key).or_default().receipts = value;
+    }
+    for (key, value) in queries {
+        counts.entry(key).or_default().queries = value;
+    }
+    counts
+}
+
+/// Compares the receipts and the queries received between two samples. Pairs whose counters
+/// went down, because indexer-service or tap-agent restarted, are skipped for this interval.
+fn compare(
+    previous: &HashMap<Key, Counts>,
+    current: &HashMap<Key, Counts>,
+    tolerance: f64,
+) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for (key, counts) in current {
+        let before = previous.get(key).copied().unwrap_or_default();
+        let receipts = counts.receipts - before.receipts;
+        let queries = counts.queries - before.queries;
+        if receipts < 0.0 || queries < 0.0 || (receipts == 0.0 && queries == 0.0) {
+            continue;
+        }
+        let ratio = if queries == 0.0 {
+            f64::INFINITY
+        } else {
+            receipts / queries
+        };
+        let verdict = if ratio < 1.0 - tolerance {
+            Some(Verdict::Missing)
+        } else if ratio > 1.0 + tolerance {
+            Some(Verdict::Excessive)
+        } else {
+            None
+        };
+        samples.push(Sample {
+            key: key.clone(),
+            ratio,
+            verdict,
+        });
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{compare, merge, parse_query_counts, Key, Verdict};
+
+    fn key(allocation: &str) -> Key {
+        ("0xSender".to_string(), allocation.to_string())
+    }
+
+    #[test]
+    fn test_parse_query_counts() {
+        let text = r#"
+# HELP indexer_query_handler_seconds Histogram for default indexer query handler
+# TYPE indexer_query_handler_seconds histogram
+indexer_query_handler_seconds_bucket{allocation="0xA",deployment="Qm1",sender="0xSender",le="0.005"} 3
+indexer_query_handler_seconds_sum{allocation="0xA",deployment="Qm1",sender="0xSender"} 0.5
+indexer_query_handler_seconds_count{allocation="0xA",deployment="Qm1",sender="0xSender"} 10
+indexer_query_handler_seconds_count{allocation="0xA",deployment="Qm2",sender="0xSender"} 5
+indexer_query_handler_seconds_count{allocation="0xB",deployment="Qm1",sender="0xSender"} 7
+indexer_receipt_failed_total{allocation="0xA",deployment="Qm1",sender="0xSender"} 2
+indexer_query_handler_failed_total{deployment="Qm1"} 4
+"#;
+        assert_eq!(
+            parse_query_counts(text),
+            HashMap::from([(key("0xA"), 13.0), (key("0xB"), 7.0)])
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let previous = merge(
+            HashMap::from([(key("0xA"), 10.0), (key("0xB"), 10.0), (key("0xC"), 50.0)]),
+            HashMap::from([(key("0xA"), 10.0), (key("0xB"), 10.0), (key("0xC"), 50.0)]),
+        );
+        let current = merge(
+            HashMap::from([
+                (key("0xA"), 105.0),
+                (key("0xB"), 20.0),
+                (key("0xC"), 60.0),
+                (key("0xD"), 5.0),
+            ]),
+            HashMap::from([
+                (key("0xA"), 110.0),
+                (key("0xB"), 40.0),
+                // indexer-service restarted
+                (key("0xC"), 5.0),
+            ]),
+        );
+        let samples: HashMap<_, _> = compare(&previous, &current, 0.1)
+            .into_iter()
+            .map(|sample| (sample.key.1, (sample.ratio, sample.verdict)))
+            .collect();
+
+        assert_eq!(samples["0xA"], (0.95, None));
+        assert_eq!(samples["0xB"], (0.5, Some(Verdict::Missing)));
+        assert!(!samples.contains_key("0xC"));
+        assert_eq!(samples["0xD"], (f64::INFINITY, Some(Verdict::Excessive)));
+    }
+}
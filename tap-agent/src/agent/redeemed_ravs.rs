@@ -0,0 +1,197 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Last RAVs of the senders that weren't redeemed yet, looked up once per escrow update.
+//!
+//! On each update of the escrow accounts, every `SenderAccount` needs its last non-final RAVs
+//! that weren't redeemed yet. Instead of a database and a subgraph query per sender, the first
+//! `SenderAccount` to ask reads the last RAVs of all the senders, and the redeem transactions of
+//! all of them from the escrow subgraph, a page at a time. The other `SenderAccount`s get their
+//! share of the result, until the escrow accounts change or it's older than the escrow syncing
+//! interval.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use anyhow::Result;
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use graphql_client::GraphQLQuery;
+use indexer_common::{escrow_accounts::EscrowAccounts, prelude::SubgraphClient};
+use sqlx::{types::BigDecimal, PgPool};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::database::{self, Subsystem};
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../graphql/tap.schema.graphql",
+    query_path = "../graphql/unfinalized_tx.query.graphql",
+    response_derives = "Debug",
+    variables_derives = "Clone"
+)]
+struct UnfinalizedTransactions;
+
+/// Page size of [`UnfinalizedTransactions`].
+const PAGE_SIZE: usize = 1000;
+
+/// Value of the last RAV of each allocation, by sender.
+type RavsBySender = HashMap<Address, HashMap<Address, u128>>;
+
+struct Lookup {
+    escrow_accounts: EscrowAccounts,
+    at: Instant,
+    ravs: Arc<RavsBySender>,
+}
+
+#[derive(Clone)]
+pub struct RedeemedRavs {
+    pgpool: PgPool,
+    escrow_subgraph: &'static SubgraphClient,
+    max_age: Duration,
+    last_lookup: Arc<Mutex<Option<Lookup>>>,
+}
+
+impl RedeemedRavs {
+    pub fn new(
+        pgpool: PgPool,
+        escrow_subgraph: &'static SubgraphClient,
+        max_age: Duration,
+    ) -> Self {
+        Self {
+            pgpool,
+            escrow_subgraph,
+            max_age,
+            last_lookup: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Last non-final RAVs of `sender` that weren't redeemed yet, by allocation, as of the update
+    /// to `escrow_accounts`.
+    pub async fn non_redeemed_ravs(
+        &self,
+        escrow_accounts: &EscrowAccounts,
+        sender: Address,
+    ) -> Result<HashMap<Address, u128>> {
+        // Held during the lookup, so that the other senders wait for it instead of repeating it
+        let mut last_lookup = self.last_lookup.lock().await;
+        let ravs = match &*last_lookup {
+            Some(lookup)
+                if lookup.escrow_accounts == *escrow_accounts
+                    && lookup.at.elapsed() < self.max_age =>
+            {
+                lookup.ravs.clone()
+            }
+            _ => {
+                let ravs = Arc::new(self.lookup().await?);
+                *last_lookup = Some(Lookup {
+                    escrow_accounts: escrow_accounts.clone(),
+                    at: Instant::now(),
+                    ravs: ravs.clone(),
+                });
+                ravs
+            }
+        };
+        Ok(ravs.get(&sender).cloned().unwrap_or_default())
+    }
+
+    async fn lookup(&self) -> Result<RavsBySender> {
+        let last_non_final_ravs = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        SELECT sender_address, allocation_id, value_aggregate
+                        FROM scalar_tap_ravs
+                        WHERE last AND NOT final;
+                    "#
+                )
+                .fetch_all(conn)
+            })
+            .await?
+            .into_iter()
+            .map(|row| (row.sender_address, row.allocation_id, row.value_aggregate))
+            .collect::<Vec<_>>();
+        if last_non_final_ravs.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let redeemed = self.redeemed(&last_non_final_ravs).await;
+
+        let mut ravs = RavsBySender::new();
+        for (sender, allocation_id, value_aggregate) in last_non_final_ravs {
+            let (Ok(sender), Ok(allocation_id), Some(value)) = (
+                Address::from_str(&sender),
+                Address::from_str(&allocation_id),
+                value_aggregate.to_bigint().and_then(|v| v.to_u128()),
+            ) else {
+                continue;
+            };
+            if !redeemed.contains(&(sender, allocation_id)) {
+                ravs.entry(sender).or_default().insert(allocation_id, value);
+            }
+        }
+        Ok(ravs)
+    }
+
+    /// (sender, allocation) of the RAVs redeemed but not marked as final yet.
+    async fn redeemed(
+        &self,
+        last_non_final_ravs: &[(String, String, BigDecimal)],
+    ) -> HashSet<(Address, Address)> {
+        let senders = last_non_final_ravs
+            .iter()
+            .filter_map(|(sender, _, _)| Address::from_str(sender).ok())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|sender| format!("{:x?}", sender))
+            .collect::<Vec<_>>();
+        let allocation_ids = last_non_final_ravs
+            .iter()
+            .map(|(_, allocation_id, _)| allocation_id.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let mut redeemed = HashSet::new();
+        let mut last_id = String::new();
+        loop {
+            let transactions = match self
+                .escrow_subgraph
+                .query::<UnfinalizedTransactions, _>(unfinalized_transactions::Variables {
+                    unfinalized_ravs_allocation_ids: allocation_ids.clone(),
+                    senders: senders.clone(),
+                    last_id: last_id.clone(),
+                })
+                .await
+            {
+                Ok(Ok(response)) => response.transactions,
+                // if we have any problems, we don't want to filter out
+                Ok(Err(err)) | Err(err) => {
+                    warn!("Failed to fetch the redeemed RAVs from the escrow subgraph: {err}");
+                    return HashSet::new();
+                }
+            };
+            let last_page = transactions.len() < PAGE_SIZE;
+            if let Some(last) = transactions.last() {
+                last_id = last.id.clone();
+            }
+
+            redeemed.extend(transactions.into_iter().filter_map(|tx| {
+                Some((
+                    Address::from_str(&tx.sender.id).ok()?,
+                    Address::from_str(&tx.allocation_id?).ok()?,
+                ))
+            }));
+            if last_page {
+                break;
+            }
+        }
+        redeemed
+    }
+}
@@ -4,34 +4,59 @@
 use alloy::hex::ToHexExt;
 use alloy::primitives::U256;
 
-use bigdecimal::num_bigint::ToBigInt;
 use bigdecimal::ToPrimitive;
 
-use graphql_client::GraphQLQuery;
-use jsonrpsee::http_client::HttpClientBuilder;
-use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
-use std::collections::{HashMap, HashSet};
-use std::str::FromStr;
-use std::time::Duration;
-use tokio::task::JoinHandle;
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_int_counter_vec, register_int_gauge_vec,
+    CounterVec, GaugeVec, IntCounterVec, IntGaugeVec,
+};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{sync::watch, task::JoinHandle};
 
 use alloy::dyn_abi::Eip712Domain;
 use alloy::primitives::Address;
 use anyhow::Result;
 use eventuals::{Eventual, EventualExt, PipeHandle};
-use indexer_common::{escrow_accounts::EscrowAccounts, prelude::SubgraphClient};
+use indexer_common::{
+    escrow_accounts::{EscrowAccounts, Trend},
+    prelude::{Allocation, SubgraphClient},
+    receipt_profiler::Stages,
+};
 use ractor::{Actor, ActorProcessingErr, ActorRef, MessagingErr, SupervisionEvent};
 use sqlx::PgPool;
 use tap_core::rav::SignedRAV;
-use tracing::{error, Level};
-
+use tracing::{error, Instrument, Level, Span};
+
+use super::aggregator_client::{self, AggregatorClient};
+use super::config_reload::Thresholds;
+use super::deny_condition::{DenyConditionInputs, DENY_CONDITION_INPUTS};
+use super::denylist_outbox::{Denial, DenyReason, DenylistOutbox, Intent};
+use super::deployment_fees::DeploymentFees;
+use super::escrow_top_up::{self, FeeVelocity};
+use super::lifecycle_hooks;
+use super::quarantine::{Quarantine, ResumeError};
+use super::rate_limits::{self, RateLimit};
+use super::rav_anomalies::{self, RavAnomalyDetector};
+use super::rav_queue::{self, RavQueueEntry};
+use super::receipt_profiler::{self, stage};
+use super::redeemed_ravs::RedeemedRavs;
 use super::sender_allocation::{SenderAllocation, SenderAllocationArgs};
+use super::sender_pause::{self, PauseError};
+use super::startup_scans::StartupScans;
+use super::trust_score::{SenderTrust, TrustTracker};
 use crate::agent::sender_allocation::SenderAllocationMessage;
 use crate::agent::sender_fee_tracker::SenderFeeTracker;
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+use crate::database::{self, Subsystem};
+use crate::logging::{event, CorrelationId};
+use crate::metrics::series::SeriesOwner;
+use crate::money::Money;
+use crate::status::{AllocationStatus, DenyEvent, SenderAccountStatus};
 use crate::{
-    config::{self},
-    tap::escrow_adapter::EscrowAdapter,
+    config::{self, AllocationEvent, RateLimits},
+    tap::escrow_adapter::{EscrowAdapter, SenderEscrow},
 };
 use lazy_static::lazy_static;
 
@@ -68,20 +93,91 @@ lazy_static! {
         &["sender"]
     )
     .unwrap();
+    /// Incremented by the `SenderAllocation`s when sending their new receipts.
+    pub(super) static ref RECEIPT_FEES_MAILBOX_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "tap_receipt_fees_mailbox_depth",
+        "New receipts updates waiting in the mailbox of the SenderAccount",
+        &["sender"]
+    )
+    .unwrap();
+    static ref ESCROW_TOP_UP_SUGGESTION: GaugeVec = register_gauge_vec!(
+        "tap_escrow_top_up_suggestion_grt",
+        "Smallest escrow top-up keeping the sender from being denied over the top-up horizon",
+        &["sender"]
+    )
+    .unwrap();
     static ref RAV_REQUEST_TRIGGER_VALUE: GaugeVec = register_gauge_vec!(
         "tap_rav_request_trigger_value",
         "RAV request trigger value divisor",
         &["sender"]
     )
     .unwrap();
+    static ref CHECKPOINT_RAV_REQUESTS: CounterVec = register_counter_vec!(
+        "tap_checkpoint_rav_requests_total",
+        "RAV requests triggered because the fees stayed unaggregated for `tap.rav_request.checkpoint_interval_days`",
+        &["sender"]
+    )
+    .unwrap();
+    static ref FEES_INSIDE_BUFFER: GaugeVec = register_gauge_vec!(
+        "tap_fees_inside_buffer_grt_total",
+        "Unaggregated fees still in the timestamp buffer, as of the last RAV trigger evaluation",
+        &["sender"]
+    )
+    .unwrap();
+    static ref FEES_OUTSIDE_BUFFER: GaugeVec = register_gauge_vec!(
+        "tap_fees_outside_buffer_grt_total",
+        "Unaggregated fees out of the timestamp buffer, as of the last RAV trigger evaluation",
+        &["sender"]
+    )
+    .unwrap();
+    static ref ALLOCATION_QUARANTINED: IntGaugeVec = register_int_gauge_vec!(
+        "tap_allocation_quarantined",
+        "Allocation quarantined after its SenderAllocation kept panicking, until resumed",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+    static ref ALLOCATION_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "tap_sender_allocation_restarts_total",
+        "SenderAllocation restarted after a panic, before its allocation is quarantined",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+    static ref SENDER_PAUSED: IntGaugeVec = register_int_gauge_vec!(
+        "tap_sender_paused",
+        "Sender paused by the operator, no RAV is requested for it until resumed",
+        &["sender"]
+    )
+    .unwrap();
+    static ref SENDER_TRUST_SCORE: GaugeVec = register_gauge_vec!(
+        "tap_sender_trust_score",
+        "Trust score of the sender, between 0 and 1",
+        &["sender"]
+    )
+    .unwrap();
+    static ref RAV_TRIGGER_SKIPPED_BY_BUFFER: CounterVec = register_counter_vec!(
+        "tap_rav_trigger_skipped_by_buffer_total",
+        "RAV trigger evaluations over the trigger value, skipped because of the fees in the timestamp buffer",
+        &["sender"]
+    )
+    .unwrap();
 }
 
+/// How many times per checkpoint interval the unaggregated fees are checked for checkpoint RAVs.
+const CHECKPOINT_CHECKS_PER_INTERVAL: u32 = 24;
+
+/// Deny and allow events kept for [`crate::status`].
+const MAX_DENY_EVENTS: usize = 20;
+
 type RavMap = HashMap<Address, u128>;
 type Balance = U256;
 
 #[derive(Debug)]
 pub enum ReceiptFees {
-    NewReceipt(u128),
+    /// Value and number of the receipts received since the last update, coalesced by the
+    /// `SenderAllocation`. Carries the correlation id of the first of them, and its stages if
+    /// it's profiled, see [`crate::agent::receipt_profiler`]. Ends with the largest timestamp
+    /// skew of the receipts, see [`crate::agent::trust_score`].
+    NewReceipts(u128, u64, CorrelationId, Option<Stages>, Duration),
     UpdateValue(UnaggregatedReceipts),
     RavRequestResponse(anyhow::Result<(UnaggregatedReceipts, Option<SignedRAV>)>),
     Retry,
@@ -89,17 +185,54 @@ pub enum ReceiptFees {
 
 #[derive(Debug)]
 pub enum SenderAccountMessage {
-    UpdateBalanceAndLastRavs(Balance, RavMap),
+    /// With the trend of the balance, see [`EscrowAccounts::balance_trend`].
+    UpdateBalanceAndLastRavs(Balance, Trend, RavMap),
     UpdateAllocationIds(HashSet<Address>),
+    /// Allocations closing soon, see [`crate::agent::allocation_closure`].
+    UpdateClosingAllocationIds(HashSet<Address>),
     NewAllocationId(Address),
     UpdateReceiptFees(Address, ReceiptFees),
+    /// Sent to itself after new receipts, evaluates the RAV triggers of the allocations that
+    /// received receipts since the last evaluation.
+    EvaluateRavTriggers,
     UpdateInvalidReceiptFees(Address, UnaggregatedReceipts),
     UpdateRav(SignedRAV),
-    #[cfg(test)]
+    /// Value of the latest Horizon RAV of an allocation.
+    UpdateHorizonRav(Address, u128),
+    /// Thresholds reloaded from the configuration file, see [`crate::agent::config_reload`].
+    UpdateConfig(Thresholds),
+    /// Sent to itself periodically if `tap.rav_request.checkpoint_interval_days` is set, requests
+    /// a checkpoint RAV for the allocations with fees unaggregated for longer than that.
+    CheckpointRavs,
+    /// Sent to itself every `tap.rate_limits.interval_secs` if set, writes the rate limit of the
+    /// sender, see [`crate::agent::rate_limits`].
+    PublishRateLimit,
+    /// Sent to itself every [`rav_anomalies::RATE_INTERVAL`], samples the receipt rate of the
+    /// sender even when it doesn't send any.
+    SampleReceiptRate,
+    /// Read-only, see [`crate::status`].
+    GetStatus(ractor::RpcReplyPort<SenderAccountStatus>),
+    /// Read-only, by decreasing priority, see [`crate::admin`].
+    GetRavQueue(ractor::RpcReplyPort<Vec<RavQueueEntry>>),
+    /// Read-only, see [`crate::agent::trust_score`].
+    GetTrust(ractor::RpcReplyPort<SenderTrust>),
+    /// Read-only, see [`crate::admin`].
+    GetEscrow(ractor::RpcReplyPort<SenderEscrow>),
+    /// Starts again the `SenderAllocation` of a quarantined allocation, see
+    /// [`crate::agent::quarantine`].
+    ResumeAllocation(Address, ractor::RpcReplyPort<Result<(), ResumeError>>),
+    /// Pauses the sender with an optional reason, see [`crate::agent::sender_pause`].
+    PauseSender(Option<String>, ractor::RpcReplyPort<Result<(), PauseError>>),
+    /// Resumes the sender paused with [`SenderAccountMessage::PauseSender`].
+    ResumeSender(ractor::RpcReplyPort<Result<(), PauseError>>),
+    /// Read-only, see [`crate::agent::debug`].
+    #[cfg(any(test, feature = "debug-rpc"))]
     GetSenderFeeTracker(ractor::RpcReplyPort<SenderFeeTracker>),
-    #[cfg(test)]
+    /// Read-only, see [`crate::agent::debug`].
+    #[cfg(any(test, feature = "debug-rpc"))]
     GetDeny(ractor::RpcReplyPort<bool>),
-    #[cfg(test)]
+    /// Read-only, see [`crate::agent::debug`].
+    #[cfg(any(test, feature = "debug-rpc"))]
     IsSchedulerEnabled(ractor::RpcReplyPort<bool>),
 }
 
@@ -117,33 +250,76 @@ pub struct SenderAccount;
 pub struct SenderAccountArgs {
     pub config: &'static config::Config,
     pub pgpool: PgPool,
+    /// Pool of the `SenderAllocation`s, see [`crate::database::Component`].
+    pub allocation_pgpool: PgPool,
+    pub denylist: DenylistOutbox,
     pub sender_id: Address,
     pub escrow_accounts: Eventual<EscrowAccounts>,
-    pub indexer_allocations: Eventual<HashSet<Address>>,
+    /// See [`indexer_common::allocations::watcher`].
+    pub indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+    pub closing_allocations: Eventual<HashSet<Address>>,
     pub escrow_subgraph: &'static SubgraphClient,
+    /// Shared by the `SenderAccount`s, see [`crate::agent::redeemed_ravs`].
+    pub redeemed_ravs: RedeemedRavs,
+    /// Shared by all the `SenderAllocation`s, see [`crate::agent::startup_scans`].
+    pub startup_scans: StartupScans,
+    /// See [`crate::agent::deployment_fees`].
+    pub deployment_fees: DeploymentFees,
     pub domain_separator: Eip712Domain,
+    /// Set if Horizon receipts are collected as well.
+    pub horizon_domain_separator: Option<Eip712Domain>,
     pub sender_aggregator_endpoint: String,
     pub allocation_ids: HashSet<Address>,
     pub prefix: Option<String>,
 
-    pub retry_interval: Duration,
+    /// Initial thresholds, updated with [`SenderAccountMessage::UpdateConfig`].
+    pub thresholds: Thresholds,
 }
 pub struct State {
     prefix: Option<String>,
     sender_fee_tracker: SenderFeeTracker,
     rav_tracker: SenderFeeTracker,
+    /// Horizon RAVs are tracked apart, an allocation can have a RAV of each format.
+    horizon_rav_tracker: SenderFeeTracker,
     invalid_receipts_tracker: SenderFeeTracker,
     allocation_ids: HashSet<Address>,
-    _indexer_allocations_handle: PipeHandle,
+    /// Latest allocations closing soon, for the [`crate::agent::rav_queue`].
+    closing_allocation_ids: HashSet<Address>,
+    /// Allocations whose `SenderAllocation` isn't restarted anymore.
+    quarantine: Quarantine,
+    /// No RAV is triggered while paused, see [`crate::agent::sender_pause`].
+    paused: bool,
+    trust: TrustTracker,
+    rav_anomalies: RavAnomalyDetector,
+    /// Forwards the updates of the indexer allocations, aborted on stop.
+    indexer_allocations_watcher: JoinHandle<()>,
+    _closing_allocations_handle: PipeHandle,
     _escrow_account_monitor: PipeHandle,
     scheduled_rav_request: Option<JoinHandle<Result<(), MessagingErr<SenderAccountMessage>>>>,
+    /// Allocations with new receipts waiting for `EvaluateRavTriggers`, with the correlation id
+    /// of their first receipt.
+    pending_trigger_evaluations: HashMap<Address, CorrelationId>,
+    /// When the oldest fees not covered by a RAV were received, per allocation. Reset on
+    /// restart, so a checkpoint RAV can come up to an interval late.
+    unaggregated_since: HashMap<Address, Instant>,
+    checkpoint_timer: Option<JoinHandle<()>>,
+    rate_limit_timer: Option<JoinHandle<()>>,
+    rav_anomalies_timer: JoinHandle<()>,
 
     sender: Address,
 
     // Deny reasons
     denied: bool,
+    /// Why the sender was denied, as recorded in the denylist, `None` while it's allowed.
+    deny_reason: Option<DenyReason>,
+    /// Latest changes of `denied`, oldest first.
+    deny_events: VecDeque<DenyEvent>,
     sender_balance: U256,
-    retry_interval: Duration,
+    balance_trend: Trend,
+    /// Fees of the new receipts, for [`State::escrow_top_up`].
+    fee_velocity: FeeVelocity,
+    /// Reloadable part of the configuration, see [`crate::agent::config_reload`].
+    thresholds: Thresholds,
 
     //Eventuals
     escrow_accounts: Eventual<EscrowAccounts>,
@@ -151,9 +327,16 @@ pub struct State {
     escrow_subgraph: &'static SubgraphClient,
     escrow_adapter: EscrowAdapter,
     domain_separator: Eip712Domain,
+    horizon_domain_separator: Option<Eip712Domain>,
     config: &'static config::Config,
     pgpool: PgPool,
-    sender_aggregator: jsonrpsee::http_client::HttpClient,
+    allocation_pgpool: PgPool,
+    denylist: DenylistOutbox,
+    startup_scans: StartupScans,
+    deployment_fees: DeploymentFees,
+    sender_aggregator: AggregatorClient,
+    /// The series of the sender, shared with its `SenderAllocation`s.
+    metrics: Arc<SeriesOwner>,
 }
 
 impl State {
@@ -161,7 +344,7 @@ impl State {
         &self,
         sender_account_ref: ActorRef<SenderAccountMessage>,
         allocation_id: Address,
-    ) -> Result<()> {
+    ) -> Result<ActorRef<SenderAllocationMessage>> {
         tracing::trace!(
             %self.sender,
             %allocation_id,
@@ -169,25 +352,30 @@ impl State {
         );
         let args = SenderAllocationArgs {
             config: self.config,
-            pgpool: self.pgpool.clone(),
+            pgpool: self.allocation_pgpool.clone(),
             allocation_id,
             sender: self.sender,
             escrow_accounts: self.escrow_accounts.clone(),
             escrow_subgraph: self.escrow_subgraph,
             escrow_adapter: self.escrow_adapter.clone(),
             domain_separator: self.domain_separator.clone(),
+            horizon_domain_separator: self.horizon_domain_separator.clone(),
             sender_account_ref: sender_account_ref.clone(),
             sender_aggregator: self.sender_aggregator.clone(),
+            sender_denied: self.denied,
+            startup_scans: self.startup_scans.clone(),
+            deployment_fees: self.deployment_fees.clone(),
+            sender_metrics: self.metrics.clone(),
         };
 
-        SenderAllocation::spawn_linked(
+        let (sender_allocation, _) = SenderAllocation::spawn_linked(
             Some(self.format_sender_allocation(&allocation_id)),
             SenderAllocation,
             args,
             sender_account_ref.get_cell(),
         )
         .await?;
-        Ok(())
+        Ok(sender_allocation)
     }
     fn format_sender_allocation(&self, allocation_id: &Address) -> String {
         let mut sender_allocation_id = String::new();
@@ -199,13 +387,75 @@ impl State {
         sender_allocation_id
     }
 
-    async fn rav_request_for_heaviest_allocation(&mut self) -> Result<()> {
+    /// Called once [`Quarantine::record_panic`] quarantined the allocation.
+    fn quarantine_allocation(&mut self, allocation_id: Address) {
+        tracing::error!(
+            event = event::ALLOCATION_QUARANTINED,
+            sender = %self.sender,
+            %allocation_id,
+            max_restarts = self.config.tap.supervision.max_allocation_restarts,
+            window = ?self.config.tap.supervision.restart_window,
+            "SenderAllocation keeps panicking, the allocation is quarantined until resumed \
+            through the admin API."
+        );
+        self.sender_fee_tracker.block_allocation_id(allocation_id);
+        self.metrics
+            .with_label_values(
+                &ALLOCATION_QUARANTINED,
+                &[&self.sender.to_string(), &allocation_id.to_string()],
+            )
+            .set(1);
+    }
+
+    async fn resume_allocation(
+        &mut self,
+        sender_account_ref: ActorRef<SenderAccountMessage>,
+        allocation_id: Address,
+    ) -> Result<(), ResumeError> {
+        if !self.quarantine.is_quarantined(&allocation_id) {
+            return Err(ResumeError::NotQuarantined(allocation_id));
+        }
+        let sender_allocation = self
+            .create_sender_allocation(sender_account_ref, allocation_id)
+            .await
+            .map_err(|e| ResumeError::StartFailed(allocation_id, e.to_string()))?;
+        self.quarantine.resume(&allocation_id);
+        self.metrics.remove(
+            &ALLOCATION_QUARANTINED,
+            &[&self.sender.to_string(), &allocation_id.to_string()],
+        );
+        tracing::info!(sender = %self.sender, %allocation_id, "Quarantined allocation resumed.");
+
+        if self.allocation_ids.contains(&allocation_id) {
+            self.sender_fee_tracker.unblock_allocation_id(allocation_id);
+        } else {
+            // Closed while quarantined, stopping it requests its last RAV
+            sender_allocation.stop(None);
+        }
+        Ok(())
+    }
+
+    /// See [`crate::agent::rav_queue`].
+    fn rav_queue(&mut self) -> BinaryHeap<RavQueueEntry> {
+        rav_queue::build(
+            self.sender_fee_tracker.rav_candidates(),
+            &self.unaggregated_since,
+            &self.closing_allocation_ids,
+            Instant::now(),
+        )
+    }
+
+    async fn rav_request_for_next_allocation(
+        &mut self,
+        correlation_id: CorrelationId,
+    ) -> Result<()> {
         let allocation_id = self
-            .sender_fee_tracker
-            .get_heaviest_allocation_id()
+            .rav_queue()
+            .pop()
+            .map(|entry| entry.allocation_id)
             .ok_or_else(|| {
                 anyhow::anyhow!(
-                    "Error while getting the heaviest allocation, \
+                    "Error while getting the next allocation to request a RAV for, \
             this is due one of the following reasons: \n
             1. allocations have too much fees under their buffer\n
             2. allocations are blocked to be redeemed due to ongoing last rav. \n
@@ -214,10 +464,21 @@ impl State {
             If this doesn't work, open an issue on our Github."
                 )
             })?;
-        self.rav_request_for_allocation(allocation_id).await
+        self.rav_request_for_allocation(allocation_id, correlation_id)
+            .await
     }
 
-    async fn rav_request_for_allocation(&mut self, allocation_id: Address) -> Result<()> {
+    async fn rav_request_for_allocation(
+        &mut self,
+        allocation_id: Address,
+        correlation_id: CorrelationId,
+    ) -> Result<()> {
+        if self.quarantine.is_quarantined(&allocation_id) {
+            anyhow::bail!("Allocation {allocation_id} is quarantined");
+        }
+        if self.paused {
+            anyhow::bail!("Sender {} is paused", self.sender);
+        }
         let sender_allocation_id = self.format_sender_allocation(&allocation_id);
         let allocation = ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_id);
 
@@ -226,89 +487,446 @@ impl State {
         };
 
         allocation
-            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .cast(SenderAllocationMessage::TriggerRAVRequest(
+                correlation_id,
+                Span::current(),
+            ))
             .map_err(|e| {
                 anyhow::anyhow!(
                     "Error while sending and waiting message for actor {allocation_id}. Error: {e}"
                 )
             })?;
         self.sender_fee_tracker.start_rav_request(allocation_id);
+        tracing::info!(
+            event = event::RAV_REQUEST_TRIGGERED,
+            sender = %self.sender,
+            allocation = %allocation_id,
+            %correlation_id,
+            "RAV request triggered."
+        );
 
         Ok(())
     }
 
+    /// Requests a RAV for the allocations with fees unaggregated for longer than `interval`,
+    /// however small, so that long-lived allocations don't build up arbitrarily large RAV
+    /// requests. The fees still in the timestamp buffer wait for the next check.
+    async fn request_checkpoint_ravs(&mut self, interval: Duration) {
+        if self.paused {
+            return;
+        }
+        let due: Vec<Address> = self
+            .unaggregated_since
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= interval)
+            .map(|(allocation_id, _)| *allocation_id)
+            .collect();
+        for allocation_id in due {
+            let has_fees = self
+                .sender_fee_tracker
+                .get_total_counter_outside_buffer_for_allocation(&allocation_id)
+                > 0;
+            if !has_fees
+                || self
+                    .sender_fee_tracker
+                    .check_allocation_has_rav_request_running(allocation_id)
+            {
+                continue;
+            }
+            tracing::info!(
+                sender = %self.sender,
+                %allocation_id,
+                ?interval,
+                "Fees unaggregated for longer than the checkpoint interval. \
+                Triggering checkpoint RAV request"
+            );
+            match self
+                .rav_request_for_allocation(allocation_id, CorrelationId::new())
+                .await
+            {
+                Ok(()) => self
+                    .metrics
+                    .with_label_values(&CHECKPOINT_RAV_REQUESTS, &[&self.sender.to_string()])
+                    .inc(),
+                Err(err) => {
+                    tracing::error!(
+                        error = %err,
+                        %allocation_id,
+                        "There was an error while requesting a checkpoint RAV."
+                    );
+                    // Most likely closed, its last RAV takes care of the fees
+                    self.unaggregated_since.remove(&allocation_id);
+                }
+            }
+        }
+    }
+
+    /// Exports the fees inside and outside of the timestamp buffer, and counts the evaluations
+    /// which would have triggered a RAV request without the buffer, to tune
+    /// `rav_request_timestamp_buffer_ms`.
+    fn record_buffer_occupancy(&self, total_fee_outside_buffer: u128, triggered: bool) {
+        let sender = self.sender.to_string();
+        let total_fee = self.sender_fee_tracker.get_total_fee();
+        self.metrics
+            .with_label_values(&FEES_INSIDE_BUFFER, &[&sender])
+            .set((total_fee - total_fee_outside_buffer) as f64);
+        self.metrics
+            .with_label_values(&FEES_OUTSIDE_BUFFER, &[&sender])
+            .set(total_fee_outside_buffer as f64);
+        if !triggered && total_fee >= self.thresholds.rav_request_trigger_value {
+            self.metrics
+                .with_label_values(&RAV_TRIGGER_SKIPPED_BY_BUFFER, &[&sender])
+                .inc();
+        }
+    }
+
+    /// Denies the sender if needed, requests a RAV if a trigger is reached after an update of
+    /// the fees of the allocation, then allows the sender again if possible.
+    async fn evaluate_rav_triggers(
+        &mut self,
+        myself: &ActorRef<SenderAccountMessage>,
+        allocation_id: Address,
+        correlation_id: CorrelationId,
+    ) {
+        // Eagerly deny the sender (if needed), before the RAV request. To be sure not to
+        // delay the denial because of the RAV request, which could take some time.
+
+        let should_deny = !self.denied && self.deny_condition_reached();
+        if should_deny {
+            self.add_to_denylist().await;
+        }
+        let total_counter_for_allocation = self
+            .sender_fee_tracker
+            .get_total_counter_outside_buffer_for_allocation(&allocation_id);
+        let counter_greater_receipt_limit = total_counter_for_allocation
+            >= self.config.tap.rav_request_receipt_limit
+            && !self
+                .sender_fee_tracker
+                .check_allocation_has_rav_request_running(allocation_id);
+        let total_fee_outside_buffer = self.sender_fee_tracker.get_total_fee_outside_buffer();
+        let total_fee_greater_trigger_value =
+            total_fee_outside_buffer >= self.thresholds.rav_request_trigger_value;
+        self.record_buffer_occupancy(
+            total_fee_outside_buffer,
+            counter_greater_receipt_limit || total_fee_greater_trigger_value,
+        );
+        let trigger_span = tracing::info_span!(
+            "rav_trigger_decision",
+            sender = %self.sender,
+            allocation = %allocation_id,
+            %correlation_id,
+        );
+        let rav_result = async {
+            match (
+                counter_greater_receipt_limit,
+                total_fee_greater_trigger_value,
+            ) {
+                (true, _) | (_, true) if self.paused => {
+                    tracing::debug!("RAV trigger reached, the sender is paused");
+                    Ok(())
+                }
+                (true, _) => {
+                    tracing::debug!(
+                        total_counter_for_allocation,
+                        rav_request_receipt_limit = self.config.tap.rav_request_receipt_limit,
+                        %allocation_id,
+                        "Total counter greater than the receipt limit per rav. Triggering RAV request"
+                    );
+
+                    self
+                        .rav_request_for_allocation(allocation_id, correlation_id)
+                        .await
+                }
+                (_, true) => {
+                    tracing::debug!(
+                        total_fee_outside_buffer = %Money(total_fee_outside_buffer),
+                        trigger_value = %Money(self.thresholds.rav_request_trigger_value),
+                        "Total fee greater than the trigger value. Triggering RAV request"
+                    );
+                    self
+                        .rav_request_for_next_allocation(correlation_id)
+                        .await
+                }
+                _ => Ok(()),
+            }
+        }
+        .instrument(trigger_span)
+        .await;
+        // In case we fail, we want our actor to keep running
+        if let Err(err) = rav_result {
+            tracing::error!(
+                error = %err,
+                "There was an error while requesting a RAV."
+            );
+        }
+
+        match (self.denied, self.deny_condition_reached()) {
+            // Allow the sender right after the potential RAV request. This way, the
+            // sender can be allowed again as soon as possible if the RAV was successful.
+            (true, false) if self.may_allow() => self.remove_from_denylist().await,
+            // if couldn't remove from denylist, resend the message in 30 seconds
+            // this may trigger another rav request
+            (true, true) => {
+                // retry in a moment
+                self.scheduled_rav_request =
+                    Some(myself.send_after(self.thresholds.retry_interval, move || {
+                        SenderAccountMessage::UpdateReceiptFees(allocation_id, ReceiptFees::Retry)
+                    }));
+            }
+            _ => {}
+        }
+    }
+
     fn deny_condition_reached(&self) -> bool {
-        let pending_ravs = self.rav_tracker.get_total_fee();
-        let unaggregated_fees = self.sender_fee_tracker.get_total_fee();
-        let pending_fees_over_balance =
-            U256::from(pending_ravs + unaggregated_fees) >= self.sender_balance;
-        let max_unaggregated_fees = self.config.tap.max_unnaggregated_fees_per_sender;
-        let invalid_receipt_fees = self.invalid_receipts_tracker.get_total_fee();
-        let total_fee_over_max_value =
-            unaggregated_fees + invalid_receipt_fees >= max_unaggregated_fees;
+        let inputs = self.deny_condition_inputs();
 
         tracing::trace!(
-            %pending_fees_over_balance,
-            %total_fee_over_max_value,
+            reason = ?inputs.reason(),
             "Verifying if deny condition was reached.",
         );
 
-        total_fee_over_max_value || pending_fees_over_balance
+        DENY_CONDITION_INPUTS.record(&self.sender, &inputs);
+        self.metrics
+            .with_label_values(&SENDER_TRUST_SCORE, &[&self.sender.to_string()])
+            .set(inputs.trust_score);
+        let top_up = self.escrow_top_up();
+        escrow_top_up::publish(self.sender, top_up);
+        self.metrics
+            .with_label_values(&ESCROW_TOP_UP_SUGGESTION, &[&self.sender.to_string()])
+            .set(top_up as f64);
+        inputs.reached
+    }
+
+    fn deny_condition_inputs(&self) -> DenyConditionInputs {
+        let mut inputs = DenyConditionInputs {
+            pending_ravs: self.rav_tracker.get_total_fee()
+                + self.horizon_rav_tracker.get_total_fee(),
+            unaggregated_fees: self.sender_fee_tracker.get_total_fee(),
+            invalid_receipt_fees: self.invalid_receipts_tracker.get_total_fee(),
+            sender_balance: self.sender_balance.to_u128().unwrap_or(u128::MAX),
+            expected_balance_decrease: match self.balance_trend {
+                Trend::Decreasing { last_decrease } => last_decrease.to_u128().unwrap_or(u128::MAX),
+                _ => 0,
+            },
+            max_unaggregated_fees: self.thresholds.max_unnaggregated_fees_per_sender,
+            trust_score: self.trust().score,
+            min_trust_score: self.config.tap.trust_score.deny_below,
+            reached: false,
+        };
+        inputs.reached = inputs.reason().is_some();
+        inputs
+    }
+
+    /// See [`crate::agent::trust_score`].
+    fn trust(&self) -> SenderTrust {
+        self.trust.trust(
+            Instant::now(),
+            self.invalid_receipts_tracker.get_total_fee(),
+            self.sender_fee_tracker.get_total_fee()
+                + self.rav_tracker.get_total_fee()
+                + self.horizon_rav_tracker.get_total_fee(),
+        )
+    }
+
+    async fn escrow(&self) -> SenderEscrow {
+        let reservation = self.escrow_adapter.reservation().await;
+        SenderEscrow {
+            error: reservation.as_ref().err().map(ToString::to_string),
+            reservation: reservation.ok(),
+            pending_ravs: self.rav_tracker.get_total_fee(),
+            unaggregated_fees: self.sender_fee_tracker.get_total_fee(),
+            invalid_receipt_fees: self.invalid_receipts_tracker.get_total_fee(),
+        }
+    }
+
+    /// See [`crate::agent::escrow_top_up`].
+    fn escrow_top_up(&self) -> u128 {
+        escrow_top_up::top_up(
+            self.sender_balance.to_u128().unwrap_or(u128::MAX),
+            self.rav_tracker.get_total_fee()
+                + self.horizon_rav_tracker.get_total_fee()
+                + self.sender_fee_tracker.get_total_fee(),
+            self.fee_velocity.per_second(Instant::now()),
+            self.config.tap.escrow_top_up.horizon,
+        )
+    }
+
+    /// See [`crate::agent::rate_limits`].
+    fn rate_limit(&self, config: &RateLimits) -> RateLimit {
+        let headroom = self
+            .sender_balance
+            .to_u128()
+            .unwrap_or(u128::MAX)
+            .saturating_sub(
+                self.rav_tracker.get_total_fee()
+                    + self.horizon_rav_tracker.get_total_fee()
+                    + self.sender_fee_tracker.get_total_fee(),
+            );
+        rate_limits::rate_limit(
+            config,
+            headroom,
+            self.fee_velocity.per_second(Instant::now()),
+        )
+    }
+
+    fn record_deny_event(&mut self, reason: Option<DenyReason>) {
+        if self.deny_events.len() == MAX_DENY_EVENTS {
+            self.deny_events.pop_front();
+        }
+        self.deny_events.push_back(DenyEvent {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            denied: self.denied,
+            reason,
+        });
+    }
+
+    fn status(&self) -> SenderAccountStatus {
+        let mut allocation_ids: Vec<Address> = self
+            .sender_fee_tracker
+            .get_list_of_allocation_ids()
+            .into_iter()
+            .chain(self.rav_tracker.get_list_of_allocation_ids())
+            .chain(self.horizon_rav_tracker.get_list_of_allocation_ids())
+            .chain(self.invalid_receipts_tracker.get_list_of_allocation_ids())
+            .chain(self.allocation_ids.iter().copied())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        allocation_ids.sort();
+        SenderAccountStatus {
+            denied: self.denied,
+            paused: self.paused,
+            escrow_balance: self.sender_balance.to_u128().unwrap_or(u128::MAX),
+            unaggregated_fees: self.sender_fee_tracker.get_total_fee(),
+            pending_ravs: self.rav_tracker.get_total_fee()
+                + self.horizon_rav_tracker.get_total_fee(),
+            invalid_receipt_fees: self.invalid_receipts_tracker.get_total_fee(),
+            fees_per_hour: (self.fee_velocity.per_second(Instant::now()) * 3600.0) as u128,
+            escrow_top_up: self.escrow_top_up(),
+            allocations: allocation_ids
+                .into_iter()
+                .map(|allocation_id| AllocationStatus {
+                    allocation_id,
+                    unaggregated_fees: self.sender_fee_tracker.get_fee(&allocation_id),
+                    pending_rav: self.rav_tracker.get_fee(&allocation_id)
+                        + self.horizon_rav_tracker.get_fee(&allocation_id),
+                    invalid_receipt_fees: self.invalid_receipts_tracker.get_fee(&allocation_id),
+                    quarantined: self.quarantine.is_quarantined(&allocation_id),
+                })
+                .collect(),
+            deny_events: self.deny_events.iter().cloned().collect(),
+        }
+    }
+
+    /// Lets the `SenderAllocation`s apply `tap.denied_sender_receipts`.
+    fn notify_sender_denied(&self) {
+        for allocation_id in &self.allocation_ids {
+            if let Some(sender_allocation) = ActorRef::<SenderAllocationMessage>::where_is(
+                self.format_sender_allocation(allocation_id),
+            ) {
+                if let Err(e) =
+                    sender_allocation.cast(SenderAllocationMessage::SenderDenied(self.denied))
+                {
+                    error!(
+                        "Error while notifying allocation {} of the sender deny status: {}",
+                        allocation_id, e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn pause(&mut self, reason: Option<String>) -> Result<(), PauseError> {
+        sender_pause::pause(&self.pgpool, self.sender, reason.clone()).await?;
+        tracing::warn!(sender = %self.sender, ?reason, "Sender paused.");
+        self.paused = true;
+        self.metrics
+            .with_label_values(&SENDER_PAUSED, &[&self.sender.to_string()])
+            .set(1);
+        Ok(())
+    }
+
+    /// Resumes the sender, then evaluates the RAV triggers of its allocations with fees, which
+    /// may have been reached while it was paused.
+    async fn resume(&mut self, myself: &ActorRef<SenderAccountMessage>) -> Result<(), PauseError> {
+        sender_pause::resume(&self.pgpool, self.sender).await?;
+        tracing::info!(sender = %self.sender, "Sender resumed.");
+        self.paused = false;
+        self.metrics
+            .with_label_values(&SENDER_PAUSED, &[&self.sender.to_string()])
+            .set(0);
+        for allocation_id in self.sender_fee_tracker.get_list_of_allocation_ids() {
+            self.pending_trigger_evaluations
+                .entry(allocation_id)
+                .or_insert_with(CorrelationId::new);
+        }
+        if let Err(e) = myself.cast(SenderAccountMessage::EvaluateRavTriggers) {
+            error!(
+                "Error while evaluating the RAV triggers after resuming: {}",
+                e
+            );
+        }
+        Ok(())
     }
 
     /// Will update [`State::denied`], as well as the denylist table in the database.
     async fn add_to_denylist(&mut self) {
+        let denial = self.deny_condition_inputs().denial();
         tracing::warn!(
-            fee_tracker = self.sender_fee_tracker.get_total_fee(),
-            rav_tracker = self.rav_tracker.get_total_fee(),
-            max_fee_per_sender = self.config.tap.max_unnaggregated_fees_per_sender,
-            sender_balance = self.sender_balance.to_u128(),
+            event = event::SENDER_DENIED,
+            sender = %self.sender,
+            reason = denial.reason.as_str(),
+            fee_tracker = %Money(self.sender_fee_tracker.get_total_fee()),
+            rav_tracker = %Money(self.rav_tracker.get_total_fee()),
+            max_fee_per_sender = %Money(self.thresholds.max_unnaggregated_fees_per_sender),
+            sender_balance = %Money(self.sender_balance.to_u128().unwrap_or(u128::MAX)),
             "Denying sender."
         );
 
-        SenderAccount::deny_sender(&self.pgpool, self.sender).await;
+        let reason = denial.reason;
+        self.denylist.deny(self.sender, denial).await;
         self.denied = true;
-        SENDER_DENIED
-            .with_label_values(&[&self.sender.to_string()])
+        self.deny_reason = Some(reason);
+        self.record_deny_event(Some(reason));
+        self.notify_sender_denied();
+        self.metrics
+            .with_label_values(&SENDER_DENIED, &[&self.sender.to_string()])
             .set(1);
     }
 
+    /// Whether the sender may be allowed again once the deny condition is gone, never if the
+    /// operator denied it.
+    fn may_allow(&self) -> bool {
+        self.deny_reason
+            .map_or(true, |reason| reason.is_automatic())
+    }
+
     /// Will update [`State::denied`], as well as the denylist table in the database.
     async fn remove_from_denylist(&mut self) {
         tracing::info!(
-            fee_tracker = self.sender_fee_tracker.get_total_fee(),
-            rav_tracker = self.rav_tracker.get_total_fee(),
-            max_fee_per_sender = self.config.tap.max_unnaggregated_fees_per_sender,
-            sender_balance = self.sender_balance.to_u128(),
+            event = event::SENDER_ALLOWED,
+            sender = %self.sender,
+            fee_tracker = %Money(self.sender_fee_tracker.get_total_fee()),
+            rav_tracker = %Money(self.rav_tracker.get_total_fee()),
+            max_fee_per_sender = %Money(self.thresholds.max_unnaggregated_fees_per_sender),
+            sender_balance = %Money(self.sender_balance.to_u128().unwrap_or(u128::MAX)),
             "Allowing sender."
         );
-        sqlx::query!(
-            r#"
-                    DELETE FROM scalar_tap_denylist
-                    WHERE sender_address = $1
-                "#,
-            self.sender.encode_hex(),
-        )
-        .execute(&self.pgpool)
-        .await
-        .expect("Should not fail to delete from denylist");
+        self.denylist.allow(self.sender).await;
         self.denied = false;
+        self.deny_reason = None;
+        self.record_deny_event(None);
+        self.notify_sender_denied();
 
-        SENDER_DENIED
-            .with_label_values(&[&self.sender.to_string()])
+        self.metrics
+            .with_label_values(&SENDER_DENIED, &[&self.sender.to_string()])
             .set(0);
     }
 }
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "../graphql/tap.schema.graphql",
-    query_path = "../graphql/unfinalized_tx.query.graphql",
-    response_derives = "Debug",
-    variables_derives = "Clone"
-)]
-struct UnfinalizedTransactions;
-
 #[async_trait::async_trait]
 impl Actor for SenderAccount {
     type Msg = SenderAccountMessage;
@@ -321,98 +939,87 @@ impl Actor for SenderAccount {
         SenderAccountArgs {
             config,
             pgpool,
+            allocation_pgpool,
+            denylist,
             sender_id,
             escrow_accounts,
             indexer_allocations,
+            closing_allocations,
             escrow_subgraph,
+            redeemed_ravs,
+            startup_scans,
+            deployment_fees,
             domain_separator,
+            horizon_domain_separator,
             sender_aggregator_endpoint,
             allocation_ids,
             prefix,
-            retry_interval,
+            thresholds,
         }: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
         let myself_clone = myself.clone();
-        let _indexer_allocations_handle =
-            indexer_allocations
-                .clone()
-                .pipe_async(move |allocation_ids| {
-                    let myself = myself_clone.clone();
-                    async move {
-                        // Update the allocation_ids
-                        myself
-                            .cast(SenderAccountMessage::UpdateAllocationIds(allocation_ids))
-                            .unwrap_or_else(|e| {
-                                error!("Error while updating allocation_ids: {:?}", e);
-                            });
-                    }
-                });
+        let mut indexer_allocations = indexer_allocations;
+        let indexer_allocations_watcher = tokio::spawn(async move {
+            loop {
+                let allocation_ids = indexer_allocations
+                    .borrow_and_update()
+                    .keys()
+                    .cloned()
+                    .collect::<HashSet<Address>>();
+                // Update the allocation_ids
+                if let Err(e) =
+                    myself_clone.cast(SenderAccountMessage::UpdateAllocationIds(allocation_ids))
+                {
+                    error!("Error while updating allocation_ids: {:?}", e);
+                    break;
+                }
+                if indexer_allocations.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let myself_clone = myself.clone();
+        let _closing_allocations_handle =
+            closing_allocations.pipe_async(move |closing_allocation_ids| {
+                let myself = myself_clone.clone();
+                async move {
+                    myself
+                        .cast(SenderAccountMessage::UpdateClosingAllocationIds(
+                            closing_allocation_ids,
+                        ))
+                        .unwrap_or_else(|e| {
+                            error!("Error while updating closing allocation_ids: {:?}", e);
+                        });
+                }
+            });
+
+        // Horizon receipts are collected by the verifying contract of their domain.
+        let horizon_collector = horizon_domain_separator
+            .as_ref()
+            .and_then(|domain| domain.verifying_contract);
 
         let myself_clone = myself.clone();
-        let pgpool_clone = pgpool.clone();
         let _escrow_account_monitor = escrow_accounts.clone().pipe_async(move |escrow_account| {
             let myself = myself_clone.clone();
-            let pgpool = pgpool_clone.clone();
-            // get balance or default value for sender
+            let redeemed_ravs = redeemed_ravs.clone();
             // this balance already takes into account thawing
-            let balance = escrow_account
-                .get_balance_for_sender(&sender_id)
-                .unwrap_or_default();
+            let balance =
+                SenderAccount::sender_balance(&escrow_account, sender_id, horizon_collector);
+            let balance_trend = escrow_account.balance_trend(&sender_id);
 
             async move {
-                let last_non_final_ravs = sqlx::query!(
-                    r#"
-                            SELECT allocation_id, value_aggregate
-                            FROM scalar_tap_ravs
-                            WHERE sender_address = $1 AND last AND NOT final;
-                        "#,
-                    sender_id.encode_hex(),
-                )
-                .fetch_all(&pgpool)
-                .await
-                .expect("Should not fail to fetch from scalar_tap_ravs");
-
-                // get a list from the subgraph of which subgraphs were already redeemed and were not marked as final
-                let redeemed_ravs_allocation_ids = match escrow_subgraph
-                    .query::<UnfinalizedTransactions, _>(unfinalized_transactions::Variables {
-                        unfinalized_ravs_allocation_ids: last_non_final_ravs
-                            .iter()
-                            .map(|rav| rav.allocation_id.to_string())
-                            .collect::<Vec<_>>(),
-                        sender: format!("{:x?}", sender_id),
-                    })
+                // the last RAVs that were not redeemed yet, looked up once for all the senders
+                let non_redeemed_ravs = redeemed_ravs
+                    .non_redeemed_ravs(&escrow_account, sender_id)
                     .await
-                {
-                    Ok(Ok(response)) => response
-                        .transactions
-                        .into_iter()
-                        .map(|tx| {
-                            tx.allocation_id
-                                .expect("all redeem tx must have allocation_id")
-                        })
-                        .collect::<Vec<_>>(),
-                    // if we have any problems, we don't want to filter out
-                    _ => vec![],
-                };
-
-                // filter the ravs marked as last that were not redeemed yet
-                let non_redeemed_ravs = last_non_final_ravs
-                    .into_iter()
-                    .filter_map(|rav| {
-                        Some((
-                            Address::from_str(&rav.allocation_id).ok()?,
-                            rav.value_aggregate.to_bigint().and_then(|v| v.to_u128())?,
-                        ))
-                    })
-                    .filter(|(allocation, _value)| {
-                        !redeemed_ravs_allocation_ids.contains(&format!("{:x?}", allocation))
-                    })
-                    .collect::<HashMap<_, _>>();
+                    .expect("Should not fail to fetch from scalar_tap_ravs");
 
                 // Update the allocation_ids
                 myself
                     .cast(SenderAccountMessage::UpdateBalanceAndLastRavs(
                         balance,
+                        balance_trend,
                         non_redeemed_ravs,
                     ))
                     .unwrap_or_else(|e| {
@@ -424,69 +1031,121 @@ impl Actor for SenderAccount {
             }
         });
 
-        let escrow_adapter = EscrowAdapter::new(escrow_accounts.clone(), sender_id);
-
-        // Get deny status from the scalar_tap_denylist table
-        let denied = sqlx::query!(
-            r#"
-                SELECT EXISTS (
-                    SELECT 1
-                    FROM scalar_tap_denylist
-                    WHERE sender_address = $1
-                ) as denied
+        let escrow_adapter = EscrowAdapter::new(escrow_accounts.clone(), sender_id)
+            .with_horizon_collector(horizon_collector);
+
+        // Get deny status from the scalar_tap_denylist table, unless a write to it is pending
+        let deny_reason = match denylist.pending_intent(sender_id).await {
+            Some(Intent::Deny(denial)) => Some(denial.reason),
+            Some(Intent::Allow) => None,
+            None => database::acquire(&pgpool, Subsystem::Denylist)
+                .await?
+                .run(|conn| {
+                    sqlx::query!(
+                        r#"
+                SELECT reason
+                FROM scalar_tap_denylist
+                WHERE sender_address = $1
             "#,
-            sender_id.encode_hex(),
-        )
-        .fetch_one(&pgpool)
-        .await?
-        .denied
-        .expect("Deny status cannot be null");
+                        sender_id.encode_hex(),
+                    )
+                    .fetch_optional(conn)
+                })
+                .await?
+                .map(|row| DenyReason::parse(row.reason.as_deref())),
+        };
+        let denied = deny_reason.is_some();
 
-        let sender_balance = escrow_accounts
-            .value()
-            .await
-            .expect("should be able to get escrow accounts")
-            .get_balance_for_sender(&sender_id)
-            .unwrap_or_default();
+        let sender_balance = SenderAccount::sender_balance(
+            &escrow_accounts
+                .value()
+                .await
+                .expect("should be able to get escrow accounts"),
+            sender_id,
+            horizon_collector,
+        );
+
+        let metrics = Arc::new(SeriesOwner::default());
 
-        SENDER_DENIED
-            .with_label_values(&[&sender_id.to_string()])
+        metrics
+            .with_label_values(&SENDER_DENIED, &[&sender_id.to_string()])
             .set(denied as i64);
 
-        MAX_FEE_PER_SENDER
-            .with_label_values(&[&sender_id.to_string()])
-            .set(config.tap.max_unnaggregated_fees_per_sender as f64);
+        let paused = sender_pause::is_paused(&pgpool, sender_id).await?;
+        if config.tap.rate_limits.is_none() {
+            // Left behind while they were enabled
+            rate_limits::remove(&pgpool, sender_id).await?;
+        }
+        metrics
+            .with_label_values(&SENDER_PAUSED, &[&sender_id.to_string()])
+            .set(paused as i64);
 
-        RAV_REQUEST_TRIGGER_VALUE
-            .with_label_values(&[&sender_id.to_string()])
-            .set(config.tap.rav_request_trigger_value as f64);
+        set_threshold_metrics(&metrics, &sender_id, &thresholds);
 
-        let sender_aggregator = HttpClientBuilder::default()
-            .request_timeout(Duration::from_secs(config.tap.rav_request_timeout_secs))
-            .build(&sender_aggregator_endpoint)?;
+        let sender_aggregator = aggregator_client::build(
+            &sender_aggregator_endpoint,
+            Duration::from_secs(config.tap.rav_request_timeout_secs),
+            config.tap.sender_aggregator_auth.get(&sender_id),
+        )?;
 
         let state = State {
             sender_fee_tracker: SenderFeeTracker::new(Duration::from_millis(
                 config.tap.rav_request_timestamp_buffer_ms,
             )),
             rav_tracker: SenderFeeTracker::default(),
+            horizon_rav_tracker: SenderFeeTracker::default(),
             invalid_receipts_tracker: SenderFeeTracker::default(),
             allocation_ids: allocation_ids.clone(),
-            _indexer_allocations_handle,
+            indexer_allocations_watcher,
+            closing_allocation_ids: HashSet::new(),
+            quarantine: Quarantine::new(&config.tap.supervision),
+            paused,
+            trust: TrustTracker::new(&config.tap.trust_score),
+            rav_anomalies: RavAnomalyDetector::new(
+                &config.tap.rav_anomalies,
+                sender_id,
+                Instant::now(),
+            ),
+            _closing_allocations_handle,
             _escrow_account_monitor,
             prefix,
             escrow_accounts,
             escrow_subgraph,
             escrow_adapter,
             domain_separator,
+            horizon_domain_separator,
             sender_aggregator,
+            metrics,
             config,
             pgpool,
+            allocation_pgpool,
+            denylist,
+            startup_scans,
+            deployment_fees,
             sender: sender_id,
             denied,
+            deny_reason,
+            deny_events: VecDeque::new(),
             sender_balance,
-            retry_interval,
+            balance_trend: Trend::default(),
+            fee_velocity: FeeVelocity::new(config.tap.escrow_top_up.velocity_window),
+            thresholds,
             scheduled_rav_request: None,
+            pending_trigger_evaluations: HashMap::new(),
+            unaggregated_since: HashMap::new(),
+            checkpoint_timer: config.tap.rav_checkpoint_interval.map(|interval| {
+                myself.send_interval(interval / CHECKPOINT_CHECKS_PER_INTERVAL, || {
+                    SenderAccountMessage::CheckpointRavs
+                })
+            }),
+            rate_limit_timer: config.tap.rate_limits.as_ref().map(|rate_limits| {
+                myself.send_interval(rate_limits.interval, || {
+                    SenderAccountMessage::PublishRateLimit
+                })
+            }),
+            rav_anomalies_timer: myself.send_interval(rav_anomalies::RATE_INTERVAL, || {
+                SenderAccountMessage::SampleReceiptRate
+            }),
         };
 
         for allocation_id in &allocation_ids {
@@ -500,6 +1159,30 @@ impl Actor for SenderAccount {
         Ok(state)
     }
 
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> std::result::Result<(), ActorProcessingErr> {
+        if let Some(checkpoint_timer) = &state.checkpoint_timer {
+            checkpoint_timer.abort();
+        }
+        if let Some(rate_limit_timer) = &state.rate_limit_timer {
+            rate_limit_timer.abort();
+            if let Err(e) = rate_limits::remove(&state.pgpool, state.sender).await {
+                tracing::warn!(error = %e, "Failed to remove the rate limit of the sender.");
+            }
+        }
+        state.rav_anomalies_timer.abort();
+        state.rav_anomalies.remove_series();
+        state.indexer_allocations_watcher.abort();
+        DENY_CONDITION_INPUTS.remove(&state.sender);
+        escrow_top_up::remove(&state.sender);
+        // Explicitly, a new `SenderAccount` of the sender may start before the state is dropped
+        state.metrics.remove_all();
+        Ok(())
+    }
+
     async fn handle(
         &self,
         myself: ActorRef<Self::Msg>,
@@ -522,11 +1205,15 @@ impl Actor for SenderAccount {
                     .rav_tracker
                     .update(rav.message.allocationId, rav.message.valueAggregate, 0);
 
-                PENDING_RAV
-                    .with_label_values(&[
-                        &state.sender.to_string(),
-                        &rav.message.allocationId.to_string(),
-                    ])
+                state
+                    .metrics
+                    .with_label_values(
+                        &PENDING_RAV,
+                        &[
+                            &state.sender.to_string(),
+                            &rav.message.allocationId.to_string(),
+                        ],
+                    )
                     .set(rav.message.valueAggregate as f64);
 
                 let should_deny = !state.denied && state.deny_condition_reached();
@@ -534,9 +1221,21 @@ impl Actor for SenderAccount {
                     state.add_to_denylist().await;
                 }
             }
+            SenderAccountMessage::UpdateHorizonRav(allocation_id, value) => {
+                state.horizon_rav_tracker.update(allocation_id, value, 0);
+
+                let should_deny = !state.denied && state.deny_condition_reached();
+                if should_deny {
+                    state.add_to_denylist().await;
+                }
+            }
             SenderAccountMessage::UpdateInvalidReceiptFees(allocation_id, unaggregated_fees) => {
-                INVALID_RECEIPT_FEES
-                    .with_label_values(&[&state.sender.to_string(), &allocation_id.to_string()])
+                state
+                    .metrics
+                    .with_label_values(
+                        &INVALID_RECEIPT_FEES,
+                        &[&state.sender.to_string(), &allocation_id.to_string()],
+                    )
                     .set(unaggregated_fees.value as f64);
 
                 state
@@ -555,8 +1254,24 @@ impl Actor for SenderAccount {
                     scheduled_rav_request.abort();
                 }
 
+                // Receipts carry the id assigned when their notification was received, any other
+                // update that ends up triggering a RAV request gets a fresh one.
+                let correlation_id = match &receipt_fees {
+                    ReceiptFees::NewReceipts(_, _, correlation_id, ..) => *correlation_id,
+                    _ => CorrelationId::new(),
+                };
+                let new_receipts = matches!(receipt_fees, ReceiptFees::NewReceipts(..));
+
                 match receipt_fees {
-                    ReceiptFees::NewReceipt(value) => {
+                    ReceiptFees::NewReceipts(value, count, _, mut stages, timestamp_skew) => {
+                        receipt_profiler::observe(&mut stages, stage::ACCOUNT_MAILBOX);
+                        state
+                            .metrics
+                            .with_label_values(
+                                &RECEIPT_FEES_MAILBOX_DEPTH,
+                                &[&state.sender.to_string()],
+                            )
+                            .dec();
                         // If state is denied and received new receipt, sender was removed manually from DB
                         if state.denied {
                             tracing::warn!(
@@ -567,15 +1282,34 @@ impl Actor for SenderAccount {
                                 fee ***MONEY***.
                                 "
                             );
-                            SenderAccount::deny_sender(&state.pgpool, state.sender).await;
+                            state
+                                .denylist
+                                .deny(
+                                    state.sender,
+                                    Denial::from(state.deny_reason.unwrap_or(DenyReason::Unknown)),
+                                )
+                                .await;
                         }
-                        state.sender_fee_tracker.add(allocation_id, value);
-
-                        UNAGGREGATED_FEES
-                            .with_label_values(&[
-                                &state.sender.to_string(),
-                                &allocation_id.to_string(),
-                            ])
+                        state
+                            .sender_fee_tracker
+                            .add_batch(allocation_id, value, count);
+                        receipt_profiler::observe(&mut stages, stage::TRACKER_UPDATE);
+                        state
+                            .unaggregated_since
+                            .entry(allocation_id)
+                            .or_insert_with(Instant::now);
+                        state.fee_velocity.add(Instant::now(), value);
+                        state
+                            .trust
+                            .record_timestamp_skew(Instant::now(), timestamp_skew);
+                        state.rav_anomalies.record_receipts(Instant::now(), count);
+
+                        state
+                            .metrics
+                            .with_label_values(
+                                &UNAGGREGATED_FEES,
+                                &[&state.sender.to_string(), &allocation_id.to_string()],
+                            )
                             .add(value as f64);
                     }
                     ReceiptFees::RavRequestResponse(rav_result) => {
@@ -583,15 +1317,24 @@ impl Actor for SenderAccount {
                         match rav_result {
                             Ok((fees, rav)) => {
                                 state.rav_tracker.ok_rav_request(allocation_id);
+                                state.trust.record_rav_request(Instant::now(), false);
 
                                 let rav_value = rav.map_or(0, |rav| rav.message.valueAggregate);
+                                if rav_value > 0 {
+                                    state.rav_anomalies.record_rav(
+                                        Instant::now(),
+                                        allocation_id,
+                                        rav_value,
+                                    );
+                                }
                                 // update rav tracker
                                 state.rav_tracker.update(allocation_id, rav_value, 0);
-                                PENDING_RAV
-                                    .with_label_values(&[
-                                        &state.sender.to_string(),
-                                        &allocation_id.to_string(),
-                                    ])
+                                state
+                                    .metrics
+                                    .with_label_values(
+                                        &PENDING_RAV,
+                                        &[&state.sender.to_string(), &allocation_id.to_string()],
+                                    )
                                     .set(rav_value as f64);
 
                                 // update sender fee tracker
@@ -600,16 +1343,28 @@ impl Actor for SenderAccount {
                                     fees.value,
                                     fees.counter,
                                 );
-                                UNAGGREGATED_FEES
-                                    .with_label_values(&[
-                                        &state.sender.to_string(),
-                                        &allocation_id.to_string(),
-                                    ])
+                                // The fees left are the ones received during the request
+                                state.unaggregated_since.remove(&allocation_id);
+                                if fees.value > 0 {
+                                    state
+                                        .unaggregated_since
+                                        .insert(allocation_id, Instant::now());
+                                }
+                                state
+                                    .metrics
+                                    .with_label_values(
+                                        &UNAGGREGATED_FEES,
+                                        &[&state.sender.to_string(), &allocation_id.to_string()],
+                                    )
                                     .set(fees.value as f64);
                             }
                             Err(err) => {
                                 state.rav_tracker.failed_rav_backoff(allocation_id);
+                                state.trust.record_rav_request(Instant::now(), true);
                                 error!(
+                                    event = event::RAV_REQUEST_FAILED,
+                                    sender = %state.sender,
+                                    allocation = %allocation_id,
                                     "Error while requesting RAV for sender {} and allocation {}: {}",
                                     state.sender,
                                     allocation_id,
@@ -624,99 +1379,76 @@ impl Actor for SenderAccount {
                             unaggregated_fees.value,
                             unaggregated_fees.counter,
                         );
+                        if unaggregated_fees.value > 0 {
+                            state
+                                .unaggregated_since
+                                .entry(allocation_id)
+                                .or_insert_with(Instant::now);
+                        } else {
+                            state.unaggregated_since.remove(&allocation_id);
+                        }
 
-                        UNAGGREGATED_FEES
-                            .with_label_values(&[
-                                &state.sender.to_string(),
-                                &allocation_id.to_string(),
-                            ])
-                            .set(unaggregated_fees.value as f64);
+                        let labels = [state.sender.to_string(), allocation_id.to_string()];
+                        let labels = [labels[0].as_str(), labels[1].as_str()];
+                        if unaggregated_fees.value == 0
+                            && !state.allocation_ids.contains(&allocation_id)
+                        {
+                            // Closed and aggregated, sent once its `SenderAllocation` stopped
+                            state.metrics.remove(&UNAGGREGATED_FEES, &labels);
+                            state.metrics.remove(&INVALID_RECEIPT_FEES, &labels);
+                        } else {
+                            state
+                                .metrics
+                                .with_label_values(&UNAGGREGATED_FEES, &labels)
+                                .set(unaggregated_fees.value as f64);
+                        }
                     }
                     ReceiptFees::Retry => {}
                 }
 
-                // Eagerly deny the sender (if needed), before the RAV request. To be sure not to
-                // delay the denial because of the RAV request, which could take some time.
-
-                let should_deny = !state.denied && state.deny_condition_reached();
-                if should_deny {
-                    state.add_to_denylist().await;
-                }
-                let total_counter_for_allocation = state
-                    .sender_fee_tracker
-                    .get_total_counter_outside_buffer_for_allocation(&allocation_id);
-                let counter_greater_receipt_limit = total_counter_for_allocation
-                    >= state.config.tap.rav_request_receipt_limit
-                    && !state
-                        .sender_fee_tracker
-                        .check_allocation_has_rav_request_running(allocation_id);
-                let total_fee_outside_buffer =
-                    state.sender_fee_tracker.get_total_fee_outside_buffer();
-                let total_fee_greater_trigger_value =
-                    total_fee_outside_buffer >= state.config.tap.rav_request_trigger_value;
-                let rav_result = match (
-                    counter_greater_receipt_limit,
-                    total_fee_greater_trigger_value,
-                ) {
-                    (true, _) => {
-                        tracing::debug!(
-                            total_counter_for_allocation,
-                            rav_request_receipt_limit = state.config.tap.rav_request_receipt_limit,
-                            %allocation_id,
-                            "Total counter greater than the receipt limit per rav. Triggering RAV request"
-                        );
-
-                        state.rav_request_for_allocation(allocation_id).await
-                    }
-                    (_, true) => {
-                        tracing::debug!(
-                            total_fee_outside_buffer,
-                            trigger_value = state.config.tap.rav_request_trigger_value,
-                            "Total fee greater than the trigger value. Triggering RAV request"
-                        );
-                        state.rav_request_for_heaviest_allocation().await
+                if new_receipts {
+                    // Evaluated once all the receipts already in the mailbox are accounted for,
+                    // rather than once per receipt during bursts.
+                    if state.pending_trigger_evaluations.is_empty() {
+                        myself.cast(SenderAccountMessage::EvaluateRavTriggers)?;
                     }
-                    _ => Ok(()),
-                };
-                // In case we fail, we want our actor to keep running
-                if let Err(err) = rav_result {
-                    tracing::error!(
-                        error = %err,
-                        "There was an error while requesting a RAV."
-                    );
+                    state
+                        .pending_trigger_evaluations
+                        .entry(allocation_id)
+                        .or_insert(correlation_id);
+                } else {
+                    state
+                        .evaluate_rav_triggers(&myself, allocation_id, correlation_id)
+                        .await;
                 }
-
-                match (state.denied, state.deny_condition_reached()) {
-                    // Allow the sender right after the potential RAV request. This way, the
-                    // sender can be allowed again as soon as possible if the RAV was successful.
-                    (true, false) => state.remove_from_denylist().await,
-                    // if couldn't remove from denylist, resend the message in 30 seconds
-                    // this may trigger another rav request
-                    (true, true) => {
-                        // retry in a moment
-                        state.scheduled_rav_request =
-                            Some(myself.send_after(state.retry_interval, move || {
-                                SenderAccountMessage::UpdateReceiptFees(
-                                    allocation_id,
-                                    ReceiptFees::Retry,
-                                )
-                            }));
-                    }
-                    _ => {}
+            }
+            SenderAccountMessage::EvaluateRavTriggers => {
+                for (allocation_id, correlation_id) in
+                    std::mem::take(&mut state.pending_trigger_evaluations)
+                {
+                    state
+                        .evaluate_rav_triggers(&myself, allocation_id, correlation_id)
+                        .await;
                 }
             }
             SenderAccountMessage::UpdateAllocationIds(allocation_ids) => {
                 // Create new sender allocations
                 for allocation_id in allocation_ids.difference(&state.allocation_ids) {
-                    if let Err(error) = state
+                    match state
                         .create_sender_allocation(myself.clone(), *allocation_id)
                         .await
                     {
-                        error!(
+                        Ok(()) => lifecycle_hooks::notify(
+                            AllocationEvent::AllocationCreated,
+                            state.sender,
+                            *allocation_id,
+                            None,
+                        ),
+                        Err(error) => error!(
                             %error,
                             %allocation_id,
                             "There was an error while creating Sender Allocation."
-                        );
+                        ),
                     }
                 }
 
@@ -729,7 +1461,14 @@ impl Actor for SenderAccount {
                         // we can not send a rav request to this allocation
                         // because it's gonna trigger the last rav
                         state.sender_fee_tracker.block_allocation_id(*allocation_id);
+                        state.rav_anomalies.remove_allocation(allocation_id);
                         sender_handle.stop(None);
+                        lifecycle_hooks::notify(
+                            AllocationEvent::AllocationClosing,
+                            state.sender,
+                            *allocation_id,
+                            None,
+                        );
                     }
                 }
 
@@ -740,8 +1479,62 @@ impl Actor for SenderAccount {
                 );
                 state.allocation_ids = allocation_ids;
             }
+            SenderAccountMessage::UpdateClosingAllocationIds(closing_allocation_ids) => {
+                state.closing_allocation_ids = closing_allocation_ids.clone();
+                // Aggregate the fees of the allocations closing soon, so that as little as
+                // possible is left for their last RAV. Repeated on every update until they're
+                // closed, as receipts keep coming in.
+                for allocation_id in closing_allocation_ids {
+                    let has_fees = state
+                        .sender_fee_tracker
+                        .get_total_counter_outside_buffer_for_allocation(&allocation_id)
+                        > 0;
+                    if !has_fees
+                        || state
+                            .sender_fee_tracker
+                            .check_allocation_has_rav_request_running(allocation_id)
+                    {
+                        continue;
+                    }
+                    tracing::debug!(
+                        %allocation_id,
+                        "Allocation closing soon. Triggering RAV request"
+                    );
+                    if let Err(err) = state
+                        .rav_request_for_allocation(allocation_id, CorrelationId::new())
+                        .await
+                    {
+                        tracing::error!(
+                            error = %err,
+                            %allocation_id,
+                            "There was an error while requesting a RAV for a closing allocation."
+                        );
+                    }
+                }
+            }
+            SenderAccountMessage::CheckpointRavs => {
+                if let Some(interval) = state.config.tap.rav_checkpoint_interval {
+                    state.request_checkpoint_ravs(interval).await;
+                }
+            }
+            SenderAccountMessage::PublishRateLimit => {
+                if let Some(config) = &state.config.tap.rate_limits {
+                    let limit = state.rate_limit(config);
+                    if let Err(e) = rate_limits::publish(&state.pgpool, state.sender, limit).await {
+                        tracing::warn!(error = %e, "Failed to publish the rate limit of the sender.");
+                    }
+                }
+            }
+            SenderAccountMessage::SampleReceiptRate => {
+                state.rav_anomalies.tick(Instant::now());
+            }
             SenderAccountMessage::NewAllocationId(allocation_id) => {
-                if let Err(error) = state
+                if state.quarantine.is_quarantined(&allocation_id) {
+                    tracing::debug!(
+                        %allocation_id,
+                        "Not starting the SenderAllocation of a quarantined allocation."
+                    );
+                } else if let Err(error) = state
                     .create_sender_allocation(myself.clone(), allocation_id)
                     .await
                 {
@@ -753,10 +1546,19 @@ impl Actor for SenderAccount {
                 }
                 state.allocation_ids.insert(allocation_id);
             }
-            SenderAccountMessage::UpdateBalanceAndLastRavs(new_balance, non_final_last_ravs) => {
+            SenderAccountMessage::UpdateBalanceAndLastRavs(
+                new_balance,
+                balance_trend,
+                non_final_last_ravs,
+            ) => {
                 state.sender_balance = new_balance;
-                ESCROW_BALANCE
-                    .with_label_values(&[&state.sender.to_string()])
+                state.balance_trend = balance_trend;
+                state
+                    .trust
+                    .record_balance(Instant::now(), new_balance.to_u128().unwrap_or(u128::MAX));
+                state
+                    .metrics
+                    .with_label_values(&ESCROW_BALANCE, &[&state.sender.to_string()])
                     .set(new_balance.to_u128().expect("should be less than 128 bits") as f64);
 
                 let non_final_last_ravs_set: HashSet<_> =
@@ -773,40 +1575,116 @@ impl Actor for SenderAccount {
                 for allocation_id in tracked_allocation_ids.difference(&active_allocation_ids) {
                     // if it's being tracked and we didn't receive any update from the non_final_last_ravs
                     // remove from the tracker
+                    lifecycle_hooks::notify(
+                        AllocationEvent::RavFinalized,
+                        state.sender,
+                        *allocation_id,
+                        Some(state.rav_tracker.get_fee(allocation_id)),
+                    );
                     state.rav_tracker.update(*allocation_id, 0, 0);
 
-                    let _ = PENDING_RAV.remove_label_values(&[
-                        &state.sender.to_string(),
-                        &allocation_id.to_string(),
-                    ]);
+                    state.metrics.remove(
+                        &PENDING_RAV,
+                        &[&state.sender.to_string(), &allocation_id.to_string()],
+                    );
+                }
+
+                // The redemption of Horizon RAVs isn't tracked yet, they only count while their
+                // allocation is open.
+                let tracked_horizon_allocation_ids =
+                    state.horizon_rav_tracker.get_list_of_allocation_ids();
+                for allocation_id in
+                    tracked_horizon_allocation_ids.difference(&state.allocation_ids)
+                {
+                    state.horizon_rav_tracker.update(*allocation_id, 0, 0);
                 }
 
                 for (allocation_id, value) in non_final_last_ravs {
                     state.rav_tracker.update(allocation_id, value, 0);
-                    PENDING_RAV
-                        .with_label_values(&[&state.sender.to_string(), &allocation_id.to_string()])
+                    state
+                        .metrics
+                        .with_label_values(
+                            &PENDING_RAV,
+                            &[&state.sender.to_string(), &allocation_id.to_string()],
+                        )
                         .set(value as f64);
                 }
                 // now that balance and rav tracker is updated, check
                 match (state.denied, state.deny_condition_reached()) {
-                    (true, false) => state.remove_from_denylist().await,
+                    (true, false) if state.may_allow() => state.remove_from_denylist().await,
                     (false, true) => state.add_to_denylist().await,
                     (_, _) => {}
                 }
             }
-            #[cfg(test)]
+            SenderAccountMessage::UpdateConfig(thresholds) => {
+                tracing::info!(sender = %state.sender, ?thresholds, "Updating the thresholds.");
+                state.thresholds = thresholds;
+                set_threshold_metrics(&state.metrics, &state.sender, &thresholds);
+                match (state.denied, state.deny_condition_reached()) {
+                    (true, false) if state.may_allow() => state.remove_from_denylist().await,
+                    (false, true) => state.add_to_denylist().await,
+                    (_, _) => {}
+                }
+            }
+            SenderAccountMessage::GetStatus(reply) => {
+                if !reply.is_closed() {
+                    let _ = reply.send(state.status());
+                }
+            }
+            SenderAccountMessage::GetRavQueue(reply) => {
+                if !reply.is_closed() {
+                    let _ = reply.send(
+                        state
+                            .rav_queue()
+                            .into_sorted_vec()
+                            .into_iter()
+                            .rev()
+                            .collect(),
+                    );
+                }
+            }
+            SenderAccountMessage::GetTrust(reply) => {
+                if !reply.is_closed() {
+                    let _ = reply.send(state.trust());
+                }
+            }
+            SenderAccountMessage::GetEscrow(reply) => {
+                let escrow = state.escrow().await;
+                if !reply.is_closed() {
+                    let _ = reply.send(escrow);
+                }
+            }
+            SenderAccountMessage::ResumeAllocation(allocation_id, reply) => {
+                let result = state.resume_allocation(myself.clone(), allocation_id).await;
+                if !reply.is_closed() {
+                    let _ = reply.send(result);
+                }
+            }
+            SenderAccountMessage::PauseSender(reason, reply) => {
+                let result = state.pause(reason).await;
+                if !reply.is_closed() {
+                    let _ = reply.send(result);
+                }
+            }
+            SenderAccountMessage::ResumeSender(reply) => {
+                let result = state.resume(&myself).await;
+                if !reply.is_closed() {
+                    let _ = reply.send(result);
+                }
+            }
+            #[cfg(any(test, feature = "debug-rpc"))]
             SenderAccountMessage::GetSenderFeeTracker(reply) => {
                 if !reply.is_closed() {
                     let _ = reply.send(state.sender_fee_tracker.clone());
                 }
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "debug-rpc"))]
             SenderAccountMessage::GetDeny(reply) => {
                 if !reply.is_closed() {
                     let _ = reply.send(state.denied);
                 }
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "debug-rpc"))]
             SenderAccountMessage::IsSchedulerEnabled(reply) => {
                 if !reply.is_closed() {
                     let _ = reply.send(state.scheduled_rav_request.is_some());
@@ -866,7 +1744,7 @@ impl Actor for SenderAccount {
                 tracing::warn!(
                     ?sender_allocation,
                     ?error,
-                    "Actor SenderAllocation panicked. Restarting..."
+                    "Actor SenderAllocation panicked."
                 );
                 let Some(allocation_id) = cell.get_name() else {
                     tracing::error!("SenderAllocation doesn't have a name");
@@ -881,6 +1759,17 @@ impl Actor for SenderAccount {
                     return Ok(());
                 };
 
+                if state.quarantine.record_panic(allocation_id, Instant::now()) {
+                    state.quarantine_allocation(allocation_id);
+                    return Ok(());
+                }
+                state
+                    .metrics
+                    .with_label_values(
+                        &ALLOCATION_RESTARTS,
+                        &[&state.sender.to_string(), &allocation_id.to_string()],
+                    )
+                    .inc();
                 if let Err(error) = state
                     .create_sender_allocation(myself.clone(), allocation_id)
                     .await
@@ -899,47 +1788,67 @@ impl Actor for SenderAccount {
 }
 
 impl SenderAccount {
-    pub async fn deny_sender(pool: &sqlx::PgPool, sender: Address) {
-        sqlx::query!(
-            r#"
-                    INSERT INTO scalar_tap_denylist (sender_address)
-                    VALUES ($1) ON CONFLICT DO NOTHING
-                "#,
-            sender.encode_hex(),
-        )
-        .execute(pool)
-        .await
-        .expect("Should not fail to insert into denylist");
+    /// Legacy escrow balance of the sender, plus its Horizon escrow balance for the collector if
+    /// Horizon receipts are collected. Zero if the sender has no escrow account.
+    fn sender_balance(
+        escrow_accounts: &EscrowAccounts,
+        sender: Address,
+        horizon_collector: Option<Address>,
+    ) -> U256 {
+        let balance = escrow_accounts
+            .get_balance_for_sender(&sender)
+            .unwrap_or_default();
+        let horizon_balance = horizon_collector
+            .and_then(|collector| {
+                escrow_accounts
+                    .get_balance_for_payer_and_collector(&sender, &collector)
+                    .ok()
+            })
+            .unwrap_or_default();
+        balance.saturating_add(horizon_balance)
     }
 }
 
+fn set_threshold_metrics(metrics: &SeriesOwner, sender: &Address, thresholds: &Thresholds) {
+    metrics
+        .with_label_values(&MAX_FEE_PER_SENDER, &[&sender.to_string()])
+        .set(thresholds.max_unnaggregated_fees_per_sender as f64);
+    metrics
+        .with_label_values(&RAV_REQUEST_TRIGGER_VALUE, &[&sender.to_string()])
+        .set(thresholds.rav_request_trigger_value as f64);
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::{SenderAccount, SenderAccountArgs, SenderAccountMessage};
+    use crate::agent::denylist_outbox::{DenyReason, DenylistOutbox};
+    use crate::agent::deployment_fees::DeploymentFees;
+    use crate::agent::redeemed_ravs::RedeemedRavs;
     use crate::agent::sender_account::ReceiptFees;
     use crate::agent::sender_accounts_manager::NewReceiptNotification;
     use crate::agent::sender_allocation::SenderAllocationMessage;
+    use crate::agent::startup_scans::StartupScans;
     use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
     use crate::config;
+    use crate::logging::CorrelationId;
     use crate::tap::test_utils::{
-        create_rav, store_rav_with_options, ALLOCATION_ID_0, ALLOCATION_ID_1, INDEXER, SENDER,
-        SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+        create_allocation, create_rav, store_rav_with_options, ALLOCATION_ID_0, ALLOCATION_ID_1,
+        INDEXER, SENDER, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
     };
     use alloy::hex::ToHexExt;
     use alloy::primitives::{Address, U256};
     use eventuals::{Eventual, EventualWriter};
     use indexer_common::escrow_accounts::EscrowAccounts;
     use indexer_common::prelude::{DeploymentDetails, SubgraphClient};
+    use indexer_test_harness::MockEscrowSubgraph;
     use ractor::concurrency::JoinHandle;
     use ractor::{call, Actor, ActorProcessingErr, ActorRef, ActorStatus};
-    use serde_json::json;
     use sqlx::PgPool;
     use std::collections::{HashMap, HashSet};
     use std::sync::atomic::AtomicU32;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
-    use wiremock::matchers::{body_string_contains, method};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use tokio::sync::watch;
 
     // we implement the PartialEq and Eq traits for SenderAccountMessage to be able to compare
     impl Eq for SenderAccountMessage {}
@@ -951,7 +1860,10 @@ pub mod tests {
                 (Self::UpdateReceiptFees(l0, l1), Self::UpdateReceiptFees(r0, r1)) => {
                     l0 == r0
                         && match (l1, r1) {
-                            (ReceiptFees::NewReceipt(l), ReceiptFees::NewReceipt(r)) => r == l,
+                            (
+                                ReceiptFees::NewReceipts(l0, l1, ..),
+                                ReceiptFees::NewReceipts(r0, r1, ..),
+                            ) => l0 == r0 && l1 == r1,
                             (ReceiptFees::UpdateValue(l), ReceiptFees::UpdateValue(r)) => r == l,
                             (
                                 ReceiptFees::RavRequestResponse(l),
@@ -970,6 +1882,12 @@ pub mod tests {
                     Self::UpdateInvalidReceiptFees(r0, r1),
                 ) => l0 == r0 && l1 == r1,
                 (Self::NewAllocationId(l0), Self::NewAllocationId(r0)) => l0 == r0,
+                (Self::UpdateClosingAllocationIds(l0), Self::UpdateClosingAllocationIds(r0)) => {
+                    l0 == r0
+                }
+                (Self::UpdateHorizonRav(l0, l1), Self::UpdateHorizonRav(r0, r1)) => {
+                    l0 == r0 && l1 == r1
+                }
                 (a, b) => match (
                     core::mem::discriminant(self),
                     core::mem::discriminant(other),
@@ -1010,13 +1928,33 @@ pub mod tests {
                 rav_request_trigger_value,
                 rav_request_timestamp_buffer_ms: BUFFER_MS,
                 rav_request_timeout_secs: 5,
+                rav_request_retry_interval: Duration::from_millis(10),
                 max_unnaggregated_fees_per_sender,
                 rav_request_receipt_limit,
                 ..Default::default()
             },
             ..Default::default()
         }));
+        create_sender_account_with_config(
+            pgpool,
+            initial_allocation,
+            config,
+            escrow_subgraph_endpoint,
+        )
+        .await
+    }
 
+    async fn create_sender_account_with_config(
+        pgpool: PgPool,
+        initial_allocation: HashSet<Address>,
+        config: &'static config::Config,
+        escrow_subgraph_endpoint: &str,
+    ) -> (
+        ActorRef<SenderAccountMessage>,
+        tokio::task::JoinHandle<()>,
+        String,
+        EventualWriter<EscrowAccounts>,
+    ) {
         let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
             reqwest::Client::new(),
             None,
@@ -1036,16 +1974,33 @@ pub mod tests {
 
         let args = SenderAccountArgs {
             config,
+            allocation_pgpool: pgpool.clone(),
+            denylist: DenylistOutbox::new(pgpool.clone(), None).unwrap(),
+            startup_scans: StartupScans::new(usize::MAX, HashMap::new()),
+            deployment_fees: DeploymentFees::new(Eventual::from_value(HashMap::new())),
+            redeemed_ravs: RedeemedRavs::new(
+                pgpool.clone(),
+                escrow_subgraph,
+                Duration::from_secs(60),
+            ),
             pgpool,
             sender_id: SENDER.1,
             escrow_accounts: escrow_accounts_eventual,
-            indexer_allocations: Eventual::from_value(initial_allocation),
+            indexer_allocations: watch::channel(
+                initial_allocation
+                    .into_iter()
+                    .map(|allocation_id| (allocation_id, create_allocation(allocation_id)))
+                    .collect(),
+            )
+            .1,
+            closing_allocations: Eventual::from_value(HashSet::new()),
             escrow_subgraph,
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            horizon_domain_separator: None,
             sender_aggregator_endpoint: DUMMY_URL.to_string(),
             allocation_ids: HashSet::new(),
             prefix: Some(prefix.clone()),
-            retry_interval: Duration::from_millis(10),
+            thresholds: Thresholds::from(&config.tap),
         };
 
         let (sender, handle) = SenderAccount::spawn(Some(prefix.clone()), SenderAccount, args)
@@ -1239,7 +2194,7 @@ pub mod tests {
             _state: &mut Self::State,
         ) -> Result<(), ActorProcessingErr> {
             match message {
-                SenderAllocationMessage::TriggerRAVRequest => {
+                SenderAllocationMessage::TriggerRAVRequest(..) => {
                     self.triggered_rav_request
                         .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
                     let signed_rav = create_rav(
@@ -1323,7 +2278,13 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(TRIGGER_VALUE - 1),
+                ReceiptFees::NewReceipts(
+                    TRIGGER_VALUE - 1,
+                    1,
+                    CorrelationId::new(),
+                    None,
+                    Duration::ZERO,
+                ),
             ))
             .unwrap();
 
@@ -1366,7 +2327,13 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(TRIGGER_VALUE),
+                ReceiptFees::NewReceipts(
+                    TRIGGER_VALUE,
+                    1,
+                    CorrelationId::new(),
+                    None,
+                    Duration::ZERO,
+                ),
             ))
             .unwrap();
 
@@ -1426,7 +2393,7 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(1),
+                ReceiptFees::NewReceipts(1, 1, CorrelationId::new(), None, Duration::ZERO),
             ))
             .unwrap();
 
@@ -1439,7 +2406,7 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(1),
+                ReceiptFees::NewReceipts(1, 1, CorrelationId::new(), None, Duration::ZERO),
             ))
             .unwrap();
 
@@ -1467,6 +2434,201 @@ pub mod tests {
         handle.await.unwrap();
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_new_receipts_burst(pgpool: PgPool) {
+        let (sender_account, handle, prefix, _) = create_sender_account(
+            pgpool,
+            HashSet::new(),
+            TRIGGER_VALUE,
+            TRIGGER_VALUE * 10,
+            DUMMY_URL,
+            RECEIPT_LIMIT,
+        )
+        .await;
+
+        let (triggered_rav_request, _, allocation, allocation_handle) =
+            create_mock_sender_allocation(
+                prefix,
+                SENDER.1,
+                *ALLOCATION_ID_0,
+                sender_account.clone(),
+            )
+            .await;
+
+        let new_receipts = |value| {
+            SenderAccountMessage::UpdateReceiptFees(
+                *ALLOCATION_ID_0,
+                ReceiptFees::NewReceipts(value, 1, CorrelationId::new(), None, Duration::ZERO),
+            )
+        };
+        // Enough to trigger a RAV once outside of the buffer
+        sender_account.cast(new_receipts(TRIGGER_VALUE)).unwrap();
+        tokio::time::sleep(Duration::from_millis(BUFFER_MS)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        // A burst of receipts, then a pause queued behind it. Evaluated once the burst is
+        // accounted for, the triggers only run after the pause, and don't request a RAV.
+        for _ in 0..50 {
+            sender_account.cast(new_receipts(1)).unwrap();
+        }
+        call!(sender_account, SenderAccountMessage::PauseSender, None)
+            .unwrap()
+            .unwrap();
+
+        let fee_tracker = call!(sender_account, SenderAccountMessage::GetSenderFeeTracker).unwrap();
+        assert_eq!(fee_tracker.get_total_fee(), TRIGGER_VALUE + 50);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        // Evaluated again once resumed
+        call!(sender_account, SenderAccountMessage::ResumeSender)
+            .unwrap()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+
+        allocation.stop_and_wait(None, None).await.unwrap();
+        allocation_handle.await.unwrap();
+
+        sender_account.stop_and_wait(None, None).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_closing_allocation_trigger_rav(pgpool: PgPool) {
+        let (sender_account, handle, prefix, _) = create_sender_account(
+            pgpool,
+            HashSet::new(),
+            TRIGGER_VALUE,
+            TRIGGER_VALUE,
+            DUMMY_URL,
+            RECEIPT_LIMIT,
+        )
+        .await;
+
+        let (triggered_rav_request, _, allocation, allocation_handle) =
+            create_mock_sender_allocation(
+                prefix,
+                SENDER.1,
+                *ALLOCATION_ID_0,
+                sender_account.clone(),
+            )
+            .await;
+
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                *ALLOCATION_ID_0,
+                ReceiptFees::NewReceipts(1, 1, CorrelationId::new(), None, Duration::ZERO),
+            ))
+            .unwrap();
+
+        // fees still in the buffer are not requested
+        sender_account
+            .cast(SenderAccountMessage::UpdateClosingAllocationIds(
+                HashSet::from([*ALLOCATION_ID_0]),
+            ))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        // wait for it to be outside buffer
+        tokio::time::sleep(Duration::from_millis(BUFFER_MS)).await;
+
+        sender_account
+            .cast(SenderAccountMessage::UpdateClosingAllocationIds(
+                HashSet::from([*ALLOCATION_ID_0]),
+            ))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+
+        allocation.stop_and_wait(None, None).await.unwrap();
+        allocation_handle.await.unwrap();
+
+        sender_account.stop_and_wait(None, None).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_checkpoint_rav(pgpool: PgPool) {
+        let config = Box::leak(Box::new(config::Config {
+            ethereum: config::Ethereum {
+                indexer_address: INDEXER.1,
+            },
+            tap: config::Tap {
+                // Making sure no RAV is triggered by the value
+                rav_request_trigger_value: u128::MAX,
+                rav_request_timestamp_buffer_ms: BUFFER_MS,
+                rav_request_retry_interval: Duration::from_millis(10),
+                max_unnaggregated_fees_per_sender: u128::MAX,
+                rav_request_receipt_limit: RECEIPT_LIMIT,
+                rav_checkpoint_interval: Some(Duration::from_millis(300)),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let (sender_account, handle, prefix, _) =
+            create_sender_account_with_config(pgpool, HashSet::new(), config, DUMMY_URL).await;
+
+        let (triggered_rav_request, _, allocation, allocation_handle) =
+            create_mock_sender_allocation(
+                prefix,
+                SENDER.1,
+                *ALLOCATION_ID_0,
+                sender_account.clone(),
+            )
+            .await;
+
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                *ALLOCATION_ID_0,
+                ReceiptFees::NewReceipts(1, 1, CorrelationId::new(), None, Duration::ZERO),
+            ))
+            .unwrap();
+
+        // outside of the buffer, but not unaggregated for long enough
+        tokio::time::sleep(Duration::from_millis(BUFFER_MS + 50)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+
+        // the RAV covered all the fees, there is nothing left to checkpoint
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+
+        allocation.stop_and_wait(None, None).await.unwrap();
+        allocation_handle.await.unwrap();
+
+        sender_account.stop_and_wait(None, None).await.unwrap();
+        handle.await.unwrap();
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_remove_sender_account(pgpool: PgPool) {
         let (sender_account, handle, prefix, _) = create_sender_account(
@@ -1535,6 +2697,52 @@ pub mod tests {
         assert!(deny);
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_operator_deny_kept(pgpool: PgPool) {
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_denylist (sender_address, reason)
+                VALUES ($1, 'operator')
+            "#,
+            SENDER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .expect("Should not fail to insert into denylist");
+
+        // No reason to keep denied
+        let (sender_account, handle, _, _) = create_sender_account(
+            pgpool.clone(),
+            HashSet::new(),
+            TRIGGER_VALUE,
+            TRIGGER_VALUE,
+            DUMMY_URL,
+            RECEIPT_LIMIT,
+        )
+        .await;
+        sender_account
+            .cast(SenderAccountMessage::UpdateConfig(Thresholds {
+                rav_request_trigger_value: TRIGGER_VALUE,
+                max_unnaggregated_fees_per_sender: TRIGGER_VALUE,
+                retry_interval: Duration::from_millis(10),
+            }))
+            .unwrap();
+
+        let deny = call!(sender_account, SenderAccountMessage::GetDeny).unwrap();
+        assert!(deny);
+        let reason = sqlx::query_scalar!(
+            "SELECT reason FROM scalar_tap_denylist WHERE sender_address = $1",
+            SENDER.1.encode_hex()
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(reason.as_deref(), Some("operator"));
+
+        sender_account.stop_and_wait(None, None).await.unwrap();
+        handle.await.unwrap();
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_retry_unaggregated_fees(pgpool: PgPool) {
         // we set to zero to block the sender, no matter the fee
@@ -1567,7 +2775,13 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(TRIGGER_VALUE),
+                ReceiptFees::NewReceipts(
+                    TRIGGER_VALUE,
+                    1,
+                    CorrelationId::new(),
+                    None,
+                    Duration::ZERO,
+                ),
             ))
             .unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
@@ -1682,6 +2896,58 @@ pub mod tests {
         // allow sender
         assert!(!deny);
 
+        // every change is in the deny timeline of the status
+        let status = call!(sender_account, SenderAccountMessage::GetStatus).unwrap();
+        let timeline: Vec<bool> = status.deny_events.iter().map(|e| e.denied).collect();
+        assert_eq!(timeline, [true, false, true, false, true, false]);
+
+        sender_account.stop_and_wait(None, None).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_update_config(pgpool: PgPool) {
+        // Making sure no RAV is gonna be triggered during the test
+        let (sender_account, handle, _, _) = create_sender_account(
+            pgpool.clone(),
+            HashSet::new(),
+            u128::MAX,
+            1000,
+            DUMMY_URL,
+            RECEIPT_LIMIT,
+        )
+        .await;
+
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                *ALLOCATION_ID_0,
+                ReceiptFees::UpdateValue(UnaggregatedReceipts {
+                    value: 500,
+                    last_id: 11,
+                    counter: 0,
+                }),
+            ))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!call!(sender_account, SenderAccountMessage::GetDeny).unwrap());
+
+        let update_config = |max_unnaggregated_fees_per_sender| {
+            sender_account
+                .cast(SenderAccountMessage::UpdateConfig(Thresholds {
+                    rav_request_trigger_value: u128::MAX,
+                    max_unnaggregated_fees_per_sender,
+                    retry_interval: Duration::from_millis(10),
+                }))
+                .unwrap();
+        };
+
+        // The fees are over the new maximum
+        update_config(500);
+        assert!(call!(sender_account, SenderAccountMessage::GetDeny).unwrap());
+
+        update_config(1000);
+        assert!(!call!(sender_account, SenderAccountMessage::GetDeny).unwrap());
+
         sender_account.stop_and_wait(None, None).await.unwrap();
         handle.await.unwrap();
     }
@@ -1775,6 +3041,14 @@ pub mod tests {
         update_receipt_fees!(half_escrow);
         let deny = get_deny_status(&sender_account).await;
         assert!(deny);
+        let reason = sqlx::query_scalar!(
+            "SELECT reason FROM scalar_tap_denylist WHERE sender_address = $1",
+            SENDER.1.encode_hex()
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(reason.as_deref(), Some(DenyReason::EscrowBalance.as_str()));
 
         update_receipt_fees!(half_escrow - 1);
         let deny = get_deny_status(&sender_account).await;
@@ -1808,20 +3082,10 @@ pub mod tests {
 
     #[sqlx::test(migrations = "../migrations")]
     async fn test_pending_rav_already_redeemed_and_redeem(pgpool: PgPool) {
-        // Start a mock graphql server using wiremock
-        let mock_server = MockServer::start().await;
-
         // Mock result for TAP redeem txs for (allocation, sender) pair.
-        mock_server
-            .register(
-                Mock::given(method("POST"))
-                    .and(body_string_contains("transactions"))
-                    .respond_with(ResponseTemplate::new(200).set_body_json(
-                        json!({ "data": { "transactions": [
-                            {"allocationID": *ALLOCATION_ID_0 }
-                        ]}}),
-                    )),
-            )
+        let escrow_subgraph = MockEscrowSubgraph::start().await;
+        escrow_subgraph
+            .set_redemptions(&[(SENDER.1, *ALLOCATION_ID_0)])
             .await;
 
         // redeemed
@@ -1840,7 +3104,7 @@ pub mod tests {
             HashSet::new(),
             TRIGGER_VALUE,
             u128::MAX,
-            &mock_server.uri(),
+            &escrow_subgraph.query_url(),
             RECEIPT_LIMIT,
         )
         .await;
@@ -1848,20 +3112,9 @@ pub mod tests {
         let deny = call!(sender_account, SenderAccountMessage::GetDeny).unwrap();
         assert!(!deny, "should start unblocked");
 
-        mock_server.reset().await;
-
         // allocation_id sent to the blockchain
-        mock_server
-            .register(
-                Mock::given(method("POST"))
-                    .and(body_string_contains("transactions"))
-                    .respond_with(ResponseTemplate::new(200).set_body_json(
-                        json!({ "data": { "transactions": [
-                            {"allocationID": *ALLOCATION_ID_0 },
-                            {"allocationID": *ALLOCATION_ID_1 }
-                        ]}}),
-                    )),
-            )
+        escrow_subgraph
+            .set_redemptions(&[(SENDER.1, *ALLOCATION_ID_0), (SENDER.1, *ALLOCATION_ID_1)])
             .await;
         // escrow_account updated
         escrow_writer.write(EscrowAccounts::new(
@@ -1961,7 +3214,13 @@ pub mod tests {
         sender_account
             .cast(SenderAccountMessage::UpdateReceiptFees(
                 *ALLOCATION_ID_0,
-                ReceiptFees::NewReceipt(TRIGGER_VALUE),
+                ReceiptFees::NewReceipts(
+                    TRIGGER_VALUE,
+                    1,
+                    CorrelationId::new(),
+                    None,
+                    Duration::ZERO,
+                ),
             ))
             .unwrap();
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -8,25 +8,49 @@ use std::{collections::HashMap, str::FromStr};
 use crate::agent::sender_allocation::SenderAllocationMessage;
 use crate::lazy_static;
 use alloy::dyn_abi::Eip712Domain;
-use alloy::primitives::Address;
+use alloy::primitives::{Address, FixedBytes};
 use anyhow::Result;
 use anyhow::{anyhow, bail};
-use eventuals::{Eventual, EventualExt, PipeHandle};
-use indexer_common::escrow_accounts::EscrowAccounts;
-use indexer_common::prelude::{Allocation, SubgraphClient};
-use ractor::{Actor, ActorCell, ActorProcessingErr, ActorRef, SupervisionEvent};
+use eventuals::{join, Eventual, EventualExt, PipeHandle};
+use indexer_common::escrow_accounts::{
+    record_unknown_signer, resolve_unknown_signers, EscrowAccounts,
+};
+use indexer_common::prelude::{allocations_eventual, Allocation, SubgraphClient};
+use indexer_common::receipt_profiler::{Sampler, Stages};
+use ractor::{
+    Actor, ActorCell, ActorProcessingErr, ActorRef, ActorStatus, RpcReplyPort, SupervisionEvent,
+};
 use serde::Deserialize;
 use sqlx::{postgres::PgListener, PgPool};
-use tokio::select;
+use thiserror::Error;
+use tokio::{select, sync::watch};
 use tracing::{error, warn};
 
 use prometheus::{register_counter_vec, CounterVec};
 
+use super::config_reload::Thresholds;
+use super::denylist_outbox::{Denial, DenyReason, DenylistOutbox};
+use super::deployment_fees::DeploymentFees;
+use super::receipt_profiler::{self, stage};
+use super::redeemed_ravs::RedeemedRavs;
 use super::sender_account::{SenderAccount, SenderAccountArgs, SenderAccountMessage};
+use super::sender_leases::SenderLeases;
+use super::startup_scans::{receipt_backlogs, StartupScans};
 use crate::config;
+use crate::database::{self, Subsystem};
+use crate::health::ManagerHealth;
+use crate::logging::{event, CorrelationId};
+use crate::money::Money;
+use crate::privileges::DatabaseFeatures;
+use crate::replay::{
+    replay_horizon_receipt_notifications, replay_receipt_notifications, revalidate_signer_receipts,
+};
+use crate::status::ManagerSenders;
+use crate::storage::{poll_new_receipts, PgStorage};
+use crate::tap::{horizon, TapVersion};
 
 lazy_static! {
-    static ref RECEIPTS_CREATED: CounterVec = register_counter_vec!(
+    pub(crate) static ref RECEIPTS_CREATED: CounterVec = register_counter_vec!(
         "tap_receipts_received_total",
         "Receipts received since start of the program.",
         &["sender", "allocation"]
@@ -34,6 +58,12 @@ lazy_static! {
     .unwrap();
 }
 
+const HORIZON_RECEIPT_CHANNEL: &str = "tap_horizon_receipt_notification";
+/// Interval between the polls of the new receipts, when Postgres notifications are unavailable.
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Notifications replayed per transaction, for the receipts of a signer once authorized.
+const REPLAY_BATCH_SIZE: i64 = 1000;
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 pub struct NewReceiptNotification {
     pub id: u64,
@@ -41,6 +71,42 @@ pub struct NewReceiptNotification {
     pub signer_address: Address,
     pub timestamp_ns: u64,
     pub value: u128,
+    /// Assigned when the notification is received, not part of the payload.
+    #[serde(skip)]
+    pub correlation_id: CorrelationId,
+    /// Set from the channel the notification was received on.
+    #[serde(skip)]
+    pub version: TapVersion,
+    /// Set when received if the notification is profiled, see
+    /// [`crate::agent::receipt_profiler`].
+    #[serde(skip)]
+    pub stages: Option<Stages>,
+}
+
+/// Payload of the Horizon receipt notifications, which identify the collection instead of the
+/// allocation.
+#[derive(Deserialize, Debug)]
+struct HorizonReceiptNotification {
+    id: u64,
+    collection_id: FixedBytes<32>,
+    signer_address: Address,
+    timestamp_ns: u64,
+    value: u128,
+}
+
+impl From<HorizonReceiptNotification> for NewReceiptNotification {
+    fn from(notification: HorizonReceiptNotification) -> Self {
+        Self {
+            id: notification.id,
+            allocation_id: horizon::allocation_id(notification.collection_id),
+            signer_address: notification.signer_address,
+            timestamp_ns: notification.timestamp_ns,
+            value: notification.value,
+            correlation_id: CorrelationId::default(),
+            version: TapVersion::V2,
+            stages: None,
+        }
+    }
 }
 
 pub struct SenderAccountsManager;
@@ -48,32 +114,88 @@ pub struct SenderAccountsManager;
 #[derive(Debug)]
 pub enum SenderAccountsManagerMessage {
     UpdateSenderAccounts(HashSet<Address>),
+    GetHealth(RpcReplyPort<ManagerHealth>),
+    /// Read-only, see [`crate::status`].
+    GetSenders(RpcReplyPort<ManagerSenders>),
+    /// Stops the `SenderAccount` of a sender and its `SenderAllocation`s, until it's started
+    /// again. The sender is denied while stopped, see [`crate::admin`].
+    StopSenderAccount(Address, RpcReplyPort<Result<(), SenderAccountControlError>>),
+    /// Starts the `SenderAccount` of a sender stopped with
+    /// [`SenderAccountsManagerMessage::StopSenderAccount`].
+    StartSenderAccount(Address, RpcReplyPort<Result<(), SenderAccountControlError>>),
+    /// Thresholds reloaded from the configuration file, see [`crate::agent::config_reload`].
+    UpdateConfig(Thresholds),
+}
+
+#[derive(Error, Debug)]
+pub enum SenderAccountControlError {
+    #[error("Sender {0} is not in the escrow accounts")]
+    UnknownSender(Address),
+    #[error("SenderAccount of sender {0} is already stopped")]
+    AlreadyStopped(Address),
+    #[error("SenderAccount of sender {0} is not stopped")]
+    NotStopped(Address),
+    #[error("Error while starting the SenderAccount of sender {0}: {1}")]
+    StartFailed(Address, String),
 }
 
 pub struct SenderAccountsManagerArgs {
     pub config: &'static config::Config,
     pub domain_separator: Eip712Domain,
+    /// Set if Horizon receipts are collected as well.
+    pub horizon_domain_separator: Option<Eip712Domain>,
 
     pub pgpool: PgPool,
-    pub indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    /// Pool of the `SenderAllocation`s, see [`crate::database::Component`].
+    pub allocation_pgpool: PgPool,
+    pub denylist: DenylistOutbox,
+    /// See [`indexer_common::allocations::watcher`].
+    pub indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+    /// See [`crate::agent::allocation_closure`].
+    pub closing_allocations: Eventual<HashSet<Address>>,
     pub escrow_accounts: Eventual<EscrowAccounts>,
     pub escrow_subgraph: &'static SubgraphClient,
     pub sender_aggregator_endpoints: HashMap<Address, String>,
+    /// See [`crate::privileges`].
+    pub database_features: DatabaseFeatures,
 
     pub prefix: Option<String>,
 }
 
+/// Where the new receipts are learned from.
+enum NewReceipts {
+    Listener(PgListener),
+    Polled(tokio::sync::mpsc::Receiver<NewReceiptNotification>),
+}
+
 pub struct State {
+    /// Senders with a `SenderAccount`. With sharding, only the ones leased by this tap-agent.
     sender_ids: HashSet<Address>,
+    /// Senders stopped through the admin API, they have no `SenderAccount` until started again.
+    stopped_sender_ids: HashSet<Address>,
     new_receipts_watcher_handle: Option<tokio::task::JoinHandle<()>>,
     _eligible_allocations_senders_pipe: PipeHandle,
+    /// Replays the notifications of the receipts of the unknown signers, once authorized. They
+    /// were dropped by `handle_notification`.
+    _resolved_signers_pipe: PipeHandle,
 
     config: &'static config::Config,
+    /// Latest thresholds, given to the new `SenderAccount`s.
+    thresholds: Thresholds,
     domain_separator: Eip712Domain,
+    horizon_domain_separator: Option<Eip712Domain>,
     pgpool: PgPool,
-    indexer_allocations: Eventual<HashSet<Address>>,
+    allocation_pgpool: PgPool,
+    denylist: DenylistOutbox,
+    /// Set if the senders are shared with other tap-agents.
+    leases: Option<SenderLeases>,
+    indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+    closing_allocations: Eventual<HashSet<Address>>,
     escrow_accounts: Eventual<EscrowAccounts>,
     escrow_subgraph: &'static SubgraphClient,
+    redeemed_ravs: RedeemedRavs,
+    startup_scans: StartupScans,
+    deployment_fees: DeploymentFees,
     sender_aggregator_endpoints: HashMap<Address, String>,
     prefix: Option<String>,
 }
@@ -90,51 +212,163 @@ impl Actor for SenderAccountsManager {
         SenderAccountsManagerArgs {
             config,
             domain_separator,
+            horizon_domain_separator,
             indexer_allocations,
+            closing_allocations,
             pgpool,
+            allocation_pgpool,
+            denylist,
             escrow_accounts,
             escrow_subgraph,
             sender_aggregator_endpoints,
+            database_features,
             prefix,
         }: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
-        let indexer_allocations = indexer_allocations.map(|allocations| async move {
-            allocations.keys().cloned().collect::<HashSet<Address>>()
-        });
-        let mut pglistener = PgListener::connect_with(&pgpool.clone()).await.unwrap();
-        pglistener
-            .listen("scalar_tap_receipt_notification")
-            .await
-            .expect(
-                "should be able to subscribe to Postgres Notify events on the channel \
-                'scalar_tap_receipt_notification'",
-            );
+        let deployment_fees =
+            DeploymentFees::new(allocations_eventual(indexer_allocations.clone()));
+        // Subscribed before the startup scans, so that no receipt is missed in between
+        let new_receipts = if database_features.listen {
+            let mut pglistener = PgListener::connect_with(&pgpool.clone()).await.unwrap();
+            pglistener
+                .listen("scalar_tap_receipt_notification")
+                .await
+                .expect(
+                    "should be able to subscribe to Postgres Notify events on the channel \
+                    'scalar_tap_receipt_notification'",
+                );
+            if horizon_domain_separator.is_some() {
+                pglistener
+                    .listen(HORIZON_RECEIPT_CHANNEL)
+                    .await
+                    .unwrap_or_else(|e| {
+                        panic!(
+                            "should be able to subscribe to Postgres Notify events on the channel \
+                            '{HORIZON_RECEIPT_CHANNEL}': {e}"
+                        )
+                    });
+            }
+            NewReceipts::Listener(pglistener)
+        } else {
+            let last = database::acquire(&pgpool, Subsystem::ReceiptScan)
+                .await?
+                .run(|conn| {
+                    sqlx::query!(
+                        r#"
+                            SELECT
+                                (SELECT COALESCE(MAX(id), 0) FROM scalar_tap_receipts) AS "id!",
+                                (SELECT COALESCE(MAX(id), 0) FROM tap_horizon_receipts)
+                                    AS "horizon_id!"
+                        "#,
+                    )
+                    .fetch_one(conn)
+                })
+                .await?;
+            NewReceipts::Polled(poll_new_receipts(
+                PgStorage::new(pgpool.clone()),
+                last.id as u64,
+                horizon_domain_separator
+                    .is_some()
+                    .then_some(last.horizon_id as u64),
+                RECEIPT_POLL_INTERVAL,
+            ))
+        };
+        let leases = config
+            .tap
+            .sharding
+            .as_ref()
+            .map(|sharding| SenderLeases::new(pgpool.clone(), sharding));
         let clone = myself.clone();
-        let _eligible_allocations_senders_pipe =
-            escrow_accounts.clone().pipe_async(move |escrow_accounts| {
-                let myself = clone.clone();
-
-                async move {
-                    myself
-                        .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(
-                            escrow_accounts.get_senders(),
-                        ))
-                        .unwrap_or_else(|e| {
-                            error!("Error while updating sender_accounts: {:?}", e);
-                        });
-                }
-            });
+        let update_sender_accounts = move |escrow_accounts: EscrowAccounts| {
+            clone
+                .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(
+                    escrow_accounts.get_senders(),
+                ))
+                .unwrap_or_else(|e| {
+                    error!("Error while updating sender_accounts: {:?}", e);
+                });
+        };
+        let _eligible_allocations_senders_pipe = match &leases {
+            // The leases are renewed on each update
+            Some(leases) => join((
+                escrow_accounts.clone(),
+                eventuals::timer(leases.rebalance_interval()),
+            ))
+            .pipe_async(move |(escrow_accounts, _)| {
+                update_sender_accounts(escrow_accounts);
+                async {}
+            }),
+            None => escrow_accounts.clone().pipe_async(move |escrow_accounts| {
+                update_sender_accounts(escrow_accounts);
+                async {}
+            }),
+        };
+
+        let replay_pgpool = pgpool.clone();
+        let listen = database_features.listen;
+        let horizon = horizon_domain_separator.is_some();
+        let _resolved_signers_pipe = escrow_accounts.clone().pipe_async(move |escrow_accounts| {
+            for (unknown, sender) in resolve_unknown_signers(&escrow_accounts) {
+                tracing::info!(
+                    signer = %unknown.signer,
+                    %sender,
+                    receipts = unknown.receipts,
+                    "Unknown signer authorized, checking its receipts again"
+                );
+                let pgpool = replay_pgpool.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        replay_signer_receipts(&pgpool, unknown.signer, listen, horizon).await
+                    {
+                        error!(
+                            signer = %unknown.signer,
+                            "Error while checking the receipts of the signer again: {:?}", e
+                        );
+                    }
+                });
+            }
+            async {}
+        });
+
+        let redeemed_ravs = RedeemedRavs::new(
+            pgpool.clone(),
+            escrow_subgraph,
+            Duration::from_millis(config.escrow_subgraph.escrow_syncing_interval_ms),
+        );
+        // The allocations with the most receipts are scanned first
+        let backlogs = receipt_backlogs(
+            &database::read_pool(&allocation_pgpool),
+            horizon_domain_separator.is_some(),
+        )
+        .await
+        .unwrap_or_else(|e| {
+            error!("Error while counting the pending receipts: {:?}", e);
+            HashMap::new()
+        });
+        let startup_scans =
+            StartupScans::new(config.postgres.max_concurrent_startup_scans, backlogs);
 
         let mut state = State {
             config,
+            thresholds: Thresholds::from(&config.tap),
             domain_separator,
+            horizon_domain_separator,
             sender_ids: HashSet::new(),
+            stopped_sender_ids: HashSet::new(),
             new_receipts_watcher_handle: None,
             _eligible_allocations_senders_pipe,
+            _resolved_signers_pipe,
             pgpool,
+            allocation_pgpool,
+            denylist,
+            leases,
             indexer_allocations,
+            closing_allocations,
             escrow_accounts: escrow_accounts.clone(),
             escrow_subgraph,
+            redeemed_ravs,
+            startup_scans,
+            deployment_fees,
             sender_aggregator_endpoints,
             prefix: prefix.clone(),
         };
@@ -145,7 +379,24 @@ impl Actor for SenderAccountsManager {
             }
         };
 
+        let owned_senders = match &mut state.leases {
+            Some(leases) => {
+                let senders = escrow_accounts
+                    .value()
+                    .await
+                    .expect("Should get escrow accounts from Eventual")
+                    .get_senders();
+                Some(leases.rebalance(&senders).await.clone())
+            }
+            None => None,
+        };
         for (sender_id, allocation_ids) in sender_allocation {
+            if owned_senders
+                .as_ref()
+                .is_some_and(|owned| !owned.contains(&sender_id))
+            {
+                continue;
+            }
             state.sender_ids.insert(sender_id);
             state
                 .create_or_deny_sender(myself.get_cell(), sender_id, allocation_ids)
@@ -154,11 +405,24 @@ impl Actor for SenderAccountsManager {
 
         // Start the new_receipts_watcher task that will consume from the `pglistener`
         // after starting all senders
-        state.new_receipts_watcher_handle = Some(tokio::spawn(new_receipts_watcher(
-            pglistener,
-            escrow_accounts,
-            prefix,
-        )));
+        let sharded = state.leases.is_some();
+        let sampler = Sampler::new(config.indexer_infrastructure.receipt_profiling_ratio);
+        state.new_receipts_watcher_handle = Some(match new_receipts {
+            NewReceipts::Listener(pglistener) => tokio::spawn(new_receipts_watcher(
+                pglistener,
+                escrow_accounts,
+                prefix,
+                sharded,
+                sampler,
+            )),
+            NewReceipts::Polled(receiver) => tokio::spawn(polled_receipts_watcher(
+                receiver,
+                escrow_accounts,
+                prefix,
+                sharded,
+                sampler,
+            )),
+        });
 
         tracing::info!("SenderAccountManager created!");
         Ok(state)
@@ -173,6 +437,11 @@ impl Actor for SenderAccountsManager {
         if let Some(handle) = &state.new_receipts_watcher_handle {
             handle.abort();
         }
+        if let Some(leases) = &state.leases {
+            if let Err(error) = leases.release_all().await {
+                warn!(%error, "Failed to release the sender leases, they will expire instead.");
+            }
+        }
         Ok(())
     }
 
@@ -188,7 +457,12 @@ impl Actor for SenderAccountsManager {
         );
 
         match msg {
-            SenderAccountsManagerMessage::UpdateSenderAccounts(target_senders) => {
+            SenderAccountsManagerMessage::UpdateSenderAccounts(escrow_senders) => {
+                let target_senders = match &mut state.leases {
+                    Some(leases) => leases.rebalance(&escrow_senders).await.clone(),
+                    None => escrow_senders.clone(),
+                };
+
                 // Create new sender accounts
                 for sender in target_senders.difference(&state.sender_ids) {
                     state
@@ -201,12 +475,67 @@ impl Actor for SenderAccountsManager {
                     if let Some(sender_handle) = ActorRef::<SenderAccountMessage>::where_is(
                         state.format_sender_account(sender),
                     ) {
-                        sender_handle.stop(None);
+                        if escrow_senders.contains(sender) {
+                            // Handed over to another tap-agent, which takes over its allocations.
+                            // Killed rather than stopped, so they aren't marked as last.
+                            if let Err(error) = sender_handle.kill_and_wait(None).await {
+                                warn!(%error, %sender, "Error while killing SenderAccount");
+                            }
+                        } else {
+                            sender_handle.stop(None);
+                        }
                     }
                 }
 
+                state
+                    .stopped_sender_ids
+                    .retain(|sender| target_senders.contains(sender));
                 state.sender_ids = target_senders;
             }
+            SenderAccountsManagerMessage::GetHealth(reply) => {
+                if !reply.is_closed() {
+                    let _ = reply.send(state.health());
+                }
+            }
+            SenderAccountsManagerMessage::GetSenders(reply) => {
+                if !reply.is_closed() {
+                    let mut sender_ids: Vec<Address> = state.sender_ids.iter().copied().collect();
+                    sender_ids.sort();
+                    let _ = reply.send(ManagerSenders {
+                        sender_ids,
+                        stopped_sender_ids: state.stopped_sender_ids.clone(),
+                        thresholds: state.thresholds,
+                    });
+                }
+            }
+            SenderAccountsManagerMessage::StopSenderAccount(sender_id, reply) => {
+                let result = state.stop_sender_account(sender_id).await;
+                if !reply.is_closed() {
+                    let _ = reply.send(result);
+                }
+            }
+            SenderAccountsManagerMessage::StartSenderAccount(sender_id, reply) => {
+                let result = state
+                    .start_sender_account(myself.get_cell(), sender_id)
+                    .await;
+                if !reply.is_closed() {
+                    let _ = reply.send(result);
+                }
+            }
+            SenderAccountsManagerMessage::UpdateConfig(thresholds) => {
+                state.thresholds = thresholds;
+                for sender in &state.sender_ids {
+                    if let Some(sender_handle) = ActorRef::<SenderAccountMessage>::where_is(
+                        state.format_sender_account(sender),
+                    ) {
+                        if let Err(error) =
+                            sender_handle.cast(SenderAccountMessage::UpdateConfig(thresholds))
+                        {
+                            warn!(%error, %sender, "Error while updating the SenderAccount config");
+                        }
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -277,6 +606,79 @@ impl State {
         sender_allocation_id
     }
 
+    fn health(&self) -> ManagerHealth {
+        // Senders without an aggregator endpoint are denied instead of getting an actor
+        let dead_sender_accounts = self
+            .sender_ids
+            .iter()
+            .filter(|sender| self.sender_aggregator_endpoints.contains_key(sender))
+            .filter(|sender| !self.stopped_sender_ids.contains(sender))
+            .filter(|sender| {
+                ActorRef::<SenderAccountMessage>::where_is(self.format_sender_account(sender))
+                    .map_or(true, |actor| actor.get_status() != ActorStatus::Running)
+            })
+            .cloned()
+            .collect();
+        let notify_listener_connected = self
+            .new_receipts_watcher_handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished());
+
+        ManagerHealth {
+            dead_sender_accounts,
+            notify_listener_connected,
+        }
+    }
+
+    /// The sender is denied first, as nothing keeps track of its fees while stopped. The subtree
+    /// is then killed rather than stopped, so the allocations aren't marked as last.
+    async fn stop_sender_account(
+        &mut self,
+        sender_id: Address,
+    ) -> Result<(), SenderAccountControlError> {
+        if !self.sender_ids.contains(&sender_id) {
+            return Err(SenderAccountControlError::UnknownSender(sender_id));
+        }
+        if !self.stopped_sender_ids.insert(sender_id) {
+            return Err(SenderAccountControlError::AlreadyStopped(sender_id));
+        }
+        self.denylist
+            .deny(sender_id, DenyReason::AdminStop.into())
+            .await;
+        if let Some(sender_account) =
+            ActorRef::<SenderAccountMessage>::where_is(self.format_sender_account(&sender_id))
+        {
+            if let Err(error) = sender_account.kill_and_wait(None).await {
+                warn!(%error, sender = %sender_id, "Error while killing SenderAccount");
+            }
+        }
+        tracing::warn!(sender = %sender_id, "SenderAccount stopped through the admin API.");
+        Ok(())
+    }
+
+    /// Same as a restart after a panic, the `SenderAccount` picks up its pending allocations
+    /// from the database, and allows the sender again if the deny condition isn't reached.
+    async fn start_sender_account(
+        &mut self,
+        supervisor: ActorCell,
+        sender_id: Address,
+    ) -> Result<(), SenderAccountControlError> {
+        if !self.stopped_sender_ids.contains(&sender_id) {
+            return Err(SenderAccountControlError::NotStopped(sender_id));
+        }
+        let allocation_ids = self
+            .get_pending_sender_allocation_id()
+            .await
+            .remove(&sender_id)
+            .unwrap_or_default();
+        self.create_sender_account(supervisor, sender_id, allocation_ids)
+            .await
+            .map_err(|e| SenderAccountControlError::StartFailed(sender_id, e.to_string()))?;
+        self.stopped_sender_ids.remove(&sender_id);
+        tracing::info!(sender = %sender_id, "SenderAccount started through the admin API.");
+        Ok(())
+    }
+
     async fn create_or_deny_sender(
         &self,
         supervisor: ActorCell,
@@ -291,7 +693,11 @@ impl State {
                 "There was an error while starting the sender {}, denying it. Error: {:?}",
                 sender_id, e
             );
-            SenderAccount::deny_sender(&self.pgpool, sender_id).await;
+            let denial = Denial::new(
+                DenyReason::StartFailed,
+                serde_json::json!({ "error": e.to_string() }),
+            );
+            self.denylist.deny(sender_id, denial).await;
         }
     }
 
@@ -343,8 +749,14 @@ impl State {
         let mut unfinalized_sender_allocations_map: HashMap<Address, HashSet<Address>> =
             HashMap::new();
 
-        let receipts_signer_allocations_in_db = sqlx::query!(
-            r#"
+        // Read from the replica if it's in sync, along with the startup scans
+        let pgpool = database::read_pool(&self.pgpool);
+        let receipts_signer_allocations_in_db = database::acquire(&pgpool, Subsystem::ReceiptScan)
+            .await
+            .expect("should be able to fetch pending receipts from the database")
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                 WITH grouped AS (
                     SELECT signer_address, allocation_id
                     FROM scalar_tap_receipts
@@ -362,10 +774,11 @@ impl State {
                     ) AS allocation_ids
                 FROM grouped AS top
             "#
-        )
-        .fetch_all(&self.pgpool)
-        .await
-        .expect("should be able to fetch pending receipts from the database");
+                )
+                .fetch_all(conn)
+            })
+            .await
+            .expect("should be able to fetch pending receipts from the database");
 
         for row in receipts_signer_allocations_in_db {
             let allocation_ids = row
@@ -390,8 +803,13 @@ impl State {
                 .extend(allocation_ids);
         }
 
-        let nonfinal_ravs_sender_allocations_in_db = sqlx::query!(
-            r#"
+        let nonfinal_ravs_sender_allocations_in_db =
+            database::acquire(&pgpool, Subsystem::RavStore)
+                .await
+                .expect("should be able to fetch unfinalized RAVs from the database")
+                .run(|conn| {
+                    sqlx::query!(
+                        r#"
                 SELECT DISTINCT
                     sender_address,
                     (
@@ -405,10 +823,11 @@ impl State {
                     ) AS allocation_id
                 FROM scalar_tap_ravs AS top
             "#
-        )
-        .fetch_all(&self.pgpool)
-        .await
-        .expect("should be able to fetch unfinalized RAVs from the database");
+                    )
+                    .fetch_all(conn)
+                })
+                .await
+                .expect("should be able to fetch unfinalized RAVs from the database");
 
         for row in nonfinal_ravs_sender_allocations_in_db {
             let allocation_ids = row
@@ -429,8 +848,82 @@ impl State {
                 .or_default()
                 .extend(allocation_ids);
         }
+
+        if self.horizon_domain_separator.is_some() {
+            self.add_pending_horizon_allocations(
+                &escrow_accounts_snapshot,
+                &mut unfinalized_sender_allocations_map,
+            )
+            .await;
+        }
         unfinalized_sender_allocations_map
     }
+
+    /// Same as above, for the Horizon receipts and RAVs. Their collections are mapped back to
+    /// allocations, so the same `SenderAllocation` handles both formats.
+    async fn add_pending_horizon_allocations(
+        &self,
+        escrow_accounts_snapshot: &EscrowAccounts,
+        unfinalized_sender_allocations_map: &mut HashMap<Address, HashSet<Address>>,
+    ) {
+        let pgpool = database::read_pool(&self.pgpool);
+        let receipts_signer_collections_in_db = database::acquire(&pgpool, Subsystem::ReceiptScan)
+            .await
+            .expect("should be able to fetch pending Horizon receipts from the database")
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT DISTINCT signer_address, collection_id
+                FROM tap_horizon_receipts
+            "#,
+                )
+                .fetch_all(conn)
+            })
+            .await
+            .expect("should be able to fetch pending Horizon receipts from the database");
+
+        for row in receipts_signer_collections_in_db {
+            let signer_id = Address::from_str(&row.signer_address)
+                .expect("signer_address should be a valid address");
+            let sender_id = escrow_accounts_snapshot
+                .get_sender_for_signer(&signer_id)
+                .expect("should be able to get sender from signer");
+            let collection_id = FixedBytes::<32>::from_str(&row.collection_id)
+                .expect("collection_id should be a valid collection id");
+
+            unfinalized_sender_allocations_map
+                .entry(sender_id)
+                .or_default()
+                .insert(horizon::allocation_id(collection_id));
+        }
+
+        let nonfinal_ravs_payer_collections_in_db = database::acquire(&pgpool, Subsystem::RavStore)
+            .await
+            .expect("should be able to fetch unfinalized Horizon RAVs from the database")
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT DISTINCT payer, collection_id
+                FROM tap_horizon_ravs
+                WHERE NOT last
+            "#,
+                )
+                .fetch_all(conn)
+            })
+            .await
+            .expect("should be able to fetch unfinalized Horizon RAVs from the database");
+
+        for row in nonfinal_ravs_payer_collections_in_db {
+            let sender_id = Address::from_str(&row.payer).expect("payer should be a valid address");
+            let collection_id = FixedBytes::<32>::from_str(&row.collection_id)
+                .expect("collection_id should be a valid collection id");
+
+            unfinalized_sender_allocations_map
+                .entry(sender_id)
+                .or_default()
+                .insert(horizon::allocation_id(collection_id));
+        }
+    }
     fn new_sender_account_args(
         &self,
         sender_id: &Address,
@@ -439,11 +932,18 @@ impl State {
         Ok(SenderAccountArgs {
             config: self.config,
             pgpool: self.pgpool.clone(),
+            allocation_pgpool: self.allocation_pgpool.clone(),
+            denylist: self.denylist.clone(),
             sender_id: *sender_id,
             escrow_accounts: self.escrow_accounts.clone(),
             indexer_allocations: self.indexer_allocations.clone(),
+            closing_allocations: self.closing_allocations.clone(),
             escrow_subgraph: self.escrow_subgraph,
+            redeemed_ravs: self.redeemed_ravs.clone(),
+            startup_scans: self.startup_scans.clone(),
+            deployment_fees: self.deployment_fees.clone(),
             domain_separator: self.domain_separator.clone(),
+            horizon_domain_separator: self.horizon_domain_separator.clone(),
             sender_aggregator_endpoint: self
                 .sender_aggregator_endpoints
                 .get(sender_id)
@@ -454,17 +954,19 @@ impl State {
                 .clone(),
             allocation_ids,
             prefix: self.prefix.clone(),
-            retry_interval: Duration::from_secs(30),
+            thresholds: self.thresholds,
         })
     }
 }
 
 /// Continuously listens for new receipt notifications from Postgres and forwards them to the
-/// corresponding SenderAccount.
+/// corresponding SenderAccount. The notifications picked by `sampler` are profiled.
 async fn new_receipts_watcher(
     mut pglistener: PgListener,
     escrow_accounts: Eventual<EscrowAccounts>,
     prefix: Option<String>,
+    sharded: bool,
+    sampler: Sampler,
 ) {
     loop {
         // TODO: recover from errors or shutdown the whole program?
@@ -472,15 +974,52 @@ async fn new_receipts_watcher(
             "should be able to receive Postgres Notify events on the channel \
                 'scalar_tap_receipt_notification'",
         );
-        let new_receipt_notification: NewReceiptNotification =
-            serde_json::from_str(pg_notification.payload()).expect(
-                "should be able to deserialize the Postgres Notify event payload as a \
+        let stages = sampler.sample();
+        let mut new_receipt_notification: NewReceiptNotification =
+            if pg_notification.channel() == HORIZON_RECEIPT_CHANNEL {
+                serde_json::from_str::<HorizonReceiptNotification>(pg_notification.payload())
+                    .expect(
+                        "should be able to deserialize the Postgres Notify event payload as a \
+                        HorizonReceiptNotification",
+                    )
+                    .into()
+            } else {
+                serde_json::from_str(pg_notification.payload()).expect(
+                    "should be able to deserialize the Postgres Notify event payload as a \
                         NewReceiptNotification",
-            );
+                )
+            };
+        new_receipt_notification.correlation_id = CorrelationId::new();
+        new_receipt_notification.stages = stages;
+        if let Err(e) = handle_notification(
+            new_receipt_notification,
+            &escrow_accounts,
+            prefix.as_deref(),
+            sharded,
+        )
+        .await
+        {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Same as [`new_receipts_watcher`], for the receipts found by [`poll_new_receipts`].
+async fn polled_receipts_watcher(
+    mut receiver: tokio::sync::mpsc::Receiver<NewReceiptNotification>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    prefix: Option<String>,
+    sharded: bool,
+    sampler: Sampler,
+) {
+    while let Some(mut new_receipt_notification) = receiver.recv().await {
+        new_receipt_notification.correlation_id = CorrelationId::new();
+        new_receipt_notification.stages = sampler.sample();
         if let Err(e) = handle_notification(
             new_receipt_notification,
             &escrow_accounts,
             prefix.as_deref(),
+            sharded,
         )
         .await
         {
@@ -489,10 +1028,38 @@ async fn new_receipts_watcher(
     }
 }
 
+/// Accounts for the receipts of `signer`, once authorized: the ones set aside are moved back to
+/// the receipts tables, which notifies them, and the ones dropped on their notification are
+/// notified again, with `listen`. Without it, the latter are only counted on restart.
+async fn replay_signer_receipts(
+    pgpool: &PgPool,
+    signer: Address,
+    listen: bool,
+    horizon: bool,
+) -> Result<()> {
+    let moved = revalidate_signer_receipts(pgpool, signer).await?;
+    tracing::info!(%signer, moved, "Receipts of the signer set aside moved back");
+    if !listen {
+        warn!(
+            %signer,
+            "The polled receipts can't be notified again, restart to account for the stored \
+            receipts of the signer"
+        );
+        return Ok(());
+    }
+    replay_receipt_notifications(pgpool, 0, REPLAY_BATCH_SIZE, Some(signer)).await?;
+    if horizon {
+        replay_horizon_receipt_notifications(pgpool, 0, REPLAY_BATCH_SIZE, Some(signer)).await?;
+    }
+    Ok(())
+}
+
+/// With `sharded`, the receipts of the senders leased by other tap-agents are ignored.
 async fn handle_notification(
-    new_receipt_notification: NewReceiptNotification,
+    mut new_receipt_notification: NewReceiptNotification,
     escrow_accounts: &Eventual<EscrowAccounts>,
     prefix: Option<&str>,
+    sharded: bool,
 ) -> Result<()> {
     tracing::trace!(
         notification = ?new_receipt_notification,
@@ -505,7 +1072,8 @@ async fn handle_notification(
         .expect("should be able to get escrow accounts")
         .get_sender_for_signer(&new_receipt_notification.signer_address)
     else {
-        // TODO: save the receipt in the failed receipts table?
+        // Replayed once the signer is authorized, see `State::_resolved_signers_pipe`
+        record_unknown_signer(new_receipt_notification.signer_address);
         bail!(
             "No sender address found for receipt signer address {}. \
                     This should not happen.",
@@ -513,9 +1081,35 @@ async fn handle_notification(
         );
     };
 
+    let sender_account_name = format!(
+        "{}{sender_address}",
+        prefix
+            .as_ref()
+            .map_or(String::default(), |prefix| format!("{prefix}:"))
+    );
+    if sharded && ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_none()
+    {
+        tracing::trace!(
+            sender = %sender_address,
+            "Ignoring receipt of a sender leased by another tap-agent."
+        );
+        return Ok(());
+    }
+
     let allocation_id = &new_receipt_notification.allocation_id;
     let allocation_str = &allocation_id.to_string();
 
+    tracing::debug!(
+        event = event::RECEIPT_RECEIVED,
+        sender = %sender_address,
+        allocation = %allocation_id,
+        correlation_id = %new_receipt_notification.correlation_id,
+        receipt_id = new_receipt_notification.id,
+        version = ?new_receipt_notification.version,
+        value = %Money(new_receipt_notification.value),
+        "New receipt received."
+    );
+
     let actor_name = format!(
         "{}{sender_address}:{allocation_id}",
         prefix
@@ -529,13 +1123,6 @@ async fn handle_notification(
                 receipt notification. Starting a new sender_allocation.",
             sender_address, allocation_id
         );
-        let sender_account_name = format!(
-            "{}{sender_address}",
-            prefix
-                .as_ref()
-                .map_or(String::default(), |prefix| format!("{prefix}:"))
-        );
-
         let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender_account_name)
         else {
             bail!(
@@ -554,6 +1141,7 @@ async fn handle_notification(
         return Ok(());
     };
 
+    receipt_profiler::observe(&mut new_receipt_notification.stages, stage::NOTIFICATION);
     sender_allocation
         .cast(SenderAllocationMessage::NewReceipt(
             new_receipt_notification,
@@ -574,14 +1162,21 @@ async fn handle_notification(
 #[cfg(test)]
 mod tests {
     use super::{
-        new_receipts_watcher, SenderAccountsManager, SenderAccountsManagerArgs,
-        SenderAccountsManagerMessage, State,
+        new_receipts_watcher, polled_receipts_watcher, SenderAccountControlError,
+        SenderAccountsManager, SenderAccountsManagerArgs, SenderAccountsManagerMessage, State,
     };
+    use crate::agent::config_reload::Thresholds;
+    use crate::agent::denylist_outbox::{DenyReason, DenylistOutbox};
+    use crate::agent::deployment_fees::DeploymentFees;
+    use crate::agent::redeemed_ravs::RedeemedRavs;
     use crate::agent::sender_account::tests::{MockSenderAllocation, PREFIX_ID};
     use crate::agent::sender_account::SenderAccountMessage;
     use crate::agent::sender_accounts_manager::{handle_notification, NewReceiptNotification};
     use crate::agent::sender_allocation::tests::MockSenderAccount;
+    use crate::agent::startup_scans::StartupScans;
     use crate::config;
+    use crate::privileges::DatabaseFeatures;
+    use crate::storage::{poll_new_receipts, PgStorage};
     use crate::tap::test_utils::{
         create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
         ALLOCATION_ID_1, INDEXER, SENDER, SENDER_2, SENDER_3, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
@@ -592,14 +1187,15 @@ mod tests {
     use indexer_common::allocations::Allocation;
     use indexer_common::escrow_accounts::EscrowAccounts;
     use indexer_common::prelude::{DeploymentDetails, SubgraphClient};
+    use indexer_common::receipt_profiler::Sampler;
     use ractor::concurrency::JoinHandle;
-    use ractor::{Actor, ActorProcessingErr, ActorRef};
+    use ractor::{call, Actor, ActorProcessingErr, ActorRef};
     use ruint::aliases::U256;
     use sqlx::postgres::PgListener;
     use sqlx::PgPool;
     use std::collections::{HashMap, HashSet};
     use std::time::Duration;
-    use tokio::sync::mpsc;
+    use tokio::sync::{mpsc, watch};
 
     const DUMMY_URL: &str = "http://localhost:1234";
 
@@ -620,6 +1216,7 @@ mod tests {
             tap: config::Tap {
                 rav_request_trigger_value: 100,
                 rav_request_timestamp_buffer_ms: 1,
+                rav_request_retry_interval: Duration::from_secs(30),
                 ..Default::default()
             },
             ..Default::default()
@@ -634,9 +1231,7 @@ mod tests {
     ) {
         let config = get_config();
 
-        let (mut indexer_allocations_writer, indexer_allocations_eventual) =
-            Eventual::<HashMap<Address, Allocation>>::new();
-        indexer_allocations_writer.write(HashMap::new());
+        let (_, indexer_allocations) = watch::channel(HashMap::<Address, Allocation>::new());
         let escrow_subgraph = get_subgraph_client();
 
         let (mut escrow_accounts_writer, escrow_accounts_eventual) =
@@ -650,14 +1245,19 @@ mod tests {
         let args = SenderAccountsManagerArgs {
             config,
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            horizon_domain_separator: None,
+            allocation_pgpool: pgpool.clone(),
+            denylist: DenylistOutbox::new(pgpool.clone(), None).unwrap(),
             pgpool,
-            indexer_allocations: indexer_allocations_eventual,
+            indexer_allocations,
+            closing_allocations: Eventual::from_value(HashSet::new()),
             escrow_accounts: escrow_accounts_eventual,
             escrow_subgraph,
             sender_aggregator_endpoints: HashMap::from([
                 (SENDER.1, String::from("http://localhost:8000")),
                 (SENDER_2.1, String::from("http://localhost:8000")),
             ]),
+            database_features: DatabaseFeatures::default(),
             prefix: Some(prefix.clone()),
         };
         (
@@ -675,6 +1275,113 @@ mod tests {
         join_handle.await.unwrap();
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_health(pgpool: PgPool) {
+        let (prefix, (actor, join_handle)) = create_sender_accounts_manager(pgpool).await;
+
+        actor
+            .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(
+                vec![SENDER.1].into_iter().collect(),
+            ))
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let health = call!(actor, SenderAccountsManagerMessage::GetHealth).unwrap();
+        assert!(health.notify_listener_connected);
+        assert!(health.dead_sender_accounts.is_empty());
+
+        // kill the sender account without letting the manager know
+        ActorRef::<SenderAccountMessage>::where_is(format!("{}:{}", prefix, SENDER.1))
+            .unwrap()
+            .kill();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let health = call!(actor, SenderAccountsManagerMessage::GetHealth).unwrap();
+        assert_eq!(health.dead_sender_accounts, vec![SENDER.1]);
+
+        actor.stop_and_wait(None, None).await.unwrap();
+        join_handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_stop_start_sender_account(pgpool: PgPool) {
+        let (prefix, (actor, join_handle)) = create_sender_accounts_manager(pgpool.clone()).await;
+
+        actor
+            .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(
+                vec![SENDER.1].into_iter().collect(),
+            ))
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        call!(
+            actor,
+            SenderAccountsManagerMessage::StopSenderAccount,
+            SENDER.1
+        )
+        .unwrap()
+        .unwrap();
+        assert!(
+            ActorRef::<SenderAccountMessage>::where_is(format!("{}:{}", prefix, SENDER.1))
+                .is_none()
+        );
+        let denied = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM scalar_tap_denylist WHERE sender_address = $1) AS "denied!""#,
+            SENDER.1.encode_hex()
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert!(denied, "Sender was not denied while stopped.");
+
+        // a stopped sender is not reported as dead
+        let health = call!(actor, SenderAccountsManagerMessage::GetHealth).unwrap();
+        assert!(health.dead_sender_accounts.is_empty());
+
+        assert!(matches!(
+            call!(
+                actor,
+                SenderAccountsManagerMessage::StopSenderAccount,
+                SENDER.1
+            )
+            .unwrap(),
+            Err(SenderAccountControlError::AlreadyStopped(_))
+        ));
+        assert!(matches!(
+            call!(
+                actor,
+                SenderAccountsManagerMessage::StopSenderAccount,
+                SENDER_3.1
+            )
+            .unwrap(),
+            Err(SenderAccountControlError::UnknownSender(_))
+        ));
+
+        call!(
+            actor,
+            SenderAccountsManagerMessage::StartSenderAccount,
+            SENDER.1
+        )
+        .unwrap()
+        .unwrap();
+        assert!(
+            ActorRef::<SenderAccountMessage>::where_is(format!("{}:{}", prefix, SENDER.1))
+                .is_some()
+        );
+        assert!(matches!(
+            call!(
+                actor,
+                SenderAccountsManagerMessage::StartSenderAccount,
+                SENDER.1
+            )
+            .unwrap(),
+            Err(SenderAccountControlError::NotStopped(_))
+        ));
+
+        actor.stop_and_wait(None, None).await.unwrap();
+        join_handle.await.unwrap();
+    }
+
     fn create_state(pgpool: PgPool) -> (String, State) {
         let config = get_config();
         let senders_to_signers = vec![(SENDER.1, vec![SIGNER.1])].into_iter().collect();
@@ -688,13 +1395,28 @@ mod tests {
             prefix.clone(),
             State {
                 config,
+                thresholds: Thresholds::from(&config.tap),
                 domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+                horizon_domain_separator: None,
                 sender_ids: HashSet::new(),
+                stopped_sender_ids: HashSet::new(),
                 new_receipts_watcher_handle: None,
                 _eligible_allocations_senders_pipe: Eventual::from_value(())
                     .pipe_async(|_| async {}),
+                _resolved_signers_pipe: Eventual::from_value(()).pipe_async(|_| async {}),
+                allocation_pgpool: pgpool.clone(),
+                denylist: DenylistOutbox::new(pgpool.clone(), None).unwrap(),
+                redeemed_ravs: RedeemedRavs::new(
+                    pgpool.clone(),
+                    get_subgraph_client(),
+                    Duration::from_secs(60),
+                ),
+                startup_scans: StartupScans::new(usize::MAX, HashMap::new()),
+                deployment_fees: DeploymentFees::new(Eventual::from_value(HashMap::new())),
+                leases: None,
                 pgpool,
-                indexer_allocations: Eventual::from_value(HashSet::new()),
+                indexer_allocations: watch::channel(HashMap::new()).1,
+                closing_allocations: Eventual::from_value(HashSet::new()),
                 escrow_accounts: Eventual::from_value(escrow_accounts),
                 escrow_subgraph: get_subgraph_client(),
                 sender_aggregator_endpoints: HashMap::from([
@@ -850,6 +1572,14 @@ mod tests {
         .expect("Deny status cannot be null");
 
         assert!(denied, "Sender was not denied after failing.");
+        let reason = sqlx::query_scalar!(
+            "SELECT reason FROM scalar_tap_denylist WHERE sender_address = $1",
+            sender_id.encode_hex()
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(reason.as_deref(), Some(DenyReason::StartFailed.as_str()));
 
         supervisor.stop_and_wait(None, None).await.unwrap();
         handle.await.unwrap();
@@ -898,6 +1628,8 @@ mod tests {
             pglistener,
             escrow_accounts_eventual,
             Some(prefix.clone()),
+            false,
+            Sampler::default(),
         ));
 
         // add receipts to the database
@@ -920,6 +1652,60 @@ mod tests {
         new_receipts_watcher_handle.abort();
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_receive_polled_receipts(pgpool: PgPool) {
+        let prefix = format!(
+            "test-{}",
+            PREFIX_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        );
+        let (mock_sender_allocation, receipts) = MockSenderAllocation::new_with_receipts();
+        let _ = MockSenderAllocation::spawn(
+            Some(format!("{}:{}:{}", prefix, SENDER.1, *ALLOCATION_ID_0)),
+            mock_sender_allocation,
+            (),
+        )
+        .await
+        .unwrap();
+
+        let escrow_accounts_eventual = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let receiver = poll_new_receipts(
+            PgStorage::new(pgpool.clone()),
+            0,
+            None,
+            Duration::from_millis(10),
+        );
+        let watcher_handle = tokio::spawn(polled_receipts_watcher(
+            receiver,
+            escrow_accounts_eventual,
+            Some(prefix.clone()),
+            false,
+            Sampler::default(),
+        ));
+
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let receipts = receipts.lock().unwrap();
+        assert_eq!(
+            receipts
+                .iter()
+                .map(|receipt| receipt.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        watcher_handle.abort();
+    }
+
     #[tokio::test]
     async fn test_create_allocation_id() {
         let senders_to_signers = vec![(SENDER.1, vec![SIGNER.1])].into_iter().collect();
@@ -949,11 +1735,19 @@ mod tests {
             signer_address: SIGNER.1,
             timestamp_ns: 1,
             value: 1,
+            correlation_id: Default::default(),
+            version: Default::default(),
+            stages: None,
         };
 
-        handle_notification(new_receipt_notification, &escrow_accounts, Some(&prefix))
-            .await
-            .unwrap();
+        handle_notification(
+            new_receipt_notification,
+            &escrow_accounts,
+            Some(&prefix),
+            false,
+        )
+        .await
+        .unwrap();
 
         tokio::time::sleep(Duration::from_millis(10)).await;
 
@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
+    collections::HashMap,
+    str::FromStr,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use alloy::primitives::Address;
@@ -11,10 +13,17 @@ use alloy::{dyn_abi::Eip712Domain, hex::ToHexExt};
 use anyhow::{anyhow, ensure, Result};
 use bigdecimal::{num_bigint::BigInt, ToPrimitive};
 use eventuals::Eventual;
-use indexer_common::{escrow_accounts::EscrowAccounts, prelude::SubgraphClient};
+use indexer_common::{
+    escrow_accounts::EscrowAccounts, fees::FeeToken, prelude::SubgraphClient,
+    receipt_profiler::Stages, tap::rejection::RejectionCode,
+};
 use jsonrpsee::{core::client::ClientT, rpc_params};
-use prometheus::{register_counter_vec, register_histogram_vec, CounterVec, HistogramVec};
-use ractor::{Actor, ActorProcessingErr, ActorRef};
+use prometheus::{
+    exponential_buckets, register_counter_vec, register_gauge_vec, register_histogram_vec,
+    CounterVec, GaugeVec, HistogramVec,
+};
+use ractor::{Actor, ActorProcessingErr, ActorRef, MessagingErr};
+use serde::Serialize;
 use sqlx::{types::BigDecimal, PgPool};
 use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
 use tap_core::{
@@ -27,21 +36,36 @@ use tap_core::{
     },
     signed_message::EIP712SignedMessage,
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
 use crate::{agent::sender_account::ReceiptFees, lazy_static};
 
-use crate::agent::sender_account::SenderAccountMessage;
+use crate::agent::aggregator_client::AggregatorClient;
+use crate::agent::deployment_fees::DeploymentFees;
+use crate::agent::lifecycle_hooks;
+use crate::agent::rav_intents::{self, RavIntent};
+use crate::agent::receipt_profiler::{self, stage};
+use crate::agent::sender_account::{SenderAccountMessage, RECEIPT_FEES_MAILBOX_DEPTH};
 use crate::agent::sender_accounts_manager::NewReceiptNotification;
+use crate::agent::startup_scans::StartupScans;
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+use crate::database::{self, Subsystem};
+use crate::logging::{event, CorrelationId};
+use crate::metrics::{exemplars, series::SeriesOwner};
+use crate::money::Money;
 use crate::{
-    config::{self},
+    config::{self, AllocationEvent, DeniedSenderReceipts},
     tap::context::{checks::Signature, TapAgentContext},
     tap::signers_trimmed,
+    tap::TapVersion,
     tap::{context::checks::AllocationId, escrow_adapter::EscrowAdapter},
 };
+use horizon::HorizonAllocation;
 use thiserror::Error;
 
+pub(crate) mod horizon;
+pub(crate) mod rav_checks;
+
 lazy_static! {
     static ref CLOSED_SENDER_ALLOCATIONS: CounterVec = register_counter_vec!(
         "tap_closed_sender_allocation_total",
@@ -61,10 +85,50 @@ lazy_static! {
         &["sender", "allocation"]
     )
     .unwrap();
+    static ref RAVS_REJECTED: CounterVec = register_counter_vec!(
+        "tap_rav_rejected_total",
+        "RAVs returned by the senders' aggregators and rejected by the sanity checks",
+        &["sender", "reason"]
+    )
+    .unwrap();
     static ref RAV_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
         "tap_rav_response_time_seconds",
         "RAV response time per sender",
-        &["sender"]
+        &["sender"],
+        // Up to the longest `rav_request_timeout_secs` that make sense
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    static ref RAV_RESPONSE_SIZE: HistogramVec = register_histogram_vec!(
+        "tap_rav_response_size_bytes",
+        "Size of the responses of the senders' aggregators, as JSON, per sender",
+        &["sender"],
+        exponential_buckets(256.0, 4.0, 8).unwrap()
+    )
+    .unwrap();
+    static ref RAV_RECEIPTS: HistogramVec = register_histogram_vec!(
+        "tap_rav_receipts",
+        "Receipts aggregated per RAV request, per sender",
+        &["sender"],
+        exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref INVALID_RECEIPTS: CounterVec = register_counter_vec!(
+        "tap_invalid_receipts_total",
+        "Receipts found invalid when requesting RAVs, by rejection code",
+        &["sender", "code"]
+    )
+    .unwrap();
+    static ref DENIED_SENDER_RECEIPTS: CounterVec = register_counter_vec!(
+        "tap_denied_sender_receipts_total",
+        "Receipts received while their sender is denied, by `tap.denied_sender_receipts` policy",
+        &["sender", "policy"]
+    )
+    .unwrap();
+    static ref UNAGGREGATED_FEES_BY_SIGNER: GaugeVec = register_gauge_vec!(
+        "tap_unaggregated_fees_by_signer",
+        "Unaggregated fees value per signer of the sender",
+        &["sender", "allocation", "signer"]
     )
     .unwrap();
 }
@@ -89,13 +153,71 @@ pub enum RavError {
 
 type TapManager = tap_core::manager::Manager<TapAgentContext>;
 
+/// Records a RAV request of `receipts` answered by the aggregator of `sender` in
+/// `response_time`.
+fn observe_rav_response<T: Serialize>(
+    metrics: &SeriesOwner,
+    sender: &Address,
+    response_time: Duration,
+    response: &T,
+    receipts: usize,
+) {
+    let sender = sender.to_string();
+    exemplars::observe(
+        &metrics.with_label_values(&RAV_RESPONSE_TIME, &[&sender]),
+        response_time.as_secs_f64(),
+    );
+    if let Ok(response) = serde_json::to_vec(response) {
+        metrics
+            .with_label_values(&RAV_RESPONSE_SIZE, &[&sender])
+            .observe(response.len() as f64);
+    }
+    metrics
+        .with_label_values(&RAV_RECEIPTS, &[&sender])
+        .observe(receipts as f64);
+}
+
+/// New receipts are summed over this window before being sent to the `SenderAccount`, so that
+/// its mailbox doesn't grow with every receipt under load.
+const RECEIPT_FEES_BATCH_WINDOW: Duration = Duration::from_millis(50);
+
+/// Receipts received during the current [`RECEIPT_FEES_BATCH_WINDOW`].
+struct ReceiptFeesBatch {
+    value: u128,
+    count: u64,
+    /// Of the first receipt of the batch.
+    correlation_id: CorrelationId,
+    /// Of the first receipt of the batch, if profiled.
+    stages: Option<Stages>,
+    /// Largest of the batch, see [`crate::agent::trust_score`].
+    timestamp_skew: Duration,
+}
+
+/// Difference between the timestamp of a receipt and now, either way.
+fn timestamp_skew(timestamp_ns: u64) -> Duration {
+    let timestamp = UNIX_EPOCH + Duration::from_nanos(timestamp_ns);
+    let now = SystemTime::now();
+    now.duration_since(timestamp)
+        .or_else(|_| timestamp.duration_since(now))
+        .unwrap_or_default()
+}
+
 /// Manages unaggregated fees and the TAP lifecyle for a specific (allocation, sender) pair.
 pub struct SenderAllocation;
 
 pub struct SenderAllocationState {
     unaggregated_fees: UnaggregatedReceipts,
+    /// Split of `unaggregated_fees.value` between the signers of the sender.
+    unaggregated_fees_by_signer: HashMap<Address, u128>,
+    /// Not sent to the `SenderAccount` yet.
+    receipt_fees_batch: Option<ReceiptFeesBatch>,
     invalid_receipts_fees: UnaggregatedReceipts,
     latest_rav: Option<SignedRAV>,
+    /// Id of the intent of the RAV request in flight, see [`crate::agent::rav_intents`].
+    rav_intent: Option<i64>,
+    /// Escrow reserved for the valid receipts of the RAV request in flight, released if it fails.
+    rav_reservation: u128,
+    escrow_adapter: EscrowAdapter,
     pgpool: PgPool,
     tap_manager: TapManager,
     allocation_id: Address,
@@ -104,8 +226,18 @@ pub struct SenderAllocationState {
     escrow_accounts: Eventual<EscrowAccounts>,
     domain_separator: Eip712Domain,
     sender_account_ref: ActorRef<SenderAccountMessage>,
-
-    sender_aggregator: jsonrpsee::http_client::HttpClient,
+    /// Set if Horizon receipts are collected as well.
+    horizon: Option<HorizonAllocation>,
+    /// Kept up to date by the `SenderAccount`, for `tap.denied_sender_receipts`.
+    sender_denied: bool,
+    startup_scans: StartupScans,
+    deployment_fees: DeploymentFees,
+    /// The series of the sender, shared with its `SenderAccount`.
+    sender_metrics: Arc<SeriesOwner>,
+    /// The series of the allocation, removed when it stops.
+    metrics: SeriesOwner,
+
+    sender_aggregator: AggregatorClient,
 }
 
 pub struct SenderAllocationArgs {
@@ -117,14 +249,29 @@ pub struct SenderAllocationArgs {
     pub escrow_subgraph: &'static SubgraphClient,
     pub escrow_adapter: EscrowAdapter,
     pub domain_separator: Eip712Domain,
+    /// Set if Horizon receipts are collected as well.
+    pub horizon_domain_separator: Option<Eip712Domain>,
     pub sender_account_ref: ActorRef<SenderAccountMessage>,
-    pub sender_aggregator: jsonrpsee::http_client::HttpClient,
+    pub sender_aggregator: AggregatorClient,
+    pub sender_denied: bool,
+    /// Shared by all the `SenderAllocation`s, see [`crate::agent::startup_scans`].
+    pub startup_scans: StartupScans,
+    /// See [`crate::agent::deployment_fees`].
+    pub deployment_fees: DeploymentFees,
+    /// The series of the sender, see [`crate::metrics::series`].
+    pub sender_metrics: Arc<SeriesOwner>,
 }
 
 #[derive(Debug)]
 pub enum SenderAllocationMessage {
     NewReceipt(NewReceiptNotification),
-    TriggerRAVRequest,
+    /// Sends the receipts of the current batch to the `SenderAccount`.
+    FlushReceiptFees,
+    /// Carries the span of the decision that triggered the request, so the RAV request shows up
+    /// under it in the exported traces.
+    TriggerRAVRequest(CorrelationId, Span),
+    /// Sent by the `SenderAccount` when the sender is denied or allowed.
+    SenderDenied(bool),
     #[cfg(test)]
     GetUnaggregatedReceipts(ractor::RpcReplyPort<UnaggregatedReceipts>),
 }
@@ -140,31 +287,68 @@ impl Actor for SenderAllocation {
         _myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
-        let sender_account_ref = args.sender_account_ref.clone();
-        let allocation_id = args.allocation_id;
-        let mut state = SenderAllocationState::new(args).await?;
+        Ok(SenderAllocationState::new(args).await?)
+    }
+
+    // The receipts are scanned once the actor is started, so that the `SenderAccount` doesn't
+    // wait for its turn in the `StartupScans`
+    async fn post_start(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> std::result::Result<(), ActorProcessingErr> {
+        let sender_account_ref = state.sender_account_ref.clone();
+        let allocation_id = state.allocation_id;
+        let _permit = state.startup_scans.acquire(allocation_id).await;
 
         // update invalid receipts
         state.invalid_receipts_fees = state.calculate_invalid_receipts_fee().await?;
-        if state.invalid_receipts_fees.value > 0 {
+        if let Some(horizon) = &mut state.horizon {
+            horizon.invalid_receipts_fees = horizon.calculate_invalid_receipts_fee().await?;
+        }
+        let invalid_receipts_fees = state.total_invalid_receipts_fees();
+        if invalid_receipts_fees.value > 0 {
             sender_account_ref.cast(SenderAccountMessage::UpdateInvalidReceiptFees(
                 allocation_id,
-                state.invalid_receipts_fees.clone(),
+                invalid_receipts_fees,
             ))?;
         }
 
+        // Before the fees, the RAV requests in flight on the last stop
+        rav_intents::reconcile(
+            &state.pgpool,
+            allocation_id,
+            state.sender,
+            state.latest_rav.as_ref().map(|rav| rav.message.timestampNs),
+        )
+        .await?;
+
         // update unaggregated_fees
         state.unaggregated_fees = state.initialize_unaggregated_receipts().await?;
+        state.update_fees_by_signer().await?;
+        if let Some(horizon) = &mut state.horizon {
+            horizon.unaggregated_fees = horizon.initialize_unaggregated_receipts().await?;
+        }
 
         sender_account_ref.cast(SenderAccountMessage::UpdateReceiptFees(
             allocation_id,
-            ReceiptFees::UpdateValue(state.unaggregated_fees.clone()),
+            ReceiptFees::UpdateValue(state.total_unaggregated_fees()),
         ))?;
 
         // update rav tracker for sender account
         if let Some(rav) = &state.latest_rav {
             sender_account_ref.cast(SenderAccountMessage::UpdateRav(rav.clone()))?;
         }
+        if let Some(horizon) = state
+            .horizon
+            .as_ref()
+            .filter(|horizon| !horizon.latest_ravs.is_empty())
+        {
+            sender_account_ref.cast(SenderAccountMessage::UpdateHorizonRav(
+                allocation_id,
+                horizon.rav_value(),
+            ))?;
+        }
 
         tracing::info!(
             sender = %state.sender,
@@ -172,7 +356,7 @@ impl Actor for SenderAllocation {
             "SenderAllocation created!",
         );
 
-        Ok(state)
+        Ok(())
     }
 
     // this method only runs on graceful stop (real close allocation)
@@ -195,22 +379,50 @@ impl Actor for SenderAllocation {
             }
         }
 
+        while state
+            .horizon
+            .as_ref()
+            .is_some_and(|horizon| horizon.unaggregated_fees.value > 0)
+        {
+            if let Err(err) = state.request_horizon_rav().await {
+                error!(error = %err, "There was an error while requesting Horizon rav. Retrying in 30 seconds...");
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        }
+
         while let Err(err) = state.mark_rav_last().await {
             error!(error = %err, %state.allocation_id, %state.sender,  "Error while marking allocation last. Retrying in 30 seconds...");
             tokio::time::sleep(Duration::from_secs(30)).await;
         }
+        if let Some(rav) = &state.latest_rav {
+            lifecycle_hooks::notify(
+                AllocationEvent::LastRav,
+                state.sender,
+                state.allocation_id,
+                Some(rav.message.valueAggregate),
+            );
+        }
+        if let Some(horizon) = &state.horizon {
+            while let Err(err) = horizon.mark_rav_last().await {
+                error!(error = %err, %state.allocation_id, %state.sender,  "Error while marking Horizon allocation last. Retrying in 30 seconds...");
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        }
 
         // Since this is only triggered after allocation is closed will be counted here
-        CLOSED_SENDER_ALLOCATIONS
-            .with_label_values(&[&state.sender.to_string()])
+        state
+            .sender_metrics
+            .with_label_values(&CLOSED_SENDER_ALLOCATIONS, &[&state.sender.to_string()])
             .inc();
+        state.set_fees_by_signer(HashMap::new());
+        state.metrics.remove_all();
 
         Ok(())
     }
 
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> std::result::Result<(), ActorProcessingErr> {
@@ -220,12 +432,40 @@ impl Actor for SenderAllocation {
             ?message,
             "New SenderAllocation message"
         );
-        let unaggregated_fees = &mut state.unaggregated_fees;
         match message {
             SenderAllocationMessage::NewReceipt(notification) => {
                 let NewReceiptNotification {
-                    id, value: fees, ..
+                    id,
+                    value: fees,
+                    signer_address,
+                    correlation_id,
+                    version,
+                    timestamp_ns,
+                    mut stages,
+                    ..
                 } = notification;
+                receipt_profiler::observe(&mut stages, stage::ALLOCATION_MAILBOX);
+                if version == TapVersion::V2 && state.horizon.is_none() {
+                    warn!("Received a Horizon receipt notification while Horizon is disabled.");
+                    return Ok(());
+                }
+                if state.sender_denied {
+                    match state.set_aside_denied_receipt(id, version).await {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => {}
+                        Err(e) => {
+                            error!(
+                                receipt_id = id,
+                                "Failed to set aside a receipt of a denied sender: {}", e
+                            );
+                        }
+                    }
+                }
+                // Receipt ids are only ordered within the table of their format.
+                let unaggregated_fees = match (version, &mut state.horizon) {
+                    (TapVersion::V2, Some(horizon)) => &mut horizon.unaggregated_fees,
+                    _ => &mut state.unaggregated_fees,
+                };
                 if id <= unaggregated_fees.last_id {
                     // our world assumption is wrong
                     warn!(
@@ -250,20 +490,73 @@ impl Actor for SenderAllocation {
                             u128::MAX
                         });
                 unaggregated_fees.counter += 1;
+
+                if version == TapVersion::V1 {
+                    let signer_fees = state
+                        .unaggregated_fees_by_signer
+                        .entry(signer_address)
+                        .or_default();
+                    *signer_fees = signer_fees.saturating_add(fees);
+                    state
+                        .metrics
+                        .with_label_values(
+                            &UNAGGREGATED_FEES_BY_SIGNER,
+                            &[
+                                &state.sender.to_string(),
+                                &state.allocation_id.to_string(),
+                                &signer_address.to_string(),
+                            ],
+                        )
+                        .set(*signer_fees as f64);
+                }
+                receipt_profiler::observe(&mut stages, stage::ALLOCATION_UPDATE);
+                let timestamp_skew = timestamp_skew(timestamp_ns);
+                match &mut state.receipt_fees_batch {
+                    Some(batch) => {
+                        batch.value = batch.value.saturating_add(fees);
+                        batch.count += 1;
+                        batch.timestamp_skew = batch.timestamp_skew.max(timestamp_skew);
+                    }
+                    None => {
+                        state.receipt_fees_batch = Some(ReceiptFeesBatch {
+                            value: fees,
+                            count: 1,
+                            correlation_id,
+                            stages,
+                            timestamp_skew,
+                        });
+                        myself.send_after(RECEIPT_FEES_BATCH_WINDOW, || {
+                            SenderAllocationMessage::FlushReceiptFees
+                        });
+                    }
+                }
+            }
+            SenderAllocationMessage::FlushReceiptFees => {
                 // it's fine to crash the actor, could not send a message to its parent
-                state
-                    .sender_account_ref
-                    .cast(SenderAccountMessage::UpdateReceiptFees(
-                        state.allocation_id,
-                        ReceiptFees::NewReceipt(fees),
-                    ))?;
+                state.flush_receipt_fees()?;
+            }
+            SenderAllocationMessage::SenderDenied(denied) => {
+                state.sender_denied = denied;
             }
-            SenderAllocationMessage::TriggerRAVRequest => {
-                let rav_result = if state.unaggregated_fees.value > 0 {
+            SenderAllocationMessage::TriggerRAVRequest(correlation_id, parent) => {
+                // The RAV request response overwrites the fees of the SenderAccount, which must
+                // not receive the batched receipts afterwards.
+                state.flush_receipt_fees()?;
+
+                let span = info_span!(
+                    parent: &parent,
+                    "rav_request",
+                    sender = %state.sender,
+                    allocation = %state.allocation_id,
+                    %correlation_id,
+                    rav_id = field::Empty,
+                );
+                let rav_result = if state.total_unaggregated_fees().value > 0 {
                     state
-                        .request_rav()
+                        .request_ravs()
+                        .instrument(span)
                         .await
-                        .map(|_| (state.unaggregated_fees.clone(), state.latest_rav.clone()))
+                        .map(|_| (state.total_unaggregated_fees(), state.latest_rav.clone()))
                 } else {
                     Err(anyhow!("Unaggregated fee equals zero"))
                 };
@@ -278,7 +571,7 @@ impl Actor for SenderAllocation {
             #[cfg(test)]
             SenderAllocationMessage::GetUnaggregatedReceipts(reply) => {
                 if !reply.is_closed() {
-                    let _ = reply.send(unaggregated_fees.clone());
+                    let _ = reply.send(state.unaggregated_fees.clone());
                 }
             }
         }
@@ -288,6 +581,29 @@ impl Actor for SenderAllocation {
 }
 
 impl SenderAllocationState {
+    fn flush_receipt_fees(
+        &mut self,
+    ) -> std::result::Result<(), MessagingErr<SenderAccountMessage>> {
+        let Some(mut batch) = self.receipt_fees_batch.take() else {
+            return Ok(());
+        };
+        receipt_profiler::observe(&mut batch.stages, stage::FEES_BATCH);
+        self.sender_metrics
+            .with_label_values(&RECEIPT_FEES_MAILBOX_DEPTH, &[&self.sender.to_string()])
+            .inc();
+        self.sender_account_ref
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                self.allocation_id,
+                ReceiptFees::NewReceipts(
+                    batch.value,
+                    batch.count,
+                    batch.correlation_id,
+                    batch.stages,
+                    batch.timestamp_skew,
+                ),
+            ))
+    }
+
     async fn new(
         SenderAllocationArgs {
             config,
@@ -298,8 +614,13 @@ impl SenderAllocationState {
             escrow_subgraph,
             escrow_adapter,
             domain_separator,
+            horizon_domain_separator,
             sender_account_ref,
             sender_aggregator,
+            sender_denied,
+            startup_scans,
+            deployment_fees,
+            sender_metrics,
         }: SenderAllocationArgs,
     ) -> anyhow::Result<Self> {
         let required_checks: Vec<Arc<dyn Check + Send + Sync>> = vec![
@@ -319,14 +640,31 @@ impl SenderAllocationState {
             allocation_id,
             sender,
             escrow_accounts.clone(),
-            escrow_adapter,
-        );
+            escrow_adapter.clone(),
+        )
+        .with_delete_receipts_with_rav(config.tap.delete_receipts_with_rav);
         let latest_rav = context.last_rav().await.unwrap_or_default();
         let tap_manager = TapManager::new(
             domain_separator.clone(),
             context,
             CheckList::new(required_checks),
         );
+        let horizon = match horizon_domain_separator {
+            Some(horizon_domain_separator) => Some(
+                HorizonAllocation::new(
+                    config,
+                    pgpool.clone(),
+                    allocation_id,
+                    sender,
+                    escrow_accounts.clone(),
+                    escrow_adapter.clone(),
+                    horizon_domain_separator,
+                    sender_metrics.clone(),
+                )
+                .await?,
+            ),
+            None => None,
+        };
 
         Ok(Self {
             pgpool,
@@ -338,83 +676,287 @@ impl SenderAllocationState {
             domain_separator,
             sender_account_ref: sender_account_ref.clone(),
             unaggregated_fees: UnaggregatedReceipts::default(),
+            unaggregated_fees_by_signer: HashMap::new(),
+            receipt_fees_batch: None,
             invalid_receipts_fees: UnaggregatedReceipts::default(),
             latest_rav,
+            rav_intent: None,
+            rav_reservation: 0,
+            escrow_adapter,
+            horizon,
+            sender_denied,
+            startup_scans,
+            deployment_fees,
+            sender_metrics,
+            metrics: SeriesOwner::default(),
             sender_aggregator,
         })
     }
 
+    /// Deletes or quarantines a receipt received while the sender is denied, as configured by
+    /// `tap.denied_sender_receipts`. Returns whether the receipt was set aside, and must not be
+    /// counted.
+    async fn set_aside_denied_receipt(&self, id: u64, version: TapVersion) -> Result<bool> {
+        let policy = self.config.tap.denied_sender_receipts;
+        self.sender_metrics
+            .with_label_values(
+                &DENIED_SENDER_RECEIPTS,
+                &[&self.sender.to_string(), policy.as_str()],
+            )
+            .inc();
+        let quarantine = match policy {
+            // The `SenderAccount` warns about them
+            DeniedSenderReceipts::Track => return Ok(false),
+            DeniedSenderReceipts::Reject => false,
+            DeniedSenderReceipts::Quarantine => true,
+        };
+        let receipt_id = id as i64;
+        database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| async move {
+                match (quarantine, version) {
+                    (false, TapVersion::V1) => {
+                        sqlx::query!("DELETE FROM scalar_tap_receipts WHERE id = $1", receipt_id)
+                            .execute(conn)
+                            .await
+                    }
+                    (false, TapVersion::V2) => {
+                        sqlx::query!("DELETE FROM tap_horizon_receipts WHERE id = $1", receipt_id)
+                            .execute(conn)
+                            .await
+                    }
+                    (true, TapVersion::V1) => {
+                        sqlx::query!(
+                            r#"
+                                WITH quarantined AS (
+                                    DELETE FROM scalar_tap_receipts WHERE id = $1 RETURNING *
+                                )
+                                INSERT INTO scalar_tap_receipts_quarantined (
+                                    id, signer_address, signature, allocation_id, timestamp_ns,
+                                    nonce, value, fee_token
+                                )
+                                SELECT id, signer_address, signature, allocation_id, timestamp_ns,
+                                    nonce, value, fee_token
+                                FROM quarantined
+                            "#,
+                            receipt_id
+                        )
+                        .execute(conn)
+                        .await
+                    }
+                    (true, TapVersion::V2) => {
+                        sqlx::query!(
+                            r#"
+                                WITH quarantined AS (
+                                    DELETE FROM tap_horizon_receipts WHERE id = $1 RETURNING *
+                                )
+                                INSERT INTO tap_horizon_receipts_quarantined (
+                                    id, signer_address, signature, collection_id, payer,
+                                    data_service, service_provider, timestamp_ns, nonce, value,
+                                    fee_token
+                                )
+                                SELECT id, signer_address, signature, collection_id, payer,
+                                    data_service, service_provider, timestamp_ns, nonce, value,
+                                    fee_token
+                                FROM quarantined
+                            "#,
+                            receipt_id
+                        )
+                        .execute(conn)
+                        .await
+                    }
+                }
+            })
+            .await?;
+        info!(
+            receipt_id = id,
+            policy = policy.as_str(),
+            "Set aside a receipt received while the sender is denied."
+        );
+        Ok(true)
+    }
+
+    /// Unaggregated fees of both formats, as tracked by the `SenderAccount`. The `last_id` is the
+    /// one of the legacy receipts.
+    fn total_unaggregated_fees(&self) -> UnaggregatedReceipts {
+        let mut total = self.unaggregated_fees.clone();
+        if let Some(horizon) = &self.horizon {
+            total.value = total.value.saturating_add(horizon.unaggregated_fees.value);
+            total.counter += horizon.unaggregated_fees.counter;
+        }
+        total
+    }
+
+    /// Invalid receipts fees of both formats, as tracked by the `SenderAccount`.
+    fn total_invalid_receipts_fees(&self) -> UnaggregatedReceipts {
+        let mut total = self.invalid_receipts_fees.clone();
+        if let Some(horizon) = &self.horizon {
+            total.value = total
+                .value
+                .saturating_add(horizon.invalid_receipts_fees.value);
+            total.counter += horizon.invalid_receipts_fees.counter;
+        }
+        total
+    }
+
+    /// Scans all the receipts on start, from the read replica if it's in sync.
     async fn initialize_unaggregated_receipts(&self) -> Result<UnaggregatedReceipts> {
-        self.calculate_fee_until_last_id(i64::MAX).await
+        self.calculate_fee_until_last_id(&database::read_pool(&self.pgpool), i64::MAX)
+            .await
     }
 
     async fn calculate_unaggregated_fee(&self) -> Result<UnaggregatedReceipts> {
-        self.calculate_fee_until_last_id(self.unaggregated_fees.last_id as i64)
+        self.calculate_fee_until_last_id(&self.pgpool, self.unaggregated_fees.last_id as i64)
             .await
     }
 
     /// Delete obsolete receipts in the DB w.r.t. the last RAV in DB, then update the tap manager
-    /// with the latest unaggregated fees from the database.
-    async fn calculate_fee_until_last_id(&self, last_id: i64) -> Result<UnaggregatedReceipts> {
+    /// with the latest unaggregated fees from `pgpool`.
+    async fn calculate_fee_until_last_id(
+        &self,
+        pgpool: &PgPool,
+        last_id: i64,
+    ) -> Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_unaggregated_fee()");
         self.tap_manager.remove_obsolete_receipts().await?;
 
         let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        self.fee_for_signers_until_last_id(pgpool, &signers, last_id)
+            .await
+    }
 
-        let res = sqlx::query!(
-            r#"
+    async fn fee_for_signers_until_last_id(
+        &self,
+        pgpool: &PgPool,
+        signers: &[String],
+        last_id: i64,
+    ) -> Result<UnaggregatedReceipts> {
+        let fees_by_signer = self
+            .fees_by_signer_until_last_id(pgpool, signers, last_id)
+            .await?;
+        Ok(fees_by_signer
+            .values()
+            .fold(UnaggregatedReceipts::default(), |total, fees| {
+                UnaggregatedReceipts {
+                    value: total.value + fees.value,
+                    last_id: total.last_id.max(fees.last_id),
+                    counter: total.counter + fees.counter,
+                }
+            }))
+    }
+
+    /// The unaggregated fees of each of `signers`, from the receipts up to `last_id` after the
+    /// last RAV. Summed up by the database in a single query, which only reads the
+    /// `scalar_tap_receipts_fees_idx` index.
+    async fn fees_by_signer_until_last_id(
+        &self,
+        pgpool: &PgPool,
+        signers: &[String],
+        last_id: i64,
+    ) -> Result<HashMap<Address, UnaggregatedReceipts>> {
+        let rows = database::acquire(pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
             SELECT
-                MAX(id),
-                SUM(value),
-                COUNT(*)
+                signer_address,
+                MAX(id) AS "max!",
+                SUM(value) AS "sum!",
+                COUNT(*) AS "count!"
             FROM
                 scalar_tap_receipts
             WHERE
                 allocation_id = $1
                 AND id <= $2
-                AND signer_address IN (SELECT unnest($3::text[]))
+                AND signer_address = ANY($3::text[])
                 AND timestamp_ns > $4
+            GROUP BY
+                signer_address
             "#,
-            self.allocation_id.encode_hex(),
-            last_id,
-            &signers,
-            BigDecimal::from(
-                self.latest_rav
-                    .as_ref()
-                    .map(|rav| rav.message.timestampNs)
-                    .unwrap_or_default()
-            ),
-        )
-        .fetch_one(&self.pgpool)
-        .await?;
+                    self.allocation_id.encode_hex(),
+                    last_id,
+                    signers,
+                    BigDecimal::from(
+                        self.latest_rav
+                            .as_ref()
+                            .map(|rav| rav.message.timestampNs)
+                            .unwrap_or_default()
+                    ),
+                )
+                .fetch_all(conn)
+            })
+            .await?;
 
-        ensure!(
-            res.sum.is_none() == res.max.is_none(),
-            "Exactly one of SUM(value) and MAX(id) is null. This should not happen."
-        );
+        rows.into_iter()
+            .map(|row| {
+                let fees = UnaggregatedReceipts {
+                    last_id: row.max.try_into()?,
+                    value: horizon::to_u128(&row.sum)?,
+                    counter: row.count.try_into()?,
+                };
+                Ok((Address::from_str(&row.signer_address)?, fees))
+            })
+            .collect()
+    }
 
-        Ok(UnaggregatedReceipts {
-            last_id: res.max.unwrap_or(0).try_into()?,
-            value: res
-                .sum
-                .unwrap_or(BigDecimal::from(0))
-                .to_string()
-                .parse::<u128>()?,
-            counter: res
-                .count
-                .unwrap_or(0)
-                .to_u64()
-                .expect("default value exists, this shouldn't be empty"),
-        })
+    /// Splits the unaggregated fees between the signers of the sender. Must be called after
+    /// `unaggregated_fees` was recalculated, so both use the same receipts.
+    async fn calculate_fees_by_signer(&self) -> Result<HashMap<Address, u128>> {
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let fees_by_signer = self
+            .fees_by_signer_until_last_id(
+                &self.pgpool,
+                &signers,
+                self.unaggregated_fees.last_id as i64,
+            )
+            .await?;
+        Ok(fees_by_signer
+            .into_iter()
+            .filter(|(_, fees)| fees.value > 0)
+            .map(|(signer, fees)| (signer, fees.value))
+            .collect())
+    }
+
+    async fn update_fees_by_signer(&mut self) -> Result<()> {
+        let fees_by_signer = self.calculate_fees_by_signer().await?;
+        self.set_fees_by_signer(fees_by_signer);
+        Ok(())
     }
 
+    fn set_fees_by_signer(&mut self, fees_by_signer: HashMap<Address, u128>) {
+        let sender = self.sender.to_string();
+        let allocation = self.allocation_id.to_string();
+        for signer in self.unaggregated_fees_by_signer.keys() {
+            if !fees_by_signer.contains_key(signer) {
+                self.metrics.remove(
+                    &UNAGGREGATED_FEES_BY_SIGNER,
+                    &[&sender, &allocation, &signer.to_string()],
+                );
+            }
+        }
+        for (signer, fees) in &fees_by_signer {
+            self.metrics
+                .with_label_values(
+                    &UNAGGREGATED_FEES_BY_SIGNER,
+                    &[&sender, &allocation, &signer.to_string()],
+                )
+                .set(*fees as f64);
+        }
+        self.unaggregated_fees_by_signer = fees_by_signer;
+    }
+
+    /// Scanned on start only, from the read replica if it's in sync.
     async fn calculate_invalid_receipts_fee(&self) -> Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_invalid_receipts_fee()");
         let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
 
         // TODO: Get `rav.timestamp_ns` from the TAP Manager's RAV storage adapter instead?
-        let res = sqlx::query!(
-            r#"
+        let res = database::acquire(&database::read_pool(&self.pgpool), Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
             SELECT
                 MAX(id),
                 SUM(value),
@@ -425,11 +967,12 @@ impl SenderAllocationState {
                 allocation_id = $1
                 AND signer_address IN (SELECT unnest($2::text[]))
             "#,
-            self.allocation_id.encode_hex(),
-            &signers
-        )
-        .fetch_one(&self.pgpool)
-        .await?;
+                    self.allocation_id.encode_hex(),
+                    &signers
+                )
+                .fetch_one(conn)
+            })
+            .await?;
 
         ensure!(
             res.sum.is_none() == res.max.is_none(),
@@ -451,23 +994,151 @@ impl SenderAllocationState {
         })
     }
 
+    /// Requests a RAV for each format with unaggregated fees.
+    async fn request_ravs(&mut self) -> Result<()> {
+        let legacy_result = if self.unaggregated_fees.value > 0 {
+            self.request_rav().await
+        } else {
+            Ok(())
+        };
+        let horizon_result = if self
+            .horizon
+            .as_ref()
+            .is_some_and(|horizon| horizon.unaggregated_fees.value > 0)
+        {
+            self.request_horizon_rav().await
+        } else {
+            Ok(())
+        };
+        // The `SenderAccount` only updates its fees on success, one of the requests went through.
+        if legacy_result.is_ok() != horizon_result.is_ok() {
+            self.sender_account_ref
+                .cast(SenderAccountMessage::UpdateReceiptFees(
+                    self.allocation_id,
+                    ReceiptFees::UpdateValue(self.total_unaggregated_fees()),
+                ))?;
+        }
+        legacy_result.and(horizon_result)
+    }
+
+    async fn request_horizon_rav(&mut self) -> Result<()> {
+        let Some(horizon) = &mut self.horizon else {
+            return Ok(());
+        };
+        let invalid_receipts_fees = horizon.invalid_receipts_fees.value;
+        let previous_rav_value = horizon.rav_value();
+        let result = horizon.request_rav(&self.sender_aggregator).await;
+        let result = match result {
+            Ok(ravs) => {
+                for rav in &ravs {
+                    info!(
+                        event = event::RAV_RESPONSE_RECEIVED,
+                        value_aggregate = %Money(rav.message.valueAggregate),
+                        data_service = %rav.message.dataService,
+                        "Horizon RAV received from the sender's TAP aggregator."
+                    );
+                    exemplars::inc(&self.metrics.with_label_values(
+                        &RAVS_CREATED,
+                        &[&self.sender.to_string(), &self.allocation_id.to_string()],
+                    ));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    event = event::RAV_REQUEST_FAILED,
+                    error = %e,
+                    "Horizon RAV request failed."
+                );
+                exemplars::inc(&self.metrics.with_label_values(
+                    &RAVS_FAILED,
+                    &[&self.sender.to_string(), &self.allocation_id.to_string()],
+                ));
+                Err(e)
+            }
+        };
+        // The RAVs of some data services may be stored before a failure.
+        let rav_value = horizon.rav_value();
+        if rav_value != previous_rav_value {
+            self.deployment_fees.record_rav(
+                self.sender,
+                self.allocation_id,
+                previous_rav_value,
+                rav_value,
+            );
+            self.sender_account_ref
+                .cast(SenderAccountMessage::UpdateHorizonRav(
+                    self.allocation_id,
+                    rav_value,
+                ))?;
+        }
+        if rav_value != previous_rav_value || matches!(result, Err(RavError::AllReceiptsInvalid)) {
+            horizon.unaggregated_fees = horizon
+                .calculate_fee_until_last_id(horizon.unaggregated_fees.last_id as i64)
+                .await?;
+        }
+        let result = result.map_err(anyhow::Error::from);
+        if horizon.invalid_receipts_fees.value != invalid_receipts_fees {
+            self.sender_account_ref
+                .cast(SenderAccountMessage::UpdateInvalidReceiptFees(
+                    self.allocation_id,
+                    self.total_invalid_receipts_fees(),
+                ))?;
+        }
+        result
+    }
+
     async fn request_rav(&mut self) -> Result<()> {
-        match self.rav_requester_single().await {
+        let result = self.rav_requester_single().await;
+        let reservation = std::mem::take(&mut self.rav_reservation);
+        if let Some(intent_id) = self.rav_intent.take() {
+            // Left behind on failure, it's reconciled on the next start instead.
+            if let Err(e) = rav_intents::complete(&self.pgpool, intent_id).await {
+                warn!(error = %e, "Failed to delete the RAV request intent.");
+            }
+        }
+        match result {
             Ok(rav) => {
+                // The RAV timestamp identifies it in the aggregator and in the database.
+                Span::current().record("rav_id", rav.message.timestampNs);
+                info!(
+                    event = event::RAV_RESPONSE_RECEIVED,
+                    value_aggregate = %Money(rav.message.valueAggregate),
+                    "RAV received from the sender's TAP aggregator."
+                );
                 self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
+                self.update_fees_by_signer().await?;
+                self.deployment_fees.record_rav(
+                    self.sender,
+                    self.allocation_id,
+                    self.latest_rav
+                        .as_ref()
+                        .map_or(0, |rav| rav.message.valueAggregate),
+                    rav.message.valueAggregate,
+                );
                 self.latest_rav = Some(rav);
-                RAVS_CREATED
-                    .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
-                    .inc();
+                exemplars::inc(&self.metrics.with_label_values(
+                    &RAVS_CREATED,
+                    &[&self.sender.to_string(), &self.allocation_id.to_string()],
+                ));
                 Ok(())
             }
             Err(e) => {
+                warn!(
+                    event = event::RAV_REQUEST_FAILED,
+                    error = %e,
+                    "RAV request failed."
+                );
+                // The receipts are reserved again by the next request
+                self.escrow_adapter.release(reservation);
                 if let RavError::AllReceiptsInvalid = e {
                     self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
+                    self.update_fees_by_signer().await?;
                 }
-                RAVS_FAILED
-                    .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
-                    .inc();
+                exemplars::inc(&self.metrics.with_label_values(
+                    &RAVS_FAILED,
+                    &[&self.sender.to_string(), &self.allocation_id.to_string()],
+                ));
                 Err(e.into())
             }
         }
@@ -489,6 +1160,10 @@ impl SenderAllocationState {
                 Some(self.config.tap.rav_request_receipt_limit),
             )
             .await?;
+        self.rav_reservation = valid_receipts
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.value)
+            .sum();
         match (
             expected_rav,
             valid_receipts.is_empty(),
@@ -516,20 +1191,24 @@ impl SenderAllocationState {
                     .max()
                     .expect("invalid receipts should not be empty");
                 let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
-                sqlx::query!(
-                    r#"
+                database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+                    .await?
+                    .run(|conn| {
+                        sqlx::query!(
+                            r#"
                         DELETE FROM scalar_tap_receipts
                         WHERE timestamp_ns BETWEEN $1 AND $2
                         AND allocation_id = $3
                         AND signer_address IN (SELECT unnest($4::text[]));
                     "#,
-                    BigDecimal::from(min_timestamp),
-                    BigDecimal::from(max_timestamp),
-                    self.allocation_id.encode_hex(),
-                    &signers,
-                )
-                .execute(&self.pgpool)
-                .await?;
+                            BigDecimal::from(min_timestamp),
+                            BigDecimal::from(max_timestamp),
+                            self.allocation_id.encode_hex(),
+                            &signers,
+                        )
+                        .execute(conn)
+                    })
+                    .await?;
                 Err(RavError::AllReceiptsInvalid)
             }
             // When it receives both valid and invalid receipts or just valid
@@ -538,6 +1217,28 @@ impl SenderAllocationState {
                     .into_iter()
                     .map(|r| r.signed_receipt().clone())
                     .collect();
+                let previous_value = previous_rav.as_ref().map(|rav| rav.message.valueAggregate);
+                let intent = RavIntent {
+                    min_timestamp_ns: valid_receipts
+                        .iter()
+                        .map(|receipt| receipt.message.timestamp_ns)
+                        .min()
+                        .unwrap_or_default(),
+                    max_timestamp_ns: expected_rav.timestampNs,
+                    receipt_count: valid_receipts.len() as u64,
+                    expected_value_aggregate: expected_rav.valueAggregate,
+                };
+                self.rav_intent = Some(
+                    rav_intents::record(&self.pgpool, self.allocation_id, self.sender, &intent)
+                        .await
+                        .map_err(|e| anyhow!("Failed to record the RAV request intent: {e}"))?,
+                );
+                debug!(
+                    event = event::RAV_REQUEST_SENT,
+                    receipts = valid_receipts.len(),
+                    "Sending RAV request to the sender's TAP aggregator."
+                );
+                let receipts = valid_receipts.len();
                 let rav_response_time_start = Instant::now();
                 let response: JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = self
                     .sender_aggregator
@@ -549,6 +1250,7 @@ impl SenderAllocationState {
                             previous_rav
                         ),
                     )
+                    .instrument(info_span!("aggregator_call"))
                     .await
                     .inspect_err(|err| {
                         if let jsonrpsee::core::ClientError::RequestTimeout = &err {
@@ -560,10 +1262,13 @@ impl SenderAllocationState {
                         }
                     })?;
 
-                let rav_response_time = rav_response_time_start.elapsed();
-                RAV_RESPONSE_TIME
-                    .with_label_values(&[&self.sender.to_string()])
-                    .observe(rav_response_time.as_secs_f64());
+                observe_rav_response(
+                    &self.sender_metrics,
+                    &self.sender,
+                    rav_response_time_start.elapsed(),
+                    &response,
+                    receipts,
+                );
                 // we only save invalid receipts when we are about to store our rav
                 //
                 // store them before we call remove_obsolete_receipts()
@@ -584,9 +1289,41 @@ impl SenderAllocationState {
                 if let Some(warnings) = response.warnings {
                     warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
                 }
+
+                let signers = self
+                    .escrow_accounts
+                    .value()
+                    .await
+                    .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?
+                    .get_signers_for_sender(&self.sender);
+                if let Err(rejection) = rav_checks::check_value(
+                    previous_value,
+                    expected_rav.valueAggregate,
+                    response.data.message.valueAggregate,
+                    self.config.tap.rav_value_tolerance,
+                )
+                .and_then(|()| {
+                    rav_checks::check_signer(
+                        response.data.recover_signer(&self.domain_separator),
+                        &signers,
+                    )
+                }) {
+                    let rejection = rejection.record(&self.sender_metrics, &self.sender);
+                    self.store_failed_rav(&expected_rav, &response.data, &rejection.to_string())
+                        .await?;
+                    return Err(
+                        anyhow!("RAV rejected, sender could be malicious: {rejection}").into(),
+                    );
+                }
+                // Within the tolerance, the rest of the RAV must still be the expected one.
+                let accepted_rav = ReceiptAggregateVoucher {
+                    valueAggregate: response.data.message.valueAggregate,
+                    ..expected_rav.clone()
+                };
                 match self
                     .tap_manager
-                    .verify_and_store_rav(expected_rav.clone(), response.data.clone())
+                    .verify_and_store_rav(accepted_rav, response.data.clone())
+                    .instrument(info_span!("store_rav"))
                     .await
                 {
                     Ok(_) => {}
@@ -646,17 +1383,21 @@ impl SenderAllocationState {
             allocation_id = %self.allocation_id,
             "Marking rav as last!",
         );
-        let updated_rows = sqlx::query!(
-            r#"
+        let updated_rows = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                         UPDATE scalar_tap_ravs
                         SET last = true
                         WHERE allocation_id = $1 AND sender_address = $2
                     "#,
-            self.allocation_id.encode_hex(),
-            self.sender.encode_hex(),
-        )
-        .execute(&self.pgpool)
-        .await?;
+                    self.allocation_id.encode_hex(),
+                    self.sender.encode_hex(),
+                )
+                .execute(conn)
+            })
+            .await?;
 
         match updated_rows.rows_affected() {
             // in case no rav was marked as final
@@ -676,6 +1417,8 @@ impl SenderAllocationState {
         }
     }
 
+    /// Stores the receipts in the invalid receipts table, and releases the escrow reserved for
+    /// them.
     async fn store_invalid_receipts(
         &mut self,
         receipts: &[ReceiptWithState<Failed>],
@@ -688,6 +1431,8 @@ impl SenderAllocationState {
         let mut nounces = Vec::with_capacity(reciepts_len);
         let mut values = Vec::with_capacity(reciepts_len);
         let mut error_logs = Vec::with_capacity(reciepts_len);
+        let mut error_codes = Vec::with_capacity(reciepts_len);
+        let mut reserved = 0u128;
 
         for received_receipt in receipts.iter() {
             let receipt = received_receipt.signed_receipt();
@@ -712,40 +1457,62 @@ impl SenderAllocationState {
             timestamps.push(BigDecimal::from(receipt.message.timestamp_ns));
             nounces.push(BigDecimal::from(receipt.message.nonce));
             values.push(BigDecimal::from(BigInt::from(receipt.message.value)));
+            let error_code = RejectionCode::classify(&receipt_error);
+            self.sender_metrics
+                .with_label_values(
+                    &INVALID_RECEIPTS,
+                    &[&self.sender.to_string(), error_code.as_str()],
+                )
+                .inc();
+            if error_code != RejectionCode::InsufficientBalance {
+                reserved = reserved.saturating_add(receipt.message.value);
+            }
             error_logs.push(receipt_error);
+            error_codes.push(error_code.as_str().to_string());
         }
-        sqlx::query!(
-            r#"INSERT INTO scalar_tap_receipts_invalid (
+        database::acquire(&self.pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"INSERT INTO scalar_tap_receipts_invalid (
                 signer_address,
                 signature,
                 allocation_id,
                 timestamp_ns,
                 nonce,
                 value,
-                error_log
-            ) SELECT * FROM UNNEST(
+                error_log,
+                error_code,
+                fee_token
+            ) SELECT *, $9 FROM UNNEST(
                 $1::CHAR(40)[],
                 $2::BYTEA[],
                 $3::CHAR(40)[],
                 $4::NUMERIC(20)[],
                 $5::NUMERIC(20)[],
                 $6::NUMERIC(40)[],
-                $7::TEXT[]
+                $7::TEXT[],
+                $8::VARCHAR(32)[]
             )"#,
-            &reciepts_signers,
-            &encoded_signatures,
-            &allocation_ids,
-            &timestamps,
-            &nounces,
-            &values,
-            &error_logs
-        )
-        .execute(&self.pgpool)
-        .await
-        .map_err(|e| {
-            error!("Failed to store invalid receipt: {}", e);
-            anyhow!(e)
-        })?;
+                    &reciepts_signers,
+                    &encoded_signatures,
+                    &allocation_ids,
+                    &timestamps,
+                    &nounces,
+                    &values,
+                    &error_logs,
+                    &error_codes,
+                    FeeToken::Grt.symbol(),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to store invalid receipt: {}", e);
+                anyhow!(e)
+            })?;
+        // Only the receipts failing for lack of escrow have none reserved
+        self.escrow_adapter.release(reserved);
 
         let fees = receipts
             .iter()
@@ -769,7 +1536,7 @@ impl SenderAllocationState {
         self.sender_account_ref
             .cast(SenderAccountMessage::UpdateInvalidReceiptFees(
                 self.allocation_id,
-                self.invalid_receipts_fees.clone(),
+                self.total_invalid_receipts_fees(),
             ))?;
 
         Ok(())
@@ -781,8 +1548,13 @@ impl SenderAllocationState {
         rav: &EIP712SignedMessage<ReceiptAggregateVoucher>,
         reason: &str,
     ) -> Result<()> {
-        sqlx::query!(
-            r#"
+        let expected_rav = serde_json::to_value(expected_rav)?;
+        let rav = serde_json::to_value(rav)?;
+        database::acquire(&self.pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                 INSERT INTO scalar_tap_rav_requests_failed (
                     allocation_id,
                     sender_address,
@@ -792,15 +1564,16 @@ impl SenderAllocationState {
                 )
                 VALUES ($1, $2, $3, $4, $5)
             "#,
-            self.allocation_id.encode_hex(),
-            self.sender.encode_hex(),
-            serde_json::to_value(expected_rav)?,
-            serde_json::to_value(rav)?,
-            reason
-        )
-        .execute(&self.pgpool)
-        .await
-        .map_err(|e| anyhow!("Failed to store failed RAV: {:?}", e))?;
+                    self.allocation_id.encode_hex(),
+                    self.sender.encode_hex(),
+                    expected_rav,
+                    rav,
+                    reason
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to store failed RAV: {:?}", e))?;
 
         Ok(())
     }
@@ -813,16 +1586,21 @@ pub mod tests {
     };
     use crate::{
         agent::{
+            aggregator_client,
+            deployment_fees::DeploymentFees,
             sender_account::{ReceiptFees, SenderAccountMessage},
             sender_accounts_manager::NewReceiptNotification,
+            startup_scans::StartupScans,
             unaggregated_receipts::UnaggregatedReceipts,
         },
-        config,
+        config::{self, AllocationEvent, DeniedSenderReceipts},
+        logging::CorrelationId,
+        metrics::series::SeriesOwner,
         tap::{
             escrow_adapter::EscrowAdapter,
             test_utils::{
                 create_rav, create_received_receipt, store_invalid_receipt, store_rav,
-                store_receipt, ALLOCATION_ID_0, INDEXER, SENDER, SIGNER,
+                store_receipt, ALLOCATION_ID_0, INDEXER, SENDER, SENDER_2, SIGNER,
                 TAP_EIP712_DOMAIN_SEPARATOR,
             },
         },
@@ -832,8 +1610,8 @@ pub mod tests {
     use indexer_common::{
         escrow_accounts::EscrowAccounts,
         subgraph_client::{DeploymentDetails, SubgraphClient},
+        tap::rejection::RejectionCode,
     };
-    use jsonrpsee::http_client::HttpClientBuilder;
     use ractor::{
         call, cast, concurrency::JoinHandle, Actor, ActorProcessingErr, ActorRef, ActorStatus,
     };
@@ -843,15 +1621,19 @@ pub mod tests {
     use std::{
         collections::HashMap,
         sync::Arc,
-        time::{SystemTime, UNIX_EPOCH},
+        time::{Duration, SystemTime, UNIX_EPOCH},
     };
     use tap_aggregator::{jsonrpsee_helpers::JsonRpcResponse, server::run_server};
-    use tap_core::receipt::{
-        checks::{Check, CheckError, CheckList, CheckResult},
-        state::Checking,
-        ReceiptWithState,
+    use tap_core::{
+        manager::adapters::EscrowHandler,
+        receipt::{
+            checks::{Check, CheckError, CheckList, CheckResult},
+            state::Checking,
+            ReceiptWithState,
+        },
     };
     use tokio::sync::mpsc;
+    use tracing::Span;
     use wiremock::{
         matchers::{body_string_contains, method},
         Mock, MockServer, Respond, ResponseTemplate,
@@ -946,9 +1728,9 @@ pub mod tests {
             None => create_mock_sender_account().await.1,
         };
 
-        let sender_aggregator = HttpClientBuilder::default()
-            .build(&sender_aggregator_endpoint)
-            .unwrap();
+        let sender_aggregator =
+            aggregator_client::build(&sender_aggregator_endpoint, Duration::from_secs(3), None)
+                .unwrap();
         SenderAllocationArgs {
             config,
             pgpool: pgpool.clone(),
@@ -958,8 +1740,13 @@ pub mod tests {
             escrow_subgraph,
             escrow_adapter,
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            horizon_domain_separator: None,
             sender_account_ref,
             sender_aggregator,
+            sender_denied: false,
+            startup_scans: StartupScans::new(usize::MAX, HashMap::new()),
+            deployment_fees: DeploymentFees::new(Eventual::from_value(HashMap::new())),
+            sender_metrics: Arc::new(SeriesOwner::default()),
         }
     }
 
@@ -1100,6 +1887,9 @@ pub mod tests {
                 allocation_id: *ALLOCATION_ID_0,
                 signer_address: SIGNER.1,
                 timestamp_ns: 0,
+                correlation_id: CorrelationId::new(),
+                version: Default::default(),
+                stages: None,
             })
         )
         .unwrap();
@@ -1112,6 +1902,9 @@ pub mod tests {
                 allocation_id: *ALLOCATION_ID_0,
                 signer_address: SIGNER.1,
                 timestamp_ns: 0,
+                correlation_id: CorrelationId::new(),
+                version: Default::default(),
+                stages: None,
             })
         )
         .unwrap();
@@ -1121,7 +1914,7 @@ pub mod tests {
         // should emit update aggregate fees message to sender account
         let expected_message = SenderAccountMessage::UpdateReceiptFees(
             *ALLOCATION_ID_0,
-            ReceiptFees::NewReceipt(20u128),
+            ReceiptFees::NewReceipts(20u128, 1, CorrelationId::new(), None, Duration::ZERO),
         );
         let startup_load_msg = message_receiver.recv().await.unwrap();
         assert_eq!(
@@ -1139,6 +1932,96 @@ pub mod tests {
         assert_eq!(last_message_emitted, expected_message);
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_quarantine_denied_sender_receipts(pgpool: PgPool) {
+        let (_message_receiver, sender_account, _join_handle) = create_mock_sender_account().await;
+        let mut args = create_sender_allocation_args(
+            pgpool.clone(),
+            DUMMY_URL.to_string(),
+            DUMMY_URL,
+            Some(sender_account),
+        )
+        .await;
+        let mut config = args.config.clone();
+        config.tap.denied_sender_receipts = DeniedSenderReceipts::Quarantine;
+        args.config = Box::leak(Box::new(config));
+        args.sender_denied = true;
+        let (sender_allocation, _join_handle) =
+            SenderAllocation::spawn(None, SenderAllocation, args)
+                .await
+                .unwrap();
+
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, 1, 10);
+        let id = store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        cast!(
+            sender_allocation,
+            SenderAllocationMessage::NewReceipt(NewReceiptNotification {
+                id,
+                value: 10,
+                allocation_id: *ALLOCATION_ID_0,
+                signer_address: SIGNER.1,
+                timestamp_ns: 1,
+                correlation_id: CorrelationId::new(),
+                version: Default::default(),
+                stages: None,
+            })
+        )
+        .unwrap();
+
+        // The receipt is set aside and not counted
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(unaggregated_fees.value, 0);
+
+        let receipts =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(receipts, 0);
+        let quarantined = sqlx::query_scalar!("SELECT id FROM scalar_tap_receipts_quarantined")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(quarantined, vec![id as i64]);
+
+        // Once the sender is allowed, its receipts are counted again
+        cast!(
+            sender_allocation,
+            SenderAllocationMessage::SenderDenied(false)
+        )
+        .unwrap();
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 2, 2, 20);
+        let id = store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        cast!(
+            sender_allocation,
+            SenderAllocationMessage::NewReceipt(NewReceiptNotification {
+                id,
+                value: 20,
+                allocation_id: *ALLOCATION_ID_0,
+                signer_address: SIGNER.1,
+                timestamp_ns: 2,
+                correlation_id: CorrelationId::new(),
+                version: Default::default(),
+                stages: None,
+            })
+        )
+        .unwrap();
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(unaggregated_fees.value, 20);
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_trigger_rav_request(pgpool: PgPool) {
         // Start a TAP aggregator server.
@@ -1196,7 +2079,10 @@ pub mod tests {
 
         // Trigger a RAV request manually and wait for updated fees.
         sender_allocation
-            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .cast(SenderAllocationMessage::TriggerRAVRequest(
+                CorrelationId::new(),
+                Span::none(),
+            ))
             .unwrap();
 
         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
@@ -1387,6 +2273,31 @@ pub mod tests {
         assert_eq!(total_unaggregated_fees.value, 45u128);
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn should_return_unaggregated_fees_by_signer(pgpool: PgPool) {
+        let args =
+            create_sender_allocation_args(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None)
+                .await;
+        let mut state = SenderAllocationState::new(args).await.unwrap();
+
+        // Add receipts from the sender's signer, and from a signer of another sender.
+        for i in 1..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SENDER_2.0, 10, 10, 100);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        state.unaggregated_fees = state.initialize_unaggregated_receipts().await.unwrap();
+        let fees_by_signer = state.calculate_fees_by_signer().await.unwrap();
+
+        assert_eq!(fees_by_signer, HashMap::from([(SIGNER.1, 45u128)]));
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn should_calculate_invalid_receipts_fee(pgpool: PgPool) {
         let args =
@@ -1466,7 +2377,9 @@ pub mod tests {
         #[async_trait::async_trait]
         impl Check for FailingCheck {
             async fn check(&self, _receipt: &ReceiptWithState<Checking>) -> CheckResult {
-                Err(CheckError::Failed(anyhow::anyhow!("Failing check")))
+                Err(CheckError::Failed(
+                    RejectionCode::ValueCap.reject("Failing check"),
+                ))
             }
         }
 
@@ -1495,11 +2408,27 @@ pub mod tests {
             .collect::<Vec<_>>();
         let failing_receipts: Vec<_> = join_all(failing_receipts).await;
 
+        state
+            .escrow_adapter
+            .subtract_escrow(SIGNER.1, 10)
+            .await
+            .unwrap();
+
         // store the failing receipts
         let result = state.store_invalid_receipts(&failing_receipts).await;
 
         // we just store a few and make sure it doesn't fail
         assert!(result.is_ok());
+        // Their escrow is released
+        assert_eq!(state.escrow_adapter.reserved(), 7);
+
+        let error_codes = sqlx::query_scalar!(
+            r#"SELECT error_code AS "error_code!" FROM scalar_tap_receipts_invalid"#
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(error_codes, vec!["value_cap"; 2]);
     }
 
     #[sqlx::test(migrations = "../migrations")]
@@ -1545,7 +2474,10 @@ pub mod tests {
         // Trigger a RAV request manually and wait for updated fees.
         // this should fail because there's no receipt with valid timestamp
         sender_allocation
-            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .cast(SenderAllocationMessage::TriggerRAVRequest(
+                CorrelationId::new(),
+                Span::none(),
+            ))
             .unwrap();
 
         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
@@ -1645,7 +2577,10 @@ pub mod tests {
         // Trigger a RAV request manually and wait for updated fees.
         // this should fail because there's no receipt with valid timestamp
         sender_allocation
-            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .cast(SenderAllocationMessage::TriggerRAVRequest(
+                CorrelationId::new(),
+                Span::none(),
+            ))
             .unwrap();
 
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
@@ -0,0 +1,1117 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Horizon (TAP v2) fee tracking and RAV requests of a `SenderAllocation`.
+//!
+//! tap_core doesn't handle Horizon receipts yet, so the receipt checks and the RAV verification
+//! are done here. A receipt is valid if it's for the collection of the allocation, paid by the
+//! sender to this indexer, and signed by one of the sender's signers. It must also keep the RAVs
+//! within the sender's Horizon escrow balance for the collector, and the receipts aren't checked
+//! while the balances are unknown. A RAV is valid if it passes the checks of `rav_checks`, and
+//! otherwise matches the aggregate of the receipts.
+//!
+//! A RAV is for a single data service, and so are the RAVs stored in `tap_horizon_ravs`: the
+//! receipts of each data service of the collection are aggregated, stored and pruned apart, against
+//! the latest RAV of their data service.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    hex::ToHexExt,
+    primitives::{Address, Bytes, FixedBytes},
+    signers::Signature,
+};
+use anyhow::{anyhow, ensure, Result};
+use bigdecimal::{
+    num_bigint::{BigInt, ToBigInt},
+    ToPrimitive,
+};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::fees::FeeToken;
+use indexer_common::tap::rejection::RejectionCode;
+use jsonrpsee::{core::client::ClientT, rpc_params};
+use sqlx::{
+    types::{chrono, BigDecimal},
+    Connection, PgPool,
+};
+use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use tracing::{debug, info_span, warn, Instrument};
+
+use super::{observe_rav_response, rav_checks, RavError, INVALID_RECEIPTS};
+use crate::{
+    agent::{aggregator_client::AggregatorClient, unaggregated_receipts::UnaggregatedReceipts},
+    config,
+    database::{self, Subsystem},
+    logging::event,
+    metrics::series::SeriesOwner,
+    tap::{
+        escrow_adapter::EscrowAdapter,
+        horizon::{self, Receipt, ReceiptAggregateVoucher, SignedRav, SignedReceipt},
+        signers_trimmed,
+    },
+};
+
+/// JSON-RPC method of the sender's TAP aggregator for Horizon receipts.
+const AGGREGATE_RECEIPTS_METHOD: &str = "aggregate_receipts_v2";
+const AGGREGATOR_API_VERSION: &str = "0.0";
+
+/// Receipt as stored by indexer-service.
+struct StoredReceipt {
+    id: i64,
+    signer_address: String,
+    receipt: SignedReceipt,
+}
+
+pub struct HorizonAllocation {
+    pub unaggregated_fees: UnaggregatedReceipts,
+    pub invalid_receipts_fees: UnaggregatedReceipts,
+    /// The latest RAV of each data service.
+    pub latest_ravs: HashMap<Address, SignedRav>,
+
+    pgpool: PgPool,
+    collection_id: FixedBytes<32>,
+    sender: Address,
+    config: &'static config::Config,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    escrow_adapter: EscrowAdapter,
+    domain_separator: Eip712Domain,
+    /// The series of the sender, see [`crate::metrics::series`].
+    sender_metrics: Arc<SeriesOwner>,
+}
+
+impl HorizonAllocation {
+    pub async fn new(
+        config: &'static config::Config,
+        pgpool: PgPool,
+        allocation_id: Address,
+        sender: Address,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        escrow_adapter: EscrowAdapter,
+        domain_separator: Eip712Domain,
+        sender_metrics: Arc<SeriesOwner>,
+    ) -> Result<Self> {
+        let mut allocation = Self {
+            unaggregated_fees: UnaggregatedReceipts::default(),
+            invalid_receipts_fees: UnaggregatedReceipts::default(),
+            latest_ravs: HashMap::new(),
+            pgpool,
+            collection_id: horizon::collection_id(allocation_id),
+            sender,
+            config,
+            escrow_accounts,
+            escrow_adapter,
+            domain_separator,
+            sender_metrics,
+        };
+        allocation.latest_ravs = allocation.last_ravs().await?;
+        Ok(allocation)
+    }
+
+    /// The sum of the latest RAVs of the data services.
+    pub fn rav_value(&self) -> u128 {
+        self.latest_ravs
+            .values()
+            .map(|rav| rav.message.valueAggregate)
+            .fold(0, u128::saturating_add)
+    }
+
+    /// The data services and timestamps of the latest RAVs, to bind as arrays.
+    fn rav_timestamps(&self) -> (Vec<String>, Vec<BigDecimal>) {
+        self.latest_ravs
+            .iter()
+            .map(|(data_service, rav)| {
+                (
+                    data_service.encode_hex(),
+                    BigDecimal::from(rav.message.timestampNs),
+                )
+            })
+            .unzip()
+    }
+
+    fn indexer(&self) -> Address {
+        self.config.ethereum.indexer_address
+    }
+
+    async fn last_ravs(&self) -> Result<HashMap<Address, SignedRav>> {
+        let rows = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT signature, data_service, timestamp_ns, value_aggregate, metadata
+                FROM tap_horizon_ravs
+                WHERE collection_id = $1 AND payer = $2 AND service_provider = $3
+            "#,
+                    self.collection_id.encode_hex(),
+                    self.sender.encode_hex(),
+                    self.indexer().encode_hex(),
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|row| -> Result<(Address, SignedRav)> {
+                let data_service = Address::from_str(&row.data_service)?;
+                let rav = SignedRav {
+                    message: ReceiptAggregateVoucher {
+                        collectionId: self.collection_id,
+                        payer: self.sender,
+                        serviceProvider: self.indexer(),
+                        dataService: data_service,
+                        timestampNs: to_u64(&row.timestamp_ns)?,
+                        valueAggregate: to_u128(&row.value_aggregate)?,
+                        metadata: Bytes::from(row.metadata),
+                    },
+                    signature: Signature::try_from(row.signature.as_slice())?,
+                };
+                Ok((data_service, rav))
+            })
+            .collect()
+    }
+
+    /// Scans all the receipts on start, from the read replica if it's in sync.
+    pub async fn initialize_unaggregated_receipts(&self) -> Result<UnaggregatedReceipts> {
+        self.fee_until_last_id(&database::read_pool(&self.pgpool), i64::MAX)
+            .await
+    }
+
+    /// Same as `SenderAllocationState::calculate_fee_until_last_id`, for the Horizon receipts.
+    pub async fn calculate_fee_until_last_id(&self, last_id: i64) -> Result<UnaggregatedReceipts> {
+        self.fee_until_last_id(&self.pgpool, last_id).await
+    }
+
+    async fn fee_until_last_id(
+        &self,
+        pgpool: &PgPool,
+        last_id: i64,
+    ) -> Result<UnaggregatedReceipts> {
+        self.remove_obsolete_receipts().await?;
+
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let (data_services, rav_timestamps) = self.rav_timestamps();
+        let row = database::acquire(pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+            SELECT
+                MAX(receipts.id) AS max,
+                SUM(receipts.value) AS sum,
+                COUNT(*) AS "count!"
+            FROM
+                tap_horizon_receipts receipts
+                LEFT JOIN UNNEST($4::text[], $5::numeric[]) AS ravs(data_service, timestamp_ns)
+                    ON receipts.data_service = ravs.data_service
+            WHERE
+                receipts.collection_id = $1
+                AND receipts.id <= $2
+                AND receipts.signer_address IN (SELECT unnest($3::text[]))
+                AND receipts.timestamp_ns > COALESCE(ravs.timestamp_ns, 0)
+            "#,
+                    self.collection_id.encode_hex(),
+                    last_id,
+                    &signers,
+                    &data_services,
+                    &rav_timestamps,
+                )
+                .fetch_one(conn)
+            })
+            .await?;
+        to_unaggregated_receipts(row.max, row.sum, row.count)
+    }
+
+    /// Scanned on start only, from the read replica if it's in sync.
+    pub async fn calculate_invalid_receipts_fee(&self) -> Result<UnaggregatedReceipts> {
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let row = database::acquire(&database::read_pool(&self.pgpool), Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+            SELECT
+                MAX(id) AS max,
+                SUM(value) AS sum,
+                COUNT(*) AS "count!"
+            FROM
+                tap_horizon_receipts_invalid
+            WHERE
+                collection_id = $1
+                AND signer_address IN (SELECT unnest($2::text[]))
+            "#,
+                    self.collection_id.encode_hex(),
+                    &signers,
+                )
+                .fetch_one(conn)
+            })
+            .await?;
+        to_unaggregated_receipts(row.max, row.sum, row.count)
+    }
+
+    /// Requests a RAV for the Horizon receipts outside of the timestamp buffer of each data
+    /// service, and stores them. The RAVs stored before a failed request are kept in
+    /// `latest_ravs`.
+    pub async fn request_rav(
+        &mut self,
+        sender_aggregator: &AggregatorClient,
+    ) -> Result<Vec<SignedRav>, RavError> {
+        let receipts = self.fetch_receipts().await?;
+        let (valid_receipts, invalid_receipts) = self.check_receipts(receipts).await?;
+        if !invalid_receipts.is_empty() {
+            warn!(
+                "Found {} invalid Horizon receipts for allocation {} and sender {}.",
+                invalid_receipts.len(),
+                horizon::allocation_id(self.collection_id),
+                self.sender
+            );
+            self.store_invalid_receipts(&invalid_receipts).await?;
+        }
+        if valid_receipts.is_empty() {
+            if invalid_receipts.is_empty() {
+                return Err(anyhow!(
+                    "It looks like there are no valid Horizon receipts for the RAV request. \
+                    This may happen if no receipts were found outside the \
+                    `rav_request_timestamp_buffer_ms`."
+                )
+                .into());
+            }
+            return Err(RavError::AllReceiptsInvalid);
+        }
+
+        let mut receipts_by_data_service = BTreeMap::<Address, Vec<SignedReceipt>>::new();
+        for stored in valid_receipts {
+            receipts_by_data_service
+                .entry(stored.receipt.message.data_service)
+                .or_default()
+                .push(stored.receipt);
+        }
+        let mut ravs = Vec::with_capacity(receipts_by_data_service.len());
+        for (data_service, receipts) in receipts_by_data_service {
+            ravs.push(
+                self.request_data_service_rav(sender_aggregator, data_service, receipts)
+                    .await?,
+            );
+        }
+        Ok(ravs)
+    }
+
+    async fn request_data_service_rav(
+        &mut self,
+        sender_aggregator: &AggregatorClient,
+        data_service: Address,
+        valid_receipts: Vec<SignedReceipt>,
+    ) -> Result<SignedRav, RavError> {
+        let previous_rav = self.latest_ravs.get(&data_service);
+        let expected_rav = self.expected_rav(previous_rav, &valid_receipts)?;
+        debug!(
+            event = event::RAV_REQUEST_SENT,
+            receipts = valid_receipts.len(),
+            %data_service,
+            "Sending Horizon RAV request to the sender's TAP aggregator."
+        );
+        let rav_response_time_start = Instant::now();
+        let response: JsonRpcResponse<SignedRav> = sender_aggregator
+            .request(
+                AGGREGATE_RECEIPTS_METHOD,
+                rpc_params!(AGGREGATOR_API_VERSION, &valid_receipts, previous_rav),
+            )
+            .instrument(info_span!("aggregator_call"))
+            .await?;
+        observe_rav_response(
+            &self.sender_metrics,
+            &self.sender,
+            rav_response_time_start.elapsed(),
+            &response,
+            valid_receipts.len(),
+        );
+        if let Some(warnings) = response.warnings {
+            warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
+        }
+
+        let rav = response.data;
+        if let Err(e) = self.verify_rav(&expected_rav, &rav).await {
+            self.store_failed_rav(&expected_rav, &rav, &e.to_string())
+                .await?;
+            return Err(anyhow!("Invalid Horizon RAV, sender could be malicious: {e}").into());
+        }
+        self.store_rav(&rav)
+            .instrument(info_span!("store_rav"))
+            .await?;
+        self.latest_ravs.insert(data_service, rav.clone());
+        Ok(rav)
+    }
+
+    pub async fn mark_rav_last(&self) -> Result<()> {
+        let updated_rows = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        UPDATE tap_horizon_ravs
+                        SET last = true
+                        WHERE collection_id = $1 AND payer = $2 AND service_provider = $3
+                    "#,
+                    self.collection_id.encode_hex(),
+                    self.sender.encode_hex(),
+                    self.indexer().encode_hex(),
+                )
+                .execute(conn)
+            })
+            .await?;
+        if updated_rows.rows_affected() == 0 {
+            warn!(
+                "No Horizon RAVs were updated as last for allocation {} and sender {}.",
+                horizon::allocation_id(self.collection_id),
+                self.sender
+            );
+        }
+        Ok(())
+    }
+
+    /// Receipts after the latest RAV of their data service and outside of the timestamp buffer,
+    /// oldest first.
+    async fn fetch_receipts(&self) -> Result<Vec<StoredReceipt>> {
+        let max_timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_nanos()
+            .saturating_sub(self.config.tap.rav_request_timestamp_buffer_ms as u128 * 1_000_000)
+            as u64;
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let (data_services, rav_timestamps) = self.rav_timestamps();
+        let rows = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                SELECT
+                    receipts.id, receipts.signer_address, receipts.signature, receipts.payer,
+                    receipts.data_service, receipts.service_provider, receipts.timestamp_ns,
+                    receipts.nonce, receipts.value
+                FROM tap_horizon_receipts receipts
+                    LEFT JOIN UNNEST($3::text[], $4::numeric[]) AS ravs(data_service, timestamp_ns)
+                        ON receipts.data_service = ravs.data_service
+                WHERE
+                    receipts.collection_id = $1
+                    AND receipts.signer_address IN (SELECT unnest($2::text[]))
+                    AND receipts.timestamp_ns > COALESCE(ravs.timestamp_ns, 0)
+                    AND receipts.timestamp_ns <= $5
+                ORDER BY receipts.timestamp_ns ASC
+                LIMIT $6
+            "#,
+                    self.collection_id.encode_hex(),
+                    &signers,
+                    &data_services,
+                    &rav_timestamps,
+                    BigDecimal::from(max_timestamp_ns),
+                    self.config.tap.rav_request_receipt_limit as i64,
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|row| -> Result<StoredReceipt> {
+                Ok(StoredReceipt {
+                    id: row.id,
+                    signer_address: row.signer_address,
+                    receipt: SignedReceipt {
+                        message: Receipt {
+                            collection_id: self.collection_id,
+                            payer: Address::from_str(&row.payer)?,
+                            data_service: Address::from_str(&row.data_service)?,
+                            service_provider: Address::from_str(&row.service_provider)?,
+                            timestamp_ns: to_u64(&row.timestamp_ns)?,
+                            nonce: to_u64(&row.nonce)?,
+                            value: to_u128(&row.value)?,
+                        },
+                        signature: Signature::try_from(row.signature.as_slice())?,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Splits the receipts between the valid ones, and the invalid ones with the reason.
+    async fn check_receipts(
+        &self,
+        receipts: Vec<StoredReceipt>,
+    ) -> Result<(Vec<StoredReceipt>, Vec<(StoredReceipt, String)>)> {
+        let escrow_accounts = self
+            .escrow_accounts
+            .value()
+            .await
+            .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?;
+        let horizon_balance = self.escrow_adapter.get_horizon_balance().await?;
+
+        let mut signatures = HashSet::new();
+        // The escrow balance covers the RAVs of all the data services.
+        let mut value_aggregate = self.rav_value();
+        let mut valid_receipts = Vec::new();
+        let mut invalid_receipts = Vec::new();
+        for stored in receipts {
+            match self.check_receipt(
+                &escrow_accounts,
+                &stored.receipt,
+                &mut signatures,
+                horizon_balance,
+                &mut value_aggregate,
+            ) {
+                Ok(()) => valid_receipts.push(stored),
+                Err(e) => invalid_receipts.push((stored, e.to_string())),
+            }
+        }
+        Ok((valid_receipts, invalid_receipts))
+    }
+
+    fn check_receipt(
+        &self,
+        escrow_accounts: &EscrowAccounts,
+        receipt: &SignedReceipt,
+        signatures: &mut HashSet<[u8; 65]>,
+        horizon_balance: Option<u128>,
+        value_aggregate: &mut u128,
+    ) -> Result<()> {
+        let message = &receipt.message;
+        ensure!(
+            message.payer == self.sender,
+            "Receipt payer {} is not the sender {}",
+            message.payer,
+            self.sender
+        );
+        ensure!(
+            message.service_provider == self.indexer(),
+            RejectionCode::AllocationMismatch.reject(format!(
+                "Receipt service provider {} is not the indexer {}",
+                message.service_provider,
+                self.indexer()
+            ))
+        );
+        let signer = receipt
+            .recover_signer(&self.domain_separator)
+            .map_err(|e| RejectionCode::BadSignature.reject(e))?;
+        ensure!(
+            escrow_accounts.get_sender_for_signer(&signer).ok() == Some(self.sender),
+            RejectionCode::UnknownSigner.reject(format!(
+                "Receipt signer {} is not a signer of the sender {}",
+                signer, self.sender
+            ))
+        );
+        ensure!(
+            signatures.insert(receipt.signature.as_bytes()),
+            RejectionCode::Duplicate.reject("Duplicate receipt signature")
+        );
+        let new_value_aggregate = value_aggregate
+            .checked_add(message.value)
+            .ok_or_else(|| anyhow!("Value aggregate overflow"))?;
+        if let Some(balance) = horizon_balance {
+            ensure!(
+                new_value_aggregate <= balance,
+                RejectionCode::InsufficientBalance.reject(format!(
+                    "Receipt brings the value aggregate to {}, over the Horizon escrow balance {}",
+                    new_value_aggregate, balance
+                ))
+            );
+        }
+        *value_aggregate = new_value_aggregate;
+        Ok(())
+    }
+
+    /// The RAV aggregating the `receipts` of a single data service into its `previous_rav`.
+    fn expected_rav(
+        &self,
+        previous_rav: Option<&SignedRav>,
+        receipts: &[SignedReceipt],
+    ) -> Result<ReceiptAggregateVoucher> {
+        let previous_rav = previous_rav.map(|rav| &rav.message);
+        let value_aggregate = receipts
+            .iter()
+            .try_fold(
+                previous_rav.map_or(0, |rav| rav.valueAggregate),
+                |total: u128, receipt| total.checked_add(receipt.message.value),
+            )
+            .ok_or_else(|| anyhow!("Overflow while aggregating the Horizon receipts"))?;
+        Ok(ReceiptAggregateVoucher {
+            collectionId: self.collection_id,
+            payer: self.sender,
+            serviceProvider: self.indexer(),
+            dataService: receipts[0].message.data_service,
+            timestampNs: receipts
+                .iter()
+                .map(|receipt| receipt.message.timestamp_ns)
+                .max()
+                .expect("receipts should not be empty"),
+            valueAggregate: value_aggregate,
+            metadata: previous_rav
+                .map(|rav| rav.metadata.clone())
+                .unwrap_or_default(),
+        })
+    }
+
+    async fn verify_rav(
+        &self,
+        expected_rav: &ReceiptAggregateVoucher,
+        rav: &SignedRav,
+    ) -> Result<()> {
+        let signers = self
+            .escrow_accounts
+            .value()
+            .await
+            .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?
+            .get_signers_for_sender(&self.sender);
+        if let Err(rejection) = rav_checks::check_value(
+            self.latest_ravs
+                .get(&expected_rav.dataService)
+                .map(|rav| rav.message.valueAggregate),
+            expected_rav.valueAggregate,
+            rav.message.valueAggregate,
+            self.config.tap.rav_value_tolerance,
+        )
+        .and_then(|()| {
+            rav_checks::check_signer(rav.recover_signer(&self.domain_separator), &signers)
+        }) {
+            return Err(rejection.record(&self.sender_metrics, &self.sender).into());
+        }
+        // Within the tolerance, the rest of the RAV must still be the expected one.
+        ensure!(
+            rav.message
+                == ReceiptAggregateVoucher {
+                    valueAggregate: rav.message.valueAggregate,
+                    ..expected_rav.clone()
+                },
+            "Received RAV {:?} does not match the expected RAV {:?}",
+            rav.message,
+            expected_rav
+        );
+        Ok(())
+    }
+
+    /// Stores the RAV, along with the deletion of the receipts it covers if
+    /// `delete_receipts_with_rav` is enabled.
+    async fn store_rav(&self, rav: &SignedRav) -> Result<()> {
+        let delete_receipts = self.config.tap.delete_receipts_with_rav;
+        let signers = if delete_receipts {
+            signers_trimmed(&self.escrow_accounts, self.sender).await?
+        } else {
+            Vec::new()
+        };
+        database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await?
+            .run(|conn| async move {
+                let mut tx = conn.begin().await?;
+                sqlx::query!(
+                    r#"
+                INSERT INTO tap_horizon_ravs (
+                    signature,
+                    collection_id,
+                    payer,
+                    data_service,
+                    service_provider,
+                    timestamp_ns,
+                    value_aggregate,
+                    metadata,
+                    fee_token,
+                    created_at,
+                    updated_at
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $10, $9, $9)
+                ON CONFLICT (collection_id, payer, service_provider, data_service)
+                DO UPDATE SET
+                    signature = $1,
+                    timestamp_ns = $6,
+                    value_aggregate = $7,
+                    metadata = $8,
+                    fee_token = $10,
+                    updated_at = $9
+            "#,
+                    rav.signature.as_bytes().to_vec(),
+                    rav.message.collectionId.encode_hex(),
+                    rav.message.payer.encode_hex(),
+                    rav.message.dataService.encode_hex(),
+                    rav.message.serviceProvider.encode_hex(),
+                    BigDecimal::from(rav.message.timestampNs),
+                    BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+                    rav.message.metadata.to_vec(),
+                    chrono::Utc::now(),
+                    FeeToken::Grt.symbol(),
+                )
+                .execute(&mut *tx)
+                .await?;
+                if delete_receipts {
+                    sqlx::query!(
+                        r#"
+                    DELETE FROM tap_horizon_receipts
+                    WHERE collection_id = $1
+                    AND data_service = $2
+                    AND signer_address IN (SELECT unnest($3::text[]))
+                    AND timestamp_ns <= $4
+                "#,
+                        rav.message.collectionId.encode_hex(),
+                        rav.message.dataService.encode_hex(),
+                        &signers,
+                        BigDecimal::from(rav.message.timestampNs),
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the receipts covered by the latest RAV of their data service.
+    async fn remove_obsolete_receipts(&self) -> Result<()> {
+        if self.latest_ravs.is_empty() {
+            return Ok(());
+        }
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let (data_services, rav_timestamps) = self.rav_timestamps();
+        database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                DELETE FROM tap_horizon_receipts receipts
+                USING UNNEST($3::text[], $4::numeric[]) AS ravs(data_service, timestamp_ns)
+                WHERE receipts.collection_id = $1
+                AND receipts.signer_address IN (SELECT unnest($2::text[]))
+                AND receipts.data_service = ravs.data_service
+                AND receipts.timestamp_ns <= ravs.timestamp_ns
+            "#,
+                    self.collection_id.encode_hex(),
+                    &signers,
+                    &data_services,
+                    &rav_timestamps,
+                )
+                .execute(conn)
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the receipts to the invalid receipts table.
+    async fn store_invalid_receipts(&mut self, receipts: &[(StoredReceipt, String)]) -> Result<()> {
+        let mut ids = Vec::with_capacity(receipts.len());
+        let mut signers = Vec::with_capacity(receipts.len());
+        let mut signatures = Vec::with_capacity(receipts.len());
+        let mut payers = Vec::with_capacity(receipts.len());
+        let mut data_services = Vec::with_capacity(receipts.len());
+        let mut service_providers = Vec::with_capacity(receipts.len());
+        let mut timestamps = Vec::with_capacity(receipts.len());
+        let mut nonces = Vec::with_capacity(receipts.len());
+        let mut values = Vec::with_capacity(receipts.len());
+        let mut error_logs = Vec::with_capacity(receipts.len());
+        let mut error_codes = Vec::with_capacity(receipts.len());
+        for (stored, error) in receipts {
+            let receipt = &stored.receipt.message;
+            debug!(
+                "Horizon receipt {} for allocation {} failed reason: {}",
+                stored.id,
+                horizon::allocation_id(self.collection_id),
+                error
+            );
+            ids.push(stored.id);
+            signers.push(stored.signer_address.clone());
+            signatures.push(stored.receipt.signature.as_bytes().to_vec());
+            payers.push(receipt.payer.encode_hex());
+            data_services.push(receipt.data_service.encode_hex());
+            service_providers.push(receipt.service_provider.encode_hex());
+            timestamps.push(BigDecimal::from(receipt.timestamp_ns));
+            nonces.push(BigDecimal::from(receipt.nonce));
+            values.push(BigDecimal::from(BigInt::from(receipt.value)));
+            error_logs.push(error.clone());
+            let error_code = RejectionCode::classify(error);
+            self.sender_metrics
+                .with_label_values(
+                    &INVALID_RECEIPTS,
+                    &[&self.sender.to_string(), error_code.as_str()],
+                )
+                .inc();
+            error_codes.push(error_code.as_str().to_string());
+        }
+        let collection_ids = vec![self.collection_id.encode_hex(); receipts.len()];
+
+        let mut connection = database::acquire(&self.pgpool, Subsystem::Analytics).await?;
+        connection
+            .run(|conn| {
+                sqlx::query!(
+                    r#"INSERT INTO tap_horizon_receipts_invalid (
+                signer_address,
+                signature,
+                collection_id,
+                payer,
+                data_service,
+                service_provider,
+                timestamp_ns,
+                nonce,
+                value,
+                error_log,
+                error_code,
+                fee_token
+            ) SELECT *, $12 FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::BYTEA[],
+                $3::CHAR(64)[],
+                $4::CHAR(40)[],
+                $5::CHAR(40)[],
+                $6::CHAR(40)[],
+                $7::NUMERIC(20)[],
+                $8::NUMERIC(20)[],
+                $9::NUMERIC(40)[],
+                $10::TEXT[],
+                $11::VARCHAR(32)[]
+            )"#,
+                    &signers,
+                    &signatures,
+                    &collection_ids,
+                    &payers,
+                    &data_services,
+                    &service_providers,
+                    &timestamps,
+                    &nonces,
+                    &values,
+                    &error_logs,
+                    &error_codes,
+                    FeeToken::Grt.symbol(),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to store invalid Horizon receipts: {e}"))?;
+        connection
+            .run(|conn| {
+                sqlx::query!("DELETE FROM tap_horizon_receipts WHERE id = ANY($1)", &ids)
+                    .execute(conn)
+            })
+            .await?;
+
+        let fees = receipts
+            .iter()
+            .map(|(stored, _)| stored.receipt.message.value)
+            .fold(0u128, u128::saturating_add);
+        self.invalid_receipts_fees.value = self.invalid_receipts_fees.value.saturating_add(fees);
+        self.invalid_receipts_fees.counter += receipts.len() as u64;
+        Ok(())
+    }
+
+    async fn store_failed_rav(
+        &self,
+        expected_rav: &ReceiptAggregateVoucher,
+        rav: &SignedRav,
+        reason: &str,
+    ) -> Result<()> {
+        let expected_rav = serde_json::to_value(expected_rav)?;
+        let rav = serde_json::to_value(rav)?;
+        database::acquire(&self.pgpool, Subsystem::Analytics)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                INSERT INTO tap_horizon_rav_requests_failed (
+                    collection_id,
+                    payer,
+                    expected_rav,
+                    rav_response,
+                    reason
+                )
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+                    self.collection_id.encode_hex(),
+                    self.sender.encode_hex(),
+                    expected_rav,
+                    rav,
+                    reason,
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to store failed Horizon RAV: {:?}", e))?;
+        Ok(())
+    }
+}
+
+fn to_unaggregated_receipts(
+    max: Option<i64>,
+    sum: Option<BigDecimal>,
+    count: i64,
+) -> Result<UnaggregatedReceipts> {
+    ensure!(
+        sum.is_none() == max.is_none(),
+        "Exactly one of SUM(value) and MAX(id) is null. This should not happen."
+    );
+    Ok(UnaggregatedReceipts {
+        last_id: max.unwrap_or(0).try_into()?,
+        value: sum.as_ref().map(to_u128).transpose()?.unwrap_or(0),
+        counter: count.try_into()?,
+    })
+}
+
+pub(crate) fn to_u64(value: &BigDecimal) -> Result<u64> {
+    value
+        .to_u64()
+        .ok_or_else(|| anyhow!("{value} does not fit in a u64"))
+}
+
+pub(crate) fn to_u128(value: &BigDecimal) -> Result<u128> {
+    // BigDecimal::to_u128() uses to_u64() under the hood, see `TapAgentContext::last_rav`.
+    value
+        .to_bigint()
+        .and_then(|v| v.to_u128())
+        .ok_or_else(|| anyhow!("{value} does not fit in a u128"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use alloy::{
+        hex::ToHexExt,
+        primitives::{Address, U256},
+    };
+    use bigdecimal::num_bigint::BigInt;
+    use eventuals::Eventual;
+    use indexer_common::{escrow_accounts::EscrowAccounts, tap::rejection::RejectionCode};
+    use sqlx::{types::BigDecimal, PgPool};
+    use tap_core::signed_message::EIP712SignedMessage;
+
+    use super::HorizonAllocation;
+    use crate::{
+        config,
+        metrics::series::SeriesOwner,
+        tap::{
+            escrow_adapter::EscrowAdapter,
+            horizon::{self, Receipt, ReceiptAggregateVoucher},
+            test_utils::{ALLOCATION_ID_0, INDEXER, SENDER, SENDER_2, SIGNER},
+        },
+    };
+
+    const DATA_SERVICE: Address = Address::repeat_byte(0x33);
+    const DATA_SERVICE_2: Address = Address::repeat_byte(0x44);
+
+    async fn store_receipt(pgpool: &PgPool, payer: Address, nonce: u64, value: u128) {
+        store_data_service_receipt(pgpool, payer, DATA_SERVICE, nonce, value).await;
+    }
+
+    async fn store_data_service_receipt(
+        pgpool: &PgPool,
+        payer: Address,
+        data_service: Address,
+        nonce: u64,
+        value: u128,
+    ) {
+        let receipt = EIP712SignedMessage::new(
+            &horizon::eip712_domain(1, Address::from([0x22u8; 20])),
+            Receipt {
+                collection_id: horizon::collection_id(*ALLOCATION_ID_0),
+                payer,
+                data_service,
+                service_provider: INDEXER.1,
+                timestamp_ns: nonce + 1,
+                nonce,
+                value,
+            },
+            &SIGNER.0,
+        )
+        .unwrap();
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_horizon_receipts (
+                    signer_address, signature, collection_id, payer, data_service,
+                    service_provider, timestamp_ns, nonce, value
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+            SIGNER.1.encode_hex(),
+            receipt.signature.as_bytes().to_vec(),
+            receipt.message.collection_id.encode_hex(),
+            receipt.message.payer.encode_hex(),
+            receipt.message.data_service.encode_hex(),
+            receipt.message.service_provider.encode_hex(),
+            BigDecimal::from(receipt.message.timestamp_ns),
+            BigDecimal::from(receipt.message.nonce),
+            BigDecimal::from(BigInt::from(receipt.message.value)),
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    async fn store_rav(pgpool: &PgPool, data_service: Address, timestamp_ns: u64, value: u128) {
+        let rav = EIP712SignedMessage::new(
+            &horizon::eip712_domain(1, Address::from([0x22u8; 20])),
+            ReceiptAggregateVoucher {
+                collectionId: horizon::collection_id(*ALLOCATION_ID_0),
+                payer: SENDER.1,
+                serviceProvider: INDEXER.1,
+                dataService: data_service,
+                timestampNs: timestamp_ns,
+                valueAggregate: value,
+                metadata: Default::default(),
+            },
+            &SIGNER.0,
+        )
+        .unwrap();
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_horizon_ravs (
+                    signature, collection_id, payer, data_service, service_provider,
+                    timestamp_ns, value_aggregate, metadata
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            rav.signature.as_bytes().to_vec(),
+            rav.message.collectionId.encode_hex(),
+            rav.message.payer.encode_hex(),
+            rav.message.dataService.encode_hex(),
+            rav.message.serviceProvider.encode_hex(),
+            BigDecimal::from(rav.message.timestampNs),
+            BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+            rav.message.metadata.to_vec(),
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    async fn create_horizon_allocation(pgpool: PgPool, horizon_balance: u128) -> HorizonAllocation {
+        let config = Box::leak(Box::new(config::Config {
+            ethereum: config::Ethereum {
+                indexer_address: INDEXER.1,
+            },
+            tap: config::Tap {
+                rav_request_timestamp_buffer_ms: 1,
+                rav_request_receipt_limit: 1000,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let collector = Address::from([0x22u8; 20]);
+        let escrow_accounts = Eventual::from_value(
+            EscrowAccounts::new(
+                HashMap::from([(SENDER.1, U256::from(1000))]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )
+            .with_horizon_balances(HashMap::from([(
+                (SENDER.1, collector),
+                U256::from(horizon_balance),
+            )])),
+        );
+        let escrow_adapter = EscrowAdapter::new(escrow_accounts.clone(), SENDER.1)
+            .with_horizon_collector(Some(collector));
+        HorizonAllocation::new(
+            config,
+            pgpool,
+            *ALLOCATION_ID_0,
+            SENDER.1,
+            escrow_accounts,
+            escrow_adapter,
+            horizon::eip712_domain(1, collector),
+            Arc::new(SeriesOwner::default()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_fees_and_receipt_checks(pgpool: PgPool) {
+        store_receipt(&pgpool, SENDER.1, 1, 10).await;
+        store_receipt(&pgpool, SENDER.1, 2, 20).await;
+        // Signed by a signer of SENDER, but paid by another sender
+        store_receipt(&pgpool, SENDER_2.1, 3, 40).await;
+
+        let allocation = create_horizon_allocation(pgpool, 1000).await;
+        assert!(allocation.latest_ravs.is_empty());
+
+        let fees = allocation
+            .calculate_fee_until_last_id(i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(fees.value, 70);
+        assert_eq!(fees.counter, 3);
+
+        let receipts = allocation.fetch_receipts().await.unwrap();
+        let (valid, invalid) = allocation.check_receipts(receipts).await.unwrap();
+        assert_eq!(valid.len(), 2);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0.receipt.message.payer, SENDER_2.1);
+
+        let valid: Vec<_> = valid.into_iter().map(|stored| stored.receipt).collect();
+        let expected_rav = allocation.expected_rav(None, &valid).unwrap();
+        assert_eq!(expected_rav.valueAggregate, 30);
+        assert_eq!(expected_rav.timestampNs, 3);
+        assert_eq!(
+            expected_rav.collectionId,
+            horizon::collection_id(*ALLOCATION_ID_0)
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_receipts_over_horizon_escrow_balance(pgpool: PgPool) {
+        store_receipt(&pgpool, SENDER.1, 1, 10).await;
+        store_receipt(&pgpool, SENDER.1, 2, 20).await;
+
+        let allocation = create_horizon_allocation(pgpool, 25).await;
+
+        let receipts = allocation.fetch_receipts().await.unwrap();
+        let (valid, invalid) = allocation.check_receipts(receipts).await.unwrap();
+        assert_eq!(valid.len(), 1);
+        assert_eq!(valid[0].receipt.message.value, 10);
+        assert_eq!(invalid.len(), 1);
+        assert!(invalid[0].1.contains("Horizon escrow balance"));
+        assert_eq!(
+            RejectionCode::classify(&invalid[0].1),
+            RejectionCode::InsufficientBalance
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_data_services_apart(pgpool: PgPool) {
+        store_data_service_receipt(&pgpool, SENDER.1, DATA_SERVICE, 1, 10).await;
+        store_data_service_receipt(&pgpool, SENDER.1, DATA_SERVICE_2, 2, 20).await;
+        store_data_service_receipt(&pgpool, SENDER.1, DATA_SERVICE, 3, 40).await;
+        store_data_service_receipt(&pgpool, SENDER.1, DATA_SERVICE_2, 4, 80).await;
+        // Covers the first receipt of DATA_SERVICE only
+        store_rav(&pgpool, DATA_SERVICE, 2, 10).await;
+
+        let allocation = create_horizon_allocation(pgpool.clone(), 1000).await;
+        assert_eq!(allocation.latest_ravs.len(), 1);
+        assert_eq!(allocation.rav_value(), 10);
+
+        let fees = allocation
+            .calculate_fee_until_last_id(i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(fees.value, 140);
+        assert_eq!(fees.counter, 3);
+        // Only the receipt covered by the RAV of its data service is deleted
+        let remaining =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM tap_horizon_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, 3);
+
+        // The receipts of both data services are valid
+        let receipts = allocation.fetch_receipts().await.unwrap();
+        let (valid, invalid) = allocation.check_receipts(receipts).await.unwrap();
+        assert_eq!(valid.len(), 3);
+        assert!(invalid.is_empty());
+
+        let receipts: Vec<_> = valid
+            .into_iter()
+            .map(|stored| stored.receipt)
+            .filter(|receipt| receipt.message.data_service == DATA_SERVICE_2)
+            .collect();
+        let expected_rav = allocation.expected_rav(None, &receipts).unwrap();
+        assert_eq!(expected_rav.dataService, DATA_SERVICE_2);
+        assert_eq!(expected_rav.valueAggregate, 100);
+        assert_eq!(expected_rav.timestampNs, 5);
+    }
+}
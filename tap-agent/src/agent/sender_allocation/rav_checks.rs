@@ -0,0 +1,143 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sanity checks of the RAVs returned by the senders' aggregators, before they're accepted.
+//!
+//! A RAV is rejected if its value is lower than the previous RAV of the allocation, if it's lower
+//! than the sum of the receipts it aggregates or higher by more than `tap.rav_value_tolerance`,
+//! or if it isn't signed by a signer of the sender. The tolerance never lets a sender pay less
+//! than its receipts. Rejected RAVs are quarantined in `scalar_tap_rav_requests_failed` (or its
+//! Horizon counterpart) by the caller, and counted in `tap_rav_rejected_total`.
+
+use alloy::primitives::Address;
+use thiserror::Error;
+
+use super::RAVS_REJECTED;
+use crate::metrics::{exemplars, series::SeriesOwner};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RavRejection {
+    #[error("RAV value {received} is lower than the previous RAV value {previous}")]
+    NotMonotonic { previous: u128, received: u128 },
+    #[error("RAV value {received} is lower than the expected value {expected}")]
+    Underpaid { expected: u128, received: u128 },
+    #[error(
+        "RAV value {received} exceeds the expected value {expected} by more than the tolerance"
+    )]
+    ValueMismatch { expected: u128, received: u128 },
+    #[error("RAV signer {0} is not a signer of the sender")]
+    UnauthorizedSigner(Address),
+    #[error("Could not recover the RAV signer: {0}")]
+    BadSignature(String),
+}
+
+impl RavRejection {
+    /// Label of the rejection in `tap_rav_rejected_total`.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RavRejection::NotMonotonic { .. } => "not_monotonic",
+            RavRejection::Underpaid { .. } => "underpaid",
+            RavRejection::ValueMismatch { .. } => "value_mismatch",
+            RavRejection::UnauthorizedSigner(_) => "unauthorized_signer",
+            RavRejection::BadSignature(_) => "bad_signature",
+        }
+    }
+
+    /// Counts the rejection, returns it for the caller to quarantine the RAV.
+    pub fn record(self, metrics: &SeriesOwner, sender: &Address) -> Self {
+        exemplars::inc(
+            &metrics.with_label_values(&RAVS_REJECTED, &[&sender.to_string(), self.reason()]),
+        );
+        self
+    }
+}
+
+/// Checks the value of a received RAV against the previous RAV of the allocation and the value
+/// expected from its receipts.
+pub fn check_value(
+    previous: Option<u128>,
+    expected: u128,
+    received: u128,
+    tolerance: f64,
+) -> Result<(), RavRejection> {
+    if let Some(previous) = previous {
+        if received < previous {
+            return Err(RavRejection::NotMonotonic { previous, received });
+        }
+    }
+    if received < expected {
+        return Err(RavRejection::Underpaid { expected, received });
+    }
+    if (received - expected) as f64 > expected as f64 * tolerance {
+        return Err(RavRejection::ValueMismatch { expected, received });
+    }
+    Ok(())
+}
+
+/// Checks that the RAV was signed by one of the `signers` of the sender.
+pub fn check_signer<E: std::fmt::Display>(
+    recovered: Result<Address, E>,
+    signers: &[Address],
+) -> Result<(), RavRejection> {
+    let signer = recovered.map_err(|e| RavRejection::BadSignature(e.to_string()))?;
+    if !signers.contains(&signer) {
+        return Err(RavRejection::UnauthorizedSigner(signer));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::{check_signer, check_value, RavRejection};
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(check_value(None, 100, 100, 0.0), Ok(()));
+        assert_eq!(check_value(Some(50), 100, 100, 0.0), Ok(()));
+        assert_eq!(
+            check_value(Some(150), 100, 100, 0.0),
+            Err(RavRejection::NotMonotonic {
+                previous: 150,
+                received: 100
+            })
+        );
+        assert_eq!(
+            check_value(Some(50), 100, 99, 0.0),
+            Err(RavRejection::Underpaid {
+                expected: 100,
+                received: 99
+            })
+        );
+        // The tolerance only covers a RAV worth more than its receipts
+        assert_eq!(
+            check_value(Some(50), 100, 99, 0.01).map_err(|e| e.reason()),
+            Err("underpaid")
+        );
+        assert_eq!(
+            check_value(Some(50), 100, 101, 0.0).map_err(|e| e.reason()),
+            Err("value_mismatch")
+        );
+        assert_eq!(check_value(Some(50), 100, 101, 0.01), Ok(()));
+        assert_eq!(
+            check_value(Some(50), 100, 102, 0.01).map_err(|e| e.reason()),
+            Err("value_mismatch")
+        );
+    }
+
+    #[test]
+    fn test_check_signer() {
+        let signer = Address::from([1; 20]);
+        let other = Address::from([2; 20]);
+        assert_eq!(check_signer(Ok::<_, String>(signer), &[signer]), Ok(()));
+        assert_eq!(
+            check_signer(Ok::<_, String>(other), &[signer]),
+            Err(RavRejection::UnauthorizedSigner(other))
+        );
+        assert_eq!(
+            check_signer(Err("invalid signature"), &[signer]).map_err(|e| e.reason()),
+            Err("bad_signature")
+        );
+    }
+}
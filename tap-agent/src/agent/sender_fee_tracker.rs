@@ -8,29 +8,33 @@ use std::{
 };
 use tracing::error;
 
+use super::rav_queue::RavCandidate;
+
 #[derive(Debug, Clone, Default)]
 struct ExpiringSum {
-    entries: VecDeque<(Instant, u128)>,
+    /// Time, value and number of receipts of each addition
+    entries: VecDeque<(Instant, u128, u64)>,
     sum: u128,
+    count: u64,
 }
 
 impl ExpiringSum {
-    fn get_sum(&mut self, duration: &Duration) -> u128 {
-        self.cleanup(duration);
+    fn get_sum(&mut self, duration: &Duration, now: Instant) -> u128 {
+        self.cleanup(duration, now);
         self.sum
     }
 
-    fn get_count(&mut self, duration: &Duration) -> u64 {
-        self.cleanup(duration);
-        self.entries.len() as u64
+    fn get_count(&mut self, duration: &Duration, now: Instant) -> u64 {
+        self.cleanup(duration, now);
+        self.count
     }
 
-    fn cleanup(&mut self, duration: &Duration) {
-        let now = Instant::now();
-        while let Some(&(timestamp, value)) = self.entries.front() {
-            if now.duration_since(timestamp) >= *duration {
+    fn cleanup(&mut self, duration: &Duration, now: Instant) {
+        while let Some(&(timestamp, value, count)) = self.entries.front() {
+            if now.saturating_duration_since(timestamp) >= *duration {
                 self.entries.pop_front();
                 self.sum -= value;
+                self.count -= count;
             } else {
                 break;
             }
@@ -89,17 +93,28 @@ impl SenderFeeTracker {
     /// zero, so the only way to make this counter lower is by using
     /// `update` function
     pub fn add(&mut self, id: Address, value: u128) {
+        self.add_batch(id, value, 1);
+    }
+
+    /// Same as [`SenderFeeTracker::add`], for `count` receipts adding up to `value`
+    pub fn add_batch(&mut self, id: Address, value: u128, count: u64) {
+        self.add_batch_at(id, value, count, Instant::now());
+    }
+
+    /// Same as [`SenderFeeTracker::add_batch`], for receipts received at `now`. The `_at`
+    /// variants keep the buffer on a clock of their caller, see [`crate::simulation`].
+    pub fn add_batch_at(&mut self, id: Address, value: u128, count: u64, now: Instant) {
         if self.buffer_window_duration > Duration::ZERO {
-            let now = Instant::now();
             let expiring_sum = self.buffer_window_fee.entry(id).or_default();
-            expiring_sum.entries.push_back((now, value));
+            expiring_sum.entries.push_back((now, value, count));
             expiring_sum.sum += value;
+            expiring_sum.count += count;
         }
         self.total_fee += value;
 
         let entry = self.id_to_fee.entry(id).or_default();
         entry.fee += value;
-        entry.count += 1;
+        entry.count += count;
     }
 
     /// Updates and overwrite the fee counter into the specific
@@ -140,9 +155,13 @@ impl SenderFeeTracker {
         self.blocked_addresses.remove(&address);
     }
 
-    pub fn get_heaviest_allocation_id(&mut self) -> Option<Address> {
-        // just loop over and get the biggest fee
-        let now = Instant::now();
+    /// Allocations with fees outside the buffer, that aren't blocked, backing off or already
+    /// requesting a RAV. See [`crate::agent::rav_queue`] for the order they're requested in.
+    pub fn rav_candidates(&mut self) -> Vec<RavCandidate> {
+        self.rav_candidates_at(Instant::now())
+    }
+
+    pub fn rav_candidates_at(&mut self, now: Instant) -> Vec<RavCandidate> {
         self.id_to_fee
             .iter()
             .filter(|(addr, _)| !self.blocked_addresses.contains(*addr))
@@ -154,47 +173,65 @@ impl SenderFeeTracker {
                     .unwrap_or(true)
             })
             // map to the value minus fees in buffer
-            .map(|(addr, fee)| {
-                (
-                    addr,
-                    fee.fee
-                        - self
-                            .buffer_window_fee
-                            .get_mut(addr)
-                            .map(|expiring| expiring.get_sum(&self.buffer_window_duration))
-                            .unwrap_or_default(),
-                )
+            .map(|(addr, fee)| RavCandidate {
+                allocation_id: *addr,
+                fee_outside_buffer: fee.fee
+                    - self
+                        .buffer_window_fee
+                        .get_mut(addr)
+                        .map(|expiring| expiring.get_sum(&self.buffer_window_duration, now))
+                        .unwrap_or_default(),
+                failed_rav_requests: self
+                    .failed_ravs
+                    .get(addr)
+                    .map(|failed_rav| failed_rav.failed_ravs_count)
+                    .unwrap_or_default(),
             })
-            .filter(|(_, fee)| *fee > 0)
-            .fold(None, |acc: Option<(&Address, u128)>, (addr, fee)| {
-                if let Some((_, max_fee)) = acc {
-                    if fee > max_fee {
-                        Some((addr, fee))
-                    } else {
-                        acc
-                    }
-                } else {
-                    Some((addr, fee))
-                }
-            })
-            .map(|(&id, _)| id)
+            .filter(|candidate| candidate.fee_outside_buffer > 0)
+            .collect()
+    }
+
+    pub fn get_heaviest_allocation_id(&mut self) -> Option<Address> {
+        self.rav_candidates()
+            .into_iter()
+            .max_by_key(|candidate| candidate.fee_outside_buffer)
+            .map(|candidate| candidate.allocation_id)
     }
 
     pub fn get_list_of_allocation_ids(&self) -> HashSet<Address> {
         self.id_to_fee.keys().cloned().collect()
     }
 
+    pub fn get_fee(&self, id: &Address) -> u128 {
+        self.id_to_fee
+            .get(id)
+            .map(|fee| fee.fee)
+            .unwrap_or_default()
+    }
+
     pub fn get_total_fee(&self) -> u128 {
         self.total_fee - self.fees_requesting
     }
 
     pub fn get_total_fee_outside_buffer(&mut self) -> u128 {
-        self.get_total_fee() - self.get_buffer_fee().min(self.total_fee)
+        self.get_total_fee_outside_buffer_at(Instant::now())
+    }
+
+    pub fn get_total_fee_outside_buffer_at(&mut self, now: Instant) -> u128 {
+        self.get_total_fee() - self.get_buffer_fee_at(now).min(self.total_fee)
     }
 
     pub fn get_total_counter_outside_buffer_for_allocation(
         &mut self,
         allocation_id: &Address,
+    ) -> u64 {
+        self.get_total_counter_outside_buffer_for_allocation_at(allocation_id, Instant::now())
+    }
+
+    pub fn get_total_counter_outside_buffer_for_allocation_at(
+        &mut self,
+        allocation_id: &Address,
+        now: Instant,
     ) -> u64 {
         let Some(allocation_counter) = self
             .id_to_fee
@@ -206,16 +243,20 @@ impl SenderFeeTracker {
         let counter_in_buffer = self
             .buffer_window_fee
             .get_mut(allocation_id)
-            .map(|window| window.get_count(&self.buffer_window_duration))
+            .map(|window| window.get_count(&self.buffer_window_duration, now))
             .unwrap_or(0);
         allocation_counter - counter_in_buffer
     }
 
     pub fn get_buffer_fee(&mut self) -> u128 {
+        self.get_buffer_fee_at(Instant::now())
+    }
+
+    pub fn get_buffer_fee_at(&mut self, now: Instant) -> u128 {
         self.buffer_window_fee
             .values_mut()
             .fold(0u128, |acc, expiring| {
-                acc + expiring.get_sum(&self.buffer_window_duration)
+                acc + expiring.get_sum(&self.buffer_window_duration, now)
             })
     }
 
@@ -253,7 +294,10 @@ impl SenderFeeTracker {
 mod tests {
     use super::SenderFeeTracker;
     use alloy::primitives::address;
-    use std::{thread::sleep, time::Duration};
+    use std::{
+        thread::sleep,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn test_allocation_id_tracker() {
@@ -507,13 +551,13 @@ mod tests {
             .buffer_window_fee
             .get_mut(&allocation_id_0)
             .expect("there should be something here");
-        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW), 10);
-        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW), 1);
+        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW, Instant::now()), 10);
+        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW, Instant::now()), 1);
 
         sleep(BUFFER_WINDOW);
 
-        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW), 0);
-        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW), 0);
+        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW, Instant::now()), 0);
+        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW, Instant::now()), 0);
 
         tracker.add(allocation_id_0, 10);
         let expiring_sum = tracker
@@ -521,12 +565,36 @@ mod tests {
             .get_mut(&allocation_id_0)
             .expect("there should be something here");
 
-        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW), 1);
-        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW), 10);
+        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW, Instant::now()), 1);
+        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW, Instant::now()), 10);
+
+        sleep(BUFFER_WINDOW);
+
+        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW, Instant::now()), 0);
+        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW, Instant::now()), 0);
+    }
+
+    #[test]
+    fn check_batch_counter_outside_buffer() {
+        let allocation_id_0 = address!("abababababababababababababababababababab");
+
+        const BUFFER_WINDOW: Duration = Duration::from_millis(20);
+        let mut tracker = SenderFeeTracker::new(BUFFER_WINDOW);
+
+        tracker.add_batch(allocation_id_0, 30, 3);
+        tracker.add(allocation_id_0, 10);
+        assert_eq!(tracker.get_total_fee(), 40);
+        assert_eq!(
+            tracker.get_total_counter_outside_buffer_for_allocation(&allocation_id_0),
+            0
+        );
 
         sleep(BUFFER_WINDOW);
 
-        assert_eq!(expiring_sum.get_count(&BUFFER_WINDOW), 0);
-        assert_eq!(expiring_sum.get_sum(&BUFFER_WINDOW), 0);
+        assert_eq!(tracker.get_total_fee_outside_buffer(), 40);
+        assert_eq!(
+            tracker.get_total_counter_outside_buffer_for_allocation(&allocation_id_0),
+            4
+        );
     }
 }
@@ -0,0 +1,294 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sharing of the senders between the tap-agents using the same database.
+//!
+//! Every tap-agent registers itself in `tap_agent_instances` with an expiration time, and leases
+//! senders in `tap_sender_leases`. On each rebalance, under a Postgres advisory lock so that only
+//! one tap-agent rebalances at a time, a tap-agent:
+//!
+//! - renews its registration, which renews all its leases,
+//! - removes the expired tap-agents, releasing their leases,
+//! - keeps at most `ceil(senders / tap-agents)` of its leases, and leases unowned senders until
+//!   it reaches that share.
+//!
+//! A lease given up is only released on the next rebalance, once the `SenderAccount` of the
+//! sender is gone, so that two tap-agents never run it at the same time. For the same reason, a
+//! tap-agent that can't renew its leases gives up all its senders once the leases expired.
+
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::config;
+
+/// Taken for the duration of a rebalance. "tap_leas" in ASCII.
+const LOCK_KEY: i64 = 0x7461_705f_6c65_6173;
+
+pub struct SenderLeases {
+    pgpool: PgPool,
+    instance_id: String,
+    lease_duration: Duration,
+    owned: HashSet<Address>,
+    /// Leases given up on the last rebalance, released on the next one.
+    released: HashSet<Address>,
+    /// The leases in `owned` can't be taken over before then.
+    valid_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Plan {
+    owned: HashSet<Address>,
+    claimed: Vec<Address>,
+    released: HashSet<Address>,
+}
+
+impl SenderLeases {
+    pub fn new(pgpool: PgPool, config: &config::Sharding) -> Self {
+        Self {
+            pgpool,
+            instance_id: config.instance_id.clone(),
+            lease_duration: config.lease_duration,
+            owned: HashSet::new(),
+            released: HashSet::new(),
+            valid_until: None,
+        }
+    }
+
+    /// Leaves a margin to renew the leases before they expire.
+    pub fn rebalance_interval(&self) -> Duration {
+        self.lease_duration / 3
+    }
+
+    /// Updates the leases of this tap-agent, returns the senders it owns.
+    pub async fn rebalance(&mut self, senders: &HashSet<Address>) -> &HashSet<Address> {
+        let start = Instant::now();
+        match self.try_rebalance(senders).await {
+            Ok(plan) => {
+                if !plan.claimed.is_empty() || !plan.released.is_empty() {
+                    info!(
+                        instance_id = %self.instance_id,
+                        claimed = plan.claimed.len(),
+                        released = plan.released.len(),
+                        owned = plan.owned.len(),
+                        "Sender leases rebalanced."
+                    );
+                }
+                self.owned = plan.owned;
+                self.released = plan.released;
+                self.valid_until = Some(start + self.lease_duration);
+            }
+            Err(error) => {
+                if self.valid_until.is_some_and(|until| Instant::now() < until) {
+                    warn!(%error, "Failed to renew the sender leases, retrying on next rebalance.");
+                } else {
+                    warn!(
+                        %error,
+                        "Sender leases expired, giving up all senders until they can be renewed."
+                    );
+                    self.released.extend(self.owned.drain());
+                }
+            }
+        }
+        &self.owned
+    }
+
+    /// Releases all the leases, for the other tap-agents to take over right away.
+    pub async fn release_all(&self) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM tap_agent_instances WHERE instance_id = $1",
+            &self.instance_id
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    async fn try_rebalance(&self, senders: &HashSet<Address>) -> anyhow::Result<Plan> {
+        let mut tx = self.pgpool.begin().await?;
+        sqlx::query!("SELECT pg_advisory_xact_lock($1)", LOCK_KEY)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_agent_instances (instance_id, expires_at)
+                VALUES ($1, NOW() + make_interval(secs => $2))
+                ON CONFLICT (instance_id)
+                DO UPDATE SET expires_at = EXCLUDED.expires_at
+            "#,
+            &self.instance_id,
+            self.lease_duration.as_secs_f64(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM tap_agent_instances WHERE expires_at < NOW()")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"
+                DELETE FROM tap_sender_leases
+                WHERE instance_id = $1 AND sender_address IN (SELECT unnest($2::text[]))
+            "#,
+            &self.instance_id,
+            &self
+                .released
+                .iter()
+                .map(|sender| sender.encode_hex())
+                .collect::<Vec<_>>(),
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let instances =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM tap_agent_instances"#)
+                .fetch_one(&mut *tx)
+                .await?;
+        let leases = sqlx::query!("SELECT sender_address, instance_id FROM tap_sender_leases")
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut mine = HashSet::new();
+        let mut taken = HashSet::new();
+        for lease in leases {
+            let sender = Address::from_str(&lease.sender_address).map_err(|e| {
+                anyhow!(
+                    "Invalid sender address {} in the leases: {e}",
+                    lease.sender_address
+                )
+            })?;
+            if lease.instance_id == self.instance_id {
+                mine.insert(sender);
+            } else {
+                taken.insert(sender);
+            }
+        }
+
+        let plan = plan(senders, instances as usize, &mine, &taken);
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_sender_leases (sender_address, instance_id)
+                SELECT unnest($1::text[]), $2
+            "#,
+            &plan
+                .claimed
+                .iter()
+                .map(|sender| sender.encode_hex())
+                .collect::<Vec<_>>(),
+            &self.instance_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(plan)
+    }
+}
+
+/// Keeps the leases of `mine` up to this tap-agent's share of the `senders`, and claims free
+/// senders to reach it. Senders are taken in address order, so that the result is stable.
+fn plan(
+    senders: &HashSet<Address>,
+    instances: usize,
+    mine: &HashSet<Address>,
+    taken: &HashSet<Address>,
+) -> Plan {
+    let share = senders.len().div_ceil(instances.max(1));
+
+    let mut kept: Vec<Address> = mine.intersection(senders).copied().collect();
+    kept.sort();
+    let mut released: HashSet<Address> = mine.difference(senders).copied().collect();
+    if kept.len() > share {
+        released.extend(kept.split_off(share));
+    }
+
+    let mut free: Vec<Address> = senders
+        .iter()
+        .filter(|sender| !mine.contains(sender) && !taken.contains(sender))
+        .copied()
+        .collect();
+    free.sort();
+    free.truncate(share - kept.len());
+
+    Plan {
+        owned: kept.iter().chain(&free).copied().collect(),
+        claimed: free,
+        released,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use alloy::primitives::Address;
+    use sqlx::PgPool;
+
+    use super::{plan, SenderLeases};
+    use crate::config;
+
+    fn senders(range: std::ops::Range<u8>) -> HashSet<Address> {
+        range.map(|i| Address::from([i; 20])).collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        let all = senders(0..4);
+
+        // Alone, all senders are claimed
+        let alone = plan(&all, 1, &HashSet::new(), &HashSet::new());
+        assert_eq!(alone.owned, all);
+        assert!(alone.released.is_empty());
+
+        // A second instance only gets the senders given up by the first one
+        let first = plan(&all, 2, &all, &HashSet::new());
+        assert_eq!(first.owned, senders(0..2));
+        assert_eq!(first.released, senders(2..4));
+        let second = plan(&all, 2, &HashSet::new(), &all);
+        assert!(second.owned.is_empty());
+        let second = plan(&all, 2, &HashSet::new(), &senders(0..2));
+        assert_eq!(second.owned, senders(2..4));
+
+        // Senders not in the escrow accounts anymore are released
+        let gone = plan(&senders(0..1), 1, &senders(0..2), &HashSet::new());
+        assert_eq!(gone.owned, senders(0..1));
+        assert_eq!(gone.released, senders(1..2));
+    }
+
+    fn leases(pgpool: &PgPool, instance_id: &str) -> SenderLeases {
+        SenderLeases::new(
+            pgpool.clone(),
+            &config::Sharding {
+                instance_id: instance_id.to_string(),
+                lease_duration: Duration::from_secs(60),
+            },
+        )
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_rebalance(pgpool: PgPool) {
+        let all = senders(0..4);
+        let mut first = leases(&pgpool, "first");
+        let mut second = leases(&pgpool, "second");
+
+        assert_eq!(first.rebalance(&all).await, &all);
+
+        // The first instance gives up half of its senders, then releases them on the next
+        // rebalance, for the second one to take over.
+        assert!(second.rebalance(&all).await.is_empty());
+        assert_eq!(first.rebalance(&all).await.len(), 2);
+        assert!(second.rebalance(&all).await.is_empty());
+        first.rebalance(&all).await;
+        let owned = second.rebalance(&all).await.clone();
+        assert_eq!(owned.len(), 2);
+        assert!(owned.is_disjoint(first.rebalance(&all).await));
+
+        // The senders of a stopped instance are taken over
+        second.release_all().await.unwrap();
+        assert_eq!(first.rebalance(&all).await, &all);
+    }
+}
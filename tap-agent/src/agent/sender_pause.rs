@@ -0,0 +1,125 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Senders paused by the operator, see [`crate::admin`].
+//!
+//! Pausing a sender puts it on hold while keeping its state, for example while a dispute with it
+//! is sorted out:
+//! - indexer-service rejects its receipts, through the `paused_sender` check watching
+//!   `tap_paused_senders`.
+//! - its `SenderAccount` keeps running and tracking its fees, but doesn't trigger RAV requests,
+//!   neither on the trigger value, the receipt limit nor the checkpoints. The last RAVs of its
+//!   closed allocations are still requested, otherwise their fees would be lost.
+//! - it's reported in the `tap_sender_paused` metric and in [`crate::status`].
+//!
+//! It stays paused until resumed, across restarts. Unlike the denylist, written by tap-agent on
+//! its own from the deny condition, `tap_paused_senders` is only written on the operator's
+//! command, and a paused sender can be denied and allowed as usual. Since no RAV lowers its
+//! unaggregated fees while it's paused, once denied it stays so until resumed.
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::database::{self, Subsystem};
+
+#[derive(Debug, Error)]
+pub enum PauseError {
+    #[error("Sender {0} is already paused")]
+    AlreadyPaused(Address),
+    #[error("Sender {0} is not paused")]
+    NotPaused(Address),
+    #[error("Failed to update tap_paused_senders: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+pub async fn is_paused(pgpool: &PgPool, sender: Address) -> Result<bool, sqlx::Error> {
+    database::acquire(pgpool, Subsystem::Denylist)
+        .await?
+        .run(|conn| {
+            sqlx::query_scalar!(
+                r#"
+                    SELECT EXISTS (SELECT 1 FROM tap_paused_senders WHERE sender_address = $1)
+                        AS "paused!"
+                "#,
+                sender.encode_hex()
+            )
+            .fetch_one(conn)
+        })
+        .await
+}
+
+pub async fn pause(
+    pgpool: &PgPool,
+    sender: Address,
+    reason: Option<String>,
+) -> Result<(), PauseError> {
+    let inserted = database::acquire(pgpool, Subsystem::Denylist)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    INSERT INTO tap_paused_senders (sender_address, reason)
+                    VALUES ($1, $2)
+                    ON CONFLICT (sender_address) DO NOTHING
+                "#,
+                sender.encode_hex(),
+                reason.as_deref()
+            )
+            .execute(conn)
+        })
+        .await?
+        .rows_affected();
+    if inserted == 0 {
+        return Err(PauseError::AlreadyPaused(sender));
+    }
+    Ok(())
+}
+
+pub async fn resume(pgpool: &PgPool, sender: Address) -> Result<(), PauseError> {
+    let deleted = database::acquire(pgpool, Subsystem::Denylist)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                "DELETE FROM tap_paused_senders WHERE sender_address = $1",
+                sender.encode_hex()
+            )
+            .execute(conn)
+        })
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(PauseError::NotPaused(sender));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{is_paused, pause, resume, PauseError};
+    use crate::tap::test_utils::SENDER;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_pause_and_resume(pgpool: PgPool) {
+        let sender = SENDER.1;
+        assert!(!is_paused(&pgpool, sender).await.unwrap());
+
+        pause(&pgpool, sender, Some("dispute".to_string()))
+            .await
+            .unwrap();
+        assert!(is_paused(&pgpool, sender).await.unwrap());
+        assert!(matches!(
+            pause(&pgpool, sender, None).await,
+            Err(PauseError::AlreadyPaused(_))
+        ));
+
+        resume(&pgpool, sender).await.unwrap();
+        assert!(!is_paused(&pgpool, sender).await.unwrap());
+        assert!(matches!(
+            resume(&pgpool, sender).await,
+            Err(PauseError::NotPaused(_))
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits the `SenderAllocation`s scanning their receipts at the same time.
+//!
+//! Each `SenderAllocation` sums up its pending receipts once it's started. After a restart with
+//! many allocations, these scans would all compete for the connections of the
+//! `sender_allocation` pool at once. Instead, at most
+//! `tap.database_pools.max_concurrent_startup_scans` of them run at the same time, and the waiting
+//! ones are let through by decreasing number of pending receipts, as counted by
+//! [`receipt_backlogs`] when the `SenderAccountsManager` starts.
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use alloy::primitives::{Address, FixedBytes};
+use anyhow::Result;
+use sqlx::PgPool;
+use tokio::sync::oneshot;
+
+use crate::{
+    database::{self, Subsystem},
+    tap::horizon,
+};
+
+#[derive(Clone)]
+pub struct StartupScans {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    available: usize,
+    /// Pending receipts of each allocation.
+    backlogs: HashMap<Address, u64>,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+struct Waiter {
+    backlog: u64,
+    seq: u64,
+    start: oneshot::Sender<ScanPermit>,
+}
+
+impl Ord for Waiter {
+    /// Largest backlog first, then first come first served.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.backlog
+            .cmp(&other.backlog)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+/// Held during a scan, lets the next waiting allocation through once dropped. Sent to the
+/// waiters, so that it's released even if a waiter stops waiting before receiving it.
+pub struct ScanPermit {
+    /// Unset once given back to `available`.
+    scans: Option<StartupScans>,
+}
+
+impl Drop for ScanPermit {
+    fn drop(&mut self) {
+        if let Some(scans) = self.scans.take() {
+            scans.release();
+        }
+    }
+}
+
+impl StartupScans {
+    pub fn new(max_concurrent: usize, backlogs: HashMap<Address, u64>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                available: max_concurrent,
+                backlogs,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    /// Waits for the turn of `allocation_id` to scan its receipts.
+    pub async fn acquire(&self, allocation_id: Address) -> ScanPermit {
+        let start = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.available > 0 {
+                inner.available -= 1;
+                return ScanPermit {
+                    scans: Some(self.clone()),
+                };
+            }
+            let (start, started) = oneshot::channel();
+            let waiter = Waiter {
+                backlog: inner
+                    .backlogs
+                    .get(&allocation_id)
+                    .copied()
+                    .unwrap_or_default(),
+                seq: inner.next_seq,
+                start,
+            };
+            inner.next_seq += 1;
+            inner.waiting.push(waiter);
+            started
+        };
+        // The waiters are only dropped along with `self`
+        start
+            .await
+            .expect("StartupScans should outlive its waiters")
+    }
+
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut permit = ScanPermit {
+            scans: Some(self.clone()),
+        };
+        // Hand the permit over to the next waiter still waiting
+        while let Some(waiter) = inner.waiting.pop() {
+            match waiter.start.send(permit) {
+                Ok(()) => return,
+                Err(unused) => permit = unused,
+            }
+        }
+        // No one waiting anymore, so the permit goes back to `available` instead
+        permit.scans = None;
+        drop(permit);
+        inner.available += 1;
+    }
+}
+
+/// Counts the pending receipts of each allocation, legacy and Horizon ones.
+pub async fn receipt_backlogs(pgpool: &PgPool, horizon: bool) -> Result<HashMap<Address, u64>> {
+    let legacy = database::acquire(pgpool, Subsystem::ReceiptScan)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    SELECT allocation_id, COUNT(*) AS "count!"
+                    FROM scalar_tap_receipts
+                    GROUP BY allocation_id
+                "#,
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+
+    let mut backlogs = HashMap::new();
+    for row in legacy {
+        if let Ok(allocation_id) = Address::from_str(&row.allocation_id) {
+            *backlogs.entry(allocation_id).or_default() += row.count as u64;
+        }
+    }
+    if !horizon {
+        return Ok(backlogs);
+    }
+
+    let horizon_receipts = database::acquire(pgpool, Subsystem::ReceiptScan)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    SELECT collection_id, COUNT(*) AS "count!"
+                    FROM tap_horizon_receipts
+                    GROUP BY collection_id
+                "#,
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+    for row in horizon_receipts {
+        if let Ok(collection_id) = FixedBytes::<32>::from_str(&row.collection_id) {
+            *backlogs
+                .entry(horizon::allocation_id(collection_id))
+                .or_default() += row.count as u64;
+        }
+    }
+    Ok(backlogs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use alloy::primitives::Address;
+    use tokio::sync::mpsc;
+
+    use super::StartupScans;
+
+    #[tokio::test]
+    async fn test_largest_backlog_first() {
+        let small = Address::from([1; 20]);
+        let large = Address::from([2; 20]);
+        let unknown = Address::from([3; 20]);
+        let scans = StartupScans::new(1, HashMap::from([(small, 10), (large, 1000)]));
+
+        let first = scans.acquire(Address::ZERO).await;
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        for allocation_id in [unknown, small, large] {
+            let scans = scans.clone();
+            let started = started.clone();
+            tokio::spawn(async move {
+                let _permit = scans.acquire(allocation_id).await;
+                started.send(allocation_id).unwrap();
+            });
+            // Queued in that order
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(started_rx.try_recv().is_err());
+
+        drop(first);
+        for expected in [large, small, unknown] {
+            assert_eq!(started_rx.recv().await, Some(expected));
+        }
+
+        // All the permits are back
+        let _permit = scans.acquire(Address::ZERO).await;
+        assert_eq!(scans.inner.lock().unwrap().available, 0);
+    }
+}
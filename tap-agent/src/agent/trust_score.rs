@@ -0,0 +1,224 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trust score of a sender, between 0 and 1, from how it behaved recently.
+//!
+//! Each component is a penalty between 0 and 1, the score is 1 minus their weighted sum:
+//! - the invalid receipt ratio, the fees of the invalid receipts over all the fees of the sender
+//!   not redeemed yet.
+//! - the RAV failure rate, the failed RAV requests over all of them.
+//! - the escrow balance volatility, the standard deviation of the escrow balance over its mean. A
+//!   balance moving a lot is harder to rely on for the deny condition.
+//! - the timestamp skew, the mean difference between the timestamps of the receipts and when
+//!   they're received, over [`SKEW_SCALE`]. A large skew is a sign of a misconfigured gateway.
+//!
+//! All but the invalid receipt ratio only count the events of the last
+//! `tap.trust_score.window_secs`, so the score recovers once a sender behaves again, even while
+//! it's denied and sends no receipts.
+//!
+//! It's reported in the `tap_sender_trust_score` metric and by the admin API, see
+//! [`crate::admin`]. If `tap.trust_score.deny_below` is set, the senders scoring below are
+//! denied, see [`crate::agent::deny_condition`].
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::config::TrustScore;
+
+const INVALID_RECEIPTS_WEIGHT: f64 = 0.4;
+const RAV_FAILURES_WEIGHT: f64 = 0.3;
+const BALANCE_VOLATILITY_WEIGHT: f64 = 0.1;
+const TIMESTAMP_SKEW_WEIGHT: f64 = 0.2;
+
+/// Mean timestamp skew with the full penalty.
+const SKEW_SCALE: Duration = Duration::from_secs(30);
+
+/// Samples kept per component, the oldest are dropped first under load.
+const MAX_SAMPLES: usize = 1024;
+
+/// Score of a sender and its components, all between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SenderTrust {
+    pub score: f64,
+    pub invalid_receipt_ratio: f64,
+    pub rav_failure_rate: f64,
+    pub balance_volatility: f64,
+    pub timestamp_skew: f64,
+}
+
+/// Samples in the window, oldest first.
+pub(super) struct Samples<T> {
+    samples: VecDeque<(Instant, T)>,
+}
+
+impl<T: Copy> Samples<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub(super) fn push(&mut self, now: Instant, value: T, window: Duration) {
+        while self.samples.front().is_some_and(|(at, _)| {
+            self.samples.len() >= MAX_SAMPLES || now.saturating_duration_since(*at) >= window
+        }) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, value));
+    }
+
+    pub(super) fn within(&self, now: Instant, window: Duration) -> impl Iterator<Item = T> + '_ {
+        self.samples
+            .iter()
+            .filter(move |(at, _)| now.saturating_duration_since(*at) < window)
+            .map(|(_, value)| *value)
+    }
+}
+
+pub struct TrustTracker {
+    window: Duration,
+    /// Whether each RAV request failed.
+    rav_requests: Samples<bool>,
+    balances: Samples<f64>,
+    timestamp_skews: Samples<Duration>,
+}
+
+impl TrustTracker {
+    pub fn new(config: &TrustScore) -> Self {
+        Self {
+            window: config.window,
+            rav_requests: Samples::new(),
+            balances: Samples::new(),
+            timestamp_skews: Samples::new(),
+        }
+    }
+
+    pub fn record_rav_request(&mut self, now: Instant, failed: bool) {
+        self.rav_requests.push(now, failed, self.window);
+    }
+
+    pub fn record_balance(&mut self, now: Instant, balance: u128) {
+        self.balances.push(now, balance as f64, self.window);
+    }
+
+    pub fn record_timestamp_skew(&mut self, now: Instant, skew: Duration) {
+        self.timestamp_skews.push(now, skew, self.window);
+    }
+
+    /// The trust of the sender at `now`, with `valid_fees` the fees of its valid receipts not
+    /// redeemed yet.
+    pub fn trust(&self, now: Instant, invalid_receipt_fees: u128, valid_fees: u128) -> SenderTrust {
+        let total_fees = invalid_receipt_fees.saturating_add(valid_fees);
+        let invalid_receipt_ratio = if total_fees == 0 {
+            0.0
+        } else {
+            invalid_receipt_fees as f64 / total_fees as f64
+        };
+
+        let (failed, requests) = self
+            .rav_requests
+            .within(now, self.window)
+            .fold((0, 0), |(failed, requests), f| {
+                (failed + f as u32, requests + 1)
+            });
+        let rav_failure_rate = if requests == 0 {
+            0.0
+        } else {
+            failed as f64 / requests as f64
+        };
+
+        let balances: Vec<f64> = self.balances.within(now, self.window).collect();
+        let balance_volatility = if balances.len() < 2 {
+            0.0
+        } else {
+            let mean = balances.iter().sum::<f64>() / balances.len() as f64;
+            let variance = balances
+                .iter()
+                .map(|balance| (balance - mean).powi(2))
+                .sum::<f64>()
+                / balances.len() as f64;
+            match (variance > 0.0, mean > 0.0) {
+                (false, _) => 0.0,
+                (true, true) => (variance.sqrt() / mean).min(1.0),
+                (true, false) => 1.0,
+            }
+        };
+
+        let skews: Vec<Duration> = self.timestamp_skews.within(now, self.window).collect();
+        let timestamp_skew = if skews.is_empty() {
+            0.0
+        } else {
+            let mean = skews.iter().sum::<Duration>() / skews.len() as u32;
+            (mean.as_secs_f64() / SKEW_SCALE.as_secs_f64()).min(1.0)
+        };
+
+        let score = 1.0
+            - INVALID_RECEIPTS_WEIGHT * invalid_receipt_ratio
+            - RAV_FAILURES_WEIGHT * rav_failure_rate
+            - BALANCE_VOLATILITY_WEIGHT * balance_volatility
+            - TIMESTAMP_SKEW_WEIGHT * timestamp_skew;
+        SenderTrust {
+            score: score.clamp(0.0, 1.0),
+            invalid_receipt_ratio,
+            rav_failure_rate,
+            balance_volatility,
+            timestamp_skew,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TrustTracker;
+    use crate::config::TrustScore;
+
+    #[test]
+    fn test_trust() {
+        let mut tracker = TrustTracker::new(&TrustScore {
+            window: Duration::from_secs(60),
+            deny_below: None,
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let trust = tracker.trust(at(0), 0, 0);
+        assert_eq!(trust.score, 1.0);
+
+        // A quarter of the fees are invalid
+        let trust = tracker.trust(at(0), 25, 75);
+        assert_eq!(trust.invalid_receipt_ratio, 0.25);
+        assert_eq!(trust.score, 0.9);
+
+        // Half of the RAV requests failed
+        tracker.record_rav_request(at(0), true);
+        tracker.record_rav_request(at(10), false);
+        // Stable balance
+        tracker.record_balance(at(0), 100);
+        tracker.record_balance(at(10), 100);
+        // 15s of skew on average
+        tracker.record_timestamp_skew(at(0), Duration::from_secs(10));
+        tracker.record_timestamp_skew(at(10), Duration::from_secs(20));
+        let trust = tracker.trust(at(10), 0, 100);
+        assert_eq!(trust.rav_failure_rate, 0.5);
+        assert_eq!(trust.balance_volatility, 0.0);
+        assert_eq!(trust.timestamp_skew, 0.5);
+        assert!((trust.score - 0.75).abs() < 1e-9);
+
+        // Balance going from 50 to 150
+        tracker.record_balance(at(20), 50);
+        tracker.record_balance(at(30), 150);
+        let trust = tracker.trust(at(30), 0, 100);
+        assert!(trust.balance_volatility > 0.0);
+
+        // The first samples are out of the window
+        let trust = tracker.trust(at(65), 0, 100);
+        assert_eq!(trust.rav_failure_rate, 0.0);
+        assert_eq!(trust.timestamp_skew, 20.0 / 30.0);
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detects the escrow accounts and allocations pipelines that stopped being updated.
+//!
+//! Their pollers only publish the values that changed and retry their errors forever, so a wedged
+//! poller silently leaves the agent deciding on arbitrarily old data. Instead, each poller beats a
+//! [`Heartbeat`] on every successful poll, and its [`Eventual`] is relayed through one owned by the
+//! watchdog. Once a pipeline went `tap.watchdog.stale_after_intervals` syncing intervals without a
+//! successful poll, it's logged and reported by the `tap_pipeline_stale` metric. With
+//! `tap.watchdog.resubscribe`, a new poller is also created in the background, with its own
+//! heartbeat, and relayed in place of the old one once it's ready, at most once per
+//! `stale_after_intervals`, so that the consumers keep their `Eventual`. The heartbeat of the old
+//! poller is then retired, a wedged poller can't be stopped but it stops if it ever gets to poll
+//! again. The pollers publishing on a `watch::Receiver` are relayed the same way, see
+//! [`watch_receiver`].
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use eventuals::{Eventual, EventualWriter, Value};
+use indexer_common::prelude::Heartbeat;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_int_gauge_vec, CounterVec, GaugeVec,
+    IntGaugeVec,
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::config::Watchdog;
+
+lazy_static! {
+    static ref PIPELINE_STALE: IntGaugeVec = register_int_gauge_vec!(
+        "tap_pipeline_stale",
+        "Whether the pipeline went without a successful poll for too long",
+        &["pipeline"]
+    )
+    .unwrap();
+    static ref PIPELINE_SECONDS_SINCE_POLL: GaugeVec = register_gauge_vec!(
+        "tap_pipeline_seconds_since_poll",
+        "Time since the last successful poll of the pipeline",
+        &["pipeline"]
+    )
+    .unwrap();
+    static ref PIPELINE_RESUBSCRIPTIONS: CounterVec = register_counter_vec!(
+        "tap_pipeline_resubscriptions_total",
+        "Number of times the poller of a stale pipeline was re-created",
+        &["pipeline"]
+    )
+    .unwrap();
+}
+
+/// Creates a pipeline with `subscribe`, and watches it.
+///
+/// `subscribe` creates the poller, which beats the given heartbeat on every successful poll.
+/// `interval` is its syncing interval.
+pub async fn watch<T, F, Fut>(
+    pipeline: &'static str,
+    interval: Duration,
+    config: &Watchdog,
+    subscribe: F,
+) -> Eventual<T>
+where
+    T: Value,
+    F: Fn(Heartbeat) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Eventual<T>> + Send + 'static,
+{
+    let heartbeat = Heartbeat::new();
+    let source = subscribe(heartbeat.clone()).await;
+    let (writer, eventual) = Eventual::new();
+    let writer = Arc::new(Mutex::new(writer));
+    let relay = relay(source, writer.clone());
+
+    supervise(
+        pipeline,
+        interval,
+        config,
+        heartbeat,
+        relay,
+        move |heartbeat| {
+            let source = subscribe(heartbeat);
+            let writer = writer.clone();
+            async move { self::relay(source.await, writer) }
+        },
+    );
+    eventual
+}
+
+/// Same as [`watch`], for the pollers publishing on a [`watch::Receiver`].
+pub async fn watch_receiver<T, F, Fut>(
+    pipeline: &'static str,
+    interval: Duration,
+    config: &Watchdog,
+    subscribe: F,
+) -> watch::Receiver<T>
+where
+    T: PartialEq + Clone + Send + Sync + 'static,
+    F: Fn(Heartbeat) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = watch::Receiver<T>> + Send + 'static,
+{
+    let heartbeat = Heartbeat::new();
+    let source = subscribe(heartbeat.clone()).await;
+    let (sender, receiver) = watch::channel(source.borrow().clone());
+    let sender = Arc::new(sender);
+    let relay = relay_receiver(source, sender.clone());
+
+    supervise(
+        pipeline,
+        interval,
+        config,
+        heartbeat,
+        relay,
+        move |heartbeat| {
+            let source = subscribe(heartbeat);
+            let sender = sender.clone();
+            async move { relay_receiver(source.await, sender) }
+        },
+    );
+    receiver
+}
+
+/// Reports the pipeline as stale when `heartbeat` doesn't beat, and replaces its `relay` with
+/// the one returned by `resubscribe` if configured to. The new poller is created in a task, the
+/// pipeline is still watched while it waits for its first poll.
+fn supervise<F, Fut>(
+    pipeline: &'static str,
+    interval: Duration,
+    config: &Watchdog,
+    heartbeat: Heartbeat,
+    relay: JoinHandle<()>,
+    resubscribe: F,
+) where
+    F: Fn(Heartbeat) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = JoinHandle<()>> + Send + 'static,
+{
+    let stale_after = interval * config.stale_after_intervals;
+    let resubscribe_enabled = config.resubscribe;
+    tokio::spawn(async move {
+        let mut heartbeat = heartbeat;
+        let mut relay = relay;
+        let mut resubscription: Option<JoinHandle<(Heartbeat, JoinHandle<()>)>> = None;
+        let mut subscribed_at = Instant::now();
+        let mut stale = false;
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Some(pending) = resubscription.take_if(|pending| pending.is_finished()) {
+                match pending.await {
+                    Ok((new_heartbeat, new_relay)) => {
+                        info!(pipeline, "The poller of the pipeline was re-created");
+                        heartbeat.retire();
+                        relay.abort();
+                        heartbeat = new_heartbeat;
+                        relay = new_relay;
+                        PIPELINE_RESUBSCRIPTIONS
+                            .with_label_values(&[pipeline])
+                            .inc();
+                    }
+                    Err(err) => error!(pipeline, %err, "Failed to re-create the poller"),
+                }
+            }
+
+            let elapsed = heartbeat.elapsed();
+            PIPELINE_SECONDS_SINCE_POLL
+                .with_label_values(&[pipeline])
+                .set(elapsed.as_secs_f64());
+            if elapsed <= stale_after {
+                if stale {
+                    info!(pipeline, "Pipeline is updated again");
+                    PIPELINE_STALE.with_label_values(&[pipeline]).set(0);
+                    stale = false;
+                }
+                continue;
+            }
+
+            if !stale {
+                error!(
+                    pipeline,
+                    ?elapsed,
+                    "No successful poll of the pipeline for too long, its last value is used"
+                );
+                PIPELINE_STALE.with_label_values(&[pipeline]).set(1);
+                stale = true;
+            }
+            if resubscribe_enabled
+                && resubscription.is_none()
+                && subscribed_at.elapsed() > stale_after
+            {
+                warn!(pipeline, "Re-creating the poller of the stale pipeline");
+                let new_heartbeat = Heartbeat::new();
+                let new_relay = resubscribe(new_heartbeat.clone());
+                resubscription = Some(tokio::spawn(
+                    async move { (new_heartbeat, new_relay.await) },
+                ));
+                subscribed_at = Instant::now();
+            }
+        }
+    });
+}
+
+fn relay<T: Value>(source: Eventual<T>, writer: Arc<Mutex<EventualWriter<T>>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reader = source.subscribe();
+        while let Ok(value) = reader.next().await {
+            writer.lock().unwrap().write(value);
+        }
+    })
+}
+
+fn relay_receiver<T: PartialEq + Clone + Send + Sync + 'static>(
+    mut source: watch::Receiver<T>,
+    sender: Arc<watch::Sender<T>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let value = source.borrow_and_update().clone();
+            sender.send_if_modified(|current| {
+                let modified = *current != value;
+                *current = value;
+                modified
+            });
+            if source.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use eventuals::Eventual;
+    use indexer_common::prelude::Heartbeat;
+
+    use super::{watch, PIPELINE_RESUBSCRIPTIONS, PIPELINE_STALE};
+    use crate::config::Watchdog;
+
+    #[tokio::test]
+    async fn test_resubscribe_stale_pipeline() {
+        let subscriptions = Arc::new(AtomicU32::new(0));
+        let heartbeats = Arc::new(Mutex::new(Vec::new()));
+        let config = Watchdog {
+            stale_after_intervals: 2,
+            resubscribe: true,
+        };
+        let eventual = watch("test", Duration::from_millis(10), &config, {
+            let subscriptions = subscriptions.clone();
+            let heartbeats = heartbeats.clone();
+            move |heartbeat: Heartbeat| {
+                let subscription = subscriptions.fetch_add(1, Ordering::SeqCst);
+                heartbeats.lock().unwrap().push(heartbeat.clone());
+                async move {
+                    // Only the second poller isn't wedged
+                    if subscription == 1 {
+                        tokio::spawn(async move {
+                            loop {
+                                heartbeat.beat();
+                                tokio::time::sleep(Duration::from_millis(5)).await;
+                            }
+                        });
+                    }
+                    Eventual::from_value(subscription)
+                }
+            }
+        })
+        .await;
+        assert_eq!(eventual.value().await.unwrap(), 0);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(subscriptions.load(Ordering::SeqCst), 2);
+        assert_eq!(eventual.value().await.unwrap(), 1);
+        assert_eq!(PIPELINE_STALE.with_label_values(&["test"]).get(), 0);
+        assert_eq!(
+            PIPELINE_RESUBSCRIPTIONS.with_label_values(&["test"]).get(),
+            1.0
+        );
+        // Each poller has its own heartbeat, the one of the replaced poller is retired
+        let heartbeats = heartbeats.lock().unwrap();
+        assert!(heartbeats[0].is_retired());
+        assert!(!heartbeats[1].is_retired());
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks that the aggregator of a sender can be used, before real fees depend on it.
+//!
+//! The endpoint of the sender is called with its configured credentials:
+//! - for HTTPS endpoints, a TLS handshake reports the negotiated protocol and cipher suite;
+//! - `api_versions` reports the RPC versions of the aggregator, which must include the one
+//!   tap-agent uses, and its latency;
+//! - `aggregate_receipts` is called with a receipt of a throwaway signer for a throwaway
+//!   allocation. The aggregator normally rejects it, as the signer isn't one of the sender, and
+//!   names the signer it recovered from the receipt. That signer is the throwaway one only if the
+//!   aggregator uses the same EIP-712 domain as the agent. If the aggregator accepts the receipt
+//!   instead, the RAV is checked against the domain and the signers of the sender.
+
+use std::{
+    fmt,
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address, signers::local::PrivateKeySigner};
+use anyhow::{anyhow, bail, Context, Result};
+use indexer_common::prelude::escrow_accounts_watcher;
+use jsonrpsee::{
+    core::{client::ClientT, ClientError},
+    rpc_params,
+};
+use reqwest::Url;
+use rustls::{pki_types::ServerName, ClientConnection};
+use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use tap_core::{
+    rav::{ReceiptAggregateVoucher, SignedRAV},
+    receipt::Receipt,
+    signed_message::EIP712SignedMessage,
+};
+use tracing::{info, warn};
+
+use crate::{
+    agent::{
+        aggregator_client::{self, AggregatorClient},
+        escrow_subgraph_client,
+    },
+    config::AggregatorAuthConfig,
+    CONFIG, EIP_712_DOMAIN,
+};
+
+/// RPC version of the RAV requests of tap-agent.
+const RPC_VERSION: &str = "0.0";
+const PROBE_VALUE: u128 = 1;
+/// JSON-RPC error code of the aggregator rejecting the receipts to aggregate.
+const AGGREGATION_ERROR_CODE: i32 = -32001;
+const ESCROW_ACCOUNTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub endpoint: String,
+    /// Negotiated with the endpoint, if it's an HTTPS one.
+    pub tls: Option<TlsDetails>,
+    /// As returned by `api_versions`.
+    pub versions_supported: Vec<String>,
+    pub latency: Option<Duration>,
+    /// Whether the aggregator recovers the signers with the EIP-712 domain of the agent, if it
+    /// could be told.
+    pub domain_matches: Option<bool>,
+    /// Response of the aggregator to the probe receipt.
+    pub probe: Option<String>,
+    /// Everything that would prevent RAV requests from succeeding.
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct TlsDetails {
+    pub protocol: String,
+    pub cipher_suite: String,
+    /// Length of the certificate chain of the server.
+    pub certificates: usize,
+    pub client_certificate: bool,
+}
+
+impl fmt::Display for TlsDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {} server certificate(s), client certificate {}",
+            self.protocol,
+            self.cipher_suite,
+            self.certificates,
+            if self.client_certificate {
+                "sent"
+            } else {
+                "not configured"
+            }
+        )
+    }
+}
+
+/// Checks the aggregator of `sender` with the production configuration, and logs the report.
+/// Fails if any problem was found.
+pub async fn run(sender: Address) -> Result<()> {
+    let endpoint = CONFIG
+        .tap
+        .sender_aggregator_endpoints
+        .get(&sender)
+        .ok_or_else(|| anyhow!("No aggregator endpoint configured for sender {sender}"))?;
+    let signers = sender_signers(sender).await;
+    let report = check(
+        endpoint,
+        Duration::from_secs(CONFIG.tap.rav_request_timeout_secs),
+        CONFIG.tap.sender_aggregator_auth.get(&sender),
+        &EIP_712_DOMAIN,
+        signers.as_deref(),
+    )
+    .await;
+
+    info!(endpoint = report.endpoint, "Aggregator checked.");
+    match &report.tls {
+        Some(tls) => info!(%tls, "TLS"),
+        None => info!("TLS: none, or the handshake failed"),
+    }
+    info!(
+        versions_supported = ?report.versions_supported,
+        latency = ?report.latency,
+        "API versions"
+    );
+    match report.domain_matches {
+        Some(true) => info!("EIP-712 domain: matches"),
+        Some(false) => info!("EIP-712 domain: mismatch"),
+        None => info!("EIP-712 domain: couldn't be told from the aggregator response"),
+    }
+    if let Some(probe) = &report.probe {
+        info!(probe, "Probe receipt");
+    }
+    for problem in &report.problems {
+        warn!(problem);
+    }
+    if !report.problems.is_empty() {
+        bail!(
+            "Found {} problem(s) with the aggregator of sender {sender}",
+            report.problems.len()
+        );
+    }
+    Ok(())
+}
+
+/// Signers of `sender` in the escrow accounts, if they can be fetched in time.
+async fn sender_signers(sender: Address) -> Option<Vec<Address>> {
+    let escrow_subgraph = escrow_subgraph_client(&CONFIG, reqwest::Client::new());
+    let accounts = tokio::time::timeout(
+        ESCROW_ACCOUNTS_TIMEOUT,
+        escrow_accounts_watcher(
+            escrow_subgraph,
+            CONFIG.ethereum.indexer_address,
+            Duration::from_millis(CONFIG.escrow_subgraph.escrow_syncing_interval_ms),
+            false,
+            None,
+            None,
+        ),
+    )
+    .await;
+    match accounts {
+        Ok(accounts) => Some(accounts.borrow().get_signers_for_sender(&sender)),
+        Err(_) => {
+            warn!("Couldn't fetch the escrow accounts, the RAV signer won't be checked.");
+            None
+        }
+    }
+}
+
+/// Checks the aggregator at `endpoint`. `signers` are the signers of the sender, if known.
+pub async fn check(
+    endpoint: &str,
+    request_timeout: Duration,
+    auth: Option<&AggregatorAuthConfig>,
+    domain: &Eip712Domain,
+    signers: Option<&[Address]>,
+) -> Report {
+    let mut report = Report {
+        endpoint: endpoint.to_string(),
+        ..Default::default()
+    };
+    let client = match aggregator_client::build(endpoint, request_timeout, auth) {
+        Ok(client) => client,
+        Err(error) => {
+            report
+                .problems
+                .push(format!("Invalid endpoint or credentials: {error:#}"));
+            return report;
+        }
+    };
+
+    match Url::parse(endpoint) {
+        Ok(url) if url.scheme() == "https" => {
+            let auth = auth.cloned().unwrap_or_default();
+            let handshake =
+                tokio::task::spawn_blocking(move || tls_handshake(&url, &auth, request_timeout))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result);
+            match handshake {
+                Ok(tls) => report.tls = Some(tls),
+                Err(error) => report
+                    .problems
+                    .push(format!("TLS handshake failed: {error:#}")),
+            }
+        }
+        Ok(_) => {}
+        Err(error) => report.problems.push(format!("Invalid endpoint: {error}")),
+    }
+
+    let start = Instant::now();
+    let versions: Result<JsonRpcResponse<serde_json::Value>, _> =
+        client.request("api_versions", rpc_params!()).await;
+    match versions {
+        Ok(response) => {
+            report.latency = Some(start.elapsed());
+            report.versions_supported = response.data["versions_supported"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|version| version.as_str().map(str::to_string))
+                .collect();
+            if !report.versions_supported.iter().any(|v| v == RPC_VERSION) {
+                report.problems.push(format!(
+                    "The aggregator doesn't support the RPC version {RPC_VERSION} used by \
+                    tap-agent, it supports {:?}",
+                    report.versions_supported
+                ));
+            }
+        }
+        Err(error) => {
+            report
+                .problems
+                .push(format!("`api_versions` request failed: {error}"));
+            return report;
+        }
+    }
+
+    if let Err(error) = probe(&client, domain, signers, &mut report).await {
+        report
+            .problems
+            .push(format!("`aggregate_receipts` request failed: {error:#}"));
+    }
+    report
+}
+
+/// Sends a receipt of a throwaway signer to the aggregator.
+async fn probe(
+    client: &AggregatorClient,
+    domain: &Eip712Domain,
+    signers: Option<&[Address]>,
+    report: &mut Report,
+) -> Result<()> {
+    let wallet = PrivateKeySigner::random();
+    let receipt = Receipt {
+        allocation_id: PrivateKeySigner::random().address(),
+        timestamp_ns: SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        nonce: 0,
+        value: PROBE_VALUE,
+    };
+    let signed_receipt = EIP712SignedMessage::new(domain, receipt.clone(), &wallet)?;
+    let response: Result<JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>>, _> = client
+        .request(
+            "aggregate_receipts",
+            rpc_params!(RPC_VERSION, vec![signed_receipt], None::<SignedRAV>),
+        )
+        .await;
+    match response {
+        Ok(response) => {
+            let rav = response.data;
+            report.probe = Some(format!(
+                "aggregated into a RAV of value {}",
+                rav.message.valueAggregate
+            ));
+            if rav.message.allocationId != receipt.allocation_id
+                || rav.message.valueAggregate != PROBE_VALUE
+            {
+                report.problems.push(format!(
+                    "The RAV doesn't match the probe receipt: {:?}",
+                    rav.message
+                ));
+            }
+            let rav_signer = rav.recover_signer(domain)?;
+            match signers {
+                Some(signers) => {
+                    let matches = signers.contains(&rav_signer);
+                    report.domain_matches = Some(matches);
+                    if !matches {
+                        report.problems.push(format!(
+                            "The RAV signer recovered with the agent's EIP-712 domain, \
+                            {rav_signer}, isn't a signer of the sender: the domains or the \
+                            aggregator key differ"
+                        ));
+                    }
+                }
+                None => warn!(%rav_signer, "Unverified RAV signer."),
+            }
+            Ok(())
+        }
+        Err(ClientError::Call(error)) if error.code() == AGGREGATION_ERROR_CODE => {
+            // Rejected by the aggregator, which names the signer it recovered.
+            let message = error.message();
+            let recovered: Vec<Address> = addresses(message)
+                .filter(|address| *address != receipt.allocation_id)
+                .collect();
+            if recovered.contains(&wallet.address()) {
+                report.domain_matches = Some(true);
+            } else if !recovered.is_empty() {
+                report.domain_matches = Some(false);
+                report.problems.push(format!(
+                    "The aggregator recovered another signer than the one of the probe receipt \
+                    ({}), its EIP-712 domain differs from the agent's: {message}",
+                    wallet.address()
+                ));
+            }
+            report.probe = Some(format!(
+                "rejected, as expected for a throwaway signer: {message}"
+            ));
+            Ok(())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// The addresses written in hex in `message`, not the prefixes of longer hex strings.
+fn addresses(message: &str) -> impl Iterator<Item = Address> + '_ {
+    message
+        .match_indices("0x")
+        .filter(|(start, _)| {
+            message
+                .get(start + 42..)
+                .and_then(|rest| rest.chars().next())
+                .map_or(true, |next| !next.is_ascii_hexdigit())
+        })
+        .filter_map(|(start, _)| message.get(start..start + 42))
+        .filter_map(|hex| hex.parse().ok())
+}
+
+fn tls_handshake(url: &Url, auth: &AggregatorAuthConfig, timeout: Duration) -> Result<TlsDetails> {
+    let host = url.host_str().context("The endpoint has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let server_name = ServerName::try_from(host.to_string())?;
+    let config = aggregator_client::tls_config(auth)?;
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)?;
+    let mut socket = TcpStream::connect((host, port))
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket)?;
+    }
+    let details = TlsDetails {
+        protocol: connection
+            .protocol_version()
+            .map_or_else(|| "unknown".to_string(), |version| format!("{version:?}")),
+        cipher_suite: connection.negotiated_cipher_suite().map_or_else(
+            || "unknown".to_string(),
+            |suite| format!("{:?}", suite.suite()),
+        ),
+        certificates: connection.peer_certificates().map_or(0, <[_]>::len),
+        client_certificate: auth.client_cert_path.is_some(),
+    };
+    connection.send_close_notify();
+    let _ = connection.complete_io(&mut socket);
+    Ok(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use alloy::primitives::Address;
+    use indexer_test_harness::Aggregator;
+    use tap_core::tap_eip712_domain;
+
+    use super::{addresses, check};
+    use crate::tap::test_utils::{SIGNER, TAP_EIP712_DOMAIN_SEPARATOR};
+
+    #[test]
+    fn test_addresses() {
+        let signer = Address::from([0x11u8; 20]);
+        let message = format!("Recovered signer {signer} is not in accepted addresses: 0x12");
+        assert_eq!(addresses(&message).collect::<Vec<_>>(), vec![signer]);
+        assert_eq!(addresses("No address, 0x").count(), 0);
+        let hash = format!("{}", alloy::primitives::B256::repeat_byte(0x22));
+        assert_eq!(addresses(&hash).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_aggregator() {
+        let aggregator = Aggregator::start(
+            SIGNER.0.clone(),
+            HashSet::from([SIGNER.1]),
+            TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+        )
+        .await
+        .unwrap();
+        let endpoint = aggregator.endpoint().to_string();
+
+        let report = check(
+            &endpoint,
+            Duration::from_secs(5),
+            None,
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            None,
+        )
+        .await;
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(report.versions_supported.contains(&"0.0".to_string()));
+        assert!(report.latency.is_some());
+        assert!(report.tls.is_none());
+        assert_eq!(report.domain_matches, Some(true));
+
+        let other_domain = tap_eip712_domain(2, Address::from([0x33u8; 20]));
+        let report = check(&endpoint, Duration::from_secs(5), None, &other_domain, None).await;
+        assert_eq!(report.domain_matches, Some(false));
+        assert_eq!(report.problems.len(), 1);
+
+        aggregator.stop().await;
+        let report = check(
+            &endpoint,
+            Duration::from_secs(1),
+            None,
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            None,
+        )
+        .await;
+        assert!(report.latency.is_none());
+        assert_eq!(report.problems.len(), 1);
+    }
+}
@@ -2,22 +2,216 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
-use clap::Parser;
-use indexer_config::{Config as IndexerConfig, ConfigPrefix};
+use clap::{Parser, Subcommand};
+use indexer_common::{
+    listener::Listener,
+    prelude::{
+        EscrowRpcFallback, LocalFailoverConfig, QueryCacheConfig, QueryPolicy, SubscriptionConfig,
+    },
+};
+pub use indexer_config::{
+    AggregatorAuthConfig, AllocationEvent, DeniedSenderReceipts, FeeUnit, MetricsBackend,
+    ReceiptCompactionMode,
+};
+use indexer_config::{
+    Config as IndexerConfig, ConfigPrefix, LogFormat, Profile, SubgraphCacheConfig,
+    SubgraphLocalFailoverConfig, SubgraphQueryPolicyConfig, SubgraphSubscriptionConfig,
+};
 use reqwest::Url;
+use sqlx::types::chrono::{DateTime, Utc};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
-use std::{collections::HashMap, str::FromStr};
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 use thegraph_core::{Address, DeploymentId};
-use tracing::subscriber::{set_global_default, SetGlobalDefaultError};
+use tracing::subscriber::set_global_default;
 use tracing::{error, level_filters::LevelFilter};
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer};
+
+use crate::{
+    accounting_export::{ExportFormat, ExportKind},
+    telemetry,
+};
 
 #[derive(Parser)]
 pub struct Cli {
     /// Path to the configuration file.
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/tap-agent for examples.
-    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    #[arg(long, value_name = "FILE", global = true, verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+    /// Network profile, providing the chain id, TAP verifier and gateway aggregators.
+    /// One of `mainnet`, `testnet` or `local-dev`.
+    /// The configuration file can override its values, except for the chain id.
+    #[arg(long, value_name = "PROFILE", global = true, verbatim_doc_comment)]
+    pub profile: Option<Profile>,
+
+    /// `run` if not set.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run tap-agent.
+    Run,
+    /// Load the configuration and check it, without connecting to anything.
+    ValidateConfig,
+    /// Print a summary of the receipts, RAVs, denied senders and failed RAV requests stored in
+    /// the database, as JSON.
+    #[command(verbatim_doc_comment)]
+    Inspect {
+        /// Only summarize this allocation.
+        #[arg(long)]
+        allocation: Option<Address>,
+    },
+    /// Send the notifications of the stored receipts again, so that a running tap-agent accounts
+    /// for the ones it missed. The receipts it already accounted for are ignored.
+    #[command(verbatim_doc_comment)]
+    Replay {
+        /// Only replay the receipts with a greater id.
+        #[arg(long, default_value_t = 0)]
+        after_id: i64,
+        /// Number of notifications sent per transaction.
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+        /// Only replay the receipts of this signer.
+        #[arg(long)]
+        signer: Option<Address>,
+        /// Replay the Horizon receipts rather than the legacy ones.
+        #[arg(long)]
+        horizon: bool,
+    },
+    /// Copy receipts and RAVs stored with a legacy schema into the current TAP tables.
+    /// Can be run while indexer-service is serving queries, and resumed if interrupted.
+    #[command(verbatim_doc_comment)]
+    MigrateLegacySchema {
+        /// Only report what would be migrated.
+        #[arg(long)]
+        dry_run: bool,
+        /// Number of rows copied per transaction.
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+    },
+    /// Send a synthetic receipt for a throwaway allocation through validation, fee tracking,
+    /// an in-process aggregator and RAV storage, then remove it from the database.
+    /// Uses the production configuration and database. A running tap-agent will log that the
+    /// receipt's signer is unknown, which is expected.
+    #[command(verbatim_doc_comment)]
+    SelfTest,
+    /// Check that the aggregator of a sender is reachable with the configured credentials,
+    /// supports the RPC version of tap-agent and uses the same EIP-712 domain, before fees
+    /// depend on it. Reports the TLS details and latency of the endpoint.
+    #[command(verbatim_doc_comment)]
+    CheckAggregator {
+        /// Address of the sender, as in `tap.sender_aggregator_endpoints`.
+        #[arg(long)]
+        sender: Address,
+    },
+    /// Write the receipts, RAVs and denylist of the indexer to an archive, to move it to another
+    /// database with `import-state`. Best run while tap-agent is stopped.
+    #[command(verbatim_doc_comment)]
+    ExportState {
+        /// Path of the archive.
+        #[arg(long, value_name = "FILE")]
+        output: PathBuf,
+        /// Compress the archive with zstd at this level, from 1 (fastest) to 22 (smallest).
+        /// `import-state` detects compressed archives.
+        #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+        compression_level: Option<i32>,
+    },
+    /// Load an archive written by `export-state` into an empty database, after checking its
+    /// integrity and that it was exported for the same indexer, chain and TAP verifiers.
+    #[command(verbatim_doc_comment)]
+    ImportState {
+        /// Path of the archive.
+        #[arg(long, value_name = "FILE")]
+        input: PathBuf,
+    },
+    /// Write the RAVs, or a daily summary of the receipts not aggregated yet, of a time range as
+    /// CSV or JSON lines, for revenue accounting. Rows are streamed as they're read.
+    #[command(verbatim_doc_comment)]
+    ExportAccounting {
+        #[arg(long, value_enum)]
+        kind: ExportKind,
+        /// Start of the range, inclusive, as RFC 3339 (e.g. `2024-12-01T00:00:00Z`).
+        #[arg(long)]
+        from: DateTime<Utc>,
+        /// End of the range, exclusive, as RFC 3339.
+        #[arg(long)]
+        to: DateTime<Utc>,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Written to stdout if unset.
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Replay the stored receipts of a sender over a time range through the fee tracking, RAV
+    /// triggers and deny condition, and print the RAV requests and denials that would have
+    /// happened, as JSON. Nothing is written to the database and no aggregator is called.
+    #[command(verbatim_doc_comment)]
+    Simulate {
+        /// Address of the sender.
+        #[arg(long)]
+        sender: Address,
+        /// Start of the range, inclusive, as RFC 3339 (e.g. `2024-12-01T00:00:00Z`).
+        #[arg(long)]
+        from: DateTime<Utc>,
+        /// End of the range, exclusive, as RFC 3339.
+        #[arg(long)]
+        to: DateTime<Utc>,
+        /// Signer of the sender, can be repeated. The current signers of the sender in the
+        /// escrow accounts if unset.
+        #[arg(long = "signer")]
+        signers: Vec<Address>,
+    },
+    /// Maintain the TAP tables, instead of running SQL against them by hand.
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Not supported, the statistics are read from the TAP tables when asked, see `db stats`.
+    /// Kept hidden so that it fails with that explanation rather than as an unknown subcommand.
+    #[command(hide = true, verbatim_doc_comment)]
+    BackfillStats,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Check the receipts against the RAVs of their sender, and print the issues found as JSON.
+    /// Fails if there's any.
+    #[command(verbatim_doc_comment)]
+    Check,
+    /// Set aside the receipts covered by the final RAVs, as `tap.receipt_compaction` does.
+    /// Uses its mode and batch size, archiving them by default.
+    #[command(verbatim_doc_comment)]
+    Prune {
+        /// Only print what would be set aside, as JSON.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Deny a sender, after warning about the fees it still owes.
+    Deny {
+        #[arg(long)]
+        sender: Address,
+        /// Note for the other operators, stored with the deny.
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Allow a denied sender. Refused if it has unaggregated receipts or RAVs not redeemed yet,
+    /// unless forced.
+    #[command(verbatim_doc_comment)]
+    Allow {
+        #[arg(long)]
+        sender: Address,
+        /// Allow it even with pending fees, which may lose them.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the number of rows and size of the TAP tables, as JSON.
+    Stats,
 }
 
 impl From<IndexerConfig> for Config {
@@ -29,20 +223,57 @@ impl From<IndexerConfig> for Config {
             receipts: Receipts {
                 receipts_verifier_chain_id: value.blockchain.chain_id as u64,
                 receipts_verifier_address: value.blockchain.receipts_verifier_address,
+                receipts_verifier_address_v2: value.blockchain.receipts_verifier_address_v2,
             },
             indexer_infrastructure: IndexerInfrastructure {
                 metrics_port: value.metrics.port,
+                metrics_listener: value.metrics.listener,
                 graph_node_query_endpoint: value.graph_node.query_url.into(),
                 graph_node_status_endpoint: value.graph_node.status_url.into(),
                 log_level: None,
+                log_format: value.logging.format,
+                otlp_endpoint: value.tracing.otlp_endpoint.map(Into::into),
+                trace_sampling_ratio: value.tracing.sampling_ratio,
+                receipt_profiling_ratio: value.metrics.receipt_profiling_ratio,
+                metrics_export: value.metrics.export.map(|export| MetricsExport {
+                    backend: export.backend,
+                    interval: export.interval_secs,
+                }),
             },
             postgres: Postgres {
                 postgres_url: value.database.get_formated_postgres_url(),
+                sender_account_pool: PoolSize {
+                    min_connections: value.tap.database_pools.sender_account.min_connections,
+                    max_connections: value.tap.database_pools.sender_account.max_connections,
+                },
+                sender_allocation_pool: PoolSize {
+                    min_connections: value.tap.database_pools.sender_allocation.min_connections,
+                    max_connections: value.tap.database_pools.sender_allocation.max_connections,
+                },
+                adaptive_pool_latency_threshold: value
+                    .tap
+                    .database_pools
+                    .adaptive
+                    .then_some(value.tap.database_pools.adaptive_latency_threshold_ms),
+                max_concurrent_startup_scans: value.tap.database_pools.max_concurrent_startup_scans,
+                secrets_refresh_interval: value.secrets.refresh_interval_secs,
+                read_replica_url: value.tap.database_pools.read_replica_url,
+                read_replica_max_lag: value.tap.database_pools.read_replica_max_lag_secs,
             },
             network_subgraph: NetworkSubgraph {
                 network_subgraph_deployment: value.subgraphs.network.config.deployment_id,
                 network_subgraph_endpoint: value.subgraphs.network.config.query_url.into(),
                 network_subgraph_auth_token: value.subgraphs.network.config.query_auth_token,
+                network_subgraph_query_policy: query_policy(
+                    value.subgraphs.network.config.query_policy,
+                ),
+                network_subgraph_cache: query_cache(value.subgraphs.network.config.cache),
+                network_subgraph_local_failover: local_failover(
+                    value.subgraphs.network.config.local_failover,
+                ),
+                network_subgraph_subscription: subscription(
+                    value.subgraphs.network.config.subscription,
+                ),
                 allocation_syncing_interval_ms: value
                     .subgraphs
                     .network
@@ -54,17 +285,40 @@ impl From<IndexerConfig> for Config {
                     .network
                     .recently_closed_allocation_buffer_secs
                     .as_secs(),
+                allocation_deployments: value
+                    .subgraphs
+                    .network
+                    .allocation_deployments
+                    .map(|deployments| deployments.into_iter().collect()),
             },
             escrow_subgraph: EscrowSubgraph {
                 escrow_subgraph_deployment: value.subgraphs.escrow.config.deployment_id,
                 escrow_subgraph_endpoint: value.subgraphs.escrow.config.query_url.into(),
                 escrow_subgraph_auth_token: value.subgraphs.escrow.config.query_auth_token,
+                escrow_subgraph_query_policy: query_policy(
+                    value.subgraphs.escrow.config.query_policy,
+                ),
+                escrow_subgraph_cache: query_cache(value.subgraphs.escrow.config.cache),
+                escrow_subgraph_local_failover: local_failover(
+                    value.subgraphs.escrow.config.local_failover,
+                ),
+                escrow_subgraph_subscription: subscription(
+                    value.subgraphs.escrow.config.subscription,
+                ),
                 escrow_syncing_interval_ms: value
                     .subgraphs
                     .escrow
                     .config
                     .syncing_interval_secs
                     .as_millis() as u64,
+                escrow_rpc_fallback: value.subgraphs.escrow.rpc_fallback.map(|rpc_fallback| {
+                    EscrowRpcFallback {
+                        rpc_url: rpc_fallback.rpc_url,
+                        escrow_address: rpc_fallback.escrow_address,
+                        max_subgraph_lag: rpc_fallback.max_subgraph_lag_secs,
+                        cross_check: rpc_fallback.cross_check,
+                    }
+                }),
             },
             tap: Tap {
                 rav_request_trigger_value: value.tap.get_trigger_value(),
@@ -74,17 +328,130 @@ impl From<IndexerConfig> for Config {
                     .timestamp_buffer_secs
                     .as_millis() as u64,
                 rav_request_timeout_secs: value.tap.rav_request.request_timeout_secs.as_secs(),
+                rav_request_retry_interval: value.tap.rav_request.retry_interval_secs,
                 sender_aggregator_endpoints: value
                     .tap
                     .sender_aggregator_endpoints
                     .into_iter()
                     .map(|(addr, url)| (addr, url.into()))
                     .collect(),
+                sender_aggregator_auth: value.tap.sender_aggregator_auth,
                 rav_request_receipt_limit: value.tap.rav_request.max_receipts_per_request,
+                closing_allocation_buffer_epochs: value
+                    .tap
+                    .rav_request
+                    .closing_allocation_buffer_epochs,
+                rav_checkpoint_interval: (value.tap.rav_request.checkpoint_interval_days > 0).then(
+                    || {
+                        Duration::from_secs(
+                            value.tap.rav_request.checkpoint_interval_days * 24 * 60 * 60,
+                        )
+                    },
+                ),
+                delete_receipts_with_rav: value.tap.rav_request.delete_receipts_with_rav,
+                rav_value_tolerance: value.tap.rav_request.value_tolerance,
+                denylist_outbox_path: value.tap.denylist_outbox_path,
+                denied_sender_receipts: value.tap.denied_sender_receipts,
+                sharding: value.tap.sharding.enabled.then(|| Sharding {
+                    instance_id: value
+                        .tap
+                        .sharding
+                        .instance_id
+                        .unwrap_or_else(generated_instance_id),
+                    lease_duration: value.tap.sharding.lease_duration_secs,
+                }),
+                capacity_planning: CapacityPlanning {
+                    retention_days: value.tap.capacity_planning.retention_days,
+                    interval: value.tap.capacity_planning.interval_secs,
+                },
+                escrow_top_up: EscrowTopUp {
+                    horizon: value.tap.escrow_top_up.horizon_secs,
+                    velocity_window: value.tap.escrow_top_up.velocity_window_secs,
+                },
+                receipt_compaction: value.tap.receipt_compaction.enabled.then(|| {
+                    ReceiptCompaction {
+                        mode: value.tap.receipt_compaction.mode,
+                        interval: value.tap.receipt_compaction.interval_secs,
+                        batch_size: value.tap.receipt_compaction.batch_size,
+                        dry_run: value.tap.receipt_compaction.dry_run,
+                    }
+                }),
+                watchdog: Watchdog {
+                    stale_after_intervals: value.tap.watchdog.stale_after_intervals,
+                    resubscribe: value.tap.watchdog.resubscribe,
+                },
+                supervision: Supervision {
+                    max_allocation_restarts: value.tap.supervision.max_allocation_restarts,
+                    restart_window: value.tap.supervision.restart_window_secs,
+                },
+                startup: Startup {
+                    database_timeout: value.tap.startup.database_timeout_secs,
+                    schema_timeout: value.tap.startup.schema_timeout_secs,
+                    escrow_accounts_timeout: value.tap.startup.escrow_accounts_timeout_secs,
+                    sender_accounts_timeout: value.tap.startup.sender_accounts_timeout_secs,
+                },
+                trust_score: TrustScore {
+                    window: value.tap.trust_score.window_secs,
+                    deny_below: value.tap.trust_score.deny_below,
+                },
+                rav_anomalies: RavAnomalies {
+                    window: value.tap.rav_anomalies.window_secs,
+                    z_score_threshold: value.tap.rav_anomalies.z_score_threshold,
+                },
+                rate_limits: value.tap.rate_limits.enabled.then(|| RateLimits {
+                    interval: value.tap.rate_limits.interval_secs,
+                    horizon: value.tap.rate_limits.horizon_secs,
+                    velocity_multiplier: value.tap.rate_limits.velocity_multiplier,
+                    burst: value.tap.rate_limits.burst_secs,
+                }),
+                receipt_sampling: (!value.tap.receipt_sampling.service_metrics_urls.is_empty())
+                    .then(|| ReceiptSampling {
+                        service_metrics_urls: value.tap.receipt_sampling.service_metrics_urls,
+                        interval: value.tap.receipt_sampling.interval_secs,
+                        tolerance: value.tap.receipt_sampling.tolerance,
+                    }),
                 max_unnaggregated_fees_per_sender: value
                     .tap
                     .max_amount_willing_to_lose_grt
                     .get_value(),
+                horizon_enabled: value.horizon.enabled,
+                dips: value.dips.enabled.then(|| Dips {
+                    verifier_address: value
+                        .blockchain
+                        .dips_verifier_address
+                        .expect("validated to be set when DIPS is enabled"),
+                    trigger_value: value.dips.trigger_value_grt.get_value(),
+                    max_voucher_age: value.dips.max_voucher_age_secs,
+                    interval: value.dips.interval_secs,
+                }),
+                admin_listener: value.tap.admin_listener,
+                signed_status: value.tap.signed_status.map(|signed_status| SignedStatus {
+                    operator_mnemonic: value
+                        .indexer
+                        .operator_mnemonic
+                        .as_ref()
+                        .expect("validated to be set with `tap.signed_status`")
+                        .to_string(),
+                    heartbeat_interval: signed_status.heartbeat_interval_secs,
+                }),
+                sender_api: value.tap.sender_api.map(|sender_api| SenderApi {
+                    listener: sender_api.listener,
+                    max_request_age: sender_api.max_request_age_secs,
+                }),
+                lifecycle_hooks: value.tap.lifecycle_hooks.map(|hooks| LifecycleHooks {
+                    command: hooks.command,
+                    url: hooks.url,
+                    events: hooks.events.map(|events| events.into_iter().collect()),
+                    timeout: hooks.timeout_secs,
+                }),
+                fee_display: FeeDisplay {
+                    api_unit: value.tap.fee_display.api_unit,
+                    usd_price: value.tap.fee_display.usd_price.map(|usd_price| UsdPrice {
+                        url: usd_price.url,
+                        json_pointer: usd_price.json_pointer,
+                        interval: usd_price.interval_secs,
+                    }),
+                },
             },
             config: None,
         }
@@ -112,36 +479,90 @@ pub struct Ethereum {
 pub struct Receipts {
     pub receipts_verifier_chain_id: u64,
     pub receipts_verifier_address: Address,
+    pub receipts_verifier_address_v2: Option<Address>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct IndexerInfrastructure {
     pub metrics_port: u16,
+    /// Served on `0.0.0.0:metrics_port` if unset.
+    pub metrics_listener: Option<Listener>,
     pub graph_node_query_endpoint: String,
     pub graph_node_status_endpoint: String,
     pub log_level: Option<String>,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    pub trace_sampling_ratio: f64,
+    /// See [`crate::agent::receipt_profiler`].
+    pub receipt_profiling_ratio: f64,
+    /// See [`crate::metrics::export`].
+    pub metrics_export: Option<MetricsExport>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MetricsExport {
+    pub backend: MetricsBackend,
+    pub interval: Duration,
 }
 
 #[derive(Clone, Debug)]
 pub struct Postgres {
     pub postgres_url: Url,
+    pub sender_account_pool: PoolSize,
+    pub sender_allocation_pool: PoolSize,
+    /// Set if the pools grow from their minimum size when RAV-related queries get slower than
+    /// this.
+    pub adaptive_pool_latency_threshold: Option<Duration>,
+    /// See [`crate::agent::startup_scans`].
+    pub max_concurrent_startup_scans: usize,
+    /// Set if the Postgres URL is reloaded to follow its rotation, see
+    /// [`indexer_common::secrets`].
+    pub secrets_refresh_interval: Option<Duration>,
+    /// Read-only replica for the heavy reads, see [`crate::database::read_pool`].
+    pub read_replica_url: Option<Url>,
+    pub read_replica_max_lag: Duration,
 }
 
 impl Default for Postgres {
     fn default() -> Self {
         Self {
             postgres_url: Url::from_str("postgres:://postgres@postgres/postgres").unwrap(),
+            sender_account_pool: PoolSize {
+                min_connections: 1,
+                max_connections: 10,
+            },
+            sender_allocation_pool: PoolSize {
+                min_connections: 5,
+                max_connections: 40,
+            },
+            adaptive_pool_latency_threshold: None,
+            max_concurrent_startup_scans: 4,
+            secrets_refresh_interval: None,
+            read_replica_url: None,
+            read_replica_max_lag: Duration::from_secs(5),
         }
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct PoolSize {
+    pub min_connections: u32,
+    pub max_connections: u32,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct NetworkSubgraph {
     pub network_subgraph_deployment: Option<DeploymentId>,
     pub network_subgraph_endpoint: String,
     pub network_subgraph_auth_token: Option<String>,
+    pub network_subgraph_query_policy: QueryPolicy,
+    pub network_subgraph_cache: Option<QueryCacheConfig>,
+    pub network_subgraph_local_failover: Option<LocalFailoverConfig>,
+    pub network_subgraph_subscription: Option<SubscriptionConfig>,
     pub allocation_syncing_interval_ms: u64,
     pub recently_closed_allocation_buffer_seconds: u64,
+    /// Only the allocations of these deployments are tracked if set.
+    pub allocation_deployments: Option<HashSet<DeploymentId>>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -149,7 +570,12 @@ pub struct EscrowSubgraph {
     pub escrow_subgraph_deployment: Option<DeploymentId>,
     pub escrow_subgraph_endpoint: String,
     pub escrow_subgraph_auth_token: Option<String>,
+    pub escrow_subgraph_query_policy: QueryPolicy,
+    pub escrow_subgraph_cache: Option<QueryCacheConfig>,
+    pub escrow_subgraph_local_failover: Option<LocalFailoverConfig>,
+    pub escrow_subgraph_subscription: Option<SubscriptionConfig>,
     pub escrow_syncing_interval_ms: u64,
+    pub escrow_rpc_fallback: Option<EscrowRpcFallback>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -157,55 +583,346 @@ pub struct Tap {
     pub rav_request_trigger_value: u128,
     pub rav_request_timestamp_buffer_ms: u64,
     pub rav_request_timeout_secs: u64,
+    /// Delay before retrying a RAV request while the sender is denied.
+    pub rav_request_retry_interval: Duration,
     pub sender_aggregator_endpoints: HashMap<Address, String>,
+    /// Credentials for the aggregators of the senders that require them.
+    pub sender_aggregator_auth: HashMap<Address, AggregatorAuthConfig>,
     pub rav_request_receipt_limit: u64,
+    pub closing_allocation_buffer_epochs: u64,
+    /// Set if the fees unaggregated for this long get a checkpoint RAV, see
+    /// [`crate::agent::sender_account`].
+    pub rav_checkpoint_interval: Option<Duration>,
+    pub delete_receipts_with_rav: bool,
+    /// How much the value of a RAV may exceed the sum of its receipts, relative to the sum.
+    pub rav_value_tolerance: f64,
+    pub denylist_outbox_path: Option<PathBuf>,
+    pub denied_sender_receipts: DeniedSenderReceipts,
+    /// Set if the senders are shared with other tap-agents, see [`crate::agent::sender_leases`].
+    pub sharding: Option<Sharding>,
+    /// Set if the receipts are compared to the queries served, see
+    /// [`crate::agent::receipt_sampling`].
+    pub receipt_sampling: Option<ReceiptSampling>,
+    /// See [`crate::agent::capacity_planning`].
+    pub capacity_planning: CapacityPlanning,
+    /// See [`crate::agent::escrow_top_up`].
+    pub escrow_top_up: EscrowTopUp,
+    /// Set if the receipts covered by a final RAV are set aside, see
+    /// [`crate::agent::receipt_compaction`].
+    pub receipt_compaction: Option<ReceiptCompaction>,
+    /// See [`crate::agent::watchdog`].
+    pub watchdog: Watchdog,
+    /// See [`crate::agent::quarantine`].
+    pub supervision: Supervision,
+    /// See [`crate::startup`].
+    pub startup: Startup,
+    /// See [`crate::agent::trust_score`].
+    pub trust_score: TrustScore,
+    /// See [`crate::agent::rav_anomalies`].
+    pub rav_anomalies: RavAnomalies,
+    /// Set if the senders' rate limits are published, see [`crate::agent::rate_limits`].
+    pub rate_limits: Option<RateLimits>,
     pub max_unnaggregated_fees_per_sender: u128,
+    pub horizon_enabled: bool,
+    /// Set if the indexing fee vouchers are aggregated, see [`crate::agent::dips`].
+    pub dips: Option<Dips>,
+    /// The admin and status endpoints are served with the metrics if unset.
+    pub admin_listener: Option<Listener>,
+    /// See [`crate::signed_status`].
+    pub signed_status: Option<SignedStatus>,
+    /// See [`crate::agent::lifecycle_hooks`].
+    pub lifecycle_hooks: Option<LifecycleHooks>,
+    /// See [`crate::sender_api`].
+    pub sender_api: Option<SenderApi>,
+    /// See [`crate::money`].
+    pub fee_display: FeeDisplay,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeDisplay {
+    pub api_unit: FeeUnit,
+    pub usd_price: Option<UsdPrice>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UsdPrice {
+    pub url: Url,
+    pub json_pointer: String,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct SenderApi {
+    pub listener: Listener,
+    pub max_request_age: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct LifecycleHooks {
+    pub command: Option<Vec<String>>,
+    pub url: Option<Url>,
+    /// All of them if unset.
+    pub events: Option<HashSet<AllocationEvent>>,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sharding {
+    pub instance_id: String,
+    pub lease_duration: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Dips {
+    /// Verifier of the vouchers and their RAVs.
+    pub verifier_address: Address,
+    pub trigger_value: u128,
+    pub max_voucher_age: Duration,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceiptSampling {
+    pub service_metrics_urls: Vec<Url>,
+    pub interval: Duration,
+    pub tolerance: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CapacityPlanning {
+    pub retention_days: u32,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EscrowTopUp {
+    pub horizon: Duration,
+    pub velocity_window: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceiptCompaction {
+    pub mode: ReceiptCompactionMode,
+    pub interval: Duration,
+    pub batch_size: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    pub stale_after_intervals: u32,
+    pub resubscribe: bool,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            stale_after_intervals: 5,
+            resubscribe: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Supervision {
+    pub max_allocation_restarts: u32,
+    pub restart_window: Duration,
+}
+
+impl Default for Supervision {
+    fn default() -> Self {
+        Self {
+            max_allocation_restarts: 5,
+            restart_window: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SignedStatus {
+    pub operator_mnemonic: String,
+    pub heartbeat_interval: Duration,
+}
+
+impl std::fmt::Debug for SignedStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedStatus")
+            .field("operator_mnemonic", &"<redacted>")
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Startup {
+    pub database_timeout: Duration,
+    pub schema_timeout: Duration,
+    pub escrow_accounts_timeout: Duration,
+    pub sender_accounts_timeout: Duration,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Self {
+            database_timeout: Duration::from_secs(30),
+            schema_timeout: Duration::from_secs(10),
+            escrow_accounts_timeout: Duration::from_secs(120),
+            sender_accounts_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TrustScore {
+    pub window: Duration,
+    /// Senders scoring below are denied, if set.
+    pub deny_below: Option<f64>,
+}
+
+impl Default for TrustScore {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(3600),
+            deny_below: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RavAnomalies {
+    pub window: Duration,
+    pub z_score_threshold: f64,
+}
+
+impl Default for RavAnomalies {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(86400),
+            z_score_threshold: 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    pub interval: Duration,
+    pub horizon: Duration,
+    pub velocity_multiplier: f64,
+    pub burst: Duration,
+}
+
+/// The retries, timeout and circuit breaker of the queries to a subgraph.
+fn query_policy(config: SubgraphQueryPolicyConfig) -> QueryPolicy {
+    QueryPolicy {
+        max_retries: config.max_retries,
+        initial_backoff: config.initial_backoff_secs,
+        max_backoff: config.max_backoff_secs,
+        timeout: config.timeout_secs,
+        circuit_breaker_failures: config.circuit_breaker_failures,
+        circuit_breaker_cooldown: config.circuit_breaker_cooldown_secs,
+    }
+}
+
+fn query_cache(config: Option<SubgraphCacheConfig>) -> Option<QueryCacheConfig> {
+    config.map(|cache| QueryCacheConfig {
+        max_entries: NonZeroUsize::new(cache.max_entries)
+            .expect("`cache.max_entries` is validated to be non-zero"),
+        ttl: cache.ttl_secs,
+        max_stale: cache.max_stale_secs,
+    })
+}
+
+fn local_failover(config: Option<SubgraphLocalFailoverConfig>) -> Option<LocalFailoverConfig> {
+    config.map(|failover| LocalFailoverConfig {
+        max_lag_blocks: failover.max_lag_blocks,
+        check_interval: failover.check_interval_secs,
+    })
+}
+
+fn subscription(config: Option<SubgraphSubscriptionConfig>) -> Option<SubscriptionConfig> {
+    config.map(|subscription| SubscriptionConfig {
+        url: subscription.url.to_string(),
+        max_reconnect_backoff: subscription.max_reconnect_backoff_secs,
+    })
+}
+
+/// Unique enough to tell apart the tap-agents sharing a database, even on the same host.
+fn generated_instance_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "tap-agent".to_string());
+    let started_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{host}-{}-{started_at}", std::process::id())
 }
 
 /// Sets up tracing, allows log level to be set from the environment variables
-fn init_tracing(format: String) -> Result<(), SetGlobalDefaultError> {
+fn init_tracing(infrastructure: &IndexerInfrastructure) -> Result<()> {
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
-    let subscriber_builder: tracing_subscriber::fmt::SubscriberBuilder<
-        tracing_subscriber::fmt::format::DefaultFields,
-        tracing_subscriber::fmt::format::Format,
-        EnvFilter,
-    > = FmtSubscriber::builder().with_env_filter(filter);
-    match format.as_str() {
-        "json" => set_global_default(subscriber_builder.json().finish()),
-        "full" => set_global_default(subscriber_builder.finish()),
-        "compact" => set_global_default(subscriber_builder.compact().finish()),
-        _ => set_global_default(subscriber_builder.with_ansi(true).pretty().finish()),
-    }
+    let fmt_layer = tracing_subscriber::fmt::layer();
+    let fmt_layer = match infrastructure.log_format {
+        // Only the innermost span is kept, so that `sender`, `allocation` and
+        // `correlation_id` always end up under the same keys.
+        LogFormat::Json => fmt_layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+        LogFormat::Full => fmt_layer.boxed(),
+        LogFormat::Compact => fmt_layer.compact().boxed(),
+        LogFormat::Pretty => fmt_layer.with_ansi(true).pretty().boxed(),
+    };
+    let otlp_layer = infrastructure
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| telemetry::otlp_layer(endpoint, infrastructure.trace_sampling_ratio))
+        .transpose()?;
+
+    set_global_default(
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(otlp_layer)
+            .with(filter),
+    )?;
+    Ok(())
 }
 
 impl Config {
     pub fn from_cli() -> Result<Self> {
-        let cli = Cli::parse();
-        let indexer_config =
-            IndexerConfig::parse(ConfigPrefix::Tap, cli.config.as_ref()).map_err(|e| {
-                error!(
-                    "Invalid configuration file `{}`: {}, if a value is missing you can also use \
-                --config to fill the rest of the values",
-                    cli.config.unwrap_or_default().display(),
-                    e
-                );
-                anyhow::anyhow!(e)
-            })?;
-        let config: Config = indexer_config.into();
+        let config = Self::load()?;
 
         // Enables tracing under RUST_LOG variable
         if let Some(log_setting) = &config.indexer_infrastructure.log_level {
             std::env::set_var("RUST_LOG", log_setting);
         };
 
-        // add a LogFormat to config
-        init_tracing("pretty".to_string()).expect(
+        init_tracing(&config.indexer_infrastructure).expect(
             "Could not set up global default subscriber for logger, check \
         environmental variable `RUST_LOG`",
         );
 
         Ok(config)
     }
+
+    /// Reads the configuration file given on the command line, without setting up the tracing.
+    /// Also used to reload it, see [`crate::agent::config_reload`].
+    pub fn load() -> Result<Self> {
+        let cli = Cli::parse();
+        let indexer_config =
+            IndexerConfig::parse(ConfigPrefix::Tap, cli.config.as_ref(), cli.profile).map_err(
+                |e| {
+                    error!(
+                    "Invalid configuration file `{}`: {}, if a value is missing you can also use \
+                --config to fill the rest of the values",
+                    cli.config.unwrap_or_default().display(),
+                    e
+                );
+                    anyhow::anyhow!(e)
+                },
+            )?;
+        Ok(indexer_config.into())
+    }
 }
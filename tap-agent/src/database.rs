@@ -1,25 +1,560 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::time::Duration;
+//! Database pools of the agent, and the metrics of their operations.
+//!
+//! With `tap.database_pools.read_replica_url`, the heavy reads that can tolerate a little lag go
+//! to a read-only replica through [`read_pool`]: the startup scans, and the read-only subcommands
+//! such as `export-accounting`. Everything else goes to the primary, and so do these reads while
+//! the replica lags more than `tap.database_pools.read_replica_max_lag_secs`.
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use tracing::debug;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Gauge, HistogramVec, IntCounterVec, IntGaugeVec,
+};
+use reqwest::Url;
+use sqlx::{
+    pool::PoolConnection,
+    postgres::{PgConnection, PgPoolOptions},
+    PgPool, Postgres,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, warn};
 
 use crate::config;
 
-pub async fn connect(config: &config::Postgres) -> PgPool {
-    let url = &config.postgres_url;
+lazy_static! {
+    static ref DB_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "tap_db_queries_total",
+        "Database operations per subsystem and outcome",
+        &["subsystem", "status"]
+    )
+    .unwrap();
+    static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "tap_db_query_duration_seconds",
+        "Duration of the database operations per subsystem, not including the pool wait",
+        &["subsystem"]
+    )
+    .unwrap();
+    static ref DB_POOL_WAIT: HistogramVec = register_histogram_vec!(
+        "tap_db_pool_wait_seconds",
+        "Time spent waiting for a pooled connection per subsystem",
+        &["subsystem"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "tap_db_pool_connections",
+        "Open connections per database pool and state (idle or in_use)",
+        &["pool", "state"]
+    )
+    .unwrap();
+    static ref DB_POOL_LIMIT: IntGaugeVec = register_int_gauge_vec!(
+        "tap_db_pool_limit",
+        "Connections currently allowed per database pool",
+        &["pool"]
+    )
+    .unwrap();
+    static ref DB_REPLICA_LAG: Gauge = register_gauge!(
+        "tap_db_replica_lag_seconds",
+        "Replication lag of the read replica, as of its last check"
+    )
+    .unwrap();
+    static ref POOLS: RwLock<Vec<Arc<RegisteredPool>>> = RwLock::new(Vec::new());
+    static ref READ_REPLICA: RwLock<Option<ReadReplica>> = RwLock::new(None);
+}
+
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(3);
+const POOL_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Part of the agent owning a database pool, exported as the `pool` label of the `tap_db_pool_*`
+/// metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    /// The `SenderAccountsManager` and the `SenderAccount`s.
+    SenderAccount,
+    /// The `SenderAllocation`s.
+    SenderAllocation,
+    /// The read replica, see [`read_pool`].
+    ReadReplica,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Component::SenderAccount => "sender-account",
+            Component::SenderAllocation => "sender-allocation",
+            Component::ReadReplica => "read-replica",
+        }
+    }
+}
+
+pub async fn connect(config: &config::Postgres, component: Component) -> anyhow::Result<PgPool> {
+    let size = match component {
+        Component::SenderAccount => config.sender_account_pool,
+        // The replica serves the same scans as the pool of the `SenderAllocation`s
+        Component::SenderAllocation | Component::ReadReplica => config.sender_allocation_pool,
+    };
+    let limit = config
+        .adaptive_pool_latency_threshold
+        .filter(|_| component != Component::ReadReplica)
+        .map(|latency_threshold| AdaptiveLimit::new(size, latency_threshold));
+    let url = match component {
+        Component::ReadReplica => config
+            .read_replica_url
+            .as_ref()
+            .unwrap_or(&config.postgres_url),
+        _ => &config.postgres_url,
+    };
+    connect_pool(url, size, component, limit).await
+}
+
+async fn connect_pool(
+    url: &Url,
+    size: config::PoolSize,
+    component: Component,
+    limit: Option<AdaptiveLimit>,
+) -> anyhow::Result<PgPool> {
     debug!(
         postgres_host = tracing::field::debug(&url.host()),
         postgres_port = tracing::field::debug(&url.port()),
         postgres_database = tracing::field::debug(&url.path()),
+        pool = component.as_str(),
         "Connecting to database"
     );
-    PgPoolOptions::new()
-        .max_connections(50)
-        .acquire_timeout(Duration::from_secs(3))
+    let pool = PgPoolOptions::new()
+        .min_connections(size.min_connections)
+        .max_connections(size.max_connections)
+        .acquire_timeout(ACQUIRE_TIMEOUT)
         .connect(url.as_str())
         .await
-        .expect("Could not connect to DATABASE_URL")
+        .with_context(|| {
+            format!(
+                "Could not connect to the database, for the {} pool",
+                component.as_str()
+            )
+        })?;
+    register(&pool, component, limit);
+
+    let metrics_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POOL_METRICS_INTERVAL);
+        while !metrics_pool.is_closed() {
+            interval.tick().await;
+            let idle = metrics_pool.num_idle() as i64;
+            let size = metrics_pool.size() as i64;
+            DB_POOL_CONNECTIONS
+                .with_label_values(&[component.as_str(), "idle"])
+                .set(idle);
+            DB_POOL_CONNECTIONS
+                .with_label_values(&[component.as_str(), "in_use"])
+                .set(size - idle);
+        }
+        unregister(&metrics_pool);
+    });
+    Ok(pool)
+}
+
+/// Connects to the read replica if `tap.database_pools.read_replica_url` is set, and starts
+/// checking its lag. From then on, [`read_pool`] returns it while it's in sync.
+pub async fn connect_read_replica(config: &config::Postgres) -> anyhow::Result<Option<PgPool>> {
+    if config.read_replica_url.is_none() {
+        return Ok(None);
+    }
+    let replica = ReadReplica {
+        pool: connect(config, Component::ReadReplica).await?,
+        max_lag: config.read_replica_max_lag,
+        in_sync: Arc::new(AtomicBool::new(false)),
+    };
+    replica.check().await;
+    let checked = replica.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POOL_METRICS_INTERVAL);
+        interval.tick().await;
+        while !checked.pool.is_closed() {
+            interval.tick().await;
+            checked.check().await;
+        }
+    });
+    let pool = replica.pool.clone();
+    *READ_REPLICA.write().unwrap() = Some(replica);
+    Ok(Some(pool))
+}
+
+/// The pool for the heavy reads that can tolerate a little lag: the read replica if it's
+/// connected and in sync, `primary` otherwise.
+pub fn read_pool(primary: &PgPool) -> PgPool {
+    READ_REPLICA
+        .read()
+        .unwrap()
+        .as_ref()
+        .filter(|replica| replica.in_sync.load(Ordering::SeqCst))
+        .map_or_else(|| primary.clone(), |replica| replica.pool.clone())
+}
+
+#[derive(Clone)]
+struct ReadReplica {
+    pool: PgPool,
+    max_lag: Duration,
+    in_sync: Arc<AtomicBool>,
+}
+
+impl ReadReplica {
+    /// Checks the lag of the replica, it's out of sync if it can't be told.
+    async fn check(&self) {
+        let lag = replica_lag(&self.pool).await;
+        match lag {
+            Ok(Some(lag)) => DB_REPLICA_LAG.set(lag),
+            Ok(None) => DB_REPLICA_LAG.set(f64::NAN),
+            Err(_) => {}
+        }
+        let in_sync = matches!(lag, Ok(Some(lag)) if lag <= self.max_lag.as_secs_f64());
+        if self.in_sync.swap(in_sync, Ordering::SeqCst) != in_sync {
+            if in_sync {
+                info!("The read replica is in sync, heavy reads go to it.");
+            } else {
+                warn!(
+                    lag = ?lag,
+                    max_lag_secs = self.max_lag.as_secs_f64(),
+                    "The read replica is lagging or unreachable, heavy reads go to the primary."
+                );
+            }
+        }
+    }
+}
+
+/// Seconds since the last transaction replayed by `pool`, 0 if it isn't a replica, `None` if it
+/// hasn't replayed any transaction yet. The lag of a replica of an idle primary grows as well.
+async fn replica_lag(pool: &PgPool) -> Result<Option<f64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT CASE
+                WHEN pg_is_in_recovery()
+                    THEN EXTRACT(EPOCH FROM NOW() - pg_last_xact_replay_timestamp())::FLOAT8
+                ELSE 0
+            END AS lag
+        "#,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// A pool created by [`connect`], so that [`acquire`] can apply its adaptive limit. It's
+/// registered until it's closed.
+struct RegisteredPool {
+    pool: PgPool,
+    component: Component,
+    limit: Option<AdaptiveLimit>,
+}
+
+/// Connections allowed out of a pool in adaptive mode. Starts at the minimum size of the pool, and
+/// grows by one connection after each RAV-related operation slower than `latency_threshold`, up to
+/// the maximum size of the pool. It never shrinks.
+struct AdaptiveLimit {
+    permits: Arc<Semaphore>,
+    limit: AtomicU32,
+    max_connections: u32,
+    latency_threshold: Duration,
+}
+
+impl AdaptiveLimit {
+    fn new(size: config::PoolSize, latency_threshold: Duration) -> Self {
+        // At least one connection, otherwise no operation could ever be slow.
+        let limit = size.min_connections.clamp(1, size.max_connections);
+        Self {
+            permits: Arc::new(Semaphore::new(limit as usize)),
+            limit: AtomicU32::new(limit),
+            max_connections: size.max_connections,
+            latency_threshold,
+        }
+    }
+
+    fn grow(&self, component: Component) {
+        let grown = self
+            .limit
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |limit| {
+                (limit < self.max_connections).then_some(limit + 1)
+            });
+        if let Ok(previous) = grown {
+            self.permits.add_permits(1);
+            DB_POOL_LIMIT
+                .with_label_values(&[component.as_str()])
+                .set((previous + 1) as i64);
+            info!(
+                pool = component.as_str(),
+                limit = previous + 1,
+                latency_threshold_ms = self.latency_threshold.as_millis() as u64,
+                "Slow RAV-related database operation, growing the database pool."
+            );
+        }
+    }
+}
+
+fn register(pool: &PgPool, component: Component, limit: Option<AdaptiveLimit>) {
+    let limit_value = limit
+        .as_ref()
+        .map_or(pool.options().get_max_connections(), |limit| {
+            limit.limit.load(Ordering::SeqCst)
+        });
+    DB_POOL_LIMIT
+        .with_label_values(&[component.as_str()])
+        .set(limit_value as i64);
+    let mut pools = POOLS.write().unwrap();
+    // The pools closed since their metrics were last updated
+    pools.retain(|registered| !registered.pool.is_closed());
+    pools.push(Arc::new(RegisteredPool {
+        pool: pool.clone(),
+        component,
+        limit,
+    }));
+}
+
+/// Forgets a closed pool, and its metrics.
+fn unregister(pool: &PgPool) {
+    let mut pools = POOLS.write().unwrap();
+    let Some(index) = pools
+        .iter()
+        .position(|registered| std::ptr::eq(registered.pool.options(), pool.options()))
+    else {
+        return;
+    };
+    let component = pools.remove(index).component;
+    // Another pool of the component keeps reporting in the same series
+    if pools
+        .iter()
+        .all(|registered| registered.component != component)
+    {
+        let _ = DB_POOL_LIMIT.remove_label_values(&[component.as_str()]);
+        for state in ["idle", "in_use"] {
+            let _ = DB_POOL_CONNECTIONS.remove_label_values(&[component.as_str(), state]);
+        }
+    }
+}
+
+fn registered(pool: &PgPool) -> Option<Arc<RegisteredPool>> {
+    // Clones of a pool share its options.
+    POOLS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|registered| {
+            !registered.pool.is_closed() && std::ptr::eq(registered.pool.options(), pool.options())
+        })
+        .cloned()
+}
+
+/// Part of the agent issuing a database operation, exported as the `subsystem` label of the
+/// `tap_db_*` metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Reading and cleaning up receipts to compute unaggregated fees and build RAV requests.
+    ReceiptScan,
+    /// Reading and writing RAVs.
+    RavStore,
+    /// Reading and writing the sender denylist.
+    Denylist,
+    /// Records kept for the operator only (invalid receipts, failed RAV requests).
+    Analytics,
+}
+
+impl Subsystem {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subsystem::ReceiptScan => "receipt-scan",
+            Subsystem::RavStore => "rav-store",
+            Subsystem::Denylist => "denylist",
+            Subsystem::Analytics => "analytics",
+        }
+    }
+
+    /// Operations whose latency drives the growth of adaptive pools.
+    fn is_rav_related(&self) -> bool {
+        matches!(self, Subsystem::ReceiptScan | Subsystem::RavStore)
+    }
+}
+
+/// A pooled connection acquired on behalf of a [`Subsystem`].
+pub struct SubsystemConnection {
+    conn: PoolConnection<Postgres>,
+    subsystem: Subsystem,
+    pool: Option<Arc<RegisteredPool>>,
+    // Dropped after the connection, so that it is back in the pool first.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Acquires a connection from `pool`, recording the time spent waiting for it.
+pub async fn acquire(
+    pool: &PgPool,
+    subsystem: Subsystem,
+) -> Result<SubsystemConnection, sqlx::Error> {
+    let start = Instant::now();
+    let registered = registered(pool);
+    let permit = match registered.as_ref().and_then(|pool| pool.limit.as_ref()) {
+        Some(limit) => tokio::time::timeout(ACQUIRE_TIMEOUT, limit.permits.clone().acquire_owned())
+            .await
+            .map(|permit| Some(permit.expect("Semaphore is never closed")))
+            .map_err(|_| sqlx::Error::PoolTimedOut),
+        None => Ok(None),
+    };
+    let conn = match permit {
+        Ok(permit) => pool.acquire().await.map(|conn| (conn, permit)),
+        Err(e) => Err(e),
+    };
+    DB_POOL_WAIT
+        .with_label_values(&[subsystem.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    if conn.is_err() {
+        DB_QUERIES
+            .with_label_values(&[subsystem.as_str(), "pool_error"])
+            .inc();
+    }
+    let (conn, permit) = conn?;
+    Ok(SubsystemConnection {
+        conn,
+        subsystem,
+        pool: registered,
+        _permit: permit,
+    })
+}
+
+impl SubsystemConnection {
+    /// Runs the operation built by `f` on this connection, recording its outcome and latency.
+    pub async fn run<'c, T, F, Fut>(&'c mut self, f: F) -> Result<T, sqlx::Error>
+    where
+        F: FnOnce(&'c mut PgConnection) -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>> + 'c,
+    {
+        let subsystem = self.subsystem.as_str();
+        let start = Instant::now();
+        let result = f(&mut *self.conn).await;
+        let elapsed = start.elapsed();
+        DB_QUERY_DURATION
+            .with_label_values(&[subsystem])
+            .observe(elapsed.as_secs_f64());
+        if let Some(pool) = &self.pool {
+            if let Some(limit) = &pool.limit {
+                if self.subsystem.is_rav_related() && elapsed > limit.latency_threshold {
+                    limit.grow(pool.component);
+                }
+            }
+        }
+        DB_QUERIES
+            .with_label_values(&[subsystem, if result.is_ok() { "ok" } else { "error" }])
+            .inc();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use super::{
+        acquire, register, registered, replica_lag, unregister, AdaptiveLimit, Component,
+        ReadReplica, Subsystem, DB_QUERIES,
+    };
+    use crate::config::PoolSize;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_run_records_outcome(pgpool: PgPool) {
+        let label = Subsystem::Analytics.as_str();
+        let ok_before = DB_QUERIES.with_label_values(&[label, "ok"]).get();
+        let err_before = DB_QUERIES.with_label_values(&[label, "error"]).get();
+
+        let mut conn = acquire(&pgpool, Subsystem::Analytics).await.unwrap();
+        let one = conn
+            .run(|conn| sqlx::query_scalar!(r#"SELECT 1 AS "one!""#).fetch_one(conn))
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
+        let mut conn = acquire(&pgpool, Subsystem::Analytics).await.unwrap();
+        // Fails on purpose, so it cannot be checked at compile time
+        conn.run(|conn| sqlx::query("SELECT * FROM missing_table").execute(conn))
+            .await
+            .unwrap_err();
+
+        // Other tests may record operations for the same subsystem concurrently.
+        assert!(DB_QUERIES.with_label_values(&[label, "ok"]).get() > ok_before);
+        assert!(DB_QUERIES.with_label_values(&[label, "error"]).get() > err_before);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_adaptive_pool_grows_on_slow_rav_operations(pgpool: PgPool) {
+        let size = PoolSize {
+            min_connections: 1,
+            max_connections: 2,
+        };
+        // Every operation is slower than a zero threshold.
+        register(
+            &pgpool,
+            Component::SenderAllocation,
+            Some(AdaptiveLimit::new(size, Duration::ZERO)),
+        );
+        let limit = || {
+            registered(&pgpool)
+                .unwrap()
+                .limit
+                .as_ref()
+                .unwrap()
+                .limit
+                .load(Ordering::SeqCst)
+        };
+        assert_eq!(limit(), 1);
+
+        let mut conn = acquire(&pgpool, Subsystem::Analytics).await.unwrap();
+        conn.run(|conn| sqlx::query!("SELECT 1").execute(conn))
+            .await
+            .unwrap();
+        drop(conn);
+        // Not RAV-related
+        assert_eq!(limit(), 1);
+
+        for _ in 0..2 {
+            let mut conn = acquire(&pgpool, Subsystem::RavStore).await.unwrap();
+            conn.run(|conn| sqlx::query!("SELECT 1").execute(conn))
+                .await
+                .unwrap();
+        }
+        // Never over the maximum size of the pool
+        assert_eq!(limit(), 2);
+
+        // Both connections can be held at once
+        let first = acquire(&pgpool, Subsystem::RavStore).await.unwrap();
+        let second = acquire(&pgpool, Subsystem::RavStore).await.unwrap();
+        drop((first, second));
+
+        unregister(&pgpool);
+        assert!(registered(&pgpool).is_none());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_read_replica_lag(pgpool: PgPool) {
+        // Not a replica, it never lags
+        assert_eq!(replica_lag(&pgpool).await.unwrap(), Some(0.0));
+
+        let replica = ReadReplica {
+            pool: pgpool.clone(),
+            max_lag: Duration::ZERO,
+            in_sync: Default::default(),
+        };
+        replica.check().await;
+        assert!(replica.in_sync.load(Ordering::SeqCst));
+
+        // Unreachable
+        pgpool.close().await;
+        replica.check().await;
+        assert!(!replica.in_sync.load(Ordering::SeqCst));
+    }
 }
@@ -0,0 +1,570 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Maintenance of the TAP tables, for the `db` subcommands, so that operators don't have to run
+//! SQL against the `scalar_tap_*` tables by hand.
+//!
+//! - [`check`] looks for receipts that don't match their RAVs.
+//! - `prune` sets aside the receipts covered by the final RAVs, see
+//!   [`crate::agent::receipt_compaction`].
+//! - [`deny`] and [`allow`] write the denylist as tap-agent does, after looking at what the sender
+//!   still owes, see [`SenderExposure`]. Allowing a sender with pending fees is refused unless
+//!   forced: tap-agent would deny it again on its next receipt, or never get the fees.
+//! - [`stats`] gives the size of the TAP tables.
+//!
+//! Only the legacy (TAP v1) tables are covered. The senders of the receipts are found through the
+//! escrow accounts.
+
+use std::str::FromStr;
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::Result;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    agent::{
+        denylist_outbox::{Denial, DenyReason},
+        sender_allocation::horizon::{to_u128, to_u64},
+    },
+    money::wei,
+    storage::PgStorage,
+};
+
+/// Tables reported by [`stats`], the ones missing from the database are skipped.
+const TABLES: &[&str] = &[
+    "scalar_tap_receipts",
+    "scalar_tap_receipts_invalid",
+    "scalar_tap_receipts_quarantined",
+    "scalar_tap_receipts_archive",
+    "scalar_tap_ravs",
+    "scalar_tap_rav_requests_failed",
+    "scalar_tap_denylist",
+    "tap_horizon_receipts",
+    "tap_horizon_receipts_invalid",
+    "tap_horizon_ravs",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The receipts covered by a RAV add up to more than it, they were probably signed for
+    /// another sender.
+    ReceiptsExceedRav,
+    /// Receipts newer than the last RAV of their allocation, which won't be aggregated anymore.
+    ReceiptsAfterLastRav,
+    /// Receipts of a signer missing from the escrow accounts, tap-agent ignores them.
+    UnknownSigner,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub sender: Option<Address>,
+    pub allocation_id: Address,
+    pub signer: Option<Address>,
+    pub receipts: u64,
+    #[serde(serialize_with = "wei")]
+    pub value: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IntegrityReport {
+    pub ravs_checked: u64,
+    pub issues: Vec<Issue>,
+}
+
+/// Checks the receipts against the RAVs of their sender. The unknown signers are only reported
+/// if the escrow accounts could be fetched.
+pub async fn check(pgpool: &PgPool, escrow_accounts: &EscrowAccounts) -> Result<IntegrityReport> {
+    let mut issues = Vec::new();
+
+    if !escrow_accounts.get_senders().is_empty() {
+        let receipts = sqlx::query!(
+            r#"
+                SELECT allocation_id, signer_address, COUNT(*) AS "count!", SUM(value) AS "value!"
+                FROM scalar_tap_receipts
+                GROUP BY allocation_id, signer_address
+                ORDER BY allocation_id, signer_address
+            "#,
+        )
+        .fetch_all(pgpool)
+        .await?;
+        for row in receipts {
+            let signer = Address::from_str(&row.signer_address)?;
+            if escrow_accounts.get_sender_for_signer(&signer).is_err() {
+                issues.push(Issue {
+                    kind: IssueKind::UnknownSigner,
+                    sender: None,
+                    allocation_id: Address::from_str(&row.allocation_id)?,
+                    signer: Some(signer),
+                    receipts: row.count.try_into()?,
+                    value: to_u128(&row.value)?,
+                });
+            }
+        }
+    }
+
+    let ravs = sqlx::query!(
+        r#"
+            SELECT sender_address, allocation_id, timestamp_ns, value_aggregate, last
+            FROM scalar_tap_ravs
+            ORDER BY sender_address, allocation_id
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let ravs_checked = ravs.len() as u64;
+    for rav in ravs {
+        let sender = Address::from_str(&rav.sender_address)?;
+        let signers = signers(escrow_accounts, sender);
+        if signers.is_empty() {
+            continue;
+        }
+        let receipts = sqlx::query!(
+            r#"
+                SELECT
+                    COUNT(*) FILTER (WHERE timestamp_ns <= $3) AS "covered!",
+                    SUM(value) FILTER (WHERE timestamp_ns <= $3) AS covered_value,
+                    COUNT(*) FILTER (WHERE timestamp_ns > $3) AS "after!",
+                    SUM(value) FILTER (WHERE timestamp_ns > $3) AS after_value
+                FROM scalar_tap_receipts
+                WHERE allocation_id = $1 AND signer_address = ANY($2)
+            "#,
+            &rav.allocation_id,
+            &signers,
+            &rav.timestamp_ns,
+        )
+        .fetch_one(pgpool)
+        .await?;
+        let allocation_id = Address::from_str(&rav.allocation_id)?;
+        if let Some(covered_value) = receipts
+            .covered_value
+            .filter(|value| *value > rav.value_aggregate)
+        {
+            issues.push(Issue {
+                kind: IssueKind::ReceiptsExceedRav,
+                sender: Some(sender),
+                allocation_id,
+                signer: None,
+                receipts: receipts.covered.try_into()?,
+                value: to_u128(&covered_value)?,
+            });
+        }
+        if let Some(after_value) = receipts.after_value.filter(|_| rav.last) {
+            issues.push(Issue {
+                kind: IssueKind::ReceiptsAfterLastRav,
+                sender: Some(sender),
+                allocation_id,
+                signer: None,
+                receipts: receipts.after.try_into()?,
+                value: to_u128(&after_value)?,
+            });
+        }
+    }
+
+    Ok(IntegrityReport {
+        ravs_checked,
+        issues,
+    })
+}
+
+/// What a sender still owes the indexer, looked at before changing its deny status.
+#[derive(Debug, Serialize)]
+pub struct SenderExposure {
+    pub sender: Address,
+    pub denied: bool,
+    /// Why tap-agent denied the sender, if recorded.
+    pub reason: Option<String>,
+    /// The signers of the sender in the escrow accounts, its receipts can't be told without them.
+    pub signers: Vec<Address>,
+    /// RAVs not redeemed yet.
+    pub pending_ravs: u64,
+    #[serde(serialize_with = "wei")]
+    pub pending_rav_value: u128,
+    /// Receipts newer than the RAV of their allocation, if any.
+    pub unaggregated_receipts: u64,
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_value: u128,
+    /// The tap-agent instance holding the lease of the sender, with `tap.sharding`.
+    pub leased_by: Option<String>,
+}
+
+impl SenderExposure {
+    /// Whether allowing the sender may lose fees.
+    pub fn has_pending_fees(&self) -> bool {
+        self.pending_rav_value > 0 || self.unaggregated_value > 0
+    }
+
+    /// Warnings for the operator before denying or allowing the sender.
+    pub fn warnings(&self, deny: bool) -> Vec<String> {
+        let mut warnings = Vec::new();
+        match (deny, self.denied) {
+            (true, true) => warnings.push(format!(
+                "Sender {} is already denied ({}).",
+                self.sender,
+                self.reason.as_deref().unwrap_or("no reason recorded")
+            )),
+            (false, false) => warnings.push(format!("Sender {} is not denied.", self.sender)),
+            _ => {}
+        }
+        if self.signers.is_empty() {
+            warnings.push(format!(
+                "Sender {} has no signer in the escrow accounts, its unaggregated receipts are \
+                unknown.",
+                self.sender
+            ));
+        }
+        if !deny && self.has_pending_fees() {
+            warnings.push(format!(
+                "Sender {} has {} unaggregated receipts worth {} wei and {} pending RAVs worth {} \
+                wei. Allowing it before they're redeemed may lose them.",
+                self.sender,
+                self.unaggregated_receipts,
+                self.unaggregated_value,
+                self.pending_ravs,
+                self.pending_rav_value
+            ));
+        }
+        if deny && self.unaggregated_value > 0 {
+            warnings.push(format!(
+                "Sender {} has {} unaggregated receipts worth {} wei, tap-agent keeps requesting \
+                RAVs for them while it's denied.",
+                self.sender, self.unaggregated_receipts, self.unaggregated_value
+            ));
+        }
+        let running = match &self.leased_by {
+            Some(instance) => format!("tap-agent instance `{instance}` holds the sender"),
+            None => "a running tap-agent".to_string(),
+        };
+        warnings.push(if deny {
+            format!(
+                "{running} only reads the deny status of the sender when it starts it, prefer \
+                `POST /admin/senders/:sender/stop` while it runs."
+            )
+        } else {
+            format!(
+                "{running} denies the sender again on its next receipt, prefer \
+                `POST /admin/senders/:sender/start` while it runs."
+            )
+        });
+        warnings
+    }
+}
+
+/// Looks at the deny status, RAVs and unaggregated receipts of `sender`.
+pub async fn exposure(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    sender: Address,
+) -> Result<SenderExposure> {
+    let sender_address = sender.encode_hex();
+    let signers = escrow_accounts.get_signers_for_sender(&sender);
+    let reason = sqlx::query_scalar!(
+        "SELECT reason FROM scalar_tap_denylist WHERE sender_address = $1",
+        &sender_address
+    )
+    .fetch_optional(pgpool)
+    .await?;
+    let pending = sqlx::query!(
+        r#"
+            SELECT COUNT(*) AS "count!", SUM(value_aggregate) AS value
+            FROM scalar_tap_ravs
+            WHERE sender_address = $1 AND NOT final
+        "#,
+        &sender_address
+    )
+    .fetch_one(pgpool)
+    .await?;
+    let unaggregated = sqlx::query!(
+        r#"
+            SELECT COUNT(*) AS "count!", SUM(scalar_tap_receipts.value) AS value
+            FROM scalar_tap_receipts
+            LEFT JOIN scalar_tap_ravs
+                ON scalar_tap_ravs.allocation_id = scalar_tap_receipts.allocation_id
+                AND scalar_tap_ravs.sender_address = $1
+            WHERE scalar_tap_receipts.signer_address = ANY($2)
+                AND scalar_tap_receipts.timestamp_ns > COALESCE(scalar_tap_ravs.timestamp_ns, -1)
+        "#,
+        &sender_address,
+        &signers
+            .iter()
+            .map(|signer| signer.encode_hex())
+            .collect::<Vec<_>>(),
+    )
+    .fetch_one(pgpool)
+    .await?;
+    let leased_by = sqlx::query_scalar!(
+        r#"
+            SELECT tap_sender_leases.instance_id
+            FROM tap_sender_leases
+            JOIN tap_agent_instances USING (instance_id)
+            WHERE sender_address = $1 AND expires_at > NOW()
+        "#,
+        &sender_address
+    )
+    .fetch_optional(pgpool)
+    .await?;
+
+    Ok(SenderExposure {
+        sender,
+        denied: reason.is_some(),
+        reason: reason.flatten(),
+        signers,
+        pending_ravs: pending.count.try_into()?,
+        pending_rav_value: pending
+            .value
+            .as_ref()
+            .map(to_u128)
+            .transpose()?
+            .unwrap_or_default(),
+        unaggregated_receipts: unaggregated.count.try_into()?,
+        unaggregated_value: unaggregated
+            .value
+            .as_ref()
+            .map(to_u128)
+            .transpose()?
+            .unwrap_or_default(),
+        leased_by,
+    })
+}
+
+/// Denies `sender`, as denied by the operator.
+pub async fn deny(pgpool: &PgPool, sender: Address, context: serde_json::Value) -> Result<()> {
+    PgStorage::new(pgpool.clone())
+        .deny_sender(sender, &Denial::new(DenyReason::Operator, context))
+        .await
+}
+
+/// Allows `sender`, refused if it has pending fees, or its receipts can't be told, unless `force`
+/// is set.
+pub async fn allow(pgpool: &PgPool, exposure: &SenderExposure, force: bool) -> Result<()> {
+    anyhow::ensure!(
+        force || (!exposure.signers.is_empty() && !exposure.has_pending_fees()),
+        "Sender {} may have pending fees, set `--force` to allow it anyway",
+        exposure.sender
+    );
+    PgStorage::new(pgpool.clone())
+        .allow_sender(exposure.sender)
+        .await
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableStats {
+    pub table: &'static str,
+    pub rows: u64,
+    /// With the indexes and TOAST.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub tables: Vec<TableStats>,
+    pub oldest_receipt_timestamp_ns: Option<u64>,
+    pub newest_receipt_timestamp_ns: Option<u64>,
+}
+
+/// Counts the rows of the TAP tables, and their size on disk.
+pub async fn stats(pgpool: &PgPool) -> Result<Stats> {
+    let mut tables = Vec::new();
+    for table in TABLES {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT to_regclass($1::TEXT) IS NOT NULL AS "exists!""#,
+            *table
+        )
+        .fetch_one(pgpool)
+        .await?;
+        if !exists {
+            continue;
+        }
+        // Same query for every table, built at runtime with its name
+        let (rows, bytes) = sqlx::query_as::<_, (i64, i64)>(&format!(
+            "SELECT COUNT(*), pg_total_relation_size('{table}') FROM {table}"
+        ))
+        .fetch_one(pgpool)
+        .await?;
+        tables.push(TableStats {
+            table,
+            rows: rows.try_into()?,
+            bytes: bytes.try_into()?,
+        });
+    }
+    let timestamps = sqlx::query!(
+        "SELECT MIN(timestamp_ns) AS oldest, MAX(timestamp_ns) AS newest FROM scalar_tap_receipts"
+    )
+    .fetch_one(pgpool)
+    .await?;
+    Ok(Stats {
+        tables,
+        oldest_receipt_timestamp_ns: timestamps.oldest.as_ref().map(to_u64).transpose()?,
+        newest_receipt_timestamp_ns: timestamps.newest.as_ref().map(to_u64).transpose()?,
+    })
+}
+
+fn signers(escrow_accounts: &EscrowAccounts, sender: Address) -> Vec<String> {
+    escrow_accounts
+        .get_signers_for_sender(&sender)
+        .iter()
+        .map(|signer| signer.encode_hex())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::{hex::ToHexExt, primitives::U256};
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::PgPool;
+
+    use super::{allow, check, deny, exposure, stats, IssueKind};
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, SENDER, SENDER_2, SIGNER,
+    };
+
+    fn escrow_accounts() -> EscrowAccounts {
+        EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        )
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_check(pgpool: PgPool) {
+        for (allocation_id, nonce, timestamp_ns) in [
+            (*ALLOCATION_ID_0, 1, 10),
+            (*ALLOCATION_ID_0, 2, 20),
+            (*ALLOCATION_ID_1, 3, 10),
+            (*ALLOCATION_ID_1, 4, 30),
+        ] {
+            let receipt =
+                create_received_receipt(&allocation_id, &SIGNER.0, nonce, timestamp_ns, 5);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SENDER_2.0, 5, 10, 7);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        // Less than the receipts it covers
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 20, 8),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 20, 5),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        sqlx::query!(
+            "UPDATE scalar_tap_ravs SET last = true WHERE allocation_id = $1",
+            ALLOCATION_ID_1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let report = check(&pgpool, &escrow_accounts()).await.unwrap();
+        assert_eq!(report.ravs_checked, 2);
+        let issues: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.kind, issue.allocation_id, issue.receipts, issue.value))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (IssueKind::UnknownSigner, *ALLOCATION_ID_0, 1, 7),
+                (IssueKind::ReceiptsExceedRav, *ALLOCATION_ID_0, 2, 10),
+                (IssueKind::ReceiptsAfterLastRav, *ALLOCATION_ID_1, 1, 5),
+            ]
+        );
+
+        // Without the escrow accounts, nothing can be told about the signers
+        let report = check(&pgpool, &EscrowAccounts::default()).await.unwrap();
+        assert!(report.issues.is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_deny_allow(pgpool: PgPool) {
+        for (allocation_id, nonce, timestamp_ns) in [
+            (*ALLOCATION_ID_0, 1, 10),
+            (*ALLOCATION_ID_0, 2, 30),
+            (*ALLOCATION_ID_1, 3, 10),
+        ] {
+            let receipt =
+                create_received_receipt(&allocation_id, &SIGNER.0, nonce, timestamp_ns, 5);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 20, 5),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+
+        let escrow_accounts = escrow_accounts();
+        let before = exposure(&pgpool, &escrow_accounts, SENDER.1).await.unwrap();
+        assert!(!before.denied);
+        assert_eq!(before.pending_ravs, 1);
+        assert_eq!(before.pending_rav_value, 5);
+        assert_eq!(before.unaggregated_receipts, 2);
+        assert_eq!(before.unaggregated_value, 10);
+
+        deny(&pgpool, SENDER.1, serde_json::Value::Null)
+            .await
+            .unwrap();
+        let denied = exposure(&pgpool, &escrow_accounts, SENDER.1).await.unwrap();
+        assert!(denied.denied);
+        assert_eq!(denied.reason.as_deref(), Some("operator"));
+        assert!(denied.warnings(true)[0].contains("already denied"));
+
+        assert!(allow(&pgpool, &denied, false).await.is_err());
+        assert!(
+            exposure(&pgpool, &escrow_accounts, SENDER.1)
+                .await
+                .unwrap()
+                .denied
+        );
+        allow(&pgpool, &denied, true).await.unwrap();
+        assert!(
+            !exposure(&pgpool, &escrow_accounts, SENDER.1)
+                .await
+                .unwrap()
+                .denied
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_stats(pgpool: PgPool) {
+        for nonce in 1..=3 {
+            let receipt =
+                create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, nonce * 10, 5);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let stats = stats(&pgpool).await.unwrap();
+        let receipts = stats
+            .tables
+            .iter()
+            .find(|table| table.table == "scalar_tap_receipts")
+            .unwrap();
+        assert_eq!(receipts.rows, 3);
+        assert!(receipts.bytes > 0);
+        assert_eq!(stats.oldest_receipt_timestamp_ns, Some(10));
+        assert_eq!(stats.newest_receipt_timestamp_ns, Some(30));
+    }
+}
@@ -0,0 +1,21 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only web UI for operators without a Grafana stack, only available with the `explorer`
+//! feature.
+//!
+//! `GET /explorer` serves a single page, embedded in the binary, that polls [`crate::status`] and
+//! shows the senders, their fees per allocation against the thresholds, their deny events and the
+//! recent RAVs. It has no external dependencies, and can't modify anything.
+
+use axum::{response::Html, routing::get, Router};
+
+const PAGE: &str = include_str!("../assets/explorer.html");
+
+async fn handler_explorer() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+pub fn router() -> Router {
+    Router::new().route("/explorer", get(handler_explorer))
+}
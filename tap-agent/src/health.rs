@@ -0,0 +1,212 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Liveness and readiness endpoints for the tap-agent.
+//!
+//! `/healthz` only looks at the agent's internal components (the actor tree and the Postgres
+//! NOTIFY listener), so a failure there means the process should be restarted. `/readyz`
+//! additionally verifies that the external dependencies (database and escrow subgraph) can be
+//! reached.
+
+use std::time::Duration;
+
+use alloy::primitives::Address;
+use axum::{
+    body::Bytes, extract::State, http::StatusCode, response::IntoResponse, routing::get, Json,
+    Router,
+};
+use indexer_common::prelude::SubgraphClient;
+use ractor::{call_t, ActorRef, ActorStatus};
+use serde::Serialize;
+use sqlx::{Connection, PgPool};
+
+use crate::agent::sender_accounts_manager::SenderAccountsManagerMessage;
+
+/// Maximum time spent on any single check before it's considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+const ESCROW_SUBGRAPH_PROBE: &str = r#"{"query": "{ _meta { block { number } } }"}"#;
+
+/// Health information reported by the `SenderAccountsManager`.
+#[derive(Debug, Clone, Default)]
+pub struct ManagerHealth {
+    /// Senders that should have a running SenderAccount but don't.
+    pub dead_sender_accounts: Vec<Address>,
+    /// Whether the task consuming `scalar_tap_receipt_notification` is still running.
+    pub notify_listener_connected: bool,
+}
+
+#[derive(Clone)]
+pub struct HealthState {
+    manager: ActorRef<SenderAccountsManagerMessage>,
+    pgpool: PgPool,
+    escrow_subgraph: &'static SubgraphClient,
+}
+
+impl HealthState {
+    pub fn new(
+        manager: ActorRef<SenderAccountsManagerMessage>,
+        pgpool: PgPool,
+        escrow_subgraph: &'static SubgraphClient,
+    ) -> Self {
+        Self {
+            manager,
+            pgpool,
+            escrow_subgraph,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl CheckResult {
+    fn from_result(result: anyhow::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                healthy: true,
+                error: None,
+            },
+            Err(e) => Self {
+                healthy: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    healthy: bool,
+    sender_accounts: CheckResult,
+    notify_listener: CheckResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<CheckResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow_subgraph: Option<CheckResult>,
+}
+
+impl HealthResponse {
+    fn into_response_with_status(self) -> impl IntoResponse {
+        let status = if self.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self))
+    }
+}
+
+async fn check_manager(
+    manager: &ActorRef<SenderAccountsManagerMessage>,
+) -> (CheckResult, CheckResult) {
+    if manager.get_status() != ActorStatus::Running {
+        let err =
+            || CheckResult::from_result(Err(anyhow::anyhow!("SenderAccountsManager is down")));
+        return (err(), err());
+    }
+    match call_t!(
+        manager,
+        SenderAccountsManagerMessage::GetHealth,
+        CHECK_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(health) => {
+            let sender_accounts = if health.dead_sender_accounts.is_empty() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!(
+                    "SenderAccount actors not running for senders: {:?}",
+                    health.dead_sender_accounts
+                ))
+            };
+            let notify_listener = if health.notify_listener_connected {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!(
+                    "Not listening to 'scalar_tap_receipt_notification'"
+                ))
+            };
+            (
+                CheckResult::from_result(sender_accounts),
+                CheckResult::from_result(notify_listener),
+            )
+        }
+        Err(e) => {
+            let err = || {
+                CheckResult::from_result(Err(anyhow::anyhow!(
+                    "SenderAccountsManager did not respond: {e}"
+                )))
+            };
+            (err(), err())
+        }
+    }
+}
+
+async fn check_database(pgpool: &PgPool) -> anyhow::Result<()> {
+    tokio::time::timeout(CHECK_TIMEOUT, async {
+        let mut conn = pgpool.acquire().await?;
+        conn.ping().await?;
+        anyhow::Ok(())
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Timed out while pinging the database"))?
+}
+
+async fn check_escrow_subgraph(escrow_subgraph: &SubgraphClient) -> anyhow::Result<()> {
+    let response = tokio::time::timeout(
+        CHECK_TIMEOUT,
+        escrow_subgraph.query_raw(Bytes::from_static(ESCROW_SUBGRAPH_PROBE.as_bytes())),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("Timed out while querying the escrow subgraph"))??;
+    let status = response.status();
+    anyhow::ensure!(
+        status.is_success(),
+        "Escrow subgraph responded with status {status}"
+    );
+    Ok(())
+}
+
+async fn handler_healthz(State(state): State<HealthState>) -> impl IntoResponse {
+    let (sender_accounts, notify_listener) = check_manager(&state.manager).await;
+    HealthResponse {
+        healthy: sender_accounts.healthy && notify_listener.healthy,
+        sender_accounts,
+        notify_listener,
+        database: None,
+        escrow_subgraph: None,
+    }
+    .into_response_with_status()
+}
+
+async fn handler_readyz(State(state): State<HealthState>) -> impl IntoResponse {
+    let ((sender_accounts, notify_listener), database, escrow_subgraph) = tokio::join!(
+        check_manager(&state.manager),
+        check_database(&state.pgpool),
+        check_escrow_subgraph(state.escrow_subgraph),
+    );
+    let database = CheckResult::from_result(database);
+    let escrow_subgraph = CheckResult::from_result(escrow_subgraph);
+    HealthResponse {
+        healthy: sender_accounts.healthy
+            && notify_listener.healthy
+            && database.healthy
+            && escrow_subgraph.healthy,
+        sender_accounts,
+        notify_listener,
+        database: Some(database),
+        escrow_subgraph: Some(escrow_subgraph),
+    }
+    .into_response_with_status()
+}
+
+pub fn router(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(handler_healthz))
+        .route("/readyz", get(handler_readyz))
+        .with_state(state)
+}
@@ -0,0 +1,215 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Summary of the TAP tables, for the `inspect` subcommand.
+//!
+//! Reads the database only, so it works while tap-agent is stopped. The receipts are grouped by
+//! signer, since mapping them to their sender requires the escrow accounts.
+
+use std::str::FromStr;
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::{anyhow, Result};
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use serde::Serialize;
+use sqlx::{types::BigDecimal, PgPool};
+
+use crate::money::wei;
+
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub receipts: Vec<ReceiptsSummary>,
+    pub ravs: Vec<RavSummary>,
+    pub denied_senders: Vec<Address>,
+    pub failed_rav_requests: Vec<FailedRavRequests>,
+}
+
+/// Receipts of a signer for an allocation.
+#[derive(Debug, Serialize)]
+pub struct ReceiptsSummary {
+    pub allocation_id: Address,
+    pub signer_address: Address,
+    pub count: u64,
+    #[serde(serialize_with = "wei")]
+    pub value: u128,
+    pub oldest_timestamp_ns: u64,
+    pub newest_timestamp_ns: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RavSummary {
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    #[serde(serialize_with = "wei")]
+    pub value_aggregate: u128,
+    pub last: bool,
+    pub is_final: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedRavRequests {
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub count: u64,
+}
+
+/// Summarizes the TAP tables, only for `allocation_id` if set.
+pub async fn inspect(pgpool: &PgPool, allocation_id: Option<Address>) -> Result<Inspection> {
+    let allocation_id = allocation_id.map(|id| id.encode_hex());
+
+    let receipts = sqlx::query!(
+        r#"
+            SELECT allocation_id, signer_address, COUNT(*) AS "count!", SUM(value) AS "value!",
+                MIN(timestamp_ns) AS "oldest!", MAX(timestamp_ns) AS "newest!"
+            FROM scalar_tap_receipts
+            WHERE $1::CHAR(40) IS NULL OR allocation_id = $1
+            GROUP BY allocation_id, signer_address
+            ORDER BY allocation_id, signer_address
+        "#,
+        allocation_id.as_deref()
+    )
+    .fetch_all(pgpool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(ReceiptsSummary {
+            allocation_id: Address::from_str(&row.allocation_id)?,
+            signer_address: Address::from_str(&row.signer_address)?,
+            count: row.count.try_into()?,
+            value: to_u128(&row.value)?,
+            oldest_timestamp_ns: to_u64(&row.oldest)?,
+            newest_timestamp_ns: to_u64(&row.newest)?,
+        })
+    })
+    .collect::<Result<_>>()?;
+
+    let ravs = sqlx::query!(
+        r#"
+            SELECT sender_address, allocation_id, timestamp_ns, value_aggregate, last,
+                final AS is_final
+            FROM scalar_tap_ravs
+            WHERE $1::CHAR(40) IS NULL OR allocation_id = $1
+            ORDER BY sender_address, allocation_id
+        "#,
+        allocation_id.as_deref()
+    )
+    .fetch_all(pgpool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(RavSummary {
+            sender: Address::from_str(&row.sender_address)?,
+            allocation_id: Address::from_str(&row.allocation_id)?,
+            timestamp_ns: to_u64(&row.timestamp_ns)?,
+            value_aggregate: to_u128(&row.value_aggregate)?,
+            last: row.last,
+            is_final: row.is_final,
+        })
+    })
+    .collect::<Result<_>>()?;
+
+    let denied_senders = sqlx::query_scalar!(
+        "SELECT sender_address FROM scalar_tap_denylist ORDER BY sender_address"
+    )
+    .fetch_all(pgpool)
+    .await?
+    .iter()
+    .map(|sender| Ok(Address::from_str(sender)?))
+    .collect::<Result<_>>()?;
+
+    let failed_rav_requests = sqlx::query!(
+        r#"
+            SELECT sender_address, allocation_id, COUNT(*) AS "count!"
+            FROM scalar_tap_rav_requests_failed
+            WHERE $1::CHAR(40) IS NULL OR allocation_id = $1
+            GROUP BY sender_address, allocation_id
+            ORDER BY sender_address, allocation_id
+        "#,
+        allocation_id.as_deref()
+    )
+    .fetch_all(pgpool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(FailedRavRequests {
+            sender: Address::from_str(&row.sender_address)?,
+            allocation_id: Address::from_str(&row.allocation_id)?,
+            count: row.count.try_into()?,
+        })
+    })
+    .collect::<Result<_>>()?;
+
+    Ok(Inspection {
+        receipts,
+        ravs,
+        denied_senders,
+        failed_rav_requests,
+    })
+}
+
+fn to_u64(value: &BigDecimal) -> Result<u64> {
+    value
+        .to_u64()
+        .ok_or_else(|| anyhow!("Error decoding {value} as a u64"))
+}
+
+fn to_u128(value: &BigDecimal) -> Result<u128> {
+    // BigDecimal::to_u128() goes through to_u64()
+    value
+        .to_bigint()
+        .and_then(|value| value.to_u128())
+        .ok_or_else(|| anyhow!("Error decoding {value} as a u128"))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::inspect;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_inspect(pgpool: PgPool) {
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i * 10, 5);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let receipt = create_received_receipt(&ALLOCATION_ID_1, &SIGNER.0, 4, 40, u128::MAX);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 4, 8),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+
+        let all = inspect(&pgpool, None).await.unwrap();
+        assert_eq!(all.receipts.len(), 2);
+        assert_eq!(all.ravs.len(), 1);
+        assert!(all.denied_senders.is_empty());
+
+        let inspection = inspect(&pgpool, Some(*ALLOCATION_ID_0)).await.unwrap();
+        assert_eq!(inspection.receipts.len(), 1);
+        let receipts = &inspection.receipts[0];
+        assert_eq!(receipts.signer_address, SIGNER.1);
+        assert_eq!(receipts.count, 3);
+        assert_eq!(receipts.value, 15);
+        assert_eq!(receipts.oldest_timestamp_ns, 10);
+        assert_eq!(receipts.newest_timestamp_ns, 30);
+        assert!(inspection.ravs.is_empty());
+
+        let inspection = inspect(&pgpool, Some(*ALLOCATION_ID_1)).await.unwrap();
+        assert_eq!(inspection.receipts[0].value, u128::MAX);
+        assert_eq!(inspection.ravs[0].sender, SENDER.1);
+        assert_eq!(inspection.ravs[0].value_aggregate, 8);
+    }
+}
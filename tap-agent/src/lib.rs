@@ -13,10 +13,48 @@ lazy_static! {
         CONFIG.receipts.receipts_verifier_chain_id,
         CONFIG.receipts.receipts_verifier_address,
     );
+    /// Domain of the Horizon (TAP v2) receipts and RAVs. Only set if they are collected.
+    pub static ref EIP_712_DOMAIN_V2: Option<Eip712Domain> = CONFIG
+        .receipts
+        .receipts_verifier_address_v2
+        .filter(|_| CONFIG.tap.horizon_enabled)
+        .map(|address| {
+            tap::horizon::eip712_domain(CONFIG.receipts.receipts_verifier_chain_id, address)
+        });
+    /// Domain of the indexing fee vouchers (DIPS) and their RAVs. Only set if they are aggregated.
+    pub static ref EIP_712_DOMAIN_DIPS: Option<Eip712Domain> =
+        CONFIG.tap.dips.as_ref().map(|dips| {
+            indexer_common::tap::dips::eip712_domain(
+                CONFIG.receipts.receipts_verifier_chain_id,
+                dips.verifier_address,
+            )
+        });
 }
 
+pub mod accounting_export;
+pub mod admin;
 pub mod agent;
+pub mod check_aggregator;
 pub mod config;
 pub mod database;
+pub mod db_maintenance;
+#[cfg(feature = "explorer")]
+pub mod explorer;
+pub mod health;
+pub mod inspect;
+pub mod logging;
 pub mod metrics;
+pub mod migration;
+pub mod money;
+pub mod privileges;
+pub mod replay;
+pub mod self_test;
+pub mod sender_api;
+pub mod signed_status;
+pub mod simulation;
+pub mod startup;
+pub mod state_archive;
+pub mod status;
+pub mod storage;
 pub mod tap;
+pub mod telemetry;
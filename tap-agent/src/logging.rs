@@ -0,0 +1,69 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers to keep the structured logs consistent across actors.
+//!
+//! Every log line related to a receipt or a RAV request carries the `sender`, `allocation` and
+//! `correlation_id` fields (through the enclosing span), plus an `event` field naming what
+//! happened. The correlation id is created when a receipt notification is received and is passed
+//! along in the actor messages up to the aggregator response, since spans don't cross actor
+//! mailboxes.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Seeded with the start time so ids don't repeat across restarts.
+    static ref NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    );
+}
+
+/// Values of the `event` log field.
+pub mod event {
+    pub const RECEIPT_RECEIVED: &str = "receipt_received";
+    pub const RAV_REQUEST_TRIGGERED: &str = "rav_request_triggered";
+    pub const RAV_REQUEST_SENT: &str = "rav_request_sent";
+    pub const RAV_RESPONSE_RECEIVED: &str = "rav_response_received";
+    pub const RAV_REQUEST_FAILED: &str = "rav_request_failed";
+    pub const RAV_ANOMALY: &str = "rav_anomaly";
+    pub const SENDER_DENIED: &str = "sender_denied";
+    pub const SENDER_ALLOWED: &str = "sender_allowed";
+    pub const ALLOCATION_QUARANTINED: &str = "allocation_quarantined";
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    pub fn new() -> Self {
+        Self(NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CorrelationId;
+
+    #[test]
+    fn test_correlation_ids_are_unique() {
+        let first = CorrelationId::new();
+        let second = CorrelationId::new();
+        assert_ne!(first, second);
+        assert_eq!(first.to_string().len(), 16);
+    }
+}
@@ -1,25 +1,329 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use std::{fs::File, io::BufWriter, net::SocketAddr};
+
+use anyhow::{Context, Result};
+use clap::Parser;
 use ractor::ActorStatus;
+use sqlx::PgPool;
 use tokio::signal::unix::{signal, SignalKind};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-use indexer_tap_agent::{agent, metrics, CONFIG};
+use indexer_common::escrow_accounts::log_unknown_signers;
+use indexer_tap_agent::config::{self, Cli, Command, Config, DbCommand, ReceiptCompactionMode};
+use indexer_tap_agent::{
+    accounting_export, admin, agent, check_aggregator, database, db_maintenance, health, inspect,
+    metrics, migration, money, replay, self_test, sender_api, signed_status, simulation, startup,
+    state_archive, status, telemetry, CONFIG,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let command = Cli::parse().command.unwrap_or(Command::Run);
+
+    // Before loading `CONFIG`, which panics on an invalid configuration.
+    if let Command::ValidateConfig = command {
+        let config = Config::load().context("Invalid configuration")?;
+        println!(
+            "Configuration is valid. Indexer {}, chain {}.",
+            config.ethereum.indexer_address, config.receipts.receipts_verifier_chain_id
+        );
+        return Ok(());
+    }
+    if let Command::BackfillStats = command {
+        anyhow::bail!(
+            "`backfill-stats` isn't supported, the statistics are read from the TAP tables when \
+             asked, run `db stats` instead"
+        );
+    }
+
     // Parse basic configurations, also initializes logging.
     lazy_static::initialize(&CONFIG);
+    money::init(&CONFIG.tap.fee_display);
+    if std::env::args()
+        .next()
+        .is_some_and(|arg| arg.ends_with("indexer-tap-agent"))
+    {
+        warn!("`indexer-tap-agent` is deprecated and will be removed, run `indexer-tap` instead");
+    }
 
-    let (manager, handler) = agent::start_agent().await;
-    info!("TAP Agent started.");
+    match command {
+        Command::Run => run().await,
+        Command::ValidateConfig | Command::BackfillStats => {
+            unreachable!("handled before loading the configuration")
+        }
+        Command::Inspect { allocation } => {
+            let inspection = inspect::inspect(&connect_read().await?, allocation).await?;
+            println!("{}", serde_json::to_string_pretty(&inspection)?);
+            Ok(())
+        }
+        Command::Replay {
+            after_id,
+            batch_size,
+            signer,
+            horizon,
+        } => {
+            let pgpool = connect().await?;
+            let replayed = if horizon {
+                replay::replay_horizon_receipt_notifications(&pgpool, after_id, batch_size, signer)
+                    .await?
+            } else {
+                replay::replay_receipt_notifications(&pgpool, after_id, batch_size, signer).await?
+            };
+            info!(replayed, "Receipt notifications replayed.");
+            Ok(())
+        }
+        Command::MigrateLegacySchema {
+            dry_run,
+            batch_size,
+        } => {
+            let report = migration::migrate_legacy_schema(
+                &connect().await?,
+                &migration::MigrationOptions {
+                    dry_run,
+                    batch_size,
+                },
+            )
+            .await?;
+            info!(
+                receipts = report.receipts_migrated,
+                ravs = report.ravs_migrated,
+                dry_run,
+                "Legacy schema migration finished."
+            );
+            Ok(())
+        }
+        Command::SelfTest => {
+            self_test::run(&connect().await?).await?;
+            info!("Self-test passed.");
+            Ok(())
+        }
+        Command::CheckAggregator { sender } => {
+            check_aggregator::run(sender).await?;
+            info!("Aggregator check passed.");
+            Ok(())
+        }
+        Command::ExportState {
+            output,
+            compression_level,
+        } => {
+            let archive =
+                state_archive::export_state(&connect().await?, &CONFIG, &output, compression_level)
+                    .await?;
+            info!(
+                path = %output.display(),
+                tables = archive.tables.len(),
+                compression_level,
+                "State exported."
+            );
+            Ok(())
+        }
+        Command::ExportAccounting {
+            kind,
+            from,
+            to,
+            format,
+            output,
+        } => {
+            let options = accounting_export::ExportOptions {
+                kind,
+                format,
+                from,
+                to,
+                horizon: CONFIG.tap.horizon_enabled,
+            };
+            let escrow_accounts = accounting_export::escrow_accounts().await;
+            let pgpool = connect_read().await?;
+            let rows = match &output {
+                Some(output) => {
+                    let mut file = BufWriter::new(File::create(output)?);
+                    accounting_export::export(&pgpool, &options, &escrow_accounts, &mut file)
+                        .await?
+                }
+                None => {
+                    let mut stdout = std::io::stdout().lock();
+                    accounting_export::export(&pgpool, &options, &escrow_accounts, &mut stdout)
+                        .await?
+                }
+            };
+            info!(rows, ?kind, "Accounting export finished.");
+            Ok(())
+        }
+        Command::Simulate {
+            sender,
+            from,
+            to,
+            mut signers,
+        } => {
+            if signers.is_empty() {
+                signers = accounting_export::escrow_accounts()
+                    .await
+                    .get_signers_for_sender(&sender);
+            }
+            anyhow::ensure!(
+                !signers.is_empty(),
+                "No signer found for sender {sender}, set them with `--signer`"
+            );
+            let options = simulation::SimulationOptions {
+                sender,
+                signers,
+                from,
+                to,
+            };
+            let report =
+                simulation::simulate(&connect_read().await?, &options, &CONFIG.tap).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Command::Db { command } => db(command).await,
+        Command::ImportState { input } => {
+            let archive = state_archive::import_state(&connect().await?, &CONFIG, &input).await?;
+            info!(
+                path = %input.display(),
+                exported_at = archive.header.exported_at,
+                "State imported."
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Database of the subcommands other than `run`.
+async fn connect() -> Result<PgPool> {
+    database::connect(&CONFIG.postgres, database::Component::SenderAccount).await
+}
+
+/// Database of the read-only subcommands, the read replica if it's set and in sync.
+async fn connect_read() -> Result<PgPool> {
+    let pgpool = connect().await?;
+    database::connect_read_replica(&CONFIG.postgres).await?;
+    Ok(database::read_pool(&pgpool))
+}
 
+async fn db(command: DbCommand) -> Result<()> {
+    match command {
+        DbCommand::Check => {
+            let escrow_accounts = accounting_export::escrow_accounts().await;
+            let report = db_maintenance::check(&connect_read().await?, &escrow_accounts).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            anyhow::ensure!(
+                report.issues.is_empty(),
+                "{} issues found",
+                report.issues.len()
+            );
+            info!(ravs = report.ravs_checked, "No issue found.");
+        }
+        DbCommand::Prune { dry_run } => {
+            let config =
+                CONFIG
+                    .tap
+                    .receipt_compaction
+                    .clone()
+                    .unwrap_or(config::ReceiptCompaction {
+                        mode: ReceiptCompactionMode::Archive,
+                        interval: Default::default(),
+                        batch_size: 1000,
+                        dry_run,
+                    });
+            let escrow_accounts = accounting_export::escrow_accounts().await;
+            let pgpool = connect().await?;
+            if dry_run {
+                let report =
+                    agent::receipt_compaction::report(&pgpool, &escrow_accounts, &config).await?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                let receipts =
+                    agent::receipt_compaction::compact(&pgpool, &escrow_accounts, &config).await?;
+                info!(receipts, mode = ?config.mode, "Receipts pruned.");
+            }
+        }
+        DbCommand::Deny { sender, note } => {
+            let escrow_accounts = accounting_export::escrow_accounts().await;
+            let pgpool = connect().await?;
+            let exposure = db_maintenance::exposure(&pgpool, &escrow_accounts, sender).await?;
+            for warning in exposure.warnings(true) {
+                warn!("{warning}");
+            }
+            let context = note.map_or(
+                serde_json::Value::Null,
+                |note| serde_json::json!({ "note": note }),
+            );
+            db_maintenance::deny(&pgpool, sender, context).await?;
+            info!(%sender, "Sender denied.");
+        }
+        DbCommand::Allow { sender, force } => {
+            let escrow_accounts = accounting_export::escrow_accounts().await;
+            let pgpool = connect().await?;
+            let exposure = db_maintenance::exposure(&pgpool, &escrow_accounts, sender).await?;
+            for warning in exposure.warnings(false) {
+                warn!("{warning}");
+            }
+            db_maintenance::allow(&pgpool, &exposure, force).await?;
+            info!(%sender, "Sender allowed.");
+        }
+        DbCommand::Stats => {
+            let stats = db_maintenance::stats(&connect_read().await?).await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+    }
+    Ok(())
+}
+
+async fn run() -> Result<()> {
+    tokio::spawn(log_unknown_signers());
+    // Served during the startup, to report its phases
+    let metrics_routes = startup::Routes::default();
+    let admin_routes = startup::Routes::default();
+    let metrics_listener = CONFIG
+        .indexer_infrastructure
+        .metrics_listener
+        .clone()
+        .unwrap_or_else(|| {
+            SocketAddr::from(([0, 0, 0, 0], CONFIG.indexer_infrastructure.metrics_port)).into()
+        });
     tokio::spawn(metrics::run_server(
-        CONFIG.indexer_infrastructure.metrics_port,
+        metrics_listener,
+        metrics_routes.router(),
     ));
-    info!("Metrics port opened");
+    match &CONFIG.tap.admin_listener {
+        Some(admin_listener) => {
+            tokio::spawn(metrics::run_admin_server(
+                admin_listener.clone(),
+                admin_routes.router(),
+            ));
+            info!("Metrics port opened, admin and status port opened");
+        }
+        None => info!("Metrics and status port opened"),
+    }
+
+    let (manager, handler, health_state, status_state) = agent::start_agent().await?;
+    info!("TAP Agent started.");
+
+    if let Some(sender_api) = &CONFIG.tap.sender_api {
+        tokio::spawn(metrics::run_sender_api_server(
+            sender_api.listener.clone(),
+            sender_api::router(
+                status_state.clone(),
+                CONFIG.ethereum.indexer_address,
+                sender_api.max_request_age,
+            ),
+        ));
+        info!("Sender API port opened");
+    }
+    let mut status_routes = admin::router(manager.clone()).merge(status::router(status_state));
+    if CONFIG.tap.signed_status.is_some() {
+        status_routes = status_routes.merge(signed_status::router());
+    }
+    #[cfg(feature = "explorer")]
+    let status_routes = status_routes.merge(indexer_tap_agent::explorer::router());
+    if CONFIG.tap.admin_listener.is_some() {
+        metrics_routes.set(health::router(health_state));
+        admin_routes.set(status_routes);
+    } else {
+        metrics_routes.set(health::router(health_state).merge(status_routes));
+    }
+    info!("Health, admin and status routes served");
 
     // Have tokio wait for SIGTERM or SIGINT.
     let mut signal_sigint = signal(SignalKind::interrupt())?;
@@ -40,6 +344,8 @@ async fn main() -> Result<()> {
             .expect("Failed to kill manager.");
     }
 
+    telemetry::shutdown();
+
     // Stop the server and wait for it to finish gracefully.
     debug!("Goodbye!");
     Ok(())
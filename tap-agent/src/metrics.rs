@@ -1,23 +1,50 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::SocketAddr, panic};
+pub mod exemplars;
+pub mod export;
+pub mod series;
 
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use std::{future::Future, panic};
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
 use futures_util::FutureExt;
+use indexer_common::listener::{self, Listener};
 use prometheus::TextEncoder;
 use tracing::{debug, error, info};
 
-async fn handler_metrics() -> (StatusCode, String) {
+/// Serves the OpenMetrics format, with the exemplars, to the scrapers asking for it.
+async fn handler_metrics(headers: HeaderMap) -> impl IntoResponse {
     let metric_families = prometheus::gather();
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    if openmetrics {
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, exemplars::CONTENT_TYPE)],
+            exemplars::encode(&metric_families),
+        );
+    }
     let encoder = TextEncoder::new();
 
     match encoder.encode_to_string(&metric_families) {
-        Ok(s) => (StatusCode::OK, s),
+        Ok(s) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            s,
+        ),
         Err(e) => {
             error!("Error encoding metrics: {}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
                 format!("Error encoding metrics: {}", e),
             )
         }
@@ -28,34 +55,42 @@ async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found")
 }
 
-async fn _run_server(port: u16) {
-    let app = Router::new()
-        .route("/metrics", get(handler_metrics))
-        .fallback(handler_404);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .expect("Failed to Bind metrics address`");
-    let server = axum::serve(listener, app.into_make_service());
+async fn _run_server(name: &'static str, listener: Listener, routes: Router) {
+    let app = routes.fallback(handler_404);
 
-    info!("Metrics server listening on {}", addr);
+    info!("{} server listening on {}", name, listener.address);
 
-    let res = server.await;
+    let res = listener::serve(&listener, app, std::future::pending()).await;
 
-    debug!("Metrics server stopped");
+    debug!("{} server stopped", name);
 
     if let Err(err) = res {
-        panic!("Metrics server error: {:#?}", err);
+        panic!("{} server error: {:#?}", name, err);
     };
 }
 
-pub async fn run_server(port: u16) {
-    // Code here is to abort program if there is a panic in _run_server
-    // Otherwise, when spawning the task, the panic will be silently ignored
-    let res = panic::AssertUnwindSafe(_run_server(port))
-        .catch_unwind()
-        .await;
+// Code here is to abort program if there is a panic in _run_server
+// Otherwise, when spawning the task, the panic will be silently ignored
+async fn abort_on_panic(server: impl Future<Output = ()>) {
+    let res = panic::AssertUnwindSafe(server).catch_unwind().await;
     if res.is_err() {
         std::process::abort();
     }
 }
+
+pub async fn run_server(listener: Listener, extra_routes: Router) {
+    let routes = Router::new()
+        .route("/metrics", get(handler_metrics))
+        .merge(extra_routes);
+    abort_on_panic(_run_server("Metrics", listener, routes)).await
+}
+
+/// Serves the admin and status endpoints apart from the metrics, see `tap.admin_listener`.
+pub async fn run_admin_server(listener: Listener, routes: Router) {
+    abort_on_panic(_run_server("Admin", listener, routes)).await
+}
+
+/// Serves the endpoints of the gateways, see [`crate::sender_api`].
+pub async fn run_sender_api_server(listener: Listener, routes: Router) {
+    abort_on_panic(_run_server("Sender API", listener, routes)).await
+}
@@ -0,0 +1,358 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exemplars of the RAV metrics, linking their samples to the traces of [`crate::telemetry`].
+//!
+//! The metrics recorded through [`observe`] and [`inc`] within a sampled trace keep its id as
+//! the exemplar of their series, or of their bucket for the histograms, so that a spike in
+//! Grafana leads to the RAV requests behind it. The prometheus crate doesn't support exemplars,
+//! they're kept here, and only exposed in the OpenMetrics format of [`encode`], served to the
+//! scrapers asking for it. The others still get the text format, without exemplars.
+//!
+//! A counter is only exposed as such in OpenMetrics if its name ends with `_total`, as the
+//! format requires. The others are exposed as `unknown`, under the same name.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use opentelemetry::trace::TraceContextExt;
+use prometheus::{
+    core::{Collector, Metric},
+    proto::{self, MetricFamily, MetricType},
+    Counter, Histogram,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A series by its metric name and labels, and its bucket for the histograms.
+type ExemplarKey = (String, Vec<(String, String)>, Option<usize>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    /// Seconds since the UNIX epoch.
+    timestamp: f64,
+}
+
+lazy_static! {
+    static ref EXEMPLARS: Mutex<HashMap<ExemplarKey, Exemplar>> = Mutex::new(HashMap::new());
+}
+
+/// Observes `value`, with the current trace as the exemplar of its bucket.
+pub fn observe(histogram: &Histogram, value: f64) {
+    histogram.observe(value);
+    if let Some(trace_id) = current_trace_id() {
+        let metric = histogram.metric();
+        let buckets = metric.get_histogram().get_bucket();
+        let bucket = buckets
+            .iter()
+            .position(|bucket| value <= bucket.get_upper_bound())
+            .unwrap_or(buckets.len());
+        record(key(histogram, &metric, Some(bucket)), trace_id, value);
+    }
+}
+
+/// Increments `counter`, with the current trace as the exemplar of its series.
+pub fn inc(counter: &Counter) {
+    counter.inc();
+    if let Some(trace_id) = current_trace_id() {
+        record(key(counter, &counter.metric(), None), trace_id, 1.0);
+    }
+}
+
+/// The id of the current trace, if it's sampled and so exported.
+fn current_trace_id() -> Option<String> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    (span_context.is_valid() && span_context.is_sampled())
+        .then(|| span_context.trace_id().to_string())
+}
+
+fn key(collector: &impl Collector, metric: &proto::Metric, bucket: Option<usize>) -> ExemplarKey {
+    let name = collector
+        .desc()
+        .first()
+        .map(|desc| desc.fq_name.clone())
+        .unwrap_or_default();
+    (name, labels(metric), bucket)
+}
+
+fn labels(metric: &proto::Metric) -> Vec<(String, String)> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+        .collect()
+}
+
+fn record(key: ExemplarKey, trace_id: String, value: f64) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    EXEMPLARS.lock().unwrap().insert(
+        key,
+        Exemplar {
+            trace_id,
+            value,
+            timestamp,
+        },
+    );
+}
+
+/// Encodes `families` in the OpenMetrics text format, with the exemplars of their series. The
+/// exemplars of the series removed since are forgotten.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let mut exemplars = EXEMPLARS.lock().unwrap();
+    let mut seen = HashSet::new();
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (family_name, metric_type) = match family.get_field_type() {
+            MetricType::COUNTER => match name.strip_suffix("_total") {
+                Some(family_name) => (family_name, "counter"),
+                None => (name, "unknown"),
+            },
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# HELP {family_name} {}", escape(family.get_help()));
+        let _ = writeln!(out, "# TYPE {family_name} {metric_type}");
+        for metric in family.get_metric() {
+            let labels = labels(metric);
+            let mut exemplar = |bucket: Option<usize>| {
+                let key = (name.to_string(), labels.clone(), bucket);
+                let exemplar = exemplars.get(&key).cloned();
+                seen.insert(key);
+                exemplar
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let exemplar = if metric_type == "counter" {
+                        exemplar(None)
+                    } else {
+                        None
+                    };
+                    sample(
+                        &mut out,
+                        name,
+                        &labels,
+                        None,
+                        metric.get_counter().get_value(),
+                        exemplar.as_ref(),
+                    );
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    sample(&mut out, name, &labels, None, value, None);
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    sample(&mut out, name, &labels, None, value, None);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{name}_bucket");
+                    for (i, bucket) in histogram.get_bucket().iter().enumerate() {
+                        sample(
+                            &mut out,
+                            &bucket_name,
+                            &labels,
+                            Some(("le", bucket.get_upper_bound())),
+                            bucket.get_cumulative_count() as f64,
+                            exemplar(Some(i)).as_ref(),
+                        );
+                    }
+                    sample(
+                        &mut out,
+                        &bucket_name,
+                        &labels,
+                        Some(("le", f64::INFINITY)),
+                        histogram.get_sample_count() as f64,
+                        exemplar(Some(histogram.get_bucket().len())).as_ref(),
+                    );
+                    let (sum, count) = (histogram.get_sample_sum(), histogram.get_sample_count());
+                    sample(&mut out, &format!("{name}_sum"), &labels, None, sum, None);
+                    let count = count as f64;
+                    sample(
+                        &mut out,
+                        &format!("{name}_count"),
+                        &labels,
+                        None,
+                        count,
+                        None,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        sample(
+                            &mut out,
+                            name,
+                            &labels,
+                            Some(("quantile", quantile.get_quantile())),
+                            quantile.get_value(),
+                            None,
+                        );
+                    }
+                    let (sum, count) = (summary.get_sample_sum(), summary.get_sample_count());
+                    sample(&mut out, &format!("{name}_sum"), &labels, None, sum, None);
+                    let count = count as f64;
+                    sample(
+                        &mut out,
+                        &format!("{name}_count"),
+                        &labels,
+                        None,
+                        count,
+                        None,
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    exemplars.retain(|key, _| seen.contains(key));
+    out
+}
+
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    extra_label: Option<(&str, f64)>,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) {
+    out.push_str(name);
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some((name, value)) = extra_label {
+        labels.push(format!("{name}=\"{}\"", number(value)));
+    }
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = write!(out, " {}", number(value));
+    if let Some(exemplar) = exemplar {
+        let _ = write!(
+            out,
+            " # {{trace_id=\"{}\"}} {} {}",
+            exemplar.trace_id,
+            number(exemplar.value),
+            exemplar.timestamp
+        );
+    }
+    out.push('\n');
+}
+
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{core::Metric, CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+
+    use super::{encode, key, record, EXEMPLARS};
+
+    #[test]
+    fn test_encode_with_exemplars() {
+        let registry = Registry::new();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("test_exemplars_seconds", "Test histogram").buckets(vec![0.5, 1.0]),
+            &["sender"],
+        )
+        .unwrap();
+        let created = CounterVec::new(
+            Opts::new("test_exemplars_total", "Test counter"),
+            &["sender"],
+        )
+        .unwrap();
+        let legacy = CounterVec::new(
+            Opts::new("test_exemplars_legacy", "Test counter"),
+            &["sender"],
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        registry.register(Box::new(created.clone())).unwrap();
+        registry.register(Box::new(legacy.clone())).unwrap();
+
+        // Recorded as `observe` and `inc` do within a sampled trace
+        let series = histogram.with_label_values(&["a"]);
+        series.observe(0.75);
+        record(key(&series, &series.metric(), Some(1)), "1234".into(), 0.75);
+        let series = created.with_label_values(&["a"]);
+        series.inc();
+        record(key(&series, &series.metric(), None), "5678".into(), 1.0);
+        legacy.with_label_values(&["a"]).inc();
+
+        let encoded = encode(&registry.gather());
+        let lines: Vec<_> = encoded
+            .lines()
+            .map(|line| match line.split_once(" # ") {
+                // Without the timestamp of the exemplar
+                Some((sample, exemplar)) => {
+                    format!("{sample} # {}", exemplar.rsplit_once(' ').unwrap().0)
+                }
+                None => line.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "# HELP test_exemplars_legacy Test counter",
+                "# TYPE test_exemplars_legacy unknown",
+                "test_exemplars_legacy{sender=\"a\"} 1",
+                "# HELP test_exemplars_seconds Test histogram",
+                "# TYPE test_exemplars_seconds histogram",
+                "test_exemplars_seconds_bucket{sender=\"a\",le=\"0.5\"} 0",
+                "test_exemplars_seconds_bucket{sender=\"a\",le=\"1\"} 1 # {trace_id=\"1234\"} 0.75",
+                "test_exemplars_seconds_bucket{sender=\"a\",le=\"+Inf\"} 1",
+                "test_exemplars_seconds_sum{sender=\"a\"} 0.75",
+                "test_exemplars_seconds_count{sender=\"a\"} 1",
+                "# HELP test_exemplars Test counter",
+                "# TYPE test_exemplars counter",
+                "test_exemplars_total{sender=\"a\"} 1 # {trace_id=\"5678\"} 1",
+                "# EOF",
+            ]
+        );
+
+        // Forgotten with their series
+        histogram.remove_label_values(&["a"]).unwrap();
+        encode(&registry.gather());
+        let exemplars = EXEMPLARS.lock().unwrap();
+        assert!(!exemplars
+            .keys()
+            .any(|(name, _, _)| name == "test_exemplars_seconds"));
+        assert!(exemplars
+            .keys()
+            .any(|(name, _, _)| name == "test_exemplars_total"));
+    }
+}
@@ -0,0 +1,410 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Push of the metrics to the backends other than Prometheus, see `metrics.export`.
+//!
+//! The metrics are still recorded in the Prometheus registry, which stays the source of truth
+//! and keeps being served on `/metrics`. Every interval, its content is gathered and handed to a
+//! [`MetricsBackend`]:
+//! - [`Statsd`] sends them to a StatsD agent such as the Datadog agent, in the DogStatsD format,
+//!   with the labels as tags. StatsD counters being deltas, the counters are sent as the increase
+//!   since the last push, and the histograms as the increase of their count and sum.
+//! - [`Otlp`] posts them to an OpenTelemetry collector over OTLP/HTTP, JSON encoded, with a
+//!   cumulative temporality as in Prometheus.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use prometheus::proto::{self, MetricFamily, MetricType};
+use reqwest::Url;
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::config::{MetricsBackend as BackendConfig, MetricsExport};
+
+const SERVICE_NAME: &str = "indexer-tap-agent";
+/// Keeps the datagrams under the usual MTU.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+#[async_trait]
+pub trait MetricsBackend: Send + Sync {
+    async fn push(&self, families: &[MetricFamily]) -> Result<()>;
+}
+
+/// Pushes the metrics to the backend of `config` every interval, forever.
+pub async fn run(http_client: reqwest::Client, config: MetricsExport) {
+    let backend: Box<dyn MetricsBackend> = match config.backend {
+        BackendConfig::Statsd { address, prefix } => {
+            match Statsd::connect(&address, prefix).await {
+                Ok(statsd) => Box::new(statsd),
+                Err(error) => {
+                    warn!(%error, "Failed to set up the StatsD export of the metrics.");
+                    return;
+                }
+            }
+        }
+        BackendConfig::Otlp { endpoint } => Box::new(Otlp::new(http_client, endpoint)),
+    };
+    info!(
+        "Pushing the metrics every {}s.",
+        config.interval.as_secs_f64()
+    );
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        if let Err(error) = backend.push(&prometheus::gather()).await {
+            warn!(%error, "Failed to push the metrics.");
+        }
+    }
+}
+
+fn labels(metric: &proto::Metric) -> impl Iterator<Item = (&str, &str)> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+}
+
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Last value of the counters, by line without the value, to send their increase.
+    counters: Mutex<HashMap<String, f64>>,
+}
+
+impl Statsd {
+    pub async fn connect(address: &str, prefix: String) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket
+            .connect(address)
+            .await
+            .with_context(|| format!("Failed to resolve the StatsD agent `{address}`"))?;
+        Ok(Self {
+            socket,
+            prefix,
+            counters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The DogStatsD lines of the metrics.
+    fn lines(&self, families: &[MetricFamily]) -> Vec<String> {
+        let mut counters = self.counters.lock().unwrap();
+        let mut lines = Vec::new();
+        let mut counter = |name: String, tags: &str, value: f64| {
+            let key = format!("{name}|c{tags}");
+            let previous = counters.insert(key, value).unwrap_or_default();
+            // Reset, such as a series removed then recorded again
+            let delta = if value < previous {
+                value
+            } else {
+                value - previous
+            };
+            if delta > 0.0 {
+                lines.push(format!("{name}:{delta}|c{tags}"));
+            }
+        };
+        let mut gauges = Vec::new();
+        for family in families {
+            let name = format!("{}{}", self.prefix, family.get_name());
+            for metric in family.get_metric() {
+                let tags = tags(metric);
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        counter(name.clone(), &tags, metric.get_counter().get_value())
+                    }
+                    MetricType::GAUGE => {
+                        gauges.push(format!("{name}:{}|g{tags}", metric.get_gauge().get_value()))
+                    }
+                    MetricType::UNTYPED => gauges.push(format!(
+                        "{name}:{}|g{tags}",
+                        metric.get_untyped().get_value()
+                    )),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        counter(
+                            format!("{name}.count"),
+                            &tags,
+                            histogram.get_sample_count() as f64,
+                        );
+                        counter(format!("{name}.sum"), &tags, histogram.get_sample_sum());
+                    }
+                    MetricType::SUMMARY => {}
+                }
+            }
+        }
+        lines.extend(gauges);
+        lines
+    }
+}
+
+/// The labels of a series as DogStatsD tags, `|#name:value,...`.
+fn tags(metric: &proto::Metric) -> String {
+    let tags: Vec<_> = labels(metric)
+        .map(|(name, value)| format!("{name}:{}", value.replace([',', '|', '#'], "_")))
+        .collect();
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!("|#{}", tags.join(","))
+    }
+}
+
+#[async_trait]
+impl MetricsBackend for Statsd {
+    async fn push(&self, families: &[MetricFamily]) -> Result<()> {
+        let mut datagram = String::new();
+        for line in self.lines(families) {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_SIZE {
+                self.socket.send(datagram.as_bytes()).await?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Otlp {
+    http_client: reqwest::Client,
+    endpoint: Url,
+    /// Start of the cumulative series, in nanoseconds since the UNIX epoch.
+    start_time_ns: u128,
+}
+
+impl Otlp {
+    pub fn new(http_client: reqwest::Client, endpoint: Url) -> Self {
+        Self {
+            http_client,
+            endpoint,
+            start_time_ns: now_ns(),
+        }
+    }
+
+    /// The `ExportMetricsServiceRequest` of the metrics, in the JSON encoding of OTLP.
+    fn request(&self, families: &[MetricFamily], time_ns: u128) -> Value {
+        let start_time_ns = self.start_time_ns.to_string();
+        let time_ns = time_ns.to_string();
+        let data_point = |metric: &proto::Metric| {
+            json!({
+                "attributes": labels(metric)
+                    .map(|(name, value)| json!({"key": name, "value": {"stringValue": value}}))
+                    .collect::<Vec<_>>(),
+                "startTimeUnixNano": start_time_ns,
+                "timeUnixNano": time_ns,
+            })
+        };
+        let with_value = |metric: &proto::Metric, value: f64| {
+            let mut point = data_point(metric);
+            point["asDouble"] = json!(value);
+            point
+        };
+        let metrics: Vec<_> = families
+            .iter()
+            .filter_map(|family| {
+                let points = family.get_metric().iter();
+                let data = match family.get_field_type() {
+                    MetricType::COUNTER => json!({"sum": {
+                        "dataPoints": points
+                            .map(|metric| with_value(metric, metric.get_counter().get_value()))
+                            .collect::<Vec<_>>(),
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    }}),
+                    MetricType::GAUGE => json!({"gauge": {
+                        "dataPoints": points
+                            .map(|metric| with_value(metric, metric.get_gauge().get_value()))
+                            .collect::<Vec<_>>(),
+                    }}),
+                    MetricType::UNTYPED => json!({"gauge": {
+                        "dataPoints": points
+                            .map(|metric| with_value(metric, metric.get_untyped().get_value()))
+                            .collect::<Vec<_>>(),
+                    }}),
+                    MetricType::HISTOGRAM => json!({"histogram": {
+                        "dataPoints": points
+                            .map(|metric| {
+                                let mut point = data_point(metric);
+                                histogram_point(&mut point, metric.get_histogram());
+                                point
+                            })
+                            .collect::<Vec<_>>(),
+                        "aggregationTemporality": 2,
+                    }}),
+                    MetricType::SUMMARY => return None,
+                };
+                let mut metric = json!({
+                    "name": family.get_name(),
+                    "description": family.get_help(),
+                });
+                metric.as_object_mut()?.extend(data.as_object()?.clone());
+                Some(metric)
+            })
+            .collect();
+        json!({"resourceMetrics": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": SERVICE_NAME}},
+            ]},
+            "scopeMetrics": [{
+                "scope": {"name": SERVICE_NAME},
+                "metrics": metrics,
+            }],
+        }]})
+    }
+}
+
+/// The buckets of Prometheus are cumulative, the ones of OTLP aren't and end with the overflow
+/// bucket. The 64-bit integers are strings in the JSON encoding.
+fn histogram_point(point: &mut Value, histogram: &proto::Histogram) {
+    let mut bucket_counts = Vec::new();
+    let mut explicit_bounds = Vec::new();
+    let mut previous = 0;
+    for bucket in histogram.get_bucket() {
+        if bucket.get_upper_bound().is_infinite() {
+            continue;
+        }
+        explicit_bounds.push(bucket.get_upper_bound());
+        bucket_counts.push((bucket.get_cumulative_count() - previous).to_string());
+        previous = bucket.get_cumulative_count();
+    }
+    bucket_counts.push((histogram.get_sample_count() - previous).to_string());
+    point["count"] = json!(histogram.get_sample_count().to_string());
+    point["sum"] = json!(histogram.get_sample_sum());
+    point["bucketCounts"] = json!(bucket_counts);
+    point["explicitBounds"] = json!(explicit_bounds);
+}
+
+fn now_ns() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[async_trait]
+impl MetricsBackend for Otlp {
+    async fn push(&self, families: &[MetricFamily]) -> Result<()> {
+        let body = serde_json::to_vec(&self.request(families, now_ns()))?;
+        self.http_client
+            .post(self.endpoint.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to post the metrics to {}", self.endpoint))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{CounterVec, Gauge, Histogram, HistogramOpts, Opts, Registry};
+
+    use super::*;
+
+    fn registry() -> (Registry, CounterVec, Gauge, Histogram) {
+        let registry = Registry::new();
+        let counter =
+            CounterVec::new(Opts::new("receipts_total", "Receipts."), &["sender"]).unwrap();
+        let gauge = Gauge::new("pending_fees", "Fees.").unwrap();
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("rav_seconds", "RAV requests.").buckets(vec![1.0, 5.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        (registry, counter, gauge, histogram)
+    }
+
+    #[tokio::test]
+    async fn test_statsd_lines() {
+        let (registry, counter, gauge, histogram) = registry();
+        let statsd = Statsd::connect("127.0.0.1:8125", "indexer.".to_string())
+            .await
+            .unwrap();
+
+        counter.with_label_values(&["0xab,c"]).inc_by(3.0);
+        gauge.set(42.0);
+        histogram.observe(2.0);
+        let mut lines = statsd.lines(&registry.gather());
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "indexer.pending_fees:42|g",
+                "indexer.rav_seconds.count:1|c",
+                "indexer.rav_seconds.sum:2|c",
+                "indexer.receipts_total:3|c|#sender:0xab_c",
+            ]
+        );
+
+        // Only the increase of the counters
+        counter.with_label_values(&["0xab,c"]).inc_by(2.0);
+        let lines = statsd.lines(&registry.gather());
+        assert_eq!(
+            lines,
+            vec![
+                "indexer.receipts_total:2|c|#sender:0xab_c",
+                "indexer.pending_fees:42|g",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_otlp_request() {
+        let (registry, counter, gauge, histogram) = registry();
+        let otlp = Otlp::new(
+            reqwest::Client::new(),
+            "http://localhost:4318/v1/metrics".parse().unwrap(),
+        );
+        counter.with_label_values(&["0xabc"]).inc_by(3.0);
+        gauge.set(42.0);
+        histogram.observe(2.0);
+        histogram.observe(10.0);
+
+        let request = otlp.request(&registry.gather(), otlp.start_time_ns + 1);
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let metric = |name: &str| {
+            metrics
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|metric| metric["name"] == name)
+                .unwrap()
+                .clone()
+        };
+
+        let receipts = metric("receipts_total");
+        assert_eq!(receipts["sum"]["isMonotonic"], true);
+        let point = &receipts["sum"]["dataPoints"][0];
+        assert_eq!(point["asDouble"], 3.0);
+        assert_eq!(
+            point["attributes"],
+            json!([{"key": "sender", "value": {"stringValue": "0xabc"}}])
+        );
+
+        assert_eq!(
+            metric("pending_fees")["gauge"]["dataPoints"][0]["asDouble"],
+            42.0
+        );
+
+        let point = &metric("rav_seconds")["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "2");
+        assert_eq!(point["sum"], 12.0);
+        assert_eq!(point["explicitBounds"], json!([1.0, 5.0]));
+        assert_eq!(point["bucketCounts"], json!(["0", "1", "1"]));
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The series created by an entity, a sender or an allocation, removed along with it.
+//!
+//! A series of a metric vector, one per set of label values, is exported until it's removed. The
+//! actors record theirs through a [`SeriesOwner`] instead of calling `with_label_values`
+//! directly, and it removes all of them once they stop, or when it's dropped, rather than each
+//! teardown path removing the series it knows about.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use prometheus::core::{MetricVec, MetricVecBuilder};
+
+trait RemoveSeries: Send + Sync {
+    fn remove_series(&self, labels: &[&str]);
+}
+
+impl<T: MetricVecBuilder> RemoveSeries for MetricVec<T> {
+    fn remove_series(&self, labels: &[&str]) {
+        let _ = self.remove_label_values(labels);
+    }
+}
+
+/// A series, by the address of its vector and its label values.
+type SeriesKey = (usize, Vec<String>);
+
+#[derive(Default)]
+pub struct SeriesOwner {
+    series: Mutex<HashMap<SeriesKey, &'static dyn RemoveSeries>>,
+}
+
+impl SeriesOwner {
+    /// The series of `vec` with `labels`, removed with the others of the owner.
+    pub fn with_label_values<T: MetricVecBuilder + 'static>(
+        &self,
+        vec: &'static MetricVec<T>,
+        labels: &[&str],
+    ) -> T::M {
+        self.series
+            .lock()
+            .unwrap()
+            .entry(key(vec, labels))
+            .or_insert(vec);
+        vec.with_label_values(labels)
+    }
+
+    /// Removes a series of the owner before the others.
+    pub fn remove<T: MetricVecBuilder + 'static>(
+        &self,
+        vec: &'static MetricVec<T>,
+        labels: &[&str],
+    ) {
+        self.series.lock().unwrap().remove(&key(vec, labels));
+        vec.remove_series(labels);
+    }
+
+    /// Removes all the series of the owner. The ones created afterwards are removed on drop.
+    pub fn remove_all(&self) {
+        let series = std::mem::take(&mut *self.series.lock().unwrap());
+        for ((_, labels), vec) in series {
+            vec.remove_series(&labels.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+    }
+}
+
+impl Drop for SeriesOwner {
+    fn drop(&mut self) {
+        self.remove_all();
+    }
+}
+
+fn key<T: MetricVecBuilder>(vec: &MetricVec<T>, labels: &[&str]) -> SeriesKey {
+    (
+        vec as *const MetricVec<T> as usize,
+        labels.iter().map(|label| label.to_string()).collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{core::Collector, IntGaugeVec, Opts};
+
+    use super::SeriesOwner;
+
+    fn series(vec: &IntGaugeVec) -> usize {
+        vec.collect()[0].get_metric().len()
+    }
+
+    #[test]
+    fn test_series_removed_with_owner() {
+        let vec: &'static IntGaugeVec = Box::leak(Box::new(
+            IntGaugeVec::new(Opts::new("test_series", "test"), &["sender", "allocation"]).unwrap(),
+        ));
+
+        let owner = SeriesOwner::default();
+        owner.with_label_values(vec, &["a", "1"]).set(1);
+        owner.with_label_values(vec, &["a", "2"]).set(2);
+        owner.with_label_values(vec, &["a", "2"]).set(3);
+        // Not the owner's
+        vec.with_label_values(&["b", "1"]).set(4);
+        assert_eq!(series(vec), 3);
+
+        owner.remove(vec, &["a", "1"]);
+        assert_eq!(series(vec), 2);
+
+        owner.remove_all();
+        assert_eq!(series(vec), 1);
+
+        // Recorded again after `remove_all`, removed on drop
+        owner.with_label_values(vec, &["a", "3"]).set(5);
+        assert_eq!(series(vec), 2);
+        drop(owner);
+        assert_eq!(series(vec), 1);
+    }
+}
@@ -0,0 +1,649 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Migration of receipts and RAVs stored with a legacy schema into the current TAP tables.
+//!
+//! Older releases stored the whole EIP-712 message as a JSON column (`receipt` / `rav`) and kept
+//! the RAVs in `scalar_tap_latest_ravs`. Since the table creation migrations use
+//! `CREATE TABLE IF NOT EXISTS`, indexers that skipped several releases can still have those
+//! layouts in place. The legacy tables are not part of `migrations/`, so the queries on them
+//! can't be checked at compile time and are built at runtime instead.
+//!
+//! The migration can run while `indexer-service` keeps writing receipts: the legacy receipts
+//! table is renamed out of the way and a fresh one is created in the same transaction, with its
+//! id sequence starting right after the last legacy id. The copy is done in batches and can be
+//! resumed if interrupted.
+//!
+//! The receipts delivered twice, with the same signer and nonce, are only copied once: the
+//! duplicates are left in the legacy table, and left out of the checksum of the copy.
+
+use std::str::FromStr;
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::{bail, ensure, Context};
+use bigdecimal::{num_bigint::BigInt, BigDecimal};
+use indexer_common::fees::FeeToken;
+use sqlx::{PgPool, Row};
+use tap_core::{rav::SignedRAV, receipt::SignedReceipt};
+use tracing::{info, warn};
+
+const RECEIPTS_TABLE: &str = "scalar_tap_receipts";
+const LEGACY_RECEIPTS_TABLE: &str = "scalar_tap_receipts_legacy";
+const RAVS_TABLE: &str = "scalar_tap_ravs";
+const LEGACY_RAVS_TABLE: &str = "scalar_tap_ravs_legacy";
+/// Name of the RAVs table before it was renamed to `scalar_tap_ravs`.
+const PRE_RENAME_RAVS_TABLE: &str = "scalar_tap_latest_ravs";
+
+// Current layouts, kept in sync with `migrations/`. Only the tables are (re)created here, the
+// notification function is expected to still be in place.
+const CREATE_RECEIPTS_TABLE: &str = r#"
+    CREATE TABLE scalar_tap_receipts (
+        id BIGSERIAL PRIMARY KEY,
+        signer_address CHAR(40) NOT NULL,
+        signature BYTEA NOT NULL,
+        allocation_id CHAR(40) NOT NULL,
+        timestamp_ns NUMERIC(20) NOT NULL,
+        nonce NUMERIC(20) NOT NULL,
+        value NUMERIC(39) NOT NULL,
+        fee_token VARCHAR(16) NOT NULL DEFAULT 'GRT',
+        relayed_by VARCHAR(255)
+    );
+    CREATE TRIGGER receipt_update AFTER INSERT OR UPDATE
+        ON scalar_tap_receipts
+        FOR EACH ROW EXECUTE PROCEDURE scalar_tap_receipt_notify();
+    CREATE INDEX scalar_tap_receipts_allocation_id_idx ON scalar_tap_receipts (allocation_id);
+    CREATE INDEX scalar_tap_receipts_timestamp_ns_idx ON scalar_tap_receipts (timestamp_ns);
+    CREATE INDEX scalar_tap_receipts_fees_idx
+        ON scalar_tap_receipts (allocation_id, timestamp_ns)
+        INCLUDE (id, signer_address, value);
+    CREATE UNIQUE INDEX scalar_tap_receipts_nonce_idx
+        ON scalar_tap_receipts (allocation_id, signer_address, nonce);
+"#;
+
+const CREATE_RAVS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS scalar_tap_ravs (
+        sender_address CHAR(40) NOT NULL,
+        signature BYTEA NOT NULL,
+        allocation_id CHAR(40) NOT NULL,
+        timestamp_ns NUMERIC(20) NOT NULL,
+        value_aggregate NUMERIC(39) NOT NULL,
+        last BOOLEAN DEFAULT FALSE NOT NULL,
+        final BOOLEAN DEFAULT FALSE NOT NULL,
+        PRIMARY KEY (allocation_id, sender_address),
+        created_at TIMESTAMP WITH TIME ZONE,
+        updated_at TIMESTAMP WITH TIME ZONE
+    );
+"#;
+
+pub struct MigrationOptions {
+    /// Only report what would be migrated.
+    pub dry_run: bool,
+    /// Number of rows copied per transaction.
+    pub batch_size: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub receipts_migrated: u64,
+    pub ravs_migrated: u64,
+}
+
+/// Total row count and value of a set of rows, used to verify a copy.
+#[derive(Debug, PartialEq)]
+struct Checksum {
+    count: i64,
+    value: BigDecimal,
+}
+
+async fn table_columns(pgpool: &PgPool, table: &str) -> anyhow::Result<Vec<String>> {
+    let columns = sqlx::query_scalar!(
+        r#"
+            SELECT column_name::TEXT AS "column_name!"
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = $1::TEXT
+        "#,
+        table
+    )
+    .fetch_all(pgpool)
+    .await?;
+    Ok(columns)
+}
+
+fn has_column(columns: &[String], column: &str) -> bool {
+    columns.iter().any(|c| c == column)
+}
+
+fn normalize_address(address: &str) -> anyhow::Result<String> {
+    Ok(Address::from_str(address.trim())
+        .with_context(|| format!("Invalid address `{address}` in legacy table"))?
+        .encode_hex())
+}
+
+pub async fn migrate_legacy_schema(
+    pgpool: &PgPool,
+    options: &MigrationOptions,
+) -> anyhow::Result<MigrationReport> {
+    ensure!(options.batch_size > 0, "batch size must be greater than 0");
+    let receipts_migrated = migrate_receipts(pgpool, options).await?;
+    let ravs_migrated = migrate_ravs(pgpool, options).await?;
+    Ok(MigrationReport {
+        receipts_migrated,
+        ravs_migrated,
+    })
+}
+
+async fn migrate_receipts(pgpool: &PgPool, options: &MigrationOptions) -> anyhow::Result<u64> {
+    let columns = table_columns(pgpool, RECEIPTS_TABLE).await?;
+    let legacy_in_place = has_column(&columns, "receipt");
+
+    if legacy_in_place {
+        let total = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+            .fetch_one(pgpool)
+            .await?;
+        info!(total, "Found receipts stored with the legacy schema");
+        if options.dry_run {
+            return Ok(total as u64);
+        }
+        swap_legacy_receipts_table(pgpool).await?;
+    } else if table_columns(pgpool, LEGACY_RECEIPTS_TABLE)
+        .await?
+        .is_empty()
+    {
+        info!("Receipts table already uses the current schema, nothing to migrate");
+        return Ok(0);
+    } else {
+        info!("Resuming receipts migration from {LEGACY_RECEIPTS_TABLE}");
+    }
+
+    let legacy_max_id: i64 = sqlx::query_scalar(&format!(
+        "SELECT COALESCE(MAX(id), 0) FROM {LEGACY_RECEIPTS_TABLE}"
+    ))
+    .fetch_one(pgpool)
+    .await?;
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {LEGACY_RECEIPTS_TABLE}"))
+        .fetch_one(pgpool)
+        .await?;
+    if options.dry_run {
+        return Ok(total as u64);
+    }
+
+    // Receipts keep their ids, so we can resume from the last copied one.
+    let mut last_id = sqlx::query_scalar!(
+        r#"SELECT COALESCE(MAX(id), 0) AS "last_id!" FROM scalar_tap_receipts WHERE id <= $1"#,
+        legacy_max_id
+    )
+    .fetch_one(pgpool)
+    .await?;
+    let mut migrated = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts WHERE id <= $1"#,
+        legacy_max_id
+    )
+    .fetch_one(pgpool)
+    .await?;
+
+    loop {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT id, signer_address, receipt::TEXT AS receipt
+                FROM {LEGACY_RECEIPTS_TABLE}
+                WHERE id > $1
+                ORDER BY id
+                LIMIT $2
+            "#
+        ))
+        .bind(last_id)
+        .bind(options.batch_size)
+        .fetch_all(pgpool)
+        .await?;
+        let Some(last_row) = rows.last() else {
+            break;
+        };
+        last_id = last_row.try_get("id")?;
+
+        let mut ids = Vec::with_capacity(rows.len());
+        let mut signers = Vec::with_capacity(rows.len());
+        let mut signatures = Vec::with_capacity(rows.len());
+        let mut allocation_ids = Vec::with_capacity(rows.len());
+        let mut timestamps = Vec::with_capacity(rows.len());
+        let mut nonces = Vec::with_capacity(rows.len());
+        let mut values = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: i64 = row.try_get("id")?;
+            let receipt: String = row.try_get("receipt")?;
+            let receipt: SignedReceipt = serde_json::from_str(&receipt)
+                .with_context(|| format!("Could not decode legacy receipt with id {id}"))?;
+            ids.push(id);
+            signers.push(normalize_address(row.try_get("signer_address")?)?);
+            signatures.push(receipt.signature.as_bytes().to_vec());
+            allocation_ids.push(receipt.message.allocation_id.encode_hex());
+            timestamps.push(BigDecimal::from(receipt.message.timestamp_ns));
+            nonces.push(BigDecimal::from(receipt.message.nonce));
+            values.push(BigDecimal::from(BigInt::from(receipt.message.value)));
+        }
+
+        let inserted = sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_receipts (
+                    id,
+                    signer_address,
+                    signature,
+                    allocation_id,
+                    timestamp_ns,
+                    nonce,
+                    value,
+                    fee_token
+                ) SELECT *, $8 FROM UNNEST(
+                    $1::BIGINT[],
+                    $2::CHAR(40)[],
+                    $3::BYTEA[],
+                    $4::CHAR(40)[],
+                    $5::NUMERIC(20)[],
+                    $6::NUMERIC(20)[],
+                    $7::NUMERIC(39)[]
+                )
+                ON CONFLICT DO NOTHING
+            "#,
+            &ids,
+            &signers,
+            &signatures,
+            &allocation_ids,
+            &timestamps,
+            &nonces,
+            &values,
+            FeeToken::Grt.symbol(),
+        )
+        .execute(pgpool)
+        .await?
+        .rows_affected();
+
+        migrated += inserted as i64;
+        info!(migrated, total, "Migrating legacy receipts");
+    }
+
+    // The first of the receipts with the same signer and nonce, as the others weren't copied
+    let legacy = legacy_checksum(
+        pgpool,
+        &format!(
+            r#"
+                SELECT COUNT(*), COALESCE(SUM(value), 0)
+                FROM (
+                    SELECT DISTINCT ON (allocation_id, signer_address, nonce) value
+                    FROM (
+                        SELECT
+                            id,
+                            value,
+                            regexp_replace(
+                                lower(receipt->'message'->>'allocation_id'), '^0x', ''
+                            ) AS allocation_id,
+                            regexp_replace(lower(trim(signer_address)), '^0x', '')
+                                AS signer_address,
+                            (receipt->'message'->>'nonce')::NUMERIC AS nonce
+                        FROM {LEGACY_RECEIPTS_TABLE}
+                    ) receipts
+                    ORDER BY allocation_id, signer_address, nonce, id
+                ) first_receipts
+            "#
+        ),
+    )
+    .await?;
+    if legacy.count < total {
+        warn!(
+            duplicates = total - legacy.count,
+            "Receipts delivered twice were only copied once, the duplicates are left in \
+            {LEGACY_RECEIPTS_TABLE}"
+        );
+    }
+    let current = sqlx::query_as!(
+        Checksum,
+        r#"
+            SELECT COUNT(*) AS "count!", COALESCE(SUM(value), 0) AS "value!"
+            FROM scalar_tap_receipts
+            WHERE id <= $1
+        "#,
+        legacy_max_id
+    )
+    .fetch_one(pgpool)
+    .await?;
+    if legacy != current {
+        bail!(
+            "Receipts checksum mismatch after migration. Legacy: {:?}, migrated: {:?}. \
+            {LEGACY_RECEIPTS_TABLE} was left untouched for inspection.",
+            legacy,
+            current
+        );
+    }
+    info!(
+        count = current.count,
+        value = %current.value,
+        "Receipts migrated and verified. {LEGACY_RECEIPTS_TABLE} can now be dropped."
+    );
+    Ok(current.count as u64)
+}
+
+/// Moves the legacy receipts table out of the way and creates the current one in its place. The
+/// id sequence of the new table starts after the last legacy receipt so that receipts inserted
+/// while the copy is running don't collide with the migrated ones.
+async fn swap_legacy_receipts_table(pgpool: &PgPool) -> anyhow::Result<()> {
+    let mut tx = pgpool.begin().await?;
+    sqlx::raw_sql(&format!(
+        r#"
+            LOCK TABLE {RECEIPTS_TABLE} IN ACCESS EXCLUSIVE MODE;
+            ALTER TABLE {RECEIPTS_TABLE} RENAME TO {LEGACY_RECEIPTS_TABLE};
+            DROP TRIGGER IF EXISTS receipt_update ON {LEGACY_RECEIPTS_TABLE};
+            ALTER INDEX IF EXISTS {RECEIPTS_TABLE}_pkey RENAME TO {LEGACY_RECEIPTS_TABLE}_pkey;
+            DROP INDEX IF EXISTS {RECEIPTS_TABLE}_allocation_id_idx;
+            DROP INDEX IF EXISTS {RECEIPTS_TABLE}_timestamp_ns_idx;
+            DROP INDEX IF EXISTS {RECEIPTS_TABLE}_fees_idx;
+            DROP INDEX IF EXISTS {RECEIPTS_TABLE}_nonce_idx;
+            {CREATE_RECEIPTS_TABLE}
+            SELECT setval(
+                pg_get_serial_sequence('{RECEIPTS_TABLE}', 'id'),
+                (SELECT COALESCE(MAX(id), 0) + 1 FROM {LEGACY_RECEIPTS_TABLE}),
+                false
+            );
+        "#
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!("Moved legacy receipts to {LEGACY_RECEIPTS_TABLE}");
+    Ok(())
+}
+
+async fn migrate_ravs(pgpool: &PgPool, options: &MigrationOptions) -> anyhow::Result<u64> {
+    let legacy_in_place = has_column(&table_columns(pgpool, RAVS_TABLE).await?, "rav");
+    let source = if !table_columns(pgpool, PRE_RENAME_RAVS_TABLE)
+        .await?
+        .is_empty()
+    {
+        PRE_RENAME_RAVS_TABLE
+    } else if legacy_in_place || !table_columns(pgpool, LEGACY_RAVS_TABLE).await?.is_empty() {
+        LEGACY_RAVS_TABLE
+    } else {
+        info!("RAVs table already uses the current schema, nothing to migrate");
+        return Ok(0);
+    };
+
+    let total: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM {}",
+        if legacy_in_place { RAVS_TABLE } else { source }
+    ))
+    .fetch_one(pgpool)
+    .await?;
+    info!(total, "Found RAVs stored with the legacy schema");
+    if options.dry_run {
+        return Ok(total as u64);
+    }
+
+    if legacy_in_place {
+        let mut tx = pgpool.begin().await?;
+        sqlx::raw_sql(&format!(
+            r#"
+                ALTER TABLE {RAVS_TABLE} RENAME TO {LEGACY_RAVS_TABLE};
+                ALTER INDEX IF EXISTS {RAVS_TABLE}_pkey RENAME TO {LEGACY_RAVS_TABLE}_pkey;
+            "#
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::raw_sql(CREATE_RAVS_TABLE).execute(&mut *tx).await?;
+        tx.commit().await?;
+        info!("Moved legacy RAVs to {LEGACY_RAVS_TABLE}");
+    } else {
+        sqlx::raw_sql(CREATE_RAVS_TABLE).execute(pgpool).await?;
+    }
+
+    // Older layouts didn't always track whether the RAV was the last one or was redeemed.
+    let columns = table_columns(pgpool, source).await?;
+    let last = if has_column(&columns, "last") {
+        "last"
+    } else {
+        "FALSE"
+    };
+    let final_rav = if has_column(&columns, "final") {
+        "final"
+    } else {
+        "FALSE"
+    };
+    let rows = sqlx::query(&format!(
+        r#"
+            SELECT sender_address, rav::TEXT AS rav, {last} AS last, {final_rav} AS final
+            FROM {source}
+        "#
+    ))
+    .fetch_all(pgpool)
+    .await?;
+
+    let mut expected = Checksum {
+        count: 0,
+        value: BigDecimal::from(0),
+    };
+    let mut migrated_allocation_ids = Vec::with_capacity(rows.len());
+    let mut migrated_senders = Vec::with_capacity(rows.len());
+    let mut processed = 0;
+    for chunk in rows.chunks(options.batch_size as usize) {
+        let mut tx = pgpool.begin().await?;
+        for row in chunk {
+            let sender = normalize_address(row.try_get("sender_address")?)?;
+            let rav: String = row.try_get("rav")?;
+            let rav: SignedRAV = serde_json::from_str(&rav)
+                .with_context(|| format!("Could not decode legacy RAV for sender {sender}"))?;
+            let allocation_id = rav.message.allocationId.encode_hex();
+            let value_aggregate = BigDecimal::from(BigInt::from(rav.message.valueAggregate));
+
+            let last: bool = row.try_get("last")?;
+            let final_rav: bool = row.try_get("final")?;
+            let inserted = sqlx::query!(
+                r#"
+                    INSERT INTO scalar_tap_ravs (
+                        sender_address,
+                        signature,
+                        allocation_id,
+                        timestamp_ns,
+                        value_aggregate,
+                        last,
+                        final,
+                        fee_token,
+                        created_at,
+                        updated_at
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW(), NOW())
+                    ON CONFLICT (allocation_id, sender_address) DO NOTHING
+                "#,
+                &sender,
+                rav.signature.as_bytes().to_vec(),
+                &allocation_id,
+                BigDecimal::from(rav.message.timestampNs),
+                &value_aggregate,
+                last,
+                final_rav,
+                FeeToken::Grt.symbol(),
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+            if inserted == 0 {
+                // Either copied by a previous run, or a newer RAV was already stored.
+                warn!(
+                    %sender,
+                    %allocation_id,
+                    "A RAV already exists in the current table, keeping it"
+                );
+                continue;
+            }
+            expected.count += 1;
+            expected.value += value_aggregate;
+            migrated_allocation_ids.push(allocation_id);
+            migrated_senders.push(sender);
+        }
+        tx.commit().await?;
+        processed += chunk.len();
+        info!(processed, total, "Migrating legacy RAVs");
+    }
+
+    let current = sqlx::query_as!(
+        Checksum,
+        r#"
+            SELECT COUNT(*) AS "count!", COALESCE(SUM(value_aggregate), 0) AS "value!"
+            FROM scalar_tap_ravs
+            WHERE (allocation_id, sender_address) IN (
+                SELECT * FROM UNNEST($1::CHAR(40)[], $2::CHAR(40)[])
+            )
+        "#,
+        &migrated_allocation_ids,
+        &migrated_senders
+    )
+    .fetch_one(pgpool)
+    .await?;
+    if current != expected {
+        bail!(
+            "RAVs checksum mismatch after migration. Expected: {:?}, migrated: {:?}. \
+            {source} was left untouched for inspection.",
+            expected,
+            current
+        );
+    }
+    info!(
+        count = current.count,
+        value = %current.value,
+        "RAVs migrated and verified. {source} can now be dropped."
+    );
+    Ok(current.count as u64)
+}
+
+/// Checksum of the rows of a legacy table, that can't be checked at compile time.
+async fn legacy_checksum(pgpool: &PgPool, query: &str) -> anyhow::Result<Checksum> {
+    let (count, value) = sqlx::query_as::<_, (i64, BigDecimal)>(query)
+        .fetch_one(pgpool)
+        .await?;
+    Ok(Checksum { count, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::hex::ToHexExt;
+    use bigdecimal::BigDecimal;
+    use sqlx::PgPool;
+
+    use super::{migrate_legacy_schema, MigrationOptions};
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_migrate_legacy_schema(pgpool: PgPool) {
+        // The legacy tables only exist in this test, so their queries are checked at runtime
+        sqlx::raw_sql(
+            r#"
+                DROP TABLE scalar_tap_receipts;
+                CREATE TABLE scalar_tap_receipts (
+                    id BIGSERIAL PRIMARY KEY,
+                    allocation_id CHAR(40) NOT NULL,
+                    signer_address CHAR(40) NOT NULL,
+                    timestamp_ns NUMERIC(20) NOT NULL,
+                    value NUMERIC(39) NOT NULL,
+                    receipt JSON NOT NULL
+                );
+                CREATE TABLE scalar_tap_latest_ravs (
+                    allocation_id CHAR(40) NOT NULL,
+                    sender_address CHAR(40) NOT NULL,
+                    rav JSON NOT NULL,
+                    final BOOLEAN DEFAULT FALSE NOT NULL,
+                    PRIMARY KEY (allocation_id, sender_address)
+                );
+            "#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        // The third receipt is delivered twice
+        for i in (1..=10).chain([3]) {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            let receipt = receipt.signed_receipt();
+            sqlx::query(
+                r#"
+                    INSERT INTO scalar_tap_receipts
+                        (allocation_id, signer_address, timestamp_ns, value, receipt)
+                    VALUES ($1, $2, $3, $4, $5::JSON)
+                "#,
+            )
+            .bind(ALLOCATION_ID_0.encode_hex())
+            .bind(SIGNER.1.encode_hex())
+            .bind(BigDecimal::from(i))
+            .bind(BigDecimal::from(i))
+            .bind(serde_json::to_string(receipt).unwrap())
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+        let rav = create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 4, 10);
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_latest_ravs (allocation_id, sender_address, rav)
+                VALUES ($1, $2, $3::JSON)
+            "#,
+        )
+        .bind(ALLOCATION_ID_1.encode_hex())
+        .bind(SENDER.1.encode_hex())
+        .bind(serde_json::to_string(&rav).unwrap())
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let dry_run = migrate_legacy_schema(
+            &pgpool,
+            &MigrationOptions {
+                dry_run: true,
+                batch_size: 3,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(dry_run.receipts_migrated, 11);
+        assert_eq!(dry_run.ravs_migrated, 1);
+
+        let report = migrate_legacy_schema(
+            &pgpool,
+            &MigrationOptions {
+                dry_run: false,
+                batch_size: 3,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.receipts_migrated, 10);
+        assert_eq!(report.ravs_migrated, 1);
+
+        let receipts = sqlx::query!("SELECT id, signature, value FROM scalar_tap_receipts")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 10);
+        let rav_value = sqlx::query!(r#"SELECT value_aggregate FROM scalar_tap_ravs"#)
+            .fetch_one(&pgpool)
+            .await
+            .unwrap()
+            .value_aggregate;
+        assert_eq!(rav_value, BigDecimal::from(10));
+
+        // New receipts don't collide with the migrated ones
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 11, 11, 11);
+        let id = crate::tap::test_utils::store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        assert_eq!(id, 12);
+
+        // Running it again is a no-op
+        let report = migrate_legacy_schema(
+            &pgpool,
+            &MigrationOptions {
+                dry_run: false,
+                batch_size: 3,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.receipts_migrated, 10);
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Display of the fee values, see `tap.fee_display`.
+//!
+//! The values stay `u128` GRT wei in the code and in the database. [`wei`] serializes them in the
+//! responses of the admin, status and sender endpoints, in `tap.fee_display.api_unit`, always as
+//! strings since they don't fit in a JSON number. The responses tell the unit in their `fee_unit`,
+//! see [`ApiUnit`]. [`Money`] displays them in the logs in GRT, and
+//! in USD as well once [`watch_usd_price`] fetched the price. The metrics stay in wei, the price
+//! is exported as `tap_grt_usd_price` for the dashboards to convert them.
+
+use std::{
+    fmt,
+    sync::{OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use indexer_common::fees::FeeAmount;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, Gauge};
+use serde::{Serialize, Serializer};
+use tracing::{info, warn};
+
+use crate::config::{FeeDisplay, FeeUnit, UsdPrice};
+
+lazy_static! {
+    static ref GRT_USD_PRICE: Gauge = register_gauge!(
+        "tap_grt_usd_price",
+        "Price of 1 GRT in USD, as of its last fetch, 0 until then"
+    )
+    .unwrap();
+}
+
+static API_UNIT: OnceLock<FeeUnit> = OnceLock::new();
+static USD_PRICE: RwLock<Option<f64>> = RwLock::new(None);
+
+/// Sets the unit of [`wei`], GRT wei until then.
+pub fn init(config: &FeeDisplay) {
+    let _ = API_UNIT.set(config.api_unit);
+}
+
+/// Price of 1 GRT in USD, if fetched already.
+pub fn usd_price() -> Option<f64> {
+    *USD_PRICE.read().unwrap()
+}
+
+/// For `#[serde(serialize_with = "wei")]`, a value in GRT wei in `tap.fee_display.api_unit`.
+pub fn wei<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&in_unit(
+        *value,
+        API_UNIT.get().copied().unwrap_or_default(),
+    ))
+}
+
+/// Unit of the values serialized with [`wei`], `"wei"` or `"grt"`, for the `fee_unit` of the
+/// responses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApiUnit;
+
+impl Serialize for ApiUnit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match API_UNIT.get().copied().unwrap_or_default() {
+            FeeUnit::Wei => "wei",
+            FeeUnit::Grt => "grt",
+        })
+    }
+}
+
+fn in_unit(value: u128, unit: FeeUnit) -> String {
+    match unit {
+        FeeUnit::Wei => value.to_string(),
+        FeeUnit::Grt => FeeAmount::grt(value).to_tokens_string(),
+    }
+}
+
+/// A value in GRT wei, displayed such as `1.5 GRT ($0.30)` for the logs.
+#[derive(Debug, Clone, Copy)]
+pub struct Money(pub u128);
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_money(f, self.0, usd_price())
+    }
+}
+
+fn format_money(f: &mut fmt::Formatter<'_>, value: u128, usd_price: Option<f64>) -> fmt::Result {
+    let amount = FeeAmount::grt(value);
+    write!(f, "{amount}")?;
+    if let Some(price) = usd_price {
+        write!(f, " (${:.2})", amount.to_tokens() * price)?;
+    }
+    Ok(())
+}
+
+/// Fetches the price every `config.interval`, forever. The last price is kept while it fails.
+pub async fn watch_usd_price(http_client: reqwest::Client, config: UsdPrice) {
+    info!(
+        url = %config.url,
+        "Fetching the price of GRT in USD every {}s.",
+        config.interval.as_secs()
+    );
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        match fetch_usd_price(&http_client, &config).await {
+            Ok(price) => {
+                *USD_PRICE.write().unwrap() = Some(price);
+                GRT_USD_PRICE.set(price);
+            }
+            Err(error) => warn!(%error, "Failed to fetch the price of GRT in USD."),
+        }
+    }
+}
+
+async fn fetch_usd_price(http_client: &reqwest::Client, config: &UsdPrice) -> Result<f64> {
+    let document: serde_json::Value = http_client
+        .get(config.url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to query {}", config.url))?
+        .json()
+        .await
+        .context("The response isn't JSON")?;
+    let price = document
+        .pointer(&config.json_pointer)
+        .ok_or_else(|| anyhow!("No `{}` in the response", config.json_pointer))?;
+    // Some feeds return the prices as strings, to keep their precision
+    let price = match price {
+        serde_json::Value::String(price) => price.parse::<f64>().ok(),
+        price => price.as_f64(),
+    }
+    .filter(|price| price.is_finite() && *price > 0.0)
+    .ok_or_else(|| anyhow!("Invalid price {price}"))?;
+    Ok(price)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    struct Display(u128, Option<f64>);
+
+    impl fmt::Display for Display {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            format_money(f, self.0, self.1)
+        }
+    }
+
+    #[test]
+    fn test_units() {
+        let value = 1_500_000_000_000_000_000;
+        assert_eq!(in_unit(value, FeeUnit::Wei), "1500000000000000000");
+        assert_eq!(in_unit(value, FeeUnit::Grt), "1.5");
+        assert_eq!(in_unit(1, FeeUnit::Grt), "0.000000000000000001");
+
+        assert_eq!(Display(value, None).to_string(), "1.5 GRT");
+        assert_eq!(Display(value, Some(0.2)).to_string(), "1.5 GRT ($0.30)");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_usd_price() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/price"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "the-graph": { "usd": 0.2 } })),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/string"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!("0.25")))
+            .mount(&mock_server)
+            .await;
+        let config = |path: &str, json_pointer: &str| UsdPrice {
+            url: format!("{}{path}", mock_server.uri()).parse().unwrap(),
+            json_pointer: json_pointer.to_string(),
+            interval: Duration::from_secs(300),
+        };
+        let http_client = reqwest::Client::new();
+
+        let price = fetch_usd_price(&http_client, &config("/price", "/the-graph/usd"))
+            .await
+            .unwrap();
+        assert_eq!(price, 0.2);
+        let price = fetch_usd_price(&http_client, &config("/string", ""))
+            .await
+            .unwrap();
+        assert_eq!(price, 0.25);
+        assert!(
+            fetch_usd_price(&http_client, &config("/price", "/the-graph/eur"))
+                .await
+                .is_err()
+        );
+        assert!(fetch_usd_price(&http_client, &config("/price", ""))
+            .await
+            .is_err());
+    }
+}
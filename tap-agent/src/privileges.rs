@@ -0,0 +1,322 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Startup check of what the database role of tap-agent is allowed to do.
+//!
+//! The role of tap-agent is often more restricted than the one of indexer-agent, which owns the
+//! tables and runs the migrations. Rather than failing with a permission error in the middle of a
+//! RAV request, the privileges are checked once at startup:
+//! - a missing privilege on a table needed by the configuration fails the startup, listing the
+//!   `GRANT` statements to run.
+//! - the optional features are disabled instead, and reported in [`DatabaseFeatures`]. Without a
+//!   working `LISTEN`/`NOTIFY`, such as behind a connection pooler in transaction mode, the new
+//!   receipts are polled from `scalar_tap_receipts`. Without `INSERT` on
+//!   `scalar_tap_receipts_archive`, the receipt compaction in archive mode is disabled rather
+//!   than deleting receipts the operator wanted archived.
+
+use std::{fmt::Write, time::Duration};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use sqlx::{postgres::PgListener, PgPool};
+use tracing::warn;
+
+use crate::config::{ReceiptCompactionMode, Tap};
+
+/// Time given to a notification to come back through `LISTEN`.
+const LISTEN_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const LISTEN_CHECK_CHANNEL: &str = "tap_agent_listen_check";
+
+type TablePrivileges = (&'static str, &'static [&'static str]);
+
+const TABLES: &[TablePrivileges] = &[
+    ("scalar_tap_receipts", &["SELECT", "DELETE"]),
+    ("scalar_tap_receipts_invalid", &["SELECT", "INSERT"]),
+    ("scalar_tap_receipts_quarantined", &["INSERT"]),
+    ("scalar_tap_ravs", &["SELECT", "INSERT", "UPDATE"]),
+    ("scalar_tap_rav_requests_failed", &["SELECT", "INSERT"]),
+    (
+        "scalar_tap_rav_request_intents",
+        &["SELECT", "INSERT", "DELETE"],
+    ),
+    ("scalar_tap_denylist", &["SELECT", "INSERT", "DELETE"]),
+    ("tap_paused_senders", &["SELECT", "INSERT", "DELETE"]),
+];
+
+const HORIZON_TABLES: &[TablePrivileges] = &[
+    ("tap_horizon_receipts", &["SELECT", "DELETE"]),
+    ("tap_horizon_receipts_invalid", &["SELECT", "INSERT"]),
+    ("tap_horizon_receipts_quarantined", &["INSERT"]),
+    ("tap_horizon_ravs", &["SELECT", "INSERT", "UPDATE"]),
+    ("tap_horizon_rav_requests_failed", &["INSERT"]),
+];
+
+const DIPS_TABLES: &[TablePrivileges] = &[
+    ("dips_agreements", &["SELECT"]),
+    ("dips_fee_vouchers", &["SELECT", "DELETE"]),
+    ("dips_fee_vouchers_invalid", &["INSERT"]),
+    ("dips_ravs", &["SELECT", "INSERT", "UPDATE"]),
+    ("dips_rav_requests_failed", &["INSERT"]),
+];
+
+const SHARDING_TABLES: &[TablePrivileges] = &[
+    (
+        "tap_agent_instances",
+        &["SELECT", "INSERT", "UPDATE", "DELETE"],
+    ),
+    ("tap_sender_leases", &["SELECT", "INSERT", "DELETE"]),
+];
+
+const ARCHIVE_TABLES: &[TablePrivileges] = &[("scalar_tap_receipts_archive", &["INSERT"])];
+
+/// Optional features and whether the database allows them, reported in `/status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DatabaseFeatures {
+    /// The new receipts are notified through `LISTEN`, they're polled otherwise.
+    pub listen: bool,
+    /// The receipts can be moved to `scalar_tap_receipts_archive`. Only checked in archive mode.
+    pub receipt_archive: bool,
+}
+
+impl Default for DatabaseFeatures {
+    fn default() -> Self {
+        Self {
+            listen: true,
+            receipt_archive: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Missing {
+    /// The migrations creating it didn't run.
+    Table(&'static str),
+    Privilege(&'static str, &'static str),
+    /// `USAGE` on the sequence of a table the role inserts into.
+    Sequence(String),
+}
+
+/// Checks the privileges needed with `tap`. Fails with the statements fixing the missing ones,
+/// unless they only affect an optional feature, which is disabled instead.
+pub async fn check(pgpool: &PgPool, tap: &Tap) -> Result<DatabaseFeatures> {
+    let role = sqlx::query_scalar!(r#"SELECT current_user::TEXT AS "role!""#)
+        .fetch_one(pgpool)
+        .await?;
+
+    let mut required = TABLES.to_vec();
+    if tap.horizon_enabled {
+        required.extend_from_slice(HORIZON_TABLES);
+    }
+    if tap.dips.is_some() {
+        required.extend_from_slice(DIPS_TABLES);
+    }
+    if tap.sharding.is_some() {
+        required.extend_from_slice(SHARDING_TABLES);
+    }
+    let missing = missing_privileges(pgpool, &role, &required).await?;
+    if !missing.is_empty() {
+        bail!(missing_message(&role, &missing));
+    }
+
+    let mut features = DatabaseFeatures::default();
+    if tap
+        .receipt_compaction
+        .as_ref()
+        .is_some_and(|compaction| compaction.mode == ReceiptCompactionMode::Archive)
+    {
+        let missing = missing_privileges(pgpool, &role, ARCHIVE_TABLES).await?;
+        if !missing.is_empty() {
+            warn!(
+                "{} Receipt compaction is disabled until then.",
+                missing_message(&role, &missing)
+            );
+            features.receipt_archive = false;
+        }
+    }
+
+    if let Err(error) = check_listen(pgpool).await {
+        if tap.horizon_enabled {
+            bail!(
+                "Postgres notifications don't reach tap-agent ({error:#}), they're needed for \
+                Horizon receipts. Connect tap-agent to Postgres directly or through a pooler in \
+                session mode."
+            );
+        }
+        warn!(
+            %error,
+            "Postgres notifications don't reach tap-agent, polling the new receipts instead. \
+            Connect tap-agent to Postgres directly or through a pooler in session mode to \
+            receive them as they're stored."
+        );
+        features.listen = false;
+    }
+    Ok(features)
+}
+
+async fn missing_privileges(
+    pgpool: &PgPool,
+    role: &str,
+    tables: &[TablePrivileges],
+) -> Result<Vec<Missing>> {
+    let mut missing = Vec::new();
+    for (table, privileges) in tables {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT to_regclass($1::TEXT) IS NOT NULL AS "exists!""#,
+            *table
+        )
+        .fetch_one(pgpool)
+        .await?;
+        if !exists {
+            missing.push(Missing::Table(table));
+            continue;
+        }
+        for privilege in *privileges {
+            let granted = sqlx::query_scalar!(
+                r#"SELECT has_table_privilege($1::TEXT::name, $2::TEXT, $3::TEXT) AS "granted!""#,
+                role,
+                *table,
+                *privilege
+            )
+            .fetch_one(pgpool)
+            .await?;
+            if !granted {
+                missing.push(Missing::Privilege(table, privilege));
+            }
+        }
+        if privileges.contains(&"INSERT") {
+            let sequences = sqlx::query_scalar!(
+                r#"
+                    SELECT sequence AS "sequence!" FROM (
+                        SELECT pg_get_serial_sequence($2::TEXT, attname) AS sequence
+                        FROM pg_attribute
+                        WHERE attrelid = $2::TEXT::regclass AND attnum > 0 AND NOT attisdropped
+                    ) AS sequences
+                    WHERE sequence IS NOT NULL
+                        AND NOT has_sequence_privilege($1::TEXT::name, sequence, 'USAGE')
+                "#,
+                role,
+                *table
+            )
+            .fetch_all(pgpool)
+            .await?;
+            missing.extend(sequences.into_iter().map(Missing::Sequence));
+        }
+    }
+    Ok(missing)
+}
+
+fn missing_message(role: &str, missing: &[Missing]) -> String {
+    let mut message = format!("The database role \"{role}\" can't be used by tap-agent:");
+    let mut grants: Vec<(&str, Vec<&str>)> = Vec::new();
+    for missing in missing {
+        match missing {
+            Missing::Table(table) => {
+                let _ = write!(
+                    message,
+                    "\n  table {table} doesn't exist, run the indexer-agent migrations."
+                );
+            }
+            Missing::Privilege(table, privilege) => {
+                match grants.iter_mut().find(|(granted, _)| granted == table) {
+                    Some((_, privileges)) => privileges.push(privilege),
+                    None => grants.push((table, vec![privilege])),
+                }
+            }
+            Missing::Sequence(sequence) => {
+                let _ = write!(
+                    message,
+                    "\n  GRANT USAGE ON SEQUENCE {sequence} TO \"{role}\";"
+                );
+            }
+        }
+    }
+    for (table, privileges) in grants {
+        let _ = write!(
+            message,
+            "\n  GRANT {} ON {table} TO \"{role}\";",
+            privileges.join(", ")
+        );
+    }
+    message
+}
+
+/// Sends a notification to itself, and waits for it.
+async fn check_listen(pgpool: &PgPool) -> Result<()> {
+    let mut listener = PgListener::connect_with(pgpool).await?;
+    listener.listen(LISTEN_CHECK_CHANNEL).await?;
+    sqlx::query!("SELECT pg_notify($1, '')", LISTEN_CHECK_CHANNEL)
+        .execute(pgpool)
+        .await?;
+    match tokio::time::timeout(LISTEN_CHECK_TIMEOUT, listener.recv()).await {
+        Ok(notification) => {
+            notification?;
+            Ok(())
+        }
+        Err(_) => bail!(
+            "no notification received within {}s",
+            LISTEN_CHECK_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{check, missing_message, missing_privileges, Missing, TABLES};
+    use crate::config::Tap;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_check(pgpool: PgPool) {
+        let features = check(&pgpool, &Tap::default()).await.unwrap();
+        assert!(features.listen);
+        assert!(features.receipt_archive);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_missing_privileges(pgpool: PgPool) {
+        let role = "tap_agent_privileges_test";
+        // Roles and grants are DDL, with the role in the statement, so they're built at runtime
+        sqlx::query(&format!(
+            r#"
+                DO $$ BEGIN
+                    CREATE ROLE {role};
+                EXCEPTION WHEN duplicate_object THEN NULL;
+                END $$
+            "#
+        ))
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        sqlx::query(&format!(
+            "GRANT SELECT, INSERT, DELETE ON scalar_tap_denylist TO {role}"
+        ))
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let missing = missing_privileges(&pgpool, role, TABLES).await.unwrap();
+        assert!(missing.contains(&Missing::Privilege("scalar_tap_ravs", "UPDATE")));
+        assert!(missing.contains(&Missing::Sequence(
+            "public.scalar_tap_receipts_invalid_id_seq".to_string()
+        )));
+        assert!(!missing
+            .iter()
+            .any(|missing| matches!(missing, Missing::Privilege("scalar_tap_denylist", _))));
+
+        let missing = missing_privileges(&pgpool, role, &[("scalar_tap_missing", &["SELECT"])])
+            .await
+            .unwrap();
+        assert_eq!(missing, vec![Missing::Table("scalar_tap_missing")]);
+
+        let message = missing_message(
+            role,
+            &[
+                Missing::Privilege("scalar_tap_ravs", "INSERT"),
+                Missing::Privilege("scalar_tap_ravs", "UPDATE"),
+            ],
+        );
+        assert!(message
+            .contains("GRANT INSERT, UPDATE ON scalar_tap_ravs TO \"tap_agent_privileges_test\";"));
+    }
+}
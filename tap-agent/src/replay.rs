@@ -0,0 +1,354 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sends the notifications of stored receipts again, for the `replay` subcommand.
+//!
+//! A running tap-agent only learns about new receipts through the notifications of the receipts
+//! tables, and those sent while its listener was disconnected are lost. Replaying them makes the
+//! `SenderAllocation`s account for the receipts they missed without a restart and its startup
+//! scans. The receipts they already accounted for are ignored by their id, so replaying is safe.
+//!
+//! The receipts of a signer unknown to the escrow accounts are dropped by tap-agent, they're
+//! replayed once the signer is authorized. Its receipts set aside meanwhile are checked again,
+//! see [`revalidate_signer_receipts`].
+
+use std::future::Future;
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::Result;
+use sqlx::PgPool;
+use tracing::info;
+
+use indexer_common::tap::rejection::RejectionCode;
+
+/// Notifies the legacy receipts with an id greater than `after_id`, of `signer` if any,
+/// `batch_size` per transaction, returns how many were notified. The payloads are the ones of the
+/// `scalar_tap_receipt_notify` trigger of `migrations`.
+pub async fn replay_receipt_notifications(
+    pgpool: &PgPool,
+    after_id: i64,
+    batch_size: i64,
+    signer: Option<Address>,
+) -> Result<u64> {
+    let signer = signer.map(|signer| signer.encode_hex());
+    replay(after_id, |last_id| {
+        sqlx::query_scalar!(
+            r#"
+                SELECT id FROM (
+                    SELECT id, pg_notify(
+                        'scalar_tap_receipt_notification',
+                        format(
+                            '{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s}',
+                            id, allocation_id, signer_address, timestamp_ns, value
+                        )
+                    )
+                    FROM scalar_tap_receipts
+                    WHERE id > $1 AND ($3::CHAR(40) IS NULL OR signer_address = $3)
+                    ORDER BY id
+                    LIMIT $2
+                ) AS notified
+            "#,
+            last_id,
+            batch_size,
+            signer.as_deref()
+        )
+        .fetch_all(pgpool)
+    })
+    .await
+}
+
+/// Same as [`replay_receipt_notifications`], for the Horizon receipts. The payloads are the ones
+/// of the `tap_horizon_receipt_notify` trigger.
+pub async fn replay_horizon_receipt_notifications(
+    pgpool: &PgPool,
+    after_id: i64,
+    batch_size: i64,
+    signer: Option<Address>,
+) -> Result<u64> {
+    let signer = signer.map(|signer| signer.encode_hex());
+    replay(after_id, |last_id| {
+        sqlx::query_scalar!(
+            r#"
+                SELECT id FROM (
+                    SELECT id, pg_notify(
+                        'tap_horizon_receipt_notification',
+                        format(
+                            '{"id": %s, "collection_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s}',
+                            id, collection_id, signer_address, timestamp_ns, value
+                        )
+                    )
+                    FROM tap_horizon_receipts
+                    WHERE id > $1 AND ($3::CHAR(40) IS NULL OR signer_address = $3)
+                    ORDER BY id
+                    LIMIT $2
+                ) AS notified
+            "#,
+            last_id,
+            batch_size,
+            signer.as_deref()
+        )
+        .fetch_all(pgpool)
+    })
+    .await
+}
+
+/// Notifies the batches of receipts returned by `batch`, each after the last id of the previous
+/// one, until one is empty.
+async fn replay<F, Fut>(after_id: i64, mut batch: F) -> Result<u64>
+where
+    F: FnMut(i64) -> Fut,
+    Fut: Future<Output = Result<Vec<i64>, sqlx::Error>>,
+{
+    let mut last_id = after_id;
+    let mut notified = 0;
+    loop {
+        let ids = batch(last_id).await?;
+        let Some(batch_last_id) = ids.last() else {
+            return Ok(notified);
+        };
+        last_id = *batch_last_id;
+        notified += ids.len() as u64;
+        info!(last_id, notified, "Receipt notifications replayed.");
+    }
+}
+
+/// Moves the receipts of `signer` set aside by tap-agent back to the receipts tables, both
+/// formats, so that they're checked again: the ones found invalid because the signer was
+/// unknown, and the ones quarantined. Returns how many were moved.
+///
+/// They're inserted with new ids, so that the `SenderAllocation`s account for them, and their
+/// checks are run again when their RAV is requested. A receipt whose nonce was stored again
+/// meanwhile stays where it is.
+pub async fn revalidate_signer_receipts(pgpool: &PgPool, signer: Address) -> Result<u64> {
+    let signer = signer.encode_hex();
+    let unknown_signer = RejectionCode::UnknownSigner.as_str();
+    let mut tx = pgpool.begin().await?;
+    let mut moved = 0;
+    moved += sqlx::query!(
+        r#"
+            WITH moved AS (
+                DELETE FROM scalar_tap_receipts_invalid invalid
+                WHERE signer_address = $1 AND error_code = $2
+                    AND NOT EXISTS (
+                        SELECT 1 FROM scalar_tap_receipts receipts
+                        WHERE receipts.allocation_id = invalid.allocation_id
+                            AND receipts.signer_address = invalid.signer_address
+                            AND receipts.nonce = invalid.nonce
+                    )
+                RETURNING signer_address, signature, allocation_id, timestamp_ns, nonce, value,
+                    fee_token
+            )
+            INSERT INTO scalar_tap_receipts (
+                signer_address, signature, allocation_id, timestamp_ns, nonce, value, fee_token
+            )
+            SELECT signer_address, signature, allocation_id, timestamp_ns, nonce, value, fee_token
+            FROM moved
+        "#,
+        &signer,
+        unknown_signer
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    moved += sqlx::query!(
+        r#"
+            WITH moved AS (
+                DELETE FROM tap_horizon_receipts_invalid invalid
+                WHERE signer_address = $1 AND error_code = $2
+                    AND NOT EXISTS (
+                        SELECT 1 FROM tap_horizon_receipts receipts
+                        WHERE receipts.collection_id = invalid.collection_id
+                            AND receipts.signer_address = invalid.signer_address
+                            AND receipts.nonce = invalid.nonce
+                    )
+                RETURNING signer_address, signature, collection_id, payer, data_service,
+                    service_provider, timestamp_ns, nonce, value, fee_token
+            )
+            INSERT INTO tap_horizon_receipts (
+                signer_address, signature, collection_id, payer, data_service, service_provider,
+                timestamp_ns, nonce, value, fee_token
+            )
+            SELECT signer_address, signature, collection_id, payer, data_service,
+                service_provider, timestamp_ns, nonce, value, fee_token
+            FROM moved
+        "#,
+        &signer,
+        unknown_signer
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    moved += sqlx::query!(
+        r#"
+            WITH moved AS (
+                DELETE FROM scalar_tap_receipts_quarantined quarantined
+                WHERE signer_address = $1
+                    AND NOT EXISTS (
+                        SELECT 1 FROM scalar_tap_receipts receipts
+                        WHERE receipts.allocation_id = quarantined.allocation_id
+                            AND receipts.signer_address = quarantined.signer_address
+                            AND receipts.nonce = quarantined.nonce
+                    )
+                RETURNING signer_address, signature, allocation_id, timestamp_ns, nonce, value
+            )
+            INSERT INTO scalar_tap_receipts (
+                signer_address, signature, allocation_id, timestamp_ns, nonce, value
+            )
+            SELECT signer_address, signature, allocation_id, timestamp_ns, nonce, value
+            FROM moved
+        "#,
+        &signer
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    moved += sqlx::query!(
+        r#"
+            WITH moved AS (
+                DELETE FROM tap_horizon_receipts_quarantined quarantined
+                WHERE signer_address = $1
+                    AND NOT EXISTS (
+                        SELECT 1 FROM tap_horizon_receipts receipts
+                        WHERE receipts.collection_id = quarantined.collection_id
+                            AND receipts.signer_address = quarantined.signer_address
+                            AND receipts.nonce = quarantined.nonce
+                    )
+                RETURNING signer_address, signature, collection_id, payer, data_service,
+                    service_provider, timestamp_ns, nonce, value
+            )
+            INSERT INTO tap_horizon_receipts (
+                signer_address, signature, collection_id, payer, data_service, service_provider,
+                timestamp_ns, nonce, value
+            )
+            SELECT signer_address, signature, collection_id, payer, data_service,
+                service_provider, timestamp_ns, nonce, value
+            FROM moved
+        "#,
+        &signer
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+    use sqlx::{postgres::PgListener, PgPool};
+
+    use super::{replay_receipt_notifications, revalidate_signer_receipts};
+    use crate::{
+        agent::sender_accounts_manager::NewReceiptNotification,
+        tap::test_utils::{
+            create_received_receipt, store_invalid_receipt, store_receipt, ALLOCATION_ID_0, SIGNER,
+        },
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_replay_receipt_notifications(pgpool: PgPool) {
+        let mut ids = Vec::new();
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i * 10, 5);
+            ids.push(
+                store_receipt(&pgpool, receipt.signed_receipt())
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let mut listener = PgListener::connect_with(&pgpool).await.unwrap();
+        listener
+            .listen("scalar_tap_receipt_notification")
+            .await
+            .unwrap();
+
+        let replayed = replay_receipt_notifications(
+            &pgpool,
+            ids[0] as i64,
+            1,
+            Some(Address::repeat_byte(0x42)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(replayed, 0);
+        let replayed = replay_receipt_notifications(&pgpool, ids[0] as i64, 1, Some(SIGNER.1))
+            .await
+            .unwrap();
+        assert_eq!(replayed, 2);
+
+        for (id, timestamp_ns) in [(ids[1], 20), (ids[2], 30)] {
+            let notification: NewReceiptNotification =
+                serde_json::from_str(listener.recv().await.unwrap().payload()).unwrap();
+            assert_eq!(notification.id, id);
+            assert_eq!(notification.allocation_id, *ALLOCATION_ID_0);
+            assert_eq!(notification.signer_address, SIGNER.1);
+            assert_eq!(notification.timestamp_ns, timestamp_ns);
+            assert_eq!(notification.value, 5);
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_revalidate_signer_receipts(pgpool: PgPool) {
+        // Found invalid for an unknown signer, for another reason, and stored again meanwhile
+        for nonce in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, 10, 5);
+            let id = store_invalid_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+            let error_code = if nonce == 2 {
+                "value_cap"
+            } else {
+                "unknown_signer"
+            };
+            sqlx::query!(
+                "UPDATE scalar_tap_receipts_invalid SET error_code = $1 WHERE id = $2",
+                error_code,
+                id as i64
+            )
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 3, 10, 5);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        let mut listener = PgListener::connect_with(&pgpool).await.unwrap();
+        listener
+            .listen("scalar_tap_receipt_notification")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            revalidate_signer_receipts(&pgpool, Address::repeat_byte(0x42))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            revalidate_signer_receipts(&pgpool, SIGNER.1).await.unwrap(),
+            1
+        );
+        let notification: NewReceiptNotification =
+            serde_json::from_str(listener.recv().await.unwrap().payload()).unwrap();
+        assert_eq!(notification.signer_address, SIGNER.1);
+
+        let nonces = sqlx::query_scalar!(
+            r#"SELECT nonce::BIGINT AS "nonce!" FROM scalar_tap_receipts ORDER BY nonce"#
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(nonces, vec![1, 3]);
+        let nonces = sqlx::query_scalar!(
+            r#"SELECT nonce::BIGINT AS "nonce!" FROM scalar_tap_receipts_invalid ORDER BY nonce"#
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(nonces, vec![2, 3]);
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! One-shot verification of the receipt to RAV pipeline, using the production configuration.
+//!
+//! A throwaway sender (which is also its own signer) sends a single receipt for a throwaway
+//! allocation. The receipt is stored in the database like indexer-service would do, picked up by
+//! a `SenderAccount`, validated, aggregated by a TAP aggregator running in-process with the
+//! throwaway key, and the resulting RAV is stored. Everything is removed from the database once
+//! done, whether the test succeeded or not.
+//!
+//! The escrow accounts and allocations of the throwaway sender are simulated, the database, the
+//! escrow subgraph and the EIP-712 domain are the production ones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    hex::ToHexExt,
+    primitives::{Address, U256},
+    signers::local::PrivateKeySigner,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use bigdecimal::num_bigint::BigInt;
+use eventuals::Eventual;
+use indexer_common::{
+    escrow_accounts::EscrowAccounts,
+    prelude::{Allocation, AllocationStatus, SubgraphDeployment},
+};
+use ractor::{Actor, ActorRef};
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::{receipt::Receipt, signed_message::EIP712SignedMessage};
+use thegraph_core::DeploymentId;
+use tokio::sync::watch;
+use tracing::{info, warn, Span};
+
+use crate::{
+    agent::{
+        config_reload::Thresholds,
+        denylist_outbox::DenylistOutbox,
+        deployment_fees::DeploymentFees,
+        escrow_subgraph_client,
+        redeemed_ravs::RedeemedRavs,
+        sender_account::{SenderAccount, SenderAccountArgs, SenderAccountMessage},
+        sender_allocation::SenderAllocationMessage,
+        startup_scans::StartupScans,
+    },
+    logging::CorrelationId,
+    CONFIG, EIP_712_DOMAIN,
+};
+
+/// Actor name prefix, keeps the test actors apart from the ones of a running agent.
+const PREFIX: &str = "self-test";
+const RECEIPT_VALUE: u128 = 1;
+const RAV_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Fixture {
+    wallet: PrivateKeySigner,
+    sender: Address,
+    allocation_id: Address,
+}
+
+/// Runs the self-test. Fails with the first step that didn't behave as expected.
+pub async fn run(pgpool: &PgPool) -> Result<()> {
+    let wallet = PrivateKeySigner::random();
+    let fixture = Fixture {
+        sender: wallet.address(),
+        allocation_id: PrivateKeySigner::random().address(),
+        wallet,
+    };
+    info!(
+        sender = %fixture.sender,
+        allocation = %fixture.allocation_id,
+        "Starting self-test with a throwaway sender and allocation."
+    );
+
+    let result = run_pipeline(pgpool, &fixture).await;
+
+    if let Err(e) = cleanup(pgpool, &fixture).await {
+        warn!(
+            error = %e,
+            sender = %fixture.sender,
+            allocation = %fixture.allocation_id,
+            "Failed to remove the self-test rows from the database."
+        );
+    }
+    result
+}
+
+/// The allocation of the throwaway receipt. Its deployment is a placeholder, it's never looked up.
+fn throwaway_allocation(fixture: &Fixture) -> Allocation {
+    Allocation {
+        id: fixture.allocation_id,
+        status: AllocationStatus::Active,
+        subgraph_deployment: SubgraphDeployment {
+            id: DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP")
+                .expect("the placeholder deployment should be valid"),
+            denied_at: None,
+        },
+        indexer: CONFIG.ethereum.indexer_address,
+        allocated_tokens: U256::ZERO,
+        created_at_epoch: 0,
+        created_at_block_hash: String::new(),
+        closed_at_epoch: None,
+        closed_at_epoch_start_block_hash: None,
+        previous_epoch_start_block_hash: None,
+        poi: None,
+        query_fee_rebates: None,
+        query_fees_collected: None,
+    }
+}
+
+async fn run_pipeline(pgpool: &PgPool, fixture: &Fixture) -> Result<()> {
+    let (aggregator, aggregator_endpoint) = tap_aggregator::server::run_server(
+        0,
+        fixture.wallet.clone(),
+        HashSet::from([fixture.sender]),
+        EIP_712_DOMAIN.clone(),
+        100 * 1024,
+        100 * 1024,
+        1,
+    )
+    .await
+    .context("Failed to start the in-process TAP aggregator")?;
+    info!("In-process TAP aggregator started.");
+
+    // Older than the timestamp buffer, so the receipt is included in the RAV request.
+    let timestamp_ns = (SystemTime::now()
+        - Duration::from_millis(CONFIG.tap.rav_request_timestamp_buffer_ms + 1000))
+    .duration_since(UNIX_EPOCH)?
+    .as_nanos() as u64;
+    store_receipt(pgpool, fixture, timestamp_ns).await?;
+    info!("Synthetic receipt stored.");
+
+    let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+        HashMap::from([(fixture.sender, U256::from(u128::MAX))]),
+        HashMap::from([(fixture.sender, vec![fixture.sender])]),
+    ));
+    let escrow_subgraph = escrow_subgraph_client(&CONFIG, reqwest::Client::new());
+    let args = SenderAccountArgs {
+        config: &CONFIG,
+        pgpool: pgpool.clone(),
+        allocation_pgpool: pgpool.clone(),
+        denylist: DenylistOutbox::new(pgpool.clone(), None)?,
+        sender_id: fixture.sender,
+        escrow_accounts,
+        indexer_allocations: watch::channel(HashMap::from([(
+            fixture.allocation_id,
+            throwaway_allocation(fixture),
+        )]))
+        .1,
+        closing_allocations: Eventual::from_value(HashSet::new()),
+        escrow_subgraph,
+        redeemed_ravs: RedeemedRavs::new(pgpool.clone(), escrow_subgraph, Duration::ZERO),
+        startup_scans: StartupScans::new(1, HashMap::new()),
+        deployment_fees: DeploymentFees::new(Eventual::from_value(HashMap::new())),
+        domain_separator: EIP_712_DOMAIN.clone(),
+        horizon_domain_separator: None,
+        sender_aggregator_endpoint: format!("http://{aggregator_endpoint}"),
+        allocation_ids: HashSet::from([fixture.allocation_id]),
+        prefix: Some(PREFIX.to_string()),
+        thresholds: Thresholds {
+            retry_interval: Duration::from_secs(1),
+            ..Thresholds::from(&CONFIG.tap)
+        },
+    };
+    let (sender_account, handle) = SenderAccount::spawn(
+        Some(format!("{PREFIX}:{}", fixture.sender)),
+        SenderAccount,
+        args,
+    )
+    .await
+    .context("Failed to start the SenderAccount")?;
+
+    let result = request_rav(pgpool, fixture, &sender_account).await;
+
+    // Killed rather than stopped, so the allocation isn't marked as last.
+    sender_account.kill();
+    let _ = handle.await;
+    let _ = aggregator.stop();
+    result
+}
+
+async fn request_rav(
+    pgpool: &PgPool,
+    fixture: &Fixture,
+    sender_account: &ActorRef<SenderAccountMessage>,
+) -> Result<()> {
+    let sender_allocation = ActorRef::<SenderAllocationMessage>::where_is(format!(
+        "{PREFIX}:{}:{}",
+        fixture.sender, fixture.allocation_id
+    ))
+    .ok_or_else(|| {
+        anyhow!(
+            "SenderAccount {} did not start a SenderAllocation",
+            sender_account.get_id()
+        )
+    })?;
+    info!("Receipt picked up by the SenderAccount.");
+
+    sender_allocation
+        .cast(SenderAllocationMessage::TriggerRAVRequest(
+            CorrelationId::new(),
+            Span::current(),
+        ))
+        .map_err(|e| anyhow!("Failed to trigger the RAV request: {e}"))?;
+
+    let rav_value = tokio::time::timeout(RAV_TIMEOUT, async {
+        loop {
+            if let Some(value) = stored_rav_value(pgpool, fixture).await? {
+                return anyhow::Ok(value);
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "No RAV stored after {}s, check the logs of the SenderAllocation",
+            RAV_TIMEOUT.as_secs()
+        )
+    })??;
+    ensure!(
+        rav_value == BigDecimal::from(RECEIPT_VALUE),
+        "Stored RAV has value {rav_value}, expected {RECEIPT_VALUE}"
+    );
+    info!("RAV aggregated, verified and stored.");
+    Ok(())
+}
+
+async fn store_receipt(pgpool: &PgPool, fixture: &Fixture, timestamp_ns: u64) -> Result<()> {
+    let receipt = EIP712SignedMessage::new(
+        &EIP_712_DOMAIN,
+        Receipt {
+            allocation_id: fixture.allocation_id,
+            nonce: 0,
+            timestamp_ns,
+            value: RECEIPT_VALUE,
+        },
+        &fixture.wallet,
+    )?;
+    sqlx::query!(
+        r#"
+            INSERT INTO scalar_tap_receipts (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        fixture.sender.encode_hex(),
+        receipt.signature.as_bytes().to_vec(),
+        fixture.allocation_id.encode_hex(),
+        BigDecimal::from(receipt.message.timestamp_ns),
+        BigDecimal::from(receipt.message.nonce),
+        BigDecimal::from(BigInt::from(receipt.message.value)),
+    )
+    .execute(pgpool)
+    .await
+    .context("Failed to store the synthetic receipt")?;
+    Ok(())
+}
+
+async fn stored_rav_value(pgpool: &PgPool, fixture: &Fixture) -> Result<Option<BigDecimal>> {
+    Ok(sqlx::query_scalar!(
+        r#"
+            SELECT value_aggregate
+            FROM scalar_tap_ravs
+            WHERE allocation_id = $1 AND sender_address = $2
+        "#,
+        fixture.allocation_id.encode_hex(),
+        fixture.sender.encode_hex(),
+    )
+    .fetch_optional(pgpool)
+    .await?)
+}
+
+async fn cleanup(pgpool: &PgPool, fixture: &Fixture) -> Result<()> {
+    let allocation_id = fixture.allocation_id.encode_hex();
+    let sender = fixture.sender.encode_hex();
+    let mut tx = pgpool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM scalar_tap_receipts WHERE allocation_id = $1",
+        &allocation_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM scalar_tap_receipts_invalid WHERE allocation_id = $1",
+        &allocation_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM scalar_tap_ravs WHERE allocation_id = $1 AND sender_address = $2",
+        &allocation_id,
+        &sender,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM scalar_tap_rav_requests_failed WHERE allocation_id = $1",
+        &allocation_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM scalar_tap_denylist WHERE sender_address = $1",
+        &sender,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!("Self-test rows removed from the database.");
+    Ok(())
+}
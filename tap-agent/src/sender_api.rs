@@ -0,0 +1,511 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only view of the account of a sender, for the gateways, see `tap.sender_api`.
+//!
+//! `GET /senders/:sender/account` returns the view of the agent of the account of a sender: its
+//! deny status, its unaggregated fees and pending RAVs, by allocation, its suggested escrow
+//! top-up, see [`crate::agent::escrow_top_up`], its latest RAV, and its invalid receipts, legacy
+//! and Horizon. A gateway suspecting lost receipts can compare it with its own records, and top
+//! up its escrow before being denied.
+//!
+//! The requests are authenticated by the EIP-191 signature of [`message`] by one of the signers
+//! of the sender, in the `X-Sender-Signature` header, next to the timestamp in
+//! `X-Sender-Timestamp`. The message is bound to the indexer and to the request, so that a
+//! request sent to an indexer can't be replayed to another one, or to another endpoint. A
+//! timestamp further than `max_request_age_secs` from the clock of the agent is rejected, so
+//! that a request can't be replayed later on. With `tap.signed_status` set, the responses are
+//! signed by the operator like the status, see [`crate::signed_status`].
+//!
+//! Served on its own listener, as it must be reachable by the gateways, unlike the metrics.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use bigdecimal::ToPrimitive;
+use indexer_common::{escrow_accounts::EscrowAccounts, fees::FeeToken};
+use ractor::{call_t, ActorRef};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    agent::{sender_account::SenderAccountMessage, sender_allocation::horizon::to_u128},
+    database::{self, Subsystem},
+    money::{wei, ApiUnit},
+    signed_status::{self, SignedKind},
+    status::{AllocationStatus, StatusState},
+};
+
+pub const TIMESTAMP_HEADER: &str = "x-sender-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-sender-signature";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// First line of [`message`], so that it can't be mistaken for another signed message.
+const MESSAGE_PREFIX: &str = "The Graph indexer sender API request";
+
+/// The message signed by the signers of `sender` to query its account from `indexer`, the
+/// addresses in lowercase hex:
+/// ```text
+/// The Graph indexer sender API request
+/// indexer: <indexer>
+/// request: GET /senders/<sender>/account
+/// timestamp_ms: <timestamp_ms>
+/// ```
+pub fn message(indexer: Address, sender: Address, timestamp_ms: u64) -> String {
+    format!(
+        "{MESSAGE_PREFIX}\nindexer: {}\nrequest: GET /senders/{}/account\ntimestamp_ms: {timestamp_ms}",
+        indexer.to_string().to_lowercase(),
+        sender.to_string().to_lowercase(),
+    )
+}
+
+#[derive(Clone)]
+pub struct SenderApiState {
+    status: StatusState,
+    indexer: Address,
+    max_request_age: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountView {
+    denied: bool,
+    #[serde(serialize_with = "wei")]
+    unaggregated_fees: u128,
+    #[serde(serialize_with = "wei")]
+    pending_ravs: u128,
+    #[serde(serialize_with = "wei")]
+    invalid_receipt_fees: u128,
+    #[serde(serialize_with = "wei")]
+    fees_per_hour: u128,
+    #[serde(serialize_with = "wei")]
+    escrow_top_up: u128,
+    allocations: Vec<AllocationStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct LatestRav {
+    allocation_id: Address,
+    timestamp_ns: u64,
+    #[serde(serialize_with = "wei")]
+    value_aggregate: u128,
+    last: bool,
+    #[serde(rename = "final")]
+    is_final: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct InvalidReceipts {
+    count: u64,
+    #[serde(serialize_with = "wei")]
+    value: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct SenderAccountResponse {
+    sender: Address,
+    fee_token: FeeToken,
+    fee_unit: ApiUnit,
+    /// Unset if the `SenderAccount` of the sender isn't running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<AccountView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    latest_rav: Option<LatestRav>,
+    /// Of the signers of the sender, since the receipts were received.
+    invalid_receipts: InvalidReceipts,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Checks that the request to `indexer` is signed by a signer of `sender`, at most
+/// `max_request_age` from `now_ms`.
+fn authenticate(
+    headers: &HeaderMap,
+    indexer: Address,
+    sender: Address,
+    escrow_accounts: &EscrowAccounts,
+    now_ms: u64,
+    max_request_age: Duration,
+) -> Result<Address, String> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| format!("Missing `{name}` header"))
+    };
+    let timestamp_ms: u64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| format!("Invalid `{TIMESTAMP_HEADER}` header"))?;
+    if now_ms.abs_diff(timestamp_ms) > max_request_age.as_millis() as u64 {
+        return Err("The request is too old, or too far ahead".to_string());
+    }
+    let signer = signed_status::verify(
+        message(indexer, sender, timestamp_ms).as_bytes(),
+        header(SIGNATURE_HEADER)?,
+    )
+    .map_err(|e| format!("Invalid signature: {e}"))?;
+    if escrow_accounts.get_sender_for_signer(&signer).ok() != Some(sender) {
+        return Err(format!("{signer} is not a signer of {sender}"));
+    }
+    Ok(signer)
+}
+
+async fn latest_rav(pgpool: &PgPool, sender: Address) -> anyhow::Result<Option<LatestRav>> {
+    let row = database::acquire(pgpool, Subsystem::Analytics)
+        .await?
+        .run(|conn| {
+            // The allocation of a Horizon RAV is the end of its collection id
+            sqlx::query!(
+                r#"
+                    SELECT
+                        allocation_id AS "allocation_id!",
+                        timestamp_ns AS "timestamp_ns!",
+                        value_aggregate AS "value_aggregate!",
+                        last AS "last!",
+                        final AS "is_final!"
+                    FROM (
+                        SELECT allocation_id, timestamp_ns, value_aggregate, last, final
+                        FROM scalar_tap_ravs
+                        WHERE sender_address = $1
+                        UNION ALL
+                        SELECT RIGHT(collection_id, 40), timestamp_ns, value_aggregate, last, final
+                        FROM tap_horizon_ravs
+                        WHERE payer = $1
+                    ) ravs
+                    ORDER BY timestamp_ns DESC
+                    LIMIT 1
+                "#,
+                sender.encode_hex()
+            )
+            .fetch_optional(conn)
+        })
+        .await?;
+    row.map(|row| {
+        Ok(LatestRav {
+            allocation_id: row.allocation_id.trim().parse()?,
+            timestamp_ns: row
+                .timestamp_ns
+                .to_u64()
+                .ok_or_else(|| anyhow::anyhow!("Invalid RAV timestamp {}", row.timestamp_ns))?,
+            value_aggregate: to_u128(&row.value_aggregate)?,
+            last: row.last,
+            is_final: row.is_final,
+        })
+    })
+    .transpose()
+}
+
+async fn invalid_receipts(pgpool: &PgPool, signers: &[Address]) -> anyhow::Result<InvalidReceipts> {
+    let signers: Vec<String> = signers.iter().map(|signer| signer.encode_hex()).collect();
+    let row = database::acquire(pgpool, Subsystem::Analytics)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    SELECT COUNT(*) AS "count!", SUM(value) AS value
+                    FROM (
+                        SELECT value
+                        FROM scalar_tap_receipts_invalid
+                        WHERE signer_address = ANY($1)
+                        UNION ALL
+                        SELECT value
+                        FROM tap_horizon_receipts_invalid
+                        WHERE signer_address = ANY($1)
+                    ) invalid_receipts
+                "#,
+                &signers
+            )
+            .fetch_one(conn)
+        })
+        .await?;
+    Ok(InvalidReceipts {
+        count: row.count as u64,
+        value: row
+            .value
+            .as_ref()
+            .map(to_u128)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+async fn account(sender: Address) -> Result<AccountView, String> {
+    let sender_account = ActorRef::<SenderAccountMessage>::where_is(sender.to_string())
+        .ok_or_else(|| "The sender has no SenderAccount running".to_string())?;
+    let status = call_t!(
+        sender_account,
+        SenderAccountMessage::GetStatus,
+        QUERY_TIMEOUT.as_millis() as u64
+    )
+    .map_err(|e| format!("SenderAccount did not respond: {e}"))?;
+    Ok(AccountView {
+        denied: status.denied,
+        unaggregated_fees: status.unaggregated_fees,
+        pending_ravs: status.pending_ravs,
+        invalid_receipt_fees: status.invalid_receipt_fees,
+        fees_per_hour: status.fees_per_hour,
+        escrow_top_up: status.escrow_top_up,
+        allocations: status.allocations,
+    })
+}
+
+fn error(status: StatusCode, error: String) -> Response {
+    (status, Json(ErrorResponse { error })).into_response()
+}
+
+async fn handler_account(
+    State(state): State<SenderApiState>,
+    Path(sender): Path<Address>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(escrow_accounts) = state.status.escrow_accounts.value_immediate() else {
+        return error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The escrow accounts are not available yet".to_string(),
+        );
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    if let Err(e) = authenticate(
+        &headers,
+        state.indexer,
+        sender,
+        &escrow_accounts,
+        now_ms,
+        state.max_request_age,
+    ) {
+        return error(StatusCode::UNAUTHORIZED, e);
+    }
+
+    let latest_rav = match latest_rav(&state.status.pgpool, sender).await {
+        Ok(latest_rav) => latest_rav,
+        Err(e) => {
+            return error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Failed to read the latest RAV: {e}"),
+            )
+        }
+    };
+    let signers = escrow_accounts.get_signers_for_sender(&sender);
+    let invalid_receipts = match invalid_receipts(&state.status.pgpool, &signers).await {
+        Ok(invalid_receipts) => invalid_receipts,
+        Err(e) => {
+            return error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Failed to read the invalid receipts: {e}"),
+            )
+        }
+    };
+    let (account, error) = match account(sender).await {
+        Ok(account) => (Some(account), None),
+        Err(error) => (None, Some(error)),
+    };
+    let response = SenderAccountResponse {
+        sender,
+        fee_token: FeeToken::Grt,
+        fee_unit: ApiUnit,
+        account,
+        error,
+        latest_rav,
+        invalid_receipts,
+    };
+    match &state.status.signer {
+        Some(signer) => signer.signed_json(SignedKind::SenderAccount, &response),
+        None => Json(response).into_response(),
+    }
+}
+
+pub fn router(status: StatusState, indexer: Address, max_request_age: Duration) -> Router {
+    Router::new()
+        .route("/senders/:sender/account", get(handler_account))
+        .with_state(SenderApiState {
+            status,
+            indexer,
+            max_request_age,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::{
+        hex,
+        primitives::U256,
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_invalid_receipt, store_rav, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, INDEXER, SENDER, SENDER_2, SIGNER,
+    };
+
+    const NOW_MS: u64 = 1_700_000_000_000;
+    const MAX_AGE: Duration = Duration::from_secs(300);
+
+    fn headers(
+        wallet: &PrivateKeySigner,
+        indexer: Address,
+        sender: Address,
+        timestamp_ms: u64,
+    ) -> HeaderMap {
+        let signature = wallet
+            .sign_message_sync(message(indexer, sender, timestamp_ms).as_bytes())
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp_ms));
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&hex::encode_prefixed(signature.as_bytes())).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let authenticate = |headers: &HeaderMap, sender: Address| {
+            authenticate(
+                headers,
+                INDEXER.1,
+                sender,
+                &escrow_accounts,
+                NOW_MS,
+                MAX_AGE,
+            )
+        };
+        let signed = |wallet: &PrivateKeySigner, sender: Address, timestamp_ms: u64| {
+            headers(wallet, INDEXER.1, sender, timestamp_ms)
+        };
+
+        assert_eq!(
+            authenticate(&signed(&SIGNER.0, SENDER.1, NOW_MS - 1000), SENDER.1),
+            Ok(SIGNER.1)
+        );
+        // Not a signer of the sender
+        assert!(authenticate(&signed(&SENDER_2.0, SENDER.1, NOW_MS), SENDER.1).is_err());
+        // Signed for another sender
+        assert!(authenticate(&signed(&SIGNER.0, SENDER_2.1, NOW_MS), SENDER.1).is_err());
+        // Signed for another indexer
+        let other_indexer = headers(&SIGNER.0, SENDER_2.1, SENDER.1, NOW_MS);
+        assert!(authenticate(&other_indexer, SENDER.1).is_err());
+        // Too old, or too far ahead
+        assert!(authenticate(&signed(&SIGNER.0, SENDER.1, NOW_MS - 301_000), SENDER.1).is_err());
+        assert!(authenticate(&signed(&SIGNER.0, SENDER.1, NOW_MS + 301_000), SENDER.1).is_err());
+        // Replaying the signature with another timestamp
+        let mut replayed = signed(&SIGNER.0, SENDER.1, NOW_MS - 400_000);
+        replayed.insert(TIMESTAMP_HEADER, HeaderValue::from(NOW_MS));
+        assert!(authenticate(&replayed, SENDER.1).is_err());
+        assert!(authenticate(&HeaderMap::new(), SENDER.1).is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_account_queries(pgpool: PgPool) {
+        assert!(latest_rav(&pgpool, SENDER.1).await.unwrap().is_none());
+        assert_eq!(
+            invalid_receipts(&pgpool, &[SIGNER.1]).await.unwrap(),
+            InvalidReceipts::default()
+        );
+
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 20, 150),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        for nonce in 0..3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, 30, 7);
+            store_invalid_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let rav = latest_rav(&pgpool, SENDER.1).await.unwrap().unwrap();
+        assert_eq!(rav.allocation_id, *ALLOCATION_ID_1);
+        assert_eq!(rav.timestamp_ns, 20);
+        assert_eq!(rav.value_aggregate, 150);
+        assert!(latest_rav(&pgpool, SENDER_2.1).await.unwrap().is_none());
+
+        assert_eq!(
+            invalid_receipts(&pgpool, &[SIGNER.1]).await.unwrap(),
+            InvalidReceipts {
+                count: 3,
+                value: 21
+            }
+        );
+
+        // Horizon RAVs and invalid receipts count too
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_horizon_ravs (
+                    signature, collection_id, payer, data_service, service_provider,
+                    timestamp_ns, value_aggregate, metadata
+                )
+                VALUES ('', $1, $2, $3, $3, 40, 300, '')
+            "#,
+            format!("{:0>64}", ALLOCATION_ID_0.encode_hex()),
+            SENDER.1.encode_hex(),
+            INDEXER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_horizon_receipts_invalid (
+                    signer_address, signature, collection_id, payer, data_service,
+                    service_provider, timestamp_ns, nonce, value
+                )
+                VALUES ($1, '', $2, $3, $4, $4, 50, 0, 9)
+            "#,
+            SIGNER.1.encode_hex(),
+            format!("{:0>64}", ALLOCATION_ID_0.encode_hex()),
+            SENDER.1.encode_hex(),
+            INDEXER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let rav = latest_rav(&pgpool, SENDER.1).await.unwrap().unwrap();
+        assert_eq!(rav.allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(rav.timestamp_ns, 40);
+        assert_eq!(rav.value_aggregate, 300);
+        assert_eq!(
+            invalid_receipts(&pgpool, &[SIGNER.1]).await.unwrap(),
+            InvalidReceipts {
+                count: 4,
+                value: 30
+            }
+        );
+        assert_eq!(
+            invalid_receipts(&pgpool, &[SENDER_2.1]).await.unwrap(),
+            InvalidReceipts::default()
+        );
+    }
+}
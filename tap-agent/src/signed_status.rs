@@ -0,0 +1,284 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signatures of the status of the agent by the operator key, for external monitoring.
+//!
+//! With `tap.signed_status` set, the responses of `GET /status`, `GET /signers/:signer`,
+//! `GET /metrics/summary` and of the sender API are signed, and a heartbeat document is signed
+//! every `heartbeat_interval_secs` and served by `GET /heartbeat`. The signature is the EIP-191
+//! signature of the exact bytes of the body, in the `X-Indexer-Signature` header, next to the
+//! operator in `X-Indexer-Signer`. Anyone knowing the operator of the indexer, from the network
+//! subgraph, can check with [`SignedBody::open`] that the data comes from its agent, for example
+//! to settle the SLA reports between indexers and gateways.
+//!
+//! The body is an [`Envelope`] of the response, with the indexer, the time it was signed and
+//! what it is, so that a captured response can't be passed off as one of another indexer, as a
+//! recent one, or as another kind of response. The heartbeats are also numbered.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    hex,
+    primitives::Address,
+    signers::{
+        local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+        Signature, SignerSync,
+    },
+};
+use anyhow::anyhow;
+use axum::{
+    http::{header, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::error;
+
+pub const SIGNER_HEADER: &str = "x-indexer-signer";
+pub const SIGNATURE_HEADER: &str = "x-indexer-signature";
+
+lazy_static! {
+    static ref LATEST_HEARTBEAT: Mutex<Option<SignedBody>> = Mutex::new(None);
+}
+
+/// What a signed body is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignedKind {
+    Heartbeat,
+    Status,
+    Signer,
+    MetricsSummary,
+    SenderAccount,
+}
+
+/// The signed body of a response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub indexer: Address,
+    /// Seconds since the UNIX epoch.
+    pub issued_at: u64,
+    pub kind: SignedKind,
+    pub body: T,
+}
+
+/// A JSON body with the signature of its bytes.
+#[derive(Debug, Clone)]
+pub struct SignedBody {
+    pub body: Vec<u8>,
+    pub signer: Address,
+    /// Hex encoded.
+    pub signature: String,
+}
+
+impl SignedBody {
+    /// Checks that the body was signed by `signer`.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        if verify(&self.body, &self.signature)? != self.signer {
+            return Err(anyhow!("The body isn't signed by {}", self.signer));
+        }
+        Ok(())
+    }
+
+    /// The response in the body, once checked that it was signed by `signer`, the operator of
+    /// `indexer`, that it's a `kind` of `indexer`, and that it was signed less than `max_age`
+    /// ago.
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        indexer: Address,
+        kind: SignedKind,
+        max_age: Duration,
+    ) -> anyhow::Result<T> {
+        self.verify()?;
+        let envelope: Envelope<T> = serde_json::from_slice(&self.body)?;
+        if envelope.indexer != indexer {
+            return Err(anyhow!("Signed for the indexer {}", envelope.indexer));
+        }
+        if envelope.kind != kind {
+            return Err(anyhow!("Signed as a {:?}", envelope.kind));
+        }
+        if unix_secs().abs_diff(envelope.issued_at) > max_age.as_secs() {
+            return Err(anyhow!("Signed at {}, too long ago", envelope.issued_at));
+        }
+        Ok(envelope.body)
+    }
+}
+
+impl IntoResponse for SignedBody {
+    fn into_response(self) -> Response {
+        (
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (
+                    HeaderName::from_static(SIGNER_HEADER),
+                    self.signer.to_string(),
+                ),
+                (HeaderName::from_static(SIGNATURE_HEADER), self.signature),
+            ],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+/// Signs the responses of `indexer` with the operator key, the first one derived from
+/// `indexer.operator_mnemonic`.
+#[derive(Clone)]
+pub struct StatusSigner {
+    wallet: PrivateKeySigner,
+    indexer: Address,
+}
+
+impl StatusSigner {
+    pub fn new(operator_mnemonic: &str, indexer: Address) -> anyhow::Result<Self> {
+        let wallet = MnemonicBuilder::<English>::default()
+            .phrase(operator_mnemonic)
+            .index(0)?
+            .build()?;
+        Ok(Self { wallet, indexer })
+    }
+
+    pub fn operator(&self) -> Address {
+        self.wallet.address()
+    }
+
+    /// Signs the [`Envelope`] of `value`.
+    pub fn sign<T: Serialize>(&self, kind: SignedKind, value: &T) -> anyhow::Result<SignedBody> {
+        let body = serde_json::to_vec(&Envelope {
+            indexer: self.indexer,
+            issued_at: unix_secs(),
+            kind,
+            body: value,
+        })?;
+        let signature = self.wallet.sign_message_sync(&body)?;
+        Ok(SignedBody {
+            body,
+            signer: self.operator(),
+            signature: hex::encode_prefixed(signature.as_bytes()),
+        })
+    }
+
+    /// The [`Envelope`] of `value` as a JSON response, with the signature of its body.
+    pub fn signed_json<T: Serialize>(&self, kind: SignedKind, value: &T) -> Response {
+        match self.sign(kind, value) {
+            Ok(signed) => signed.into_response(),
+            Err(e) => {
+                error!("Failed to sign the status: {:#}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// The signer of `body`, to compare with the operator of the indexer.
+pub fn verify(body: &[u8], signature: &str) -> anyhow::Result<Address> {
+    let signature = Signature::try_from(hex::decode(signature)?.as_slice())?;
+    Ok(signature.recover_address_from_msg(body)?)
+}
+
+/// Signed in an [`Envelope`] with the indexer and the time it was issued at.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub operator: Address,
+    pub version: String,
+    /// Seconds since the UNIX epoch.
+    pub started_at: u64,
+    /// Since the start, from 0.
+    pub sequence: u64,
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Latest heartbeat signed, if any was yet.
+pub fn latest_heartbeat() -> Option<SignedBody> {
+    LATEST_HEARTBEAT.lock().unwrap().clone()
+}
+
+/// Signs a heartbeat every `interval`, forever.
+pub async fn run_heartbeats(signer: StatusSigner, interval: Duration) {
+    let started_at = unix_secs();
+    let mut interval = tokio::time::interval(interval);
+    for sequence in 0.. {
+        interval.tick().await;
+        let heartbeat = Heartbeat {
+            operator: signer.operator(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at,
+            sequence,
+        };
+        match signer.sign(SignedKind::Heartbeat, &heartbeat) {
+            Ok(signed) => *LATEST_HEARTBEAT.lock().unwrap() = Some(signed),
+            Err(e) => error!("Failed to sign the heartbeat: {:#}", e),
+        }
+    }
+}
+
+async fn handler_heartbeat() -> Response {
+    match latest_heartbeat() {
+        Some(signed) => signed.into_response(),
+        None => (StatusCode::NOT_FOUND, "No heartbeat signed yet").into_response(),
+    }
+}
+
+pub fn router() -> Router {
+    Router::new().route("/heartbeat", get(handler_heartbeat))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use alloy::primitives::Address;
+
+    use super::{latest_heartbeat, verify, Heartbeat, SignedKind, StatusSigner};
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[tokio::test]
+    async fn test_signed_heartbeat() {
+        let indexer = Address::repeat_byte(0x11);
+        let signer = StatusSigner::new(MNEMONIC, indexer).unwrap();
+        tokio::spawn(super::run_heartbeats(
+            signer.clone(),
+            Duration::from_secs(3600),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut signed = latest_heartbeat().unwrap();
+        signed.verify().unwrap();
+        assert_eq!(signed.signer, signer.operator());
+        let max_age = Duration::from_secs(60);
+        let heartbeat: Heartbeat = signed
+            .open(indexer, SignedKind::Heartbeat, max_age)
+            .unwrap();
+        assert_eq!(heartbeat.operator, signer.operator());
+        assert_eq!(heartbeat.sequence, 0);
+
+        // Not a response of another indexer, nor of another kind
+        assert!(signed
+            .open::<Heartbeat>(Address::ZERO, SignedKind::Heartbeat, max_age)
+            .is_err());
+        assert!(signed
+            .open::<Heartbeat>(indexer, SignedKind::Status, max_age)
+            .is_err());
+
+        // Tampered with
+        signed.body[2] ^= 1;
+        assert_ne!(
+            verify(&signed.body, &signed.signature).unwrap(),
+            signer.operator()
+        );
+        assert!(signed.verify().is_err());
+    }
+}
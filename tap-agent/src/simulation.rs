@@ -0,0 +1,473 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replay of the stored receipts of a sender through the fee tracking and RAV triggers of a
+//! `SenderAccount`, for the `simulate` subcommand.
+//!
+//! The receipts of a time range are added in the order of their timestamps, on a clock following
+//! them, and the RAV triggers and deny condition are evaluated after each one as a
+//! `SenderAccount` does. The RAVs that would have been requested are assumed to succeed right
+//! away. Nothing is written to the database and no aggregator is called, so it's safe to run
+//! against the production database, even while tap-agent is running.
+//!
+//! This isn't an exact replay of the past:
+//! - the aggregated receipts are only replayed if they're kept, with
+//!   `tap.rav_request.delete_receipts_with_rav` disabled, or archived by the receipt compaction.
+//! - the fees start from zero at the start of the range.
+//! - the deny condition only checks the unaggregated fees against
+//!   `tap.max_amount_willing_to_lose_grt`. The escrow balances, invalid receipts and trust
+//!   scores of the past aren't stored.
+//! - only the legacy (TAP v1) receipts are replayed.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::{
+    types::{
+        chrono::{DateTime, Utc},
+        BigDecimal,
+    },
+    PgPool,
+};
+
+use crate::{
+    agent::{
+        config_reload::Thresholds,
+        deny_condition::DenyConditionInputs,
+        denylist_outbox::DenyReason,
+        rav_queue,
+        sender_allocation::horizon::{to_u128, to_u64},
+        sender_fee_tracker::SenderFeeTracker,
+    },
+    config::Tap,
+    money::wei,
+};
+
+#[derive(Debug, Clone)]
+pub struct SimulationOptions {
+    pub sender: Address,
+    /// Signers of the sender, whose receipts are replayed.
+    pub signers: Vec<Address>,
+    /// Start of the range, inclusive.
+    pub from: DateTime<Utc>,
+    /// End of the range, exclusive.
+    pub to: DateTime<Utc>,
+}
+
+/// What would have triggered a RAV request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RavTrigger {
+    /// The receipts of the allocation outside the buffer reached
+    /// `tap.rav_request.max_receipts_per_request`.
+    ReceiptLimit,
+    /// The fees of the sender outside the buffer reached `tap.rav_request.trigger_value_divisor`
+    /// of the amount willing to lose, the allocation was the first of the RAV queue.
+    TriggerValue,
+    /// The fees of the allocation went unaggregated for longer than
+    /// `tap.rav_request.checkpoint_interval_days`.
+    Checkpoint,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulatedRav {
+    /// Timestamp of the receipt after which the RAV would have been requested.
+    pub requested_at_ns: u64,
+    pub allocation_id: Address,
+    pub trigger: RavTrigger,
+    pub receipts: u64,
+    #[serde(serialize_with = "wei")]
+    pub value_aggregate: u128,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulatedDenial {
+    pub denied_at_ns: u64,
+    pub reason: DenyReason,
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_fees: u128,
+    /// Unset if the sender would still be denied at the end of the range.
+    pub allowed_at_ns: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulationReport {
+    pub sender: Address,
+    pub receipts: u64,
+    pub ravs: Vec<SimulatedRav>,
+    pub denials: Vec<SimulatedDenial>,
+    /// At the end of the range.
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_fees: u128,
+}
+
+/// Replays the receipts of `options` with the RAV request settings of `tap`.
+pub async fn simulate(
+    pgpool: &PgPool,
+    options: &SimulationOptions,
+    tap: &Tap,
+) -> Result<SimulationReport> {
+    let signers: Vec<String> = options
+        .signers
+        .iter()
+        .map(|signer| signer.encode_hex())
+        .collect();
+    let [from, to] = [options.from, options.to]
+        .map(|at| at.timestamp_nanos_opt().unwrap_or(i64::MAX).max(0) as u64);
+
+    let mut simulation = Simulation::new(options.sender, from, tap);
+    let mut rows = sqlx::query!(
+        r#"
+            SELECT
+                allocation_id AS "allocation_id!",
+                timestamp_ns AS "timestamp_ns!",
+                value AS "value!"
+            FROM (
+                SELECT id, allocation_id, timestamp_ns, value
+                FROM scalar_tap_receipts
+                WHERE signer_address = ANY($1) AND timestamp_ns >= $2 AND timestamp_ns < $3
+                UNION ALL
+                SELECT receipt.id, receipt.allocation_id, receipt.timestamp_ns, receipt.value
+                FROM scalar_tap_receipts_archive batch,
+                    jsonb_populate_recordset(NULL::scalar_tap_receipts, batch.receipts) receipt
+                WHERE batch.sender_address = $4
+                    AND receipt.signer_address = ANY($1)
+                    AND receipt.timestamp_ns >= $2
+                    AND receipt.timestamp_ns < $3
+            ) receipts
+            ORDER BY timestamp_ns, id
+        "#,
+        &signers,
+        BigDecimal::from(from),
+        BigDecimal::from(to),
+        options.sender.encode_hex()
+    )
+    .fetch(pgpool);
+    while let Some(row) = rows.try_next().await? {
+        simulation.receipt(
+            Address::from_str(&row.allocation_id)?,
+            to_u64(&row.timestamp_ns)?,
+            to_u128(&row.value)?,
+        );
+    }
+    Ok(simulation.report())
+}
+
+/// The state of a `SenderAccount` relevant to its RAV requests, on the clock of the receipts.
+struct Simulation<'a> {
+    tap: &'a Tap,
+    thresholds: Thresholds,
+    buffer_ns: u64,
+    /// The clock at `origin_ns`.
+    origin: Instant,
+    origin_ns: u64,
+    tracker: SenderFeeTracker,
+    /// Unaggregated receipts of each allocation, timestamp and value, oldest first.
+    unaggregated: HashMap<Address, VecDeque<(u64, u128)>>,
+    unaggregated_since: HashMap<Address, Instant>,
+    report: SimulationReport,
+}
+
+impl<'a> Simulation<'a> {
+    fn new(sender: Address, origin_ns: u64, tap: &'a Tap) -> Self {
+        Self {
+            tap,
+            thresholds: Thresholds::from(tap),
+            buffer_ns: tap.rav_request_timestamp_buffer_ms * 1_000_000,
+            origin: Instant::now(),
+            origin_ns,
+            tracker: SenderFeeTracker::new(Duration::from_millis(
+                tap.rav_request_timestamp_buffer_ms,
+            )),
+            unaggregated: HashMap::new(),
+            unaggregated_since: HashMap::new(),
+            report: SimulationReport {
+                sender,
+                receipts: 0,
+                ravs: Vec::new(),
+                denials: Vec::new(),
+                unaggregated_fees: 0,
+            },
+        }
+    }
+
+    fn at(&self, timestamp_ns: u64) -> Instant {
+        self.origin + Duration::from_nanos(timestamp_ns.saturating_sub(self.origin_ns))
+    }
+
+    /// Adds a receipt, received in timestamp order, then evaluates the RAV triggers as
+    /// `SenderAccount::evaluate_rav_triggers` and the checkpoint RAVs do.
+    fn receipt(&mut self, allocation_id: Address, timestamp_ns: u64, value: u128) {
+        let now = self.at(timestamp_ns);
+        self.report.receipts += 1;
+        self.tracker.add_batch_at(allocation_id, value, 1, now);
+        self.unaggregated
+            .entry(allocation_id)
+            .or_default()
+            .push_back((timestamp_ns, value));
+        self.unaggregated_since.entry(allocation_id).or_insert(now);
+
+        self.evaluate_deny_condition(timestamp_ns);
+        let receipt_limit_reached = self
+            .tracker
+            .get_total_counter_outside_buffer_for_allocation_at(&allocation_id, now)
+            >= self.tap.rav_request_receipt_limit;
+        let trigger_value_reached = self.tracker.get_total_fee_outside_buffer_at(now)
+            >= self.thresholds.rav_request_trigger_value;
+        if receipt_limit_reached {
+            self.request_rav(allocation_id, RavTrigger::ReceiptLimit, timestamp_ns);
+        } else if trigger_value_reached {
+            let next = rav_queue::build(
+                self.tracker.rav_candidates_at(now),
+                &self.unaggregated_since,
+                &HashSet::new(),
+                now,
+            )
+            .pop();
+            if let Some(next) = next {
+                self.request_rav(next.allocation_id, RavTrigger::TriggerValue, timestamp_ns);
+            }
+        }
+        if let Some(interval) = self.tap.rav_checkpoint_interval {
+            let due: Vec<Address> = self
+                .unaggregated_since
+                .iter()
+                .filter(|(_, since)| now.saturating_duration_since(**since) >= interval)
+                .map(|(allocation_id, _)| *allocation_id)
+                .collect();
+            for allocation_id in due {
+                self.request_rav(allocation_id, RavTrigger::Checkpoint, timestamp_ns);
+            }
+        }
+        self.evaluate_deny_condition(timestamp_ns);
+    }
+
+    /// Aggregates the receipts of the allocation outside the buffer, up to the receipt limit.
+    fn request_rav(&mut self, allocation_id: Address, trigger: RavTrigger, timestamp_ns: u64) {
+        let Some(unaggregated) = self.unaggregated.get_mut(&allocation_id) else {
+            return;
+        };
+        let (mut receipts, mut value_aggregate) = (0, 0);
+        while receipts < self.tap.rav_request_receipt_limit
+            && unaggregated
+                .front()
+                .is_some_and(|(receipt_timestamp_ns, _)| {
+                    timestamp_ns.saturating_sub(*receipt_timestamp_ns) >= self.buffer_ns
+                })
+        {
+            let (_, value) = unaggregated.pop_front().expect("checked above");
+            receipts += 1;
+            value_aggregate += value;
+        }
+        if receipts == 0 {
+            return;
+        }
+        let fees = unaggregated.iter().map(|(_, value)| value).sum();
+        let counter = unaggregated.len() as u64;
+        self.tracker.update(allocation_id, fees, counter);
+        // The fees left are the ones in the buffer
+        self.unaggregated_since.remove(&allocation_id);
+        if fees > 0 {
+            self.unaggregated_since
+                .insert(allocation_id, self.at(timestamp_ns));
+        }
+        self.report.ravs.push(SimulatedRav {
+            requested_at_ns: timestamp_ns,
+            allocation_id,
+            trigger,
+            receipts,
+            value_aggregate,
+        });
+    }
+
+    fn evaluate_deny_condition(&mut self, timestamp_ns: u64) {
+        let inputs = DenyConditionInputs {
+            unaggregated_fees: self.tracker.get_total_fee(),
+            sender_balance: u128::MAX,
+            max_unaggregated_fees: self.thresholds.max_unnaggregated_fees_per_sender,
+            ..Default::default()
+        };
+        let denied = self
+            .report
+            .denials
+            .last()
+            .is_some_and(|denial| denial.allowed_at_ns.is_none());
+        match (denied, inputs.reason()) {
+            (false, Some(reason)) => self.report.denials.push(SimulatedDenial {
+                denied_at_ns: timestamp_ns,
+                reason,
+                unaggregated_fees: inputs.unaggregated_fees,
+                allowed_at_ns: None,
+            }),
+            (true, None) => {
+                if let Some(denial) = self.report.denials.last_mut() {
+                    denial.allowed_at_ns = Some(timestamp_ns);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn report(mut self) -> SimulationReport {
+        self.report.unaggregated_fees = self.tracker.get_total_fee();
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::hex::ToHexExt;
+    use sqlx::{
+        types::chrono::{DateTime, Utc},
+        PgPool,
+    };
+
+    use super::{
+        simulate, RavTrigger, SimulatedDenial, SimulatedRav, Simulation, SimulationOptions,
+    };
+    use crate::{
+        agent::denylist_outbox::DenyReason,
+        config::Tap,
+        tap::test_utils::{
+            create_received_receipt, store_receipt, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER,
+            SIGNER,
+        },
+    };
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn tap() -> Tap {
+        Tap {
+            rav_request_trigger_value: 100,
+            rav_request_timestamp_buffer_ms: 1000,
+            rav_request_receipt_limit: 3,
+            max_unnaggregated_fees_per_sender: 150,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulation() {
+        let tap = tap();
+        let mut simulation = Simulation::new(SENDER.1, 0, &tap);
+        // Up to the receipt limit once out of the buffer
+        for timestamp in 0..4 {
+            simulation.receipt(*ALLOCATION_ID_0, timestamp * SECOND, 1);
+        }
+        // Over the trigger value out of the buffer
+        simulation.receipt(*ALLOCATION_ID_1, 10 * SECOND, 60);
+        simulation.receipt(*ALLOCATION_ID_0, 10 * SECOND, 50);
+        simulation.receipt(*ALLOCATION_ID_1, 12 * SECOND, 1);
+        // Denied over the amount willing to lose, until the fees are out of the buffer
+        simulation.receipt(*ALLOCATION_ID_0, 20 * SECOND, 200);
+        simulation.receipt(*ALLOCATION_ID_1, 22 * SECOND, 1);
+
+        let report = simulation.report();
+        assert_eq!(report.receipts, 9);
+        assert_eq!(
+            report.ravs,
+            vec![
+                SimulatedRav {
+                    requested_at_ns: 3 * SECOND,
+                    allocation_id: *ALLOCATION_ID_0,
+                    trigger: RavTrigger::ReceiptLimit,
+                    receipts: 3,
+                    value_aggregate: 3,
+                },
+                SimulatedRav {
+                    requested_at_ns: 12 * SECOND,
+                    allocation_id: *ALLOCATION_ID_1,
+                    trigger: RavTrigger::TriggerValue,
+                    receipts: 1,
+                    value_aggregate: 60,
+                },
+                SimulatedRav {
+                    requested_at_ns: 22 * SECOND,
+                    allocation_id: *ALLOCATION_ID_0,
+                    trigger: RavTrigger::TriggerValue,
+                    receipts: 3,
+                    value_aggregate: 251,
+                },
+            ]
+        );
+        assert_eq!(
+            report.denials,
+            vec![SimulatedDenial {
+                denied_at_ns: 20 * SECOND,
+                reason: DenyReason::MaxUnaggregatedFees,
+                unaggregated_fees: 252,
+                allowed_at_ns: Some(22 * SECOND),
+            }]
+        );
+        assert_eq!(report.unaggregated_fees, 2);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_simulate(pgpool: PgPool) {
+        for (nonce, timestamp_ns) in [(1, SECOND), (2, 2 * SECOND), (3, 3 * SECOND)] {
+            let receipt =
+                create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, timestamp_ns, 60);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Out of the range
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 4, 10 * SECOND, 60);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        // The first one was archived by the receipt compaction
+        sqlx::query!(
+            r#"
+                WITH moved AS (DELETE FROM scalar_tap_receipts WHERE nonce = 1 RETURNING *)
+                INSERT INTO scalar_tap_receipts_archive (
+                    sender_address, allocation_id, rav_timestamp_ns, receipt_count, value, receipts
+                )
+                SELECT $1, allocation_id, timestamp_ns, 1, value, jsonb_agg(moved)
+                FROM moved
+                GROUP BY allocation_id, timestamp_ns, value
+            "#,
+            SENDER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let options = SimulationOptions {
+            sender: SENDER.1,
+            signers: vec![SIGNER.1],
+            from: DateTime::<Utc>::from_timestamp(0, 0).unwrap(),
+            to: DateTime::<Utc>::from_timestamp(10, 0).unwrap(),
+        };
+        let report = simulate(&pgpool, &options, &tap()).await.unwrap();
+        assert_eq!(report.receipts, 3);
+        assert_eq!(
+            report.ravs,
+            vec![SimulatedRav {
+                requested_at_ns: 3 * SECOND,
+                allocation_id: *ALLOCATION_ID_0,
+                trigger: RavTrigger::TriggerValue,
+                receipts: 2,
+                value_aggregate: 120,
+            }]
+        );
+        // Denied before the RAV request, as a `SenderAccount` does, then allowed again
+        assert_eq!(
+            report.denials,
+            vec![SimulatedDenial {
+                denied_at_ns: 3 * SECOND,
+                reason: DenyReason::MaxUnaggregatedFees,
+                unaggregated_fees: 180,
+                allowed_at_ns: Some(3 * SECOND),
+            }]
+        );
+        assert_eq!(report.unaggregated_fees, 60);
+    }
+}
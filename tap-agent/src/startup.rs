@@ -0,0 +1,431 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Ordering of the startup of the agent, gated on the readiness of its dependencies.
+//!
+//! The actors are only spawned once the database is reachable, the migrations tap-agent needs
+//! are applied, the EIP-712 domains are valid and the first escrow accounts are known. They used
+//! to be awaited along the way, some of them in the `pre_start` of the `SenderAccountsManager`,
+//! where an unreachable subgraph left the agent hanging without saying on what.
+//!
+//! Each [`Phase`] runs with its timeout in `tap.startup`, and the first one failing or timing out
+//! fails the startup with its name. The phases are logged, timed by the
+//! `tap_startup_phase_seconds` metric and reported in `/status` with [`report`].
+//!
+//! The metrics and status servers are started before the phases, through [`Routes`]: until the
+//! agent is started, `/status` only has the phases and the other routes answer 503.
+
+use std::{
+    convert::Infallible,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address};
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::any,
+    Json, Router,
+};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use serde::Serialize;
+use sqlx::PgPool;
+use tower::ServiceExt;
+use tracing::{error, info};
+
+lazy_static! {
+    static ref PHASE_SECONDS: GaugeVec = register_gauge_vec!(
+        "tap_startup_phase_seconds",
+        "Time taken by each phase of the startup",
+        &["phase"]
+    )
+    .unwrap();
+    static ref REPORT: Mutex<Vec<PhaseReport>> = Mutex::new(
+        Phase::ALL
+            .iter()
+            .map(|phase| PhaseReport {
+                phase: *phase,
+                state: PhaseState::Pending,
+            })
+            .collect()
+    );
+}
+
+/// A column added by each migration tap-agent needs, to tell whether it's applied. The
+/// migrations are run by indexer-agent, which doesn't record them in `_sqlx_migrations`.
+const MIGRATION_MARKERS: &[(&str, &str, &str)] = &[
+    (
+        "20230912220523_tap_receipts",
+        "scalar_tap_receipts",
+        "signer_address",
+    ),
+    (
+        "20230915230734_tap_ravs",
+        "scalar_tap_ravs",
+        "value_aggregate",
+    ),
+    (
+        "20231118024433_tap_denylist",
+        "scalar_tap_denylist",
+        "sender_address",
+    ),
+    (
+        "20240925120000_tap_horizon",
+        "tap_horizon_receipts",
+        "signer_address",
+    ),
+    (
+        "20241007120000_tap_sender_leases",
+        "tap_sender_leases",
+        "instance_id",
+    ),
+    (
+        "20241014120000_tap_receipts_invalid_error_code",
+        "scalar_tap_receipts_invalid",
+        "error_code",
+    ),
+    (
+        "20241021120000_tap_receipts_quarantined",
+        "scalar_tap_receipts_quarantined",
+        "quarantined_at",
+    ),
+    (
+        "20241028120000_tap_receipts_archive",
+        "scalar_tap_receipts_archive",
+        "receipts",
+    ),
+    (
+        "20241111120000_tap_denylist_reason",
+        "scalar_tap_denylist",
+        "reason",
+    ),
+    (
+        "20241118120000_tap_paused_senders",
+        "tap_paused_senders",
+        "paused_at",
+    ),
+    (
+        "20241125120000_tap_rav_request_intents",
+        "scalar_tap_rav_request_intents",
+        "expected_value_aggregate",
+    ),
+    (
+        "20241202120000_tap_sender_rate_limits",
+        "tap_sender_rate_limits",
+        "capacity",
+    ),
+    (
+        "20241209120000_tap_fee_token",
+        "scalar_tap_ravs",
+        "fee_token",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Connection to the database.
+    Database,
+    /// See [`check_schema`].
+    Schema,
+    /// See [`crate::privileges`].
+    Privileges,
+    /// See [`check_domains`].
+    Domains,
+    /// First escrow accounts.
+    EscrowAccounts,
+    /// Spawn of the `SenderAccountsManager`, and of the `SenderAccount`s of the pending receipts.
+    SenderAccounts,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::Database,
+        Phase::Schema,
+        Phase::Privileges,
+        Phase::Domains,
+        Phase::EscrowAccounts,
+        Phase::SenderAccounts,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Database => "database",
+            Phase::Schema => "schema",
+            Phase::Privileges => "privileges",
+            Phase::Domains => "domains",
+            Phase::EscrowAccounts => "escrow_accounts",
+            Phase::SenderAccounts => "sender_accounts",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PhaseState {
+    Pending,
+    Running,
+    Done { seconds: f64 },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseReport {
+    pub phase: Phase,
+    #[serde(flatten)]
+    pub state: PhaseState,
+}
+
+/// The state of each phase, in order.
+pub fn report() -> Vec<PhaseReport> {
+    REPORT.lock().unwrap().clone()
+}
+
+fn set_state(phase: Phase, state: PhaseState) {
+    let mut report = REPORT.lock().unwrap();
+    if let Some(entry) = report.iter_mut().find(|entry| entry.phase == phase) {
+        entry.state = state;
+    }
+}
+
+/// Routes served from before the startup, the ones needing the started agent are only set once
+/// it is.
+#[derive(Clone, Default)]
+pub struct Routes(Arc<OnceLock<Router>>);
+
+impl Routes {
+    /// Serves `routes` from now on.
+    pub fn set(&self, routes: Router) {
+        if self.0.set(routes).is_err() {
+            error!("The routes of the started agent were already set");
+        }
+    }
+
+    /// Every route but `/metrics`, which is served as soon as the server is.
+    pub fn router(&self) -> Router {
+        let routes = self.clone();
+        let handler = move |request: Request| {
+            let routes = routes.clone();
+            async move { routes.call(request).await }
+        };
+        Router::new()
+            .route("/", any(handler.clone()))
+            .route("/*path", any(handler))
+    }
+
+    async fn call(&self, request: Request) -> Response {
+        match self.0.get() {
+            Some(routes) => {
+                let response: Result<Response, Infallible> = routes.clone().oneshot(request).await;
+                response.unwrap_or_else(|e| match e {})
+            }
+            None if request.uri().path() == "/status" => {
+                Json(serde_json::json!({ "startup": report() })).into_response()
+            }
+            None => (StatusCode::SERVICE_UNAVAILABLE, "Starting up").into_response(),
+        }
+    }
+}
+
+/// Runs `f` as `phase`, failing if it takes longer than `timeout`.
+pub async fn run<T>(
+    phase: Phase,
+    timeout: Duration,
+    f: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    info!(%phase, "Starting up");
+    set_state(phase, PhaseState::Running);
+    let start = Instant::now();
+    let result = match tokio::time::timeout(timeout, f).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out after {}s", timeout.as_secs_f64())),
+    };
+    let seconds = start.elapsed().as_secs_f64();
+    match &result {
+        Ok(_) => {
+            PHASE_SECONDS
+                .with_label_values(&[phase.as_str()])
+                .set(seconds);
+            set_state(phase, PhaseState::Done { seconds });
+        }
+        Err(e) => {
+            error!(%phase, "Startup failed: {:#}", e);
+            set_state(
+                phase,
+                PhaseState::Failed {
+                    error: format!("{e:#}"),
+                },
+            );
+        }
+    }
+    result.with_context(|| format!("Startup phase `{phase}` failed"))
+}
+
+/// Checks that the database answers.
+pub async fn check_database(pgpool: &PgPool) -> anyhow::Result<()> {
+    sqlx::query!("SELECT 1").execute(pgpool).await?;
+    Ok(())
+}
+
+/// Checks that the migrations tap-agent needs are applied, see [`MIGRATION_MARKERS`].
+pub async fn check_schema(pgpool: &PgPool) -> anyhow::Result<()> {
+    let columns = sqlx::query!(
+        r#"
+            SELECT table_name::TEXT AS "table_name!", column_name::TEXT AS "column_name!"
+            FROM information_schema.columns
+            WHERE table_schema = current_schema()
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let missing: Vec<&str> = MIGRATION_MARKERS
+        .iter()
+        .filter(|(_, table, column)| {
+            !columns
+                .iter()
+                .any(|row| row.table_name == *table && row.column_name == *column)
+        })
+        .map(|(migration, _, _)| *migration)
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "The migrations {} aren't applied, update indexer-agent or run them",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the EIP-712 domains of the receipts have a chain id and a verifier, and that the
+/// Horizon one isn't the legacy one, whose receipts it would accept.
+pub fn check_domains(domain: &Eip712Domain, horizon: Option<&Eip712Domain>) -> anyhow::Result<()> {
+    for domain in std::iter::once(domain).chain(horizon) {
+        if domain.chain_id.is_none() {
+            bail!("The EIP-712 domain {:?} has no chain id", domain.name);
+        }
+        match domain.verifying_contract {
+            Some(verifier) if verifier != Address::ZERO => {}
+            _ => bail!("The EIP-712 domain {:?} has no verifier", domain.name),
+        }
+    }
+    if horizon.is_some_and(|horizon| horizon.verifying_contract == domain.verifying_contract) {
+        bail!("`receipts_verifier_address_v2` must differ from `receipts_verifier_address`");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use alloy::primitives::Address;
+    use sqlx::PgPool;
+    use tap_core::tap_eip712_domain;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::{check_domains, check_schema, report, run, Phase, PhaseState, Routes};
+    use crate::tap::horizon;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_check_schema(pgpool: PgPool) {
+        check_schema(&pgpool).await.unwrap();
+
+        sqlx::query!("ALTER TABLE scalar_tap_ravs DROP COLUMN fee_token")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let error = check_schema(&pgpool).await.unwrap_err().to_string();
+        assert!(error.contains("20241209120000_tap_fee_token"), "{error}");
+    }
+
+    #[test]
+    fn test_check_domains() {
+        let legacy = tap_eip712_domain(1, Address::repeat_byte(0x11));
+        check_domains(&legacy, None).unwrap();
+        check_domains(
+            &legacy,
+            Some(&horizon::eip712_domain(1, Address::repeat_byte(0x22))),
+        )
+        .unwrap();
+
+        assert!(check_domains(&tap_eip712_domain(1, Address::ZERO), None).is_err());
+        assert!(check_domains(
+            &legacy,
+            Some(&horizon::eip712_domain(1, Address::repeat_byte(0x11)))
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_phase_timeout() {
+        run(Phase::EscrowAccounts, Duration::from_secs(1), async {
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let error = run(
+            Phase::SenderAccounts,
+            Duration::from_millis(10),
+            std::future::pending::<anyhow::Result<()>>(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("sender_accounts"));
+
+        let report = report();
+        assert!(matches!(report[4].state, PhaseState::Done { .. }));
+        assert!(matches!(
+            &report[5].state,
+            PhaseState::Failed { error } if error.contains("Timed out")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_routes_before_startup() {
+        let routes = Routes::default();
+        let router = routes.router();
+        let fetch = |path: &str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let response = fetch("/status").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["startup"][0]["phase"], "database");
+        assert_eq!(
+            fetch("/healthz").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        routes.set(Router::new().route("/healthz", get(|| async { "ok" })));
+        assert_eq!(fetch("/healthz").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            fetch("/status").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
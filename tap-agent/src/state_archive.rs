@@ -0,0 +1,580 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Export and import of the state of tap-agent, to move an indexer to another database.
+//!
+//! The archive holds the rows of the TAP and DIPS tables, read from a single `REPEATABLE READ`
+//! snapshot so that receipts and RAVs are consistent with each other, and the pending intents of
+//! the denylist outbox. The lease tables are left out, they're renewed by the running tap-agents.
+//!
+//! It's a JSON lines file: an [`ArchiveHeader`], then for each table a [`TableHeader`], its rows,
+//! one per line, and a [`TableTrailer`] with the keccak256 of the row lines. The header holds a
+//! hash of the indexer address, chain id and TAP verifiers the state was exported with, and is
+//! only imported by a tap-agent configured with the same ones. The import runs in a single
+//! transaction, into empty tables, in batches of [`IMPORT_BATCH_SIZE`] rows, and moves the id
+//! sequences past the imported ids. A checksum mismatch rolls it back.
+//!
+//! The archives of busy indexers run to hundreds of gigabytes, they're never held in memory: the
+//! rows are streamed between the database and a blocking task doing the file IO. They can be
+//! compressed with zstd, recognized on import by their magic number.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::primitives::{keccak256, Keccak256, B256};
+use anyhow::{anyhow, bail, ensure, Context};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+/// Bumped when the tables or their columns change: 2 added their `fee_token`, 3 the `relayed_by`
+/// of the receipts, 4 the DIPS tables, 5 the paused senders, rate limits and RAV request intents,
+/// 6 the JSON lines format.
+pub const ARCHIVE_VERSION: u32 = 6;
+
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Lines buffered between the database and the file.
+const LINE_BUFFER: usize = 1000;
+
+/// First bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Tables of the archive, in the order they're imported. The queries are the same for all of
+/// them, so they're built at runtime with the table name.
+const TABLES: &[&str] = &[
+    "scalar_tap_receipts",
+    "scalar_tap_receipts_invalid",
+    "scalar_tap_receipts_quarantined",
+    "scalar_tap_receipts_archive",
+    "scalar_tap_ravs",
+    "scalar_tap_rav_requests_failed",
+    "scalar_tap_rav_request_intents",
+    "scalar_tap_denylist",
+    "tap_paused_senders",
+    "tap_sender_rate_limits",
+    "tap_horizon_receipts",
+    "tap_horizon_receipts_invalid",
+    "tap_horizon_receipts_quarantined",
+    "tap_horizon_ravs",
+    "tap_horizon_rav_requests_failed",
+    "dips_agreements",
+    "dips_fee_vouchers",
+    "dips_fee_vouchers_invalid",
+    "dips_ravs",
+    "dips_rav_requests_failed",
+];
+
+/// Tables whose `id` comes from a sequence.
+const SERIAL_TABLES: &[&str] = &[
+    "scalar_tap_receipts",
+    "scalar_tap_receipts_invalid",
+    "scalar_tap_receipts_archive",
+    "scalar_tap_rav_requests_failed",
+    "scalar_tap_rav_request_intents",
+    "tap_horizon_receipts",
+    "tap_horizon_receipts_invalid",
+    "tap_horizon_rav_requests_failed",
+    "dips_fee_vouchers",
+    "dips_fee_vouchers_invalid",
+    "dips_rav_requests_failed",
+];
+
+/// First line of the archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    pub version: u32,
+    /// Unix timestamp of the export, in seconds.
+    pub exported_at: u64,
+    /// See [`config_hash`].
+    pub config_hash: B256,
+    /// Content of `tap.denylist_outbox_path`, if it had pending intents.
+    pub denylist_outbox: Option<serde_json::Value>,
+}
+
+/// Line before the rows of a table.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableHeader {
+    pub name: String,
+    pub rows: u64,
+}
+
+/// Line after the rows of a table.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableTrailer {
+    /// keccak256 of the row lines, with their line feeds.
+    pub checksum: B256,
+}
+
+/// What was exported or imported.
+#[derive(Debug)]
+pub struct StateArchive {
+    pub header: ArchiveHeader,
+    pub tables: Vec<TableDump>,
+}
+
+#[derive(Debug)]
+pub struct TableDump {
+    pub name: String,
+    pub rows: u64,
+    pub checksum: B256,
+}
+
+/// Hash of the settings the TAP state belongs to: the indexer address, the chain id and the TAP
+/// verifiers.
+pub fn config_hash(config: &Config) -> B256 {
+    keccak256(format!(
+        "{}:{}:{}:{:?}",
+        config.ethereum.indexer_address,
+        config.receipts.receipts_verifier_chain_id,
+        config.receipts.receipts_verifier_address,
+        config.receipts.receipts_verifier_address_v2,
+    ))
+}
+
+/// Writes the state to `output`, compressed with zstd at `compression_level` if set.
+pub async fn export_state(
+    pgpool: &PgPool,
+    config: &Config,
+    output: &Path,
+    compression_level: Option<i32>,
+) -> anyhow::Result<StateArchive> {
+    let denylist_outbox = match &config.tap.denylist_outbox_path {
+        Some(path) if tokio::fs::try_exists(path).await? => {
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Some(serde_json::from_str(&content)?)
+        }
+        _ => None,
+    };
+    let header = ArchiveHeader {
+        version: ARCHIVE_VERSION,
+        exported_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        config_hash: config_hash(config),
+        denylist_outbox,
+    };
+
+    // Written next to the output first, so that an interrupted export leaves no partial archive
+    let tmp = output.with_extension("tmp");
+    let (lines, receiver) = mpsc::channel(LINE_BUFFER);
+    let writer = tokio::task::spawn_blocking({
+        let tmp = tmp.clone();
+        move || write_archive(receiver, &tmp, compression_level)
+    });
+    let exported = export_tables(pgpool, &header, &lines).await;
+    drop(lines);
+    // The error of the writer explains why the lines couldn't be sent
+    let written = writer
+        .await?
+        .with_context(|| format!("Failed to write {}", tmp.display()));
+    let tables = match written.and(exported) {
+        Ok(tables) => tables,
+        Err(error) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(error);
+        }
+    };
+    tokio::fs::rename(&tmp, output)
+        .await
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    Ok(StateArchive { header, tables })
+}
+
+async fn export_tables(
+    pgpool: &PgPool,
+    header: &ArchiveHeader,
+    lines: &mpsc::Sender<Vec<u8>>,
+) -> anyhow::Result<Vec<TableDump>> {
+    send_line(lines, json_line(header)?).await?;
+
+    let mut tx = pgpool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    let mut tables = Vec::with_capacity(TABLES.len());
+    for table in TABLES {
+        // Same snapshot as the rows
+        let rows: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to export {table}"))?;
+        let rows = rows as u64;
+        send_line(
+            lines,
+            json_line(&TableHeader {
+                name: table.to_string(),
+                rows,
+            })?,
+        )
+        .await?;
+
+        // JSONB is printed on a single line
+        let query = format!("SELECT to_jsonb(t)::TEXT FROM (SELECT * FROM {table} ORDER BY 1) t");
+        let mut stream = sqlx::query_scalar::<_, String>(&query).fetch(&mut *tx);
+        let mut hasher = Keccak256::new();
+        let mut exported = 0u64;
+        while let Some(row) = stream
+            .try_next()
+            .await
+            .with_context(|| format!("Failed to export {table}"))?
+        {
+            let mut line = row.into_bytes();
+            line.push(b'\n');
+            hasher.update(&line);
+            send_line(lines, line).await?;
+            exported += 1;
+        }
+        ensure!(
+            exported == rows,
+            "Exported {exported} rows of {table}, expected {rows}"
+        );
+        let checksum = hasher.finalize();
+        send_line(lines, json_line(&TableTrailer { checksum })?).await?;
+        info!(table, rows, "Exported table");
+        tables.push(TableDump {
+            name: table.to_string(),
+            rows,
+            checksum,
+        });
+    }
+    tx.commit().await?;
+    Ok(tables)
+}
+
+fn json_line<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+async fn send_line(lines: &mpsc::Sender<Vec<u8>>, line: Vec<u8>) -> anyhow::Result<()> {
+    lines
+        .send(line)
+        .await
+        .map_err(|_| anyhow!("The archive writer stopped"))
+}
+
+/// Blocking, writes the lines until the sender is dropped.
+fn write_archive(
+    mut lines: mpsc::Receiver<Vec<u8>>,
+    path: &Path,
+    compression_level: Option<i32>,
+) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    match compression_level {
+        Some(level) => {
+            let mut encoder = zstd::Encoder::new(file, level)?;
+            while let Some(line) = lines.blocking_recv() {
+                encoder.write_all(&line)?;
+            }
+            encoder.finish()?.flush()?;
+        }
+        None => {
+            while let Some(line) = lines.blocking_recv() {
+                file.write_all(&line)?;
+            }
+            file.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Blocking, sends the lines of the archive until the receiver is dropped.
+fn read_archive(path: &Path, lines: mpsc::Sender<Vec<u8>>) -> anyhow::Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        send_lines(BufReader::new(zstd::Decoder::with_buffer(file)?), lines)
+    } else {
+        send_lines(file, lines)
+    }
+}
+
+fn send_lines(mut reader: impl BufRead, lines: mpsc::Sender<Vec<u8>>) -> anyhow::Result<()> {
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 || lines.blocking_send(line).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Checks the version and settings of an archive.
+pub fn verify_header(header: &ArchiveHeader, config: &Config) -> anyhow::Result<()> {
+    ensure!(
+        header.version == ARCHIVE_VERSION,
+        "Unsupported archive version {}, expected {ARCHIVE_VERSION}",
+        header.version
+    );
+    ensure!(
+        header.config_hash == config_hash(config),
+        "The archive was exported for another indexer address, chain id or TAP verifier"
+    );
+    Ok(())
+}
+
+pub async fn import_state(
+    pgpool: &PgPool,
+    config: &Config,
+    input: &Path,
+) -> anyhow::Result<StateArchive> {
+    let (sender, mut lines) = mpsc::channel(LINE_BUFFER);
+    let reader = tokio::task::spawn_blocking({
+        let input = input.to_owned();
+        move || read_archive(&input, sender)
+    });
+    let imported = import_tables(pgpool, config, &mut lines).await;
+    drop(lines);
+    // The error of the reader explains a missing line
+    reader
+        .await?
+        .with_context(|| format!("Failed to read {}", input.display()))?;
+    let archive = imported?;
+
+    if let Some(outbox) = &archive.header.denylist_outbox {
+        match &config.tap.denylist_outbox_path {
+            Some(path) => tokio::fs::write(path, serde_json::to_vec(outbox)?)
+                .await
+                .with_context(|| format!("Failed to write {}", path.display()))?,
+            None => warn!(
+                "The archive has pending denylist writes, but `tap.denylist_outbox_path` is not \
+                set. They are not imported."
+            ),
+        }
+    }
+    Ok(archive)
+}
+
+async fn import_tables(
+    pgpool: &PgPool,
+    config: &Config,
+    lines: &mut mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<StateArchive> {
+    let header: ArchiveHeader =
+        serde_json::from_slice(&next_line(lines).await?).context("Invalid archive header")?;
+    verify_header(&header, config)?;
+
+    let mut tx = pgpool.begin().await?;
+    for table in TABLES {
+        let existing: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(&mut *tx)
+            .await?;
+        if existing > 0 {
+            bail!(
+                "{table} already has {existing} rows, the state is only imported into empty tables"
+            );
+        }
+    }
+    let mut tables = Vec::new();
+    while let Some(line) = lines.recv().await {
+        let TableHeader { name, rows } =
+            serde_json::from_slice(&line).context("Invalid table header")?;
+        ensure!(
+            TABLES.contains(&name.as_str()),
+            "Unexpected table {name} in the archive"
+        );
+        let mut hasher = Keccak256::new();
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut inserted = 0;
+        for _ in 0..rows {
+            let line = next_line(lines).await?;
+            hasher.update(&line);
+            batch.push(line);
+            if batch.len() == IMPORT_BATCH_SIZE {
+                inserted += insert_rows(&mut tx, &name, &batch).await?;
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            inserted += insert_rows(&mut tx, &name, &batch).await?;
+        }
+        let TableTrailer { checksum } =
+            serde_json::from_slice(&next_line(lines).await?).context("Invalid table trailer")?;
+        ensure!(
+            hasher.finalize() == checksum,
+            "Checksum mismatch for table {name}, the archive is corrupted"
+        );
+        ensure!(
+            inserted == rows,
+            "Imported {inserted} rows into {name}, the archive has {rows}"
+        );
+        if SERIAL_TABLES.contains(&name.as_str()) {
+            sqlx::query(&format!(
+                r#"
+                    SELECT setval(
+                        pg_get_serial_sequence('{name}', 'id'),
+                        COALESCE((SELECT MAX(id) FROM {name}), 0) + 1,
+                        false
+                    )
+                "#
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        info!(table = %name, rows = inserted, "Imported table");
+        tables.push(TableDump {
+            name,
+            rows,
+            checksum,
+        });
+    }
+    tx.commit().await?;
+    Ok(StateArchive { header, tables })
+}
+
+async fn next_line(lines: &mut mpsc::Receiver<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    lines
+        .recv()
+        .await
+        .ok_or_else(|| anyhow!("The archive is truncated"))
+}
+
+async fn insert_rows(
+    tx: &mut Transaction<'_, Postgres>,
+    table: &str,
+    rows: &[Vec<u8>],
+) -> anyhow::Result<u64> {
+    let mut json = String::from("[");
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(std::str::from_utf8(row)?.trim_end());
+    }
+    json.push(']');
+    Ok(sqlx::query(&format!(
+        "INSERT INTO {table} SELECT * FROM json_populate_recordset(NULL::{table}, $1::JSON)"
+    ))
+    .bind(json)
+    .execute(&mut **tx)
+    .await
+    .with_context(|| format!("Failed to import {table}"))?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::hex::ToHexExt;
+    use sqlx::PgPool;
+
+    use super::{export_state, import_state, verify_header, ArchiveHeader};
+    use crate::{
+        config::Config,
+        tap::test_utils::{
+            create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0, SENDER,
+            SIGNER,
+        },
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_export_import_state(pgpool: PgPool) {
+        let config = Config::default();
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 55);
+        store_rav(&pgpool, rav, SENDER.1).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO tap_paused_senders (sender_address, reason) VALUES ($1, 'test')",
+            SENDER.1.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let archive = export_state(&pgpool, &config, &path, None).await.unwrap();
+        assert_eq!(archive.tables[0].rows, 10);
+
+        // Only into empty tables
+        assert!(import_state(&pgpool, &config, &path).await.is_err());
+
+        sqlx::query!("TRUNCATE scalar_tap_receipts, scalar_tap_ravs, tap_paused_senders")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        // Tampered, two receipts swapped
+        let content = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<&str> = content.lines().collect();
+        lines.swap(2, 3);
+        let tampered = dir.path().join("tampered.json");
+        std::fs::write(&tampered, lines.join("\n") + "\n").unwrap();
+        let error = import_state(&pgpool, &config, &tampered).await.unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch"));
+
+        import_state(&pgpool, &config, &path).await.unwrap();
+        let receipts =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(receipts, 10);
+        let value =
+            sqlx::query_scalar!(r#"SELECT value_aggregate::TEXT AS "value!" FROM scalar_tap_ravs"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(value, "55");
+        let reason = sqlx::query_scalar!("SELECT reason FROM tap_paused_senders")
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(reason.as_deref(), Some("test"));
+        // New receipts get ids after the imported ones
+        let next_id =
+            sqlx::query_scalar!(r#"SELECT nextval('scalar_tap_receipts_id_seq') AS "id!""#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(next_id, 11);
+
+        // Exported with other settings
+        let header: ArchiveHeader = serde_json::from_str(lines[0]).unwrap();
+        assert!(verify_header(&header, &config).is_ok());
+        let mut other = Config::default();
+        other.receipts.receipts_verifier_chain_id = 1;
+        assert!(verify_header(&header, &other).is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_compressed_archive(pgpool: PgPool) {
+        let config = Config::default();
+        for i in 1..=100 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("state.json");
+        let compressed = dir.path().join("state.json.zst");
+        let archive = export_state(&pgpool, &config, &plain, None).await.unwrap();
+        export_state(&pgpool, &config, &compressed, Some(3))
+            .await
+            .unwrap();
+        let content = std::fs::read(&compressed).unwrap();
+        assert!(content.starts_with(&super::ZSTD_MAGIC));
+        assert!(content.len() < std::fs::metadata(&plain).unwrap().len() as usize);
+
+        sqlx::query!("TRUNCATE scalar_tap_receipts")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let imported = import_state(&pgpool, &config, &compressed).await.unwrap();
+        assert_eq!(imported.tables[0].rows, 100);
+        assert_eq!(imported.tables[0].checksum, archive.tables[0].checksum);
+    }
+}
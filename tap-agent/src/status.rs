@@ -0,0 +1,649 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only view of the state of the agent, as JSON.
+//!
+//! `GET /status` returns the RAV request and deny thresholds, the fees tracked by the
+//! `SenderAccount` of each sender per allocation, their suggested escrow top-up, their latest
+//! deny and allow events, the most recent RAVs stored, the projected storage of the receipts and
+//! the optional features disabled by the privileges of the database role, the state of the
+//! phases of the startup, and the signers of receipts authorized by no sender.
+//! `GET /signers/:signer` returns the sender authorizing a signer, if any, and its receipts while
+//! it was unknown.
+//! `GET /metrics/summary` returns the totals over the senders a dashboard needs, without summing
+//! up the series of the metrics: the denied senders, the unaggregated fees, the pending RAVs and
+//! the age of the oldest unaggregated receipt.
+//!
+//! The fee values are decimal strings in `tap.fee_display.api_unit`, GRT wei by default, given in
+//! `fee_unit`, see [`crate::money`]. `GET /status` and `GET /metrics/summary` also have the price
+//! of GRT in USD in `usd_per_grt`, once fetched.
+//!
+//! Served next to the metrics, which should stay private. Signed by the operator with
+//! `tap.signed_status`, see [`crate::signed_status`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use bigdecimal::ToPrimitive;
+use eventuals::Eventual;
+use futures_util::future::join_all;
+use indexer_common::{
+    escrow_accounts::{unknown_signers, EscrowAccounts, UnknownSigner},
+    fees::{FeeAmount, FeeToken},
+};
+use ractor::{call_t, ActorRef};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    agent::{
+        capacity_planning::{self, CapacityReport},
+        config_reload,
+        denylist_outbox::DenyReason,
+        sender_account::SenderAccountMessage,
+        sender_accounts_manager::SenderAccountsManagerMessage,
+    },
+    database::{self, Subsystem},
+    money::{self, wei, ApiUnit},
+    privileges::DatabaseFeatures,
+    signed_status::{SignedKind, StatusSigner},
+    startup::{self, PhaseReport},
+};
+
+/// Maximum time waited for any single actor to respond.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+const RECENT_RAVS: i64 = 20;
+
+/// Senders known by the `SenderAccountsManager`.
+#[derive(Debug, Clone, Default)]
+pub struct ManagerSenders {
+    pub sender_ids: Vec<Address>,
+    /// Stopped through the admin API, they have no `SenderAccount`.
+    pub stopped_sender_ids: HashSet<Address>,
+    /// Latest thresholds, which may have been reloaded since the start.
+    pub thresholds: config_reload::Thresholds,
+}
+
+/// Deny or allow of a sender by its `SenderAccount`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DenyEvent {
+    /// Milliseconds since the UNIX epoch.
+    pub at_ms: u64,
+    pub denied: bool,
+    /// Set when denied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<DenyReason>,
+}
+
+/// Reported by a `SenderAccount`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SenderAccountStatus {
+    pub denied: bool,
+    /// See [`crate::agent::sender_pause`].
+    pub paused: bool,
+    #[serde(serialize_with = "wei")]
+    pub escrow_balance: u128,
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_fees: u128,
+    #[serde(serialize_with = "wei")]
+    pub pending_ravs: u128,
+    #[serde(serialize_with = "wei")]
+    pub invalid_receipt_fees: u128,
+    /// Fees of the new receipts, averaged over `tap.escrow_top_up.velocity_window_secs`.
+    #[serde(serialize_with = "wei")]
+    pub fees_per_hour: u128,
+    /// See [`crate::agent::escrow_top_up`].
+    #[serde(serialize_with = "wei")]
+    pub escrow_top_up: u128,
+    pub allocations: Vec<AllocationStatus>,
+    /// Oldest first, only the latest ones are kept.
+    pub deny_events: Vec<DenyEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocationStatus {
+    pub allocation_id: Address,
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_fees: u128,
+    #[serde(serialize_with = "wei")]
+    pub pending_rav: u128,
+    #[serde(serialize_with = "wei")]
+    pub invalid_receipt_fees: u128,
+    /// See [`crate::agent::quarantine`].
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Thresholds {
+    #[serde(serialize_with = "wei")]
+    rav_request_trigger_value: u128,
+    #[serde(serialize_with = "wei")]
+    max_unaggregated_fees_per_sender: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct SenderStatus {
+    sender: Address,
+    stopped: bool,
+    /// Age of the oldest stored receipt not covered by a RAV, from its timestamp.
+    unaggregated_for_secs: Option<u64>,
+    #[serde(flatten)]
+    account: Option<SenderAccountStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RavStatus {
+    sender: Address,
+    allocation_id: Address,
+    timestamp_ns: u64,
+    #[serde(serialize_with = "wei")]
+    value_aggregate: u128,
+    fee_token: FeeToken,
+    last: bool,
+    #[serde(rename = "final")]
+    is_final: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    /// Token of the fees tracked by the senders and of the thresholds.
+    fee_token: FeeToken,
+    fee_unit: ApiUnit,
+    /// See [`crate::money`].
+    usd_per_grt: Option<f64>,
+    thresholds: Thresholds,
+    senders: Vec<SenderStatus>,
+    recent_ravs: Vec<RavStatus>,
+    /// Latest storage projection, see [`crate::agent::capacity_planning`].
+    capacity: Option<CapacityReport>,
+    /// See [`crate::privileges`].
+    database: DatabaseFeatures,
+    /// See [`crate::startup`].
+    startup: Vec<PhaseReport>,
+    /// Most recently seen first, see [`indexer_common::escrow_accounts::UnknownSigner`].
+    unknown_signers: Vec<UnknownSigner>,
+}
+
+#[derive(Debug, Serialize)]
+struct SignerResponse {
+    signer: Address,
+    /// Authorizing the signer, as of the latest escrow accounts.
+    sender: Option<Address>,
+    /// Set while the signer is unknown, or until its receipts are replayed.
+    unknown: Option<UnknownSigner>,
+}
+
+/// Totals over the senders, see [`Summary::add`].
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    /// Token of the fees.
+    fee_token: FeeToken,
+    fee_unit: ApiUnit,
+    /// See [`crate::money`].
+    usd_per_grt: Option<f64>,
+    senders: usize,
+    denied_senders: usize,
+    /// Stopped through the admin API, they're denied as well.
+    stopped_senders: usize,
+    /// Whose `SenderAccount` didn't respond, they aren't counted in the totals.
+    unavailable_senders: usize,
+    #[serde(serialize_with = "wei")]
+    unaggregated_fees: u128,
+    #[serde(serialize_with = "wei")]
+    pending_ravs: u128,
+    /// Age of the oldest stored receipt not covered by a RAV over the senders.
+    oldest_unaggregated_secs: Option<u64>,
+}
+
+impl Summary {
+    fn add(&mut self, status: &SenderStatus) {
+        self.senders += 1;
+        self.oldest_unaggregated_secs = self
+            .oldest_unaggregated_secs
+            .max(status.unaggregated_for_secs);
+        if status.stopped {
+            self.stopped_senders += 1;
+            self.denied_senders += 1;
+            return;
+        }
+        let Some(account) = &status.account else {
+            self.unavailable_senders += 1;
+            return;
+        };
+        if account.denied {
+            self.denied_senders += 1;
+        }
+        self.unaggregated_fees = self
+            .unaggregated_fees
+            .saturating_add(account.unaggregated_fees);
+        self.pending_ravs = self.pending_ravs.saturating_add(account.pending_ravs);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Clone)]
+pub struct StatusState {
+    manager: ActorRef<SenderAccountsManagerMessage>,
+    pub(crate) pgpool: PgPool,
+    pub(crate) escrow_accounts: Eventual<EscrowAccounts>,
+    database_features: DatabaseFeatures,
+    /// Shared with [`crate::sender_api`].
+    pub(crate) signer: Option<StatusSigner>,
+}
+
+impl StatusState {
+    pub fn new(
+        manager: ActorRef<SenderAccountsManagerMessage>,
+        pgpool: PgPool,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        database_features: DatabaseFeatures,
+        signer: Option<StatusSigner>,
+    ) -> Self {
+        Self {
+            manager,
+            pgpool,
+            escrow_accounts,
+            database_features,
+            signer,
+        }
+    }
+}
+
+/// Statuses of the senders of `senders`, in their order.
+async fn sender_statuses(
+    state: &StatusState,
+    senders: &ManagerSenders,
+) -> anyhow::Result<Vec<SenderStatus>> {
+    let oldest_unaggregated = oldest_unaggregated(
+        &state.pgpool,
+        &state.escrow_accounts.value_immediate().unwrap_or_default(),
+        &senders.sender_ids,
+    )
+    .await?;
+    let now_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut statuses = join_all(
+        senders
+            .sender_ids
+            .iter()
+            .map(|sender| sender_status(*sender, senders.stopped_sender_ids.contains(sender))),
+    )
+    .await;
+    for status in &mut statuses {
+        status.unaggregated_for_secs = oldest_unaggregated
+            .get(&status.sender)
+            .map(|timestamp_ns| now_ns.saturating_sub(*timestamp_ns) / 1_000_000_000);
+    }
+    Ok(statuses)
+}
+
+/// Timestamp of the oldest receipt not covered by a RAV of each of `senders` that has one, of
+/// both formats.
+async fn oldest_unaggregated(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    senders: &[Address],
+) -> anyhow::Result<HashMap<Address, u64>> {
+    let (signers, signer_senders): (Vec<String>, Vec<String>) = senders
+        .iter()
+        .flat_map(|sender| {
+            escrow_accounts
+                .get_signers_for_sender(sender)
+                .into_iter()
+                .map(|signer| (signer.encode_hex(), sender.encode_hex()))
+        })
+        .unzip();
+    let senders: Vec<String> = senders.iter().map(|sender| sender.encode_hex()).collect();
+    let rows = database::acquire(pgpool, Subsystem::Analytics)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    WITH signers AS (
+                        SELECT * FROM UNNEST($1::CHAR(40)[], $2::CHAR(40)[])
+                            AS signers(signer_address, sender_address)
+                    ),
+                    unaggregated AS (
+                        SELECT signers.sender_address, receipts.timestamp_ns
+                        FROM scalar_tap_receipts receipts
+                        JOIN signers USING (signer_address)
+                        LEFT JOIN scalar_tap_ravs ravs
+                            ON ravs.allocation_id = receipts.allocation_id
+                            AND ravs.sender_address = signers.sender_address
+                        WHERE ravs.timestamp_ns IS NULL
+                            OR receipts.timestamp_ns > ravs.timestamp_ns
+                        UNION ALL
+                        SELECT receipts.payer, receipts.timestamp_ns
+                        FROM tap_horizon_receipts receipts
+                        LEFT JOIN tap_horizon_ravs ravs
+                            ON ravs.collection_id = receipts.collection_id
+                            AND ravs.payer = receipts.payer
+                            AND ravs.data_service = receipts.data_service
+                            AND ravs.service_provider = receipts.service_provider
+                        WHERE receipts.payer = ANY($3)
+                            AND (
+                                ravs.timestamp_ns IS NULL
+                                OR receipts.timestamp_ns > ravs.timestamp_ns
+                            )
+                    )
+                    SELECT sender_address AS "sender_address!", MIN(timestamp_ns) AS "timestamp_ns!"
+                    FROM unaggregated
+                    GROUP BY sender_address
+                "#,
+                &signers,
+                &signer_senders,
+                &senders
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok((
+                Address::from_str(&row.sender_address)?,
+                row.timestamp_ns
+                    .to_u64()
+                    .ok_or_else(|| anyhow!("Invalid receipt timestamp {}", row.timestamp_ns))?,
+            ))
+        })
+        .collect()
+}
+
+async fn sender_status(sender: Address, stopped: bool) -> SenderStatus {
+    let result = if stopped {
+        Ok(None)
+    } else {
+        match ActorRef::<SenderAccountMessage>::where_is(sender.to_string()) {
+            Some(sender_account) => call_t!(
+                sender_account,
+                SenderAccountMessage::GetStatus,
+                STATUS_TIMEOUT.as_millis() as u64
+            )
+            .map(Some)
+            .map_err(|e| format!("SenderAccount did not respond: {e}")),
+            None => Err("SenderAccount is not running".to_string()),
+        }
+    };
+    let (account, error) = match result {
+        Ok(account) => (account, None),
+        Err(error) => (None, Some(error)),
+    };
+    SenderStatus {
+        sender,
+        stopped,
+        unaggregated_for_secs: None,
+        account,
+        error,
+    }
+}
+
+async fn recent_ravs(pgpool: &PgPool) -> anyhow::Result<Vec<RavStatus>> {
+    let rows = database::acquire(pgpool, Subsystem::Analytics)
+        .await?
+        .run(|conn| {
+            sqlx::query!(
+                r#"
+                    SELECT
+                        sender_address, allocation_id, timestamp_ns, value_aggregate, fee_token,
+                        last, final AS is_final
+                    FROM scalar_tap_ravs
+                    ORDER BY timestamp_ns DESC
+                    LIMIT $1
+                "#,
+                RECENT_RAVS
+            )
+            .fetch_all(conn)
+        })
+        .await?;
+    rows.into_iter()
+        .map(|row| {
+            let value_aggregate = FeeAmount::from_decimal(&row.value_aggregate, &row.fee_token)?;
+            Ok(RavStatus {
+                sender: Address::from_str(&row.sender_address)?,
+                allocation_id: Address::from_str(&row.allocation_id)?,
+                timestamp_ns: row
+                    .timestamp_ns
+                    .to_u64()
+                    .ok_or_else(|| anyhow!("Invalid RAV timestamp {}", row.timestamp_ns))?,
+                value_aggregate: value_aggregate.value,
+                fee_token: value_aggregate.token,
+                last: row.last,
+                is_final: row.is_final,
+            })
+        })
+        .collect()
+}
+
+async fn handler_status(State(state): State<StatusState>) -> impl IntoResponse {
+    let senders = match call_t!(
+        state.manager,
+        SenderAccountsManagerMessage::GetSenders,
+        STATUS_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(senders) => senders,
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse {
+                    error: format!("SenderAccountsManager did not respond: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    };
+    let recent_ravs = match recent_ravs(&state.pgpool).await {
+        Ok(recent_ravs) => recent_ravs,
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse {
+                    error: format!("Failed to read the recent RAVs: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    };
+    let statuses = match sender_statuses(&state, &senders).await {
+        Ok(statuses) => statuses,
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse {
+                    error: format!("Failed to read the unaggregated receipts: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    };
+
+    let response = StatusResponse {
+        fee_token: FeeToken::Grt,
+        fee_unit: ApiUnit,
+        usd_per_grt: money::usd_price(),
+        thresholds: Thresholds {
+            rav_request_trigger_value: senders.thresholds.rav_request_trigger_value,
+            max_unaggregated_fees_per_sender: senders.thresholds.max_unnaggregated_fees_per_sender,
+        },
+        senders: statuses,
+        recent_ravs,
+        capacity: capacity_planning::latest(),
+        database: state.database_features,
+        startup: startup::report(),
+        unknown_signers: unknown_signers(),
+    };
+    match &state.signer {
+        Some(signer) => signer.signed_json(SignedKind::Status, &response),
+        None => Json(response).into_response(),
+    }
+}
+
+async fn handler_summary(State(state): State<StatusState>) -> impl IntoResponse {
+    let senders = match call_t!(
+        state.manager,
+        SenderAccountsManagerMessage::GetSenders,
+        STATUS_TIMEOUT.as_millis() as u64
+    ) {
+        Ok(senders) => senders,
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse {
+                    error: format!("SenderAccountsManager did not respond: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    };
+    let mut summary = Summary {
+        usd_per_grt: money::usd_price(),
+        ..Default::default()
+    };
+    match sender_statuses(&state, &senders).await {
+        Ok(statuses) => statuses.iter().for_each(|status| summary.add(status)),
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse {
+                    error: format!("Failed to read the unaggregated receipts: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    }
+    match &state.signer {
+        Some(signer) => signer.signed_json(SignedKind::MetricsSummary, &summary),
+        None => Json(summary).into_response(),
+    }
+}
+
+async fn handler_signer(
+    State(state): State<StatusState>,
+    Path(signer): Path<Address>,
+) -> impl IntoResponse {
+    let Some(escrow_accounts) = state.escrow_accounts.value_immediate() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse {
+                error: "The escrow accounts are not available yet".to_string(),
+            }),
+        )
+            .into_response();
+    };
+    let response = SignerResponse {
+        signer,
+        sender: escrow_accounts.get_sender_for_signer(&signer).ok(),
+        unknown: unknown_signers()
+            .into_iter()
+            .find(|unknown| unknown.signer == signer),
+    };
+    match &state.signer {
+        Some(status_signer) => status_signer.signed_json(SignedKind::Signer, &response),
+        None => Json(response).into_response(),
+    }
+}
+
+pub fn router(state: StatusState) -> Router {
+    Router::new()
+        .route("/status", get(handler_status))
+        .route("/signers/:signer", get(handler_signer))
+        .route("/metrics/summary", get(handler_summary))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy::primitives::{Address, U256};
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::PgPool;
+
+    use super::{oldest_unaggregated, SenderStatus, Summary};
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    #[test]
+    fn test_summary() {
+        let mut summary = Summary::default();
+        for (stopped, unaggregated_for_secs) in [(true, Some(60)), (false, Some(30)), (false, None)]
+        {
+            summary.add(&SenderStatus {
+                sender: Address::ZERO,
+                stopped,
+                unaggregated_for_secs,
+                account: None,
+                error: None,
+            });
+        }
+        assert_eq!(summary.senders, 3);
+        // The stopped senders are denied as well
+        assert_eq!(summary.stopped_senders, 1);
+        assert_eq!(summary.denied_senders, 1);
+        assert_eq!(summary.unavailable_senders, 2);
+        assert_eq!(summary.oldest_unaggregated_secs, Some(60));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_oldest_unaggregated(pgpool: PgPool) {
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        assert!(oldest_unaggregated(&pgpool, &escrow_accounts, &[SENDER.1])
+            .await
+            .unwrap()
+            .is_empty());
+
+        for (nonce, allocation_id, timestamp_ns) in [
+            (1, *ALLOCATION_ID_0, 10),
+            (2, *ALLOCATION_ID_0, 20),
+            (3, *ALLOCATION_ID_0, 30),
+            (4, *ALLOCATION_ID_1, 25),
+        ] {
+            let receipt =
+                create_received_receipt(&allocation_id, &SIGNER.0, nonce, timestamp_ns, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Covers the first two receipts of the allocation
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 20, 20);
+        store_rav(&pgpool, rav, SENDER.1).await.unwrap();
+
+        assert_eq!(
+            oldest_unaggregated(&pgpool, &escrow_accounts, &[SENDER.1])
+                .await
+                .unwrap(),
+            HashMap::from([(SENDER.1, 25)])
+        );
+        // The signers of other senders aren't counted
+        assert!(
+            oldest_unaggregated(&pgpool, &EscrowAccounts::default(), &[SENDER.1])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes of the denylist, and polling of the new receipts, on the Postgres tables shared with
+//! indexer-service and indexer-agent. The sender actors query Postgres directly.
+
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use alloy::{
+    hex::ToHexExt,
+    primitives::{Address, FixedBytes},
+};
+use anyhow::{anyhow, Context, Result};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    agent::{
+        denylist_outbox::Denial,
+        sender_accounts_manager::NewReceiptNotification,
+        sender_allocation::horizon::{to_u128, to_u64},
+    },
+    database::{self, Subsystem},
+    logging::CorrelationId,
+    tap::{horizon, TapVersion},
+};
+
+/// Maximum number of receipts fetched by each poll of [`poll_new_receipts`].
+const POLL_BATCH_SIZE: u32 = 1000;
+/// Time after which [`poll_new_receipts`] gives up on a missing id, see [`ReceiptCursor`].
+const GAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tables of `migrations`, in the database shared with indexer-service and indexer-agent.
+#[derive(Clone)]
+pub struct PgStorage {
+    pgpool: PgPool,
+}
+
+impl PgStorage {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self { pgpool }
+    }
+
+    /// The notifications Postgres would send for the receipts with an id greater than
+    /// `after_id`, by increasing id.
+    pub async fn receipts_after(
+        &self,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<Vec<NewReceiptNotification>> {
+        let rows = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        SELECT id, allocation_id, signer_address, timestamp_ns, value
+                        FROM scalar_tap_receipts
+                        WHERE id > $1
+                        ORDER BY id
+                        LIMIT $2
+                    "#,
+                    i64::try_from(after_id).unwrap_or(i64::MAX),
+                    i64::from(limit),
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(NewReceiptNotification {
+                    id: row.id.try_into()?,
+                    allocation_id: decode_address(&row.allocation_id)?,
+                    signer_address: decode_address(&row.signer_address)?,
+                    timestamp_ns: to_u64(&row.timestamp_ns)?,
+                    value: to_u128(&row.value)?,
+                    correlation_id: CorrelationId::new(),
+                    version: TapVersion::V1,
+                    stages: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Same as [`PgStorage::receipts_after`] for the Horizon receipts.
+    pub async fn horizon_receipts_after(
+        &self,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<Vec<NewReceiptNotification>> {
+        let rows = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        SELECT id, collection_id, signer_address, timestamp_ns, value
+                        FROM tap_horizon_receipts
+                        WHERE id > $1
+                        ORDER BY id
+                        LIMIT $2
+                    "#,
+                    i64::try_from(after_id).unwrap_or(i64::MAX),
+                    i64::from(limit),
+                )
+                .fetch_all(conn)
+            })
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let collection_id: FixedBytes<32> = row.collection_id.parse().map_err(|e| {
+                    anyhow!("Error decoding collection id {}: {e}", row.collection_id)
+                })?;
+                Ok(NewReceiptNotification {
+                    id: row.id.try_into()?,
+                    allocation_id: horizon::allocation_id(collection_id),
+                    signer_address: decode_address(&row.signer_address)?,
+                    timestamp_ns: to_u64(&row.timestamp_ns)?,
+                    value: to_u128(&row.value)?,
+                    correlation_id: CorrelationId::new(),
+                    version: TapVersion::V2,
+                    stages: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Idempotent, the reason of a sender already denied is replaced by the latest one, unless
+    /// the operator denied it.
+    pub async fn deny_sender(&self, sender: Address, denial: &Denial) -> Result<()> {
+        database::acquire(&self.pgpool, Subsystem::Denylist)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
+                        INSERT INTO scalar_tap_denylist (sender_address, reason, context)
+                        VALUES ($1, $2, $3)
+                        ON CONFLICT (sender_address) DO UPDATE
+                        SET reason = excluded.reason, context = excluded.context
+                        WHERE scalar_tap_denylist.reason IS DISTINCT FROM 'operator'
+                    "#,
+                    sender.encode_hex(),
+                    denial.reason.as_str(),
+                    &denial.context,
+                )
+                .execute(conn)
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Idempotent.
+    pub async fn allow_sender(&self, sender: Address) -> Result<()> {
+        database::acquire(&self.pgpool, Subsystem::Denylist)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    "DELETE FROM scalar_tap_denylist WHERE sender_address = $1",
+                    sender.encode_hex(),
+                )
+                .execute(conn)
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Position of [`poll_new_receipts`] in a receipts table.
+///
+/// The ids are taken from a sequence when the receipts are inserted, but their transactions
+/// commit in any order, so a receipt can show up after the ones with a greater id. The cursor
+/// stays before the first missing id, the receipts after it being polled again but notified only
+/// once, until the id shows up, or until the receipt after it was seen [`GAP_TIMEOUT`] ago, as
+/// the ids of the rolled back transactions never do.
+#[derive(Debug)]
+struct ReceiptCursor {
+    /// All the receipts up to this id were notified, or given up on.
+    last_id: u64,
+    /// The receipts after `last_id` already notified, with when they were.
+    notified: BTreeMap<u64, Instant>,
+}
+
+impl ReceiptCursor {
+    fn new(last_id: u64) -> Self {
+        Self {
+            last_id,
+            notified: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the receipt wasn't notified yet, it's then recorded as notified.
+    fn notify(&mut self, id: u64, now: Instant) -> bool {
+        if id <= self.last_id || self.notified.contains_key(&id) {
+            return false;
+        }
+        self.notified.insert(id, now);
+        true
+    }
+
+    /// Moves past the receipts notified without a missing id before them, and past the missing
+    /// ids given up on.
+    fn advance(&mut self, now: Instant) {
+        while let Some(entry) = self.notified.first_entry() {
+            let id = *entry.key();
+            if id != self.last_id + 1 && now.duration_since(*entry.get()) < GAP_TIMEOUT {
+                break;
+            }
+            if id != self.last_id + 1 {
+                warn!(
+                    from = self.last_id + 1,
+                    to = id - 1,
+                    "Receipts ids still missing, assuming their transactions rolled back."
+                );
+            }
+            entry.remove();
+            self.last_id = id;
+        }
+    }
+}
+
+/// Sends a notification for each receipt stored with an id greater than `after_id`, and each
+/// Horizon receipt with an id greater than `after_horizon_id` if set, polling Postgres every
+/// `interval`. Stops once the receiver is dropped.
+///
+/// The receipts are notified once each, even when they're committed out of order, see
+/// [`ReceiptCursor`].
+pub fn poll_new_receipts(
+    storage: PgStorage,
+    after_id: u64,
+    after_horizon_id: Option<u64>,
+    interval: Duration,
+) -> mpsc::Receiver<NewReceiptNotification> {
+    let (sender, receiver) = mpsc::channel(POLL_BATCH_SIZE as usize);
+    tokio::spawn(async move {
+        let mut cursor = ReceiptCursor::new(after_id);
+        let mut horizon_cursor = after_horizon_id.map(ReceiptCursor::new);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let mut notifications = match storage
+                .receipts_after(cursor.last_id, POLL_BATCH_SIZE)
+                .await
+            {
+                Ok(notifications) => notifications,
+                Err(error) => {
+                    warn!(%error, "Failed to poll the new receipts.");
+                    continue;
+                }
+            };
+            if let Some(ref horizon_cursor) = horizon_cursor {
+                match storage
+                    .horizon_receipts_after(horizon_cursor.last_id, POLL_BATCH_SIZE)
+                    .await
+                {
+                    Ok(horizon_notifications) => notifications.extend(horizon_notifications),
+                    Err(error) => {
+                        warn!(%error, "Failed to poll the new Horizon receipts.");
+                        continue;
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            for notification in notifications {
+                let table_cursor = match notification.version {
+                    TapVersion::V1 => &mut cursor,
+                    TapVersion::V2 => horizon_cursor
+                        .as_mut()
+                        .expect("only polled with a Horizon cursor"),
+                };
+                if !table_cursor.notify(notification.id, now) {
+                    continue;
+                }
+                if sender.send(notification).await.is_err() {
+                    return;
+                }
+            }
+            cursor.advance(now);
+            if let Some(ref mut horizon_cursor) = horizon_cursor {
+                horizon_cursor.advance(now);
+            }
+        }
+    });
+    receiver
+}
+
+fn decode_address(address: &str) -> Result<Address> {
+    Address::from_str(address).with_context(|| format!("Error decoding address {address}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use alloy::hex::ToHexExt;
+    use bigdecimal::BigDecimal;
+    use sqlx::{PgExecutor, PgPool};
+
+    use super::{poll_new_receipts, PgStorage, ReceiptCursor, GAP_TIMEOUT};
+    use crate::{
+        agent::denylist_outbox::{Denial, DenyReason},
+        tap::{
+            horizon,
+            test_utils::{
+                create_received_receipt, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SIGNER,
+            },
+            TapVersion,
+        },
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_deny_reason(pgpool: PgPool) {
+        let storage = PgStorage::new(pgpool.clone());
+        let reason = || async {
+            sqlx::query_scalar!(
+                "SELECT reason FROM scalar_tap_denylist WHERE sender_address = $1",
+                SENDER.1.encode_hex(),
+            )
+            .fetch_one(&pgpool)
+            .await
+            .unwrap()
+        };
+
+        for (denied, expected) in [
+            (DenyReason::InvalidReceipts, DenyReason::InvalidReceipts),
+            (DenyReason::EscrowBalance, DenyReason::EscrowBalance),
+            (DenyReason::Operator, DenyReason::Operator),
+            // The deny of the operator is kept
+            (DenyReason::EscrowBalance, DenyReason::Operator),
+        ] {
+            storage
+                .deny_sender(SENDER.1, &Denial::from(denied))
+                .await
+                .unwrap();
+            assert_eq!(reason().await.as_deref(), Some(expected.as_str()));
+        }
+
+        storage.allow_sender(SENDER.1).await.unwrap();
+        storage.allow_sender(SENDER.1).await.unwrap();
+        let denied = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_denylist"#)
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(denied, 0);
+    }
+
+    /// Stores a receipt with `value` as its nonce, timestamp and value, returns its id.
+    async fn insert_receipt(conn: impl PgExecutor<'_>, value: u64) -> i64 {
+        let receipt =
+            create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, value, value, value.into());
+        sqlx::query_scalar!(
+            r#"
+                INSERT INTO scalar_tap_receipts (
+                    signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                )
+                VALUES ($1, $2, $3, $4, $4, $4)
+                RETURNING id
+            "#,
+            SIGNER.1.encode_hex(),
+            receipt.signed_receipt().signature.as_bytes().to_vec(),
+            ALLOCATION_ID_0.encode_hex(),
+            BigDecimal::from(value),
+        )
+        .fetch_one(conn)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_poll_new_receipts_out_of_order(pgpool: PgPool) {
+        let mut notifications = poll_new_receipts(
+            PgStorage::new(pgpool.clone()),
+            0,
+            Some(0),
+            Duration::from_millis(10),
+        );
+
+        // The first receipt is committed after the second one
+        let mut transaction = pgpool.begin().await.unwrap();
+        let first = insert_receipt(&mut *transaction, 1).await;
+        let second = insert_receipt(&pgpool, 2).await;
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification.id, second as u64);
+        assert_eq!(notification.signer_address, SIGNER.1);
+        assert_eq!(notification.value, 2);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        transaction.commit().await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap().id, first as u64);
+
+        // The Horizon receipts are polled too
+        sqlx::query!(
+            r#"
+                INSERT INTO tap_horizon_receipts (
+                    signer_address, signature, collection_id, payer, data_service,
+                    service_provider, timestamp_ns, nonce, value
+                )
+                VALUES ($1, $2, $3, $4, $4, $4, 30, 3, 3)
+            "#,
+            SIGNER.1.encode_hex(),
+            vec![0u8; 65],
+            horizon::collection_id(*ALLOCATION_ID_1).encode_hex(),
+            SENDER.1.encode_hex(),
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification.version, TapVersion::V2);
+        assert_eq!(notification.allocation_id, *ALLOCATION_ID_1);
+        assert_eq!(notification.value, 3);
+        // Each receipt is notified once
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(notifications.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_receipt_cursor_gap() {
+        let start = Instant::now();
+        let mut cursor = ReceiptCursor::new(1);
+        assert!(!cursor.notify(1, start));
+        assert!(cursor.notify(3, start));
+        assert!(!cursor.notify(3, start));
+        cursor.advance(start);
+        assert_eq!(cursor.last_id, 1);
+
+        // The missing id shows up
+        assert!(cursor.notify(2, start));
+        cursor.advance(start);
+        assert_eq!(cursor.last_id, 3);
+
+        // Or it's given up on
+        assert!(cursor.notify(5, start));
+        cursor.advance(start + GAP_TIMEOUT / 2);
+        assert_eq!(cursor.last_id, 3);
+        cursor.advance(start + GAP_TIMEOUT);
+        assert_eq!(cursor.last_id, 5);
+    }
+}
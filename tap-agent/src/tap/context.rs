@@ -22,6 +22,7 @@ pub struct TapAgentContext {
     sender: Address,
     escrow_accounts: Eventual<EscrowAccounts>,
     escrow_adapter: EscrowAdapter,
+    delete_receipts_with_rav: bool,
 }
 
 impl TapAgentContext {
@@ -38,6 +39,14 @@ impl TapAgentContext {
             sender,
             escrow_accounts,
             escrow_adapter,
+            delete_receipts_with_rav: false,
         }
     }
+
+    /// Deletes the receipts covered by a RAV in the same transaction that stores it, instead of
+    /// leaving them to `remove_obsolete_receipts`.
+    pub fn with_delete_receipts_with_rav(mut self, enabled: bool) -> Self {
+        self.delete_receipts_with_rav = enabled;
+        self
+    }
 }
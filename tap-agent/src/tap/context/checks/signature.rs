@@ -2,9 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use alloy::{dyn_abi::Eip712Domain, primitives::U256};
-use anyhow::anyhow;
 use eventuals::Eventual;
 use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::tap::rejection::RejectionCode;
 use tap_core::receipt::{
     checks::{Check, CheckError, CheckResult},
     state::Checking,
@@ -33,7 +33,7 @@ impl Check for Signature {
         let signer = receipt
             .signed_receipt()
             .recover_signer(&self.domain_separator)
-            .map_err(|e| CheckError::Failed(e.into()))?;
+            .map_err(|e| CheckError::Failed(RejectionCode::BadSignature.reject(e)))?;
         let escrow_accounts = self
             .escrow_accounts
             .value()
@@ -45,18 +45,19 @@ impl Check for Signature {
 
         let sender = escrow_accounts
             .get_sender_for_signer(&signer)
-            .map_err(|e| CheckError::Failed(e.into()))?;
+            .map_err(|e| CheckError::Failed(RejectionCode::UnknownSigner.reject(e)))?;
 
         let balance = escrow_accounts
             .get_balance_for_sender(&sender)
-            .map_err(|e| CheckError::Failed(e.into()))?;
+            .map_err(|e| CheckError::Failed(RejectionCode::InsufficientBalance.reject(e)))?;
 
         if balance == U256::ZERO {
-            Err(CheckError::Failed(anyhow!(
-                "Balance for sender {}, signer {} is not positive",
-                sender,
-                signer
-            )))
+            Err(CheckError::Failed(
+                RejectionCode::InsufficientBalance.reject(format!(
+                    "Balance for sender {}, signer {} is not positive",
+                    sender, signer
+                )),
+            ))
         } else {
             Ok(())
         }
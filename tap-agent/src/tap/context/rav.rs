@@ -4,11 +4,15 @@
 use std::str::FromStr;
 
 use super::{error::AdapterError, TapAgentContext};
+use crate::database::{self, Subsystem};
+use crate::tap::signers_trimmed;
 use alloy::signers::Signature;
 use alloy::{hex::ToHexExt, primitives::Address};
 use bigdecimal::num_bigint::{BigInt, ToBigInt};
 use bigdecimal::ToPrimitive;
+use indexer_common::fees::FeeToken;
 use sqlx::types::{chrono, BigDecimal};
+use sqlx::Connection;
 use tap_core::{
     manager::adapters::{RAVRead, RAVStore},
     rav::{ReceiptAggregateVoucher, SignedRAV},
@@ -19,20 +23,27 @@ impl RAVRead for TapAgentContext {
     type AdapterError = AdapterError;
 
     async fn last_rav(&self) -> Result<Option<SignedRAV>, Self::AdapterError> {
-        let row = sqlx::query!(
-            r#"
+        let row = database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await
+            .map_err(|e| AdapterError::RavRead {
+                error: e.to_string(),
+            })?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                 SELECT signature, allocation_id, timestamp_ns, value_aggregate
                 FROM scalar_tap_ravs
                 WHERE allocation_id = $1 AND sender_address = $2
             "#,
-            self.allocation_id.encode_hex(),
-            self.sender.encode_hex()
-        )
-        .fetch_optional(&self.pgpool)
-        .await
-        .map_err(|e| AdapterError::RavRead {
-            error: e.to_string(),
-        })?;
+                    self.allocation_id.encode_hex(),
+                    self.sender.encode_hex()
+                )
+                .fetch_optional(conn)
+            })
+            .await
+            .map_err(|e| AdapterError::RavRead {
+                error: e.to_string(),
+            })?;
 
         match row {
             Some(row) => {
@@ -90,51 +101,96 @@ impl RAVStore for TapAgentContext {
     async fn update_last_rav(&self, rav: SignedRAV) -> Result<(), Self::AdapterError> {
         let signature_bytes: Vec<u8> = rav.signature.as_bytes().to_vec();
 
-        let _fut = sqlx::query!(
-            r#"
+        // Deleting the receipts needs their signers, fetch them before holding a connection.
+        let signers = if self.delete_receipts_with_rav {
+            signers_trimmed(&self.escrow_accounts, self.sender)
+                .await
+                .map_err(|e| AdapterError::RavStore {
+                    error: format!("{:?}.", e),
+                })?
+        } else {
+            Vec::new()
+        };
+
+        database::acquire(&self.pgpool, Subsystem::RavStore)
+            .await
+            .map_err(|e| AdapterError::RavStore {
+                error: e.to_string(),
+            })?
+            .run(|conn| async move {
+                let mut tx = conn.begin().await?;
+                sqlx::query!(
+                    r#"
                 INSERT INTO scalar_tap_ravs (
                     sender_address,
                     signature,
                     allocation_id,
                     timestamp_ns,
                     value_aggregate,
+                    fee_token,
                     created_at,
                     updated_at
 
                 )
-                VALUES ($1, $2, $3, $4, $5, $6, $6)
+                VALUES ($1, $2, $3, $4, $5, $7, $6, $6)
                 ON CONFLICT (allocation_id, sender_address)
                 DO UPDATE SET
                     signature = $2,
                     timestamp_ns = $4,
                     value_aggregate = $5,
+                    fee_token = $7,
                     updated_at = $6
             "#,
-            self.sender.encode_hex(),
-            signature_bytes,
-            self.allocation_id.encode_hex(),
-            BigDecimal::from(rav.message.timestampNs),
-            BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
-            chrono::Utc::now()
-        )
-        .execute(&self.pgpool)
-        .await
-        .map_err(|e| AdapterError::RavStore {
-            error: e.to_string(),
-        })?;
+                    self.sender.encode_hex(),
+                    signature_bytes,
+                    self.allocation_id.encode_hex(),
+                    BigDecimal::from(rav.message.timestampNs),
+                    BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+                    chrono::Utc::now(),
+                    FeeToken::Grt.symbol()
+                )
+                .execute(&mut *tx)
+                .await?;
+                if self.delete_receipts_with_rav {
+                    sqlx::query!(
+                        r#"
+                        DELETE FROM scalar_tap_receipts
+                        WHERE allocation_id = $1
+                        AND signer_address IN (SELECT unnest($2::text[]))
+                        AND timestamp_ns <= $3
+                    "#,
+                        self.allocation_id.encode_hex(),
+                        &signers,
+                        BigDecimal::from(rav.message.timestampNs),
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await
+            })
+            .await
+            .map_err(|e| AdapterError::RavStore {
+                error: e.to_string(),
+            })?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
+    use alloy::primitives::U256;
     use eventuals::Eventual;
+    use indexer_common::escrow_accounts::EscrowAccounts;
     use sqlx::PgPool;
 
     use super::*;
     use crate::tap::{
         escrow_adapter::EscrowAdapter,
-        test_utils::{create_rav, ALLOCATION_ID_0, SENDER, SIGNER},
+        test_utils::{
+            create_rav, create_received_receipt, store_receipt, ALLOCATION_ID_0, SENDER, SIGNER,
+        },
     };
 
     #[derive(Debug)]
@@ -191,4 +247,43 @@ mod test {
         let last_rav = context.last_rav().await.unwrap();
         assert_eq!(TestableRav(new_rav), TestableRav(last_rav.unwrap()));
     }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn update_rav_deletes_covered_receipts(pool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let context = TapAgentContext::new(
+            pool.clone(),
+            *ALLOCATION_ID_0,
+            SENDER.1,
+            escrow_accounts,
+            EscrowAdapter::mock(),
+        )
+        .with_delete_receipts_with_rav(true);
+        for timestamp_ns in 1..=4 {
+            let receipt = create_received_receipt(
+                &ALLOCATION_ID_0,
+                &SIGNER.0,
+                timestamp_ns,
+                timestamp_ns,
+                10,
+            );
+            store_receipt(&pool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 2, 20);
+        context.update_last_rav(rav).await.unwrap();
+
+        let remaining = sqlx::query_scalar!(
+            "SELECT timestamp_ns FROM scalar_tap_receipts ORDER BY timestamp_ns"
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(remaining, vec![BigDecimal::from(3), BigDecimal::from(4)]);
+    }
 }
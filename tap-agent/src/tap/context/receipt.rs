@@ -16,6 +16,7 @@ use tap_core::{
     receipt::{state::Checking, Receipt, ReceiptWithState, SignedReceipt},
 };
 
+use crate::database::{self, Subsystem};
 use crate::tap::signers_trimmed;
 
 use super::{error::AdapterError, TapAgentContext};
@@ -89,8 +90,11 @@ impl ReceiptRead for TapAgentContext {
 
         let receipts_limit = receipts_limit.map_or(1000, |limit| limit);
 
-        let records = sqlx::query!(
-            r#"
+        let records = database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                 SELECT id, signature, allocation_id, timestamp_ns, nonce, value
                 FROM scalar_tap_receipts
                 WHERE allocation_id = $1 AND signer_address IN (SELECT unnest($2::text[]))
@@ -98,13 +102,14 @@ impl ReceiptRead for TapAgentContext {
                 ORDER BY timestamp_ns ASC
                 LIMIT $4
             "#,
-            self.allocation_id.encode_hex(),
-            &signers,
-            rangebounds_to_pgrange(timestamp_range_ns),
-            (receipts_limit + 1) as i64,
-        )
-        .fetch_all(&self.pgpool)
-        .await?;
+                    self.allocation_id.encode_hex(),
+                    &signers,
+                    rangebounds_to_pgrange(timestamp_range_ns),
+                    (receipts_limit + 1) as i64,
+                )
+                .fetch_all(conn)
+            })
+            .await?;
         let mut receipts = records
             .into_iter()
             .map(|record| {
@@ -174,18 +179,22 @@ impl ReceiptDelete for TapAgentContext {
                 error: format!("{:?}.", e),
             })?;
 
-        sqlx::query!(
-            r#"
+        database::acquire(&self.pgpool, Subsystem::ReceiptScan)
+            .await?
+            .run(|conn| {
+                sqlx::query!(
+                    r#"
                 DELETE FROM scalar_tap_receipts
                 WHERE allocation_id = $1 AND signer_address IN (SELECT unnest($2::text[]))
                     AND $3::numrange @> timestamp_ns
             "#,
-            self.allocation_id.encode_hex(),
-            &signers,
-            rangebounds_to_pgrange(timestamp_ns)
-        )
-        .execute(&self.pgpool)
-        .await?;
+                    self.allocation_id.encode_hex(),
+                    &signers,
+                    rangebounds_to_pgrange(timestamp_ns)
+                )
+                .execute(conn)
+            })
+            .await?;
         Ok(())
     }
 }
@@ -472,12 +481,12 @@ mod test {
             .unwrap();
         assert_eq!(recovered_received_receipt_vec.len(), 50);
 
-        // add a copy in the same timestamp
+        // add a copy in the same timestamp, with another nonce as they're unique
         for i in 0..100 {
             let receipt = create_received_receipt(
                 &ALLOCATION_ID_0,
                 &SIGNER.0,
-                i + 684,
+                i + 784,
                 i + 43,
                 (i + 124).into(),
             );
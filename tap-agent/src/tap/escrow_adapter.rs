@@ -6,11 +6,14 @@ use std::sync::{Arc, RwLock};
 use alloy::primitives::Address;
 use async_trait::async_trait;
 use eventuals::Eventual;
-use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::escrow_accounts::{EscrowAccounts, EscrowAccountsError};
+use serde::Serialize;
 use tap_core::manager::adapters::EscrowHandler as EscrowAdapterTrait;
 
 use super::context::AdapterError;
 
+use crate::money::wei;
+
 /// The EscrowAdapter is used to track the available escrow for all senders. It is updated when
 /// receipt checks are finalized (right before a RAV request).
 ///
@@ -18,12 +21,17 @@ use super::context::AdapterError;
 /// shared through clones.
 ///
 /// It is not used to track unaggregated fees (yet?), because we are currently batch finalizing
-/// receipt checks only when we need to send a RAV request.
+/// receipt checks only when we need to send a RAV request. The escrow of the valid receipts of a
+/// RAV request is reserved when the request is created, and released by the `SenderAllocation`
+/// if it fails, as the same receipts are reserved again by the next request, or as its receipts
+/// are moved to the invalid receipts table.
 #[derive(Clone)]
 pub struct EscrowAdapter {
     escrow_accounts: Eventual<EscrowAccounts>,
     sender_id: Address,
     sender_pending_fees: Arc<RwLock<u128>>,
+    /// Collector of the Horizon receipts, set if they are collected as well.
+    horizon_collector: Option<Address>,
 }
 
 impl EscrowAdapter {
@@ -32,8 +40,97 @@ impl EscrowAdapter {
             escrow_accounts,
             sender_pending_fees: Arc::new(RwLock::new(0)),
             sender_id,
+            horizon_collector: None,
         }
     }
+
+    pub fn with_horizon_collector(mut self, horizon_collector: Option<Address>) -> Self {
+        self.horizon_collector = horizon_collector;
+        self
+    }
+
+    /// Balance of the sender's Horizon escrow account for the collector of the Horizon receipts,
+    /// zero if it has no such account. `None` if Horizon receipts aren't collected. Fails while
+    /// the Horizon balances are unknown, rather than letting the receipts through.
+    pub async fn get_horizon_balance(&self) -> Result<Option<u128>, AdapterError> {
+        let Some(collector) = self.horizon_collector else {
+            return Ok(None);
+        };
+        let escrow_accounts = self.escrow_accounts.value().await?;
+        match escrow_accounts.get_balance_for_payer_and_collector(&self.sender_id, &collector) {
+            Ok(balance) => {
+                balance
+                    .try_into()
+                    .map(Some)
+                    .map_err(|_| AdapterError::BalanceTooLarge {
+                        sender: self.sender_id,
+                    })
+            }
+            Err(EscrowAccountsError::NoHorizonBalanceFound { .. }) => Ok(Some(0)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Escrow of the sender reserved by the RAV requests so far.
+    pub fn reserved(&self) -> u128 {
+        *self.sender_pending_fees.read().unwrap()
+    }
+
+    /// Releases escrow reserved by a RAV request that didn't result in a RAV, or for receipts
+    /// found invalid.
+    pub fn release(&self, value: u128) {
+        let mut fees = self.sender_pending_fees.write().unwrap();
+        *fees = fees.saturating_sub(value);
+    }
+
+    /// Balance of the sender's escrow account, and the part of it reserved by the RAV requests.
+    pub async fn reservation(&self) -> Result<EscrowReservation, AdapterError> {
+        let escrow_accounts = self.escrow_accounts.value().await?;
+        let balance: u128 = escrow_accounts
+            .get_balance_for_sender(&self.sender_id)?
+            .to_owned()
+            .try_into()
+            .map_err(|_| AdapterError::BalanceTooLarge {
+                sender: self.sender_id,
+            })?;
+        let reserved = self.reserved();
+        Ok(EscrowReservation {
+            balance,
+            reserved,
+            available: balance.saturating_sub(reserved),
+        })
+    }
+}
+
+/// See [`EscrowAdapter::reservation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EscrowReservation {
+    #[serde(serialize_with = "wei")]
+    pub balance: u128,
+    #[serde(serialize_with = "wei")]
+    pub reserved: u128,
+    /// Lower than the balance minus the reserved escrow, if the balance was reduced by the
+    /// redemption of RAVs.
+    #[serde(serialize_with = "wei")]
+    pub available: u128,
+}
+
+/// Reserved escrow of a sender next to the fees tracked by its `SenderAccount`, to tell when they
+/// drift apart, see [`crate::admin`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SenderEscrow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reservation: Option<EscrowReservation>,
+    /// Set if the balance of the sender couldn't be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Of the legacy receipts only, the only ones the escrow is reserved for.
+    #[serde(serialize_with = "wei")]
+    pub pending_ravs: u128,
+    #[serde(serialize_with = "wei")]
+    pub unaggregated_fees: u128,
+    #[serde(serialize_with = "wei")]
+    pub invalid_receipt_fees: u128,
 }
 
 #[async_trait]
@@ -53,7 +150,7 @@ impl EscrowAdapterTrait for EscrowAdapter {
             })?;
 
         let fees = *self.sender_pending_fees.read().unwrap();
-        Ok(balance - fees)
+        Ok(balance.saturating_sub(fees))
     }
 
     async fn subtract_escrow(&self, signer: Address, value: u128) -> Result<(), AdapterError> {
@@ -112,6 +209,7 @@ mod test {
                 escrow_accounts,
                 sender_pending_fees: Arc::new(RwLock::new(0)),
                 sender_id: Address::ZERO,
+                horizon_collector: None,
             }
         }
     }
@@ -129,6 +227,7 @@ mod test {
             escrow_accounts,
             sender_pending_fees,
             sender_id: Address::ZERO,
+            horizon_collector: None,
         };
         adapter
             .subtract_escrow(SIGNER.1, 500)
@@ -154,6 +253,7 @@ mod test {
             escrow_accounts,
             sender_pending_fees,
             sender_id: Address::ZERO,
+            horizon_collector: None,
         };
         adapter
             .subtract_escrow(SIGNER.1, 250)
@@ -166,4 +266,66 @@ mod test {
             .expect("Get available escrow.");
         assert_eq!(available_escrow, 250);
     }
+
+    #[tokio::test]
+    async fn test_release() {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let adapter = EscrowAdapter::new(escrow_accounts, SENDER.1);
+
+        adapter
+            .subtract_escrow(SIGNER.1, 600)
+            .await
+            .expect("Subtract escrow.");
+        assert_eq!(
+            adapter.reservation().await.unwrap(),
+            EscrowReservation {
+                balance: 1000,
+                reserved: 600,
+                available: 400,
+            }
+        );
+
+        adapter.release(200);
+        assert_eq!(adapter.reserved(), 400);
+        assert_eq!(adapter.get_available_escrow(SIGNER.1).await.unwrap(), 600);
+
+        adapter.release(1000);
+        assert_eq!(adapter.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_horizon_balance() {
+        let collector = Address::from([0x22u8; 20]);
+        let escrow_accounts = Eventual::from_value(
+            EscrowAccounts::new(
+                HashMap::from([(SENDER.1, U256::from(1000))]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )
+            .with_horizon_balances(HashMap::from([((SENDER.1, collector), U256::from(300))])),
+        );
+
+        let adapter = EscrowAdapter::new(escrow_accounts.clone(), SENDER.1);
+        assert_eq!(adapter.get_horizon_balance().await.unwrap(), None);
+
+        let adapter = adapter.with_horizon_collector(Some(collector));
+        assert_eq!(adapter.get_horizon_balance().await.unwrap(), Some(300));
+
+        let adapter = EscrowAdapter::new(escrow_accounts, SENDER.1)
+            .with_horizon_collector(Some(Address::from([0x33u8; 20])));
+        assert_eq!(adapter.get_horizon_balance().await.unwrap(), Some(0));
+
+        // Unknown balances
+        let adapter = EscrowAdapter::new(
+            Eventual::from_value(EscrowAccounts::new(
+                HashMap::from([(SENDER.1, U256::from(1000))]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )),
+            SENDER.1,
+        )
+        .with_horizon_collector(Some(collector));
+        assert!(adapter.get_horizon_balance().await.is_err());
+    }
 }
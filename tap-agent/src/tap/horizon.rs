@@ -0,0 +1,83 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Horizon (TAP v2) receipts and RAVs.
+//!
+//! Horizon receipts are collection-based: they carry a `collection_id` instead of an allocation
+//! id, as well as the payer, data service and service provider, and are signed for the
+//! GraphTallyCollector contract. They are stored in the `tap_horizon_*` tables, next to the legacy
+//! receipts, as indexers collect both formats during the transition to Horizon.
+//!
+//! The collection id of an allocation is the allocation id, left-padded to 32 bytes. This is what
+//! allows a `SenderAllocation` to track the fees of both formats.
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    primitives::{Address, FixedBytes},
+    sol,
+    sol_types::eip712_domain,
+};
+use serde::{Deserialize, Serialize};
+use tap_core::signed_message::EIP712SignedMessage;
+
+sol! {
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Receipt {
+        bytes32 collection_id;
+        address payer;
+        address data_service;
+        address service_provider;
+        uint64 timestamp_ns;
+        uint64 nonce;
+        uint128 value;
+    }
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct ReceiptAggregateVoucher {
+        bytes32 collectionId;
+        address payer;
+        address serviceProvider;
+        address dataService;
+        uint64 timestampNs;
+        uint128 valueAggregate;
+        bytes metadata;
+    }
+}
+
+pub type SignedReceipt = EIP712SignedMessage<Receipt>;
+pub type SignedRav = EIP712SignedMessage<ReceiptAggregateVoucher>;
+
+pub fn eip712_domain(chain_id: u64, verifying_contract: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "GraphTallyCollector",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: verifying_contract,
+    }
+}
+
+pub fn collection_id(allocation_id: Address) -> FixedBytes<32> {
+    allocation_id.into_word()
+}
+
+pub fn allocation_id(collection_id: FixedBytes<32>) -> Address {
+    Address::from_word(collection_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::address;
+
+    use super::{allocation_id, collection_id};
+
+    #[test]
+    fn test_collection_id_round_trip() {
+        let allocation = address!("abababababababababababababababababababab");
+        let collection = collection_id(allocation);
+        assert_eq!(
+            collection.to_string(),
+            "0x000000000000000000000000abababababababababababababababababababab"
+        );
+        assert_eq!(allocation_id(collection), allocation);
+    }
+}
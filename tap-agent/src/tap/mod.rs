@@ -9,10 +9,21 @@ use indexer_common::escrow_accounts::EscrowAccounts;
 
 pub mod context;
 pub mod escrow_adapter;
+pub mod horizon;
 
 #[cfg(test)]
 pub mod test_utils;
 
+/// Format of a receipt, see [`horizon`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TapVersion {
+    /// Allocation-based receipts, stored in the `scalar_tap_*` tables.
+    #[default]
+    V1,
+    /// Collection-based Horizon receipts, stored in the `tap_horizon_*` tables.
+    V2,
+}
+
 pub async fn signers_trimmed(
     escrow_accounts: &Eventual<EscrowAccounts>,
     sender: Address,
@@ -3,24 +3,24 @@
 
 use std::str::FromStr;
 
-use alloy::{
-    primitives::hex::ToHexExt,
-    signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
-};
+use alloy::{primitives::hex::ToHexExt, signers::local::PrivateKeySigner};
 use bigdecimal::num_bigint::BigInt;
 
 use sqlx::types::BigDecimal;
 
 use alloy::dyn_abi::Eip712Domain;
 use alloy::primitives::Address;
+use indexer_common::prelude::{Allocation, AllocationStatus, SubgraphDeployment};
+pub use indexer_test_harness::wallet;
+use indexer_test_harness::wallets::test_domain;
 use lazy_static::lazy_static;
 use sqlx::PgPool;
 use tap_core::{
     rav::{ReceiptAggregateVoucher, SignedRAV},
     receipt::{state::Checking, Receipt, ReceiptWithState, SignedReceipt},
     signed_message::EIP712SignedMessage,
-    tap_eip712_domain,
 };
+use thegraph_core::DeploymentId;
 
 lazy_static! {
     pub static ref ALLOCATION_ID_0: Address =
@@ -32,8 +32,29 @@ lazy_static! {
     pub static ref SENDER_3: (PrivateKeySigner, Address) = wallet(4);
     pub static ref SIGNER: (PrivateKeySigner, Address) = wallet(2);
     pub static ref INDEXER: (PrivateKeySigner, Address) = wallet(3);
-    pub static ref TAP_EIP712_DOMAIN_SEPARATOR: Eip712Domain =
-        tap_eip712_domain(1, Address::from([0x11u8; 20]),);
+    pub static ref TAP_EIP712_DOMAIN_SEPARATOR: Eip712Domain = test_domain();
+}
+
+/// Fixture to generate an active allocation of the indexer, for an arbitrary deployment
+pub fn create_allocation(allocation_id: Address) -> Allocation {
+    Allocation {
+        id: allocation_id,
+        status: AllocationStatus::Active,
+        subgraph_deployment: SubgraphDeployment {
+            id: DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap(),
+            denied_at: None,
+        },
+        indexer: INDEXER.1,
+        allocated_tokens: Default::default(),
+        created_at_epoch: 1,
+        created_at_block_hash: String::new(),
+        closed_at_epoch: None,
+        closed_at_epoch_start_block_hash: None,
+        previous_epoch_start_block_hash: None,
+        poi: None,
+        query_fee_rebates: None,
+        query_fees_collected: None,
+    }
 }
 
 /// Fixture to generate a RAV using the wallet from `keys()`
@@ -135,18 +156,6 @@ pub async fn store_invalid_receipt(
     Ok(id)
 }
 
-/// Fixture to generate a wallet and address
-pub fn wallet(index: u32) -> (PrivateKeySigner, Address) {
-    let wallet: PrivateKeySigner= MnemonicBuilder::<English>::default()
-        .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
-        .index(index)
-        .unwrap()
-        .build()
-        .unwrap();
-    let address = wallet.address();
-    (wallet, address)
-}
-
 pub async fn store_rav(
     pgpool: &PgPool,
     signed_rav: SignedRAV,
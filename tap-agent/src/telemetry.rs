@@ -0,0 +1,63 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry export of the tracing spans.
+//!
+//! The RAV request lifecycle is covered by the following spans, so that collectors like Tempo or
+//! Jaeger can show where the time goes:
+//! - `rav_trigger_decision` (SenderAccount): a fee update is received and checked against the
+//!   RAV request triggers.
+//! - `rav_request` (SenderAllocation): the RAV request itself, child of the trigger decision.
+//! - `aggregator_call`: call to the sender's TAP aggregator, its trace context is sent to the
+//!   aggregator in the `traceparent` header, see [`crate::agent::aggregator_client`].
+//! - `store_rav`: verification of the RAV and write to the database.
+//!
+//! The RAV metrics recorded within a sampled trace keep its id as exemplar, see
+//! [`crate::metrics::exemplars`].
+
+use anyhow::Result;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Config, Sampler, Tracer},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+const SERVICE_NAME: &str = "indexer-tap-agent";
+
+/// Creates a layer exporting the spans to the OTLP collector at `endpoint`, keeping a
+/// `sampling_ratio` fraction of the traces.
+pub fn otlp_layer<S>(endpoint: &str, sampling_ratio: f64) -> Result<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            Config::default()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    sampling_ratio,
+                ))))
+                .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)])),
+        )
+        .install_batch(runtime::Tokio)?;
+    let tracer = provider.tracer(SERVICE_NAME);
+    global::set_tracer_provider(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flushes the spans that haven't been exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
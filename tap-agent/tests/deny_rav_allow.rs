@@ -0,0 +1,246 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The economic loop of tap-agent against the reference TAP aggregator: the receipts of a sender
+//! pile up until it's denied, a RAV of the aggregator aggregates them, and the sender is allowed
+//! again.
+//!
+//! The receipts are inserted in the database like indexer-service would do, and notified to the
+//! agent by the database triggers. The sender is paused meanwhile, so that no RAV is requested
+//! before the denial is observed, whatever the timing: the RAV is only requested once it's
+//! resumed.
+//!
+//! Needs Docker to run the aggregator, and a database as the other tests:
+//! `cargo test -p indexer-tap-agent --test deny_rav_allow -- --ignored`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    str::FromStr,
+    time::Duration,
+};
+
+use alloy::{
+    hex::ToHexExt,
+    primitives::{Address, U256},
+};
+use bigdecimal::num_bigint::BigInt;
+use eventuals::Eventual;
+use indexer_common::prelude::{
+    Allocation, AllocationStatus, DeploymentDetails, SubgraphClient, SubgraphDeployment,
+};
+use indexer_tap_agent::{
+    agent::{
+        denylist_outbox::DenylistOutbox,
+        sender_account::SenderAccountMessage,
+        sender_accounts_manager::{SenderAccountsManager, SenderAccountsManagerArgs},
+    },
+    config,
+    privileges::DatabaseFeatures,
+};
+use indexer_test_harness::{
+    wallet, wallets::test_domain, AggregatorContainer, InMemoryEscrow, MockEscrowSubgraph,
+    ReceiptGenerator,
+};
+use ractor::{call, Actor, ActorRef};
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::DeploymentId;
+use tokio::sync::watch;
+
+const MAX_UNAGGREGATED_FEES: u128 = 1000;
+const RECEIPT_VALUE: u128 = 100;
+const ESCROW_BALANCE: u128 = 1_000_000;
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn allocation(id: Address, indexer: Address) -> Allocation {
+    Allocation {
+        id,
+        status: AllocationStatus::Active,
+        subgraph_deployment: SubgraphDeployment {
+            id: DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap(),
+            denied_at: None,
+        },
+        indexer,
+        allocated_tokens: U256::ZERO,
+        created_at_epoch: 1,
+        created_at_block_hash: String::new(),
+        closed_at_epoch: None,
+        closed_at_epoch_start_block_hash: None,
+        previous_epoch_start_block_hash: None,
+        poi: None,
+        query_fee_rebates: None,
+        query_fees_collected: None,
+    }
+}
+
+async fn store_receipt(pgpool: &PgPool, signer: Address, receipt: &SignedReceipt) {
+    sqlx::query!(
+        r#"
+            INSERT INTO scalar_tap_receipts (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        signer.encode_hex(),
+        receipt.signature.as_bytes().to_vec(),
+        receipt.message.allocation_id.encode_hex(),
+        BigDecimal::from(receipt.message.timestamp_ns),
+        BigDecimal::from(receipt.message.nonce),
+        BigDecimal::from(BigInt::from(receipt.message.value)),
+    )
+    .execute(pgpool)
+    .await
+    .unwrap();
+}
+
+async fn denied(pgpool: &PgPool, sender: Address) -> bool {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM scalar_tap_denylist WHERE sender_address = $1) AS "denied!""#,
+        sender.encode_hex()
+    )
+    .fetch_one(pgpool)
+    .await
+    .unwrap()
+}
+
+async fn rav_count(pgpool: &PgPool) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_ravs"#)
+        .fetch_one(pgpool)
+        .await
+        .unwrap()
+}
+
+async fn wait_until<F: Future<Output = bool>>(what: &str, mut condition: impl FnMut() -> F) {
+    tokio::time::timeout(TIMEOUT, async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{what} after {}s", TIMEOUT.as_secs()));
+}
+
+#[sqlx::test(migrations = "../migrations")]
+#[ignore = "needs Docker to run the aggregator"]
+async fn test_deny_rav_allow(pgpool: PgPool) {
+    let (aggregator_wallet, aggregator_address) = wallet(30);
+    let (signer_wallet, signer) = wallet(31);
+    let sender = wallet(32).1;
+    let allocation_id = wallet(33).1;
+    let indexer = wallet(34).1;
+
+    let aggregator =
+        AggregatorContainer::start(aggregator_wallet, HashSet::from([signer]), test_domain())
+            .await
+            .expect("should be able to start the aggregator container, is Docker running?");
+
+    // The RAVs are signed by the aggregator, on behalf of the sender
+    let escrow = InMemoryEscrow::new();
+    escrow.set_balance(sender, U256::from(ESCROW_BALANCE));
+    escrow.add_signer(sender, signer);
+    escrow.add_signer(sender, aggregator_address);
+    let escrow_subgraph = MockEscrowSubgraph::start().await;
+    let (_allocations_tx, indexer_allocations) = watch::channel(HashMap::from([(
+        allocation_id,
+        allocation(allocation_id, indexer),
+    )]));
+
+    let config = Box::leak(Box::new(config::Config {
+        ethereum: config::Ethereum {
+            indexer_address: indexer,
+        },
+        tap: config::Tap {
+            rav_request_trigger_value: MAX_UNAGGREGATED_FEES,
+            rav_request_timestamp_buffer_ms: 0,
+            rav_request_timeout_secs: 5,
+            rav_request_retry_interval: RETRY_INTERVAL,
+            rav_request_receipt_limit: 1000,
+            max_unnaggregated_fees_per_sender: MAX_UNAGGREGATED_FEES,
+            ..Default::default()
+        },
+        ..Default::default()
+    }));
+    let prefix = "deny-rav-allow".to_string();
+    let (manager, manager_handle) = SenderAccountsManager::spawn(
+        None,
+        SenderAccountsManager,
+        SenderAccountsManagerArgs {
+            config,
+            domain_separator: test_domain(),
+            horizon_domain_separator: None,
+            pgpool: pgpool.clone(),
+            allocation_pgpool: pgpool.clone(),
+            denylist: DenylistOutbox::new(pgpool.clone(), None).unwrap(),
+            indexer_allocations,
+            closing_allocations: Eventual::from_value(HashSet::new()),
+            escrow_accounts: escrow.accounts(),
+            escrow_subgraph: Box::leak(Box::new(SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&escrow_subgraph.query_url()).unwrap(),
+            ))),
+            sender_aggregator_endpoints: HashMap::from([(
+                sender,
+                aggregator.endpoint().to_string(),
+            )]),
+            database_features: DatabaseFeatures::default(),
+            prefix: Some(prefix.clone()),
+        },
+    )
+    .await
+    .unwrap();
+
+    let sender_account_name = format!("{prefix}:{sender}");
+    wait_until("No SenderAccount started", || async {
+        ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_some()
+    })
+    .await;
+    let sender_account = ActorRef::<SenderAccountMessage>::where_is(sender_account_name).unwrap();
+    call!(sender_account, SenderAccountMessage::PauseSender, None)
+        .unwrap()
+        .unwrap();
+
+    // Up to the limit, while no RAV can be requested
+    let mut receipts = ReceiptGenerator::new(test_domain(), signer_wallet, allocation_id);
+    let count = MAX_UNAGGREGATED_FEES / RECEIPT_VALUE;
+    for receipt in receipts.batch(count as usize, RECEIPT_VALUE) {
+        store_receipt(&pgpool, signer, &receipt).await;
+    }
+    wait_until("Sender not denied", || denied(&pgpool, sender)).await;
+    assert_eq!(rav_count(&pgpool).await, 0);
+
+    // Resumed, the RAV trigger reached meanwhile requests a RAV
+    call!(sender_account, SenderAccountMessage::ResumeSender)
+        .unwrap()
+        .unwrap();
+    wait_until("Sender not allowed again", || async {
+        !denied(&pgpool, sender).await
+    })
+    .await;
+
+    let rav_value = sqlx::query_scalar!(
+        "SELECT value_aggregate FROM scalar_tap_ravs WHERE sender_address = $1 AND allocation_id = $2",
+        sender.encode_hex(),
+        allocation_id.encode_hex()
+    )
+    .fetch_one(&pgpool)
+    .await
+    .unwrap();
+    assert_eq!(
+        rav_value,
+        BigDecimal::from(BigInt::from(MAX_UNAGGREGATED_FEES))
+    );
+    let failed_ravs =
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_rav_requests_failed"#)
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+    assert_eq!(failed_ravs, 0);
+
+    let status = call!(sender_account, SenderAccountMessage::GetStatus).unwrap();
+    let timeline: Vec<bool> = status.deny_events.iter().map(|e| e.denied).collect();
+    assert_eq!(timeline, [true, false]);
+
+    manager.stop_and_wait(None, None).await.unwrap();
+    manager_handle.await.unwrap();
+}
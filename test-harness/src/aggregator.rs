@@ -0,0 +1,108 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address, signers::local::PrivateKeySigner};
+use anyhow::Result;
+use jsonrpsee::server::ServerHandle;
+
+/// Maximum size of the requests and responses of the aggregator.
+const MAX_MESSAGE_SIZE: u32 = 100 * 1024;
+
+/// A TAP aggregator served on a random local port, signing the RAVs with `wallet`.
+///
+/// Only aggregates the receipts signed by one of its accepted signers, with its EIP-712 domain.
+/// Stopped when dropped.
+pub struct Aggregator {
+    handle: ServerHandle,
+    endpoint: String,
+}
+
+impl Aggregator {
+    pub async fn start(
+        wallet: PrivateKeySigner,
+        accepted_signers: HashSet<Address>,
+        domain: Eip712Domain,
+    ) -> Result<Self> {
+        let (handle, address) = tap_aggregator::server::run_server(
+            0,
+            wallet,
+            accepted_signers,
+            domain,
+            MAX_MESSAGE_SIZE,
+            MAX_MESSAGE_SIZE,
+            1,
+        )
+        .await?;
+        Ok(Self {
+            handle,
+            endpoint: format!("http://{address}"),
+        })
+    }
+
+    /// URL of the JSON-RPC endpoint, as in `tap.sender_aggregator_endpoints`.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub async fn stop(self) {
+        let _ = self.handle.stop();
+        self.handle.clone().stopped().await;
+    }
+}
+
+impl Drop for Aggregator {
+    fn drop(&mut self) {
+        let _ = self.handle.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder, rpc_params};
+    use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+    use tap_core::{
+        rav::{ReceiptAggregateVoucher, SignedRAV},
+        signed_message::EIP712SignedMessage,
+    };
+
+    use super::Aggregator;
+    use crate::{wallet, wallets::test_domain, ReceiptGenerator};
+
+    #[tokio::test]
+    async fn test_aggregate_generated_receipts() {
+        let (aggregator_wallet, aggregator_address) = wallet(10);
+        let (signer_wallet, signer) = wallet(11);
+        let aggregator =
+            Aggregator::start(aggregator_wallet, HashSet::from([signer]), test_domain())
+                .await
+                .unwrap();
+
+        let allocation_id = wallet(12).1;
+        let mut receipts = ReceiptGenerator::new(test_domain(), signer_wallet, allocation_id);
+        let batch = receipts.batch(10, 5);
+
+        let client = HttpClientBuilder::default()
+            .build(aggregator.endpoint())
+            .unwrap();
+        let response: JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!("0.0", batch, None::<SignedRAV>),
+            )
+            .await
+            .unwrap();
+        let rav = response.data;
+        assert_eq!(rav.message.allocationId, allocation_id);
+        assert_eq!(rav.message.valueAggregate, 50);
+        assert_eq!(
+            rav.recover_signer(&test_domain()).unwrap(),
+            aggregator_address
+        );
+
+        aggregator.stop().await;
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashSet, time::Duration};
+
+use alloy::{
+    dyn_abi::Eip712Domain, hex::ToHexExt, primitives::Address, signers::local::PrivateKeySigner,
+};
+use anyhow::{anyhow, Result};
+use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder, rpc_params};
+use testcontainers::{
+    core::IntoContainerPort, runners::AsyncRunner, ContainerAsync, GenericImage, ImageExt,
+};
+
+/// Image of the reference TAP aggregator. Pinned so that the tests don't change under us, to be
+/// bumped along with the `tap_aggregator` dependency.
+pub const IMAGE: &str = "ghcr.io/semiotic-ai/tap_aggregator";
+pub const TAG: &str = "v0.3.1";
+
+/// Port of the JSON-RPC server in the container.
+const PORT: u16 = 8080;
+/// How long the aggregator has to answer once the container started.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The reference TAP aggregator, the one the gateways run, in a Docker container.
+///
+/// Unlike [`crate::Aggregator`], it doesn't share any code with tap-agent, so the RAV requests
+/// go through the same serialization as in production. Signs the RAVs with `wallet`, and only
+/// aggregates the receipts signed by one of its accepted signers, with its EIP-712 domain.
+/// The container is removed when dropped.
+pub struct AggregatorContainer {
+    _container: ContainerAsync<GenericImage>,
+    endpoint: String,
+}
+
+impl AggregatorContainer {
+    /// Starts the container, and waits for the aggregator to answer. Needs Docker.
+    pub async fn start(
+        wallet: PrivateKeySigner,
+        accepted_signers: HashSet<Address>,
+        domain: Eip712Domain,
+    ) -> Result<Self> {
+        let mut image = GenericImage::new(IMAGE, TAG)
+            .with_exposed_port(PORT.tcp())
+            .with_env_var("TAP_PORT", PORT.to_string())
+            .with_env_var("TAP_PRIVATE_KEY", wallet.to_bytes().encode_hex())
+            .with_env_var(
+                "TAP_PUBLIC_KEYS",
+                accepted_signers
+                    .iter()
+                    .map(|signer| signer.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+        if let Some(name) = &domain.name {
+            image = image.with_env_var("TAP_DOMAIN_NAME", name.to_string());
+        }
+        if let Some(version) = &domain.version {
+            image = image.with_env_var("TAP_DOMAIN_VERSION", version.to_string());
+        }
+        if let Some(chain_id) = &domain.chain_id {
+            image = image.with_env_var("TAP_DOMAIN_CHAIN_ID", chain_id.to_string());
+        }
+        if let Some(verifying_contract) = &domain.verifying_contract {
+            image = image.with_env_var(
+                "TAP_DOMAIN_VERIFYING_CONTRACT",
+                verifying_contract.to_string(),
+            );
+        }
+        if let Some(salt) = &domain.salt {
+            image = image.with_env_var("TAP_DOMAIN_SALT", salt.to_string());
+        }
+
+        let container = image.start().await?;
+        let endpoint = format!(
+            "http://{}:{}",
+            container.get_host().await?,
+            container.get_host_port_ipv4(PORT.tcp()).await?
+        );
+        wait_ready(&endpoint).await?;
+        Ok(Self {
+            _container: container,
+            endpoint,
+        })
+    }
+
+    /// URL of the JSON-RPC endpoint, as in `tap.sender_aggregator_endpoints`.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
+
+/// Waits for the aggregator to list its API versions. The port is mapped before the server
+/// listens, so it's the only way to know it's up.
+async fn wait_ready(endpoint: &str) -> Result<()> {
+    let client = HttpClientBuilder::default().build(endpoint)?;
+    tokio::time::timeout(READY_TIMEOUT, async {
+        while client
+            .request::<serde_json::Value, _>("api_versions", rpc_params!())
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "The aggregator at {} didn't answer after {}s",
+            endpoint,
+            READY_TIMEOUT.as_secs()
+        )
+    })
+}
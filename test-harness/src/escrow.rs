@@ -0,0 +1,136 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use alloy::primitives::{Address, U256};
+use eventuals::{Eventual, EventualWriter};
+use indexer_common::escrow_accounts::EscrowAccounts;
+
+/// Escrow accounts changed by the test, instead of read from the escrow subgraph.
+///
+/// Every change is published to the [`Eventual`] of [`InMemoryEscrow::accounts`], as the escrow
+/// accounts watcher would do.
+#[derive(Clone)]
+pub struct InMemoryEscrow {
+    inner: Arc<Mutex<Inner>>,
+    accounts: Eventual<EscrowAccounts>,
+}
+
+struct Inner {
+    balances: HashMap<Address, U256>,
+    signers: HashMap<Address, Vec<Address>>,
+    writer: EventualWriter<EscrowAccounts>,
+}
+
+impl Default for InMemoryEscrow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryEscrow {
+    /// Without any account, which is published too.
+    pub fn new() -> Self {
+        let (writer, accounts) = Eventual::new();
+        let mut inner = Inner {
+            balances: HashMap::new(),
+            signers: HashMap::new(),
+            writer,
+        };
+        inner.publish();
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            accounts,
+        }
+    }
+
+    pub fn accounts(&self) -> Eventual<EscrowAccounts> {
+        self.accounts.clone()
+    }
+
+    pub fn set_balance(&self, sender: Address, balance: U256) {
+        self.update(|inner| {
+            inner.balances.insert(sender, balance);
+        });
+    }
+
+    pub fn add_signer(&self, sender: Address, signer: Address) {
+        self.update(|inner| {
+            let signers = inner.signers.entry(sender).or_default();
+            if !signers.contains(&signer) {
+                signers.push(signer);
+            }
+        });
+    }
+
+    pub fn remove_signer(&self, sender: Address, signer: Address) {
+        self.update(|inner| {
+            if let Some(signers) = inner.signers.get_mut(&sender) {
+                signers.retain(|s| *s != signer);
+            }
+        });
+    }
+
+    /// Removes the account and the signers of `sender`.
+    pub fn remove_sender(&self, sender: Address) {
+        self.update(|inner| {
+            inner.balances.remove(&sender);
+            inner.signers.remove(&sender);
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Inner)) {
+        let mut inner = self.inner.lock().unwrap();
+        f(&mut inner);
+        inner.publish();
+    }
+}
+
+impl Inner {
+    fn publish(&mut self) {
+        self.writer.write(EscrowAccounts::new(
+            self.balances.clone(),
+            self.signers.clone(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::U256;
+
+    use super::InMemoryEscrow;
+    use crate::wallet;
+
+    #[tokio::test]
+    async fn test_in_memory_escrow() {
+        let sender = wallet(0).1;
+        let signer = wallet(1).1;
+        let escrow = InMemoryEscrow::new();
+        let accounts = escrow.accounts();
+        assert!(accounts.value().await.unwrap().get_senders().is_empty());
+
+        escrow.set_balance(sender, U256::from(1000));
+        escrow.add_signer(sender, signer);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let snapshot = accounts.value().await.unwrap();
+        assert_eq!(snapshot.get_sender_for_signer(&signer).unwrap(), sender);
+        assert_eq!(
+            snapshot.get_balance_for_sender(&sender).unwrap(),
+            U256::from(1000)
+        );
+
+        escrow.remove_signer(sender, signer);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(accounts
+            .value()
+            .await
+            .unwrap()
+            .get_sender_for_signer(&signer)
+            .is_err());
+    }
+}
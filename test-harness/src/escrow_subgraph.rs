@@ -0,0 +1,57 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloy::primitives::Address;
+use serde_json::json;
+use wiremock::{
+    matchers::{body_string_contains, method},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Escrow subgraph answering the queries for the RAV redemptions, which tap-agent uses to tell
+/// which allocations were already redeemed.
+pub struct MockEscrowSubgraph {
+    server: MockServer,
+}
+
+impl MockEscrowSubgraph {
+    /// Without any redemption.
+    pub async fn start() -> Self {
+        let subgraph = Self {
+            server: MockServer::start().await,
+        };
+        subgraph.set_redemptions(&[]).await;
+        subgraph
+    }
+
+    /// URL of the subgraph query endpoint.
+    pub fn query_url(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Replaces the redemptions, as `(sender, allocation_id)` pairs.
+    pub async fn set_redemptions(&self, redemptions: &[(Address, Address)]) {
+        let transactions = redemptions
+            .iter()
+            .enumerate()
+            .map(|(i, (sender, allocation_id))| {
+                json!({
+                    "id": format!("0x{:02x}", i + 1),
+                    "allocationID": allocation_id,
+                    "sender": { "id": sender }
+                })
+            })
+            .collect::<Vec<_>>();
+        self.server.reset().await;
+        self.server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("transactions"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "data": { "transactions": transactions } })),
+                    ),
+            )
+            .await;
+    }
+}
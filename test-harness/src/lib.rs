@@ -0,0 +1,25 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixtures to test against the behavior of tap-agent without a gateway or a chain: an
+//! in-process TAP aggregator signing real RAVs, in-memory escrow accounts, receipt generators,
+//! and a mock escrow subgraph. The tap-agent tests use them too.
+//!
+//! With the `container` feature, the reference TAP aggregator can be run in a Docker container
+//! instead, see [`aggregator_container`].
+
+pub mod aggregator;
+#[cfg(feature = "container")]
+pub mod aggregator_container;
+pub mod escrow;
+pub mod escrow_subgraph;
+pub mod receipts;
+pub mod wallets;
+
+pub use aggregator::Aggregator;
+#[cfg(feature = "container")]
+pub use aggregator_container::AggregatorContainer;
+pub use escrow::InMemoryEscrow;
+pub use escrow_subgraph::MockEscrowSubgraph;
+pub use receipts::ReceiptGenerator;
+pub use wallets::wallet;
@@ -0,0 +1,83 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloy::{dyn_abi::Eip712Domain, primitives::Address, signers::local::PrivateKeySigner};
+use tap_core::{
+    rav::{ReceiptAggregateVoucher, SignedRAV},
+    receipt::{Receipt, SignedReceipt},
+    signed_message::EIP712SignedMessage,
+};
+
+/// Signs receipts of one signer for one allocation, with increasing nonces and timestamps.
+///
+/// The timestamps start at the creation time, and increase by one nanosecond per receipt, so
+/// that all the receipts are distinct and ordered.
+pub struct ReceiptGenerator {
+    domain: Eip712Domain,
+    wallet: PrivateKeySigner,
+    allocation_id: Address,
+    nonce: u64,
+    timestamp_ns: u64,
+}
+
+impl ReceiptGenerator {
+    pub fn new(domain: Eip712Domain, wallet: PrivateKeySigner, allocation_id: Address) -> Self {
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Self::starting_at(domain, wallet, allocation_id, timestamp_ns)
+    }
+
+    pub fn starting_at(
+        domain: Eip712Domain,
+        wallet: PrivateKeySigner,
+        allocation_id: Address,
+        timestamp_ns: u64,
+    ) -> Self {
+        Self {
+            domain,
+            wallet,
+            allocation_id,
+            nonce: 0,
+            timestamp_ns,
+        }
+    }
+
+    pub fn signer(&self) -> Address {
+        self.wallet.address()
+    }
+
+    pub fn next(&mut self, value: u128) -> SignedReceipt {
+        let receipt = Receipt {
+            allocation_id: self.allocation_id,
+            nonce: self.nonce,
+            timestamp_ns: self.timestamp_ns,
+            value,
+        };
+        self.nonce += 1;
+        self.timestamp_ns += 1;
+        EIP712SignedMessage::new(&self.domain, receipt, &self.wallet).unwrap()
+    }
+
+    /// `count` receipts of `value` each.
+    pub fn batch(&mut self, count: usize, value: u128) -> Vec<SignedReceipt> {
+        (0..count).map(|_| self.next(value)).collect()
+    }
+
+    /// A RAV of the allocation signed by the generator's wallet, as an aggregator using it would.
+    pub fn rav(&self, timestamp_ns: u64, value_aggregate: u128) -> SignedRAV {
+        EIP712SignedMessage::new(
+            &self.domain,
+            ReceiptAggregateVoucher {
+                allocationId: self.allocation_id,
+                timestampNs: timestamp_ns,
+                valueAggregate: value_aggregate,
+            },
+            &self.wallet,
+        )
+        .unwrap()
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    primitives::Address,
+    signers::local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+};
+use tap_core::tap_eip712_domain;
+
+const MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+/// Deterministic wallet number `index` of the test mnemonic, and its address.
+pub fn wallet(index: u32) -> (PrivateKeySigner, Address) {
+    let wallet: PrivateKeySigner = MnemonicBuilder::<English>::default()
+        .phrase(MNEMONIC)
+        .index(index)
+        .unwrap()
+        .build()
+        .unwrap();
+    let address = wallet.address();
+    (wallet, address)
+}
+
+/// EIP-712 domain of the tap-agent tests, for chain 1 and a dummy verifier.
+pub fn test_domain() -> Eip712Domain {
+    tap_eip712_domain(1, Address::from([0x11u8; 20]))
+}